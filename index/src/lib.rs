@@ -1,4 +1,5 @@
 extern crate mmap;
+extern crate libc;
 #[macro_use] extern crate quick_error;
 
 use std::path::Path;
@@ -7,14 +8,21 @@ use std::mem;
 use std::ptr;
 use std::io;
 use std::slice;
+#[cfg(unix)]
 use std::os::unix::io::AsRawFd;
+#[cfg(windows)]
+use std::os::windows::io::AsRawHandle;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use mmap::{MemoryMap, MapOption, MapError};
 
 
 pub const MAX_USAGE: f64 = 0.9;
 pub const MIN_USAGE: f64 = 0.35;
+pub const FIT_USAGE: f64 = 0.8;
 pub const INITIAL_SIZE: usize = 1024;
+/// Size of the memory pages, a lookup usually touches only one of them
+const PAGE_SIZE: usize = 4096;
 
 
 quick_error!{
@@ -154,9 +162,16 @@ pub struct Index<K: 'static, V: 'static> {
     fd: File,
     mmap: MemoryMap,
     header: &'static mut Header,
-    data: &'static mut [Entry<K, V>]
+    data: &'static mut [Entry<K, V>],
+    // Number of mapped bytes that should stay resident in memory, 0 for no limit
+    max_resident: usize,
+    // Number of lookups since the mapped pages have been released
+    lookups: AtomicUsize
 }
 
+// The mapped entries are only modified via &mut self, shared references can only read them
+unsafe impl<K: Sync, V: Sync> Sync for Index<K, V> {}
+
 impl<K: Key, V: Value> Index<K, V> {
     pub fn new(path: &Path, create: bool, magic: &[u8; 7], version: u8) -> Result<Self, IndexError> {
         let fd = try!(OpenOptions::new().read(true).write(true).create(create).open(path));
@@ -167,6 +182,16 @@ impl<K: Key, V: Value> Index<K, V> {
         if mmap.len() < mem::size_of::<Header>() {
             return Err(IndexError::WrongMagic);
         }
+        if !create {
+            // The size of the entries depends on the version, so check it before mapping them
+            let header = unsafe { &*(mmap.data() as *const Header) };
+            if header.magic != *magic {
+                return Err(IndexError::WrongMagic);
+            }
+            if header.version != version {
+                return Err(IndexError::UnsupportedVersion(header.version));
+            }
+        }
         let (header, data) = unsafe { mmap_as_ref::<K, V>(&mmap, INITIAL_SIZE as usize) };
         if create {
             // This is safe, nothing in header is Drop
@@ -179,12 +204,6 @@ impl<K: Key, V: Value> Index<K, V> {
                 unsafe { ptr::write(d, Entry::default()) }
             }
         }
-        if header.magic != *magic {
-            return Err(IndexError::WrongMagic);
-        }
-        if header.version != version {
-            return Err(IndexError::UnsupportedVersion(header.version));
-        }
         let (header, data) = unsafe { mmap_as_ref(&mmap, header.capacity as usize) };
         let index = Index{
             capacity: header.capacity as usize,
@@ -195,7 +214,9 @@ impl<K: Key, V: Value> Index<K, V> {
             fd: fd,
             mmap: mmap,
             data: data,
-            header: header
+            header: header,
+            max_resident: 0,
+            lookups: AtomicUsize::new(0)
         };
         debug_assert!(index.check().is_ok(), "Inconsistent after creation");
         Ok(index)
@@ -215,11 +236,15 @@ impl<K: Key, V: Value> Index<K, V> {
 
     #[inline]
     fn map_fd(fd: &File) -> Result<MemoryMap, IndexError> {
+        #[cfg(unix)]
+        let source = MapOption::MapFd(fd.as_raw_fd());
+        #[cfg(windows)]
+        let source = MapOption::MapFd(fd.as_raw_handle() as _);
         MemoryMap::new(
             try!(fd.metadata().map_err(IndexError::Io)).len() as usize,
             &[MapOption::MapReadable,
             MapOption::MapWritable,
-            MapOption::MapFd(fd.as_raw_fd()),
+            source,
             MapOption::MapNonStandardFlags(0x0001) //libc::consts::os::posix88::MAP_SHARED
         ]).map_err(IndexError::Mmap)
     }
@@ -261,6 +286,11 @@ impl<K: Key, V: Value> Index<K, V> {
         if self.entries >= self.min_entries || self.capacity <= INITIAL_SIZE {
             return Ok(false)
         }
+        try!(self.halve());
+        Ok(true)
+    }
+
+    fn halve(&mut self) -> Result<(), IndexError> {
         let old_capacity = self.capacity;
         let new_capacity = self.capacity / 2;
         self.set_capacity(new_capacity);
@@ -271,7 +301,25 @@ impl<K: Key, V: Value> Index<K, V> {
         self.header = header;
         self.data = data;
         assert_eq!(self.data.len(), self.capacity);
-        Ok(true)
+        Ok(())
+    }
+
+    /// Shrinks the index as long as it stays at most `FIT_USAGE` full
+    ///
+    /// Deleting entries only shrinks the index when it gets less than `MIN_USAGE` full, so after
+    /// removing many entries it can still be almost three times larger than needed.
+    /// Returns whether the capacity has changed.
+    pub fn shrink_to_fit(&mut self) -> Result<bool, IndexError> {
+        let mut shrunk = false;
+        while self.capacity > INITIAL_SIZE &&
+            self.entries <= ((self.capacity / 2) as f64 * FIT_USAGE) as usize
+        {
+            try!(self.halve());
+            shrunk = true;
+        }
+        self.write_header();
+        debug_assert!(self.check().is_ok(), "Inconsistent after shrinking");
+        Ok(shrunk)
     }
 
     fn extend(&mut self) -> Result<bool, IndexError> {
@@ -335,10 +383,47 @@ impl<K: Key, V: Value> Index<K, V> {
         self.header.capacity = self.capacity as u64;
     }
 
+    /// Limits the memory used by the mapped pages of the index file, 0 means no limit
+    ///
+    /// Lookups touch pages at random positions, so over time the whole index becomes resident.
+    /// After as many lookups as pages fit into the limit, all mapped pages are released. Changed
+    /// pages are still written back to the file by the kernel.
+    pub fn set_max_resident(&mut self, limit: usize) {
+        self.max_resident = limit;
+        self.lookups.store(0, Ordering::Relaxed);
+    }
+
+    /// Tells the kernel that the mapped pages are not needed anymore
+    ///
+    /// The pages are read from the file again when they are accessed the next time.
+    #[cfg(unix)]
+    pub fn release_pages(&self) {
+        unsafe {
+            let data = self.mmap.data() as *mut libc::c_void;
+            libc::madvise(data, self.mmap.len(), libc::MADV_DONTNEED);
+        }
+    }
+
+    /// Does nothing on Windows, the residency of the pages is left to the system
+    #[cfg(windows)]
+    pub fn release_pages(&self) {}
+
+    #[inline]
+    fn count_lookup(&self) {
+        if self.max_resident == 0 || self.mmap.len() <= self.max_resident {
+            return;
+        }
+        if self.lookups.fetch_add(1, Ordering::Relaxed) >= self.max_resident / PAGE_SIZE {
+            self.lookups.store(0, Ordering::Relaxed);
+            self.release_pages();
+        }
+    }
+
     /// Finds the position for this key
     /// If the key is in the table, it will be the position of the key,
     /// otherwise it will be the position where this key should be inserted
     fn locate(&self, key: &K) -> LocateResult {
+        self.count_lookup();
         let mut pos = key.hash() as usize & self.mask;
         let mut dist = 0;
         loop {
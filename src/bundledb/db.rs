@@ -3,11 +3,15 @@ use super::*;
 
 use std::path::{Path, PathBuf};
 use std::collections::{HashMap, HashSet};
-use std::fs;
+use std::fs::{self, File};
 use std::sync::{Arc, Mutex};
 use std::io;
 use std::mem;
-use std::cmp::min;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::cmp::{min, max};
+
+use crossbeam;
 
 quick_error!{
     #[derive(Debug)]
@@ -35,6 +39,9 @@ quick_error!{
             description("Failed to read/write bundle cache")
             display("Bundle db error: failed to read/write bundle cache\n\tcaused by: {}", err)
         }
+        TransferFailed {
+            description("Background transfer failed")
+        }
         UploadFailed {
             description("Uploading a bundle failed")
         }
@@ -48,11 +55,35 @@ quick_error!{
             description("No such bundle")
             display("Bundle db error: no such bundle: {:?}", bundle)
         }
+        Dictionary(err: DictionaryError) {
+            from()
+            cause(err)
+            description("Failed to read/write dictionary")
+            display("Bundle db error: failed to read/write dictionary\n\tcaused by: {}", err)
+        }
+        NoSuchDictionary(dictionary: Hash) {
+            description("No such dictionary")
+            display("Bundle db error: no such dictionary: {}", dictionary)
+        }
         Remove(err: io::Error, bundle: BundleId) {
             cause(err)
             description("Failed to remove bundle")
             display("Bundle db error: failed to remove bundle {}\n\tcaused by: {}", bundle, err)
         }
+        Proxy(err: ProxyError) {
+            from()
+            cause(err)
+            description("Failed to fetch bundle via proxy")
+            display("Bundle db error: failed to fetch bundle via proxy\n\tcaused by: {}", err)
+        }
+        UploadMismatch(path: PathBuf) {
+            description("Uploaded bundle differs")
+            display("Bundle db error: uploaded bundle {:?} differs from the written one", path)
+        }
+        WrongChunkData(bundle: BundleId, id: usize) {
+            description("Wrong chunk data")
+            display("Bundle db error: chunk {} of bundle {} does not match its hash", id, bundle)
+        }
     }
 }
 
@@ -62,6 +93,7 @@ fn load_bundles(
     base: &Path,
     bundles: &mut HashMap<BundleId, StoredBundle>,
     crypto: Arc<Mutex<Crypto>>,
+    transfers: &TransferPool,
 ) -> Result<(Vec<StoredBundle>, Vec<StoredBundle>), BundleDbError> {
     let mut paths = vec![path.to_path_buf()];
     let mut bundle_paths = HashSet::new();
@@ -72,7 +104,7 @@ fn load_bundles(
             if path.is_dir() {
                 paths.push(path);
             } else {
-                if path.extension() != Some("bundle".as_ref()) {
+                if !is_bundle_file(&path) {
                     continue;
                 }
                 bundle_paths.insert(path.strip_prefix(base).unwrap().to_path_buf());
@@ -87,9 +119,18 @@ fn load_bundles(
             bundle_paths.remove(&bundle.path);
         }
     }
-    let mut new = vec![];
+    // Reading the headers of all new bundles at once to hide the latency of the remote storage
+    let mut loading = Vec::with_capacity(bundle_paths.len());
     for path in bundle_paths {
-        let info = match BundleReader::load_info(base.join(&path), crypto.clone()) {
+        let (full_path, crypto) = (base.join(&path), crypto.clone());
+        let transfer = transfers.spawn(move || {
+            Ok(try!(BundleReader::load_info(full_path, crypto)))
+        });
+        loading.push((path, transfer));
+    }
+    let mut new = vec![];
+    for (path, transfer) in loading {
+        let info = match transfer.wait() {
             Ok(info) => info,
             Err(err) => {
                 warn!("Failed to read bundle {:?}\n\tcaused by: {}", path, err);
@@ -115,13 +156,187 @@ fn load_bundles(
 
 
 
+/// Copies the file via a temporary file so that an interrupted copy leaves no partial file
+fn copy_file_atomic(src_path: &Path, dst_path: &Path) -> Result<(), BundleDbError> {
+    let folder = dst_path.parent().unwrap();
+    try!(fs::create_dir_all(folder).context(folder));
+    let temp_path = dst_path.with_extension("tmp");
+    try!(fs::copy(src_path, &temp_path).context(&temp_path as &Path));
+    try!(fs::rename(&temp_path, dst_path).context(dst_path));
+    Ok(())
+}
+
+
+fn load_bundle_at(
+    path: PathBuf,
+    crypto: &Arc<Mutex<Crypto>>,
+    dictionaries: &HashMap<Hash, Dictionary>,
+) -> Result<BundleReader, BundleDbError> {
+    let mut bundle = try!(BundleReader::load(path, crypto.clone()));
+    if let Some(id) = bundle.info.dictionary {
+        if let Some(dictionary) = dictionaries.get(&id) {
+            bundle.set_dictionary(dictionary.data.clone());
+        }
+    }
+    Ok(bundle)
+}
+
+/// Loads the bundle and its contents so that the chunk `id` can be extracted
+///
+/// Remote bundles are fetched via the proxy if one is given.
+fn load_bundle_contents(
+    layout: &RepositoryLayout,
+    crypto: &Arc<Mutex<Crypto>>,
+    dictionaries: &HashMap<Hash, Dictionary>,
+    proxy: Option<&str>,
+    stored: &StoredBundle,
+    id: usize,
+) -> Result<(BundleReader, Vec<u8>), BundleDbError> {
+    let (mut bundle, proxied) = match proxy {
+        // Local bundles are cheap to read, only remote bundles are fetched via the proxy
+        Some(proxy) if stored.path.starts_with("remote") => {
+            let path = layout.temp_bundle_path();
+            try!(fetch_bundle(proxy, &stored.path, &path));
+            (try!(load_bundle_at(path, crypto, dictionaries)), true)
+        }
+        _ => {
+            let path = layout.base_path().join(&stored.path);
+            (try!(load_bundle_at(path, crypto, dictionaries)), false)
+        }
+    };
+    try!(bundle.get_chunk_position(id));
+    let data = bundle.load_contents();
+    if proxied {
+        try!(fs::remove_file(&bundle.path).context(&bundle.path as &Path));
+    }
+    let data = try!(data);
+    Ok((bundle, data))
+}
+
+/// Opens the bundle for reading its chunks one by one, see `ChunkStream`
+///
+/// Remote bundles are fetched via the proxy if one is given, their contents are decoded as a
+/// whole then.
+fn open_chunk_stream(
+    layout: &RepositoryLayout,
+    crypto: &Arc<Mutex<Crypto>>,
+    dictionaries: &HashMap<Hash, Dictionary>,
+    proxy: Option<&str>,
+    stored: &StoredBundle,
+) -> Result<ChunkStream, BundleDbError> {
+    match proxy {
+        Some(_) if stored.path.starts_with("remote") => {
+            let (bundle, data) = try!(load_bundle_contents(
+                layout,
+                crypto,
+                dictionaries,
+                proxy,
+                stored,
+                0
+            ));
+            Ok(try!(ChunkStream::with_contents(bundle, data)))
+        }
+        _ => {
+            let path = layout.base_path().join(&stored.path);
+            Ok(try!(ChunkStream::new(try!(load_bundle_at(path, crypto, dictionaries)))))
+        }
+    }
+}
+
+/// Compares the length and, if `verify` is set, the hash of the chunk with the expected ones
+fn check_chunk(
+    info: &BundleInfo,
+    id: usize,
+    data: &[u8],
+    expected: Chunk,
+    verify: bool,
+    hash_key: Option<&Hash>,
+) -> Result<(), BundleDbError> {
+    if data.len() != expected.1 as usize {
+        return Err(BundleDbError::WrongChunkData(info.id.clone(), id));
+    }
+    if verify {
+        // Chunks of keyed bundles can not be verified without the key
+        if let Some(actual) = info.hash_chunk(data, hash_key) {
+            if actual != expected.0 {
+                return Err(BundleDbError::WrongChunkData(info.id.clone(), id));
+            }
+        }
+    }
+    Ok(())
+}
+
+
+/// Reads chunks from the bundles without needing mutable access to the bundle db
+///
+/// Every fetcher has its own bundle cache, so one fetcher can be used by each thread.
+pub struct ChunkFetcher<'a> {
+    layout: &'a RepositoryLayout,
+    crypto: &'a Arc<Mutex<Crypto>>,
+    dictionaries: &'a HashMap<Hash, Dictionary>,
+    local_bundles: &'a HashMap<BundleId, StoredBundle>,
+    remote_bundles: &'a HashMap<BundleId, StoredBundle>,
+    proxy: Option<&'a str>,
+    bundle_cache: LruCache<BundleId, (BundleReader, Vec<u8>)>
+}
+
+impl<'a> ChunkFetcher<'a> {
+    pub fn get_chunk(&mut self, bundle_id: &BundleId, id: usize) -> Result<Vec<u8>, BundleDbError> {
+        if let Some(&mut (ref mut bundle, ref data)) = self.bundle_cache.get_mut(bundle_id) {
+            return Ok(try!(bundle.extract_chunk(data, id)));
+        }
+        let stored = try!(
+            self.local_bundles
+                .get(bundle_id)
+                .or_else(|| self.remote_bundles.get(bundle_id))
+                .ok_or_else(|| BundleDbError::NoSuchBundle(bundle_id.clone()))
+        );
+        let (mut bundle, data) = try!(load_bundle_contents(
+            self.layout,
+            self.crypto,
+            self.dictionaries,
+            self.proxy,
+            stored,
+            id
+        ));
+        let chunk = try!(bundle.extract_chunk(&data, id));
+        self.bundle_cache.put(bundle_id.clone(), (bundle, data));
+        Ok(chunk)
+    }
+}
+
+
 pub struct BundleDb {
     pub layout: RepositoryLayout,
     uploader: Option<Arc<BundleUploader>>,
     crypto: Arc<Mutex<Crypto>>,
     local_bundles: HashMap<BundleId, StoredBundle>,
     remote_bundles: HashMap<BundleId, StoredBundle>,
-    bundle_cache: LruCache<BundleId, (BundleReader, Vec<u8>)>
+    dictionaries: HashMap<Hash, Dictionary>,
+    bundle_cache: LruCache<BundleId, (BundleReader, Vec<u8>)>,
+    proxy: Option<String>,
+    // Additional remote storages that receive copies of new bundles
+    mirrors: Vec<(String, PathBuf)>,
+    failed_mirrors: HashSet<String>,
+    transfers: TransferPool,
+    // Bundles that are being loaded in the background, they are moved into the cache when used
+    // and limited like the cache so that unused ones do not pile up in memory
+    prefetched: LruCache<BundleId, Transfer<(BundleReader, Vec<u8>)>>,
+    // Bundles that chunks have been read from with the order of their first access
+    accessed: Option<HashMap<BundleId, usize>>,
+    // Time spent waiting for free slots in the upload queue
+    upload_wait: Duration,
+    upload_verification: UploadVerification,
+    codec_helpers: Option<Arc<CodecHelpers>>,
+    short_names: bool,
+    // Secret key of the chunk hashes of the repository
+    hash_key: Option<Hash>,
+    // Maximal number of decoded bundles in the cache of each fetcher, fetchers are sent to other
+    // threads so they can not use chunk streams
+    fetcher_cache_size: usize,
+    prefetch: bool,
+    // Chunks are read via a chunk stream instead of caching decoded bundles if this is set
+    chunk_stream: Option<Option<ChunkStream>>
 }
 
 
@@ -133,7 +348,22 @@ impl BundleDb {
             uploader: None,
             local_bundles: HashMap::new(),
             remote_bundles: HashMap::new(),
-            bundle_cache: LruCache::new(5, 10)
+            dictionaries: HashMap::new(),
+            bundle_cache: LruCache::new(5, 10),
+            proxy: None,
+            mirrors: vec![],
+            failed_mirrors: HashSet::new(),
+            transfers: TransferPool::new(worker_threads(TRANSFER_THREADS)),
+            prefetched: LruCache::new(5, 10),
+            accessed: None,
+            upload_wait: Duration::new(0, 0),
+            upload_verification: UploadVerification::Off,
+            codec_helpers: None,
+            short_names: false,
+            hash_key: None,
+            fetcher_cache_size: 10,
+            prefetch: true,
+            chunk_stream: None
         }
     }
 
@@ -159,7 +389,8 @@ impl BundleDb {
             &self.layout.local_bundles_path(),
             base_path,
             &mut self.local_bundles,
-            self.crypto.clone()
+            self.crypto.clone(),
+            &self.transfers
         ));
         if !new.is_empty() || !gone.is_empty() {
             let bundles: Vec<_> = self.local_bundles.values().cloned().collect();
@@ -172,7 +403,8 @@ impl BundleDb {
             &self.layout.remote_bundles_path(),
             base_path,
             &mut self.remote_bundles,
-            self.crypto.clone()
+            self.crypto.clone(),
+            &self.transfers
         ));
         if !new.is_empty() || !gone.is_empty() {
             let bundles: Vec<_> = self.remote_bundles.values().cloned().collect();
@@ -184,6 +416,51 @@ impl BundleDb {
         Ok((new, gone))
     }
 
+    fn load_dictionaries(&mut self) -> Result<(), BundleDbError> {
+        let path = self.layout.dictionaries_path();
+        if !path.exists() {
+            return Ok(());
+        }
+        for entry in try!(fs::read_dir(&path).context(&path as &Path)) {
+            let entry = try!(entry.context(&path as &Path));
+            match Dictionary::load(entry.path(), &self.crypto) {
+                Ok(dictionary) => {
+                    self.dictionaries.insert(dictionary.id, dictionary);
+                }
+                Err(err) => warn!("Failed to load dictionary: {}", err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores a new dictionary so that bundles can be compressed with it
+    pub fn add_dictionary(
+        &mut self,
+        data: Vec<u8>,
+        hash_method: HashMethod,
+        encryption: Option<Encryption>,
+    ) -> Result<Hash, BundleDbError> {
+        let dictionary = Dictionary::new(data, hash_method);
+        let id = dictionary.id;
+        if !self.dictionaries.contains_key(&id) {
+            let path = self.layout.dictionaries_path();
+            try!(fs::create_dir_all(&path).context(&path as &Path));
+            try!(dictionary.save(
+                self.layout.dictionary_path(&id),
+                hash_method,
+                encryption,
+                &self.crypto
+            ));
+            self.dictionaries.insert(id, dictionary);
+        }
+        Ok(id)
+    }
+
+    #[inline]
+    pub fn has_dictionary(&self, id: &Hash) -> bool {
+        self.dictionaries.contains_key(id)
+    }
+
     pub fn flush(&mut self) -> Result<(), BundleDbError> {
         self.finish_uploads().and_then(|()| self.save_cache())
     }
@@ -239,6 +516,7 @@ impl BundleDb {
         let mut self_ = Self::new(layout, crypto);
         let (new, gone) = try!(self_.load_bundle_list());
         try!(self_.update_cache());
+        try!(self_.load_dictionaries());
         let new = new.into_iter().map(|s| s.info).collect();
         let gone = gone.into_iter().map(|s| s.info).collect();
         Ok((self_, new, gone))
@@ -275,13 +553,26 @@ impl BundleDb {
         hash_method: HashMethod,
         compression: Option<Compression>,
         encryption: Option<Encryption>,
+        delta: bool,
+        dictionary: Option<Hash>,
     ) -> Result<BundleWriter, BundleDbError> {
+        let dictionary = match dictionary {
+            Some(id) => {
+                Some(try!(self.dictionaries.get(&id).ok_or_else(|| {
+                    BundleDbError::NoSuchDictionary(id)
+                })))
+            }
+            None => None,
+        };
         Ok(try!(BundleWriter::new(
             mode,
             hash_method,
             compression,
             encryption,
-            self.crypto.clone()
+            self.crypto.clone(),
+            delta,
+            dictionary,
+            self.codec_helpers.clone()
         )))
     }
 
@@ -298,31 +589,291 @@ impl BundleDb {
 
     #[inline]
     fn get_bundle(&self, stored: &StoredBundle) -> Result<BundleReader, BundleDbError> {
-        let base_path = self.layout.base_path();
-        Ok(try!(BundleReader::load(
-            base_path.join(&stored.path),
-            self.crypto.clone()
-        )))
+        self.load_bundle(self.layout.base_path().join(&stored.path))
+    }
+
+    #[inline]
+    fn load_bundle(&self, path: PathBuf) -> Result<BundleReader, BundleDbError> {
+        load_bundle_at(path, &self.crypto, &self.dictionaries)
+    }
+
+    /// Returns a fetcher that reads chunks independently of this bundle db
+    pub fn fetcher(&self) -> ChunkFetcher {
+        ChunkFetcher {
+            layout: &self.layout,
+            crypto: &self.crypto,
+            dictionaries: &self.dictionaries,
+            local_bundles: &self.local_bundles,
+            remote_bundles: &self.remote_bundles,
+            proxy: self.proxy.as_ref().map(String::as_str),
+            bundle_cache: LruCache::new(
+                max(1, self.fetcher_cache_size / 2),
+                self.fetcher_cache_size
+            )
+        }
     }
 
     pub fn get_chunk(&mut self, bundle_id: &BundleId, id: usize) -> Result<Vec<u8>, BundleDbError> {
+        try!(self.finish_prefetch(bundle_id));
+        if let Some(ref mut accessed) = self.accessed {
+            if !accessed.contains_key(bundle_id) {
+                let num = accessed.len();
+                accessed.insert(bundle_id.clone(), num);
+            }
+        }
         if let Some(&mut (ref mut bundle, ref data)) = self.bundle_cache.get_mut(bundle_id) {
-            let (pos, len) = try!(bundle.get_chunk_position(id));
-            let mut chunk = Vec::with_capacity(len);
-            chunk.extend_from_slice(&data[pos..pos + len]);
-            return Ok(chunk);
+            return Ok(try!(bundle.extract_chunk(data, id)));
         }
-        let mut bundle = try!(self.get_stored_bundle(bundle_id).and_then(
-            |s| self.get_bundle(s)
-        ));
-        let (pos, len) = try!(bundle.get_chunk_position(id));
-        let mut chunk = Vec::with_capacity(len);
-        let data = try!(bundle.load_contents());
-        chunk.extend_from_slice(&data[pos..pos + len]);
+        if self.chunk_stream.is_some() {
+            return self.stream_chunk(bundle_id, id);
+        }
+        let (mut bundle, data) = {
+            let stored = try!(self.get_stored_bundle(bundle_id));
+            try!(load_bundle_contents(
+                &self.layout,
+                &self.crypto,
+                &self.dictionaries,
+                self.proxy.as_ref().map(String::as_str),
+                stored,
+                id
+            ))
+        };
+        let chunk = try!(bundle.extract_chunk(&data, id));
         self.bundle_cache.put(bundle_id.clone(), (bundle, data));
         Ok(chunk)
     }
 
+    /// Reads the chunk via the chunk stream of its bundle instead of decoding the whole bundle
+    fn stream_chunk(&mut self, bundle_id: &BundleId, id: usize) -> Result<Vec<u8>, BundleDbError> {
+        let reopen = match self.chunk_stream {
+            Some(Some(ref stream)) => stream.id() != *bundle_id,
+            _ => true,
+        };
+        if reopen {
+            // The old stream is dropped first as it might hold decoded contents
+            self.chunk_stream = Some(None);
+            let stream = {
+                let stored = try!(self.get_stored_bundle(bundle_id));
+                try!(open_chunk_stream(
+                    &self.layout,
+                    &self.crypto,
+                    &self.dictionaries,
+                    self.proxy.as_ref().map(String::as_str),
+                    stored
+                ))
+            };
+            self.chunk_stream = Some(Some(stream));
+        }
+        match self.chunk_stream {
+            Some(Some(ref mut stream)) => Ok(try!(stream.read_chunk(id))),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Starts loading the bundle in the background so that its chunks are available sooner
+    ///
+    /// Nothing happens if the bundle is already cached or being loaded. If more bundles are being
+    /// prefetched than the cache can hold, the oldest ones are dropped.
+    pub fn prefetch_bundle(&mut self, bundle_id: &BundleId) -> Result<(), BundleDbError> {
+        if !self.prefetch || self.prefetched.get(bundle_id).is_some() ||
+            self.bundle_cache.get(bundle_id).is_some()
+        {
+            return Ok(());
+        }
+        let stored = try!(self.get_stored_bundle(bundle_id)).clone();
+        if stored.info.chunk_count == 0 {
+            return Ok(());
+        }
+        let layout = self.layout.clone();
+        let crypto = self.crypto.clone();
+        let dictionaries = self.dictionaries.clone();
+        let proxy = self.proxy.clone();
+        let transfer = self.transfers.spawn(move || {
+            load_bundle_contents(
+                &layout,
+                &crypto,
+                &dictionaries,
+                proxy.as_ref().map(String::as_str),
+                &stored,
+                0
+            )
+        });
+        self.prefetched.put(bundle_id.clone(), transfer);
+        Ok(())
+    }
+
+    /// Starts recording which bundles chunks are read from
+    pub fn record_access(&mut self) {
+        self.accessed = Some(HashMap::new());
+    }
+
+    /// Returns the recorded bundles in the order of their first access
+    pub fn accessed_bundles(&self) -> Vec<BundleId> {
+        let mut bundles: Vec<_> = match self.accessed {
+            Some(ref accessed) => accessed.iter().collect(),
+            None => return vec![],
+        };
+        bundles.sort_by_key(|&(_, num)| *num);
+        bundles.into_iter().map(|(id, _)| id.clone()).collect()
+    }
+
+    /// Reads the bundle files in a background thread so that later reads are served from caches
+    ///
+    /// The contents are not kept in memory, this only warms the page cache and the caches of
+    /// network filesystems or the bundle proxy. Unknown bundles are ignored. Returns the number
+    /// of bundles that will be read.
+    pub fn prewarm_bundles(&self, bundles: &[BundleId]) -> usize {
+        let paths: Vec<_> = bundles
+            .iter()
+            .filter_map(|id| self.get_stored_bundle(id).ok())
+            .map(|stored| stored.path.clone())
+            .collect();
+        let count = paths.len();
+        let layout = self.layout.clone();
+        let proxy = self.proxy.clone();
+        thread::spawn(move || {
+            for path in paths {
+                let res = match proxy {
+                    Some(ref proxy) if path.starts_with("remote") => {
+                        let temp_path = layout.temp_bundle_path();
+                        let res = fetch_bundle(proxy, &path, &temp_path);
+                        fs::remove_file(&temp_path).ok();
+                        res.map_err(BundleDbError::Proxy)
+                    }
+                    _ => {
+                        let full_path = layout.base_path().join(&path);
+                        File::open(&full_path)
+                            .and_then(|mut file| io::copy(&mut file, &mut io::sink()))
+                            .map(|_| ())
+                            .map_err(|err| BundleDbError::Io(err, full_path))
+                    }
+                };
+                if let Err(err) = res {
+                    debug!("Failed to prewarm bundle {:?}\n\tcaused by: {}", path, err);
+                }
+            }
+        });
+        count
+    }
+
+    /// Moves the bundle into the cache once it has been loaded if it is being prefetched
+    fn finish_prefetch(&mut self, bundle_id: &BundleId) -> Result<(), BundleDbError> {
+        if let Some(transfer) = self.prefetched.remove(bundle_id) {
+            let (bundle, data) = try!(transfer.wait());
+            self.bundle_cache.put(bundle_id.clone(), (bundle, data));
+        }
+        Ok(())
+    }
+
+    /// Writes the chunks of the bundle to the current position of the file
+    ///
+    /// Chunks that are stored verbatim in a bundle file that can be read directly are copied with
+    /// `copy_file_range` without passing through user space. Otherwise the chunks are taken from
+    /// the cached bundle contents and written with a single `writev` without copying them first.
+    /// The `path` of the file is only used in error messages. The lengths of the chunks are
+    /// always compared with the expected `chunks` before anything is written. With `verify`,
+    /// every chunk is also hashed and nothing is copied. Without a bundle cache, the chunks are
+    /// read and written one by one via a chunk stream.
+    pub fn write_chunks(
+        &mut self,
+        bundle_id: &BundleId,
+        ids: &[usize],
+        chunks: &[Chunk],
+        file: &File,
+        path: &Path,
+        verify: bool,
+    ) -> Result<(), BundleDbError> {
+        try!(self.finish_prefetch(bundle_id));
+        let hash_key = self.hash_key;
+        if self.bundle_cache.get(bundle_id).is_none() {
+            // Copied chunks never pass through memory, so they can not be verified
+            if !verify && try!(self.copy_chunks(bundle_id, ids, chunks, file)) {
+                return Ok(());
+            }
+            if self.chunk_stream.is_some() {
+                for (&id, &expected) in ids.iter().zip(chunks) {
+                    let chunk = try!(self.stream_chunk(bundle_id, id));
+                    if let Some(Some(ref stream)) = self.chunk_stream {
+                        try!(check_chunk(
+                            stream.info(),
+                            id,
+                            &chunk,
+                            expected,
+                            verify,
+                            hash_key.as_ref()
+                        ));
+                    }
+                    try!(write_all_vectored(file, &[&chunk]).context(path));
+                }
+                return Ok(());
+            }
+            let (bundle, data) = {
+                let stored = try!(self.get_stored_bundle(bundle_id));
+                try!(load_bundle_contents(
+                    &self.layout,
+                    &self.crypto,
+                    &self.dictionaries,
+                    self.proxy.as_ref().map(String::as_str),
+                    stored,
+                    ids[0]
+                ))
+            };
+            self.bundle_cache.put(bundle_id.clone(), (bundle, data));
+        }
+        let &mut (ref mut bundle, ref data) = self.bundle_cache.get_mut(bundle_id).unwrap();
+        let mut slices = Vec::with_capacity(ids.len());
+        for (&id, &expected) in ids.iter().zip(chunks) {
+            let chunk = try!(bundle.get_chunk_slice(data, id));
+            try!(check_chunk(&bundle.info, id, &chunk, expected, verify, hash_key.as_ref()));
+            slices.push(chunk);
+        }
+        let bufs: Vec<&[u8]> = slices.iter().map(|chunk| chunk.as_ref()).collect();
+        try!(write_all_vectored(file, &bufs).context(path));
+        Ok(())
+    }
+
+    /// Copies the chunks from the bundle file if they are all stored verbatim
+    ///
+    /// Returns `false` if nothing has been written because the chunks have to be decoded or the
+    /// kernel can not copy between those files.
+    fn copy_chunks(
+        &self,
+        bundle_id: &BundleId,
+        ids: &[usize],
+        chunks: &[Chunk],
+        file: &File,
+    ) -> Result<bool, BundleDbError> {
+        let stored = try!(self.get_stored_bundle(bundle_id));
+        // Remote bundles are not accessible directly when using a proxy
+        if self.proxy.is_some() && stored.path.starts_with("remote") {
+            return Ok(false);
+        }
+        let mut bundle = try!(self.get_bundle(stored));
+        let mut positions = Vec::with_capacity(ids.len());
+        for (&id, &(_, expected)) in ids.iter().zip(chunks) {
+            let (pos, len) = try!(bundle.get_chunk_position(id));
+            if !bundle.is_stored_verbatim(id) {
+                return Ok(false);
+            }
+            if len != expected as usize {
+                return Err(BundleDbError::WrongChunkData(bundle_id.clone(), id));
+            }
+            positions.push(((bundle.content_start + pos) as u64, len));
+        }
+        let src = try!(File::open(&bundle.path).context(&bundle.path as &Path));
+        for (i, &(pos, len)) in positions.iter().enumerate() {
+            if let Err(err) = copy_file_range(&src, pos, file, len) {
+                // Only the first copy can fall back as the others already changed the file
+                if i == 0 {
+                    debug!("Failed to copy chunks from bundle {}: {}", bundle_id, err);
+                    return Ok(false);
+                }
+                return Err(BundleDbError::Io(err, bundle.path.clone()));
+            }
+        }
+        Ok(true)
+    }
+
     fn copy_remote_bundle_to_cache(&mut self, bundle: &StoredBundle) -> Result<(), BundleDbError> {
         let id = bundle.id();
         let (folder, filename) = self.layout.local_bundle_path(&id, self.local_bundles.len());
@@ -340,26 +891,55 @@ impl BundleDb {
         if bundle.info.mode == BundleMode::Meta {
             try!(self.copy_remote_bundle_to_cache(&bundle))
         }
-        let (folder, filename) = self.layout.remote_bundle_path(self.remote_bundles.len());
-        let dst_path = folder.join(filename);
+        let dst_path = if self.short_names {
+            // The short names are random, so unlike the long ones they could collide
+            loop {
+                let (folder, filename) = self.layout.short_remote_bundle_path();
+                let path = folder.join(filename);
+                if !path.exists() {
+                    break path;
+                }
+            }
+        } else {
+            let (folder, filename) = self.layout.remote_bundle_path(self.remote_bundles.len());
+            folder.join(filename)
+        };
         let src_path = self.layout.base_path().join(bundle.path);
         bundle.path = dst_path
             .strip_prefix(self.layout.base_path())
             .unwrap()
             .to_path_buf();
+        let mirrors = {
+            let rel_path = dst_path.strip_prefix(self.layout.remote_path()).unwrap();
+            self.mirrors
+                .iter()
+                .filter(|&&(ref name, _)| !self.failed_mirrors.contains(name))
+                .map(|&(ref name, ref path)| (name.clone(), path.join(rel_path)))
+                .collect()
+        };
         if self.uploader.is_none() {
-            self.uploader = Some(BundleUploader::new(5));
+            self.uploader = Some(BundleUploader::new(5, self.upload_verification));
         }
-        try!(self.uploader.as_ref().unwrap().queue(src_path, dst_path));
+        let start = Instant::now();
+        try!(self.uploader.as_ref().unwrap().queue(src_path, dst_path, mirrors));
+        self.upload_wait += start.elapsed();
         self.remote_bundles.insert(bundle.id(), bundle.clone());
         Ok(bundle.info)
     }
 
+    /// Returns the total time that adding bundles had to wait for the upload queue
+    #[inline]
+    pub fn upload_wait(&self) -> Duration {
+        self.upload_wait
+    }
+
     fn finish_uploads(&mut self) -> Result<(), BundleDbError> {
         let mut uploader = None;
         mem::swap(&mut self.uploader, &mut uploader);
         if let Some(uploader) = uploader {
-            uploader.finish()
+            let res = uploader.finish();
+            self.failed_mirrors.extend(uploader.failed_mirrors());
+            res
         } else {
             Ok(())
         }
@@ -372,6 +952,16 @@ impl BundleDb {
         Ok(try!(bundle.get_chunk_list()).clone())
     }
 
+    /// Loads the remote bundle together with its chunk list to inspect its internal structure
+    pub fn load_bundle_reader(&self, bundle: &BundleId) -> Result<BundleReader, BundleDbError> {
+        let stored = try!(self.remote_bundles.get(bundle).ok_or_else(|| {
+            BundleDbError::NoSuchBundle(bundle.clone())
+        }));
+        let mut reader = try!(self.get_bundle(stored));
+        try!(reader.get_chunk_list());
+        Ok(reader)
+    }
+
     #[inline]
     pub fn get_bundle_info(&self, bundle: &BundleId) -> Option<&StoredBundle> {
         self.remote_bundles.get(bundle)
@@ -393,6 +983,7 @@ impl BundleDb {
     }
 
     pub fn delete_bundle(&mut self, bundle: &BundleId) -> Result<(), BundleDbError> {
+        self.prefetched.remove(bundle);
         try!(self.delete_local_bundle(bundle));
         if let Some(bundle) = self.remote_bundles.remove(bundle) {
             let path = self.layout.base_path().join(&bundle.path);
@@ -402,34 +993,66 @@ impl BundleDb {
         }
     }
 
-    pub fn check(&mut self, full: bool, repair: bool) -> Result<bool, BundleDbError> {
-        let mut to_repair = vec![];
-        for (id, stored) in ProgressIter::new(
-            "checking bundles",
-            self.remote_bundles.len(),
-            self.remote_bundles.iter()
-        )
+    /// Checks all remote bundles using the given number of threads
+    ///
+    /// Without `repair` all broken bundles are reported and the first error is returned.
+    /// Otherwise the broken bundles are repaired and `true` is returned if there were any.
+    pub fn check(
+        &mut self,
+        full: bool,
+        repair: bool,
+        threads: usize,
+    ) -> Result<bool, BundleDbError> {
+        let failed = Mutex::new(vec![]);
         {
-            let mut bundle = match self.get_bundle(stored) {
-                Ok(bundle) => bundle,
-                Err(err) => {
-                    if repair {
-                        to_repair.push(id.clone());
-                        continue;
-                    } else {
-                        return Err(err);
-                    }
-                }
-            };
-            if let Err(err) = bundle.check(full) {
-                if repair {
-                    to_repair.push(id.clone());
-                    continue;
-                } else {
-                    return Err(err.into());
+            let bundles = Mutex::new(
+                ProgressIter::new(
+                    "checking bundles",
+                    self.remote_bundles.len(),
+                    self.remote_bundles.iter()
+                ).fuse()
+            );
+            let (layout, crypto, dictionaries) = (&self.layout, &self.crypto, &self.dictionaries);
+            let (bundles, failed) = (&bundles, &failed);
+            crossbeam::scope(|scope| {
+                for num in 0..max(threads, 1) {
+                    scope.spawn(move || {
+                        pin_worker(num);
+                        loop {
+                            let next = bundles.lock().unwrap().next();
+                            let (id, stored) = match next {
+                                Some(next) => next,
+                                None => break,
+                            };
+                            let path = layout.base_path().join(&stored.path);
+                            let readable = stored.info.encryption.as_ref().map_or(true, |enc| {
+                                crypto.lock().unwrap().can_decrypt(enc)
+                            });
+                            // Without the secret key, only the structure can be checked
+                            let res = if readable {
+                                load_bundle_at(path, crypto, dictionaries)
+                                    .and_then(|mut bundle| Ok(try!(bundle.check(full))))
+                            } else {
+                                BundleReader::check_structure(path, &stored.info)
+                                    .map_err(BundleDbError::from)
+                            };
+                            if let Err(err) = res {
+                                failed.lock().unwrap().push((id.clone(), err));
+                            }
+                        }
+                    });
                 }
+            });
+        }
+        let mut failed = failed.into_inner().unwrap();
+        failed.sort_by(|a, b| a.0.cmp(&b.0));
+        if !repair && !failed.is_empty() {
+            for &(ref id, ref err) in &failed[1..] {
+                error!("Bundle {} is broken\n\tcaused by: {}", id, err);
             }
+            return Err(failed.swap_remove(0).1);
         }
+        let to_repair: Vec<_> = failed.into_iter().map(|(id, _)| id).collect();
         if !to_repair.is_empty() {
             for id in ProgressIter::new("repairing bundles", to_repair.len(), to_repair.iter()) {
                 try!(self.repair_bundle(id.clone()));
@@ -439,22 +1062,25 @@ impl BundleDb {
         Ok(!to_repair.is_empty())
     }
 
-    fn evacuate_broken_bundle(&mut self, mut bundle: StoredBundle) -> Result<(), BundleDbError> {
+    fn evacuate_broken_bundle(
+        &mut self,
+        bundle: StoredBundle,
+        reason: &str,
+        details: &[String],
+    ) -> Result<(), BundleDbError> {
         let src = self.layout.base_path().join(&bundle.path);
-        let mut dst = src.with_extension("bundle.broken");
-        let mut num = 1;
-        while dst.exists() {
-            dst = src.with_extension(&format!("bundle.{}.broken", num));
-            num += 1;
-        }
-        warn!("Moving bundle to {:?}", dst);
-        try!(bundle.move_to(self.layout.base_path(), dst));
+        let dst = Path::new("bundles").join(src.file_name().unwrap());
+        let dst = try!(
+            quarantine_file(&self.layout, &src, &dst, reason, details).context(&src as &Path)
+        );
+        warn!("Moved bundle to quarantine at {:?}", dst);
         self.remote_bundles.remove(&bundle.info.id);
         Ok(())
     }
 
     fn repair_bundle(&mut self, id: BundleId) -> Result<(), BundleDbError> {
         let stored = self.remote_bundles[&id].clone();
+        let mut details = vec![format!("bundle id: {}", id)];
         let mut bundle = match self.get_bundle(&stored) {
             Ok(bundle) => bundle,
             Err(err) => {
@@ -463,7 +1089,8 @@ impl BundleDb {
                     id,
                     err
                 );
-                return self.evacuate_broken_bundle(stored);
+                let reason = format!("failed to read bundle header: {}", err);
+                return self.evacuate_broken_bundle(stored, &reason, &details);
             }
         };
         let chunks = match bundle.get_chunk_list() {
@@ -474,7 +1101,8 @@ impl BundleDb {
                     id,
                     err
                 );
-                return self.evacuate_broken_bundle(stored);
+                let reason = format!("failed to read bundle chunks: {}", err);
+                return self.evacuate_broken_bundle(stored, &reason, &details);
             }
         };
         let data = match bundle.load_contents() {
@@ -485,7 +1113,8 @@ impl BundleDb {
                     id,
                     err
                 );
-                return self.evacuate_broken_bundle(stored);
+                let reason = format!("failed to read bundle data: {}", err);
+                return self.evacuate_broken_bundle(stored, &reason, &details);
             }
         };
         warn!("Problem detected: bundle data was truncated: {}", id);
@@ -495,24 +1124,202 @@ impl BundleDb {
             info.mode,
             info.hash_method,
             info.compression,
-            info.encryption
+            info.encryption,
+            !info.delta_chunks.is_empty(),
+            info.dictionary
         ));
-        let mut pos = 0;
-        for (hash, mut len) in chunks.into_inner() {
+        new_bundle.set_origin(info.host.clone(), info.backup.clone());
+        new_bundle.set_storage_class(info.storage_class);
+        new_bundle.set_keyed_hash(info.keyed_hash);
+        let chunk_count = chunks.len();
+        let mut recovered = 0;
+        for (id, (hash, _)) in chunks.into_inner().into_iter().enumerate() {
+            let (pos, len) = try!(bundle.get_chunk_position(id));
             if pos >= data.len() {
                 break;
             }
-            len = min(len, (data.len() - pos) as u32);
-            try!(new_bundle.add(&data[pos..pos + len as usize], hash));
-            pos += len as usize;
+            if info.delta_chunks.iter().any(|d| d.0 as usize == id) {
+                // Truncated deltas can not be decoded
+                match bundle.extract_chunk(&data, id) {
+                    Ok(chunk) => try!(new_bundle.add(&chunk, hash)),
+                    Err(_) => break,
+                };
+            } else {
+                let len = min(len, data.len() - pos);
+                try!(new_bundle.add(&data[pos..pos + len], hash));
+            }
+            recovered += 1;
         }
         let bundle = try!(self.add_bundle(new_bundle));
         info!("New bundle id is {}", bundle.id);
-        self.evacuate_broken_bundle(stored)
+        details.push(format!("new bundle id: {}", bundle.id));
+        details.push(format!("recovered chunks: {} of {}", recovered, chunk_count));
+        self.evacuate_broken_bundle(stored, "bundle data was truncated", &details)
     }
 
     #[inline]
     pub fn len(&self) -> usize {
         self.remote_bundles.len()
     }
+
+    /// Sets the maximal number of decoded bundles to keep in memory, including prefetched ones
+    ///
+    /// A quarter of them is used for prefetching, so nothing is prefetched with less than 4.
+    /// With 0, bundles are not decoded as a whole where avoidable, their chunks are read one by
+    /// one via a chunk stream instead.
+    pub fn set_cache_size(&mut self, bundles: usize) {
+        let prefetched = bundles / 4;
+        let cached = max(1, bundles - prefetched);
+        self.bundle_cache.resize(max(1, cached / 2), cached);
+        self.prefetched.resize(max(1, prefetched / 2), max(1, prefetched));
+        self.prefetch = prefetched > 0;
+        self.fetcher_cache_size = cached;
+        self.chunk_stream = if bundles == 0 { Some(None) } else { None };
+    }
+
+    /// Fetches the contents of remote bundles via the proxy at the given address
+    #[inline]
+    pub fn set_proxy(&mut self, proxy: Option<String>) {
+        self.proxy = proxy
+    }
+
+    /// Reads back new bundles after uploading them to verify that they have been written correctly
+    #[inline]
+    pub fn set_upload_verification(&mut self, verification: UploadVerification) {
+        self.upload_verification = verification
+    }
+
+    /// Compresses and encrypts new bundles in that many helper processes, 0 disables the helpers
+    pub fn set_codec_helpers(&mut self, count: usize) {
+        self.codec_helpers = if count > 0 {
+            Some(Arc::new(CodecHelpers::new(count)))
+        } else {
+            None
+        }
+    }
+
+    /// Names new remote bundles so that they also work on 8.3 and case-insensitive filesystems
+    #[inline]
+    pub fn set_short_names(&mut self, short_names: bool) {
+        self.short_names = short_names
+    }
+
+    /// Sets the key that the chunk hashes are computed with when verifying chunks
+    #[inline]
+    pub fn set_hash_key(&mut self, key: Option<Hash>) {
+        self.hash_key = key
+    }
+
+    /// Copies new bundles to the given additional remote storages as well
+    ///
+    /// The paths are the base folders of the remotes, corresponding to the `remote` folder.
+    #[inline]
+    pub fn set_mirrors(&mut self, mirrors: Vec<(String, PathBuf)>) {
+        self.mirrors = mirrors;
+        self.failed_mirrors.clear();
+    }
+
+    #[inline]
+    pub fn mirrors(&self) -> &[(String, PathBuf)] {
+        &self.mirrors
+    }
+
+    /// Returns the names of the additional remotes that failed to receive a new bundle
+    #[inline]
+    pub fn failed_mirrors(&self) -> &HashSet<String> {
+        &self.failed_mirrors
+    }
+
+    /// Copies all bundles and dictionaries that are missing in the additional remote storage
+    ///
+    /// Returns the number of copied bundles.
+    pub fn sync_to_mirror(&self, mirror: &Path) -> Result<usize, BundleDbError> {
+        let mut count = 0;
+        for bundle in self.remote_bundles.values() {
+            let src_path = self.layout.base_path().join(&bundle.path);
+            let rel_path = src_path.strip_prefix(self.layout.remote_path()).unwrap();
+            let dst_path = mirror.join(rel_path);
+            if dst_path.exists() {
+                continue;
+            }
+            try!(copy_file_atomic(&src_path, &dst_path));
+            count += 1;
+        }
+        for id in self.dictionaries.keys() {
+            let src_path = self.layout.dictionary_path(id);
+            let rel_path = src_path.strip_prefix(self.layout.remote_path()).unwrap();
+            let dst_path = mirror.join(rel_path);
+            if !dst_path.exists() {
+                try!(copy_file_atomic(&src_path, &dst_path));
+            }
+        }
+        Ok(count)
+    }
+
+    /// Copies the remote bundles created after `since` and all dictionaries into the folder `dst`
+    ///
+    /// The files keep their paths relative to the remote storage. Returns the number of copied
+    /// bundles.
+    pub fn export_bundles(&self, since: i64, dst: &Path) -> Result<usize, BundleDbError> {
+        let mut count = 0;
+        for bundle in self.remote_bundles.values() {
+            if bundle.info.timestamp <= since {
+                continue;
+            }
+            let src_path = self.layout.base_path().join(&bundle.path);
+            let rel_path = src_path.strip_prefix(self.layout.remote_path()).unwrap();
+            try!(copy_file_atomic(&src_path, &dst.join(rel_path)));
+            count += 1;
+        }
+        for id in self.dictionaries.keys() {
+            let src_path = self.layout.dictionary_path(id);
+            let rel_path = src_path.strip_prefix(self.layout.remote_path()).unwrap();
+            try!(copy_file_atomic(&src_path, &dst.join(rel_path)));
+        }
+        Ok(count)
+    }
+
+    /// Copies the bundles and dictionaries in the folder `src` that are missing into the remote
+    /// storage
+    ///
+    /// The folder must have the structure created by `export_bundles`. The new bundles are added
+    /// to the index when the repository is opened the next time. Returns the number of copied
+    /// bundles.
+    pub fn import_bundles(&self, src: &Path) -> Result<usize, BundleDbError> {
+        let mut count = 0;
+        let mut paths = vec![src.join("bundles"), src.join("dictionaries")];
+        while let Some(path) = paths.pop() {
+            if !path.exists() {
+                continue;
+            }
+            for entry in try!(fs::read_dir(&path).context(&path as &Path)) {
+                let entry = try!(entry.context(&path as &Path));
+                let src_path = entry.path();
+                if src_path.is_dir() {
+                    paths.push(src_path);
+                    continue;
+                }
+                // Leftovers of interrupted copies are ignored
+                let is_bundle = is_bundle_file(&src_path);
+                if !is_bundle && src_path.extension() != Some("dict".as_ref()) {
+                    continue;
+                }
+                let rel_path = src_path.strip_prefix(src).unwrap();
+                let dst_path = self.layout.remote_path().join(rel_path);
+                if dst_path.exists() {
+                    continue;
+                }
+                try!(copy_file_atomic(&src_path, &dst_path));
+                if is_bundle {
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// Serves the remote bundles to other clients on the given address
+    pub fn serve_proxy(&self, addr: &str) -> Result<(), BundleDbError> {
+        Ok(try!(BundleProxy::new(self.layout.clone()).serve(addr)))
+    }
 }
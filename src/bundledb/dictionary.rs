@@ -0,0 +1,150 @@
+use prelude::*;
+
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::sync::{Arc, Mutex};
+
+
+pub static DICTIONARY_FILE_STRING: [u8; 7] = *b"zvault\x05";
+pub static DICTIONARY_FILE_VERSION: u8 = 1;
+
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum DictionaryError {
+        Io(err: io::Error, path: PathBuf) {
+            cause(err)
+            context(path: &'a Path, err: io::Error) -> (err, path.to_path_buf())
+            description("Failed to read or write dictionary")
+            display("Dictionary error: failed to access dictionary {:?}\n\tcaused by: {}", path, err)
+        }
+        WrongHeader(path: PathBuf) {
+            description("Wrong header")
+            display("Dictionary error: wrong header on dictionary {:?}", path)
+        }
+        UnsupportedVersion(path: PathBuf, version: u8) {
+            description("Wrong version")
+            display("Dictionary error: unsupported version on dictionary {:?}: {}", path, version)
+        }
+        Decode(err: msgpack::DecodeError, path: PathBuf) {
+            cause(err)
+            context(path: &'a Path, err: msgpack::DecodeError) -> (err, path.to_path_buf())
+            description("Failed to decode dictionary header")
+            display("Dictionary error: failed to decode dictionary header of {:?}\n\tcaused by: {}", path, err)
+        }
+        Encode(err: msgpack::EncodeError, path: PathBuf) {
+            cause(err)
+            context(path: &'a Path, err: msgpack::EncodeError) -> (err, path.to_path_buf())
+            description("Failed to encode dictionary header")
+            display("Dictionary error: failed to encode dictionary header of {:?}\n\tcaused by: {}", path, err)
+        }
+        Encryption(err: EncryptionError) {
+            from()
+            cause(err)
+            description("Encryption failed")
+            display("Dictionary error: encryption failed\n\tcaused by: {}", err)
+        }
+        Integrity(path: PathBuf) {
+            description("Dictionary has an integrity error")
+            display("Dictionary error: dictionary {:?} does not match its id", path)
+        }
+    }
+}
+
+
+#[derive(Clone)]
+pub struct DictionaryHeader {
+    pub encryption: Option<Encryption>,
+    pub hash_method: HashMethod,
+    pub id: Hash
+}
+impl Default for DictionaryHeader {
+    fn default() -> Self {
+        DictionaryHeader {
+            encryption: None,
+            hash_method: HashMethod::Blake2,
+            id: Hash::empty()
+        }
+    }
+}
+serde_impl!(DictionaryHeader(u8) {
+    encryption: Option<Encryption> => 0,
+    hash_method: HashMethod => 1,
+    id: Hash => 2
+});
+
+
+/// A compression dictionary that is shared by all bundles referencing its id
+#[derive(Clone)]
+pub struct Dictionary {
+    pub id: Hash,
+    pub data: Arc<Vec<u8>>
+}
+
+impl Dictionary {
+    pub fn new(data: Vec<u8>, hash_method: HashMethod) -> Self {
+        Dictionary {
+            id: hash_method.hash(&data),
+            data: Arc::new(data)
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        crypto: &Mutex<Crypto>,
+    ) -> Result<Self, DictionaryError> {
+        let path = path.as_ref();
+        let mut file = BufReader::new(try!(File::open(path).context(path)));
+        let mut header = [0u8; 8];
+        try!(file.read_exact(&mut header).context(path));
+        if header[..DICTIONARY_FILE_STRING.len()] != DICTIONARY_FILE_STRING {
+            return Err(DictionaryError::WrongHeader(path.to_path_buf()));
+        }
+        let version = header[DICTIONARY_FILE_STRING.len()];
+        if version != DICTIONARY_FILE_VERSION {
+            return Err(DictionaryError::UnsupportedVersion(path.to_path_buf(), version));
+        }
+        let header: DictionaryHeader = try!(
+            msgpack::decode_from_stream_limited(&mut file, msgpack::MAX_HEADER_SIZE).context(path)
+        );
+        let mut data = Vec::new();
+        try!(file.read_to_end(&mut data).context(path));
+        if let Some(ref encryption) = header.encryption {
+            data = try!(crypto.lock().unwrap().decrypt(encryption, &data));
+        }
+        if header.hash_method.hash(&data) != header.id {
+            return Err(DictionaryError::Integrity(path.to_path_buf()));
+        }
+        Ok(Dictionary {
+            id: header.id,
+            data: Arc::new(data)
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(
+        &self,
+        path: P,
+        hash_method: HashMethod,
+        encryption: Option<Encryption>,
+        crypto: &Mutex<Crypto>,
+    ) -> Result<(), DictionaryError> {
+        let path = path.as_ref();
+        let data = if let Some(ref encryption) = encryption {
+            try!(crypto.lock().unwrap().encrypt(encryption, &self.data))
+        } else {
+            self.data.to_vec()
+        };
+        let header = DictionaryHeader {
+            encryption: encryption,
+            hash_method: hash_method,
+            id: self.id
+        };
+        let mut file = BufWriter::new(try!(File::create(path).context(path)));
+        try!(file.write_all(&DICTIONARY_FILE_STRING).context(path));
+        try!(file.write_all(&[DICTIONARY_FILE_VERSION]).context(path));
+        try!(msgpack::encode_to_stream(&header, &mut file).context(path));
+        try!(file.write_all(&data).context(path));
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use prelude::*;
+
+use std::env;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+use crossbeam::sync::MsQueue;
+
+
+/// Name of the hidden subcommand that runs a codec helper process
+pub const CODEC_HELPER_COMMAND: &'static str = "codec-helper";
+
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum CodecHelperError {
+        Spawn(err: io::Error) {
+            cause(err)
+            description("Failed to start codec helper")
+            display("Codec helper error: failed to start helper process\n\tcaused by: {}", err)
+        }
+        Io(err: io::Error) {
+            cause(err)
+            description("Failed to communicate with codec helper")
+            display("Codec helper error: failed to communicate with helper\n\tcaused by: {}", err)
+        }
+        Encode(err: msgpack::EncodeError) {
+            from()
+            cause(err)
+            description("Failed to send request to codec helper")
+            display("Codec helper error: failed to send request\n\tcaused by: {}", err)
+        }
+        Decode(err: msgpack::DecodeError) {
+            from()
+            cause(err)
+            description("Failed to read response of codec helper")
+            display("Codec helper error: failed to read response, the helper might have crashed\n\tcaused by: {}", err)
+        }
+        Failed(msg: String) {
+            description("Codec helper failed")
+            display("Codec helper error: {}", msg)
+        }
+    }
+}
+
+
+#[derive(Default)]
+struct CodecRequest {
+    compression: Option<Compression>,
+    dictionary: Option<msgpack::Bytes>,
+    encryption: Option<Encryption>,
+    data: msgpack::Bytes
+}
+serde_impl!(CodecRequest(u8) {
+    compression: Option<Compression> => 0,
+    dictionary: Option<msgpack::Bytes> => 1,
+    encryption: Option<Encryption> => 2,
+    data: msgpack::Bytes => 3
+});
+
+
+#[derive(Default)]
+struct CodecResponse {
+    data: msgpack::Bytes,
+    error: Option<String>
+}
+serde_impl!(CodecResponse(u8) {
+    data: msgpack::Bytes => 0,
+    error: Option<String> => 1
+});
+
+
+/// Compresses and encrypts the data of the request like the bundle writer does
+fn encode_request(request: &CodecRequest) -> Result<Vec<u8>, String> {
+    let mut data = Vec::with_capacity(request.data.len());
+    match (request.compression.as_ref(), request.dictionary.as_ref()) {
+        (Some(compression), Some(dictionary)) => {
+            let mut stream = try!(
+                compression
+                    .compress_stream_with_dictionary(dictionary)
+                    .map_err(|err| err.to_string())
+            );
+            try!(stream.process(&request.data, &mut data).map_err(|err| err.to_string()));
+            try!(stream.finish(&mut data).map_err(|err| err.to_string()));
+        }
+        (Some(compression), None) => {
+            let mut stream = try!(compression.compress_stream().map_err(|err| err.to_string()));
+            try!(stream.process(&request.data, &mut data).map_err(|err| err.to_string()));
+            try!(stream.finish(&mut data).map_err(|err| err.to_string()));
+        }
+        (None, _) => data.extend_from_slice(&request.data),
+    }
+    if let Some(ref encryption) = request.encryption {
+        // Encryption only needs the public key
+        data = try!(Crypto::dummy().encrypt(encryption, &data).map_err(|err| err.to_string()));
+    }
+    Ok(data)
+}
+
+/// Answers codec requests on stdin until it is closed, this is the main loop of the helpers
+pub fn serve_codec_requests() -> Result<(), CodecHelperError> {
+    let stdin = io::stdin();
+    let mut input = BufReader::new(stdin.lock());
+    let stdout = io::stdout();
+    let mut output = BufWriter::new(stdout.lock());
+    loop {
+        // The parent closes the pipe when it does not need the helper anymore
+        let request: CodecRequest = match msgpack::decode_from_stream(&mut input) {
+            Ok(request) => request,
+            Err(_) => return Ok(()),
+        };
+        let response = match encode_request(&request) {
+            Ok(data) => CodecResponse {
+                data: data.into(),
+                error: None
+            },
+            Err(err) => CodecResponse {
+                data: vec![].into(),
+                error: Some(err)
+            },
+        };
+        try!(msgpack::encode_to_stream(&response, &mut output));
+        try!(output.flush().map_err(CodecHelperError::Io));
+    }
+}
+
+
+struct CodecHelper {
+    child: Child,
+    input: BufWriter<ChildStdin>,
+    output: BufReader<ChildStdout>
+}
+
+impl CodecHelper {
+    fn spawn() -> Result<Self, CodecHelperError> {
+        let exe = try!(env::current_exe().map_err(CodecHelperError::Spawn));
+        let mut child = try!(
+            Command::new(exe)
+                .arg(CODEC_HELPER_COMMAND)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .map_err(CodecHelperError::Spawn)
+        );
+        let input = BufWriter::new(child.stdin.take().unwrap());
+        let output = BufReader::new(child.stdout.take().unwrap());
+        Ok(CodecHelper {
+            child: child,
+            input: input,
+            output: output
+        })
+    }
+
+    fn run(&mut self, request: &CodecRequest) -> Result<Vec<u8>, CodecHelperError> {
+        try!(msgpack::encode_to_stream(request, &mut self.input));
+        try!(self.input.flush().map_err(CodecHelperError::Io));
+        let response: CodecResponse = try!(msgpack::decode_from_stream(&mut self.output));
+        match response.error {
+            Some(err) => Err(CodecHelperError::Failed(err)),
+            None => Ok(response.data.into()),
+        }
+    }
+}
+
+impl Drop for CodecHelper {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+
+/// Pool of helper processes that compress and encrypt bundle data
+///
+/// The helpers run the same binary, so crashes in the codec libraries only kill the helper
+/// instead of leaving the repository in an inconsistent state. A helper that failed is replaced
+/// by a new process for the next request. Helpers are started on first use.
+pub struct CodecHelpers {
+    slots: MsQueue<Option<CodecHelper>>
+}
+
+impl CodecHelpers {
+    pub fn new(count: usize) -> Self {
+        let slots = MsQueue::new();
+        for _ in 0..count {
+            slots.push(None);
+        }
+        CodecHelpers { slots: slots }
+    }
+
+    /// Compresses and encrypts the data in one of the helpers, blocks while all are busy
+    pub fn encode(
+        &self,
+        compression: Option<&Compression>,
+        dictionary: Option<&[u8]>,
+        encryption: Option<&Encryption>,
+        data: &[u8],
+    ) -> Result<Vec<u8>, CodecHelperError> {
+        let request = CodecRequest {
+            compression: compression.cloned(),
+            dictionary: dictionary.map(|d| d.to_vec().into()),
+            encryption: encryption.cloned(),
+            data: data.to_vec().into()
+        };
+        let mut helper = match self.slots.pop() {
+            Some(helper) => helper,
+            None => {
+                match CodecHelper::spawn() {
+                    Ok(helper) => helper,
+                    Err(err) => {
+                        self.slots.push(None);
+                        return Err(err);
+                    }
+                }
+            }
+        };
+        let result = helper.run(&request);
+        match result {
+            Ok(_) |
+            Err(CodecHelperError::Failed(_)) => self.slots.push(Some(helper)),
+            Err(_) => {
+                warn!("Codec helper failed, starting a new one for the next request");
+                self.slots.push(None)
+            }
+        }
+        result
+    }
+}
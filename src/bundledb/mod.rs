@@ -3,22 +3,46 @@ mod reader;
 mod db;
 mod cache;
 mod uploader;
+mod dictionary;
+mod proxy;
+mod transfer;
+mod helper;
+mod stream;
 
-pub use self::cache::{StoredBundle, BundleCacheError};
+pub use self::cache::{StoredBundle, BundleCacheError, CACHE_FILE_STRING};
 pub use self::writer::{BundleWriter, BundleWriterError};
 pub use self::reader::{BundleReader, BundleReaderError};
 pub use self::db::*;
-pub use self::uploader::BundleUploader;
+pub use self::uploader::{BundleUploader, UploadVerification};
+pub use self::dictionary::{Dictionary, DictionaryError, DICTIONARY_FILE_STRING};
+pub use self::proxy::{BundleProxy, ProxyError, fetch_bundle};
+pub use self::transfer::{Transfer, TransferPool, TRANSFER_THREADS};
+pub use self::helper::{CodecHelpers, CodecHelperError, serve_codec_requests, CODEC_HELPER_COMMAND};
+pub use self::stream::ChunkStream;
 
 use prelude::*;
 
 use std::fmt;
+use std::path::Path;
 use serde;
 use rand;
 
 
 pub static HEADER_STRING: [u8; 7] = *b"zvault\x01";
 pub static HEADER_VERSION: u8 = 1;
+/// Version of bundles that contain delta encoded chunks, older readers must not read them
+pub static DELTA_HEADER_VERSION: u8 = 2;
+/// Upper limit for the encoded bundle info, larger values in a header are treated as corrupt
+pub static MAX_INFO_SIZE: usize = 16 * 1024 * 1024;
+
+
+/// Whether the file is a bundle, `bnd` is the extension of the short naming scheme
+pub fn is_bundle_file(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("bundle") | Some("bnd") => true,
+        _ => false,
+    }
+}
 
 
 #[derive(Hash, PartialEq, Eq, Clone, Default, Ord, PartialOrd)]
@@ -80,6 +104,56 @@ serde_impl!(BundleMode(u8) {
 });
 
 
+/// Hint for the storage tier that a bundle should be kept in
+///
+/// zVault itself treats all bundles the same. Object storage backends map the hint to the
+/// storage classes of their provider, e.g. an archive tier for old backups that are rarely
+/// restored. Restoring from the colder tiers is slower and can be expensive.
+#[derive(Eq, Debug, PartialEq, Clone, Copy, Hash)]
+pub enum StorageClass {
+    Standard,
+    Infrequent,
+    Archive
+}
+serde_impl!(StorageClass(u8) {
+    Standard => 0,
+    Infrequent => 1,
+    Archive => 2
+});
+
+impl StorageClass {
+    pub fn from_string(name: &str) -> Result<Self, &'static str> {
+        match name {
+            "standard" => Ok(StorageClass::Standard),
+            "infrequent" => Ok(StorageClass::Infrequent),
+            "archive" => Ok(StorageClass::Archive),
+            _ => Err("Unsupported storage class"),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            StorageClass::Standard => "standard",
+            StorageClass::Infrequent => "infrequent",
+            StorageClass::Archive => "archive",
+        }
+    }
+}
+
+impl Default for StorageClass {
+    fn default() -> Self {
+        StorageClass::Standard
+    }
+}
+
+impl fmt::Display for StorageClass {
+    #[inline]
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "{}", self.name())
+    }
+}
+
+
 #[derive(Default, Debug, Clone)]
 pub struct BundleHeader {
     pub encryption: Option<Encryption>,
@@ -102,7 +176,19 @@ pub struct BundleInfo {
     pub encoded_size: usize,
     pub chunk_count: usize,
     pub chunk_list_size: usize,
-    pub timestamp: i64
+    pub timestamp: i64,
+    /// Chunks stored as delta: (chunk id, base chunk id, stored size)
+    pub delta_chunks: Vec<(u32, u32, u32)>,
+    /// Id of the dictionary used for compression
+    pub dictionary: Option<Hash>,
+    /// Host that created the bundle
+    pub host: Option<String>,
+    /// Name of the backup that the bundle was created for
+    pub backup: Option<String>,
+    /// Storage tier that the bundle should be kept in
+    pub storage_class: StorageClass,
+    /// Whether the chunk hashes have been computed with the secret key of the repository
+    pub keyed_hash: bool
 }
 serde_impl!(BundleInfo(u64?) {
     id: BundleId => 0,
@@ -114,7 +200,13 @@ serde_impl!(BundleInfo(u64?) {
     encoded_size: usize => 7,
     chunk_count: usize => 8,
     chunk_list_size: usize => 9,
-    timestamp: i64 => 10
+    timestamp: i64 => 10,
+    delta_chunks: Vec<(u32, u32, u32)> => 11,
+    dictionary: Option<Hash> => 12,
+    host: Option<String> => 13,
+    backup: Option<String> => 14,
+    storage_class: StorageClass => 15,
+    keyed_hash: bool => 16
 });
 
 impl Default for BundleInfo {
@@ -129,7 +221,26 @@ impl Default for BundleInfo {
             chunk_count: 0,
             mode: BundleMode::Data,
             chunk_list_size: 0,
-            timestamp: 0
+            timestamp: 0,
+            delta_chunks: vec![],
+            dictionary: None,
+            host: None,
+            backup: None,
+            storage_class: StorageClass::Standard,
+            keyed_hash: false
+        }
+    }
+}
+
+impl BundleInfo {
+    /// Hashes the data of a chunk the same way as the chunks of this bundle have been hashed
+    ///
+    /// Returns `None` if the chunks have been hashed with a key but no key is given.
+    pub fn hash_chunk(&self, data: &[u8], key: Option<&Hash>) -> Option<Hash> {
+        match (self.keyed_hash, key) {
+            (true, None) => None,
+            (true, key) => Some(self.hash_method.hash_keyed(data, key)),
+            (false, _) => Some(self.hash_method.hash(data)),
         }
     }
 }
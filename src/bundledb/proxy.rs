@@ -0,0 +1,159 @@
+use prelude::*;
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum ProxyError {
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            description("IO error")
+            display("Proxy error: IO error\n\tcaused by: {}", err)
+        }
+        InvalidPath(path: PathBuf) {
+            description("Invalid bundle path")
+            display("Proxy error: invalid bundle path {:?}", path)
+        }
+        InvalidResponse {
+            description("Invalid response")
+            display("Proxy error: invalid response from proxy")
+        }
+        Remote(msg: String) {
+            description("Proxy failed")
+            display("Proxy error: the proxy failed to serve the bundle: {}", msg)
+        }
+    }
+}
+
+
+/// Allows to omit the host part of the address to listen on all interfaces, e.g. `:9000`
+fn normalize_address(addr: &str) -> String {
+    if addr.starts_with(':') {
+        format!("0.0.0.0{}", addr)
+    } else {
+        addr.to_string()
+    }
+}
+
+/// Downloads a bundle via a proxy into the file `dst`
+///
+/// The path of the bundle is relative to the repository folder, e.g. `remote/bundles/...`.
+pub fn fetch_bundle<P: AsRef<Path>>(addr: &str, path: &Path, dst: P) -> Result<(), ProxyError> {
+    let mut stream = try!(TcpStream::connect(addr));
+    try!(stream.write_all(format!("GET {}\n", path.to_string_lossy()).as_bytes()));
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    try!(reader.read_line(&mut line));
+    let line = line.trim_right();
+    if line.starts_with("ERROR ") {
+        return Err(ProxyError::Remote(line[6..].to_string()));
+    }
+    if !line.starts_with("OK ") {
+        return Err(ProxyError::InvalidResponse);
+    }
+    let size: u64 = try!(line[3..].parse().map_err(|_| ProxyError::InvalidResponse));
+    let mut file = try!(File::create(dst));
+    if try!(io::copy(&mut reader.take(size), &mut file)) != size {
+        return Err(ProxyError::InvalidResponse);
+    }
+    Ok(())
+}
+
+
+/// Serves remote bundles of a repository to other clients and caches them locally
+pub struct BundleProxy {
+    layout: RepositoryLayout,
+    fetch_lock: Mutex<()>
+}
+
+impl BundleProxy {
+    pub fn new(layout: RepositoryLayout) -> Self {
+        BundleProxy {
+            layout: layout,
+            fetch_lock: Mutex::new(())
+        }
+    }
+
+    fn get_cached(&self, path: &Path) -> Result<PathBuf, ProxyError> {
+        let is_valid = path.components().all(|c| match c {
+            Component::Normal(_) => true,
+            _ => false,
+        }) && super::is_bundle_file(path);
+        let remote = self.layout.base_path().join(path);
+        let cached = match remote.strip_prefix(self.layout.remote_bundles_path()) {
+            Ok(rel_path) if is_valid => self.layout.proxy_cache_path().join(rel_path),
+            _ => return Err(ProxyError::InvalidPath(path.to_path_buf())),
+        };
+        if cached.exists() {
+            return Ok(cached);
+        }
+        // Only fetch one bundle at a time so that concurrent requests for a bundle share it
+        let _lock = self.fetch_lock.lock().unwrap();
+        if !cached.exists() {
+            info!("Fetching bundle {:?}", path);
+            try!(fs::create_dir_all(cached.parent().unwrap()));
+            let temp = cached.with_extension("bundle.tmp");
+            try!(fs::copy(&remote, &temp));
+            try!(fs::rename(&temp, &cached));
+        }
+        Ok(cached)
+    }
+
+    fn handle(&self, stream: TcpStream) -> Result<(), ProxyError> {
+        let mut line = String::new();
+        try!(BufReader::new(try!(stream.try_clone())).read_line(&mut line));
+        let mut stream = stream;
+        if !line.starts_with("GET ") {
+            try!(stream.write_all(b"ERROR invalid request\n"));
+            return Ok(());
+        }
+        let path = PathBuf::from(line[4..].trim_right());
+        match self.get_cached(&path) {
+            Ok(cached) => {
+                let mut file = try!(File::open(&cached));
+                let size = try!(file.metadata()).len();
+                try!(stream.write_all(format!("OK {}\n", size).as_bytes()));
+                try!(io::copy(&mut file, &mut stream));
+            }
+            Err(err) => {
+                warn!("Failed to serve bundle {:?}\n\tcaused by: {}", path, err);
+                let msg = err.to_string().replace('\n', " ");
+                try!(stream.write_all(format!("ERROR {}\n", msg).as_bytes()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Serves bundles on the given address until the process is terminated
+    pub fn serve(self, addr: &str) -> Result<(), ProxyError> {
+        let addr = normalize_address(addr);
+        let listener = try!(TcpListener::bind(&addr as &str));
+        info!("Serving bundles on {}", addr);
+        let proxy = Arc::new(self);
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("Failed to accept connection\n\tcaused by: {}", err);
+                    continue;
+                }
+            };
+            let proxy = proxy.clone();
+            thread::spawn(move || {
+                let peer = stream.peer_addr();
+                if let Err(err) = proxy.handle(stream) {
+                    warn!("Failed to serve {:?}\n\tcaused by: {}", peer, err);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
@@ -5,8 +5,10 @@ use std::path::{Path, PathBuf};
 use std::fs::{self, File};
 use std::io::{self, Read, Seek, SeekFrom, BufReader};
 use std::cmp::max;
+use std::borrow::Cow;
 use std::fmt::{self, Debug};
 use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
 
 
 quick_error!{
@@ -48,6 +50,14 @@ quick_error!{
             description("Decryption failed")
             display("Bundle reader error: decryption failed on bundle {:?}\n\tcaused by: {}", path, err)
         }
+        MissingDictionary(bundle: BundleId, dictionary: Hash) {
+            description("Dictionary is missing")
+            display("Bundle reader error: bundle {:?} needs the missing dictionary {}", bundle, dictionary)
+        }
+        Malformed(path: PathBuf, reason: &'static str) {
+            description("Bundle is malformed")
+            display("Bundle reader error: bundle {:?} is malformed: {}", path, reason)
+        }
         Integrity(bundle: BundleId, reason: &'static str) {
             description("Bundle has an integrity error")
             display("Bundle reader error: bundle {:?} has an integrity error: {}", bundle, reason)
@@ -63,7 +73,10 @@ pub struct BundleReader {
     crypto: Arc<Mutex<Crypto>>,
     pub content_start: usize,
     pub chunks: Option<ChunkList>,
-    pub chunk_positions: Option<Vec<usize>>
+    pub chunk_positions: Option<Vec<usize>>,
+    stored_size: usize,
+    delta_chunks: HashMap<usize, (usize, usize)>,
+    dictionary: Option<Arc<Vec<u8>>>
 }
 
 impl BundleReader {
@@ -81,7 +94,10 @@ impl BundleReader {
             path: path,
             crypto: crypto,
             content_start: content_start,
-            chunk_positions: None
+            chunk_positions: None,
+            stored_size: 0,
+            delta_chunks: HashMap::new(),
+            dictionary: None
         }
     }
 
@@ -90,27 +106,53 @@ impl BundleReader {
         self.info.id.clone()
     }
 
-    fn load_header<P: AsRef<Path>>(
-        path: P,
-        crypto: Arc<Mutex<Crypto>>,
-    ) -> Result<(BundleInfo, u8, usize), BundleReaderError> {
-        let path = path.as_ref();
-        let mut file = BufReader::new(try!(File::open(path).context(path)));
+    /// Sets the dictionary referenced in the bundle info, it is needed to decode the contents
+    #[inline]
+    pub fn set_dictionary(&mut self, dictionary: Arc<Vec<u8>>) {
+        self.dictionary = Some(dictionary);
+    }
+
+    /// Reads the unencrypted part of the header, returns it with the version, the position of the
+    /// bundle info and the file size
+    fn read_header(
+        file: &mut BufReader<File>,
+        path: &Path,
+    ) -> Result<(BundleHeader, u8, usize, usize), BundleReaderError> {
         let mut header = [0u8; 8];
         try!(file.read_exact(&mut header).context(path));
         if header[..HEADER_STRING.len()] != HEADER_STRING {
             return Err(BundleReaderError::WrongHeader(path.to_path_buf()));
         }
         let version = header[HEADER_STRING.len()];
-        if version != HEADER_VERSION {
+        if version != HEADER_VERSION && version != DELTA_HEADER_VERSION {
             return Err(BundleReaderError::UnsupportedVersion(
                 path.to_path_buf(),
                 version
             ));
         }
-        let header: BundleHeader = try!(msgpack::decode_from_stream(&mut file).context(path));
-        let mut info_data = Vec::with_capacity(header.info_size);
-        info_data.resize(header.info_size, 0);
+        let file_size = try!(file.get_ref().metadata().context(path)).len() as usize;
+        let header: BundleHeader = try!(
+            msgpack::decode_from_stream_limited(&mut *file, msgpack::MAX_HEADER_SIZE).context(path)
+        );
+        let pos = try!(file.seek(SeekFrom::Current(0)).context(path)) as usize;
+        // Checking the sizes before allocating anything as they might be garbage
+        if header.info_size > MAX_INFO_SIZE || header.info_size > file_size - pos {
+            return Err(BundleReaderError::Malformed(
+                path.to_path_buf(),
+                "bundle info is larger than the file"
+            ));
+        }
+        Ok((header, version, pos, file_size))
+    }
+
+    fn load_header<P: AsRef<Path>>(
+        path: P,
+        crypto: Arc<Mutex<Crypto>>,
+    ) -> Result<(BundleInfo, u8, usize), BundleReaderError> {
+        let path = path.as_ref();
+        let mut file = BufReader::new(try!(File::open(path).context(path)));
+        let (header, version, pos, file_size) = try!(Self::read_header(&mut file, path));
+        let mut info_data = vec![0; header.info_size];
         try!(file.read_exact(&mut info_data).context(path));
         if let Some(ref encryption) = header.encryption {
             info_data = try!(
@@ -124,11 +166,37 @@ impl BundleReader {
         let mut info: BundleInfo = try!(msgpack::decode(&info_data).context(path));
         info.encryption = header.encryption;
         debug!("Load bundle {}", info.id);
-        let content_start = file.seek(SeekFrom::Current(0)).unwrap() as usize +
-            info.chunk_list_size;
+        let pos = pos + header.info_size;
+        if info.chunk_list_size > file_size - pos {
+            return Err(BundleReaderError::Malformed(
+                path.to_path_buf(),
+                "chunk list is larger than the file"
+            ));
+        }
+        let content_start = pos + info.chunk_list_size;
         Ok((info, version, content_start))
     }
 
+    /// Checks the parts of the bundle that can be checked without decrypting it
+    ///
+    /// The header has to be valid and the size of the file has to match the sizes in `info`,
+    /// which is all that can be checked on encrypted bundles without the secret key.
+    pub fn check_structure<P: AsRef<Path>>(
+        path: P,
+        info: &BundleInfo,
+    ) -> Result<(), BundleReaderError> {
+        let path = path.as_ref();
+        let mut file = BufReader::new(try!(File::open(path).context(path)));
+        let (header, _version, pos, file_size) = try!(Self::read_header(&mut file, path));
+        if file_size != pos + header.info_size + info.chunk_list_size + info.encoded_size {
+            return Err(BundleReaderError::Integrity(
+                info.id.clone(),
+                "File size does not match size in header, truncated file"
+            ));
+        }
+        Ok(())
+    }
+
     #[inline]
     pub fn load_info<P: AsRef<Path>>(
         path: P,
@@ -157,12 +225,19 @@ impl BundleReader {
         );
         let mut file = BufReader::new(try!(File::open(&self.path).context(&self.path as &Path)));
         let len = self.info.chunk_list_size;
-        let start = self.content_start - len;
+        let start = match self.content_start.checked_sub(len) {
+            Some(start) => start,
+            None => {
+                return Err(BundleReaderError::Malformed(
+                    self.path.clone(),
+                    "chunk list is larger than the file"
+                ))
+            }
+        };
         try!(file.seek(SeekFrom::Start(start as u64)).context(
             &self.path as &Path
         ));
-        let mut chunk_data = Vec::with_capacity(len);
-        chunk_data.resize(self.info.chunk_list_size, 0);
+        let mut chunk_data = vec![0; len];
         try!(file.read_exact(&mut chunk_data).context(
             &self.path as &Path
         ));
@@ -176,12 +251,22 @@ impl BundleReader {
             );
         }
         let chunks = ChunkList::read_from(&chunk_data);
+        self.delta_chunks = self.info
+            .delta_chunks
+            .iter()
+            .map(|&(id, base, len)| (id as usize, (base as usize, len as usize)))
+            .collect();
         let mut chunk_positions = Vec::with_capacity(chunks.len());
         let mut pos = 0;
-        for &(_, len) in (&chunks).iter() {
+        for (id, &(_, len)) in (&chunks).iter().enumerate() {
             chunk_positions.push(pos);
-            pos += len as usize;
+            // Delta encoded chunks take up the size of the delta in the contents
+            pos += match self.delta_chunks.get(&id) {
+                Some(&(_, len)) => len,
+                None => len as usize,
+            };
         }
+        self.stored_size = pos;
         self.chunks = Some(chunks);
         self.chunk_positions = Some(chunk_positions);
         Ok(())
@@ -218,13 +303,25 @@ impl BundleReader {
             );
         }
         if let Some(ref compression) = self.info.compression {
-            let mut stream = try!(compression.decompress_stream().context(&self.path as &Path));
-            let mut buffer = Vec::with_capacity(self.info.raw_size);
-            try!(stream.process(&data, &mut buffer).context(
-                &self.path as &Path
-            ));
-            try!(stream.finish(&mut buffer).context(&self.path as &Path));
-            data = buffer;
+            if let Some(id) = self.info.dictionary {
+                let dictionary = match self.dictionary {
+                    Some(ref dictionary) => dictionary,
+                    None => return Err(BundleReaderError::MissingDictionary(self.id(), id)),
+                };
+                data = try!(
+                    compression
+                        .decompress_with_dictionary(&data, dictionary)
+                        .context(&self.path as &Path)
+                );
+            } else {
+                let mut stream = try!(compression.decompress_stream().context(&self.path as &Path));
+                let mut buffer = Vec::with_capacity(self.info.raw_size);
+                try!(stream.process(&data, &mut buffer).context(
+                    &self.path as &Path
+                ));
+                try!(stream.finish(&mut buffer).context(&self.path as &Path));
+                data = buffer;
+            }
         }
         Ok(data)
     }
@@ -244,10 +341,60 @@ impl BundleReader {
             try!(self.load_chunklist());
         }
         let pos = self.chunk_positions.as_ref().unwrap()[id];
-        let len = self.chunks.as_ref().unwrap()[id].1 as usize;
+        let len = match self.delta_chunks.get(&id) {
+            Some(&(_, len)) => len,
+            None => self.chunks.as_ref().unwrap()[id].1 as usize,
+        };
         Ok((pos, len))
     }
 
+    /// Returns whether the chunks are stored verbatim in the bundle file
+    ///
+    /// This is the case if the bundle is neither compressed nor encrypted and the chunk is not
+    /// delta encoded. The chunk is then located at `content_start` plus its position. The chunk
+    /// list must already be loaded, e.g. by `get_chunk_position`.
+    pub fn is_stored_verbatim(&self, id: usize) -> bool {
+        self.info.compression.is_none() && self.info.encryption.is_none() &&
+            !self.delta_chunks.contains_key(&id)
+    }
+
+    /// Extracts the chunk from the decoded contents, resolving delta encoded chunks
+    #[inline]
+    pub fn extract_chunk(&mut self, data: &[u8], id: usize) -> Result<Vec<u8>, BundleReaderError> {
+        self.get_chunk_slice(data, id).map(|chunk| chunk.into_owned())
+    }
+
+    /// Like `extract_chunk` but only delta encoded chunks are copied
+    pub fn get_chunk_slice<'a>(
+        &mut self,
+        data: &'a [u8],
+        id: usize,
+    ) -> Result<Cow<'a, [u8]>, BundleReaderError> {
+        let (pos, len) = try!(self.get_chunk_position(id));
+        if pos + len > data.len() {
+            return Err(BundleReaderError::Integrity(self.id(), "Chunk exceeds bundle contents"));
+        }
+        let base = match self.delta_chunks.get(&id) {
+            Some(&(base, _)) => base,
+            None => return Ok(Cow::Borrowed(&data[pos..pos + len])),
+        };
+        if self.delta_chunks.contains_key(&base) {
+            return Err(BundleReaderError::Integrity(self.id(), "Delta base is a delta itself"));
+        }
+        let (base_pos, base_len) = try!(self.get_chunk_position(base));
+        if base_pos + base_len > data.len() {
+            return Err(BundleReaderError::Integrity(self.id(), "Chunk exceeds bundle contents"));
+        }
+        let chunk = try!(
+            decode_delta(&data[base_pos..base_pos + base_len], &data[pos..pos + len])
+                .map_err(|_| BundleReaderError::Integrity(self.id(), "Invalid delta"))
+        );
+        if chunk.len() != self.chunks.as_ref().unwrap()[id].1 as usize {
+            return Err(BundleReaderError::Integrity(self.id(), "Delta has wrong size"));
+        }
+        Ok(Cow::Owned(chunk))
+    }
+
     pub fn check(&mut self, full: bool) -> Result<(), BundleReaderError> {
         if self.chunks.is_none() || self.chunk_positions.is_none() {
             try!(self.load_chunklist());
@@ -270,6 +417,16 @@ impl BundleReader {
                 "Individual chunk sizes do not add up to total size"
             ));
         }
+        for &(id, base, _) in &self.info.delta_chunks {
+            if id as usize >= self.info.chunk_count || base >= id ||
+                self.delta_chunks.contains_key(&(base as usize))
+            {
+                return Err(BundleReaderError::Integrity(
+                    self.id(),
+                    "Invalid delta chunk reference"
+                ));
+            }
+        }
         if !full {
             let size = try!(fs::metadata(&self.path).context(&self.path as &Path)).len();
             if size as usize != self.info.encoded_size + self.content_start {
@@ -288,12 +445,16 @@ impl BundleReader {
             ));
         }
         let contents = try!(self.decode_contents(encoded_contents));
-        if self.info.raw_size != contents.len() {
+        if self.stored_size != contents.len() {
             return Err(BundleReaderError::Integrity(
                 self.id(),
                 "Raw data size does not match size in header, truncated bundle"
             ));
         }
+        let delta_ids: Vec<usize> = self.delta_chunks.keys().cloned().collect();
+        for id in delta_ids {
+            try!(self.extract_chunk(&contents, id));
+        }
         //TODO: verify checksum
         Ok(())
     }
@@ -0,0 +1,202 @@
+use prelude::*;
+use super::*;
+
+use std::path::Path;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, BufReader};
+use std::cmp::min;
+use std::collections::HashMap;
+
+
+/// Amount of encoded contents that is decompressed at once
+const BLOCK_SIZE: usize = 64 * 1024;
+
+
+enum StreamSource {
+    /// Contents that are neither compressed nor encrypted, chunks are read directly
+    Raw(File),
+    /// Compressed contents that are decompressed while reading, `None` when finished
+    Compressed(BufReader<File>, Option<CompressionStream>),
+    /// Contents that can only be decoded as a whole, i.e. encrypted ones or ones compressed with
+    /// a dictionary
+    Decoded(Vec<u8>)
+}
+
+
+/// Reads the chunks of a bundle one by one without keeping its decoded contents in memory
+///
+/// Compressed contents are decompressed while reading and only the requested chunks are kept,
+/// so reading the chunks in the order of their positions decodes the bundle only once. Reading
+/// a chunk before the current position starts over from the beginning. Encrypted bundles and
+/// bundles compressed with a dictionary still have to be decoded as a whole.
+pub struct ChunkStream {
+    bundle: BundleReader,
+    source: StreamSource,
+    // Decoded contents starting at position `pos` that have not been consumed yet
+    buffer: Vec<u8>,
+    pos: usize,
+    // Chunks that delta encoded chunks are based on, kept as they are passed
+    bases: HashMap<usize, Vec<u8>>
+}
+
+impl ChunkStream {
+    pub fn new(mut bundle: BundleReader) -> Result<Self, BundleReaderError> {
+        // Loads the chunk list so that the positions are known
+        try!(bundle.get_chunk_list());
+        let source = try!(Self::open(&bundle));
+        Ok(ChunkStream {
+            bundle: bundle,
+            source: source,
+            buffer: vec![],
+            pos: 0,
+            bases: HashMap::new()
+        })
+    }
+
+    /// Creates a stream from the decoded contents, e.g. of a bundle fetched via a proxy
+    pub fn with_contents(
+        mut bundle: BundleReader,
+        data: Vec<u8>,
+    ) -> Result<Self, BundleReaderError> {
+        try!(bundle.get_chunk_list());
+        Ok(ChunkStream {
+            bundle: bundle,
+            source: StreamSource::Decoded(data),
+            buffer: vec![],
+            pos: 0,
+            bases: HashMap::new()
+        })
+    }
+
+    fn open(bundle: &BundleReader) -> Result<StreamSource, BundleReaderError> {
+        let compression = match bundle.info.compression {
+            _ if bundle.info.encryption.is_some() || bundle.info.dictionary.is_some() => {
+                return Ok(StreamSource::Decoded(try!(bundle.load_contents())));
+            }
+            Some(ref compression) => compression,
+            None => {
+                let file = try!(File::open(&bundle.path).context(&bundle.path as &Path));
+                return Ok(StreamSource::Raw(file));
+            }
+        };
+        let file = try!(File::open(&bundle.path).context(&bundle.path as &Path));
+        let mut file = BufReader::new(file);
+        try!(
+            file.seek(SeekFrom::Start(bundle.content_start as u64))
+                .context(&bundle.path as &Path)
+        );
+        let stream = try!(compression.decompress_stream().context(&bundle.path as &Path));
+        Ok(StreamSource::Compressed(file, Some(stream)))
+    }
+
+    #[inline]
+    pub fn id(&self) -> BundleId {
+        self.bundle.id()
+    }
+
+    #[inline]
+    pub fn info(&self) -> &BundleInfo {
+        &self.bundle.info
+    }
+
+    /// Decodes the contents until the buffer reaches position `end`
+    ///
+    /// Decoded contents before position `start` are dropped on the way.
+    fn fill(&mut self, start: usize, end: usize) -> Result<(), BundleReaderError> {
+        let mut block = vec![0; BLOCK_SIZE];
+        while self.pos + self.buffer.len() < end {
+            match self.source {
+                StreamSource::Compressed(ref mut file, ref mut stream) => {
+                    let size = try!(file.read(&mut block).context(&self.bundle.path as &Path));
+                    if size > 0 {
+                        if let Some(ref mut stream) = *stream {
+                            try!(stream.process(&block[..size], &mut self.buffer).context(
+                                &self.bundle.path as &Path
+                            ));
+                        }
+                    } else if let Some(stream) = stream.take() {
+                        try!(stream.finish(&mut self.buffer).context(&self.bundle.path as &Path));
+                    } else {
+                        return Err(BundleReaderError::Integrity(
+                            self.bundle.id(),
+                            "Chunk exceeds bundle contents"
+                        ));
+                    }
+                }
+                _ => unreachable!(),
+            }
+            if self.pos < start {
+                let skip = min(start - self.pos, self.buffer.len());
+                self.buffer.drain(..skip);
+                self.pos += skip;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the stored data of the chunk, i.e. the delta for delta encoded chunks
+    fn read_stored(&mut self, id: usize) -> Result<Vec<u8>, BundleReaderError> {
+        let (start, len) = try!(self.bundle.get_chunk_position(id));
+        if let StreamSource::Raw(ref mut file) = self.source {
+            let mut data = vec![0; len];
+            let offset = (self.bundle.content_start + start) as u64;
+            try!(file.seek(SeekFrom::Start(offset)).context(&self.bundle.path as &Path));
+            try!(file.read_exact(&mut data).context(&self.bundle.path as &Path));
+            return Ok(data);
+        }
+        if start < self.pos {
+            self.source = try!(Self::open(&self.bundle));
+            self.buffer.clear();
+            self.pos = 0;
+        }
+        try!(self.fill(start, start + len));
+        let offset = start - self.pos;
+        let data = self.buffer[offset..offset + len].to_vec();
+        self.buffer.drain(..offset + len);
+        self.pos = start + len;
+        Ok(data)
+    }
+
+    /// Returns the data of the chunk, resolving delta encoded chunks
+    pub fn read_chunk(&mut self, id: usize) -> Result<Vec<u8>, BundleReaderError> {
+        if let StreamSource::Decoded(ref data) = self.source {
+            return self.bundle.extract_chunk(data, id);
+        }
+        let bundle_id = self.bundle.id();
+        let size = match try!(self.bundle.get_chunk_list()).get(id) {
+            Some(&(_, size)) => size as usize,
+            None => return Err(BundleReaderError::NoSuchChunk(bundle_id, id)),
+        };
+        let (base, is_base) = {
+            let deltas = &self.bundle.info.delta_chunks;
+            (
+                deltas.iter().find(|d| d.0 as usize == id).map(|d| d.1 as usize),
+                deltas.iter().any(|d| d.1 as usize == id)
+            )
+        };
+        let base = match base {
+            Some(base) => base,
+            None => {
+                let data = try!(self.read_stored(id));
+                // Raw chunks can be read again cheaply
+                let raw = if let StreamSource::Raw(_) = self.source { true } else { false };
+                if is_base && !raw {
+                    self.bases.insert(id, data.clone());
+                }
+                return Ok(data);
+            }
+        };
+        let base_data = match self.bases.get(&base).cloned() {
+            Some(data) => data,
+            None => try!(self.read_chunk(base)),
+        };
+        let delta = try!(self.read_stored(id));
+        let chunk = try!(decode_delta(&base_data, &delta).map_err(|_| {
+            BundleReaderError::Integrity(bundle_id.clone(), "Invalid delta")
+        }));
+        if chunk.len() != size {
+            return Err(BundleReaderError::Integrity(bundle_id, "Delta has wrong size"));
+        }
+        Ok(chunk)
+    }
+}
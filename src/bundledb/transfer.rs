@@ -0,0 +1,98 @@
+use prelude::*;
+
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crossbeam::sync::MsQueue;
+
+
+/// Default number of threads that transfer bundles from the remote storage in the background
+pub const TRANSFER_THREADS: usize = 4;
+
+
+trait Job: Send {
+    fn run(self: Box<Self>);
+}
+
+impl<F: FnOnce() + Send> Job for F {
+    fn run(self: Box<Self>) {
+        (*self)()
+    }
+}
+
+
+/// A transfer running in the background, its result is returned by `wait`
+pub struct Transfer<T> {
+    result: Receiver<Result<T, BundleDbError>>
+}
+
+impl<T> Transfer<T> {
+    /// Blocks until the transfer has finished and returns its result
+    pub fn wait(self) -> Result<T, BundleDbError> {
+        match self.result.recv() {
+            Ok(result) => result,
+            Err(_) => Err(BundleDbError::TransferFailed),
+        }
+    }
+}
+
+
+/// Runs transfers from the remote storage on a pool of threads
+///
+/// This way slow remote storages do not block the caller and transfers overlap with each other
+/// and with the processing of the data. Uploads are handled by the `BundleUploader` instead as
+/// they have to be finished in order. The rest of the code uses `Transfer::wait` to get the
+/// results synchronously.
+pub struct TransferPool {
+    threads: usize,
+    queue: Arc<MsQueue<Option<Box<Job>>>>
+}
+
+impl TransferPool {
+    pub fn new(threads: usize) -> Self {
+        let queue = Arc::new(MsQueue::new());
+        for num in 0..threads {
+            let queue = queue.clone();
+            thread::Builder::new()
+                .name(format!("transfer-{}", num))
+                .spawn(move || {
+                    pin_worker(num);
+                    Self::worker_thread(&queue)
+                })
+                .unwrap();
+        }
+        TransferPool {
+            threads: threads,
+            queue: queue
+        }
+    }
+
+    fn worker_thread(queue: &MsQueue<Option<Box<Job>>>) {
+        while let Some(job) = queue.pop() {
+            job.run();
+        }
+    }
+
+    /// Starts the transfer in the background
+    pub fn spawn<T, F>(&self, transfer: F) -> Transfer<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, BundleDbError> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        self.queue.push(Some(Box::new(move || {
+            // Nobody might be waiting for the result anymore
+            let _ = sender.send(transfer());
+        })));
+        Transfer { result: receiver }
+    }
+}
+
+impl Drop for TransferPool {
+    fn drop(&mut self) {
+        for _ in 0..self.threads {
+            self.queue.push(None);
+        }
+    }
+}
@@ -3,29 +3,118 @@ use prelude::*;
 use std::sync::atomic::{Ordering, AtomicBool, AtomicUsize};
 use std::sync::{Mutex, Condvar, Arc};
 use std::{mem, fs, thread};
+use std::fs::File;
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
+use std::collections::HashSet;
 
 use crossbeam::sync::MsQueue;
 
 
+/// Number of bytes at the start of a bundle that are compared when verifying only the header
+const HEADER_CHECK_SIZE: u64 = 64 * 1024;
+
+
+/// How bundles are read back and compared after they have been written to the remote storage
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum UploadVerification {
+    /// The write is trusted
+    Off,
+    /// The size and the start of the bundle including its header are compared
+    Header,
+    /// The checksums of the whole bundles are compared
+    Full
+}
+serde_impl!(UploadVerification(u8) {
+    Off => 0,
+    Header => 1,
+    Full => 2
+});
+
+impl UploadVerification {
+    pub fn from_string(name: &str) -> Result<Self, &'static str> {
+        match name {
+            "off" => Ok(UploadVerification::Off),
+            "header" => Ok(UploadVerification::Header),
+            "full" => Ok(UploadVerification::Full),
+            _ => Err("Unsupported upload verification"),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            UploadVerification::Off => "off",
+            UploadVerification::Header => "header",
+            UploadVerification::Full => "full",
+        }
+    }
+}
+
+
+#[cfg(target_os = "linux")]
+fn drop_page_cache(file: &File) {
+    use libc;
+    use std::os::unix::io::AsRawFd;
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn drop_page_cache(_file: &File) {}
+
+/// Returns the size of the file and the checksum of its first `limit` bytes
+///
+/// The file is synced and its cached pages are dropped first, so the data is read back from
+/// the storage instead of the page cache where the platform allows it.
+fn read_back(path: &Path, limit: u64) -> Result<(u64, [u8; 32]), io::Error> {
+    let file = try!(File::open(path));
+    try!(file.sync_all());
+    drop_page_cache(&file);
+    let size = try!(file.metadata()).len();
+    let mut hasher = Sha256::new();
+    try!(io::copy(&mut file.take(limit), &mut hasher));
+    Ok((size, hasher.finish()))
+}
+
+/// Checks that the bundle at `dst_path` has been written correctly
+fn verify_copy(
+    src_path: &Path,
+    dst_path: &Path,
+    verification: UploadVerification,
+) -> Result<bool, BundleDbError> {
+    let limit = match verification {
+        UploadVerification::Off => return Ok(true),
+        UploadVerification::Header => HEADER_CHECK_SIZE,
+        UploadVerification::Full => u64::max_value(),
+    };
+    let local = try!(read_back(src_path, limit).context(src_path));
+    let remote = try!(read_back(dst_path, limit).context(dst_path));
+    Ok(local == remote)
+}
+
+
 pub struct BundleUploader {
     capacity: usize,
+    verification: UploadVerification,
     error_present: AtomicBool,
     error: Mutex<Option<BundleDbError>>,
     waiting: AtomicUsize,
-    queue: MsQueue<Option<(PathBuf, PathBuf)>>,
-    wait: (Condvar, Mutex<()>)
+    queue: MsQueue<Option<(PathBuf, PathBuf, Vec<(String, PathBuf)>)>>,
+    wait: (Condvar, Mutex<()>),
+    // Names of the additional remotes that failed to receive a bundle
+    failed_mirrors: Mutex<HashSet<String>>
 }
 
 impl BundleUploader {
-    pub fn new(capacity: usize) -> Arc<Self> {
+    pub fn new(capacity: usize, verification: UploadVerification) -> Arc<Self> {
         let self_ = Arc::new(BundleUploader {
             capacity: capacity,
+            verification: verification,
             error_present: AtomicBool::new(false),
             error: Mutex::new(None),
             waiting: AtomicUsize::new(0),
             queue: MsQueue::new(),
-            wait: (Condvar::new(), Mutex::new(()))
+            wait: (Condvar::new(), Mutex::new(())),
+            failed_mirrors: Mutex::new(HashSet::new())
         });
         let self2 = self_.clone();
         thread::Builder::new()
@@ -49,7 +138,16 @@ impl BundleUploader {
         }
     }
 
-    pub fn queue(&self, local_path: PathBuf, remote_path: PathBuf) -> Result<(), BundleDbError> {
+    /// Queues a bundle to be moved to the remote storage
+    ///
+    /// Before that, the bundle is copied to the paths in `mirrors` that belong to additional named
+    /// remotes. Failing to copy it there does not fail the upload.
+    pub fn queue(
+        &self,
+        local_path: PathBuf,
+        remote_path: PathBuf,
+        mirrors: Vec<(String, PathBuf)>,
+    ) -> Result<(), BundleDbError> {
         while self.waiting.load(Ordering::SeqCst) >= self.capacity {
             debug!("Upload queue is full, waiting for slots");
             let _ = self.wait.0.wait(self.wait.1.lock().unwrap()).unwrap();
@@ -57,7 +155,7 @@ impl BundleUploader {
         trace!("Adding to upload queue: {:?}", local_path);
         if !self.error_present.load(Ordering::SeqCst) {
             self.waiting.fetch_add(1, Ordering::SeqCst);
-            self.queue.push(Some((local_path, remote_path)));
+            self.queue.push(Some((local_path, remote_path, mirrors)));
         }
         self.get_status()
     }
@@ -73,14 +171,54 @@ impl BundleUploader {
         self.get_status()
     }
 
+    /// Returns the names of the additional remotes that failed to receive a bundle
+    pub fn failed_mirrors(&self) -> HashSet<String> {
+        self.failed_mirrors.lock().unwrap().clone()
+    }
+
+    fn copy_to_mirror(&self, src_path: &Path, dst_path: &Path) -> Result<(), BundleDbError> {
+        let folder = dst_path.parent().unwrap();
+        try!(fs::create_dir_all(&folder).context(folder as &Path));
+        try!(fs::copy(src_path, dst_path).context(dst_path));
+        if !try!(verify_copy(src_path, dst_path, self.verification)) {
+            return Err(BundleDbError::UploadMismatch(dst_path.to_path_buf()));
+        }
+        Ok(())
+    }
+
+    /// Copies the bundle to the remote storage, a copy that differs is written a second time
+    fn upload(&self, src_path: &Path, dst_path: &Path) -> Result<(), BundleDbError> {
+        let folder = dst_path.parent().unwrap();
+        try!(fs::create_dir_all(&folder).context(folder as &Path));
+        try!(fs::copy(src_path, dst_path).context(dst_path));
+        if try!(verify_copy(src_path, dst_path, self.verification)) {
+            return Ok(());
+        }
+        warn!(
+            "Uploaded bundle {:?} differs from the written one, uploading it again",
+            dst_path
+        );
+        try!(fs::copy(src_path, dst_path).context(dst_path));
+        if try!(verify_copy(src_path, dst_path, self.verification)) {
+            Ok(())
+        } else {
+            Err(BundleDbError::UploadMismatch(dst_path.to_path_buf()))
+        }
+    }
+
     fn worker_thread_inner(&self) -> Result<(), BundleDbError> {
-        while let Some((src_path, dst_path)) = self.queue.pop() {
+        while let Some((src_path, dst_path, mirrors)) = self.queue.pop() {
             trace!("Uploading {:?} to {:?}", src_path, dst_path);
             self.waiting.fetch_sub(1, Ordering::SeqCst);
             self.wait.0.notify_all();
-            let folder = dst_path.parent().unwrap();
-            try!(fs::create_dir_all(&folder).context(folder as &Path));
-            try!(fs::copy(&src_path, &dst_path).context(&dst_path as &Path));
+            for (name, mirror_path) in mirrors {
+                if let Err(err) = self.copy_to_mirror(&src_path, &mirror_path) {
+                    warn!("Failed to copy bundle to remote {}\n\tcaused by: {}", name, err);
+                    self.failed_mirrors.lock().unwrap().insert(name);
+                }
+            }
+            // The local bundle is only removed once the upload is known to be intact
+            try!(self.upload(&src_path, &dst_path));
             try!(fs::remove_file(&src_path).context(&src_path as &Path));
             debug!("Uploaded {:?} to {:?}", src_path, dst_path);
         }
@@ -5,6 +5,7 @@ use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::io::{self, Write, BufWriter};
 use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
 
 use chrono::prelude::*;
 
@@ -28,6 +29,12 @@ quick_error!{
             description("Encryption failed")
             display("Bundle writer error: failed to encrypt data\n\tcaused by: {}", err)
         }
+        Helper(err: CodecHelperError) {
+            from()
+            cause(err)
+            description("Codec helper failed")
+            display("Bundle writer error: codec helper failed\n\tcaused by: {}", err)
+        }
         Encode(err: msgpack::EncodeError, path: PathBuf) {
             cause(err)
             context(path: &'a Path, err: msgpack::EncodeError) -> (err, path.to_path_buf())
@@ -44,17 +51,48 @@ quick_error!{
 }
 
 
+/// Chunks smaller than this are never delta encoded
+const MIN_DELTA_CHUNK_SIZE: usize = 512;
+
+
+/// Encrypts the data, in a codec helper if there are any
+fn encrypt(
+    crypto: &Mutex<Crypto>,
+    helpers: Option<&CodecHelpers>,
+    encryption: &Encryption,
+    data: &[u8],
+) -> Result<Vec<u8>, BundleWriterError> {
+    match helpers {
+        Some(helpers) => Ok(try!(helpers.encode(None, None, Some(encryption), data))),
+        None => Ok(try!(crypto.lock().unwrap().encrypt(encryption, data))),
+    }
+}
+
+
 pub struct BundleWriter {
     mode: BundleMode,
     hash_method: HashMethod,
     data: Vec<u8>,
     compression: Option<Compression>,
     compression_stream: Option<CompressionStream>,
+    dictionary: Option<Hash>,
+    dictionary_stream: Option<DictionaryCompressionStream>,
+    dictionary_data: Option<Arc<Vec<u8>>>,
     encryption: Option<Encryption>,
     crypto: Arc<Mutex<Crypto>>,
+    // With codec helpers, the data is collected uncompressed and encoded by them when finishing
+    helpers: Option<Arc<CodecHelpers>>,
     raw_size: usize,
     chunk_count: usize,
-    chunks: ChunkList
+    chunks: ChunkList,
+    delta: bool,
+    delta_chunks: Vec<(u32, u32, u32)>,
+    delta_bases: HashMap<(usize, u64), usize>,
+    delta_base_data: HashMap<usize, Vec<u8>>,
+    host: Option<String>,
+    backup: Option<String>,
+    storage_class: StorageClass,
+    keyed_hash: bool
 }
 
 impl BundleWriter {
@@ -64,12 +102,39 @@ impl BundleWriter {
         compression: Option<Compression>,
         encryption: Option<Encryption>,
         crypto: Arc<Mutex<Crypto>>,
+        delta: bool,
+        dictionary: Option<&Dictionary>,
+        helpers: Option<Arc<CodecHelpers>>,
     ) -> Result<Self, BundleWriterError> {
-        let compression_stream = match compression {
-            Some(ref compression) => Some(try!(compression.compress_stream().map_err(
-                BundleWriterError::CompressionSetup
-            ))),
-            None => None,
+        let dictionary = match compression {
+            Some(ref compression) if compression.supports_dictionary() => dictionary,
+            _ => None,
+        };
+        let streamed = if helpers.is_none() {
+            compression.as_ref()
+        } else {
+            None
+        };
+        let (compression_stream, dictionary_stream) = match (streamed, dictionary) {
+            (Some(compression), Some(dictionary)) => {
+                (
+                    None,
+                    Some(try!(
+                        compression
+                            .compress_stream_with_dictionary(&dictionary.data)
+                            .map_err(BundleWriterError::CompressionSetup)
+                    ))
+                )
+            }
+            (Some(compression), None) => {
+                (
+                    Some(try!(compression.compress_stream().map_err(
+                        BundleWriterError::CompressionSetup
+                    ))),
+                    None
+                )
+            }
+            (None, _) => (None, None),
         };
         Ok(BundleWriter {
             mode: mode,
@@ -77,35 +142,121 @@ impl BundleWriter {
             data: vec![],
             compression: compression,
             compression_stream: compression_stream,
+            dictionary: dictionary.map(|d| d.id),
+            dictionary_stream: dictionary_stream,
+            dictionary_data: dictionary.map(|d| d.data.clone()),
             encryption: encryption,
             crypto: crypto,
+            helpers: helpers,
             raw_size: 0,
             chunk_count: 0,
-            chunks: ChunkList::new()
+            chunks: ChunkList::new(),
+            delta: delta,
+            delta_chunks: vec![],
+            delta_bases: HashMap::new(),
+            delta_base_data: HashMap::new(),
+            host: None,
+            backup: None,
+            storage_class: StorageClass::Standard,
+            keyed_hash: false
         })
     }
 
+    /// Records the host and the backup that the bundle is created for in its info
+    #[inline]
+    pub fn set_origin(&mut self, host: Option<String>, backup: Option<String>) {
+        self.host = host;
+        self.backup = backup;
+    }
+
+    /// Records the storage tier that the bundle should be kept in in its info
+    #[inline]
+    pub fn set_storage_class(&mut self, class: StorageClass) {
+        self.storage_class = class;
+    }
+
+    /// Records in its info that the chunk hashes have been computed with the key of the repository
+    #[inline]
+    pub fn set_keyed_hash(&mut self, keyed: bool) {
+        self.keyed_hash = keyed;
+    }
+
+    /// Tries to encode the chunk as a delta to a similar chunk in this bundle
+    ///
+    /// Only chunks that are stored literally can be used as bases, so deltas are never chained.
+    fn find_delta(&mut self, chunk: &[u8]) -> Option<(usize, Vec<u8>)> {
+        if !self.delta || chunk.len() < MIN_DELTA_CHUNK_SIZE {
+            return None;
+        }
+        let features = similarity_features(chunk);
+        let mut result = None;
+        for (i, feature) in features.iter().enumerate() {
+            if let Some(&base) = self.delta_bases.get(&(i, *feature)) {
+                let delta = encode_delta(&self.delta_base_data[&base], chunk);
+                if delta.len() < chunk.len() / 2 {
+                    result = Some((base, delta));
+                    break;
+                }
+            }
+        }
+        if result.is_none() {
+            let id = self.chunk_count;
+            for (i, feature) in features.iter().enumerate() {
+                self.delta_bases.entry((i, *feature)).or_insert(id);
+            }
+            self.delta_base_data.insert(id, chunk.to_vec());
+        }
+        result
+    }
+
     pub fn add(&mut self, chunk: &[u8], hash: Hash) -> Result<usize, BundleWriterError> {
-        if let Some(ref mut stream) = self.compression_stream {
-            try!(stream.process(chunk, &mut self.data).map_err(
+        let id = self.chunk_count;
+        let delta = self.find_delta(chunk);
+        let stored = match delta {
+            Some((base, ref delta)) => {
+                self.delta_chunks.push((id as u32, base as u32, delta.len() as u32));
+                &delta[..]
+            }
+            None => chunk,
+        };
+        if let Some(ref mut stream) = self.dictionary_stream {
+            try!(stream.process(stored, &mut self.data).map_err(
+                BundleWriterError::Compression
+            ))
+        } else if let Some(ref mut stream) = self.compression_stream {
+            try!(stream.process(stored, &mut self.data).map_err(
                 BundleWriterError::Compression
             ))
         } else {
-            self.data.extend_from_slice(chunk)
+            self.data.extend_from_slice(stored)
         }
         self.raw_size += chunk.len();
         self.chunk_count += 1;
         self.chunks.push((hash, chunk.len() as u32));
-        Ok(self.chunk_count - 1)
+        Ok(id)
     }
 
     pub fn finish(mut self, db: &BundleDb) -> Result<StoredBundle, BundleWriterError> {
+        if let Some(stream) = self.dictionary_stream {
+            try!(stream.finish(&mut self.data).map_err(
+                BundleWriterError::Compression
+            ))
+        }
         if let Some(stream) = self.compression_stream {
             try!(stream.finish(&mut self.data).map_err(
                 BundleWriterError::Compression
             ))
         }
-        if let Some(ref encryption) = self.encryption {
+        let shared_helpers = self.helpers.take();
+        let helpers = shared_helpers.as_ref().map(|helpers| &**helpers);
+        if let Some(helpers) = helpers {
+            self.data = try!(helpers.encode(
+                self.compression.as_ref(),
+                self.dictionary_data.as_ref().map(|d| &d[..]),
+                self.encryption.as_ref(),
+                &self.data
+            ));
+        } else if let Some(ref encryption) = self.encryption {
             self.data = try!(self.crypto.lock().unwrap().encrypt(encryption, &self.data));
         }
         let encoded_size = self.data.len();
@@ -113,12 +264,17 @@ impl BundleWriter {
         self.chunks.write_to(&mut chunk_data).unwrap();
         let id = BundleId(self.hash_method.hash(&chunk_data));
         if let Some(ref encryption) = self.encryption {
-            chunk_data = try!(self.crypto.lock().unwrap().encrypt(encryption, &chunk_data));
+            chunk_data = try!(encrypt(&self.crypto, helpers, encryption, &chunk_data));
         }
         let mut path = db.layout.temp_bundle_path();
         let mut file = BufWriter::new(try!(File::create(&path).context(&path as &Path)));
         try!(file.write_all(&HEADER_STRING).context(&path as &Path));
-        try!(file.write_all(&[HEADER_VERSION]).context(&path as &Path));
+        let version = if self.delta_chunks.is_empty() {
+            HEADER_VERSION
+        } else {
+            DELTA_HEADER_VERSION
+        };
+        try!(file.write_all(&[version]).context(&path as &Path));
         let info = BundleInfo {
             mode: self.mode,
             hash_method: self.hash_method,
@@ -129,11 +285,17 @@ impl BundleWriter {
             raw_size: self.raw_size,
             encoded_size: encoded_size,
             chunk_list_size: chunk_data.len(),
-            timestamp: Local::now().timestamp()
+            timestamp: Local::now().timestamp(),
+            delta_chunks: self.delta_chunks,
+            dictionary: self.dictionary,
+            host: self.host,
+            backup: self.backup,
+            storage_class: self.storage_class,
+            keyed_hash: self.keyed_hash
         };
         let mut info_data = try!(msgpack::encode(&info).context(&path as &Path));
         if let Some(ref encryption) = self.encryption {
-            info_data = try!(self.crypto.lock().unwrap().encrypt(encryption, &info_data));
+            info_data = try!(encrypt(&self.crypto, helpers, encryption, &info_data));
         }
         let header = BundleHeader {
             encryption: self.encryption,
@@ -159,6 +321,12 @@ impl BundleWriter {
         self.raw_size
     }
 
+    /// The encoded size so far, with codec helpers this is the raw size until finishing
+    #[inline]
+    pub fn encoded_size(&self) -> usize {
+        self.data.len()
+    }
+
     #[inline]
     pub fn estimate_final_size(&self) -> usize {
         self.data.len() + self.chunk_count * 20 + 500
@@ -40,6 +40,62 @@ fn chunk(data: &[u8], mut chunker: Box<Chunker>, sink: &mut ChunkSink) {
     sink.end_chunk();
 }
 
+/// Stores the data in a temporary repository, reads it back and removes it again via vacuum
+///
+/// Unlike the steps above, this runs the code of the repository including its index and bundle
+/// files, which are kept on the memory filesystem if there is one.
+#[cfg(feature = "memory")]
+fn repository_roundtrip(data: &[u8], config: Config) {
+    println!("Storing the data in a temporary repository (without encryption) ...");
+    let mut repo = Repository::create_temporary(config).unwrap();
+    let mut chunks = ChunkList::new();
+    let write_time = Duration::span(|| {
+        chunks = repo.put_data(BundleMode::Data, data).unwrap();
+        repo.flush().unwrap();
+    }).num_milliseconds() as f32 / 1_000.0;
+    println!(
+        "- {}, {}",
+        to_duration(write_time),
+        to_speed(data.len() as u64, write_time)
+    );
+    let info = repo.info();
+    println!(
+        "- {} bundles, {}",
+        info.bundle_count,
+        to_file_size(info.encoded_data_size)
+    );
+
+    println!();
+
+    println!("Reading the data from the repository ...");
+    let mut restored = vec![];
+    let read_time = Duration::span(|| { restored = repo.get_data(&chunks).unwrap(); })
+        .num_milliseconds() as f32 / 1_000.0;
+    assert!(restored == data, "Data read from the repository differs");
+    println!(
+        "- {}, {}",
+        to_duration(read_time),
+        to_speed(data.len() as u64, read_time)
+    );
+
+    println!();
+
+    println!("Removing the unused data via vacuum ...");
+    let vacuum_time = Duration::span(|| repo.vacuum(1.0, None, true).unwrap())
+        .num_milliseconds() as f32 / 1_000.0;
+    println!(
+        "- {}, {} bundles left",
+        to_duration(vacuum_time),
+        repo.info().bundle_count
+    );
+}
+
+/// Temporary repositories are only available with the memory feature
+#[cfg(not(feature = "memory"))]
+fn repository_roundtrip(_data: &[u8], _config: Config) {
+    println!("Skipping the repository test, zVault has been built without the memory feature");
+}
+
 #[allow(dead_code)]
 pub fn run(
     path: &str,
@@ -52,6 +108,15 @@ pub fn run(
     let mut total_write_time = 0.0;
     let mut total_read_time = 0.0;
 
+    let cpu_features = CpuFeatures::get().names();
+    if cpu_features.is_empty() {
+        println!("CPU features: none");
+    } else {
+        println!("CPU features: {}", cpu_features.join(", "));
+    }
+
+    println!();
+
     println!("Reading input file ...");
     let mut file = File::open(path).unwrap();
     let total_size = file.metadata().unwrap().len();
@@ -77,6 +142,7 @@ pub fn run(
         written: 0,
         pos: 0
     };
+    let chunker_type = chunker;
     let chunker = chunker.create();
     let chunk_time = Duration::span(|| chunk(&data, chunker, &mut chunk_sink))
         .num_milliseconds() as f32 / 1_000.0;
@@ -104,7 +170,7 @@ pub fn run(
 
     println!();
 
-    println!("Hashing chunks with {} ...", hash.name());
+    println!("Hashing chunks with {} using {} ...", hash.name(), hash.implementation());
     let mut hashes = Vec::with_capacity(chunks.len());
     let hash_time = Duration::span(|| for &(pos, len) in &chunks {
         hashes.push(hash.hash(&data[pos..pos + len]))
@@ -191,7 +257,7 @@ pub fn run(
         crypto.add_secret_key(public, secret);
         let encryption = (EncryptionMethod::Sodium, public[..].to_vec().into());
 
-        println!("Encrypting bundles...");
+        println!("Encrypting bundles using {} ...", encryption.0.implementation());
         let mut encrypted_bundles = Vec::with_capacity(bundles.len());
 
         let encrypt_time = Duration::span(|| for bundle in bundles {
@@ -219,7 +285,7 @@ pub fn run(
         total_read_time += decrypt_time;
     }
 
-    if let Some(compression) = compression {
+    if let Some(ref compression) = compression {
         println!();
 
         println!("Decompressing bundles with {} ...", compression.to_string());
@@ -257,4 +323,15 @@ pub fn run(
         "Total read speed: {}",
         to_speed(total_size, total_read_time)
     );
+
+    println!();
+
+    let config = Config {
+        compression: compression,
+        bundle_size: bundle_size,
+        chunker: chunker_type,
+        hash: hash,
+        ..Config::default()
+    };
+    repository_roundtrip(&data, config);
 }
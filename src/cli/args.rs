@@ -1,6 +1,8 @@
 use prelude::*;
 use super::*;
 
+use chrono::prelude::*;
+
 use std::path::{Path, PathBuf};
 use log::LogLevel;
 use clap::{App, AppSettings, Arg, SubCommand};
@@ -13,7 +15,11 @@ pub enum Arguments {
         compression: Option<Compression>,
         encryption: bool,
         hash: HashMethod,
-        remote_path: String
+        keyed_hash: bool,
+        delta_compression: bool,
+        remote_path: String,
+        excludes_template: Option<String>,
+        readme_template: Option<String>
     },
     Backup {
         repo_path: PathBuf,
@@ -21,25 +27,54 @@ pub enum Arguments {
         src_path: String,
         full: bool,
         reference: Option<String>,
+        reference_any: bool,
+        reference_host: Option<String>,
         same_device: bool,
+        dereference: bool,
+        dereference_args: bool,
         excludes: Vec<String>,
         excludes_from: Option<String>,
         no_default_excludes: bool,
-        tar: bool
+        tar: bool,
+        timestamp: Option<i64>,
+        list_only: bool,
+        tags: Vec<String>,
+        special_files: SpecialFiles,
+        order: BackupOrder,
+        remotes: Vec<String>,
+        stats: bool,
+        snapshot: Option<String>,
+        wait: bool,
+        scan_threads: usize,
+        include_repositories: bool,
+        storage_class: StorageClass
     },
     Restore {
         repo_path: PathBuf,
         backup_name: String,
         inode: Option<String>,
         dst_path: String,
-        tar: bool
+        tar: bool,
+        delta: bool,
+        special_files: SpecialFiles,
+        atomic: bool,
+        verify: bool,
+        link_dest: Option<PathBuf>,
+        project_ids: bool,
+        send_stream: bool,
+        send_parent: Option<String>
     },
     Remove {
         repo_path: PathBuf,
         backup_name: String,
         inode: Option<String>,
+        ignore_min_retention: bool,
         force: bool
     },
+    Undelete {
+        repo_path: PathBuf,
+        backup_name: Option<String>
+    },
     Prune {
         repo_path: PathBuf,
         prefix: String,
@@ -47,13 +82,24 @@ pub enum Arguments {
         weekly: usize,
         monthly: usize,
         yearly: usize,
+        ignore_min_retention: bool,
         force: bool
     },
+    Purge {
+        repo_path: PathBuf,
+        patterns: Vec<String>,
+        force: bool
+    },
+    Proxy {
+        repo_path: PathBuf,
+        listen: String
+    },
     Vacuum {
         repo_path: PathBuf,
         ratio: f32,
         force: bool,
-        combine: bool
+        combine: bool,
+        combine_threshold: Option<usize>
     },
     Check {
         repo_path: PathBuf,
@@ -62,14 +108,45 @@ pub enum Arguments {
         bundles: bool,
         bundle_data: bool,
         index: bool,
+        backup_files: bool,
+        fix: Vec<BackupFileProblem>,
+        sample: Option<f32>,
+        rebuild_backups: bool,
         repair: bool
     },
     List {
         repo_path: PathBuf,
         backup_name: Option<String>,
-        inode: Option<String>
+        inode: Option<String>,
+        tags: Vec<String>,
+        host: Option<String>,
+        path: Option<String>
+    },
+    Tag {
+        repo_path: PathBuf,
+        backup_name: String,
+        add: Vec<String>,
+        remove: Vec<String>
+    },
+    Remote {
+        repo_path: PathBuf,
+        add: Option<(String, String)>,
+        remove: Option<String>,
+        check: bool
     },
     Info {
+        repo_path: PathBuf,
+        backup_name: Option<String>,
+        inode: Option<String>,
+        field: Option<String>,
+        stats: bool,
+        rules: bool
+    },
+    Status {
+        repo_path: PathBuf
+    },
+    LockStatus { repo_path: PathBuf },
+    Exists {
         repo_path: PathBuf,
         backup_name: Option<String>,
         inode: Option<String>
@@ -84,9 +161,29 @@ pub enum Arguments {
         repo_path: PathBuf,
         backup_name: Option<String>,
         inode: Option<String>,
-        mount_point: String
+        mount_point: String,
+        preload_meta: bool,
+        prewarm: bool,
+        raw: bool,
+        verify: bool
     },
     Versions { repo_path: PathBuf, path: String },
+    Log {
+        repo_path: PathBuf,
+        action: Option<String>
+    },
+    Manifest {
+        repo_path: PathBuf,
+        backup_name: String,
+        inode: Option<String>
+    },
+    VerifyManifest { manifest: String, path: String },
+    Publish {
+        repo_path: PathBuf,
+        backup_name: String,
+        inode: Option<String>,
+        dst_path: String
+    },
     Diff {
         repo_path_old: PathBuf,
         backup_name_old: String,
@@ -95,24 +192,103 @@ pub enum Arguments {
         backup_name_new: String,
         inode_new: Option<String>
     },
-    Analyze { repo_path: PathBuf },
+    Analyze {
+        repo_path: PathBuf,
+        by_extension: bool,
+        by_host: bool,
+        by_origin: bool
+    },
+    AnalyzeChunking {
+        path: String,
+        chunkers: Vec<ChunkerType>,
+        hash: HashMethod
+    },
     BundleList { repo_path: PathBuf },
     BundleInfo {
         repo_path: PathBuf,
         bundle_id: BundleId
     },
+    BundleAffected {
+        repo_path: PathBuf,
+        bundle_id: BundleId
+    },
+    DebugBundleDump {
+        repo_path: PathBuf,
+        bundle_id: BundleId
+    },
+    DebugChunk {
+        repo_path: PathBuf,
+        hash: Hash
+    },
+    DebugFsckFile {
+        path: String,
+        key_files: Vec<String>
+    },
+    ExcludesEdit { repo_path: PathBuf },
+    IndexShrink { repo_path: PathBuf },
     Import {
         repo_path: PathBuf,
         remote_path: String,
         key_files: Vec<String>
     },
+    ChunkHas {
+        repo_path: PathBuf,
+        hashes: Vec<Hash>,
+        stdin: bool
+    },
+    ExportDelta {
+        repo_path: PathBuf,
+        since: String,
+        dst_path: String
+    },
+    ImportDelta {
+        repo_path: PathBuf,
+        src_path: String
+    },
+    SeedExport {
+        repo_path: PathBuf,
+        dst_path: String,
+        bwlimit: Option<u64>
+    },
+    SeedImport {
+        src_path: String,
+        remote_path: String,
+        bwlimit: Option<u64>
+    },
+    SeedFinish {
+        repo_path: PathBuf,
+        remote_path: String,
+        bwlimit: Option<u64>
+    },
+    Clone {
+        repo_path: PathBuf,
+        target_path: PathBuf,
+        remote_path: Option<String>
+    },
     Config {
         repo_path: PathBuf,
         bundle_size: Option<usize>,
         chunker: Option<ChunkerType>,
         compression: Option<Option<Compression>>,
         encryption: Option<Option<PublicKey>>,
-        hash: Option<HashMethod>
+        hash: Option<HashMethod>,
+        delta_compression: Option<bool>,
+        dictionary: Option<Option<Hash>>,
+        freshness: Vec<(String, u64)>,
+        trash_retention: Option<u64>,
+        min_retention: Option<u64>,
+        upload_verification: Option<UploadVerification>,
+        zero_chunks: Option<bool>,
+        codec_helpers: Option<usize>,
+        short_bundle_names: Option<bool>,
+        threads: Option<usize>,
+        cpus: Option<Vec<usize>>,
+        check: bool
+    },
+    TrainDictionary {
+        repo_path: PathBuf,
+        size: usize,
+        max_chunk_size: usize
     },
     GenKey {
         file: Option<String>,
@@ -124,6 +300,18 @@ pub enum Arguments {
         password: Option<String>,
         set_default: bool
     },
+    ChangePassphrase {
+        repo_path: PathBuf
+    },
+    Agent { timeout: u64, stop: bool },
+    CodecHelper,
+    SystemdUnits {
+        src_path: String,
+        backup: String,
+        name: String,
+        schedule: String,
+        output_dir: Option<String>
+    },
     AlgoTest {
         file: String,
         bundle_size: usize,
@@ -131,6 +319,15 @@ pub enum Arguments {
         compression: Option<Compression>,
         encrypt: bool,
         hash: HashMethod
+    },
+    Bench {
+        path: String,
+        bundle_size: usize,
+        chunkers: Vec<ChunkerType>,
+        compressions: Vec<Option<Compression>>,
+        hashes: Vec<HashMethod>,
+        encrypt: bool,
+        json: bool
     }
 }
 
@@ -147,20 +344,9 @@ fn convert_repo_path(mut path_str: &str) -> PathBuf {
     }
 }
 
-fn parse_repo_path(
-    repo_path: &str,
-    existing: bool,
-    backup_restr: Option<bool>,
-    path_restr: Option<bool>,
-) -> Result<(PathBuf, Option<&str>, Option<&str>), String> {
+fn split_repo_path(repo_path: &str) -> (PathBuf, Option<&str>, Option<&str>) {
     let mut parts = repo_path.splitn(3, "::");
     let repo = convert_repo_path(parts.next().unwrap_or(""));
-    if existing && !repo.join("config.yaml").exists() {
-        return Err("The specified repository does not exist".to_string());
-    }
-    if !existing && repo.exists() {
-        return Err("The specified repository already exists".to_string());
-    }
     let mut backup = parts.next();
     if let Some(val) = backup {
         if val.is_empty() {
@@ -173,6 +359,22 @@ fn parse_repo_path(
             path = None
         }
     }
+    (repo, backup, path)
+}
+
+fn parse_repo_path(
+    repo_path: &str,
+    existing: bool,
+    backup_restr: Option<bool>,
+    path_restr: Option<bool>,
+) -> Result<(PathBuf, Option<&str>, Option<&str>), String> {
+    let (repo, backup, path) = split_repo_path(repo_path);
+    if existing && !repo.join("config.yaml").exists() {
+        return Err("The specified repository does not exist".to_string());
+    }
+    if !existing && repo.exists() {
+        return Err("The specified repository already exists".to_string());
+    }
     if let Some(restr) = backup_restr {
         if !restr && backup.is_some() {
             return Err("No backup may be given here".to_string());
@@ -202,6 +404,49 @@ fn validate_repo_path(
     parse_repo_path(&repo_path, existing, backup_restr, path_restr).map(|_| ())
 }
 
+/// Expands the placeholders in the name of a new backup, see `expand_name_template`
+fn expand_backup_name(template: &str) -> Result<String, String> {
+    expand_name_template(
+        template,
+        &get_hostname().unwrap_or_default(),
+        &get_username(),
+        &Local::now()
+    )
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_backup_name(val: String) -> Result<(), String> {
+    let (_repo, backup, _inode) = try!(parse_repo_path(&val, true, Some(true), Some(false)));
+    expand_backup_name(backup.unwrap()).map(|_| ())
+}
+
+/// Finds the repository, backup and subpath behind a path inside an active mount
+#[cfg(unix)]
+fn parse_mounted_path(path: &str) -> Option<(PathBuf, String, Option<String>)> {
+    MountFile::find(&ZVAULT_FOLDER.join("mounts"), path).and_then(|(mount, rel_path)| {
+        mount.resolve(&rel_path).map(|(backup, inode)| {
+            (mount.repo_path(), backup, inode)
+        })
+    })
+}
+
+/// Backups can only be mounted on unix
+#[cfg(not(unix))]
+fn parse_mounted_path(_path: &str) -> Option<(PathBuf, String, Option<String>)> {
+    None
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_restore_source(val: String) -> Result<(), String> {
+    validate_repo_path(val.clone(), true, Some(true), None).or_else(|err| {
+        if parse_mounted_path(&val).is_some() {
+            Ok(())
+        } else {
+            Err(err)
+        }
+    })
+}
+
 fn parse_num(num: &str) -> Result<u64, String> {
     if let Ok(num) = num.parse::<u64>() {
         Ok(num)
@@ -215,6 +460,65 @@ fn validate_num(val: String) -> Result<(), String> {
     parse_num(&val).map(|_| ())
 }
 
+/// Parses a percentage like `1%` or `0.5` into a ratio
+fn parse_percentage(val: &str) -> Result<f32, String> {
+    let num = val.trim_right_matches('%');
+    match num.parse::<f32>() {
+        Ok(num) if num > 0.0 && num <= 100.0 => Ok(num / 100.0),
+        _ => Err("Must be a percentage above 0 and up to 100".to_string()),
+    }
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_percentage(val: String) -> Result<(), String> {
+    parse_percentage(&val).map(|_| ())
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_size(val: String, default_unit: u64) -> Result<(), String> {
+    parse_file_size(&val, default_unit).map(|_| ())
+}
+
+fn parse_cpus(val: &str) -> Result<Vec<usize>, String> {
+    if val == "all" {
+        return Ok(vec![]);
+    }
+    parse_cpu_list(val).map_err(|err| err.to_string())
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_cpus(val: String) -> Result<(), String> {
+    parse_cpus(&val).map(|_| ())
+}
+
+fn parse_freshness(val: &str) -> Result<(String, u64), String> {
+    let pos = try!(val.rfind('=').ok_or_else(|| {
+        "Freshness rules must have the format PATTERN=DURATION".to_string()
+    }));
+    if pos == 0 {
+        return Err("The pattern of a freshness rule must not be empty".to_string());
+    }
+    // Rules are stored in hours, durations in between are rounded up
+    let secs = try!(parse_duration(&val[pos + 1..], 3600));
+    Ok((val[..pos].to_string(), (secs + 3599) / 3600))
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_freshness(val: String) -> Result<(), String> {
+    parse_freshness(&val).map(|_| ())
+}
+
+fn parse_retention(val: &str) -> Result<u64, String> {
+    // The retention is stored in days, durations in between are rounded up
+    let secs = try!(parse_duration(val, 24 * 3600));
+    Ok((secs + 24 * 3600 - 1) / (24 * 3600))
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_retention(val: String) -> Result<(), String> {
+    parse_retention(&val).map(|_| ())
+}
+
 fn parse_chunker(val: &str) -> Result<ChunkerType, String> {
     if let Ok(chunker) = ChunkerType::from_string(val) {
         Ok(chunker)
@@ -279,6 +583,207 @@ fn validate_hash(val: String) -> Result<(), String> {
     parse_hash(&val).map(|_| ())
 }
 
+fn parse_special_files(val: &str) -> Result<SpecialFiles, String> {
+    SpecialFiles::from_string(val).map_err(|err| err.to_string())
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_special_files(val: String) -> Result<(), String> {
+    parse_special_files(&val).map(|_| ())
+}
+
+fn parse_storage_class(val: &str) -> Result<StorageClass, String> {
+    StorageClass::from_string(val).map_err(|err| err.to_string())
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_storage_class(val: String) -> Result<(), String> {
+    parse_storage_class(&val).map(|_| ())
+}
+
+fn parse_backup_order(val: &str) -> Result<BackupOrder, String> {
+    BackupOrder::from_string(val).map_err(|err| err.to_string())
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_backup_order(val: String) -> Result<(), String> {
+    parse_backup_order(&val).map(|_| ())
+}
+
+fn parse_backup_file_problem(val: &str) -> Result<BackupFileProblem, String> {
+    BackupFileProblem::from_string(val).ok_or_else(|| {
+        "Invalid problem class, expected missing-roots, duplicates or orphaned-meta".to_string()
+    })
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_backup_file_problem(val: String) -> Result<(), String> {
+    parse_backup_file_problem(&val).map(|_| ())
+}
+
+fn parse_dictionary(val: &str) -> Result<Option<Hash>, String> {
+    if val.to_lowercase() == "none" {
+        return Ok(None);
+    }
+    if val.len() != 32 {
+        return Err("Invalid dictionary id".to_string());
+    }
+    Hash::from_string(val).map(Some).map_err(
+        |_| "Invalid dictionary id".to_string()
+    )
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_dictionary(val: String) -> Result<(), String> {
+    parse_dictionary(&val).map(|_| ())
+}
+
+fn parse_bool(val: &str) -> Result<bool, String> {
+    match val {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err("Invalid boolean value".to_string()),
+    }
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_bool(val: String) -> Result<(), String> {
+    parse_bool(&val).map(|_| ())
+}
+
+fn parse_timestamp(val: &str) -> Result<i64, String> {
+    if let Ok(timestamp) = val.parse::<i64>() {
+        return Ok(timestamp);
+    }
+    if let Ok(date) = DateTime::parse_from_rfc3339(val) {
+        return Ok(date.timestamp());
+    }
+    if let Ok(date) = Local.datetime_from_str(val, "%Y-%m-%d %H:%M:%S") {
+        return Ok(date.timestamp());
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(val, "%Y-%m-%d") {
+        if let Some(date) = Local.from_local_datetime(&date.and_hms(0, 0, 0)).earliest() {
+            return Ok(date.timestamp());
+        }
+    }
+    Err("Invalid date, must be a unix timestamp, an RFC 3339 date or YYYY-MM-DD[ HH:MM:SS]".to_string())
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_timestamp(val: String) -> Result<(), String> {
+    parse_timestamp(&val).map(|_| ())
+}
+
+fn parse_tag(val: &str) -> Result<String, String> {
+    if val.is_empty() || val.contains(|c: char| c.is_whitespace() || c == ',') {
+        Err("Tags must not be empty or contain whitespace or commas".to_string())
+    } else {
+        Ok(val.to_string())
+    }
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_tag(val: String) -> Result<(), String> {
+    parse_tag(&val).map(|_| ())
+}
+
+fn parse_remote_name(val: &str) -> Result<String, String> {
+    if val.is_empty() || !val.chars().all(|c| c.is_alphanumeric() || "-_.".contains(c)) {
+        Err("Remote names must only contain letters, digits, '-', '_' and '.'".to_string())
+    } else if val == "all" {
+        Err("The remote name 'all' is reserved".to_string())
+    } else {
+        Ok(val.to_string())
+    }
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_remote_name(val: String) -> Result<(), String> {
+    parse_remote_name(&val).map(|_| ())
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_remote_target(val: String) -> Result<(), String> {
+    if val == "all" {
+        Ok(())
+    } else {
+        validate_remote_name(val)
+    }
+}
+
+fn parse_tag_change(val: &str) -> Result<(bool, String), String> {
+    if val.starts_with('+') {
+        parse_tag(&val[1..]).map(|tag| (true, tag))
+    } else if val.starts_with('-') {
+        parse_tag(&val[1..]).map(|tag| (false, tag))
+    } else {
+        Err("Tag changes must start with + or -".to_string())
+    }
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_tag_change(val: String) -> Result<(), String> {
+    parse_tag_change(&val).map(|_| ())
+}
+
+/// A configuration value given as `KEY=VALUE` to `config --set`
+enum Setting {
+    BundleSize(usize),
+    Chunker(ChunkerType),
+    Compression(Option<Compression>),
+    Encryption(Option<PublicKey>),
+    Hash(HashMethod),
+    DeltaCompression(bool),
+    Dictionary(Option<Hash>),
+    Freshness((String, u64)),
+    TrashRetention(u64),
+    MinRetention(u64),
+    UploadVerification(UploadVerification),
+    ZeroChunks(bool),
+    CodecHelpers(usize),
+    ShortBundleNames(bool),
+    Threads(usize),
+    Cpus(Vec<usize>)
+}
+
+fn parse_setting(val: &str) -> Result<Setting, String> {
+    let pos = try!(val.find('=').ok_or_else(|| {
+        "Settings must have the format KEY=VALUE".to_string()
+    }));
+    let value = &val[pos + 1..];
+    // The keys are the names used in the config file, dashes are accepted as well
+    match &val[..pos].replace('-', "_") as &str {
+        "bundle_size" => {
+            parse_file_size(value, 1024 * 1024).map(|v| Setting::BundleSize(v as usize))
+        }
+        "chunker" => parse_chunker(value).map(Setting::Chunker),
+        "compression" => parse_compression(value).map(Setting::Compression),
+        "encryption" => parse_public_key(value).map(Setting::Encryption),
+        "hash" => parse_hash(value).map(Setting::Hash),
+        "delta_compression" => parse_bool(value).map(Setting::DeltaCompression),
+        "dictionary" => parse_dictionary(value).map(Setting::Dictionary),
+        "freshness" => parse_freshness(value).map(Setting::Freshness),
+        "trash_retention" => parse_retention(value).map(Setting::TrashRetention),
+        "min_retention" => parse_retention(value).map(Setting::MinRetention),
+        "upload_verification" => {
+            UploadVerification::from_string(value)
+                .map(Setting::UploadVerification)
+                .map_err(|err| err.to_string())
+        }
+        "zero_chunks" => parse_bool(value).map(Setting::ZeroChunks),
+        "codec_helpers" => parse_num(value).map(|v| Setting::CodecHelpers(v as usize)),
+        "short_bundle_names" => parse_bool(value).map(Setting::ShortBundleNames),
+        "threads" => parse_num(value).map(|v| Setting::Threads(v as usize)),
+        "cpus" => parse_cpus(value).map(Setting::Cpus),
+        key => Err(format!("Unknown setting: {}", key)),
+    }
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_setting(val: String) -> Result<(), String> {
+    parse_setting(&val).map(|_| ())
+}
+
 fn parse_bundle_id(val: &str) -> Result<BundleId, ErrorCode> {
     if let Ok(hash) = Hash::from_string(val) {
         Ok(BundleId(hash))
@@ -288,6 +793,15 @@ fn parse_bundle_id(val: &str) -> Result<BundleId, ErrorCode> {
     }
 }
 
+fn parse_chunk_hash(val: &str) -> Result<Hash, String> {
+    Hash::from_string(val).map_err(|_| "Invalid chunk hash".to_string())
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_chunk_hash(val: String) -> Result<(), String> {
+    parse_chunk_hash(&val).map(|_| ())
+}
+
 #[allow(unknown_lints, needless_pass_by_value)]
 fn validate_existing_path(val: String) -> Result<(), String> {
     if !Path::new(&val).exists() {
@@ -298,25 +812,57 @@ fn validate_existing_path(val: String) -> Result<(), String> {
 }
 
 #[allow(unknown_lints, needless_pass_by_value)]
-fn validate_existing_path_or_stdio(val: String) -> Result<(), String> {
-    if val != "-" && !Path::new(&val).exists() {
-        Err("Path does not exist".to_string())
-    } else {
-        Ok(())
+fn validate_backup_source(val: String) -> Result<(), String> {
+    match try!(TarSource::parse(&val)) {
+        TarSource::File(ref path) if !path.exists() => Err("Path does not exist".to_string()),
+        _ => Ok(()),
     }
 }
 
 
 #[allow(unknown_lints, cyclomatic_complexity)]
-pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
+pub struct GlobalOptions {
+    pub log_level: LogLevel,
+    pub max_memory: Option<usize>,
+    pub background: bool,
+    pub proxy: Option<String>,
+    pub passcommand: Option<String>,
+    pub command: String,
+    pub si_units: bool,
+    pub pager: bool,
+    pub json_output: bool,
+    pub systemd: bool,
+    pub notify: bool,
+    pub threads: Option<usize>,
+    pub cpus: Option<Vec<usize>>
+}
+
+
+pub fn parse() -> Result<(GlobalOptions, Arguments), ErrorCode> {
     let args = App::new("zvault").version(crate_version!()).author(crate_authors!(",\n")).about(crate_description!())
         .settings(&[AppSettings::VersionlessSubcommands, AppSettings::SubcommandRequiredElseHelp])
         .global_settings(&[AppSettings::AllowMissingPositional, AppSettings::UnifiedHelpMessage, AppSettings::ColoredHelp, AppSettings::ColorAuto])
         .arg(Arg::from_usage("-v --verbose 'Print more information'").global(true).multiple(true).max_values(3).takes_value(false))
         .arg(Arg::from_usage("-q --quiet 'Print less information'").global(true).conflicts_with("verbose"))
+        .arg(Arg::from_usage("[max_memory] --max-memory [SIZE] 'Limit the memory used by the repository in MiB or with a unit'")
+            .global(true).validator(|val| validate_size(val, 1024 * 1024)))
+        .arg(Arg::from_usage("--background 'Run with idle priority and limited resources'").global(true))
+        .arg(Arg::from_usage("--proxy [ADDR] 'Fetch bundles via the bundle proxy at this address'").global(true))
+        .arg(Arg::from_usage("--passcommand [COMMAND] 'Run this shell command to get the passphrase of the keys'").global(true))
+        .arg(Arg::from_usage("--threads [NUM] 'Number of threads of each worker pool'")
+            .global(true).validator(validate_num))
+        .arg(Arg::from_usage("--cpus [LIST] 'Pin the worker threads to these CPUs, e.g. 0-3,6, or all'")
+            .global(true).validator(validate_cpus))
+        .arg(Arg::from_usage("--si 'Display sizes in units based on 1000 (kB, MB, ...)'").global(true).conflicts_with("binary"))
+        .arg(Arg::from_usage("--binary 'Display sizes in units based on 1024 (KiB, MiB, ...), the default'").global(true))
+        .arg(Arg::from_usage("[no_pager] --no-pager 'Never show long outputs in a pager'").global(true))
+        .arg(Arg::from_usage("--output [FORMAT] 'Report a failure as text or as a JSON object on stdout'")
+            .global(true).possible_values(&["text", "json"]))
+        .arg(Arg::from_usage("--systemd 'Notify systemd about the progress, prevent sleeping during backups and stop cleanly on SIGTERM'").global(true))
+        .arg(Arg::from_usage("--notify 'Show desktop notifications when a backup starts, finishes or fails'").global(true))
         .subcommand(SubCommand::with_name("init").about("Initialize a new repository")
-            .arg(Arg::from_usage("[bundle_size] --bundle-size [SIZE] 'Set the target bundle size in MiB'")
-                .default_value(DEFAULT_BUNDLE_SIZE_STR).validator(validate_num))
+            .arg(Arg::from_usage("[bundle_size] --bundle-size [SIZE] 'Set the target bundle size in MiB or with a unit, e.g. 1GiB'")
+                .default_value(DEFAULT_BUNDLE_SIZE_STR).validator(|val| validate_size(val, 1024 * 1024)))
             .arg(Arg::from_usage("--chunker [CHUNKER] 'Set the chunker algorithm and target chunk size'")
                 .default_value(DEFAULT_CHUNKER).validator(validate_chunker))
             .arg(Arg::from_usage("-c --compression [COMPRESSION] 'Set the compression method and level'")
@@ -324,33 +870,98 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
             .arg(Arg::from_usage("-e --encrypt 'Generate a keypair and enable encryption'"))
             .arg(Arg::from_usage("--hash [HASH] 'Set the hash method'")
                 .default_value(DEFAULT_HASH).validator(validate_hash))
+            .arg(Arg::from_usage("[keyed_hash] --keyed-hash 'Compute the chunk hashes with a random secret key of this repository'"))
+            .arg(Arg::from_usage("[delta_compression] --delta-compression 'Store similar chunks as deltas to each other'"))
             .arg(Arg::from_usage("-r --remote <REMOTE> 'Set the path to the mounted remote storage'")
                 .validator(validate_existing_path))
+            .arg(Arg::from_usage("[excludes_template] --excludes-template [FILE] 'Use this file as the default excludes of the repository'")
+                .validator(validate_existing_path))
+            .arg(Arg::from_usage("[readme_template] --readme-template [FILE] 'Use this file as the README in the remote storage'")
+                .validator(validate_existing_path))
             .arg(Arg::from_usage("<REPO> 'The path for the new repository'")
                 .validator(|val| validate_repo_path(val, false, Some(false), Some(false)))))
         .subcommand(SubCommand::with_name("backup").about("Create a new backup")
             .arg(Arg::from_usage("--full 'Create a full backup without using a reference'"))
             .arg(Arg::from_usage("[reference] --ref [REF] 'Base the new backup on this reference'")
                 .conflicts_with("full"))
+            .arg(Arg::from_usage("[reference_any] --reference-any 'Use the most similar backup of any host and path as reference'")
+                .conflicts_with_all(&["full", "reference"]))
+            .arg(Arg::from_usage("[reference_host] --reference-host [HOST] 'Look for the reference among the backups of this host'")
+                .conflicts_with_all(&["full", "reference", "reference_any"]))
             .arg(Arg::from_usage("[cross_device] -x --xdev 'Allow to cross filesystem boundaries'"))
+            .arg(Arg::from_usage("-L --dereference 'Follow symlinks and backup their targets'"))
+            .arg(Arg::from_usage("[dereference_args] -H --dereference-args 'Follow a symlink given as source path'"))
             .arg(Arg::from_usage("-e --exclude [PATTERN]... 'Exclude this path or file pattern'"))
             .arg(Arg::from_usage("[excludes_from] --excludes-from [FILE] 'Read the list of excludes from this file'"))
             .arg(Arg::from_usage("[no_default_excludes] --no-default-excludes 'Do not load the default excludes file'"))
             .arg(Arg::from_usage("--tar 'Read the source data from a tar file'")
-                .conflicts_with_all(&["reference", "exclude", "excludes_from"]))
-            .arg(Arg::from_usage("<SRC> 'Source path to backup'")
-                .validator(validate_existing_path_or_stdio))
-            .arg(Arg::from_usage("<BACKUP> 'Backup path, [repository]::backup'")
-                .validator(|val| validate_repo_path(val, true, Some(true), Some(false)))))
+                .conflicts_with_all(&["reference", "reference_any", "reference_host", "exclude", "excludes_from", "dereference", "dereference_args"]))
+            .arg(Arg::from_usage("--timestamp [DATE] 'Store this date as the backup time instead of the current time'")
+                .validator(validate_timestamp))
+            .arg(Arg::from_usage("[list_only] --list-only 'Only list the paths and whether they would be excluded'")
+                .conflicts_with("tar"))
+            .arg(Arg::from_usage("--stats 'Also print the time spent in each phase of the backup'"))
+            .arg(Arg::from_usage("--snapshot [NAME] 'Record that SRC is the snapshot with this name'"))
+            .arg(Arg::from_usage("--wait 'Wait for a running backup of the same source instead of failing'")
+                .conflicts_with("list_only"))
+            .arg(Arg::from_usage("[scan_threads] --scan-threads [NUM] 'Read this many directories ahead in parallel'")
+                .default_value("0").validator(validate_num))
+            .arg(Arg::from_usage("[include_repositories] --include-repositories 'Do not skip this repository and other zvault repositories in the source'")
+                .conflicts_with("tar"))
+            .arg(Arg::from_usage("--tag [TAG]... 'Add this tag to the backup'")
+                .number_of_values(1).validator(validate_tag))
+            .arg(Arg::from_usage("[special_files] --special-files [POLICY] 'Keep, skip or fail on devices, pipes and sockets'")
+                .default_value("keep").validator(validate_special_files))
+            .arg(Arg::from_usage("[storage_class] --storage-class [CLASS] 'Hint for the storage tier of the new data bundles: standard, infrequent or archive'")
+                .default_value("standard").validator(validate_storage_class))
+            .arg(Arg::from_usage("--order [ORDER] 'Back up the entries of each directory ordered by path, mtime or size'")
+                .default_value("path").validator(validate_backup_order))
+            .arg(Arg::from_usage("--to [REMOTE]... 'Also store the backup on this named remote, or on all of them'")
+                .number_of_values(1).validator(validate_remote_target))
+            .arg(Arg::from_usage("<SRC> 'Source path to backup, - or ssh://host:command with --tar'")
+                .validator(validate_backup_source))
+            .arg(Arg::from_usage("<BACKUP> 'Backup path, [repository]::backup, may contain placeholders like {hostname} or {now:%Y-%m-%d}'")
+                .validator(validate_backup_name)))
         .subcommand(SubCommand::with_name("restore").about("Restore a backup or subtree")
             .arg(Arg::from_usage("--tar 'Restore in form of a tar file'"))
+            .arg(Arg::from_usage("--delta 'Update existing files in place, only writing changed parts'")
+                .conflicts_with("tar"))
+            .arg(Arg::from_usage("--atomic 'Restore into a staging folder that is renamed to DST when finished'")
+                .conflicts_with_all(&["tar", "delta"]))
+            .arg(Arg::from_usage("[special_files] --special-files [POLICY] 'Keep, skip or fail on devices, pipes and sockets'")
+                .default_value("keep").validator(validate_special_files))
+            .arg(Arg::from_usage("[no_verify] --no-verify 'Do not compare the data of every chunk with its hash'"))
+            .arg(Arg::from_usage("[link_dest] --link-dest [DIR] 'Hardlink unchanged files from this earlier restore instead of writing them'")
+                .conflicts_with_all(&["tar", "delta"]).validator(validate_existing_path))
+            .arg(Arg::from_usage("[project_ids] --project-ids 'Set the recorded project (quota) IDs on the restored files'")
+                .conflicts_with("tar"))
+            .arg(Arg::from_usage("[send_stream] --send-stream 'Restore in form of a btrfs send stream of a read-only snapshot'")
+                .conflicts_with_all(&["tar", "delta", "atomic", "link_dest", "project_ids"]))
+            .arg(Arg::from_usage("[send_parent] --send-parent [BACKUP] 'Create an incremental send stream against this earlier backup'")
+                .requires("send_stream"))
+            .arg(Arg::from_usage("<BACKUP> 'The backup/subtree path, [repository]::backup[::subtree] or a path in a mounted backup'")
+                .validator(validate_restore_source))
+            .arg(Arg::from_usage("<DST> 'Destination path for backup'")))
+        .subcommand(SubCommand::with_name("manifest").about("Print checksums of all files in a backup or subtree")
+            .arg(Arg::from_usage("<BACKUP> 'The backup/subtree path, [repository]::backup[::subtree]'")
+                .validator(|val| validate_repo_path(val, true, Some(true), None))))
+        .subcommand(SubCommand::with_name("verify-manifest").about("Check a restored tree against a checksum manifest")
+            .arg(Arg::from_usage("<MANIFEST> 'Manifest file as created by the manifest subcommand'")
+                .validator(validate_existing_path))
+            .arg(Arg::from_usage("<PATH> 'Path of the restored tree'")
+                .validator(validate_existing_path)))
+        .subcommand(SubCommand::with_name("publish").about("Export a backup or subtree as a static website")
             .arg(Arg::from_usage("<BACKUP> 'The backup/subtree path, [repository]::backup[::subtree]'")
                 .validator(|val| validate_repo_path(val, true, Some(true), None)))
-            .arg(Arg::from_usage("<DST> 'Destination path for backup'")))
+            .arg(Arg::from_usage("<DST> 'Destination folder of the website'")))
         .subcommand(SubCommand::with_name("remove").aliases(&["rm", "delete", "del"]).about("Remove a backup or a subtree")
             .arg(Arg::from_usage("-f --force 'Remove multiple backups in a backup folder'"))
+            .arg(Arg::from_usage("[ignore_min_retention] --ignore-min-retention 'Also remove backups that are younger than the minimal retention'"))
             .arg(Arg::from_usage("<BACKUP> 'The backup/subtree path, [repository]::backup[::subtree]'")
                 .validator(|val| validate_repo_path(val, true, Some(true), None))))
+        .subcommand(SubCommand::with_name("undelete").about("Restore a removed backup from the trash")
+            .arg(Arg::from_usage("<BACKUP> 'The backup to restore, [repository]::backup, lists the trash without a backup'")
+                .validator(|val| validate_repo_path(val, true, None, Some(false)))))
         .subcommand(SubCommand::with_name("prune").about("Remove backups based on age")
             .arg(Arg::from_usage("-p --prefix [PREFIX] 'Only consider backups starting with this prefix'"))
             .arg(Arg::from_usage("-d --daily [NUM] 'Keep this number of daily backups'")
@@ -362,12 +973,21 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
             .arg(Arg::from_usage("-y --yearly [NUM] 'Keep this number of yearly backups'")
                 .default_value("0").validator(validate_num))
             .arg(Arg::from_usage("-f --force 'Actually run the prune instead of simulating it'"))
+            .arg(Arg::from_usage("[ignore_min_retention] --ignore-min-retention 'Also remove backups that are younger than the minimal retention'"))
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("purge").about("Remove matching paths from all backups")
+            .arg(Arg::from_usage("-p --pattern <PATTERN>... 'Remove paths matching this pattern'")
+                .number_of_values(1))
+            .arg(Arg::from_usage("-f --force 'Actually run the purge instead of simulating it'"))
             .arg(Arg::from_usage("<REPO> 'Path of the repository'")
                 .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
         .subcommand(SubCommand::with_name("vacuum").about("Reclaim space by rewriting bundles")
             .arg(Arg::from_usage("-r --ratio [NUM] 'Ratio in % of unused space in a bundle to rewrite that bundle'")
                 .default_value(DEFAULT_VACUUM_RATIO_STR).validator(validate_num))
             .arg(Arg::from_usage("--combine 'Combine small bundles into larger ones'"))
+            .arg(Arg::from_usage("[combine_threshold] --combine-threshold [SIZE] 'Combine bundles smaller than this size in MiB or with a unit, default: a quarter of the bundle size'")
+                .requires("combine").validator(|val| validate_size(val, 1024 * 1024)))
             .arg(Arg::from_usage("-f --force 'Actually run the vacuum instead of simulating it'"))
             .arg(Arg::from_usage("<REPO> 'Path of the repository'")
                 .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
@@ -375,13 +995,44 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
             .arg(Arg::from_usage("-b --bundles 'Check the bundles'"))
             .arg(Arg::from_usage("[bundle_data] --bundle-data 'Check bundle contents (slow)'").requires("bundles").alias("data"))
             .arg(Arg::from_usage("-i --index 'Check the chunk index'"))
+            .arg(Arg::from_usage("-f --backup-files 'Check backup files for missing roots, duplicates and orphaned meta bundles'"))
+            .arg(Arg::from_usage("--fix [CLASS]... 'Repair only this class of backup file problems: missing-roots, duplicates or orphaned-meta'")
+                .number_of_values(1).requires("backup_files").validator(validate_backup_file_problem))
+            .arg(Arg::from_usage("--sample [PERCENT] 'Restore this percentage of randomly chosen files and verify their data'")
+                .validator(validate_percentage))
+            .arg(Arg::from_usage("[rebuild_backups] --rebuild-backups 'Recreate lost backup files from the root directories in the meta bundles'"))
             .arg(Arg::from_usage("-r --repair 'Try to repair errors'"))
             .arg(Arg::from_usage("<PATH> 'Path of the repository/backup/subtree, [repository][::backup[::subtree]]'")
                 .validator(|val| validate_repo_path(val, true, None, None))))
         .subcommand(SubCommand::with_name("list").alias("ls").about("List backups or backup contents")
+            .arg(Arg::from_usage("--tag [TAG]... 'Only list backups with this tag'")
+                .number_of_values(1).validator(validate_tag))
+            .arg(Arg::from_usage("--host [HOST] 'Only list backups of this host'"))
+            .arg(Arg::from_usage("--path [SRC] 'Only list backups of this source path'"))
             .arg(Arg::from_usage("<PATH> 'Path of the repository/backup/subtree, [repository][::backup[::subtree]]'")
                 .validator(|val| validate_repo_path(val, true, None, None))))
+        .subcommand(SubCommand::with_name("tag").about("Add or remove tags of a backup")
+            .arg(Arg::from_usage("<BACKUP> 'The backup path, [repository]::backup'")
+                .validator(|val| validate_repo_path(val, true, Some(true), Some(false))))
+            .arg(Arg::from_usage("<CHANGE>... 'Tag to add (+tag) or to remove (-tag)'")
+                .allow_hyphen_values(true).validator(validate_tag_change)))
+        .subcommand(SubCommand::with_name("remote").about("List, add or remove named remotes")
+            .arg(Arg::from_usage("--add [NAME] 'Add a remote with this name stored in PATH'")
+                .requires("PATH").validator(validate_remote_name))
+            .arg(Arg::from_usage("--remove [NAME] 'Remove the remote with this name'")
+                .conflicts_with("add"))
+            .arg(Arg::from_usage("--check 'Check the remote storage and all named remotes and measure their speed'")
+                .conflicts_with_all(&["add", "remove"]))
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false))))
+            .arg(Arg::from_usage("[PATH] 'Folder of the new remote'")
+                .requires("add")))
         .subcommand(SubCommand::with_name("mount").about("Mount the repository, a backup or a subtree")
+            .arg(Arg::from_usage("--preload-meta 'Load all metadata into memory before mounting'"))
+            .arg(Arg::from_usage("--prewarm 'Read the bundles used by the last mount in the background'"))
+            .arg(Arg::from_usage("--raw 'Mount the bundles and backup files of the repository for debugging'")
+                .conflicts_with_all(&["preload-meta", "prewarm"]))
+            .arg(Arg::from_usage("[no_verify] --no-verify 'Do not compare the data of every chunk with its hash'"))
             .arg(Arg::from_usage("<PATH> 'Path of the repository/backup/subtree, [repository][::backup[::subtree]]'")
                 .validator(|val| validate_repo_path(val, true, None, None)))
             .arg(Arg::from_usage("<MOUNTPOINT> 'Existing mount point'")
@@ -393,22 +1044,124 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
             .arg(Arg::from_usage("<REPO> 'Path of the repository'")
                 .validator(|val| validate_repo_path(val, true, Some(false), Some(false))))
             .arg(Arg::from_usage("<BUNDLE> 'Id of the bundle'")))
+        .subcommand(SubCommand::with_name("bundleaffected").about("List all backup paths that use data of a bundle")
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false))))
+            .arg(Arg::from_usage("<BUNDLE> 'Id of the bundle'")))
+        .subcommand(SubCommand::with_name("debug").about("Inspect the internal structures of a repository")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("bundle-dump").about("Display the header and the chunk table of a bundle")
+                .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                    .validator(|val| validate_repo_path(val, true, Some(false), Some(false))))
+                .arg(Arg::from_usage("<BUNDLE> 'Id of the bundle'")))
+            .subcommand(SubCommand::with_name("chunk").about("Locate a chunk and write its decoded data to stdout")
+                .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                    .validator(|val| validate_repo_path(val, true, Some(false), Some(false))))
+                .arg(Arg::from_usage("<HASH> 'Hash of the chunk'")
+                    .validator(validate_chunk_hash)))
+            .subcommand(SubCommand::with_name("fsck-file").about("Check that a single repository file can be decoded")
+                .arg(Arg::from_usage("-k --key [FILE]... 'Key file needed to decrypt the file'"))
+                .arg(Arg::from_usage("<PATH> 'Path of the bundle, backup or other repository file'")
+                    .validator(validate_existing_path))))
+        .subcommand(SubCommand::with_name("excludes").about("Manage the default excludes of a repository")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("edit").about("Edit the default excludes in $EDITOR")
+                .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                    .validator(|val| validate_repo_path(val, true, Some(false), Some(false))))))
+        .subcommand(SubCommand::with_name("index").about("Manage the local chunk index of a repository")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("shrink").about("Shrink the index to fit its entries")
+                .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                    .validator(|val| validate_repo_path(val, true, Some(false), Some(false))))))
+        .subcommand(SubCommand::with_name("chunk-has").about("Check whether chunks are stored in a repository")
+            .arg(Arg::from_usage("--stdin 'Read the chunk hashes from stdin, one per line'")
+                .conflicts_with("HASH"))
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false))))
+            .arg(Arg::from_usage("[HASH]... 'Hash of the chunk'").required_unless("stdin")
+                .validator(validate_chunk_hash)))
         .subcommand(SubCommand::with_name("import").about("Reconstruct a repository from the remote storage")
             .arg(Arg::from_usage("-k --key [FILE]... 'Key file needed to read the bundles'"))
             .arg(Arg::from_usage("<REMOTE> 'Remote repository path'")
                 .validator(validate_existing_path))
             .arg(Arg::from_usage("<REPO> 'The path for the new repository'")
                 .validator(|val| validate_repo_path(val, false, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("export-delta").about("Export the bundles and backups created after a backup")
+            .arg(Arg::from_usage("-s --since <BACKUP> 'Only export data created after this backup'"))
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false))))
+            .arg(Arg::from_usage("<DST> 'Folder to write the exported files to'")))
+        .subcommand(SubCommand::with_name("import-delta").about("Import bundles and backups written by export-delta")
+            .arg(Arg::from_usage("<SRC> 'Folder with the exported files'")
+                .validator(validate_existing_path))
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("seed").about("Seed a remote storage offline via an external drive")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("export").about("Copy all bundles and backups of a repository to a drive")
+                .arg(Arg::from_usage("[bwlimit] --bwlimit [RATE] 'Limit the transfer rate in MiB/s or with a unit per second'")
+                    .validator(|val| validate_size(val, 1024 * 1024)))
+                .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                    .validator(|val| validate_repo_path(val, true, Some(false), Some(false))))
+                .arg(Arg::from_usage("<DST> 'Folder on the drive to write the files to'")))
+            .subcommand(SubCommand::with_name("import").about("Copy the files from a drive into the remote storage on the server")
+                .arg(Arg::from_usage("[bwlimit] --bwlimit [RATE] 'Limit the transfer rate in MiB/s or with a unit per second'")
+                    .validator(|val| validate_size(val, 1024 * 1024)))
+                .arg(Arg::from_usage("<SRC> 'Folder on the drive with the exported files'")
+                    .validator(validate_existing_path))
+                .arg(Arg::from_usage("<REMOTE> 'Path of the remote storage on the server'")))
+            .subcommand(SubCommand::with_name("finish").about("Upload the remaining files and switch a repository to the seeded remote storage")
+                .arg(Arg::from_usage("[bwlimit] --bwlimit [RATE] 'Limit the transfer rate in MiB/s or with a unit per second'")
+                    .validator(|val| validate_size(val, 1024 * 1024)))
+                .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                    .validator(|val| validate_repo_path(val, true, Some(false), Some(false))))
+                .arg(Arg::from_usage("<REMOTE> 'Path of the seeded remote storage'")
+                    .validator(validate_existing_path))))
+        .subcommand(SubCommand::with_name("clone").about("Create a read-only clone of a repository using the same remote storage")
+            .arg(Arg::from_usage("-r --remote [REMOTE] 'Path of the remote storage if it is mounted at a different path'")
+                .validator(validate_existing_path))
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false))))
+            .arg(Arg::from_usage("<TARGET> 'The path for the new repository'")
+                .validator(|val| validate_repo_path(val, false, Some(false), Some(false)))))
         .subcommand(SubCommand::with_name("info").about("Display information on a repository, a backup or a subtree")
+            .arg(Arg::from_usage("--field [FIELD] 'Only print the raw value of this field'"))
+            .arg(Arg::from_usage("--stats 'Also print the time spent in each phase of a backup'"))
+            .arg(Arg::from_usage("--rules 'Print the exclude rules of a backup as an excludes file'")
+                .conflicts_with_all(&["field", "stats"]))
             .arg(Arg::from_usage("<PATH> 'Path of the repository/backup/subtree, [repository][::backup[::subtree]]'")
                 .validator(|val| validate_repo_path(val, true, None, None))))
-        .subcommand(SubCommand::with_name("analyze").about("Analyze the used and reclaimable space of bundles")
+        .subcommand(SubCommand::with_name("status").about("Check that the latest backups are not older than configured")
             .arg(Arg::from_usage("<REPO> 'Path of the repository'")
                 .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("lock-status").about("List the current locks of a repository")
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("exists").about("Check whether a repository, a backup or a subtree exists")
+            .arg(Arg::from_usage("<PATH> 'Path of the repository/backup/subtree, [repository][::backup[::subtree]]'")))
+        .subcommand(SubCommand::with_name("analyze").about("Analyze the used and reclaimable space of bundles")
+            .arg(Arg::from_usage("[chunking] --chunking [PATH] 'Compare chunkers on the data in this path instead'")
+                .validator(validate_existing_path))
+            .arg(Arg::from_usage("--chunker [CHUNKER]... 'Chunker algorithm and target chunk size to compare'")
+                .number_of_values(1).requires("chunking").validator(validate_chunker))
+            .arg(Arg::from_usage("--hash [HASH] 'Set the hash method'")
+                .requires("chunking").validator(validate_hash))
+            .arg(Arg::from_usage("--by-extension 'Break down the data by file extension instead'")
+                .conflicts_with("chunking"))
+            .arg(Arg::from_usage("--by-host 'Break down the data by the hosts sharing it instead'")
+                .conflicts_with_all(&["chunking", "by-extension"]))
+            .arg(Arg::from_usage("--by-origin 'Break down the bundles by the hosts that created them instead'")
+                .conflicts_with_all(&["chunking", "by-extension", "by-host"]))
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'").required_unless("chunking")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
         .subcommand(SubCommand::with_name("versions").about("Find different versions of a file in all backups")
             .arg(Arg::from_usage("<REPO> 'Path of the repository'")
                 .validator(|val| validate_repo_path(val, true, Some(false), Some(false))))
             .arg(Arg::from_usage("<PATH> 'Path of the file'")))
+        .subcommand(SubCommand::with_name("log").about("Display the log of all changes to the repository")
+            .arg(Arg::from_usage("--action [ACTION] 'Only display entries of this action'"))
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
         .subcommand(SubCommand::with_name("diff").about("Display differences between two backup versions")
             .arg(Arg::from_usage("<OLD> 'Old version, [repository]::backup[::subpath]'")
                 .validator(|val| validate_repo_path(val, true, Some(true), None)))
@@ -420,8 +1173,8 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
             .arg(Arg::from_usage("<DST> 'Destination backup, [repository]::backup'")
                 .validator(|val| validate_repo_path(val, true, Some(true), Some(false)))))
         .subcommand(SubCommand::with_name("config").about("Display or change the configuration")
-            .arg(Arg::from_usage("[bundle_size] --bundle-size [SIZE] 'Set the target bundle size in MiB'")
-                .validator(validate_num))
+            .arg(Arg::from_usage("[bundle_size] --bundle-size [SIZE] 'Set the target bundle size in MiB or with a unit, e.g. 1GiB'")
+                .validator(|val| validate_size(val, 1024 * 1024)))
             .arg(Arg::from_usage("--chunker [CHUNKER] 'Set the chunker algorithm and target chunk size'")
                 .validator(validate_chunker))
             .arg(Arg::from_usage("-c --compression [COMPRESSION] 'Set the compression method and level'")
@@ -430,6 +1183,35 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                 .validator(validate_public_key))
             .arg(Arg::from_usage("--hash [HASH] 'Set the hash method'")
                 .validator(validate_hash))
+            .arg(Arg::from_usage("[delta_compression] --delta-compression [BOOL] 'Enable or disable storing similar chunks as deltas'")
+                .validator(validate_bool))
+            .arg(Arg::from_usage("--dictionary [ID] 'The compression dictionary to use or none'")
+                .validator(validate_dictionary))
+            .arg(Arg::from_usage("--freshness [RULE]... 'Expect a backup matching PATTERN within DURATION, given as PATTERN=DURATION'")
+                .number_of_values(1).validator(validate_freshness))
+            .arg(Arg::from_usage("[trash_retention] --trash-retention [DURATION] 'Keep removed backups in the trash for this long, in days or with a unit, 0 disables the trash'")
+                .validator(validate_retention))
+            .arg(Arg::from_usage("[upload_verification] --upload-verification [MODE] 'Read back new bundles after uploading them and compare their header or their full contents'")
+                .possible_values(&["off", "header", "full"]))
+            .arg(Arg::from_usage("[zero_chunks] --zero-chunks [BOOL] 'Enable or disable detecting chunks of only zeros without hashing them'")
+                .validator(validate_bool))
+            .arg(Arg::from_usage("[min_retention] --min-retention [DURATION] 'Protect backups from prune and remove for this long, in days or with a unit, 0 disables this'")
+                .validator(validate_retention))
+            .arg(Arg::from_usage("--set [SETTING]... 'Set a configuration value, given as KEY=VALUE'")
+                .number_of_values(1).validator(validate_setting))
+            .arg(Arg::from_usage("--check 'Only validate the changes and show their impact without saving them'"))
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("train-dictionary").about("Train a compression dictionary on small chunks and use it")
+            .arg(Arg::from_usage("--size [SIZE] 'Maximal size of the dictionary in KiB or with a unit'")
+                .default_value("112").validator(|val| validate_size(val, 1024)))
+            .arg(Arg::from_usage("[max_chunk_size] --max-chunk-size [SIZE] 'Only use chunks up to this size in KiB or with a unit as samples'")
+                .default_value("16").validator(|val| validate_size(val, 1024)))
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("proxy").about("Serve and cache bundles for other clients")
+            .arg(Arg::from_usage("-l --listen [ADDR] 'Address to listen on'")
+                .default_value(DEFAULT_PROXY_ADDR))
             .arg(Arg::from_usage("<REPO> 'Path of the repository'")
                 .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
         .subcommand(SubCommand::with_name("genkey").about("Generate a new key pair")
@@ -445,9 +1227,28 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                 .validator(validate_existing_path))
             .arg(Arg::from_usage("<REPO> 'Path of the repository'")
                 .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("change-passphrase").about("Change the passphrase protecting the stored keys")
+            .arg(Arg::from_usage("<REPO> 'Path of the repository'")
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("agent").about("Cache the passphrase of the keys for other commands")
+            .arg(Arg::from_usage("[timeout] --timeout [MINUTES] 'Exit when the passphrase has not been used for this long'")
+                .default_value("15").validator(validate_num))
+            .arg(Arg::from_usage("--stop 'Stop the running agent'")))
+        .subcommand(SubCommand::with_name(CODEC_HELPER_COMMAND).setting(AppSettings::Hidden)
+            .about("Compress and encrypt bundle data for another zvault process"))
+        .subcommand(SubCommand::with_name("systemd-units").about("Create systemd units for regular backups")
+            .arg(Arg::from_usage("-n --name [NAME] 'Name of the units, zvault-NAME.service and zvault-NAME.timer'")
+                .default_value("backup"))
+            .arg(Arg::from_usage("[schedule] --on-calendar [SCHEDULE] 'When to run the backup, as a systemd calendar event'")
+                .default_value("daily"))
+            .arg(Arg::from_usage("[output_dir] -o --output-dir [DIR] 'Write the units into this directory instead of printing them'")
+                .validator(validate_existing_path))
+            .arg(Arg::from_usage("<SRC> 'Source path to backup'"))
+            .arg(Arg::from_usage("<BACKUP> 'Backup path, [repository]::backup, placeholders are expanded on each run'")
+                .validator(validate_backup_name)))
         .subcommand(SubCommand::with_name("algotest").about("Test a specific algorithm combination")
-            .arg(Arg::from_usage("[bundle_size] --bundle-size [SIZE] 'Set the target bundle size in MiB'")
-                .default_value(DEFAULT_BUNDLE_SIZE_STR).validator(validate_num))
+            .arg(Arg::from_usage("[bundle_size] --bundle-size [SIZE] 'Set the target bundle size in MiB or with a unit, e.g. 1GiB'")
+                .default_value(DEFAULT_BUNDLE_SIZE_STR).validator(|val| validate_size(val, 1024 * 1024)))
             .arg(Arg::from_usage("--chunker [CHUNKER] 'Set the chunker algorithm and target chunk size'")
                 .default_value(DEFAULT_CHUNKER).validator(validate_chunker))
             .arg(Arg::from_usage("-c --compression [COMPRESSION] 'Set the compression method and level'")
@@ -456,6 +1257,19 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
             .arg(Arg::from_usage("--hash [HASH] 'Set the hash method'")
                 .default_value(DEFAULT_HASH).validator(validate_hash))
             .arg(Arg::from_usage("<FILE> 'File with test data'")
+                .validator(validate_existing_path)))
+        .subcommand(SubCommand::with_name("bench").about("Compare algorithm combinations on a directory tree")
+            .arg(Arg::from_usage("[bundle_size] --bundle-size [SIZE] 'Set the target bundle size in MiB or with a unit, e.g. 1GiB'")
+                .default_value(DEFAULT_BUNDLE_SIZE_STR).validator(|val| validate_size(val, 1024 * 1024)))
+            .arg(Arg::from_usage("--chunker [CHUNKER]... 'Chunker algorithm and target chunk size to compare'")
+                .number_of_values(1).default_value(DEFAULT_CHUNKER).validator(validate_chunker))
+            .arg(Arg::from_usage("-c --compression [COMPRESSION]... 'Compression method and level to compare'")
+                .number_of_values(1).default_value(DEFAULT_COMPRESSION).validator(validate_compression))
+            .arg(Arg::from_usage("--hash [HASH]... 'Hash method to compare'")
+                .number_of_values(1).default_value(DEFAULT_HASH).validator(validate_hash))
+            .arg(Arg::from_usage("-e --encrypt 'Also encrypt the bundles'"))
+            .arg(Arg::from_usage("--json 'Output the results as JSON instead of CSV'"))
+            .arg(Arg::from_usage("<PATH> 'Directory with test data'")
                 .validator(validate_existing_path))).get_matches();
     let verbose_count = args.subcommand()
         .1
@@ -465,6 +1279,46 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
         .1
         .map(|m| m.occurrences_of("quiet"))
         .unwrap_or(0) + args.occurrences_of("quiet");
+    let max_memory = args.subcommand()
+        .1
+        .and_then(|m| m.value_of("max_memory"))
+        .or_else(|| args.value_of("max_memory"))
+        .map(|v| parse_file_size(v, 1024 * 1024).unwrap() as usize);
+    let background = args.subcommand().1.map_or(false, |m| m.is_present("background")) ||
+        args.is_present("background");
+    let si_units = args.subcommand().1.map_or(false, |m| m.is_present("si")) ||
+        args.is_present("si");
+    let pager = !(args.subcommand().1.map_or(false, |m| m.is_present("no_pager")) ||
+        args.is_present("no_pager"));
+    let proxy = args.subcommand()
+        .1
+        .and_then(|m| m.value_of("proxy"))
+        .or_else(|| args.value_of("proxy"))
+        .map(|v| v.to_string());
+    let command = args.subcommand_name().unwrap_or("").to_string();
+    let passcommand = args.subcommand()
+        .1
+        .and_then(|m| m.value_of("passcommand"))
+        .or_else(|| args.value_of("passcommand"))
+        .map(|v| v.to_string());
+    let json_output = args.subcommand()
+        .1
+        .and_then(|m| m.value_of("output"))
+        .or_else(|| args.value_of("output")) == Some("json");
+    let systemd = args.subcommand().1.map_or(false, |m| m.is_present("systemd")) ||
+        args.is_present("systemd");
+    let notify = args.subcommand().1.map_or(false, |m| m.is_present("notify")) ||
+        args.is_present("notify");
+    let threads = args.subcommand()
+        .1
+        .and_then(|m| m.value_of("threads"))
+        .or_else(|| args.value_of("threads"))
+        .map(|v| parse_num(v).unwrap() as usize);
+    let cpus = args.subcommand()
+        .1
+        .and_then(|m| m.value_of("cpus"))
+        .or_else(|| args.value_of("cpus"))
+        .map(|v| parse_cpus(v).unwrap());
     let log_level = match 1 + verbose_count - quiet_count {
         0 => LogLevel::Warn,
         1 => LogLevel::Info,
@@ -480,14 +1334,18 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                 Some(false)
             ).unwrap();
             Arguments::Init {
-                bundle_size: (parse_num(args.value_of("bundle_size").unwrap()).unwrap() *
-                                  1024 * 1024) as usize,
+                bundle_size: parse_file_size(args.value_of("bundle_size").unwrap(), 1024 * 1024)
+                    .unwrap() as usize,
                 chunker: parse_chunker(args.value_of("chunker").unwrap()).unwrap(),
                 compression: parse_compression(args.value_of("compression").unwrap()).unwrap(),
                 encryption: args.is_present("encrypt"),
                 hash: parse_hash(args.value_of("hash").unwrap()).unwrap(),
+                keyed_hash: args.is_present("keyed_hash"),
+                delta_compression: args.is_present("delta_compression"),
                 repo_path: repository,
-                remote_path: args.value_of("remote").unwrap().to_string()
+                remote_path: args.value_of("remote").unwrap().to_string(),
+                excludes_template: args.value_of("excludes_template").map(|v| v.to_string()),
+                readme_template: args.value_of("readme_template").map(|v| v.to_string())
             }
         }
         ("backup", Some(args)) => {
@@ -499,28 +1357,88 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
             ).unwrap();
             Arguments::Backup {
                 repo_path: repository,
-                backup_name: backup.unwrap().to_string(),
+                backup_name: expand_backup_name(backup.unwrap()).unwrap(),
                 full: args.is_present("full"),
                 same_device: !args.is_present("cross_device"),
+                dereference: args.is_present("dereference"),
+                dereference_args: args.is_present("dereference_args"),
                 excludes: args.values_of("exclude")
                     .map(|v| v.map(|k| k.to_string()).collect())
                     .unwrap_or_else(|| vec![]),
                 excludes_from: args.value_of("excludes_from").map(|v| v.to_string()),
                 src_path: args.value_of("SRC").unwrap().to_string(),
                 reference: args.value_of("reference").map(|v| v.to_string()),
+                reference_any: args.is_present("reference_any"),
+                reference_host: args.value_of("reference_host").map(|v| v.to_string()),
                 no_default_excludes: args.is_present("no_default_excludes"),
-                tar: args.is_present("tar")
+                tar: args.is_present("tar"),
+                timestamp: args.value_of("timestamp").map(|v| parse_timestamp(v).unwrap()),
+                list_only: args.is_present("list_only"),
+                tags: args.values_of("tag")
+                    .map(|v| v.map(|k| k.to_string()).collect())
+                    .unwrap_or_else(|| vec![]),
+                special_files: parse_special_files(args.value_of("special_files").unwrap()).unwrap(),
+                order: parse_backup_order(args.value_of("order").unwrap()).unwrap(),
+                remotes: args.values_of("to")
+                    .map(|v| v.map(|k| k.to_string()).collect())
+                    .unwrap_or_else(|| vec![]),
+                stats: args.is_present("stats"),
+                snapshot: args.value_of("snapshot").map(|v| v.to_string()),
+                wait: args.is_present("wait"),
+                scan_threads: parse_num(args.value_of("scan_threads").unwrap()).unwrap() as usize,
+                include_repositories: args.is_present("include_repositories"),
+                storage_class: parse_storage_class(args.value_of("storage_class").unwrap()).unwrap()
             }
         }
         ("restore", Some(args)) => {
+            let source = args.value_of("BACKUP").unwrap();
+            // Paths inside a mount are restored directly from the repository instead of via fuse
+            let parsed = parse_repo_path(source, true, Some(true), None);
+            let (repository, backup, inode) = match parsed {
+                Ok((repository, backup, inode)) => {
+                    (repository, backup.unwrap().to_string(), inode.map(|v| v.to_string()))
+                }
+                Err(_) => parse_mounted_path(source).unwrap(),
+            };
+            Arguments::Restore {
+                repo_path: repository,
+                backup_name: backup,
+                inode: inode,
+                dst_path: args.value_of("DST").unwrap().to_string(),
+                tar: args.is_present("tar"),
+                delta: args.is_present("delta"),
+                special_files: parse_special_files(args.value_of("special_files").unwrap()).unwrap(),
+                atomic: args.is_present("atomic"),
+                verify: !args.is_present("no_verify"),
+                link_dest: args.value_of("link_dest").map(PathBuf::from),
+                project_ids: args.is_present("project_ids"),
+                send_stream: args.is_present("send_stream"),
+                send_parent: args.value_of("send_parent").map(|v| v.to_string())
+            }
+        }
+        ("manifest", Some(args)) => {
             let (repository, backup, inode) =
                 parse_repo_path(args.value_of("BACKUP").unwrap(), true, Some(true), None).unwrap();
-            Arguments::Restore {
+            Arguments::Manifest {
+                repo_path: repository,
+                backup_name: backup.unwrap().to_string(),
+                inode: inode.map(|v| v.to_string())
+            }
+        }
+        ("publish", Some(args)) => {
+            let (repository, backup, inode) =
+                parse_repo_path(args.value_of("BACKUP").unwrap(), true, Some(true), None).unwrap();
+            Arguments::Publish {
                 repo_path: repository,
                 backup_name: backup.unwrap().to_string(),
                 inode: inode.map(|v| v.to_string()),
-                dst_path: args.value_of("DST").unwrap().to_string(),
-                tar: args.is_present("tar")
+                dst_path: args.value_of("DST").unwrap().to_string()
+            }
+        }
+        ("verify-manifest", Some(args)) => {
+            Arguments::VerifyManifest {
+                manifest: args.value_of("MANIFEST").unwrap().to_string(),
+                path: args.value_of("PATH").unwrap().to_string()
             }
         }
         ("remove", Some(args)) => {
@@ -530,9 +1448,22 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                 repo_path: repository,
                 backup_name: backup.unwrap().to_string(),
                 inode: inode.map(|v| v.to_string()),
+                ignore_min_retention: args.is_present("ignore_min_retention"),
                 force: args.is_present("force")
             }
         }
+        ("undelete", Some(args)) => {
+            let (repository, backup, _inode) = parse_repo_path(
+                args.value_of("BACKUP").unwrap(),
+                true,
+                None,
+                Some(false)
+            ).unwrap();
+            Arguments::Undelete {
+                repo_path: repository,
+                backup_name: backup.map(|v| v.to_string())
+            }
+        }
         ("prune", Some(args)) => {
             let (repository, _backup, _inode) = parse_repo_path(
                 args.value_of("REPO").unwrap(),
@@ -543,6 +1474,7 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
             Arguments::Prune {
                 repo_path: repository,
                 prefix: args.value_of("prefix").unwrap_or("").to_string(),
+                ignore_min_retention: args.is_present("ignore_min_retention"),
                 force: args.is_present("force"),
                 daily: parse_num(args.value_of("daily").unwrap()).unwrap() as usize,
                 weekly: parse_num(args.value_of("weekly").unwrap()).unwrap() as usize,
@@ -550,6 +1482,22 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                 yearly: parse_num(args.value_of("yearly").unwrap()).unwrap() as usize
             }
         }
+        ("purge", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::Purge {
+                repo_path: repository,
+                patterns: args.values_of("pattern")
+                    .unwrap()
+                    .map(|v| v.to_string())
+                    .collect(),
+                force: args.is_present("force")
+            }
+        }
         ("vacuum", Some(args)) => {
             let (repository, _backup, _inode) = parse_repo_path(
                 args.value_of("REPO").unwrap(),
@@ -561,12 +1509,18 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                 repo_path: repository,
                 force: args.is_present("force"),
                 combine: args.is_present("combine"),
+                combine_threshold: args.value_of("combine_threshold")
+                    .map(|v| parse_file_size(v, 1024 * 1024).unwrap() as usize),
                 ratio: parse_num(args.value_of("ratio").unwrap()).unwrap() as f32 / 100.0
             }
         }
         ("check", Some(args)) => {
             let (repository, backup, inode) =
                 parse_repo_path(args.value_of("PATH").unwrap(), true, None, None).unwrap();
+            if args.is_present("rebuild_backups") && backup.is_some() {
+                error!("Backups can only be rebuilt for a whole repository");
+                return Err(ErrorCode::InvalidArgs);
+            }
             Arguments::Check {
                 repo_path: repository,
                 backup_name: backup.map(|v| v.to_string()),
@@ -574,6 +1528,12 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                 bundles: args.is_present("bundles"),
                 bundle_data: args.is_present("bundle_data"),
                 index: args.is_present("index"),
+                backup_files: args.is_present("backup_files"),
+                fix: args.values_of("fix")
+                    .map(|v| v.map(|p| parse_backup_file_problem(p).unwrap()).collect())
+                    .unwrap_or_else(|| vec![]),
+                sample: args.value_of("sample").map(|v| parse_percentage(v).unwrap()),
+                rebuild_backups: args.is_present("rebuild_backups"),
                 repair: args.is_present("repair")
             }
         }
@@ -583,7 +1543,50 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
             Arguments::List {
                 repo_path: repository,
                 backup_name: backup.map(|v| v.to_string()),
-                inode: inode.map(|v| v.to_string())
+                inode: inode.map(|v| v.to_string()),
+                tags: args.values_of("tag")
+                    .map(|v| v.map(|k| k.to_string()).collect())
+                    .unwrap_or_else(|| vec![]),
+                host: args.value_of("host").map(|v| v.to_string()),
+                path: args.value_of("path").map(|v| v.to_string())
+            }
+        }
+        ("tag", Some(args)) => {
+            let (repository, backup, _inode) = parse_repo_path(
+                args.value_of("BACKUP").unwrap(),
+                true,
+                Some(true),
+                Some(false)
+            ).unwrap();
+            let mut add = vec![];
+            let mut remove = vec![];
+            for change in args.values_of("CHANGE").unwrap() {
+                match parse_tag_change(change).unwrap() {
+                    (true, tag) => add.push(tag),
+                    (false, tag) => remove.push(tag),
+                }
+            }
+            Arguments::Tag {
+                repo_path: repository,
+                backup_name: backup.unwrap().to_string(),
+                add: add,
+                remove: remove
+            }
+        }
+        ("remote", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::Remote {
+                repo_path: repository,
+                add: args.value_of("add").map(|name| {
+                    (name.to_string(), args.value_of("PATH").unwrap().to_string())
+                }),
+                remove: args.value_of("remove").map(|v| v.to_string()),
+                check: args.is_present("check")
             }
         }
         ("bundlelist", Some(args)) => {
@@ -607,10 +1610,55 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                 bundle_id: try!(parse_bundle_id(args.value_of("BUNDLE").unwrap()))
             }
         }
+        ("bundleaffected", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::BundleAffected {
+                repo_path: repository,
+                bundle_id: try!(parse_bundle_id(args.value_of("BUNDLE").unwrap()))
+            }
+        }
         ("info", Some(args)) => {
             let (repository, backup, inode) =
                 parse_repo_path(args.value_of("PATH").unwrap(), true, None, None).unwrap();
+            if args.is_present("rules") && (backup.is_none() || inode.is_some()) {
+                error!("The rules can only be printed for a whole backup");
+                return Err(ErrorCode::InvalidArgs);
+            }
             Arguments::Info {
+                repo_path: repository,
+                backup_name: backup.map(|v| v.to_string()),
+                inode: inode.map(|v| v.to_string()),
+                field: args.value_of("field").map(|v| v.to_string()),
+                stats: args.is_present("stats"),
+                rules: args.is_present("rules")
+            }
+        }
+        ("status", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::Status { repo_path: repository }
+        }
+        ("lock-status", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::LockStatus { repo_path: repository }
+        }
+        ("exists", Some(args)) => {
+            let (repository, backup, inode) = split_repo_path(args.value_of("PATH").unwrap());
+            Arguments::Exists {
                 repo_path: repository,
                 backup_name: backup.map(|v| v.to_string()),
                 inode: inode.map(|v| v.to_string())
@@ -633,11 +1681,19 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
         ("mount", Some(args)) => {
             let (repository, backup, inode) =
                 parse_repo_path(args.value_of("PATH").unwrap(), true, None, None).unwrap();
+            if args.is_present("raw") && backup.is_some() {
+                error!("A raw mount needs a repository path without a backup");
+                return Err(ErrorCode::InvalidArgs);
+            }
             Arguments::Mount {
                 repo_path: repository,
                 backup_name: backup.map(|v| v.to_string()),
                 inode: inode.map(|v| v.to_string()),
-                mount_point: args.value_of("MOUNTPOINT").unwrap().to_string()
+                mount_point: args.value_of("MOUNTPOINT").unwrap().to_string(),
+                preload_meta: args.is_present("preload-meta"),
+                prewarm: args.is_present("prewarm"),
+                raw: args.is_present("raw"),
+                verify: !args.is_present("no_verify")
             }
         }
         ("versions", Some(args)) => {
@@ -652,6 +1708,18 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                 path: args.value_of("PATH").unwrap().to_string()
             }
         }
+        ("log", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::Log {
+                repo_path: repository,
+                action: args.value_of("action").map(|v| v.to_string())
+            }
+        }
         ("diff", Some(args)) => {
             let (repository_old, backup_old, inode_old) =
                 parse_repo_path(args.value_of("OLD").unwrap(), true, Some(true), None).unwrap();
@@ -666,6 +1734,18 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                 inode_new: inode_new.map(|v| v.to_string())
             }
         }
+        ("analyze", Some(args)) if args.is_present("chunking") => {
+            Arguments::AnalyzeChunking {
+                path: args.value_of("chunking").unwrap().to_string(),
+                chunkers: match args.values_of("chunker") {
+                    Some(values) => values.map(|v| parse_chunker(v).unwrap()).collect(),
+                    None => CHUNKING_ADVISOR_CHUNKERS.iter()
+                        .map(|v| parse_chunker(v).unwrap())
+                        .collect(),
+                },
+                hash: parse_hash(args.value_of("hash").unwrap_or(DEFAULT_HASH)).unwrap()
+            }
+        }
         ("analyze", Some(args)) => {
             let (repository, _backup, _inode) = parse_repo_path(
                 args.value_of("REPO").unwrap(),
@@ -673,7 +1753,12 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                 Some(false),
                 Some(false)
             ).unwrap();
-            Arguments::Analyze { repo_path: repository }
+            Arguments::Analyze {
+                repo_path: repository,
+                by_extension: args.is_present("by-extension"),
+                by_host: args.is_present("by-host"),
+                by_origin: args.is_present("by-origin")
+            }
         }
         ("import", Some(args)) => {
             let (repository, _backup, _inode) = parse_repo_path(
@@ -690,6 +1775,183 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                     .unwrap_or_else(|| vec![])
             }
         }
+        ("debug", Some(args)) => {
+            match args.subcommand() {
+                ("bundle-dump", Some(args)) => {
+                    let (repository, _backup, _inode) = parse_repo_path(
+                        args.value_of("REPO").unwrap(),
+                        true,
+                        Some(false),
+                        Some(false)
+                    ).unwrap();
+                    Arguments::DebugBundleDump {
+                        repo_path: repository,
+                        bundle_id: try!(parse_bundle_id(args.value_of("BUNDLE").unwrap()))
+                    }
+                }
+                ("chunk", Some(args)) => {
+                    let (repository, _backup, _inode) = parse_repo_path(
+                        args.value_of("REPO").unwrap(),
+                        true,
+                        Some(false),
+                        Some(false)
+                    ).unwrap();
+                    Arguments::DebugChunk {
+                        repo_path: repository,
+                        hash: parse_chunk_hash(args.value_of("HASH").unwrap()).unwrap()
+                    }
+                }
+                ("fsck-file", Some(args)) => {
+                    Arguments::DebugFsckFile {
+                        path: args.value_of("PATH").unwrap().to_string(),
+                        key_files: args.values_of("key")
+                            .map(|v| v.map(|k| k.to_string()).collect())
+                            .unwrap_or_else(|| vec![])
+                    }
+                }
+                _ => {
+                    error!("No debug subcommand given");
+                    return Err(ErrorCode::InvalidArgs);
+                }
+            }
+        }
+        ("excludes", Some(args)) => {
+            match args.subcommand() {
+                ("edit", Some(args)) => {
+                    let (repository, _backup, _inode) = parse_repo_path(
+                        args.value_of("REPO").unwrap(),
+                        true,
+                        Some(false),
+                        Some(false)
+                    ).unwrap();
+                    Arguments::ExcludesEdit { repo_path: repository }
+                }
+                _ => {
+                    error!("No excludes subcommand given");
+                    return Err(ErrorCode::InvalidArgs);
+                }
+            }
+        }
+        ("index", Some(args)) => {
+            match args.subcommand() {
+                ("shrink", Some(args)) => {
+                    let (repository, _backup, _inode) = parse_repo_path(
+                        args.value_of("REPO").unwrap(),
+                        true,
+                        Some(false),
+                        Some(false)
+                    ).unwrap();
+                    Arguments::IndexShrink { repo_path: repository }
+                }
+                _ => {
+                    error!("No index subcommand given");
+                    return Err(ErrorCode::InvalidArgs);
+                }
+            }
+        }
+        ("chunk-has", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::ChunkHas {
+                repo_path: repository,
+                hashes: args.values_of("HASH")
+                    .map(|v| v.map(|h| parse_chunk_hash(h).unwrap()).collect())
+                    .unwrap_or_else(|| vec![]),
+                stdin: args.is_present("stdin")
+            }
+        }
+        ("export-delta", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::ExportDelta {
+                repo_path: repository,
+                since: args.value_of("since").unwrap().to_string(),
+                dst_path: args.value_of("DST").unwrap().to_string()
+            }
+        }
+        ("import-delta", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::ImportDelta {
+                repo_path: repository,
+                src_path: args.value_of("SRC").unwrap().to_string()
+            }
+        }
+        ("seed", Some(args)) => {
+            match args.subcommand() {
+                ("export", Some(args)) => {
+                    let (repository, _backup, _inode) = parse_repo_path(
+                        args.value_of("REPO").unwrap(),
+                        true,
+                        Some(false),
+                        Some(false)
+                    ).unwrap();
+                    Arguments::SeedExport {
+                        repo_path: repository,
+                        dst_path: args.value_of("DST").unwrap().to_string(),
+                        bwlimit: args.value_of("bwlimit")
+                            .map(|v| parse_file_size(v, 1024 * 1024).unwrap())
+                    }
+                }
+                ("import", Some(args)) => {
+                    Arguments::SeedImport {
+                        src_path: args.value_of("SRC").unwrap().to_string(),
+                        remote_path: args.value_of("REMOTE").unwrap().to_string(),
+                        bwlimit: args.value_of("bwlimit")
+                            .map(|v| parse_file_size(v, 1024 * 1024).unwrap())
+                    }
+                }
+                ("finish", Some(args)) => {
+                    let (repository, _backup, _inode) = parse_repo_path(
+                        args.value_of("REPO").unwrap(),
+                        true,
+                        Some(false),
+                        Some(false)
+                    ).unwrap();
+                    Arguments::SeedFinish {
+                        repo_path: repository,
+                        remote_path: args.value_of("REMOTE").unwrap().to_string(),
+                        bwlimit: args.value_of("bwlimit")
+                            .map(|v| parse_file_size(v, 1024 * 1024).unwrap())
+                    }
+                }
+                _ => {
+                    error!("No seed subcommand given");
+                    return Err(ErrorCode::InvalidArgs);
+                }
+            }
+        }
+        ("clone", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            let (target, _backup, _inode) = parse_repo_path(
+                args.value_of("TARGET").unwrap(),
+                false,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::Clone {
+                repo_path: repository,
+                target_path: target,
+                remote_path: args.value_of("remote").map(|v| v.to_string())
+            }
+        }
         ("config", Some(args)) => {
             let (repository, _backup, _inode) = parse_repo_path(
                 args.value_of("REPO").unwrap(),
@@ -697,21 +1959,108 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                 Some(false),
                 Some(false)
             ).unwrap();
+            let mut bundle_size = args.value_of("bundle_size").map(|v| {
+                parse_file_size(v, 1024 * 1024).unwrap() as usize
+            });
+            let mut chunker = args.value_of("chunker").map(|v| parse_chunker(v).unwrap());
+            let mut compression = args.value_of("compression").map(|v| {
+                parse_compression(v).unwrap()
+            });
+            let mut encryption = args.value_of("encryption").map(
+                |v| parse_public_key(v).unwrap()
+            );
+            let mut hash = args.value_of("hash").map(|v| parse_hash(v).unwrap());
+            let mut delta_compression = args.value_of("delta_compression").map(|v| {
+                parse_bool(v).unwrap()
+            });
+            let mut dictionary = args.value_of("dictionary").map(|v| parse_dictionary(v).unwrap());
+            let mut freshness: Vec<_> = args.values_of("freshness")
+                .map(|v| v.map(|k| parse_freshness(k).unwrap()).collect())
+                .unwrap_or_else(|| vec![]);
+            let mut trash_retention = args.value_of("trash_retention").map(|v| {
+                parse_retention(v).unwrap()
+            });
+            let mut min_retention = args.value_of("min_retention").map(|v| {
+                parse_retention(v).unwrap()
+            });
+            let mut upload_verification = args.value_of("upload_verification").map(|v| {
+                UploadVerification::from_string(v).unwrap()
+            });
+            let mut zero_chunks = args.value_of("zero_chunks").map(|v| parse_bool(v).unwrap());
+            let mut codec_helpers = None;
+            let mut short_bundle_names = None;
+            // The global --threads and --cpus options only apply to this run
+            let mut threads = None;
+            let mut cpus = None;
+            if let Some(settings) = args.values_of("set") {
+                for setting in settings {
+                    match parse_setting(setting).unwrap() {
+                        Setting::BundleSize(v) => bundle_size = Some(v),
+                        Setting::Chunker(v) => chunker = Some(v),
+                        Setting::Compression(v) => compression = Some(v),
+                        Setting::Encryption(v) => encryption = Some(v),
+                        Setting::Hash(v) => hash = Some(v),
+                        Setting::DeltaCompression(v) => delta_compression = Some(v),
+                        Setting::Dictionary(v) => dictionary = Some(v),
+                        Setting::Freshness(v) => freshness.push(v),
+                        Setting::TrashRetention(v) => trash_retention = Some(v),
+                        Setting::MinRetention(v) => min_retention = Some(v),
+                        Setting::UploadVerification(v) => upload_verification = Some(v),
+                        Setting::ZeroChunks(v) => zero_chunks = Some(v),
+                        Setting::CodecHelpers(v) => codec_helpers = Some(v),
+                        Setting::ShortBundleNames(v) => short_bundle_names = Some(v),
+                        Setting::Threads(v) => threads = Some(v),
+                        Setting::Cpus(v) => cpus = Some(v),
+                    }
+                }
+            }
             Arguments::Config {
-                bundle_size: args.value_of("bundle_size").map(|v| {
-                    parse_num(v).unwrap() as usize * 1024 * 1024
-                }),
-                chunker: args.value_of("chunker").map(|v| parse_chunker(v).unwrap()),
-                compression: args.value_of("compression").map(|v| {
-                    parse_compression(v).unwrap()
-                }),
-                encryption: args.value_of("encryption").map(
-                    |v| parse_public_key(v).unwrap()
-                ),
-                hash: args.value_of("hash").map(|v| parse_hash(v).unwrap()),
+                bundle_size: bundle_size,
+                chunker: chunker,
+                compression: compression,
+                encryption: encryption,
+                hash: hash,
+                delta_compression: delta_compression,
+                dictionary: dictionary,
+                freshness: freshness,
+                trash_retention: trash_retention,
+                min_retention: min_retention,
+                upload_verification: upload_verification,
+                zero_chunks: zero_chunks,
+                codec_helpers: codec_helpers,
+                short_bundle_names: short_bundle_names,
+                threads: threads,
+                cpus: cpus,
+                check: args.is_present("check"),
                 repo_path: repository
             }
         }
+        ("train-dictionary", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::TrainDictionary {
+                repo_path: repository,
+                size: parse_file_size(args.value_of("size").unwrap(), 1024).unwrap() as usize,
+                max_chunk_size: parse_file_size(args.value_of("max_chunk_size").unwrap(), 1024)
+                    .unwrap() as usize
+            }
+        }
+        ("proxy", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::Proxy {
+                repo_path: repository,
+                listen: args.value_of("listen").unwrap().to_string()
+            }
+        }
         ("genkey", Some(args)) => {
             Arguments::GenKey {
                 file: args.value_of("FILE").map(|v| v.to_string()),
@@ -732,10 +2081,35 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                 file: args.value_of("FILE").map(|v| v.to_string())
             }
         }
+        ("change-passphrase", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::ChangePassphrase { repo_path: repository }
+        }
+        ("agent", Some(args)) => {
+            Arguments::Agent {
+                timeout: parse_num(args.value_of("timeout").unwrap()).unwrap(),
+                stop: args.is_present("stop")
+            }
+        }
+        (CODEC_HELPER_COMMAND, Some(_)) => Arguments::CodecHelper,
+        ("systemd-units", Some(args)) => {
+            Arguments::SystemdUnits {
+                src_path: args.value_of("SRC").unwrap().to_string(),
+                backup: args.value_of("BACKUP").unwrap().to_string(),
+                name: args.value_of("name").unwrap().to_string(),
+                schedule: args.value_of("schedule").unwrap().to_string(),
+                output_dir: args.value_of("output_dir").map(|v| v.to_string())
+            }
+        }
         ("algotest", Some(args)) => {
             Arguments::AlgoTest {
-                bundle_size: (parse_num(args.value_of("bundle_size").unwrap()).unwrap() *
-                                  1024 * 1024) as usize,
+                bundle_size: parse_file_size(args.value_of("bundle_size").unwrap(), 1024 * 1024)
+                    .unwrap() as usize,
                 chunker: parse_chunker(args.value_of("chunker").unwrap()).unwrap(),
                 compression: parse_compression(args.value_of("compression").unwrap()).unwrap(),
                 encrypt: args.is_present("encrypt"),
@@ -743,10 +2117,46 @@ pub fn parse() -> Result<(LogLevel, Arguments), ErrorCode> {
                 file: args.value_of("FILE").unwrap().to_string()
             }
         }
+        ("bench", Some(args)) => {
+            Arguments::Bench {
+                bundle_size: parse_file_size(args.value_of("bundle_size").unwrap(), 1024 * 1024)
+                    .unwrap() as usize,
+                chunkers: args.values_of("chunker")
+                    .unwrap()
+                    .map(|v| parse_chunker(v).unwrap())
+                    .collect(),
+                compressions: args.values_of("compression")
+                    .unwrap()
+                    .map(|v| parse_compression(v).unwrap())
+                    .collect(),
+                hashes: args.values_of("hash")
+                    .unwrap()
+                    .map(|v| parse_hash(v).unwrap())
+                    .collect(),
+                encrypt: args.is_present("encrypt"),
+                json: args.is_present("json"),
+                path: args.value_of("PATH").unwrap().to_string()
+            }
+        }
         _ => {
             error!("No subcommand given");
             return Err(ErrorCode::InvalidArgs);
         }
     };
-    Ok((log_level, args))
+    let options = GlobalOptions {
+        log_level: log_level,
+        max_memory: max_memory,
+        background: background,
+        proxy: proxy,
+        passcommand: passcommand,
+        command: command,
+        si_units: si_units,
+        pager: pager,
+        json_output: json_output,
+        systemd: systemd,
+        notify: notify,
+        threads: threads,
+        cpus: cpus
+    };
+    Ok((options, args))
 }
@@ -0,0 +1,408 @@
+use prelude::*;
+
+use std::io::{self, Cursor, Read};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use std::mem;
+use std::cmp::max;
+
+use index::{INITIAL_SIZE, MAX_USAGE};
+use serde_json;
+
+
+struct BenchResult {
+    chunker: ChunkerType,
+    compression: Option<Compression>,
+    hash: HashMethod,
+    encrypt: bool,
+    total_size: u64,
+    chunk_count: usize,
+    unique_chunk_count: usize,
+    unique_size: u64,
+    stored_size: u64,
+    bundle_count: usize,
+    chunk_time: f32,
+    compress_time: f32,
+    encrypt_time: f32
+}
+
+/// A result in the JSON output, the times are in seconds
+#[derive(Default)]
+struct BenchRecord {
+    chunker: String,
+    compression: String,
+    hash: String,
+    encryption: bool,
+    total_size: u64,
+    chunks: usize,
+    unique_chunks: usize,
+    unique_size: u64,
+    stored_size: u64,
+    bundles: usize,
+    chunk_time: f32,
+    compress_time: f32,
+    encrypt_time: f32,
+    total_time: f32,
+    ratio: f32
+}
+serde_impl!(BenchRecord(String) {
+    chunker: String => "chunker",
+    compression: String => "compression",
+    hash: String => "hash",
+    encryption: bool => "encryption",
+    total_size: u64 => "total_size",
+    chunks: usize => "chunks",
+    unique_chunks: usize => "unique_chunks",
+    unique_size: u64 => "unique_size",
+    stored_size: u64 => "stored_size",
+    bundles: usize => "bundles",
+    chunk_time: f32 => "chunk_time",
+    compress_time: f32 => "compress_time",
+    encrypt_time: f32 => "encrypt_time",
+    total_time: f32 => "total_time",
+    ratio: f32 => "ratio"
+});
+
+impl BenchResult {
+    #[inline]
+    fn total_time(&self) -> f32 {
+        self.chunk_time + self.compress_time + self.encrypt_time
+    }
+
+    #[inline]
+    fn compression_name(&self) -> String {
+        self.compression.as_ref().map_or_else(|| "none".to_string(), |c| c.to_string())
+    }
+}
+
+
+#[inline]
+fn to_secs(duration: Duration) -> f32 {
+    duration.as_secs() as f32 + duration.subsec_nanos() as f32 / 1_000_000_000.0
+}
+
+fn find_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<(), io::Error> {
+    let meta = try!(fs::symlink_metadata(path));
+    if meta.is_file() {
+        files.push(path.to_path_buf());
+    } else if meta.is_dir() {
+        for entry in try!(fs::read_dir(path)) {
+            let entry = try!(entry);
+            if let Err(err) = find_files(&entry.path(), files) {
+                warn!("Failed to read {:?}: {}", entry.path(), err);
+            }
+        }
+    }
+    Ok(())
+}
+
+
+struct BundleSink<'a> {
+    bundle_size: usize,
+    compression: Option<Compression>,
+    stream: Option<CompressionStream>,
+    crypto: Option<(&'a Crypto, Encryption)>,
+    data: Vec<u8>,
+    stored_size: u64,
+    bundle_count: usize,
+    compress_time: f32,
+    encrypt_time: f32
+}
+
+impl<'a> BundleSink<'a> {
+    fn add(&mut self, chunk: &[u8]) -> Result<(), CompressionError> {
+        let start = Instant::now();
+        if let Some(ref compression) = self.compression {
+            if self.stream.is_none() {
+                self.stream = Some(try!(compression.compress_stream()));
+            }
+            try!(self.stream.as_mut().unwrap().process(chunk, &mut self.data));
+        } else {
+            self.data.extend_from_slice(chunk);
+        }
+        self.compress_time += to_secs(start.elapsed());
+        if self.data.len() >= self.bundle_size {
+            try!(self.finish_bundle());
+        }
+        Ok(())
+    }
+
+    fn finish_bundle(&mut self) -> Result<(), CompressionError> {
+        let start = Instant::now();
+        if let Some(stream) = self.stream.take() {
+            try!(stream.finish(&mut self.data));
+        }
+        self.compress_time += to_secs(start.elapsed());
+        if self.data.is_empty() {
+            return Ok(());
+        }
+        let mut size = self.data.len();
+        if let Some((crypto, ref encryption)) = self.crypto {
+            let start = Instant::now();
+            size = crypto.encrypt(encryption, &self.data).unwrap().len();
+            self.encrypt_time += to_secs(start.elapsed());
+        }
+        self.stored_size += size as u64;
+        self.bundle_count += 1;
+        self.data.clear();
+        Ok(())
+    }
+}
+
+
+fn run_combination(
+    files: &[PathBuf],
+    bundle_size: usize,
+    chunker_type: ChunkerType,
+    compression: Option<Compression>,
+    hash: HashMethod,
+    crypto: Option<(&Crypto, Encryption)>,
+) -> Result<BenchResult, io::Error> {
+    let mut chunker = chunker_type.create();
+    let mut seen = HashSet::new();
+    let mut result = BenchResult {
+        chunker: chunker_type,
+        compression: compression.clone(),
+        hash: hash,
+        encrypt: crypto.is_some(),
+        total_size: 0,
+        chunk_count: 0,
+        unique_chunk_count: 0,
+        unique_size: 0,
+        stored_size: 0,
+        bundle_count: 0,
+        chunk_time: 0.0,
+        compress_time: 0.0,
+        encrypt_time: 0.0
+    };
+    let mut sink = BundleSink {
+        bundle_size: bundle_size,
+        compression: compression,
+        stream: None,
+        crypto: crypto,
+        data: Vec::with_capacity(bundle_size * 2),
+        stored_size: 0,
+        bundle_count: 0,
+        compress_time: 0.0,
+        encrypt_time: 0.0
+    };
+    let mut chunk = Vec::with_capacity(chunker_type.avg_size() * 2);
+    for path in files {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) => {
+                warn!("Failed to open {:?}: {}", path, err);
+                continue;
+            }
+        };
+        loop {
+            let start = Instant::now();
+            chunk.clear();
+            let mut output = Cursor::new(chunk);
+            let res = try!(
+                chunker
+                    .chunk(&mut file, &mut output)
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+            );
+            chunk = output.into_inner();
+            let chunk_hash = hash.hash(&chunk);
+            result.chunk_time += to_secs(start.elapsed());
+            result.total_size += chunk.len() as u64;
+            result.chunk_count += 1;
+            if seen.insert(chunk_hash) {
+                result.unique_chunk_count += 1;
+                result.unique_size += chunk.len() as u64;
+                try!(sink.add(&chunk).map_err(|err| {
+                    io::Error::new(io::ErrorKind::Other, err.to_string())
+                }));
+            }
+            if res == ChunkerStatus::Finished {
+                break;
+            }
+        }
+    }
+    try!(sink.finish_bundle().map_err(|err| {
+        io::Error::new(io::ErrorKind::Other, err.to_string())
+    }));
+    result.stored_size = sink.stored_size;
+    result.bundle_count = sink.bundle_count;
+    result.compress_time = sink.compress_time;
+    result.encrypt_time = sink.encrypt_time;
+    Ok(result)
+}
+
+
+fn print_csv(results: &[BenchResult]) {
+    println!(
+        "chunker,compression,hash,encryption,total_size,chunks,unique_chunks,unique_size,\
+         stored_size,bundles,chunk_time,compress_time,encrypt_time,total_time,ratio"
+    );
+    for r in results {
+        println!(
+            "{},{},{},{},{},{},{},{},{},{},{:.3},{:.3},{:.3},{:.3},{:.4}",
+            r.chunker.to_string(),
+            r.compression_name(),
+            r.hash.name(),
+            r.encrypt,
+            r.total_size,
+            r.chunk_count,
+            r.unique_chunk_count,
+            r.unique_size,
+            r.stored_size,
+            r.bundle_count,
+            r.chunk_time,
+            r.compress_time,
+            r.encrypt_time,
+            r.total_time(),
+            r.stored_size as f32 / r.total_size as f32
+        );
+    }
+}
+
+fn print_json(results: &[BenchResult]) {
+    let records: Vec<_> = results.iter().map(|r| {
+        BenchRecord {
+            chunker: r.chunker.to_string(),
+            compression: r.compression_name(),
+            hash: r.hash.name().to_string(),
+            encryption: r.encrypt,
+            total_size: r.total_size,
+            chunks: r.chunk_count,
+            unique_chunks: r.unique_chunk_count,
+            unique_size: r.unique_size,
+            stored_size: r.stored_size,
+            bundles: r.bundle_count,
+            chunk_time: r.chunk_time,
+            compress_time: r.compress_time,
+            encrypt_time: r.encrypt_time,
+            total_time: r.total_time(),
+            ratio: r.stored_size as f32 / r.total_size as f32
+        }
+    }).collect();
+    println!("{}", serde_json::to_string_pretty(&records).unwrap());
+}
+
+
+pub fn run(
+    path: &str,
+    bundle_size: usize,
+    chunkers: &[ChunkerType],
+    compressions: &[Option<Compression>],
+    hashes: &[HashMethod],
+    encrypt: bool,
+    json: bool,
+) -> Result<(), io::Error> {
+    let mut files = vec![];
+    try!(find_files(Path::new(path), &mut files));
+    // Reading all files once so that all combinations start with the same cache state
+    let mut total_size = 0;
+    let mut buf = vec![0; 1024 * 1024];
+    for path in &files {
+        if let Ok(mut file) = File::open(path) {
+            while let Ok(len) = file.read(&mut buf) {
+                if len == 0 {
+                    break;
+                }
+                total_size += len as u64;
+            }
+        }
+    }
+    info!("Found {} files with {}", files.len(), to_file_size(total_size));
+    let mut crypto = Crypto::dummy();
+    let encryption = if encrypt {
+        let (public, secret) = Crypto::gen_keypair();
+        crypto.add_secret_key(public, secret);
+        Some((EncryptionMethod::Sodium, public[..].to_vec().into()))
+    } else {
+        None
+    };
+    let mut results = vec![];
+    for chunker in chunkers {
+        for compression in compressions {
+            for hash in hashes {
+                info!(
+                    "Testing {}, {}, {}",
+                    chunker.to_string(),
+                    compression.as_ref().map_or_else(|| "none".to_string(), |c| c.to_string()),
+                    hash.name()
+                );
+                results.push(try!(run_combination(
+                    &files,
+                    bundle_size,
+                    *chunker,
+                    compression.clone(),
+                    *hash,
+                    encryption.clone().map(|e| (&crypto, e))
+                )));
+            }
+        }
+    }
+    if json {
+        print_json(&results);
+    } else {
+        print_csv(&results);
+    }
+    Ok(())
+}
+
+
+/// Estimates the size of the index for the given number of chunks
+fn estimate_index_size(chunks: usize) -> u64 {
+    let mut capacity = INITIAL_SIZE;
+    while capacity as f64 * MAX_USAGE < chunks as f64 {
+        capacity *= 2;
+    }
+    (capacity * (mem::size_of::<Hash>() + mem::size_of::<Location>())) as u64
+}
+
+pub fn analyze_chunking(
+    path: &str,
+    chunkers: &[ChunkerType],
+    hash: HashMethod,
+) -> Result<(), io::Error> {
+    let mut files = vec![];
+    try!(find_files(Path::new(path), &mut files));
+    info!("Found {} files", files.len());
+    let mut best = None;
+    println!(
+        "{:15}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}",
+        "Chunker",
+        "Chunks",
+        "Unique",
+        "Avg size",
+        "Dedup",
+        "Index",
+        "Total"
+    );
+    for chunker in chunkers {
+        info!("Chunking with {}", chunker.to_string());
+        let result = try!(run_combination(&files, 1024 * 1024, *chunker, None, hash, None));
+        let index_size = estimate_index_size(result.unique_chunk_count);
+        // Every file stores its chunk list with 20 bytes per chunk
+        let total = result.unique_size + index_size + result.chunk_count as u64 * 20;
+        println!(
+            "{:15}  {:>10}  {:>10}  {:>10}  {:>9.1}%  {:>10}  {:>10}",
+            chunker.to_string(),
+            result.chunk_count,
+            result.unique_chunk_count,
+            to_file_size(result.total_size / max(result.chunk_count, 1) as u64),
+            (1.0 - result.unique_size as f32 / result.total_size as f32) * 100.0,
+            to_file_size(index_size),
+            to_file_size(total)
+        );
+        if best.as_ref().map_or(true, |&(_, best_total)| total < best_total) {
+            best = Some((*chunker, total));
+        }
+    }
+    if let Some((chunker, _)) = best {
+        println!();
+        println!(
+            "Recommended chunker: {} (use with `zvault init --chunker {}`)",
+            chunker.to_string(),
+            chunker.to_string()
+        );
+    }
+    Ok(())
+}
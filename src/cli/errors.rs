@@ -0,0 +1,301 @@
+use prelude::*;
+use bundledb::BundleReaderError;
+
+use serde_json;
+use libc;
+
+use std::any::Any;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+
+use super::ErrorCode;
+use super::logger;
+
+
+// Whether failures are reported as JSON objects, set via --output json
+static JSON_OUTPUT: AtomicBool = ATOMIC_BOOL_INIT;
+
+lazy_static! {
+    // The failure that made the command fail, recorded by the checked! macro
+    static ref FAILURE: Mutex<Option<ErrorObject>> = Mutex::new(None);
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCategory {
+    InvalidArguments,
+    NotFound,
+    SourceVanished,
+    RemoteUnreachable,
+    PermissionDenied,
+    NoSpace,
+    Locked,
+    Interrupted,
+    Encryption,
+    Integrity,
+    Io,
+    Failed
+}
+
+impl ErrorCategory {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ErrorCategory::InvalidArguments => "invalid-arguments",
+            ErrorCategory::NotFound => "not-found",
+            ErrorCategory::SourceVanished => "source-vanished",
+            ErrorCategory::RemoteUnreachable => "remote-unreachable",
+            ErrorCategory::PermissionDenied => "permission-denied",
+            ErrorCategory::NoSpace => "no-space",
+            ErrorCategory::Locked => "locked",
+            ErrorCategory::Interrupted => "interrupted",
+            ErrorCategory::Encryption => "encryption",
+            ErrorCategory::Integrity => "integrity",
+            ErrorCategory::Io => "io",
+            ErrorCategory::Failed => "failed",
+        }
+    }
+
+    /// Whether running the same command again later can succeed without any intervention
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            ErrorCategory::SourceVanished |
+            ErrorCategory::RemoteUnreachable |
+            ErrorCategory::Locked |
+            ErrorCategory::Interrupted => true,
+            _ => false,
+        }
+    }
+
+    /// The exit code for this category if it is more specific than the one of the operation
+    fn error_code(&self) -> Option<ErrorCode> {
+        match *self {
+            ErrorCategory::SourceVanished => Some(ErrorCode::SourceVanished),
+            ErrorCategory::RemoteUnreachable => Some(ErrorCode::RemoteUnreachable),
+            ErrorCategory::PermissionDenied => Some(ErrorCode::PermissionDenied),
+            ErrorCategory::NoSpace => Some(ErrorCode::NoSpace),
+            ErrorCategory::Locked => Some(ErrorCode::RepositoryLocked),
+            ErrorCategory::Interrupted => Some(ErrorCode::Interrupted),
+            _ => None,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Default)]
+struct ErrorObject {
+    code: i32,
+    category: String,
+    message: String,
+    path: Option<String>,
+    retryable: bool
+}
+serde_impl!(ErrorObject(String) {
+    code: i32 => "code",
+    category: String => "category",
+    message: String => "message",
+    path: Option<String> => "path",
+    retryable: bool => "retryable"
+});
+
+
+pub fn set_json_output(json: bool) {
+    JSON_OUTPUT.store(json, Ordering::SeqCst);
+}
+
+
+#[cfg(unix)]
+fn os_error_category(code: i32) -> Option<ErrorCategory> {
+    match code {
+        libc::ENOSPC | libc::EDQUOT => Some(ErrorCategory::NoSpace),
+        libc::ENOTCONN | libc::ESTALE | libc::EHOSTDOWN | libc::EHOSTUNREACH |
+        libc::ENETDOWN | libc::ENETUNREACH => Some(ErrorCategory::RemoteUnreachable),
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+fn os_error_category(code: i32) -> Option<ErrorCategory> {
+    // ERROR_HANDLE_DISK_FULL, ERROR_DISK_FULL and ERROR_BAD_NETPATH
+    match code {
+        39 | 112 => Some(ErrorCategory::NoSpace),
+        53 => Some(ErrorCategory::RemoteUnreachable),
+        _ => None,
+    }
+}
+
+fn io_category(err: &io::Error) -> ErrorCategory {
+    if let Some(category) = err.raw_os_error().and_then(os_error_category) {
+        return category;
+    }
+    match err.kind() {
+        io::ErrorKind::NotFound => ErrorCategory::NotFound,
+        io::ErrorKind::PermissionDenied => ErrorCategory::PermissionDenied,
+        io::ErrorKind::ConnectionRefused |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::NotConnected |
+        io::ErrorKind::TimedOut => ErrorCategory::RemoteUnreachable,
+        _ => ErrorCategory::Io,
+    }
+}
+
+fn classify_inode(err: &InodeError) -> (ErrorCategory, Option<&Path>) {
+    match *err {
+        InodeError::ReadMetadata(ref err, ref path) |
+        InodeError::ReadXattr(ref err, ref path) |
+        InodeError::ReadLinkTarget(ref err, ref path) => {
+            // The file was listed but is gone when it is read
+            if err.kind() == io::ErrorKind::NotFound {
+                (ErrorCategory::SourceVanished, Some(path))
+            } else {
+                (io_category(err), Some(path))
+            }
+        }
+        InodeError::Create(ref err, ref path) => (io_category(err), Some(path)),
+        InodeError::UnsupportedFiletype(ref path) => (ErrorCategory::Failed, Some(path)),
+        _ => (ErrorCategory::Integrity, None),
+    }
+}
+
+fn classify_reader(err: &BundleReaderError) -> (ErrorCategory, Option<&Path>) {
+    match *err {
+        BundleReaderError::Read(ref err, ref path) => (io_category(err), Some(path)),
+        BundleReaderError::Decryption(_, ref path) => (ErrorCategory::Encryption, Some(path)),
+        BundleReaderError::WrongHeader(ref path) |
+        BundleReaderError::UnsupportedVersion(ref path, _) |
+        BundleReaderError::Decode(_, ref path) |
+        BundleReaderError::Decompression(_, ref path) |
+        BundleReaderError::Malformed(ref path, _) => (ErrorCategory::Integrity, Some(path)),
+        _ => (ErrorCategory::Integrity, None),
+    }
+}
+
+fn classify_bundledb(err: &BundleDbError) -> (ErrorCategory, Option<&Path>) {
+    match *err {
+        BundleDbError::ListBundles(ref err) |
+        BundleDbError::Remove(ref err, _) => (io_category(err), None),
+        BundleDbError::Io(ref err, ref path) => (io_category(err), Some(path)),
+        BundleDbError::Reader(ref err) => classify_reader(err),
+        BundleDbError::Writer(BundleWriterError::Write(ref err, ref path)) => {
+            (io_category(err), Some(path))
+        }
+        BundleDbError::Proxy(_) |
+        BundleDbError::TransferFailed |
+        BundleDbError::UploadFailed => (ErrorCategory::RemoteUnreachable, None),
+        BundleDbError::UploadMismatch(ref path) => (ErrorCategory::Integrity, Some(path)),
+        BundleDbError::WrongChunkData(..) |
+        BundleDbError::NoSuchBundle(_) |
+        BundleDbError::Dictionary(_) |
+        BundleDbError::NoSuchDictionary(_) => (ErrorCategory::Integrity, None),
+        _ => (ErrorCategory::Failed, None),
+    }
+}
+
+fn classify_lock(err: &LockError) -> (ErrorCategory, Option<&Path>) {
+    match *err {
+        LockError::Locked => (ErrorCategory::Locked, None),
+        LockError::Io(ref err) => (io_category(err), None),
+        _ => (ErrorCategory::Failed, None),
+    }
+}
+
+fn classify_repository(err: &RepositoryError) -> (ErrorCategory, Option<&Path>) {
+    match *err {
+        RepositoryError::NoRemote => (ErrorCategory::RemoteUnreachable, None),
+        RepositoryError::BundleDb(ref err) => classify_bundledb(err),
+        RepositoryError::BundleWriter(BundleWriterError::Write(ref err, ref path)) => {
+            (io_category(err), Some(path))
+        }
+        RepositoryError::BackupFile(BackupFileError::Read(ref err, ref path)) |
+        RepositoryError::BackupFile(BackupFileError::Write(ref err, ref path)) => {
+            (io_category(err), Some(path))
+        }
+        RepositoryError::BackupFile(BackupFileError::Decryption(_, ref path)) => {
+            (ErrorCategory::Encryption, Some(path))
+        }
+        RepositoryError::Inode(ref err) => classify_inode(err),
+        RepositoryError::Lock(ref err) => classify_lock(err),
+        RepositoryError::Io(ref err) |
+        RepositoryError::Index(IndexError::Io(ref err)) => (io_category(err), None),
+        RepositoryError::LoadKeys(_) => (ErrorCategory::Encryption, None),
+        RepositoryError::Integrity(_) |
+        RepositoryError::Dirty => (ErrorCategory::Integrity, None),
+        RepositoryError::Backup(BackupError::TargetExists(ref path)) |
+        RepositoryError::Backup(BackupError::SpecialFile(ref path)) => {
+            (ErrorCategory::Failed, Some(path))
+        }
+        RepositoryError::Backup(BackupError::Interrupted) => (ErrorCategory::Interrupted, None),
+        RepositoryError::NoSuchFileInBackup(_, ref path) => (ErrorCategory::NotFound, Some(path)),
+        RepositoryError::NoSuchRemote(_) |
+        RepositoryError::NotInTrash(_) => (ErrorCategory::NotFound, None),
+        RepositoryError::BackupExists(_) |
+        RepositoryError::NotADirectory(_) |
+        RepositoryError::Protected(..) => (ErrorCategory::InvalidArguments, None),
+        _ => (ErrorCategory::Failed, None),
+    }
+}
+
+fn classify(err: &Any) -> (ErrorCategory, Option<&Path>) {
+    if let Some(err) = err.downcast_ref::<RepositoryError>() {
+        classify_repository(err)
+    } else if let Some(err) = err.downcast_ref::<io::Error>() {
+        (io_category(err), None)
+    } else if let Some(err) = err.downcast_ref::<BundleDbError>() {
+        classify_bundledb(err)
+    } else if let Some(err) = err.downcast_ref::<InodeError>() {
+        classify_inode(err)
+    } else if let Some(err) = err.downcast_ref::<LockError>() {
+        classify_lock(err)
+    } else if err.downcast_ref::<EncryptionError>().is_some() {
+        (ErrorCategory::Encryption, None)
+    } else {
+        (ErrorCategory::Failed, None)
+    }
+}
+
+
+/// Logs the failed operation and records it for the final error report
+///
+/// The returned error code is more specific than `code` if the cause of the error is known,
+/// e.g. when the remote storage is unreachable.
+pub fn fail<E: fmt::Display + Any>(err: &E, msg: &str, code: ErrorCode) -> ErrorCode {
+    error!("Failed to {}\n\tcaused by: {}", msg, err);
+    let (category, path) = classify(err);
+    let code = category.error_code().unwrap_or(code);
+    *FAILURE.lock().unwrap() = Some(ErrorObject {
+        code: code.code(),
+        category: category.name().to_string(),
+        message: format!("Failed to {}: {}", msg, err),
+        path: path.map(|p| p.to_string_lossy().to_string()),
+        retryable: category.is_retryable()
+    });
+    code
+}
+
+/// Prints the error of a failed command as a single JSON object if `--output json` is set
+pub fn report_error(code: &ErrorCode) {
+    if !JSON_OUTPUT.load(Ordering::SeqCst) {
+        return;
+    }
+    let recorded = FAILURE.lock().unwrap().take();
+    let error = match recorded {
+        Some(error) if error.code == code.code() => error,
+        _ => {
+            let category = code.category();
+            ErrorObject {
+                code: code.code(),
+                category: category.name().to_string(),
+                message: logger::last_error().unwrap_or_else(|| "The command failed".to_string()),
+                path: None,
+                retryable: category.is_retryable()
+            }
+        }
+    };
+    match serde_json::to_string(&error) {
+        Ok(json) => println!("{}", json),
+        Err(err) => println!("{{\"code\": {}, \"message\": {:?}}}", code.code(), err.to_string()),
+    }
+}
@@ -3,6 +3,7 @@ pub use log::SetLoggerError;
 
 use ansi_term::{Color, Style};
 use std::io::Write;
+use std::sync::Mutex;
 
 
 macro_rules! println_stderr(
@@ -12,6 +13,11 @@ macro_rules! println_stderr(
     } }
 );
 
+lazy_static! {
+    // The last error that has been logged, used when reporting a failed command
+    static ref LAST_ERROR: Mutex<Option<String>> = Mutex::new(None);
+}
+
 struct Logger(LogLevel);
 
 impl log::Log for Logger {
@@ -20,6 +26,9 @@ impl log::Log for Logger {
     }
 
     fn log(&self, record: &LogRecord) {
+        if record.level() == LogLevel::Error {
+            *LAST_ERROR.lock().unwrap() = Some(format!("{}", record.args()));
+        }
         if self.enabled(record.metadata()) {
             match record.level() {
                 LogLevel::Error => {
@@ -50,3 +59,7 @@ pub fn init(level: LogLevel) -> Result<(), SetLoggerError> {
         Box::new(Logger(level))
     })
 }
+
+pub fn last_error() -> Option<String> {
+    LAST_ERROR.lock().unwrap().clone()
+}
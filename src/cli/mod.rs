@@ -1,20 +1,33 @@
 mod args;
 mod logger;
 mod algotest;
+mod bench;
+mod errors;
 
 use prelude::*;
 
 use chrono::prelude::*;
 use regex::{self, RegexSet};
+use ansi_term::Color;
+use libc;
 
-use std::collections::HashMap;
-use std::io::{BufReader, BufRead};
-use std::fs::File;
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufReader, BufRead, Read, Write};
+use std::fs::{self, File};
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
 use std::env;
 use std::str;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use std::process::Command;
+use std::time::Duration;
 
 use self::args::Arguments;
+use self::errors::{fail, set_json_output, ErrorCategory};
+pub use self::errors::report_error;
 
 
 pub enum ErrorCode {
@@ -45,7 +58,33 @@ pub enum ErrorCode {
     DiffRun,
     VersionsRun,
     ImportRun,
-    FuseMount
+    FuseMount,
+    BenchRun,
+    LogRun,
+    ManifestRun,
+    ManifestMismatch,
+    TrainDictionary,
+    PurgeRun,
+    ProxyRun,
+    DoesNotExist,
+    BackupOverdue,
+    UndeleteRun,
+    CloneRun,
+    ExportRun,
+    EditRun,
+    AgentRun,
+    CodecHelperRun,
+    PublishRun,
+    BackupRunning,
+    RemoteCheckRun,
+    SecretKeyRequired,
+    SourceVanished,
+    RemoteUnreachable,
+    RepositoryLocked,
+    PermissionDenied,
+    NoSpace,
+    Interrupted,
+    SystemdRun
 }
 impl ErrorCode {
     pub fn code(&self) -> i32 {
@@ -54,7 +93,8 @@ impl ErrorCode {
             ErrorCode::InitializeLogger |
             ErrorCode::InvalidExcludes => -1,
             // Arguments
-            ErrorCode::InvalidArgs => 1,
+            ErrorCode::InvalidArgs |
+            ErrorCode::DoesNotExist => 1,
             ErrorCode::UnsafeArgs => 2,
             // Load things
             ErrorCode::LoadRepository => 3,
@@ -84,6 +124,54 @@ impl ErrorCode {
             //
             ErrorCode::NoSuchBackup => 25,
             ErrorCode::BackupAlreadyExists => 26,
+            ErrorCode::BenchRun => 27,
+            ErrorCode::LogRun => 28,
+            ErrorCode::ManifestRun => 29,
+            ErrorCode::ManifestMismatch => 30,
+            ErrorCode::TrainDictionary => 31,
+            ErrorCode::PurgeRun => 32,
+            ErrorCode::ProxyRun => 33,
+            ErrorCode::BackupOverdue => 34,
+            ErrorCode::UndeleteRun => 35,
+            ErrorCode::CloneRun => 36,
+            ErrorCode::ExportRun => 37,
+            ErrorCode::EditRun => 38,
+            ErrorCode::AgentRun => 39,
+            // Causes
+            ErrorCode::SourceVanished => 40,
+            ErrorCode::RemoteUnreachable => 41,
+            ErrorCode::RepositoryLocked => 42,
+            ErrorCode::PermissionDenied => 43,
+            ErrorCode::NoSpace => 44,
+            ErrorCode::Interrupted => 45,
+            //
+            ErrorCode::SystemdRun => 46,
+            ErrorCode::CodecHelperRun => 47,
+            ErrorCode::PublishRun => 48,
+            ErrorCode::BackupRunning => 49,
+            ErrorCode::RemoteCheckRun => 50,
+            ErrorCode::SecretKeyRequired => 51,
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match *self {
+            ErrorCode::InvalidArgs |
+            ErrorCode::UnsafeArgs |
+            ErrorCode::InvalidExcludes |
+            ErrorCode::BackupAlreadyExists => ErrorCategory::InvalidArguments,
+            ErrorCode::DoesNotExist |
+            ErrorCode::NoSuchBackup => ErrorCategory::NotFound,
+            ErrorCode::ManifestMismatch => ErrorCategory::Integrity,
+            ErrorCode::SecretKeyRequired => ErrorCategory::Encryption,
+            ErrorCode::SourceVanished => ErrorCategory::SourceVanished,
+            ErrorCode::RemoteUnreachable => ErrorCategory::RemoteUnreachable,
+            ErrorCode::RepositoryLocked |
+            ErrorCode::BackupRunning => ErrorCategory::Locked,
+            ErrorCode::PermissionDenied => ErrorCategory::PermissionDenied,
+            ErrorCode::NoSpace => ErrorCategory::NoSpace,
+            ErrorCode::Interrupted => ErrorCategory::Interrupted,
+            _ => ErrorCategory::Failed,
         }
     }
 }
@@ -94,33 +182,57 @@ pub const DEFAULT_HASH: &'static str = "blake2";
 pub const DEFAULT_COMPRESSION: &'static str = "brotli/3";
 pub const DEFAULT_BUNDLE_SIZE_STR: &'static str = "25";
 pub const DEFAULT_VACUUM_RATIO_STR: &'static str = "0";
+pub const DEFAULT_PROXY_ADDR: &'static str = ":9000";
+pub const DEFAULT_CHECK_THREADS: usize = 4;
+pub const BACKGROUND_MAX_MEMORY: usize = 256 * 1024 * 1024;
+pub const CHUNKING_ADVISOR_CHUNKERS: [&'static str; 5] =
+    ["fastcdc/4", "fastcdc/8", "fastcdc/16", "fastcdc/32", "fastcdc/64"];
+// Memory limit of the repository in bytes given via --max-memory, 0 means no limit
+static MAX_MEMORY: AtomicUsize = ATOMIC_USIZE_INIT;
+
 lazy_static! {
     pub static ref ZVAULT_FOLDER: PathBuf = {
         env::home_dir().unwrap().join(".zvault")
     };
+    // Address of the bundle proxy given via --proxy
+    static ref PROXY: Mutex<Option<String>> = Mutex::new(None);
 }
 
 macro_rules! checked {
     ($expr:expr, $msg:expr, $code:expr) => {
         match $expr {
             Ok(val) => val,
-            Err(err) => {
-                error!("Failed to {}\n\tcaused by: {}", $msg, err);
-                return Err($code)
-            }
+            Err(err) => return Err(fail(&err, $msg, $code)),
         }
     };
 }
 
 fn open_repository(path: &Path) -> Result<Repository, ErrorCode> {
-    Ok(checked!(
+    let mut repo = checked!(
         Repository::open(path),
         "load repository",
         ErrorCode::LoadRepository
-    ))
+    );
+    let max_memory = MAX_MEMORY.load(Ordering::SeqCst);
+    if max_memory > 0 {
+        repo.set_memory_limit(max_memory);
+    }
+    repo.set_proxy(PROXY.lock().unwrap().clone());
+    Ok(repo)
+}
+
+/// Fails if the repository can not be read because only its public key is available
+fn require_secret_key(repo: &Repository) -> Result<(), ErrorCode> {
+    if repo.is_write_only() {
+        error!("The secret key of the repository is required, only its public key is available");
+        info!("Hosts with only the public key can create backups and check the structure");
+        return Err(ErrorCode::SecretKeyRequired);
+    }
+    Ok(())
 }
 
 fn get_backup(repo: &Repository, backup_name: &str) -> Result<Backup, ErrorCode> {
+    try!(require_secret_key(repo));
     if !repo.has_backup(backup_name) {
         error!("A backup with that name does not exist");
         return Err(ErrorCode::NoSuchBackup);
@@ -132,28 +244,42 @@ fn get_backup(repo: &Repository, backup_name: &str) -> Result<Backup, ErrorCode>
     ))
 }
 
-fn find_reference_backup(
-    repo: &Repository,
-    path: &str,
-) -> Result<Option<(String, Backup)>, ErrorCode> {
-    let mut matching = Vec::new();
-    let hostname = match get_hostname() {
-        Ok(hostname) => hostname,
-        Err(_) => return Ok(None),
-    };
-    let backup_map = match repo.get_all_backups() {
-        Ok(backup_map) => backup_map,
+fn get_reference_candidates(repo: &Repository) -> Result<HashMap<String, Backup>, ErrorCode> {
+    if repo.is_write_only() {
+        info!("Backups can not be read without the secret key, not using a reference");
+        return Ok(HashMap::new());
+    }
+    match repo.get_all_backups() {
+        Ok(backup_map) => Ok(backup_map),
         Err(RepositoryError::BackupFile(BackupFileError::PartialBackupsList(backup_map,
                                                                             _failed))) => {
             warn!("Some backups could not be read, ignoring them");
-            backup_map
+            Ok(backup_map)
         }
         Err(err) => {
             error!("Failed to load backup files: {}", err);
-            return Err(ErrorCode::LoadBackup);
+            Err(ErrorCode::LoadBackup)
+        }
+    }
+}
+
+/// Returns the latest backup of the path from the given host or the local host
+fn find_reference_backup(
+    repo: &Repository,
+    path: &str,
+    host: Option<&str>,
+) -> Result<Option<(String, Backup)>, ErrorCode> {
+    let mut matching = Vec::new();
+    let hostname = match host {
+        Some(host) => host.to_string(),
+        None => {
+            match get_hostname() {
+                Ok(hostname) => hostname,
+                Err(_) => return Ok(None),
+            }
         }
     };
-    for (name, backup) in backup_map {
+    for (name, backup) in try!(get_reference_candidates(repo)) {
         if backup.host == hostname && backup.path == path {
             matching.push((name, backup));
         }
@@ -162,7 +288,219 @@ fn find_reference_backup(
     Ok(matching.pop())
 }
 
-fn print_backup(backup: &Backup) {
+/// Returns the backup of any host and path whose top-level names overlap most with the path
+///
+/// The overlap is measured as the number of shared names relative to all names of both, ties
+/// are broken by taking the latest backup. Backups without any shared name are not used.
+fn find_similar_backup(
+    repo: &mut Repository,
+    path: &str,
+) -> Result<Option<(String, Backup)>, ErrorCode> {
+    let names: HashSet<String> = match fs::read_dir(path) {
+        Ok(entries) => {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .collect()
+        }
+        Err(_) => {
+            Path::new(path)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .into_iter()
+                .collect()
+        }
+    };
+    let mut best: Option<(usize, usize, String, Backup)> = None;
+    for (name, backup) in try!(get_reference_candidates(repo)) {
+        let root = match repo.get_inode(&backup.root) {
+            Ok(root) => root,
+            Err(err) => {
+                warn!("Failed to load the root of backup {}: {}", name, err);
+                continue;
+            }
+        };
+        let other: HashSet<String> = match root.children {
+            Some(ref children) => children.keys().cloned().collect(),
+            None => Some(root.name.clone()).into_iter().collect(),
+        };
+        let shared = names.intersection(&other).count();
+        if shared == 0 {
+            continue;
+        }
+        let total = names.union(&other).count();
+        let better = match best {
+            None => true,
+            Some((best_shared, best_total, _, ref best_backup)) => {
+                // Comparing shared / total with best_shared / best_total without rounding
+                let (score, best_score) = (shared * best_total, best_shared * total);
+                score > best_score ||
+                    (score == best_score && backup.timestamp > best_backup.timestamp)
+            }
+        };
+        if better {
+            best = Some((shared, total, name, backup));
+        }
+    }
+    Ok(best.map(|(_, _, name, backup)| (name, backup)))
+}
+
+/// Converts shell-like path patterns into a regex set matching paths starting with `/`
+///
+/// Empty lines and lines starting with `#` are ignored so that exclude files can contain comments.
+fn parse_patterns(patterns: &[String]) -> Result<Option<RegexSet>, ErrorCode> {
+    let mut parsed = Vec::with_capacity(patterns.len());
+    for pattern in patterns {
+        if pattern.starts_with('#') || pattern.is_empty() {
+            continue;
+        }
+        let pattern = regex::escape(pattern)
+            .replace('?', ".")
+            .replace(r"\*\*", ".*")
+            .replace(r"\*", "[^/]*");
+        parsed.push(if pattern.starts_with('/') {
+            format!(r"^{}($|/)", pattern)
+        } else {
+            format!(r"/{}($|/)", pattern)
+        });
+    }
+    if parsed.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(checked!(
+        RegexSet::new(parsed),
+        "parse exclude patterns",
+        ErrorCode::InvalidExcludes
+    )))
+}
+
+fn read_lines(data: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(data).lines().map(|line| line.to_string()).collect()
+}
+
+/// Reads the files placed into a new repository
+///
+/// Files given on the command line take precedence over the user templates in
+/// `~/.zvault/templates` which take precedence over the built-in defaults.
+fn load_templates(
+    excludes: Option<String>,
+    readme: Option<String>,
+) -> Result<RepositoryTemplates, ErrorCode> {
+    let mut templates = RepositoryTemplates::default();
+    let folder = ZVAULT_FOLDER.join("templates");
+    let user_excludes = folder.join("excludes");
+    let user_readme = folder.join("README.md");
+    let excludes = excludes.map(PathBuf::from).or_else(|| if user_excludes.exists() {
+        Some(user_excludes)
+    } else {
+        None
+    });
+    let readme = readme.map(PathBuf::from).or_else(|| if user_readme.exists() {
+        Some(user_readme)
+    } else {
+        None
+    });
+    if let Some(path) = excludes {
+        info!("Using excludes template {:?}", path);
+        let mut data = vec![];
+        checked!(
+            File::open(&path).and_then(|mut f| f.read_to_end(&mut data)),
+            "read excludes template",
+            ErrorCode::LoadExcludes
+        );
+        try!(parse_patterns(&read_lines(&data)));
+        templates.excludes = data;
+    }
+    if let Some(path) = readme {
+        info!("Using README template {:?}", path);
+        let mut data = vec![];
+        checked!(
+            File::open(&path).and_then(|mut f| f.read_to_end(&mut data)),
+            "read README template",
+            ErrorCode::CreateRepository
+        );
+        templates.readme = data;
+    }
+    Ok(templates)
+}
+
+/// Opens the file in the editor given by `$VISUAL` or `$EDITOR` and waits until it is closed
+fn run_editor(path: &Path) -> Result<(), io::Error> {
+    let editor = env::var("VISUAL").or_else(|_| env::var("EDITOR")).unwrap_or_else(
+        |_| "vi".to_string()
+    );
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().unwrap_or("vi");
+    let status = try!(Command::new(program).args(parts).arg(path).status());
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} exited with {}", program, status)
+        ))
+    }
+}
+
+/// Shows desktop notifications about a backup run, a failure is reported if it is dropped early
+struct BackupNotification {
+    name: String,
+    finished: bool
+}
+
+impl BackupNotification {
+    fn start(name: &str, src_path: &str) -> Self {
+        desktop_notify(
+            &format!("Backup {} started", name),
+            &format!("Backing up {}", src_path),
+            false
+        );
+        BackupNotification {
+            name: name.to_string(),
+            finished: false
+        }
+    }
+
+    fn finish(mut self, backup: &Backup, failed: usize) {
+        self.finished = true;
+        let mut body = format!(
+            "{} files, {} modified, {} new data in {}",
+            backup.file_count,
+            to_file_size(backup.changed_data_size),
+            to_file_size(backup.deduplicated_data_size),
+            to_duration(backup.duration)
+        );
+        if failed > 0 {
+            body.push_str(&format!("\n{} files could not be backed up", failed));
+        }
+        desktop_notify(&format!("Backup {} finished", self.name), &body, failed > 0);
+    }
+}
+
+impl Drop for BackupNotification {
+    fn drop(&mut self) {
+        if !self.finished {
+            let reason = logger::last_error().unwrap_or_else(|| "The backup failed".to_string());
+            desktop_notify(&format!("Backup {} failed", self.name), &reason, true);
+        }
+    }
+}
+
+/// Prints the rules so that the output can be used with `--no-default-excludes --excludes-from`
+fn print_backup_rules(name: &str, rules: &BackupRules) {
+    println!("# Exclude rules of backup {}", name);
+    let args = rules.to_args();
+    if args.is_empty() {
+        println!("# Options: none");
+    } else {
+        println!("# Options: {}", args.join(" "));
+    }
+    for exclude in &rules.excludes {
+        println!("{}", exclude);
+    }
+}
+
+fn print_backup(backup: &Backup, stats: bool) {
     if backup.modified {
         warn!("This backup has been modified");
     }
@@ -171,6 +509,15 @@ fn print_backup(backup: &Backup) {
         Local.timestamp(backup.timestamp, 0).to_rfc2822()
     );
     println!("Source: {}:{}", backup.host, backup.path);
+    if backup.source.is_known() {
+        print_source_info(&backup.source);
+    }
+    if !backup.tags.is_empty() {
+        println!("Tags: {}", backup.tags.join(", "));
+    }
+    if !backup.remotes.is_empty() {
+        println!("Copies on: {}", backup.remotes.join(", "));
+    }
     println!("Duration: {}", to_duration(backup.duration));
     println!(
         "Entries: {} files, {} dirs",
@@ -203,45 +550,249 @@ fn print_backup(backup: &Backup) {
         backup.chunk_count,
         to_file_size(backup.avg_chunk_size as u64)
     );
+    if backup.zero_chunk_count > 0 {
+        println!("Zero chunks: {}", backup.zero_chunk_count);
+    }
+    if !backup.extensions.is_empty() {
+        println!();
+        println!("Top file types by new data:");
+        print_extension_stats(&backup.extensions, Some(10));
+    }
+    if stats {
+        println!();
+        print_backup_timings(&backup.timings, backup.duration);
+    }
+}
+
+fn print_source_info(source: &SourceInfo) {
+    if let Some(ref snapshot) = source.snapshot {
+        println!("Snapshot: {}", snapshot);
+    }
+    if !source.fs_type.is_empty() {
+        let uuid = source.uuid.as_ref().map(|uuid| format!(", UUID {}", uuid)).unwrap_or_default();
+        println!(
+            "Filesystem: {} on {}{}, mounted at {} with {}",
+            source.fs_type,
+            source.device,
+            uuid,
+            source.mount_point,
+            source.mount_options.join(",")
+        );
+        if source.fs_root != "/" {
+            println!("Filesystem root: {}", source.fs_root);
+        }
+    }
+    println!(
+        "Consistency: {}",
+        if source.is_consistent() {
+            "consistent (snapshot or read-only)"
+        } else {
+            "live filesystem, files may have changed during the backup"
+        }
+    );
+}
+
+fn print_backup_timings(timings: &BackupTimings, duration: f32) {
+    println!("Time spent:");
+    for &(name, time) in &[
+        ("traversal", timings.traversal),
+        ("chunking", timings.chunking),
+        ("hashing", timings.hashing),
+        ("compression", timings.compression),
+        ("upload", timings.upload),
+        ("flush", timings.flush),
+    ]
+    {
+        println!(
+            "  - {:12} {}, {:5.1}%",
+            name,
+            to_duration(time),
+            time / duration.max(0.001) * 100.0
+        );
+    }
+}
+
+/// Returns the percentage of the size that has been saved, 0 for empty sizes
+fn saved_percent(size: u64, reduced_size: u64) -> f32 {
+    if size == 0 {
+        0.0
+    } else {
+        (1.0 - reduced_size as f32 / size as f32) * 100.0
+    }
+}
+
+fn print_extension_stats(stats: &HashMap<String, ExtensionStats>, limit: Option<usize>) {
+    let mut stats: Vec<_> = stats.iter().collect();
+    stats.sort_by(|a, b| b.1.deduplicated_data_size.cmp(&a.1.deduplicated_data_size));
+    if let Some(limit) = limit {
+        stats.truncate(limit);
+    }
+    println!(
+        "{:12}  {:>8}  {:>10}  {:>10}  {:>10}  {:>7}  {:>10}  {:>7}",
+        "Extension",
+        "Files",
+        "Size",
+        "Modified",
+        "Dedup",
+        "Saved",
+        "Compr",
+        "Saved"
+    );
+    for (ext, stats) in stats {
+        println!(
+            "{:12}  {:>8}  {:>10}  {:>10}  {:>10}  {:>6.1}%  {:>10}  {:>6.1}%",
+            if ext.is_empty() { "(none)" } else { ext.as_str() },
+            stats.file_count,
+            to_file_size(stats.total_data_size),
+            to_file_size(stats.changed_data_size),
+            to_file_size(stats.deduplicated_data_size),
+            saved_percent(stats.changed_data_size, stats.deduplicated_data_size),
+            to_file_size(stats.encoded_data_size),
+            saved_percent(stats.deduplicated_data_size, stats.encoded_data_size)
+        );
+    }
+}
+
+fn print_host_analysis(analysis: &HostAnalysis) {
+    println!("{:30}  {:>10}  {:>10}  {:>10}", "Host", "Size", "Encoded", "Unique");
+    for &(ref host, raw_size, encoded_size) in &analysis.hosts {
+        let unique = analysis
+            .shares
+            .iter()
+            .find(|share| share.hosts.len() == 1 && share.hosts[0] == *host)
+            .map(|share| share.encoded_size)
+            .unwrap_or(0);
+        println!(
+            "{:30}  {:>10}  {:>10}  {:>10}",
+            host,
+            to_file_size(raw_size),
+            to_file_size(encoded_size),
+            to_file_size(unique)
+        );
+    }
+    println!();
+    println!("{:>10}  {:>10}  {:>10}  Shared by", "Chunks", "Size", "Encoded");
+    for share in &analysis.shares {
+        println!(
+            "{:>10}  {:>10}  {:>10}  {}",
+            share.chunk_count,
+            to_file_size(share.raw_size),
+            to_file_size(share.encoded_size),
+            share.hosts.join(", ")
+        );
+    }
+    println!();
+    let stored = analysis.stored_size();
+    let separate = analysis.separate_size();
+    // The sizes are estimated, so the rounding errors could make the difference negative
+    let saved = separate.saturating_sub(stored);
+    println!("Stored data: {}", to_file_size(stored));
+    println!("Data with a repository per host: {}", to_file_size(separate));
+    println!(
+        "Saved by deduplication across hosts: {}, {:.1}%",
+        to_file_size(saved),
+        saved as f32 / separate.max(1) as f32 * 100.0
+    );
+}
+
+fn print_origin_analysis(origins: &[OriginUsage]) {
+    println!("{:30}  {:>8}  {:>10}  {:>10}", "Host", "Bundles", "Size", "Used");
+    for origin in origins {
+        println!(
+            "{:30}  {:>8}  {:>10}  {:>10}",
+            origin.host.as_ref().map_or("(unknown)", |host| host.as_str()),
+            origin.bundle_count,
+            to_file_size(origin.encoded_size),
+            to_file_size(origin.used_size)
+        );
+    }
+}
+
+fn print_lock_status(locks: &[(&str, PathBuf, LockFile)]) {
+    let hostname = get_hostname().unwrap_or_default();
+    let now = Utc::now().timestamp();
+    println!(
+        "{:8}  {:9}  {:20}  {:>7}  {:15}  {:>12}  File",
+        "Location",
+        "Type",
+        "Host",
+        "PID",
+        "Operation",
+        "Age"
+    );
+    for &(location, ref path, ref lock) in locks {
+        // Only processes on this host can be checked
+        let stale = lock.hostname == hostname && !process_exists(lock.processid);
+        println!(
+            "{:8}  {:9}  {:20}  {:>7}  {:15}  {:>12}  {}{}",
+            location,
+            if lock.exclusive { "exclusive" } else { "shared" },
+            lock.hostname,
+            lock.processid,
+            if lock.operation.is_empty() { "unknown" } else { lock.operation.as_str() },
+            to_duration(now.saturating_sub(lock.date).max(0) as f32),
+            path.display(),
+            if stale { " (stale, process is gone)" } else { "" }
+        );
+    }
+}
+
+fn inode_display_name(inode: &Inode) -> String {
+    if inode.file_type == FileType::Directory {
+        format!("{}/", inode.name)
+    } else {
+        inode.name.clone()
+    }
+}
+
+/// Returns the width of the name column needed to align the given inodes
+pub fn inode_name_width(inodes: &[Inode]) -> usize {
+    column_width(inodes.iter().map(inode_display_name))
 }
 
-pub fn format_inode_one_line(inode: &Inode) -> String {
+pub fn format_inode_one_line(inode: &Inode, name_width: usize) -> String {
+    let name = inode_display_name(inode);
     match inode.file_type {
         FileType::Directory => {
             format!(
-                "{:25}\t{} entries",
-                format!("{}/", inode.name),
-                inode.children.as_ref().map(|c| c.len()).unwrap_or(0)
+                "{:width$}  {} entries",
+                name,
+                inode.children.as_ref().map(|c| c.len()).unwrap_or(0),
+                width = name_width
             )
         }
         FileType::File => {
             format!(
-                "{:25}\t{:>10}\t{}",
-                inode.name,
+                "{:width$}  {:>10}  {}",
+                name,
                 to_file_size(inode.size),
-                Local.timestamp(inode.timestamp, 0).to_rfc2822()
+                Local.timestamp(inode.timestamp, 0).to_rfc2822(),
+                width = name_width
             )
         }
         FileType::Symlink => {
             format!(
-                "{:25}\t -> {}",
-                inode.name,
+                "{:width$}   -> {}",
+                name,
                 inode.symlink_target.as_ref().map(|s| s as &str).unwrap_or(
                     "?"
-                )
+                ),
+                width = name_width
             )
         }
         FileType::BlockDevice | FileType::CharDevice => {
             let device = inode.device.unwrap_or((0, 0));
             format!(
-                "{:25}\t{:12}\t{}:{}",
-                inode.name,
+                "{:width$}  {:12}  {}:{}",
+                name,
                 inode.file_type,
                 device.0,
-                device.1
+                device.1,
+                width = name_width
             )
         }
-        FileType::NamedPipe => format!("{:25}\t fifo", inode.name),
+        FileType::NamedPipe => format!("{:width$}   fifo", name, width = name_width),
+        FileType::Socket => format!("{:width$}   socket", name, width = name_width),
     }
 }
 
@@ -256,6 +807,12 @@ fn print_inode(inode: &Inode) {
         "Timestamp: {}",
         Local.timestamp(inode.timestamp, 0).to_rfc2822()
     );
+    if let Some(birth_time) = inode.birth_time {
+        println!("Birth time: {}", Local.timestamp(birth_time, 0).to_rfc2822());
+    }
+    if let Some(id) = inode.project_id {
+        println!("Project id: {}", id);
+    }
     if let Some(ref target) = inode.symlink_target {
         println!("Symlink target: {}", target);
     }
@@ -280,19 +837,180 @@ fn print_inode(inode: &Inode) {
     }
 }
 
+fn print_file_chunks(analysis: &FileChunkAnalysis) {
+    fn format_chunks(chunks: &(usize, u64)) -> String {
+        format!("{} chunks, {}", chunks.0, to_file_size(chunks.1))
+    }
+    println!("Chunks: {}", format_chunks(&analysis.total));
+    println!("  - new at backup time: {}", format_chunks(&analysis.new));
+    println!("  - already stored: {}", format_chunks(&analysis.existing));
+    if analysis.repeated.0 > 0 {
+        println!("  - repeated in the file: {}", format_chunks(&analysis.repeated));
+    }
+    if analysis.unknown.0 > 0 {
+        println!("  - unknown origin: {}", format_chunks(&analysis.unknown));
+    }
+    println!("Bundles:");
+    for &(ref id, ref backup, count, size) in &analysis.bundles {
+        println!(
+            "  - {}: {}{}",
+            id,
+            format_chunks(&(count, size)),
+            backup.as_ref().map_or(String::new(), |b| format!(" (created for {})", b))
+        );
+    }
+}
+
+fn print_remote_check(name: &str, path: &Path, check: &RemoteCheck) {
+    println!("Remote {} at {}", name, path.display());
+    println!(
+        "  - listing bundles: {} entries in {:.0} ms",
+        check.list_entries,
+        check.list_time * 1000.0
+    );
+    for &(ref folder, ref err) in &check.unwritable {
+        println!("  - {}", Color::Red.paint(format!("can not write to {}: {}", folder, err)));
+    }
+    if let Some(time) = check.upload_time {
+        println!(
+            "  - writing test bundle: {} in {}, {}",
+            to_file_size(check.test_size as u64),
+            to_duration(time),
+            to_speed(check.test_size as u64, time)
+        );
+    }
+    if let Some(time) = check.download_time {
+        println!(
+            "  - reading test bundle: {}, {}",
+            to_duration(time),
+            to_speed(check.test_size as u64, time)
+        );
+    }
+    if check.upload_time.is_some() && !check.data_ok {
+        println!("  - {}", Color::Red.paint("test bundle could not be read back unchanged"));
+    }
+    if let Some(skew) = check.clock_skew {
+        let msg = format!("clock difference: {} s", skew);
+        if skew.abs() > MAX_CLOCK_SKEW {
+            println!("  - {}", Color::Red.paint(msg));
+        } else {
+            println!("  - {}", msg);
+        }
+    }
+    if check.is_ok() {
+        println!("  - {}", Color::Green.paint("ok"));
+    }
+}
+
+fn get_backup_field(backup: &Backup, field: &str) -> Option<String> {
+    Some(match field {
+        "timestamp" => backup.timestamp.to_string(),
+        "host" => backup.host.clone(),
+        "path" => backup.path.clone(),
+        "duration" => backup.duration.to_string(),
+        "file_count" => backup.file_count.to_string(),
+        "dir_count" => backup.dir_count.to_string(),
+        "total_data_size" => backup.total_data_size.to_string(),
+        "changed_data_size" => backup.changed_data_size.to_string(),
+        "deduplicated_data_size" => backup.deduplicated_data_size.to_string(),
+        "encoded_data_size" => backup.encoded_data_size.to_string(),
+        "bundle_count" => backup.bundle_count.to_string(),
+        "chunk_count" => backup.chunk_count.to_string(),
+        "zero_chunk_count" => backup.zero_chunk_count.to_string(),
+        "avg_chunk_size" => backup.avg_chunk_size.to_string(),
+        "modified" => backup.modified.to_string(),
+        "tags" => backup.tags.join(","),
+        "remotes" => backup.remotes.join(","),
+        "snapshot" => backup.source.snapshot.clone().unwrap_or_default(),
+        "source_device" => backup.source.device.clone(),
+        "source_uuid" => backup.source.uuid.clone().unwrap_or_default(),
+        "consistent" => backup.source.is_consistent().to_string(),
+        _ => return None,
+    })
+}
+
+fn get_inode_field(inode: &Inode, field: &str) -> Option<String> {
+    Some(match field {
+        "name" => inode.name.clone(),
+        "type" => inode.file_type.to_string(),
+        "size" => inode.size.to_string(),
+        "mode" => format!("{:o}", inode.mode),
+        "user" => inode.user.to_string(),
+        "group" => inode.group.to_string(),
+        "timestamp" => inode.timestamp.to_string(),
+        "symlink_target" => inode.symlink_target.clone().unwrap_or_default(),
+        "cum_size" => inode.cum_size.to_string(),
+        "cum_files" => inode.cum_files.to_string(),
+        "cum_dirs" => inode.cum_dirs.to_string(),
+        _ => return None,
+    })
+}
+
+fn get_repoinfo_field(info: &RepositoryInfo, field: &str) -> Option<String> {
+    Some(match field {
+        "bundle_count" => info.bundle_count.to_string(),
+        "encoded_data_size" => info.encoded_data_size.to_string(),
+        "raw_data_size" => info.raw_data_size.to_string(),
+        "compression_ratio" => info.compression_ratio.to_string(),
+        "chunk_count" => info.chunk_count.to_string(),
+        "avg_chunk_size" => info.avg_chunk_size.to_string(),
+        "index_size" => info.index_size.to_string(),
+        "index_capacity" => info.index_capacity.to_string(),
+        "index_entries" => info.index_entries.to_string(),
+        _ => return None,
+    })
+}
+
+fn print_field(value: Option<String>, field: &str) -> Result<(), ErrorCode> {
+    if let Some(value) = value {
+        println!("{}", value);
+        Ok(())
+    } else {
+        error!("Unknown field: {}", field);
+        Err(ErrorCode::InvalidArgs)
+    }
+}
+
 fn print_backups(backup_map: &HashMap<String, Backup>) {
     let mut backups: Vec<_> = backup_map.into_iter().collect();
     backups.sort_by_key(|b| b.0);
-    for (name, backup) in backups {
-        println!(
-            "{:40}  {:>32}  {:7} files, {:6} dirs, {:>10}",
+    let dates: Vec<_> = backups
+        .iter()
+        .map(|&(_, backup)| Local.timestamp(backup.timestamp, 0).to_rfc2822())
+        .collect();
+    let sizes: Vec<_> = backups
+        .iter()
+        .map(|&(_, backup)| to_file_size(backup.total_data_size))
+        .collect();
+    // The columns are only as wide as their contents, so short names do not waste space
+    let name_width = column_width(backups.iter().map(|&(name, _)| name));
+    let date_width = column_width(&dates);
+    let files_width = column_width(backups.iter().map(|&(_, b)| b.file_count.to_string()));
+    let dirs_width = column_width(backups.iter().map(|&(_, b)| b.dir_count.to_string()));
+    let size_width = column_width(&sizes);
+    let mut lines = Vec::with_capacity(backups.len());
+    for (i, &(name, backup)) in backups.iter().enumerate() {
+        let tags = if backup.tags.is_empty() {
+            String::new()
+        } else {
+            format!("  [{}]", backup.tags.join(", "))
+        };
+        lines.push(format!(
+            "{:name$}  {:>date$}  {:>files$} files, {:>dirs$} dirs, {:>size$}{}",
             name,
-            Local.timestamp(backup.timestamp, 0).to_rfc2822(),
+            dates[i],
             backup.file_count,
             backup.dir_count,
-            to_file_size(backup.total_data_size)
-        );
+            sizes[i],
+            tags,
+            name = name_width,
+            date = date_width,
+            files = files_width,
+            dirs = dirs_width,
+            size = size_width
+        ));
     }
+    print_lines(&lines);
 }
 
 fn print_repoinfo(info: &RepositoryInfo) {
@@ -313,6 +1031,25 @@ fn print_repoinfo(info: &RepositoryInfo) {
     );
 }
 
+fn warn_storage_classes(classes: &HashMap<StorageClass, (usize, u64)>) {
+    if let Some(&(count, size)) = classes.get(&StorageClass::Infrequent) {
+        warn!(
+            "The data is stored in {} bundles ({}) in the infrequent access tier, reading them \
+             might incur retrieval costs",
+            count,
+            to_file_size(size)
+        );
+    }
+    if let Some(&(count, size)) = classes.get(&StorageClass::Archive) {
+        warn!(
+            "The data is stored in {} bundles ({}) in the archive tier, retrieving them can take \
+             hours and incur significant costs",
+            count,
+            to_file_size(size)
+        );
+    }
+}
+
 fn print_bundle(bundle: &StoredBundle) {
     println!("Bundle {}", bundle.info.id);
     println!("  - Mode: {:?}", bundle.info.mode);
@@ -329,6 +1066,9 @@ fn print_bundle(bundle: &StoredBundle) {
     };
     println!("  - Encryption: {}", encryption);
     println!("  - Chunks: {}", bundle.info.chunk_count);
+    if !bundle.info.delta_chunks.is_empty() {
+        println!("  - Delta chunks: {}", bundle.info.delta_chunks.len());
+    }
     println!(
         "  - Size: {}",
         to_file_size(bundle.info.encoded_size as u64)
@@ -348,6 +1088,43 @@ fn print_bundle(bundle: &StoredBundle) {
         compression,
         ratio * 100.0
     );
+    if let Some(ref dictionary) = bundle.info.dictionary {
+        println!("  - Dictionary: {}", dictionary);
+    }
+    if let Some(ref host) = bundle.info.host {
+        println!("  - Host: {}", host);
+    }
+    if let Some(ref backup) = bundle.info.backup {
+        println!("  - Backup: {}", backup);
+    }
+    if bundle.info.storage_class != StorageClass::Standard {
+        println!("  - Storage class: {}", bundle.info.storage_class);
+    }
+}
+
+fn print_bundle_dump(bundle: &BundleReader) {
+    println!("  - Header version: {}", bundle.version);
+    println!("  - Chunk list size: {}", bundle.info.chunk_list_size);
+    println!("  - Contents start: {}", bundle.content_start);
+    if let Ok(meta) = bundle.path.metadata() {
+        let size = meta.len().saturating_sub(bundle.content_start as u64);
+        println!("  - Stored contents size: {}", size);
+    }
+    let deltas: HashMap<usize, (usize, usize)> = bundle.info
+        .delta_chunks
+        .iter()
+        .map(|&(id, base, len)| (id as usize, (base as usize, len as usize)))
+        .collect();
+    let chunks = bundle.chunks.as_ref().unwrap();
+    let positions = bundle.chunk_positions.as_ref().unwrap();
+    println!("Chunks:");
+    for (id, &(hash, len)) in chunks.iter().enumerate() {
+        let delta = match deltas.get(&id) {
+            Some(&(base, delta_len)) => format!("  delta of {}, {} bytes", base, delta_len),
+            None => String::new(),
+        };
+        println!("{:>6}  {}  {:>10}  at {:>10}{}", id, hash, len, positions[id], delta);
+    }
 }
 
 fn print_bundle_one_line(bundle: &BundleInfo) {
@@ -374,9 +1151,45 @@ fn print_config(config: &Config) {
         println!("Encryption: none");
     }
     println!("Hash method: {}", config.hash.name());
+    println!("Keyed chunk hashes: {}", config.hash_key.is_some());
+    println!("Delta compression: {}", config.delta_compression);
+    if let Some(ref dictionary) = config.dictionary {
+        println!("Dictionary: {}", dictionary);
+    } else {
+        println!("Dictionary: none");
+    }
+    println!("Maximal check age: {} days", config.health.max_check_age);
+    println!("Maximal lock age: {} hours", config.health.max_lock_age);
+    for (pattern, max_age) in &config.health.freshness {
+        println!("Freshness: {} every {} hours", pattern, max_age);
+    }
+    println!("Trash retention: {} days", config.trash_retention);
+    if config.min_retention > 0 {
+        println!("Minimal retention: {} days", config.min_retention);
+    } else {
+        println!("Minimal retention: none");
+    }
+    println!("Upload verification: {}", config.upload_verification.name());
+    println!("Zero chunk detection: {}", config.zero_chunks);
+    if config.codec_helpers > 0 {
+        println!("Codec helpers: {}", config.codec_helpers);
+    } else {
+        println!("Codec helpers: none");
+    }
+    println!("Short bundle names: {}", config.short_bundle_names);
+    if config.threads > 0 {
+        println!("Worker threads: {}", config.threads);
+    } else {
+        println!("Worker threads: default");
+    }
+    if config.cpus.is_empty() {
+        println!("CPUs: all");
+    } else {
+        println!("CPUs: {}", format_cpu_list(&config.cpus));
+    }
 }
 
-fn print_analysis(analysis: &HashMap<u32, BundleAnalysis>) {
+fn print_analysis(analysis: &HashMap<u64, BundleAnalysis>) {
     let mut reclaim_space = [0; 11];
     let mut rewrite_size = [0; 11];
     let mut data_total = 0;
@@ -393,7 +1206,7 @@ fn print_analysis(analysis: &HashMap<u32, BundleAnalysis>) {
     println!("Total bundle size: {}", to_file_size(data_total as u64));
     let used = data_total - reclaim_space[10];
     println!(
-        "Space used: {}, {:.1} %",
+        "Space used: {}, {:.1}%",
         to_file_size(used as u64),
         used as f32 / data_total as f32 * 100.0
     );
@@ -401,7 +1214,7 @@ fn print_analysis(analysis: &HashMap<u32, BundleAnalysis>) {
     #[allow(unknown_lints, needless_range_loop)]
     for i in 0..11 {
         println!(
-            "  - ratio={:3}: {:>10}, {:4.1} %, rewriting {:>10}",
+            "  - ratio={:3}: {:>10}, {:5.1}%, rewriting {:>10}",
             i * 10,
             to_file_size(reclaim_space[i] as u64),
             reclaim_space[i] as f32 / data_total as f32 * 100.0,
@@ -410,14 +1223,123 @@ fn print_analysis(analysis: &HashMap<u32, BundleAnalysis>) {
     }
 }
 
+fn print_backup_file_report(report: &BackupFileReport, fix: &HashSet<BackupFileProblem>) {
+    let action = |problem| if fix.contains(&problem) {
+        "fixed"
+    } else {
+        "found"
+    };
+    if !report.missing_roots.is_empty() {
+        println!("Backups with missing roots ({}):", action(BackupFileProblem::MissingRoot));
+        for name in &report.missing_roots {
+            println!("  - {}", name);
+        }
+    }
+    if !report.duplicates.is_empty() {
+        println!("Duplicated backups ({}):", action(BackupFileProblem::Duplicate));
+        for &(ref name, ref original) in &report.duplicates {
+            println!("  - {} (same as {})", name, original);
+        }
+    }
+    if !report.orphaned_meta.is_empty() {
+        println!("Orphaned meta bundles ({}):", action(BackupFileProblem::OrphanedMeta));
+        for id in &report.orphaned_meta {
+            println!("  - {}", id);
+        }
+    }
+}
+
+/// Analyzes the bundle usage before backups are removed, failures are only reported
+fn analyze_before_removal(repo: &mut Repository) -> Option<HashMap<u64, BundleAnalysis>> {
+    match repo.analyze_usage_ignoring_trash() {
+        Ok(usage) => Some(usage),
+        Err(err) => {
+            warn!("Failed to analyze the repository, reclaimable space will not be reported");
+            warn!("\tcaused by: {}", err);
+            None
+        }
+    }
+}
+
+/// Prints how much space the removal made reclaimable and which vacuum ratio reclaims it
+fn print_reclaimable(repo: &mut Repository, before: Option<HashMap<u64, BundleAnalysis>>) {
+    let before = match before {
+        Some(before) => before,
+        None => return,
+    };
+    // New metadata has to be stored in bundles before it can be analyzed
+    let after = match repo.flush().and_then(|()| repo.analyze_usage_ignoring_trash()) {
+        Ok(after) => after,
+        Err(err) => {
+            warn!("Failed to analyze the repository\n\tcaused by: {}", err);
+            return;
+        }
+    };
+    let report = ReclaimReport::new(&before, &after);
+    if report.bundles.is_empty() {
+        info!("The removed data is still used by other backups, no space became reclaimable");
+        return;
+    }
+    println!(
+        "Reclaimable space: {} in {} bundles",
+        to_file_size(report.reclaimable_size),
+        report.bundles.len()
+    );
+    println!(
+        "Run vacuum with --ratio {} or higher to reclaim it by rewriting {}",
+        report.vacuum_ratio(),
+        to_file_size(report.rewrite_size)
+    );
+    if repo.config.trash_retention > 0 {
+        info!(
+            "The space can only be reclaimed when the removed backups expire from the trash"
+        );
+    }
+}
+
 
 #[allow(unknown_lints, cyclomatic_complexity)]
 pub fn run() -> Result<(), ErrorCode> {
-    let (log_level, args) = try!(args::parse());
-    if let Err(err) = logger::init(log_level) {
+    let (options, args) = try!(args::parse());
+    if let Err(err) = logger::init(options.log_level) {
         println!("Failed to initialize the logger: {}", err);
         return Err(ErrorCode::InitializeLogger);
     }
+    if options.background {
+        if let Err(err) = set_lowest_cpu_priority() {
+            warn!("Failed to lower the CPU priority: {}", err);
+        }
+        if let Err(err) = set_idle_io_priority() {
+            warn!("Failed to set the IO priority to idle: {}", err);
+        }
+    }
+    if let Some(max_memory) = options.max_memory {
+        MAX_MEMORY.store(max_memory, Ordering::SeqCst);
+    } else if options.background {
+        MAX_MEMORY.store(BACKGROUND_MAX_MEMORY, Ordering::SeqCst);
+    }
+    *PROXY.lock().unwrap() = options.proxy;
+    set_passphrase_command(options.passcommand);
+    set_lock_operation(&options.command);
+    if let Some(threads) = options.threads {
+        set_worker_threads(threads);
+    }
+    if let Some(cpus) = options.cpus {
+        if let Err(err) = set_worker_cpus(cpus) {
+            error!("Failed to restrict the worker threads to the given CPUs: {}", err);
+            return Err(ErrorCode::InvalidArgs);
+        }
+    }
+    set_si_units(options.si_units);
+    set_pager(options.pager);
+    set_json_output(options.json_output);
+    if options.systemd {
+        enable_systemd();
+        notify_ready();
+    }
+    if options.notify {
+        enable_desktop_notifications();
+    }
     match args {
         Arguments::Init {
             repo_path,
@@ -426,23 +1348,51 @@ pub fn run() -> Result<(), ErrorCode> {
             compression,
             encryption,
             hash,
-            remote_path
+            keyed_hash,
+            delta_compression,
+            remote_path,
+            excludes_template,
+            readme_template
         } => {
             if !Path::new(&remote_path).is_absolute() {
                 error!("The remote path of a repository must be absolute.");
                 return Err(ErrorCode::InvalidArgs);
             }
-            let mut repo = checked!(
-                Repository::create(
-                    repo_path,
+            let templates = try!(load_templates(excludes_template, readme_template));
+            let hash_key = if keyed_hash {
+                if hash != HashMethod::Blake2 {
+                    error!("Keyed chunk hashes are only secure with the blake2 hash method.");
+                    return Err(ErrorCode::InvalidArgs);
+                }
+                warn!("The hash key is only stored in the local configuration, please keep a copy of it");
+                Some(checked!(Hash::random_key(), "generate hash key", ErrorCode::CreateRepository))
+            } else {
+                None
+            };
+            let mut repo = checked!(
+                Repository::create_with_templates(
+                    repo_path,
                     Config {
                         bundle_size: bundle_size,
                         chunker: chunker,
                         compression: compression,
                         encryption: None,
-                        hash: hash
+                        hash: hash,
+                        delta_compression: delta_compression,
+                        dictionary: None,
+                        health: HealthConfig::default(),
+                        trash_retention: 7,
+                        min_retention: 0,
+                        upload_verification: UploadVerification::Off,
+                        zero_chunks: true,
+                        codec_helpers: 0,
+                        short_bundle_names: false,
+                        hash_key: hash_key,
+                        threads: 0,
+                        cpus: vec![]
                     },
-                    remote_path
+                    remote_path,
+                    &templates
                 ),
                 "create repository",
                 ErrorCode::CreateRepository
@@ -466,29 +1416,91 @@ pub fn run() -> Result<(), ErrorCode> {
             }
             print_config(&repo.config);
         }
+        #[cfg(unix)]
         Arguments::Backup {
             repo_path,
             backup_name,
             src_path,
             full,
             reference,
+            reference_any,
+            reference_host,
             same_device,
+            dereference,
+            dereference_args,
             mut excludes,
             excludes_from,
             no_default_excludes,
-            tar
+            tar,
+            timestamp,
+            list_only,
+            tags,
+            special_files,
+            order,
+            remotes,
+            stats,
+            snapshot,
+            wait,
+            scan_threads,
+            include_repositories,
+            storage_class
         } => {
             let mut repo = try!(open_repository(&repo_path));
-            if repo.has_backup(&backup_name) {
+            // Scheduled backups that overrun must not be backed up twice at the same time
+            let _source_lock = if list_only {
+                None
+            } else {
+                let host = get_hostname().unwrap_or_default();
+                let path = if tar {
+                    src_path.clone()
+                } else {
+                    fs::canonicalize(&src_path)
+                        .map(|p| p.to_string_lossy().to_string())
+                        .unwrap_or_else(|_| src_path.clone())
+                };
+                let prefix = backup_name.rfind('/').map_or("", |i| &backup_name[..i]);
+                let mut lock = repo.lock_backup_source(&host, &path, prefix, false);
+                let locked = match lock {
+                    Err(RepositoryError::Lock(LockError::Locked)) => true,
+                    _ => false,
+                };
+                if locked && wait {
+                    info!("Waiting for the running backup of {} to finish", src_path);
+                    lock = repo.lock_backup_source(&host, &path, prefix, true);
+                }
+                match lock {
+                    Ok(lock) => Some(lock),
+                    Err(RepositoryError::Lock(LockError::Locked)) => {
+                        error!("A backup of {} into this folder is already running", src_path);
+                        return Err(ErrorCode::BackupRunning);
+                    }
+                    Err(err) => return Err(fail(&err, "lock backup source", ErrorCode::BackupRun)),
+                }
+            };
+            if !list_only && repo.has_backup(&backup_name) {
                 error!("A backup with that name already exists");
                 return Err(ErrorCode::BackupAlreadyExists);
             }
-            if src_path == "-" && !tar {
-                error!("Reading from stdin requires --tar");
-                return Err(ErrorCode::InvalidArgs);
+            let tar_source = match TarSource::parse(&src_path) {
+                Ok(source) => source,
+                Err(msg) => {
+                    error!("{}", msg);
+                    return Err(ErrorCode::InvalidArgs);
+                }
+            };
+            match tar_source {
+                TarSource::Stdin if !tar => {
+                    error!("Reading from stdin requires --tar");
+                    return Err(ErrorCode::InvalidArgs);
+                }
+                TarSource::Ssh { .. } if !tar => {
+                    error!("Reading from a remote command requires --tar");
+                    return Err(ErrorCode::InvalidArgs);
+                }
+                _ => (),
             }
             let mut reference_backup = None;
-            if !full && !tar {
+            if !full && !tar && !list_only {
                 reference_backup = match reference {
                     Some(r) => {
                         let b = try!(get_backup(&repo, &r));
@@ -497,7 +1509,15 @@ pub fn run() -> Result<(), ErrorCode> {
                     None => None,
                 };
                 if reference_backup.is_none() {
-                    reference_backup = try!(find_reference_backup(&repo, &src_path));
+                    reference_backup = if reference_any {
+                        try!(find_similar_backup(&mut repo, &src_path))
+                    } else {
+                        try!(find_reference_backup(
+                            &repo,
+                            &src_path,
+                            reference_host.as_ref().map(|h| h.as_str())
+                        ))
+                    };
                 }
                 if let Some(&(ref name, _)) = reference_backup.as_ref() {
                     info!("Using backup {} as reference", name);
@@ -534,69 +1554,166 @@ pub fn run() -> Result<(), ErrorCode> {
                     ));
                 }
             }
-            let mut excludes_parsed = Vec::with_capacity(excludes.len());
-            for mut exclude in excludes {
-                if exclude.starts_with('#') || exclude.is_empty() {
-                    continue;
+            excludes.retain(|exclude| !exclude.starts_with('#') && !exclude.is_empty());
+            // Backing up the repository into itself would grow it with every backup
+            let mut skip_dirs = vec![];
+            if !include_repositories {
+                for path in &[repo.layout.base_path().to_path_buf(), repo.layout.remote_path()] {
+                    if let Ok(meta) = fs::metadata(path) {
+                        skip_dirs.push((meta.dev(), meta.ino()));
+                    }
                 }
-                exclude = regex::escape(&exclude)
-                    .replace('?', ".")
-                    .replace(r"\*\*", ".*")
-                    .replace(r"\*", "[^/]*");
-                excludes_parsed.push(if exclude.starts_with('/') {
-                    format!(r"^{}($|/)", exclude)
-                } else {
-                    format!(r"/{}($|/)", exclude)
-                });
             }
-            let excludes = if excludes_parsed.is_empty() {
-                None
-            } else {
-                Some(checked!(
-                    RegexSet::new(excludes_parsed),
-                    "parse exclude patterns",
-                    ErrorCode::InvalidExcludes
-                ))
-            };
             let options = BackupOptions {
                 same_device: same_device,
-                excludes: excludes
+                excludes: try!(parse_patterns(&excludes)),
+                exclude_patterns: excludes.clone(),
+                dereference: dereference,
+                dereference_args: dereference_args,
+                special_files: special_files,
+                order: order,
+                snapshot: snapshot,
+                scan_threads: scan_threads,
+                skip_dirs: skip_dirs,
+                skip_repositories: !include_repositories
+            };
+            if list_only {
+                checked!(
+                    list_backup_paths(&src_path, &options, |path, status| match status {
+                        BackupPathStatus::Included => println!("+ {}", path.display()),
+                        BackupPathStatus::Excluded(index) => {
+                            println!("- {} (excluded by {:?})", path.display(), excludes[index])
+                        }
+                        BackupPathStatus::OtherDevice => {
+                            println!("- {} (other filesystem)", path.display())
+                        }
+                        BackupPathStatus::DirectoryLoop => {
+                            println!("- {} (directory loop)", path.display())
+                        }
+                        BackupPathStatus::SpecialFile => {
+                            println!("- {} (special file)", path.display())
+                        }
+                        BackupPathStatus::Repository => {
+                            println!("- {} (zvault repository)", path.display())
+                        }
+                    }),
+                    "list paths",
+                    ErrorCode::BackupRun
+                );
+                return Ok(());
+            }
+            let notification = BackupNotification::start(&backup_name, &src_path);
+            let mut remotes = remotes;
+            if !remotes.is_empty() {
+                let known = checked!(repo.get_remotes(), "load remotes", ErrorCode::LoadRepository);
+                if remotes.iter().any(|r| r == "all") {
+                    remotes = known.keys().cloned().collect();
+                }
+                for remote in &remotes {
+                    if !known.contains_key(remote) {
+                        error!("There is no remote named {}", remote);
+                        return Err(ErrorCode::InvalidArgs);
+                    }
+                }
+                remotes.sort();
+                remotes.dedup();
+                let reachable = checked!(
+                    repo.set_mirrors(&remotes),
+                    "set up remotes",
+                    ErrorCode::LoadRepository
+                );
+                if !reachable.is_empty() {
+                    info!("Also storing the backup on {}", reachable.join(", "));
+                }
+            }
+            // Keeps the system awake until the backup has been saved
+            let _inhibitor = if systemd_enabled() {
+                match SleepInhibitor::new("Backup running") {
+                    Ok(inhibitor) => Some(inhibitor),
+                    Err(err) => {
+                        warn!("Failed to prevent the system from sleeping: {}", err);
+                        None
+                    }
+                }
+            } else {
+                None
             };
+            notify_status(&format!("Backing up {} to {}", src_path, backup_name));
+            repo.set_bundle_origin(Some(backup_name.clone()));
+            repo.set_bundle_storage_class(storage_class);
             let result = if tar {
-                repo.import_tarfile(&src_path)
+                repo.import_tar_source(&tar_source)
             } else {
                 repo.create_backup_recursively(&src_path, reference_backup.as_ref(), &options)
             };
-            let backup = match result {
+            let mut failed = 0;
+            let mut backup = match result {
                 Ok(backup) => {
                     info!("Backup finished");
                     backup
                 }
-                Err(RepositoryError::Backup(BackupError::FailedPaths(backup, _failed_paths))) => {
+                Err(RepositoryError::Backup(BackupError::FailedPaths(backup, failed_paths))) => {
                     warn!("Some files are missing from the backup");
+                    failed = failed_paths.len();
                     backup
                 }
-                Err(err) => {
-                    error!("Backup failed: {}", err);
-                    return Err(ErrorCode::BackupRun);
+                Err(err @ RepositoryError::Backup(BackupError::Interrupted)) => {
+                    notify_stopping();
+                    return Err(fail(&err, "create backup", ErrorCode::BackupRun));
                 }
+                Err(err) => return Err(fail(&err, "create backup", ErrorCode::BackupRun)),
             };
+            if let Some(timestamp) = timestamp {
+                backup.timestamp = timestamp;
+            }
+            backup.tags = tags;
+            backup.tags.sort();
+            backup.tags.dedup();
+            if !remotes.is_empty() {
+                checked!(
+                    repo.mirror_backup(&mut backup, &backup_name),
+                    "store backup on remotes",
+                    ErrorCode::SaveBackup
+                );
+                for remote in &remotes {
+                    if !backup.remotes.contains(remote) {
+                        warn!("The backup is not stored on remote {}", remote);
+                    }
+                }
+            }
             checked!(
                 repo.save_backup(&backup, &backup_name),
                 "save backup file",
                 ErrorCode::SaveBackup
             );
-            print_backup(&backup);
+            notify_status("Backup finished");
+            notification.finish(&backup, failed);
+            print_backup(&backup, stats);
+        }
+        #[cfg(not(unix))]
+        Arguments::Backup { .. } => {
+            error!("Creating backups is only supported on unix");
+            return Err(ErrorCode::BackupRun);
         }
         Arguments::Restore {
             repo_path,
             backup_name,
             inode,
             dst_path,
-            tar
+            tar,
+            delta,
+            special_files,
+            atomic,
+            verify,
+            link_dest,
+            project_ids,
+            send_stream,
+            send_parent
         } => {
             let mut repo = try!(open_repository(&repo_path));
+            repo.set_verify_reads(verify);
             let backup = try!(get_backup(&repo, &backup_name));
+            let subtree = inode.clone();
             let inode = if let Some(inode) = inode {
                 checked!(
                     repo.get_backup_inode(&backup, &inode),
@@ -610,21 +1727,137 @@ pub fn run() -> Result<(), ErrorCode> {
                     ErrorCode::LoadInode
                 )
             };
+            match repo.analyze_storage_classes(&inode) {
+                Ok(classes) => warn_storage_classes(&classes),
+                Err(err) => warn!("Failed to determine the storage tiers of the data: {}", err),
+            }
             if tar {
                 checked!(
                     repo.export_tarfile(&backup, inode, &dst_path),
                     "restore backup",
                     ErrorCode::RestoreRun
                 );
+            } else if send_stream {
+                let parent = if let Some(ref parent_name) = send_parent {
+                    Some(try!(get_backup(&repo, parent_name)))
+                } else {
+                    None
+                };
+                // Backup names can contain folders but the snapshot is created in one directory
+                let name = backup_name.replace('/', "_");
+                checked!(
+                    repo.export_send_stream(
+                        &name,
+                        &backup,
+                        parent.as_ref(),
+                        subtree.as_ref().map(|v| v.as_str()),
+                        &dst_path
+                    ),
+                    "restore backup",
+                    ErrorCode::RestoreRun
+                );
             } else {
+                let options = RestoreOptions {
+                    delta: delta,
+                    special_files: special_files,
+                    atomic: atomic,
+                    link_dest: link_dest,
+                    project_ids: project_ids
+                };
                 checked!(
-                    repo.restore_inode_tree(&backup, inode, &dst_path),
+                    repo.restore_inode_tree(&backup, inode, &dst_path, &options),
                     "restore backup",
                     ErrorCode::RestoreRun
                 );
             }
             info!("Restore finished");
         }
+        Arguments::Manifest {
+            repo_path,
+            backup_name,
+            inode
+        } => {
+            let mut repo = try!(open_repository(&repo_path));
+            let backup = try!(get_backup(&repo, &backup_name));
+            let inode = if let Some(inode) = inode {
+                checked!(
+                    repo.get_backup_inode(&backup, &inode),
+                    "load subpath inode",
+                    ErrorCode::LoadInode
+                )
+            } else {
+                checked!(
+                    repo.get_inode(&backup.root),
+                    "load root inode",
+                    ErrorCode::LoadInode
+                )
+            };
+            let stdout = io::stdout();
+            checked!(
+                repo.write_manifest(inode, &mut stdout.lock()),
+                "create manifest",
+                ErrorCode::ManifestRun
+            );
+        }
+        Arguments::Publish {
+            repo_path,
+            backup_name,
+            inode,
+            dst_path
+        } => {
+            let mut repo = try!(open_repository(&repo_path));
+            let backup = try!(get_backup(&repo, &backup_name));
+            let (title, inode) = if let Some(inode) = inode {
+                (
+                    format!("{}::{}", backup_name, inode),
+                    checked!(
+                        repo.get_backup_inode(&backup, &inode),
+                        "load subpath inode",
+                        ErrorCode::LoadInode
+                    )
+                )
+            } else {
+                (
+                    backup_name.clone(),
+                    checked!(
+                        repo.get_inode(&backup.root),
+                        "load root inode",
+                        ErrorCode::LoadInode
+                    )
+                )
+            };
+            let stats = checked!(
+                repo.publish_backup(&title, inode, &dst_path),
+                "publish backup",
+                ErrorCode::PublishRun
+            );
+            info!(
+                "Published {} directories and {} files, stored {} new files with {}",
+                stats.dirs,
+                stats.files,
+                stats.stored_files,
+                to_file_size(stats.stored_size)
+            );
+        }
+        Arguments::VerifyManifest { manifest, path } => {
+            let report = checked!(
+                verify_manifest(&manifest, &path),
+                "verify manifest",
+                ErrorCode::ManifestRun
+            );
+            if !report.is_ok() {
+                error!(
+                    "{} of {} files failed: {} missing, {} modified, {} invalid lines",
+                    report.missing.len() + report.mismatched.len(),
+                    report.checked,
+                    report.missing.len(),
+                    report.mismatched.len(),
+                    report.invalid_lines
+                );
+                return Err(ErrorCode::ManifestMismatch);
+            }
+            info!("All {} files match the manifest", report.checked);
+        }
         Arguments::Copy {
             repo_path_src,
             backup_name_src,
@@ -651,11 +1884,20 @@ pub fn run() -> Result<(), ErrorCode> {
             repo_path,
             backup_name,
             inode,
+            ignore_min_retention,
             force
         } => {
             let mut repo = try!(open_repository(&repo_path));
             if let Some(inode) = inode {
                 let mut backup = try!(get_backup(&repo, &backup_name));
+                if !ignore_min_retention {
+                    checked!(
+                        repo.check_min_retention(&backup_name, &backup),
+                        "remove backup subpath",
+                        ErrorCode::RemoveRun
+                    );
+                }
+                let usage = analyze_before_removal(&mut repo);
                 checked!(
                     repo.remove_backup_path(&mut backup, inode),
                     "remove backup subpath",
@@ -666,7 +1908,8 @@ pub fn run() -> Result<(), ErrorCode> {
                     "save backup file",
                     ErrorCode::SaveBackup
                 );
-                info!("The backup subpath has been deleted, run vacuum to reclaim space");
+                info!("The backup subpath has been deleted");
+                print_reclaimable(&mut repo, usage);
             } else if repo.layout.backups_path().join(&backup_name).is_dir() {
                 let backups = checked!(
                     repo.get_backups(&backup_name),
@@ -674,6 +1917,19 @@ pub fn run() -> Result<(), ErrorCode> {
                     ErrorCode::RemoveRun
                 );
                 if force {
+                    if !ignore_min_retention {
+                        for (name, backup) in &backups {
+                            checked!(
+                                repo.check_min_retention(
+                                    &format!("{}/{}", &backup_name, name),
+                                    backup
+                                ),
+                                "remove backups",
+                                ErrorCode::RemoveRun
+                            );
+                        }
+                    }
+                    let usage = analyze_before_removal(&mut repo);
                     for name in backups.keys() {
                         checked!(
                             repo.delete_backup(&format!("{}/{}", &backup_name, name)),
@@ -681,6 +1937,7 @@ pub fn run() -> Result<(), ErrorCode> {
                             ErrorCode::RemoveRun
                         );
                     }
+                    print_reclaimable(&mut repo, usage);
                 } else {
                     error!("Denying to remove multiple backups (use --force):");
                     for name in backups.keys() {
@@ -688,12 +1945,61 @@ pub fn run() -> Result<(), ErrorCode> {
                     }
                 }
             } else {
+                if !ignore_min_retention && repo.config.min_retention > 0 {
+                    let backup = try!(get_backup(&repo, &backup_name));
+                    checked!(
+                        repo.check_min_retention(&backup_name, &backup),
+                        "delete backup",
+                        ErrorCode::RemoveRun
+                    );
+                }
+                let usage = analyze_before_removal(&mut repo);
                 checked!(
                     repo.delete_backup(&backup_name),
                     "delete backup",
                     ErrorCode::RemoveRun
                 );
-                info!("The backup has been deleted, run vacuum to reclaim space");
+                if repo.config.trash_retention > 0 {
+                    info!(
+                        "The backup is kept in the trash for {} days, use undelete to restore it",
+                        repo.config.trash_retention
+                    );
+                } else {
+                    info!("The backup has been deleted");
+                }
+                print_reclaimable(&mut repo, usage);
+            }
+        }
+        Arguments::Undelete {
+            repo_path,
+            backup_name
+        } => {
+            let mut repo = try!(open_repository(&repo_path));
+            if let Some(backup_name) = backup_name {
+                if repo.has_backup(&backup_name) {
+                    error!("A backup with that name already exists");
+                    return Err(ErrorCode::BackupAlreadyExists);
+                }
+                let name = checked!(
+                    repo.undelete_backup(&backup_name),
+                    "restore backup from trash",
+                    ErrorCode::UndeleteRun
+                );
+                info!("The backup has been restored from the trash as {}", name);
+            } else {
+                let trashed = checked!(
+                    repo.get_trashed_backups(),
+                    "list trash",
+                    ErrorCode::UndeleteRun
+                );
+                for (name, _backup, removed) in trashed {
+                    println!(
+                        "{:40}  removed {}, expires {}",
+                        name,
+                        Local.timestamp(removed, 0).format("%Y-%m-%d %H:%M"),
+                        Local.timestamp(repo.trash_expiry(removed), 0).format("%Y-%m-%d %H:%M")
+                    );
+                }
             }
         }
         Arguments::Prune {
@@ -703,30 +2009,90 @@ pub fn run() -> Result<(), ErrorCode> {
             weekly,
             monthly,
             yearly,
+            ignore_min_retention,
             force
         } => {
             let mut repo = try!(open_repository(&repo_path));
+            try!(require_secret_key(&repo));
             if daily + weekly + monthly + yearly == 0 {
                 error!("This would remove all those backups");
                 return Err(ErrorCode::UnsafeArgs);
             }
+            let usage = if force {
+                analyze_before_removal(&mut repo)
+            } else {
+                None
+            };
             checked!(
-                repo.prune_backups(&prefix, daily, weekly, monthly, yearly, force),
+                repo.prune_backups(
+                    &prefix,
+                    daily,
+                    weekly,
+                    monthly,
+                    yearly,
+                    ignore_min_retention,
+                    force
+                ),
                 "prune backups",
                 ErrorCode::PruneRun
             );
             if !force {
                 info!("Run with --force to actually execute this command");
+            } else {
+                print_reclaimable(&mut repo, usage);
+            }
+        }
+        Arguments::Purge {
+            repo_path,
+            patterns,
+            force
+        } => {
+            let mut repo = try!(open_repository(&repo_path));
+            let patterns = match try!(parse_patterns(&patterns)) {
+                Some(patterns) => patterns,
+                None => {
+                    error!("No patterns given");
+                    return Err(ErrorCode::InvalidArgs);
+                }
+            };
+            let report = checked!(repo.purge(&patterns, force), "purge", ErrorCode::PurgeRun);
+            for &(ref backup, ref path) in &report.paths {
+                println!("{}::{}", backup, path);
+            }
+            if report.paths.is_empty() {
+                info!("No matching paths found");
+            } else if !force {
+                info!("Run with --force to actually execute this command");
+            } else if report.bundles.is_empty() {
+                info!("The purged data is still used by other files");
+            } else {
+                info!("The following bundles contain purged data that can now be reclaimed:");
+                let mut max_ratio = 0.0f32;
+                for &(ref id, ratio) in &report.bundles {
+                    println!("{}: {:.1}% used", id, ratio * 100.0);
+                    max_ratio = max_ratio.max(ratio);
+                }
+                info!(
+                    "Run vacuum with --ratio {} or higher to rewrite them",
+                    (max_ratio * 100.0).ceil() as u64
+                );
             }
         }
         Arguments::Vacuum {
             repo_path,
             ratio,
             force,
-            combine
+            combine,
+            combine_threshold
         } => {
             let mut repo = try!(open_repository(&repo_path));
+            try!(require_secret_key(&repo));
             let info_before = repo.info();
+            let combine = if combine {
+                Some(combine_threshold.unwrap_or(repo.config.bundle_size / 4))
+            } else {
+                None
+            };
             checked!(
                 repo.vacuum(ratio, combine, force),
                 "vacuum",
@@ -749,9 +2115,19 @@ pub fn run() -> Result<(), ErrorCode> {
             bundles,
             index,
             bundle_data,
+            backup_files,
+            fix,
+            sample,
+            rebuild_backups,
             repair
         } => {
             let mut repo = try!(open_repository(&repo_path));
+            // Background checks should not occupy more than one core
+            let threads = if options.background {
+                1
+            } else {
+                worker_threads(DEFAULT_CHECK_THREADS)
+            };
             checked!(
                 repo.check_repository(repair),
                 "check repository",
@@ -759,7 +2135,7 @@ pub fn run() -> Result<(), ErrorCode> {
             );
             if bundles {
                 checked!(
-                    repo.check_bundles(bundle_data, repair),
+                    repo.check_bundles(bundle_data, repair, threads),
                     "check bundles",
                     ErrorCode::CheckRun
                 );
@@ -767,27 +2143,109 @@ pub fn run() -> Result<(), ErrorCode> {
             if index {
                 checked!(repo.check_index(repair), "check index", ErrorCode::CheckRun);
             }
-            if let Some(backup_name) = backup_name {
-                let mut backup = try!(get_backup(&repo, &backup_name));
+            if repo.is_write_only() {
+                if rebuild_backups || backup_files || backup_name.is_some() || sample.is_some() {
+                    try!(require_secret_key(&repo));
+                }
+                warn!("Without the secret key, backups and bundle contents have not been checked");
+                info!("Repository structure verified");
+                return Ok(());
+            }
+            // Before the backup files are checked, as orphaned meta bundles could be removed there
+            if rebuild_backups {
+                let rebuilt = checked!(
+                    repo.rebuild_backups(),
+                    "rebuild backups",
+                    ErrorCode::CheckRun
+                );
+                if rebuilt.is_empty() {
+                    info!("No lost backups found");
+                } else {
+                    info!("Rebuilt {} backups", rebuilt.len());
+                }
+            }
+            if backup_files {
+                // Without explicit classes, --repair fixes all of them
+                let fix: HashSet<_> = if fix.is_empty() && repair {
+                    vec![
+                        BackupFileProblem::MissingRoot,
+                        BackupFileProblem::Duplicate,
+                        BackupFileProblem::OrphanedMeta,
+                    ].into_iter()
+                        .collect()
+                } else {
+                    fix.into_iter().collect()
+                };
+                let report = checked!(
+                    repo.check_backup_files(&fix),
+                    "check backup files",
+                    ErrorCode::CheckRun
+                );
+                print_backup_file_report(&report, &fix);
+                let unfixed = |problem, found: bool| found && !fix.contains(&problem);
+                if unfixed(BackupFileProblem::MissingRoot, !report.missing_roots.is_empty()) ||
+                    unfixed(BackupFileProblem::Duplicate, !report.duplicates.is_empty()) ||
+                    unfixed(BackupFileProblem::OrphanedMeta, !report.orphaned_meta.is_empty())
+                {
+                    error!("Problems with backup files found, use --repair or --fix to fix them");
+                    return Err(ErrorCode::CheckRun);
+                }
+            }
+            if let Some(ref backup_name) = backup_name {
+                let mut backup = try!(get_backup(&repo, backup_name));
                 if let Some(path) = inode {
                     checked!(
-                        repo.check_backup_inode(&backup_name, &mut backup, Path::new(&path), repair),
+                        repo.check_backup_inode(backup_name, &mut backup, Path::new(&path), repair),
                         "check inode",
                         ErrorCode::CheckRun
                     )
                 } else {
                     checked!(
-                        repo.check_backup(&backup_name, &mut backup, repair),
+                        repo.check_backup(backup_name, &mut backup, repair),
                         "check backup",
                         ErrorCode::CheckRun
                     )
                 }
             } else {
                 checked!(
-                    repo.check_backups(repair),
+                    repo.check_backups(repair, threads),
                     "check repository",
                     ErrorCode::CheckRun
-                )
+                );
+                checked!(repo.set_checked(), "record check", ErrorCode::CheckRun)
+            }
+            if let Some(ratio) = sample {
+                let backups: Vec<_> = if let Some(ref backup_name) = backup_name {
+                    vec![(backup_name.clone(), try!(get_backup(&repo, backup_name)))]
+                } else {
+                    let backup_map = match repo.get_all_backups() {
+                        Ok(backup_map) => backup_map,
+                        Err(RepositoryError::BackupFile(BackupFileError::PartialBackupsList(backup_map,
+                                                                                            _failed))) => {
+                            warn!("Some backups could not be read, ignoring them");
+                            backup_map
+                        }
+                        Err(err) => return Err(fail(&err, "load backups", ErrorCode::LoadBackup)),
+                    };
+                    backup_map.into_iter().collect()
+                };
+                let report = checked!(
+                    repo.check_sample(&backups, ratio),
+                    "restore sampled files",
+                    ErrorCode::CheckRun
+                );
+                for &(ref name, ref path, ref err) in &report.failed {
+                    error!("Failed to restore {}::/{}: {}", name, path.display(), err);
+                }
+                info!(
+                    "Restored {} sampled files with {}",
+                    report.files,
+                    to_file_size(report.size)
+                );
+                if !report.failed.is_empty() {
+                    error!("{} sampled files could not be restored", report.failed.len());
+                    return Err(ErrorCode::CheckRun);
+                }
             }
             repo.set_clean();
             info!("Integrity verified")
@@ -795,13 +2253,26 @@ pub fn run() -> Result<(), ErrorCode> {
         Arguments::List {
             repo_path,
             backup_name,
-            inode
+            inode,
+            tags,
+            host,
+            path
         } => {
-            let mut repo = try!(open_repository(&repo_path));
+            // Listing backups only needs the backup files, not the index and the bundles
+            let catalog = checked!(
+                BackupCatalog::open(&repo_path),
+                "load repository",
+                ErrorCode::LoadRepository
+            );
             let backup_map = if let Some(backup_name) = backup_name {
-                if repo.layout.backups_path().join(&backup_name).is_dir() {
-                    repo.get_backups(&backup_name)
+                if catalog.layout.backups_path().join(&backup_name).is_dir() {
+                    catalog.get_backups(&backup_name)
                 } else {
+                    if !tags.is_empty() || host.is_some() || path.is_some() {
+                        error!("Filters can only be used when listing backups");
+                        return Err(ErrorCode::InvalidArgs);
+                    }
+                    let mut repo = try!(open_repository(&repo_path));
                     let backup = try!(get_backup(&repo, &backup_name));
                     let inode = checked!(
                         repo.get_backup_inode(
@@ -811,23 +2282,33 @@ pub fn run() -> Result<(), ErrorCode> {
                         "load subpath inode",
                         ErrorCode::LoadInode
                     );
-                    println!("{}", format_inode_one_line(&inode));
-                    if let Some(children) = inode.children {
-                        for chunks in children.values() {
-                            let inode = checked!(
+                    let mut children = vec![];
+                    if let Some(ref chunks) = inode.children {
+                        for chunks in chunks.values() {
+                            children.push(checked!(
                                 repo.get_inode(chunks),
                                 "load child inode",
                                 ErrorCode::LoadInode
-                            );
-                            println!("- {}", format_inode_one_line(&inode));
+                            ));
                         }
                     }
+                    // The children are indented by two, so the names line up with the parent
+                    let width = max(
+                        inode_display_name(&inode).chars().count(),
+                        inode_name_width(&children) + 2
+                    );
+                    let mut lines = Vec::with_capacity(children.len() + 1);
+                    lines.push(format_inode_one_line(&inode, width));
+                    for child in &children {
+                        lines.push(format!("- {}", format_inode_one_line(child, width - 2)));
+                    }
+                    print_lines(&lines);
                     return Ok(());
                 }
             } else {
-                repo.get_all_backups()
+                catalog.get_all_backups()
             };
-            let backup_map = match backup_map {
+            let mut backup_map = match backup_map {
                 Ok(backup_map) => backup_map,
                 Err(RepositoryError::BackupFile(BackupFileError::PartialBackupsList(backup_map, _failed))) => {
                     warn!("Some backups could not be read, ignoring them");
@@ -838,38 +2319,312 @@ pub fn run() -> Result<(), ErrorCode> {
                     return Err(ErrorCode::LoadBackup);
                 }
             };
+            backup_map.retain(|_, backup| {
+                tags.iter().all(|tag| backup.tags.contains(tag)) &&
+                    host.as_ref().map_or(true, |host| &backup.host == host) &&
+                    path.as_ref().map_or(true, |path| &backup.path == path)
+            });
             print_backups(&backup_map);
         }
+        Arguments::Tag {
+            repo_path,
+            backup_name,
+            add,
+            remove
+        } => {
+            let mut repo = try!(open_repository(&repo_path));
+            if !repo.has_backup(&backup_name) {
+                error!("A backup with that name does not exist");
+                return Err(ErrorCode::NoSuchBackup);
+            }
+            let tags = checked!(
+                repo.tag_backup(&backup_name, &add, &remove),
+                "save backup file",
+                ErrorCode::SaveBackup
+            );
+            info!("Tags: {}", tags.join(", "));
+        }
+        Arguments::Remote {
+            repo_path,
+            check: true,
+            ..
+        } => {
+            // The repository is not opened, so this also works when opening it fails
+            let layout = RepositoryLayout::new(&repo_path);
+            let config = checked!(
+                Config::load(layout.config_path()),
+                "load config",
+                ErrorCode::LoadRepository
+            );
+            let mut remotes = vec![("main".to_string(), layout.remote_path())];
+            remotes.extend(checked!(
+                load_remotes(&layout),
+                "load remotes",
+                ErrorCode::LoadRepository
+            ));
+            let mut result = Ok(());
+            for (name, path) in remotes {
+                match check_remote(&path, config.bundle_size) {
+                    Ok(check) => {
+                        print_remote_check(&name, &path, &check);
+                        if !check.is_ok() && result.is_ok() {
+                            result = Err(ErrorCode::RemoteCheckRun);
+                        }
+                    }
+                    Err(err) => {
+                        error!(
+                            "Failed to list remote {} at {:?}\n\tcaused by: {}",
+                            name,
+                            path,
+                            err
+                        );
+                        result = Err(ErrorCode::RemoteUnreachable);
+                    }
+                }
+            }
+            return result;
+        }
+        Arguments::Remote {
+            repo_path,
+            add,
+            remove,
+            ..
+        } => {
+            let mut repo = try!(open_repository(&repo_path));
+            let remotes = checked!(repo.get_remotes(), "load remotes", ErrorCode::LoadRepository);
+            if let Some((name, path)) = add {
+                if remotes.contains_key(&name) {
+                    error!("A remote with that name already exists");
+                    return Err(ErrorCode::InvalidArgs);
+                }
+                if !Path::new(&path).is_absolute() {
+                    error!("The path of a remote must be absolute.");
+                    return Err(ErrorCode::InvalidArgs);
+                }
+                checked!(
+                    repo.add_remote(&name, Path::new(&path)),
+                    "add remote",
+                    ErrorCode::SaveConfig
+                );
+                info!("Added remote {} at {}", name, path);
+            } else if let Some(name) = remove {
+                checked!(repo.remove_remote(&name), "remove remote", ErrorCode::SaveConfig);
+                info!("Removed remote {}, its contents have not been deleted", name);
+            } else {
+                for (name, path) in remotes {
+                    let status = if remote_reachable(&path) {
+                        "reachable"
+                    } else {
+                        "not reachable"
+                    };
+                    println!("{:15} {} ({})", name, path.display(), status);
+                }
+            }
+        }
         Arguments::Info {
             repo_path,
             backup_name,
-            inode
+            inode,
+            field,
+            stats,
+            rules
         } => {
             let mut repo = try!(open_repository(&repo_path));
             if let Some(backup_name) = backup_name {
                 let backup = try!(get_backup(&repo, &backup_name));
-                if let Some(inode) = inode {
+                if rules {
+                    match backup.rules {
+                        Some(ref rules) => print_backup_rules(&backup_name, rules),
+                        None => {
+                            error!("The backup has no recorded rules");
+                            info!("Tar imports and backups of older versions have no rules");
+                            return Err(ErrorCode::InvalidArgs);
+                        }
+                    }
+                } else if let Some(inode) = inode {
                     let inode = checked!(
                         repo.get_backup_inode(&backup, inode),
                         "load subpath inode",
                         ErrorCode::LoadInode
                     );
-                    print_inode(&inode);
+                    if let Some(field) = field {
+                        try!(print_field(get_inode_field(&inode, &field), &field));
+                    } else {
+                        print_inode(&inode);
+                        if inode.file_type == FileType::File {
+                            match repo.analyze_file_chunks(&backup_name, &inode) {
+                                Ok(ref analysis) if analysis.total.0 > 0 => {
+                                    println!();
+                                    print_file_chunks(analysis)
+                                }
+                                Ok(_) => (),
+                                Err(err) => warn!("Failed to analyze the file chunks: {}", err),
+                            }
+                        }
+                    }
+                } else if let Some(field) = field {
+                    try!(print_field(get_backup_field(&backup, &field), &field));
                 } else {
-                    print_backup(&backup);
+                    print_backup(&backup, stats);
+                    match repo.get_unique_size(&backup_name, &backup) {
+                        Ok(Some((raw_size, encoded_size))) => {
+                            println!();
+                            println!(
+                                "Unique data: {}, {} encoded",
+                                to_file_size(raw_size),
+                                to_file_size(encoded_size)
+                            )
+                        }
+                        Ok(None) => (),
+                        Err(err) => warn!("Failed to calculate the unique data: {}", err),
+                    }
                 }
+            } else if let Some(field) = field {
+                try!(print_field(get_repoinfo_field(&repo.info(), &field), &field));
             } else {
                 print_repoinfo(&repo.info());
             }
         }
+        Arguments::LockStatus { repo_path } => {
+            // The repository is not opened as that would need a lock itself
+            let layout = RepositoryLayout::new(&repo_path);
+            let mut locks = vec![];
+            for (location, path) in vec![
+                ("remote", layout.remote_locks_path()),
+                ("local", layout.local_locks_path()),
+            ]
+            {
+                let files = checked!(
+                    LockFolder::new(path).get_lock_files(),
+                    "read locks",
+                    ErrorCode::LoadRepository
+                );
+                locks.extend(files.into_iter().map(|(path, lock)| (location, path, lock)));
+            }
+            if locks.is_empty() {
+                info!("The repository is not locked");
+                return Ok(());
+            }
+            print_lock_status(&locks);
+        }
+        Arguments::Status { repo_path } => {
+            let repo = try!(open_repository(&repo_path));
+            if repo.config.health.freshness.is_empty() {
+                warn!("No freshness rules configured, set them via `zvault config --freshness`");
+                return Ok(());
+            }
+            let backup_map = match repo.get_all_backups() {
+                Ok(backup_map) => backup_map,
+                Err(RepositoryError::BackupFile(BackupFileError::PartialBackupsList(backup_map,
+                                                                                    _failed))) => {
+                    warn!("Some backups could not be read, ignoring them");
+                    backup_map
+                }
+                Err(err) => {
+                    error!("Failed to load backup files: {}", err);
+                    return Err(ErrorCode::LoadBackup);
+                }
+            };
+            let now = Local::now().timestamp();
+            let mut overdue = 0;
+            for status in repo.config.health.check_freshness(&backup_map) {
+                let (state, color) = if status.is_overdue(now) {
+                    overdue += 1;
+                    ("OVERDUE", Color::Red)
+                } else {
+                    ("OK", Color::Green)
+                };
+                let latest = match status.latest {
+                    Some((ref name, date)) => {
+                        format!("{}, {} ago", name, to_duration((now - date) as f32))
+                    }
+                    None => "no backup".to_string(),
+                };
+                println!(
+                    "{} {:20} every {:3} hours, latest: {}",
+                    color.bold().paint(format!("{:7}", state)),
+                    status.pattern,
+                    status.max_age,
+                    latest
+                );
+            }
+            if overdue > 0 {
+                error!("{} backup patterns are overdue", overdue);
+                return Err(ErrorCode::BackupOverdue);
+            }
+        }
+        Arguments::Exists {
+            repo_path,
+            backup_name,
+            inode
+        } => {
+            if !repo_path.join("config.yaml").exists() {
+                return Err(ErrorCode::DoesNotExist);
+            }
+            if let Some(backup_name) = backup_name {
+                let mut repo = try!(open_repository(&repo_path));
+                if !repo.has_backup(&backup_name) {
+                    let is_folder = repo.layout.backups_path().join(&backup_name).is_dir();
+                    if inode.is_none() && is_folder {
+                        return Ok(());
+                    }
+                    return Err(ErrorCode::DoesNotExist);
+                }
+                if let Some(inode) = inode {
+                    let backup = try!(get_backup(&repo, &backup_name));
+                    match repo.get_backup_inode(&backup, inode) {
+                        Ok(_) => (),
+                        Err(RepositoryError::NoSuchFileInBackup(..)) => {
+                            return Err(ErrorCode::DoesNotExist)
+                        }
+                        Err(err) => {
+                            error!("Failed to load subpath inode\n\tcaused by: {}", err);
+                            return Err(ErrorCode::LoadInode);
+                        }
+                    }
+                }
+            }
+        }
+        #[cfg(unix)]
         Arguments::Mount {
             repo_path,
             backup_name,
             inode,
-            mount_point
+            mount_point,
+            preload_meta,
+            prewarm,
+            raw,
+            verify
         } => {
             let mut repo = try!(open_repository(&repo_path));
-            let fs = if let Some(backup_name) = backup_name {
+            try!(require_secret_key(&repo));
+            repo.set_verify_reads(verify);
+            if prewarm {
+                let count = checked!(
+                    repo.prewarm_from_mount_hints(),
+                    "read mount hints",
+                    ErrorCode::FuseMount
+                );
+                info!("Prewarming {} bundles in the background", count);
+            }
+            let pid = unsafe { libc::getpid() } as usize;
+            let mount_file_path = ZVAULT_FOLDER
+                .join("mounts")
+                .join(format!("{}.yaml", pid));
+            let mount_file = MountFile::new(
+                pid,
+                &fs::canonicalize(&mount_point).unwrap_or_else(|_| PathBuf::from(&mount_point)),
+                &fs::canonicalize(&repo_path).unwrap_or_else(|_| repo_path.clone()),
+                backup_name.clone(),
+                inode.clone()
+            );
+            let mut fs = if raw {
+                checked!(
+                    FuseFilesystem::from_raw(&mut repo),
+                    "create fuse filesystem",
+                    ErrorCode::FuseMount
+                )
+            } else if let Some(backup_name) = backup_name {
                 if repo.layout.backups_path().join(&backup_name).is_dir() {
                     checked!(
                         FuseFilesystem::from_repository(&mut repo, Some(&backup_name)),
@@ -904,24 +2659,84 @@ pub fn run() -> Result<(), ErrorCode> {
                     ErrorCode::FuseMount
                 )
             };
+            if preload_meta {
+                info!("Loading metadata...");
+                let count = checked!(fs.preload_meta(), "load metadata", ErrorCode::FuseMount);
+                info!("Loaded {} inodes", count);
+            }
+            // Other commands use the mount file to find the backup behind paths in the mount,
+            // paths in raw mounts do not belong to backups
+            let saved = if raw {
+                Ok(())
+            } else {
+                fs::create_dir_all(mount_file_path.parent().unwrap())
+                    .map_err(Into::into)
+                    .and_then(|()| mount_file.save(&mount_file_path))
+            };
+            if let Err(err) = saved {
+                warn!("Failed to save mount file\n\tcaused by: {}", err);
+            }
             info!("Mounting the filesystem...");
             info!(
                 "Please unmount the filesystem via 'fusermount -u {}' when done.",
                 mount_point
             );
-            checked!(
-                fs.mount(&mount_point),
-                "mount filesystem",
-                ErrorCode::FuseMount
-            );
+            let res = fs.mount(&mount_point);
+            fs::remove_file(&mount_file_path).ok();
+            checked!(res, "mount filesystem", ErrorCode::FuseMount);
+        }
+        #[cfg(not(unix))]
+        Arguments::Mount { .. } => {
+            error!("Mounting is only supported on unix");
+            return Err(ErrorCode::FuseMount);
         }
-        Arguments::Analyze { repo_path } => {
+        Arguments::Analyze {
+            repo_path,
+            by_extension,
+            by_host,
+            by_origin
+        } => {
             let mut repo = try!(open_repository(&repo_path));
-            print_analysis(&checked!(
-                repo.analyze_usage(),
-                "analyze repository",
+            try!(require_secret_key(&repo));
+            if by_origin {
+                print_origin_analysis(&checked!(
+                    repo.analyze_origins(),
+                    "analyze repository",
+                    ErrorCode::AnalyzeRun
+                ));
+            } else if by_host {
+                print_host_analysis(&checked!(
+                    repo.analyze_hosts(),
+                    "analyze repository",
+                    ErrorCode::AnalyzeRun
+                ));
+            } else if by_extension {
+                print_extension_stats(
+                    &checked!(
+                        repo.analyze_extensions(),
+                        "analyze repository",
+                        ErrorCode::AnalyzeRun
+                    ),
+                    None
+                );
+            } else {
+                print_analysis(&checked!(
+                    repo.analyze_usage(),
+                    "analyze repository",
+                    ErrorCode::AnalyzeRun
+                ));
+            }
+        }
+        Arguments::AnalyzeChunking {
+            path,
+            chunkers,
+            hash
+        } => {
+            checked!(
+                bench::analyze_chunking(&path, &chunkers, hash),
+                "analyze chunking",
                 ErrorCode::AnalyzeRun
-            ));
+            );
         }
         Arguments::BundleList { repo_path } => {
             let repo = try!(open_repository(&repo_path));
@@ -941,6 +2756,152 @@ pub fn run() -> Result<(), ErrorCode> {
                 return Err(ErrorCode::LoadBundle);
             }
         }
+        Arguments::DebugBundleDump {
+            repo_path,
+            bundle_id
+        } => {
+            let repo = try!(open_repository(&repo_path));
+            if let Some(bundle) = repo.get_bundle(&bundle_id) {
+                print_bundle(bundle);
+            } else {
+                error!("No such bundle");
+                return Err(ErrorCode::LoadBundle);
+            }
+            let reader = checked!(
+                repo.load_bundle_reader(&bundle_id),
+                "load bundle",
+                ErrorCode::LoadBundle
+            );
+            print_bundle_dump(&reader);
+        }
+        Arguments::DebugChunk { repo_path, hash } => {
+            let mut repo = try!(open_repository(&repo_path));
+            let (bundle_id, chunk) = match checked!(
+                repo.find_chunk(&hash),
+                "locate chunk",
+                ErrorCode::LoadBundle
+            ) {
+                Some(location) => location,
+                None => {
+                    error!("The chunk is not stored in the repository");
+                    return Err(ErrorCode::DoesNotExist);
+                }
+            };
+            info!("Chunk {} is stored in bundle {} as chunk {}", hash, bundle_id, chunk);
+            let data = match checked!(repo.get_chunk(hash), "load chunk", ErrorCode::LoadBundle) {
+                Some(data) => data,
+                None => return Err(ErrorCode::DoesNotExist),
+            };
+            if let Some(bundle) = repo.get_bundle(&bundle_id) {
+                match bundle.info.hash_chunk(&data, repo.config.hash_key.as_ref()) {
+                    Some(actual) if actual != hash => {
+                        warn!("The data of the chunk does not match its hash, its hash is {}", actual)
+                    }
+                    Some(_) => (),
+                    None => warn!("The chunk hash is keyed but the hash key is not known"),
+                }
+            }
+            let stdout = io::stdout();
+            checked!(stdout.lock().write_all(&data), "write chunk data", ErrorCode::LoadBundle);
+        }
+        Arguments::DebugFsckFile { path, key_files } => {
+            let path = Path::new(&path);
+            let kind = match checked!(FileKind::detect(path), "read file", ErrorCode::CheckRun) {
+                Some(kind) => kind,
+                None => {
+                    error!("The file is not a known repository file");
+                    return Err(ErrorCode::InvalidArgs);
+                }
+            };
+            let mut crypto = Crypto::dummy();
+            for file in key_files {
+                checked!(crypto.register_keyfile(file), "load key file", ErrorCode::LoadKey);
+            }
+            let description = checked!(
+                check_file(path, kind, Arc::new(Mutex::new(crypto))),
+                &format!("check {}", kind.name()),
+                ErrorCode::CheckRun
+            );
+            println!("{}", description);
+            info!("The {} is valid", kind.name());
+        }
+        Arguments::ExcludesEdit { repo_path } => {
+            let path = RepositoryLayout::new(repo_path).excludes_path();
+            let edit_path = path.with_extension("edit");
+            let mut original = vec![];
+            checked!(
+                File::open(&path).and_then(|mut f| f.read_to_end(&mut original)),
+                "read excludes file",
+                ErrorCode::LoadExcludes
+            );
+            checked!(fs::copy(&path, &edit_path), "copy excludes file", ErrorCode::EditRun);
+            loop {
+                if let Err(err) = run_editor(&edit_path) {
+                    fs::remove_file(&edit_path).ok();
+                    error!("Failed to run editor\n\tcaused by: {}", err);
+                    return Err(ErrorCode::EditRun);
+                }
+                let mut data = vec![];
+                checked!(
+                    File::open(&edit_path).and_then(|mut f| f.read_to_end(&mut data)),
+                    "read edited excludes",
+                    ErrorCode::EditRun
+                );
+                if data == original {
+                    fs::remove_file(&edit_path).ok();
+                    info!("The excludes have not been changed");
+                    break;
+                }
+                if parse_patterns(&read_lines(&data)).is_ok() {
+                    checked!(fs::rename(&edit_path, &path), "save excludes", ErrorCode::EditRun);
+                    info!("Saved the new excludes");
+                    break;
+                }
+                print!("Edit the excludes again? [Y/n] ");
+                io::stdout().flush().ok();
+                let mut answer = String::new();
+                io::stdin().read_line(&mut answer).ok();
+                if answer.trim().to_lowercase().starts_with('n') {
+                    fs::remove_file(&edit_path).ok();
+                    error!("The excludes have not been saved");
+                    return Err(ErrorCode::InvalidExcludes);
+                }
+            }
+        }
+        Arguments::IndexShrink { repo_path } => {
+            let mut repo = try!(open_repository(&repo_path));
+            let (before, after) = checked!(
+                repo.shrink_index(),
+                "shrink index",
+                ErrorCode::VacuumRun
+            );
+            if before == after {
+                info!("The index already fits its entries ({})", to_file_size(after as u64));
+            } else {
+                info!(
+                    "Shrunk the index from {} to {}",
+                    to_file_size(before as u64),
+                    to_file_size(after as u64)
+                );
+            }
+        }
+        Arguments::BundleAffected {
+            repo_path,
+            bundle_id
+        } => {
+            let mut repo = try!(open_repository(&repo_path));
+            let paths = checked!(
+                repo.find_affected_paths(&bundle_id),
+                "find affected paths",
+                ErrorCode::LoadBundle
+            );
+            if paths.is_empty() {
+                info!("No backup uses data of this bundle");
+            }
+            for (backup, path) in paths {
+                println!("{}::{}", backup, path);
+            }
+        }
         Arguments::Import {
             repo_path,
             remote_path,
@@ -953,24 +2914,185 @@ pub fn run() -> Result<(), ErrorCode> {
             );
             info!("Import finished");
         }
-        Arguments::Versions { repo_path, path } => {
-            let mut repo = try!(open_repository(&repo_path));
-            let mut found = false;
-            for (name, mut inode) in
-                checked!(
-                    repo.find_versions(&path),
-                    "find versions",
-                    ErrorCode::VersionsRun
-                )
+        Arguments::ChunkHas {
+            repo_path,
+            hashes,
+            stdin
+        } => {
+            let repo = try!(open_repository(&repo_path));
+            let mut missing = false;
             {
-                inode.name = format!("{}::{}", name, &path);
-                println!("{}", format_inode_one_line(&inode));
-                found = true;
+                let mut report = |hash: &Hash| if repo.has_chunk(hash) {
+                    println!("{} present", hash);
+                } else {
+                    println!("{} missing", hash);
+                    missing = true;
+                };
+                for hash in &hashes {
+                    report(hash);
+                }
+                if stdin {
+                    // Answering line by line so that the results can be consumed while streaming
+                    let input = io::stdin();
+                    for line in input.lock().lines() {
+                        let line = checked!(line, "read hashes from stdin", ErrorCode::InvalidArgs);
+                        let line = line.trim();
+                        if line.is_empty() {
+                            continue;
+                        }
+                        match Hash::from_string(line) {
+                            Ok(hash) => report(&hash),
+                            Err(()) => {
+                                error!("Invalid chunk hash: {}", line);
+                                return Err(ErrorCode::InvalidArgs);
+                            }
+                        }
+                    }
+                }
             }
-            if !found {
+            if missing {
+                return Err(ErrorCode::DoesNotExist);
+            }
+        }
+        Arguments::ExportDelta {
+            repo_path,
+            since,
+            dst_path
+        } => {
+            let mut repo = try!(open_repository(&repo_path));
+            if !repo.has_backup(&since) {
+                error!("A backup with that name does not exist");
+                return Err(ErrorCode::NoSuchBackup);
+            }
+            let (bundles, backups) = checked!(
+                repo.export_delta(&since, Path::new(&dst_path)),
+                "export delta",
+                ErrorCode::ExportRun
+            );
+            info!("Exported {} bundles and {} backups", bundles, backups);
+        }
+        Arguments::ImportDelta {
+            repo_path,
+            src_path
+        } => {
+            let mut repo = try!(open_repository(&repo_path));
+            let (bundles, backups) = checked!(
+                repo.import_delta(Path::new(&src_path)),
+                "import delta",
+                ErrorCode::ImportRun
+            );
+            drop(repo);
+            // Opening the repository again adds the new bundles to the index
+            try!(open_repository(&repo_path));
+            info!("Imported {} bundles and {} backups", bundles, backups);
+        }
+        Arguments::SeedExport {
+            repo_path,
+            dst_path,
+            bwlimit
+        } => {
+            let mut repo = try!(open_repository(&repo_path));
+            let report = checked!(
+                repo.seed_export(Path::new(&dst_path), bwlimit),
+                "export seed",
+                ErrorCode::ExportRun
+            );
+            info!(
+                "Copied {} files ({}), {} files were already present",
+                report.copied,
+                to_file_size(report.size),
+                report.skipped
+            );
+            info!("Export finished, import the files on the server with `zvault seed import`");
+        }
+        Arguments::SeedImport {
+            src_path,
+            remote_path,
+            bwlimit
+        } => {
+            let report = checked!(
+                copy_seed_files(Path::new(&src_path), Path::new(&remote_path), bwlimit),
+                "import seed",
+                ErrorCode::ImportRun
+            );
+            info!(
+                "Copied {} files ({}), {} files were already present",
+                report.copied,
+                to_file_size(report.size),
+                report.skipped
+            );
+            info!("Import finished, switch the repository over with `zvault seed finish`");
+        }
+        Arguments::SeedFinish {
+            repo_path,
+            remote_path,
+            bwlimit
+        } => {
+            let mut repo = try!(open_repository(&repo_path));
+            let report = checked!(
+                repo.seed_finish(Path::new(&remote_path), bwlimit),
+                "finish seeding",
+                ErrorCode::ExportRun
+            );
+            info!(
+                "Copied {} files ({}), {} files were already present",
+                report.copied,
+                to_file_size(report.size),
+                report.skipped
+            );
+            info!("The repository now uses the remote storage {}", remote_path);
+        }
+        Arguments::Clone {
+            repo_path,
+            target_path,
+            remote_path
+        } => {
+            checked!(
+                Repository::clone_from(repo_path, target_path, remote_path.as_ref().map(Path::new)),
+                "clone repository",
+                ErrorCode::CloneRun
+            );
+            info!("Clone finished, the new repository can only be used for reading");
+        }
+        Arguments::Versions { repo_path, path } => {
+            let mut repo = try!(open_repository(&repo_path));
+            try!(require_secret_key(&repo));
+            let versions: Vec<_> = checked!(
+                repo.find_versions(&path),
+                "find versions",
+                ErrorCode::VersionsRun
+            ).into_iter()
+                .map(|(name, mut inode)| {
+                    inode.name = format!("{}::{}", name, &path);
+                    inode
+                })
+                .collect();
+            let width = inode_name_width(&versions);
+            let lines: Vec<_> = versions
+                .iter()
+                .map(|inode| format_inode_one_line(inode, width))
+                .collect();
+            print_lines(&lines);
+            if versions.is_empty() {
                 info!("No versions of that file were found.");
             }
         }
+        Arguments::Log { repo_path, action } => {
+            let repo = try!(open_repository(&repo_path));
+            for entry in checked!(repo.get_audit_log(), "read log", ErrorCode::LogRun) {
+                if action.as_ref().map_or(false, |action| *action != entry.action) {
+                    continue;
+                }
+                println!(
+                    "{}  {}@{}  {:15}  {}",
+                    Local.timestamp(entry.date, 0).to_rfc2822(),
+                    entry.user,
+                    entry.hostname,
+                    entry.action,
+                    entry.details
+                );
+            }
+        }
         Arguments::Diff {
             repo_path_old,
             backup_name_old,
@@ -1004,15 +3126,12 @@ pub fn run() -> Result<(), ErrorCode> {
                 ErrorCode::DiffRun
             );
             for diff in &diffs {
-                println!(
-                    "{} {:?}",
-                    match diff.0 {
-                        DiffType::Add => "add",
-                        DiffType::Mod => "mod",
-                        DiffType::Del => "del",
-                    },
-                    diff.1
-                );
+                match diff.0 {
+                    DiffType::Add => println!("add {:?}", diff.1),
+                    DiffType::Mod => println!("mod {:?}", diff.1),
+                    DiffType::Del => println!("del {:?}", diff.1),
+                    DiffType::Mov(ref old_path) => println!("mov {:?} -> {:?}", old_path, diff.1),
+                }
             }
             if diffs.is_empty() {
                 info!("No differences found");
@@ -1024,7 +3143,19 @@ pub fn run() -> Result<(), ErrorCode> {
             chunker,
             compression,
             encryption,
-            hash
+            hash,
+            delta_compression,
+            dictionary,
+            freshness,
+            trash_retention,
+            min_retention,
+            upload_verification,
+            zero_chunks,
+            codec_helpers,
+            short_bundle_names,
+            threads,
+            cpus,
+            check
         } => {
             let mut repo = try!(open_repository(&repo_path));
             let mut changed = false;
@@ -1033,34 +3164,113 @@ pub fn run() -> Result<(), ErrorCode> {
                 changed = true;
             }
             if let Some(chunker) = chunker {
-                warn!(
-                    "Changing the chunker makes it impossible to use existing data for deduplication"
-                );
+                if chunker != repo.config.chunker {
+                    warn!(
+                        "Changing the chunker means that existing data will no longer deduplicate"
+                    );
+                }
                 repo.config.chunker = chunker;
                 changed = true;
             }
             if let Some(compression) = compression {
+                if compression != repo.config.compression {
+                    info!("Existing bundles keep their compression, only new bundles are affected");
+                }
                 repo.config.compression = compression;
                 changed = true;
             }
             if let Some(encryption) = encryption {
+                if encryption.is_none() && repo.config.encryption.is_some() {
+                    warn!("New bundles and backups will be stored unencrypted");
+                }
                 repo.set_encryption(encryption.as_ref());
                 changed = true;
             }
             if let Some(hash) = hash {
-                warn!(
-                    "Changing the hash makes it impossible to use existing data for deduplication"
-                );
+                if hash != repo.config.hash {
+                    warn!("Changing the hash means that existing data will no longer deduplicate");
+                }
                 repo.config.hash = hash;
                 changed = true;
             }
-            if changed {
+            if let Some(delta_compression) = delta_compression {
+                repo.config.delta_compression = delta_compression;
+                changed = true;
+            }
+            if let Some(dictionary) = dictionary {
+                checked!(repo.set_dictionary(dictionary), "set dictionary", ErrorCode::SaveConfig);
+                changed = true;
+            }
+            for (pattern, max_age) in freshness {
+                if max_age == 0 {
+                    repo.config.health.freshness.remove(&pattern);
+                } else {
+                    repo.config.health.freshness.insert(pattern, max_age);
+                }
+                changed = true;
+            }
+            if let Some(trash_retention) = trash_retention {
+                repo.config.trash_retention = trash_retention;
+                changed = true;
+            }
+            if let Some(min_retention) = min_retention {
+                repo.config.min_retention = min_retention;
+                changed = true;
+            }
+            if let Some(upload_verification) = upload_verification {
+                repo.config.upload_verification = upload_verification;
+                changed = true;
+            }
+            if let Some(zero_chunks) = zero_chunks {
+                repo.config.zero_chunks = zero_chunks;
+                changed = true;
+            }
+            if let Some(codec_helpers) = codec_helpers {
+                repo.config.codec_helpers = codec_helpers;
+                changed = true;
+            }
+            if let Some(short_bundle_names) = short_bundle_names {
+                repo.config.short_bundle_names = short_bundle_names;
+                changed = true;
+            }
+            if let Some(threads) = threads {
+                repo.config.threads = threads;
+                changed = true;
+            }
+            if let Some(cpus) = cpus {
+                repo.config.cpus = cpus;
+                changed = true;
+            }
+            if check {
+                print_config(&repo.config);
+                info!("Dry run, the configuration has not been changed.");
+            } else if changed {
                 checked!(repo.save_config(), "save config", ErrorCode::SaveConfig);
                 info!("The configuration has been updated.");
             } else {
                 print_config(&repo.config);
             }
         }
+        Arguments::TrainDictionary {
+            repo_path,
+            size,
+            max_chunk_size
+        } => {
+            let mut repo = try!(open_repository(&repo_path));
+            try!(require_secret_key(&repo));
+            let id = checked!(
+                repo.train_dictionary(size, max_chunk_size),
+                "train dictionary",
+                ErrorCode::TrainDictionary
+            );
+            checked!(repo.set_dictionary(Some(id)), "set dictionary", ErrorCode::SaveConfig);
+            checked!(repo.save_config(), "save config", ErrorCode::SaveConfig);
+            info!("New bundles will be compressed with dictionary {}", id);
+        }
+        Arguments::Proxy { repo_path, listen } => {
+            let repo = try!(open_repository(&repo_path));
+            checked!(repo.serve_proxy(&listen), "serve bundles", ErrorCode::ProxyRun);
+        }
         Arguments::GenKey { file, password } => {
             let (public, secret) = match password {
                 None => Crypto::gen_keypair(),
@@ -1113,6 +3323,109 @@ pub fn run() -> Result<(), ErrorCode> {
                 );
             }
         }
+        Arguments::CodecHelper => {
+            checked!(serve_codec_requests(), "serve codec requests", ErrorCode::CodecHelperRun);
+        }
+        Arguments::Agent { timeout, stop } => {
+            if stop {
+                checked!(agent_stop(), "stop agent", ErrorCode::AgentRun);
+                info!("Stopped the agent");
+                return Ok(());
+            }
+            let mut agent = checked!(
+                PassphraseAgent::new(Duration::from_secs(timeout * 60)),
+                "start agent",
+                ErrorCode::AgentRun
+            );
+            info!(
+                "Caching the passphrase until it has not been used for {} minutes",
+                timeout
+            );
+            checked!(agent.run(), "run agent", ErrorCode::AgentRun);
+        }
+        Arguments::SystemdUnits {
+            src_path,
+            backup,
+            name,
+            schedule,
+            output_dir
+        } => {
+            // Services run in the root folder, so relative paths would break
+            let src_path = fs::canonicalize(&src_path)
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or(src_path);
+            let exe = env::current_exe()
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_else(|_| "zvault".to_string());
+            let command = vec![
+                exe,
+                "--systemd".to_string(),
+                "backup".to_string(),
+                src_path.clone(),
+                backup
+            ];
+            let units = [
+                (
+                    format!("zvault-{}.service", name),
+                    systemd_service_unit(&format!("zvault backup of {}", src_path), &command)
+                ),
+                (
+                    format!("zvault-{}.timer", name),
+                    systemd_timer_unit(&format!("Regular zvault backup of {}", src_path), &schedule)
+                )
+            ];
+            if let Some(output_dir) = output_dir {
+                for &(ref file, ref content) in &units {
+                    let path = Path::new(&output_dir).join(file);
+                    checked!(
+                        File::create(&path).and_then(|mut f| f.write_all(content.as_bytes())),
+                        "write unit file",
+                        ErrorCode::SystemdRun
+                    );
+                    info!("Created {:?}", path);
+                }
+                info!("Enable the timer with: systemctl enable --now zvault-{}.timer", name);
+            } else {
+                for &(ref file, ref content) in &units {
+                    println!("# {}", file);
+                    println!("{}", content);
+                }
+            }
+        }
+        Arguments::ChangePassphrase { repo_path } => {
+            let keys_path = RepositoryLayout::new(repo_path).keys_path();
+            let passphrase = checked!(
+                read_passphrase("New passphrase (empty to remove): "),
+                "read passphrase",
+                ErrorCode::SaveKey
+            );
+            let repeated = checked!(
+                read_passphrase("Repeat passphrase: "),
+                "read passphrase",
+                ErrorCode::SaveKey
+            );
+            if passphrase != repeated {
+                error!("The passphrases do not match");
+                return Err(ErrorCode::InvalidArgs);
+            }
+            let passphrase = if passphrase.is_empty() {
+                None
+            } else {
+                Some(&passphrase as &str)
+            };
+            let count = checked!(
+                Crypto::change_passphrase(keys_path, passphrase),
+                "change passphrase",
+                ErrorCode::SaveKey
+            );
+            if count == 0 {
+                warn!("The repository does not contain any secret keys");
+            } else if passphrase.is_some() {
+                info!("Protected {} secret keys with the new passphrase", count);
+            } else {
+                info!("Removed the passphrase from {} secret keys", count);
+            }
+        }
         Arguments::AlgoTest {
             bundle_size,
             chunker,
@@ -1123,6 +3436,21 @@ pub fn run() -> Result<(), ErrorCode> {
         } => {
             algotest::run(&file, bundle_size, chunker, compression, encrypt, hash);
         }
+        Arguments::Bench {
+            path,
+            bundle_size,
+            chunkers,
+            compressions,
+            hashes,
+            encrypt,
+            json
+        } => {
+            checked!(
+                bench::run(&path, bundle_size, &chunkers, &compressions, &hashes, encrypt, json),
+                "run benchmark",
+                ErrorCode::BenchRun
+            );
+        }
     }
     Ok(())
 }
@@ -9,9 +9,11 @@ extern crate rmp_serde;
 #[macro_use]
 extern crate serde_utils;
 extern crate squash_sys as squash;
+extern crate zstd;
 extern crate blake2_rfc as blake2;
 extern crate murmurhash3;
 extern crate serde_yaml;
+extern crate serde_json;
 #[macro_use]
 extern crate quick_error;
 extern crate chrono;
@@ -27,12 +29,15 @@ extern crate filetime;
 extern crate regex;
 #[macro_use]
 extern crate lazy_static;
+#[cfg(unix)]
 extern crate fuse;
 extern crate rand;
 extern crate time;
+#[cfg(unix)]
 extern crate xattr;
 extern crate crossbeam;
 extern crate pbr;
+#[cfg(unix)]
 extern crate users;
 extern crate libc;
 extern crate tar;
@@ -44,6 +49,7 @@ mod bundledb;
 mod repository;
 mod cli;
 mod prelude;
+#[cfg(unix)]
 mod mount;
 mod chunker;
 
@@ -52,6 +58,9 @@ use std::process::exit;
 fn main() {
     match cli::run() {
         Ok(()) => exit(0),
-        Err(code) => exit(code.code()),
+        Err(code) => {
+            cli::report_error(&code);
+            exit(code.code())
+        }
     }
 }
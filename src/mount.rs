@@ -1,7 +1,9 @@
 use prelude::*;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io;
 use std::collections::HashMap;
 use std::cell::RefCell;
 use std::rc::Rc;
@@ -9,11 +11,19 @@ use std::mem;
 use std::cmp::min;
 
 use fuse;
+use serde_yaml;
 use users::{self, Users, Groups};
 use time::Timespec;
 use libc;
 
 
+// Error for missing extended attributes, Linux uses ENODATA for it
+#[cfg(target_os = "linux")]
+const ENOATTR: libc::c_int = libc::ENODATA;
+#[cfg(not(target_os = "linux"))]
+const ENOATTR: libc::c_int = libc::ENOATTR;
+
+
 macro_rules! fuse_try(
     ($val:expr, $reply:expr) => {
         match $val {
@@ -72,6 +82,8 @@ fn convert_file_type(kind: FileType) -> fuse::FileType {
         FileType::BlockDevice => fuse::FileType::BlockDevice,
         FileType::CharDevice => fuse::FileType::CharDevice,
         FileType::NamedPipe => fuse::FileType::NamedPipe,
+        // Sockets are not part of the filesystem as this version of fuse can not represent them
+        FileType::Socket => unreachable!(),
     }
 }
 
@@ -83,6 +95,12 @@ pub struct FuseInode {
     parent: Option<FuseInodeRef>,
     children: HashMap<String, FuseInodeRef>,
     chunks: Option<ChunkList>,
+    // Bundle whose chunks are added as children when this directory of a raw mount is accessed
+    bundle: Option<BundleId>,
+    // Chunk of a bundle that is the content of this file in a raw mount
+    bundle_chunk: Option<(BundleId, usize)>,
+    // Detects sequential reads of this file to prefetch the next bundle
+    read_ahead: ReadAhead,
     name_cache: Rc<users::UsersCache>,
     user_names: Rc<HashMap<u32, String>>,
     group_names: Rc<HashMap<u32, String>>
@@ -109,7 +127,7 @@ impl FuseInode {
             atime: Timespec::new(self.inode.timestamp, 0),
             mtime: Timespec::new(self.inode.timestamp, 0),
             ctime: Timespec::new(0, 0),
-            crtime: Timespec::new(0, 0),
+            crtime: Timespec::new(self.inode.birth_time.unwrap_or(0), 0),
             kind: convert_file_type(self.inode.file_type),
             perm: self.inode.mode as u16,
             nlink: 1,
@@ -117,9 +135,9 @@ impl FuseInode {
             gid: gid,
             rdev: self.inode.device.map_or(
                 0,
-                |(major, minor)| (major << 8) + minor
+                |(major, minor)| make_device(major, minor) as u32
             ),
-            flags: 0
+            flags: self.inode.flags.unwrap_or(0)
         }
     }
 
@@ -148,6 +166,146 @@ impl FuseInode {
 }
 
 
+quick_error!{
+    #[derive(Debug)]
+    pub enum MountFileError {
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            description("IO error")
+            display("Mount file error: IO error\n\tcaused by: {}", err)
+        }
+        Yaml(err: serde_yaml::Error) {
+            from()
+            cause(err)
+            description("Yaml format error")
+            display("Mount file error: yaml format error\n\tcaused by: {}", err)
+        }
+    }
+}
+
+
+/// Describes an active mount so that other commands can find the backup behind its paths
+///
+/// The paths are stored as raw bytes so that paths that are not valid UTF-8 are kept intact.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct MountFile {
+    pub processid: usize,
+    pub mount_point: msgpack::Bytes,
+    pub repo_path: msgpack::Bytes,
+    pub backup_name: Option<String>,
+    pub inode: Option<String>
+}
+serde_impl!(MountFile(String) {
+    processid: usize => "processid",
+    mount_point: msgpack::Bytes => "mount_point",
+    repo_path: msgpack::Bytes => "repo_path",
+    backup_name: Option<String> => "backup_name",
+    inode: Option<String> => "inode"
+});
+
+impl MountFile {
+    pub fn new(
+        processid: usize,
+        mount_point: &Path,
+        repo_path: &Path,
+        backup_name: Option<String>,
+        inode: Option<String>,
+    ) -> Self {
+        MountFile {
+            processid: processid,
+            mount_point: path_to_bytes(mount_point).into_owned().into(),
+            repo_path: path_to_bytes(repo_path).into_owned().into(),
+            backup_name: backup_name,
+            inode: inode
+        }
+    }
+
+    #[inline]
+    pub fn mount_point(&self) -> PathBuf {
+        path_from_bytes(&self.mount_point)
+    }
+
+    #[inline]
+    pub fn repo_path(&self) -> PathBuf {
+        path_from_bytes(&self.repo_path)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, MountFileError> {
+        let f = try!(File::open(path));
+        Ok(try!(serde_yaml::from_reader(f)))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), MountFileError> {
+        let mut f = try!(File::create(path));
+        Ok(try!(serde_yaml::to_writer(&mut f, &self)))
+    }
+
+    /// Checks whether the process that mounted the filesystem is still running
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        process_exists(self.processid)
+    }
+
+    /// Finds the active mount in `folder` that contains `path`
+    ///
+    /// Returns the mount together with the path relative to its mount point.
+    pub fn find<P: AsRef<Path>>(folder: &Path, path: P) -> Option<(Self, PathBuf)> {
+        let path = path.as_ref();
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        for entry in fs::read_dir(folder).ok().into_iter().flat_map(|entries| entries) {
+            let mount = match entry.ok().and_then(|entry| Self::load(entry.path()).ok()) {
+                Some(mount) => mount,
+                None => continue,
+            };
+            if !mount.is_active() {
+                continue;
+            }
+            if let Ok(rel_path) = path.strip_prefix(mount.mount_point()).map(Path::to_path_buf) {
+                return Some((mount, rel_path));
+            }
+        }
+        None
+    }
+
+    /// Translates a path relative to the mount point into a backup name and a subpath
+    pub fn resolve(&self, rel_path: &Path) -> Option<(String, Option<String>)> {
+        let layout = RepositoryLayout::new(self.repo_path());
+        let mut parts = rel_path.iter().map(|part| part.to_string_lossy().to_string());
+        let backup_name = match self.backup_name {
+            Some(ref name) if !layout.backups_path().join(name).is_dir() => name.clone(),
+            ref folder => {
+                // The backups are mounted as a directory tree, the backup name ends where the
+                // first backup file is found
+                let mut name = folder.clone().unwrap_or_default();
+                loop {
+                    let part = match parts.next() {
+                        Some(part) => part,
+                        None => return None,
+                    };
+                    if !name.is_empty() {
+                        name.push('/');
+                    }
+                    name.push_str(&part);
+                    if layout.backup_path(&name).exists() {
+                        break;
+                    }
+                }
+                name
+            }
+        };
+        let mut inode = self.inode.clone().unwrap_or_default();
+        for part in parts {
+            if !inode.ends_with('/') {
+                inode.push('/');
+            }
+            inode.push_str(&part);
+        }
+        Some((backup_name, if inode.is_empty() { None } else { Some(inode) }))
+    }
+}
+
+
 pub struct FuseFilesystem<'a> {
     next_id: u64,
     repository: &'a mut Repository,
@@ -156,6 +314,7 @@ pub struct FuseFilesystem<'a> {
 
 impl<'a> FuseFilesystem<'a> {
     pub fn new(repository: &'a mut Repository) -> Result<Self, RepositoryError> {
+        repository.record_bundle_access();
         Ok(FuseFilesystem {
             next_id: 1,
             repository: repository,
@@ -215,6 +374,56 @@ impl<'a> FuseFilesystem<'a> {
         Ok(fs)
     }
 
+    /// Creates a filesystem that exposes the internal structures of the repository for debugging
+    ///
+    /// The chunks of all bundles are files in `/bundles/<id>/chunks/<n>` and contain the
+    /// decrypted and decompressed data. The backup files are readable as `/backups/<name>.yaml`.
+    pub fn from_raw(repository: &'a mut Repository) -> Result<Self, RepositoryError> {
+        let backups = try!(repository.get_all_backups());
+        let mut bundles: Vec<_> = repository
+            .list_bundles()
+            .into_iter()
+            .map(|bundle| bundle.id.clone())
+            .collect();
+        bundles.sort();
+        let mut fs = try!(FuseFilesystem::new(repository));
+        let root = fs.add_virtual_directory("".to_string(), None);
+        let bundles_dir = fs.add_virtual_directory("bundles".to_string(), Some(root.clone()));
+        for bundle in bundles {
+            let dir = fs.add_virtual_directory(bundle.to_string(), Some(bundles_dir.clone()));
+            let chunks_dir = fs.add_virtual_directory("chunks".to_string(), Some(dir));
+            // The chunk list is only loaded when the directory is accessed
+            chunks_dir.borrow_mut().bundle = Some(bundle);
+        }
+        let backups_dir = fs.add_virtual_directory("backups".to_string(), Some(root));
+        for (name, backup) in backups {
+            let mut parts: Vec<_> = name.split('/').collect();
+            let file_name = format!("{}.yaml", parts.pop().unwrap());
+            let mut parent = backups_dir.clone();
+            for part in parts {
+                parent = match fs.get_child(&parent, part).unwrap() {
+                    Some(child) => child,
+                    None => fs.add_virtual_directory(part.to_string(), Some(parent)),
+                };
+            }
+            let data = backup.to_yaml().into_bytes();
+            fs.add_inode(
+                Inode {
+                    name: file_name,
+                    size: data.len() as u64,
+                    mode: 0o444,
+                    timestamp: backup.timestamp,
+                    data: Some(FileData::Inline(data.into())),
+                    ..Default::default()
+                },
+                Some(parent),
+                HashMap::default(),
+                HashMap::default()
+            );
+        }
+        Ok(fs)
+    }
+
     pub fn add_virtual_directory(
         &mut self,
         name: String,
@@ -244,6 +453,9 @@ impl<'a> FuseFilesystem<'a> {
             num: self.next_id,
             parent: parent.clone(),
             chunks: None,
+            bundle: None,
+            bundle_chunk: None,
+            read_ahead: ReadAhead::default(),
             children: HashMap::new(),
             user_names: Rc::new(user_names),
             group_names: Rc::new(group_names),
@@ -281,18 +493,26 @@ impl<'a> FuseFilesystem<'a> {
         parent: &FuseInodeRef,
         name: &str,
     ) -> Result<Option<FuseInodeRef>, RepositoryError> {
+        try!(self.fetch_bundle_chunks(parent));
         let mut parent_mut = parent.borrow_mut();
         if let Some(child) = parent_mut.children.get(name) {
             return Ok(Some(child.clone()));
         }
         let child;
         if let Some(chunks) = parent_mut.inode.children.as_ref().and_then(|c| c.get(name)) {
+            let inode = try!(self.repository.get_inode(chunks));
+            if inode.file_type == FileType::Socket {
+                return Ok(None);
+            }
             child = Rc::new(RefCell::new(FuseInode {
                 num: self.next_id,
-                inode: try!(self.repository.get_inode(chunks)),
+                inode: inode,
                 parent: Some(parent.clone()),
                 children: HashMap::new(),
                 chunks: None,
+                bundle: None,
+                bundle_chunk: None,
+                read_ahead: ReadAhead::default(),
                 user_names: parent_mut.user_names.clone(),
                 group_names: parent_mut.group_names.clone(),
                 name_cache: parent_mut.name_cache.clone()
@@ -307,18 +527,26 @@ impl<'a> FuseFilesystem<'a> {
     }
 
     pub fn fetch_children(&mut self, parent: &FuseInodeRef) -> Result<(), RepositoryError> {
+        try!(self.fetch_bundle_chunks(parent));
         let mut parent_mut = parent.borrow_mut();
         let mut parent_children = HashMap::new();
         mem::swap(&mut parent_children, &mut parent_mut.children);
         if let Some(ref children) = parent_mut.inode.children {
             for (name, chunks) in children {
-                if !parent_mut.children.contains_key(name) {
+                if !parent_children.contains_key(name) {
+                    let inode = try!(self.repository.get_inode(chunks));
+                    if inode.file_type == FileType::Socket {
+                        continue;
+                    }
                     let child = Rc::new(RefCell::new(FuseInode {
                         num: self.next_id,
-                        inode: try!(self.repository.get_inode(chunks)),
+                        inode: inode,
                         parent: Some(parent.clone()),
                         children: HashMap::new(),
                         chunks: None,
+                        bundle: None,
+                        bundle_chunk: None,
+                        read_ahead: ReadAhead::default(),
                         user_names: parent_mut.user_names.clone(),
                         group_names: parent_mut.group_names.clone(),
                         name_cache: parent_mut.name_cache.clone()
@@ -333,8 +561,35 @@ impl<'a> FuseFilesystem<'a> {
         Ok(())
     }
 
+    /// Adds the chunks of the bundle as files to the chunk directory of a raw mount
+    fn fetch_bundle_chunks(&mut self, dir: &FuseInodeRef) -> Result<(), RepositoryError> {
+        let bundle = match dir.borrow_mut().bundle.take() {
+            Some(bundle) => bundle,
+            None => return Ok(()),
+        };
+        let chunks = try!(self.repository.get_bundle_chunk_list(&bundle));
+        for (i, &(_hash, len)) in chunks.iter().enumerate() {
+            let chunk = self.add_inode(
+                Inode {
+                    name: i.to_string(),
+                    size: len as u64,
+                    mode: 0o444,
+                    ..Default::default()
+                },
+                Some(dir.clone()),
+                HashMap::default(),
+                HashMap::default()
+            );
+            chunk.borrow_mut().bundle_chunk = Some((bundle.clone(), i));
+        }
+        Ok(())
+    }
+
     pub fn fetch_chunks(&mut self, inode: &FuseInodeRef) -> Result<(), RepositoryError> {
         let mut inode = inode.borrow_mut();
+        if inode.chunks.is_some() {
+            return Ok(());
+        }
         let mut chunks = None;
         match inode.inode.data {
             None |
@@ -350,6 +605,31 @@ impl<'a> FuseFilesystem<'a> {
         inode.chunks = chunks;
         Ok(())
     }
+
+    /// Loads all inodes and chunk lists of the mounted tree into memory
+    ///
+    /// Directory traversals then no longer need to read any meta bundles.
+    pub fn preload_meta(&mut self) -> Result<usize, RepositoryError> {
+        let mut count = 0;
+        let mut todo: Vec<_> = self.inodes
+            .values()
+            .filter(|inode| inode.borrow().parent.is_none())
+            .cloned()
+            .collect();
+        while let Some(inode) = todo.pop() {
+            count += 1;
+            let file_type = inode.borrow().inode.file_type;
+            match file_type {
+                FileType::Directory => {
+                    try!(self.fetch_children(&inode));
+                    todo.extend(inode.borrow().children.values().cloned());
+                }
+                FileType::File => try!(self.fetch_chunks(&inode)),
+                _ => (),
+            }
+        }
+        Ok(count)
+    }
 }
 
 
@@ -366,6 +646,10 @@ impl<'a> fuse::Filesystem for FuseFilesystem<'a> {
 
     fn destroy(&mut self, _req: &fuse::Request) {
         info!("destroy");
+        match self.repository.save_mount_hints() {
+            Ok(count) => debug!("Saved {} bundles as mount hints", count),
+            Err(err) => warn!("Failed to save mount hints\n\tcaused by: {}", err),
+        }
     }
 
     /// Forget about an inode
@@ -532,12 +816,20 @@ impl<'a> fuse::Filesystem for FuseFilesystem<'a> {
         _req: &fuse::Request,
         ino: u64,
         _fh: u64,
-        mut offset: u64,
-        mut size: u32,
+        offset: u64,
+        size: u32,
         reply: fuse::ReplyData,
     ) {
         let inode = inode!(self, ino, reply);
-        let inode = inode.borrow();
+        let mut inode = inode.borrow_mut();
+        let inode = &mut *inode;
+        if let Some((ref bundle, chunk)) = inode.bundle_chunk {
+            let data = fuse_try!(self.repository.get_bundle_chunk(bundle, chunk), reply);
+            return reply.data(
+                &data[min(offset as usize, data.len())..
+                          min(offset as usize + size as usize, data.len())]
+            );
+        }
         match inode.inode.data {
             None => return reply.data(&[]),
             Some(FileData::Inline(ref data)) => {
@@ -549,26 +841,15 @@ impl<'a> fuse::Filesystem for FuseFilesystem<'a> {
             _ => (),
         }
         if let Some(ref chunks) = inode.chunks {
+            fuse_try!(
+                self.repository.read_ahead(&mut inode.read_ahead, chunks, offset, size as u64),
+                reply
+            );
             let mut data = Vec::with_capacity(size as usize);
-            for &(hash, len) in chunks.iter() {
-                if len as u64 <= offset {
-                    offset -= len as u64;
-                    continue;
-                }
-                let chunk = match fuse_try!(self.repository.get_chunk(hash), reply) {
-                    Some(chunk) => chunk,
-                    None => return reply.error(libc::EIO),
-                };
-                assert_eq!(chunk.len() as u32, len);
-                data.extend_from_slice(
-                    &chunk[offset as usize..min(offset as usize + size as usize, len as usize)]
-                );
-                if len - offset as u32 >= size {
-                    break;
-                }
-                size -= len - offset as u32;
-                offset = 0;
-            }
+            fuse_try!(
+                self.repository.get_stream_range(chunks, offset, size as u64, &mut data),
+                reply
+            );
             reply.data(&data)
         } else {
             reply.error(libc::EBADF)
@@ -745,7 +1026,7 @@ impl<'a> fuse::Filesystem for FuseFilesystem<'a> {
                 reply.error(libc::ERANGE);
             }
         } else {
-            reply.error(libc::ENODATA);
+            reply.error(ENOATTR);
         }
     }
 
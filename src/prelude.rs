@@ -1,12 +1,25 @@
 pub use util::*;
 pub use bundledb::{BundleReader, BundleMode, BundleWriter, BundleInfo, BundleId, BundleDbError,
-                   BundleDb, BundleWriterError, StoredBundle};
+                   BundleDb, BundleWriterError, StorageClass, StoredBundle, UploadVerification,
+                   serve_codec_requests, CODEC_HELPER_COMMAND};
 pub use chunker::{ChunkerType, Chunker, ChunkerStatus, ChunkerError};
 pub use repository::{Repository, Backup, Config, RepositoryError, RepositoryInfo, Inode, FileType,
                      IntegrityError, BackupFileError, BackupError, BackupOptions, BundleAnalysis,
-                     FileData, DiffType, InodeError, RepositoryLayout, Location};
+                     FileData, DiffType, InodeError, RepositoryLayout, Location, ExtensionStats,
+                     verify_manifest, RestoreOptions,
+                     BackupPathStatus, HealthConfig,
+                     RestorePlan, SpecialFiles, BackupOrder, remote_reachable,
+                     check_remote, load_remotes, RemoteCheck, MAX_CLOCK_SKEW,
+                     quarantine_file, FileKind, check_file, TarSource,
+                     RepositoryTemplates, ReclaimReport, BackupFileProblem, BackupFileReport,
+                     HostAnalysis, OriginUsage, BackupTimings, SourceInfo,
+                     BackupRules, BackupCatalog, FileChunkAnalysis,
+                     copy_seed_files, ReadAhead};
+#[cfg(unix)]
+pub use repository::list_backup_paths;
 pub use index::{Index, IndexError};
-pub use mount::FuseFilesystem;
+#[cfg(unix)]
+pub use mount::{FuseFilesystem, MountFile};
 
 pub use serde::{Serialize, Deserialize};
 
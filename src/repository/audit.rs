@@ -0,0 +1,95 @@
+use prelude::*;
+
+use serde_json;
+use chrono::prelude::*;
+
+use std::path::Path;
+use std::io::{self, BufRead, BufReader, Write};
+use std::fs::{File, OpenOptions};
+
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum AuditLogError {
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            description("IO error")
+            display("Audit log error: IO error\n\tcaused by: {}", err)
+        }
+        Json(err: serde_json::Error, line: usize) {
+            cause(err)
+            description("Json format error")
+            display("Audit log error: json format error in line {}\n\tcaused by: {}", line, err)
+        }
+        Encode(err: serde_json::Error) {
+            from()
+            cause(err)
+            description("Failed to encode entry")
+            display("Audit log error: failed to encode entry\n\tcaused by: {}", err)
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Default)]
+pub struct AuditEntry {
+    pub date: i64,
+    pub hostname: String,
+    pub user: String,
+    pub action: String,
+    pub details: String
+}
+serde_impl!(AuditEntry(String) {
+    date: i64 => "date",
+    hostname: String => "hostname",
+    user: String => "user",
+    action: String => "action",
+    details: String => "details"
+});
+
+impl AuditEntry {
+    pub fn new(action: &str, details: String) -> Self {
+        AuditEntry {
+            date: Utc::now().timestamp(),
+            hostname: get_hostname().unwrap_or_default(),
+            user: get_username(),
+            action: action.to_string(),
+            details: details
+        }
+    }
+}
+
+
+pub struct AuditLog;
+
+impl AuditLog {
+    /// Appends the entry as a single line, existing lines are never modified
+    pub fn append<P: AsRef<Path>>(path: P, entry: &AuditEntry) -> Result<(), AuditLogError> {
+        let mut line = try!(serde_json::to_vec(entry));
+        line.push(b'\n');
+        let mut file = try!(OpenOptions::new().create(true).append(true).open(path));
+        // Writing the line at once so that concurrent writers do not interleave
+        try!(file.write_all(&line));
+        Ok(())
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Vec<AuditEntry>, AuditLogError> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        let file = BufReader::new(try!(File::open(path)));
+        let mut entries = vec![];
+        for (i, line) in file.lines().enumerate() {
+            let line = try!(line);
+            if line.trim().is_empty() {
+                continue;
+            }
+            entries.push(try!(serde_json::from_str(&line).map_err(|err| {
+                AuditLogError::Json(err, i + 1)
+            })));
+        }
+        Ok(entries)
+    }
+}
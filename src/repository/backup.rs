@@ -1,12 +1,22 @@
 use prelude::*;
 
 use std::fs;
+use std::io;
+use std::mem;
 use std::path::{self, Path, PathBuf};
-use std::collections::{HashMap, BTreeMap, VecDeque};
-use std::os::linux::fs::MetadataExt;
+use std::collections::{HashMap, HashSet, BTreeMap, VecDeque};
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+use std::time::Instant;
+
+use super::basic_io::PhaseTimes;
+#[cfg(unix)]
+use super::scanner::{DirScanner, SCAN_THREADS};
 
 use chrono::prelude::*;
 use regex::RegexSet;
+use rand;
+#[cfg(unix)]
 use users::{self, Users, Groups};
 
 
@@ -22,20 +32,382 @@ quick_error!{
             description("The root of a backup can not be removed")
             display("Backup error: the root of a backup can not be removed")
         }
+        SpecialFile(path: PathBuf) {
+            description("Special file not allowed")
+            display("Backup error: refusing to handle special file {:?}", path)
+        }
+        TargetExists(path: PathBuf) {
+            description("Restore target already exists")
+            display("Backup error: the restore target {:?} already exists", path)
+        }
+        Interrupted {
+            description("Backup interrupted")
+            display("Backup error: the backup has been interrupted by SIGTERM")
+        }
+    }
+}
+
+
+/// Returns the lowercase extension used to group file statistics
+///
+/// Files without a short alphanumeric extension are grouped under an empty name.
+pub fn file_extension(name: &str) -> String {
+    match Path::new(name).extension().and_then(|ext| ext.to_str()) {
+        Some(ext) if ext.len() <= 10 && ext.chars().all(|c| c.is_alphanumeric()) => {
+            ext.to_lowercase()
+        }
+        _ => String::new(),
+    }
+}
+
+
+/// How devices, named pipes and sockets are handled in backups and restores
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialFiles {
+    /// Store and restore their metadata
+    Keep,
+    /// Leave them out
+    Skip,
+    /// Abort with an error
+    Fail
+}
+
+impl SpecialFiles {
+    pub fn from_string(name: &str) -> Result<Self, &'static str> {
+        match name {
+            "keep" => Ok(SpecialFiles::Keep),
+            "skip" => Ok(SpecialFiles::Skip),
+            "fail" => Ok(SpecialFiles::Fail),
+            _ => Err("Unsupported special file policy"),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SpecialFiles::Keep => "keep",
+            SpecialFiles::Skip => "skip",
+            SpecialFiles::Fail => "fail",
+        }
+    }
+}
+
+impl Default for SpecialFiles {
+    fn default() -> Self {
+        SpecialFiles::Keep
+    }
+}
+
+
+/// The order in which the entries of each directory are backed up
+///
+/// If a backup is interrupted, the entries that have been processed first are the ones that
+/// have been saved, so ordering by modification time saves the most recently changed data first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackupOrder {
+    /// Sorted by name
+    Path,
+    /// Most recently modified first
+    Mtime,
+    /// Smallest first, so that as many files as possible are saved early
+    Size
+}
+
+impl BackupOrder {
+    pub fn from_string(name: &str) -> Result<Self, &'static str> {
+        match name {
+            "path" => Ok(BackupOrder::Path),
+            "mtime" => Ok(BackupOrder::Mtime),
+            "size" => Ok(BackupOrder::Size),
+            _ => Err("Unsupported backup order"),
+        }
+    }
+
+    /// Sorts the entries of a directory, entries that compare equal are sorted by name
+    #[cfg(unix)]
+    fn sort(&self, entries: &mut [(fs::DirEntry, fs::Metadata)]) {
+        match *self {
+            BackupOrder::Path => entries.sort_by_key(|e| e.0.file_name()),
+            BackupOrder::Mtime => {
+                entries.sort_by(|a, b| {
+                    (b.1.mtime(), b.1.mtime_nsec())
+                        .cmp(&(a.1.mtime(), a.1.mtime_nsec()))
+                        .then_with(|| a.0.file_name().cmp(&b.0.file_name()))
+                })
+            }
+            BackupOrder::Size => {
+                entries.sort_by(|a, b| {
+                    a.1.len().cmp(&b.1.len()).then_with(
+                        || a.0.file_name().cmp(&b.0.file_name())
+                    )
+                })
+            }
+        }
     }
 }
 
+impl Default for BackupOrder {
+    fn default() -> Self {
+        BackupOrder::Path
+    }
+}
+
+
+#[cfg(unix)]
+fn is_special_file(meta: &fs::Metadata) -> bool {
+    let file_type = meta.file_type();
+    !file_type.is_file() && !file_type.is_dir() && !file_type.is_symlink()
+}
+
+/// Whether the directory is the local folder or the remote storage of a zvault repository
+#[cfg(unix)]
+fn is_repository_dir(path: &Path) -> bool {
+    let local = RepositoryLayout::new(path);
+    if local.bundle_map_path().is_file() && local.config_path().is_file() {
+        return true;
+    }
+    // The folder that the `remote` link of a repository points to
+    path.join("locks").is_dir() && path.join("bundles").is_dir() && path.join("backups").is_dir()
+}
+
 
 pub struct BackupOptions {
     pub same_device: bool,
-    pub excludes: Option<RegexSet>
+    pub excludes: Option<RegexSet>,
+    /// The patterns that `excludes` has been built from, recorded in the backup
+    pub exclude_patterns: Vec<String>,
+    pub dereference: bool,
+    pub dereference_args: bool,
+    pub special_files: SpecialFiles,
+    pub order: BackupOrder,
+    /// Name of the snapshot that is backed up, recorded in the backup
+    pub snapshot: Option<String>,
+    /// Number of threads that read directories ahead of the backup, 0 for the default
+    pub scan_threads: usize,
+    /// Directories as (device, inode) that are never backed up, i.e. the repository itself
+    pub skip_dirs: Vec<(u64, u64)>,
+    /// Whether other repositories and remote storages found in the source are skipped
+    pub skip_repositories: bool
+}
+
+
+/// The reason why a path would be included in or left out of a backup
+pub enum BackupPathStatus {
+    Included,
+    /// Excluded by the exclude pattern with the given index
+    Excluded(usize),
+    OtherDevice,
+    DirectoryLoop,
+    /// A device, named pipe or socket that is skipped by the special file policy
+    SpecialFile,
+    /// The local folder or remote storage of a repository
+    Repository
+}
+
+impl BackupOptions {
+    /// The rules that are recorded in backups created with these options
+    pub fn rules(&self) -> BackupRules {
+        BackupRules {
+            excludes: self.exclude_patterns.clone(),
+            same_device: self.same_device,
+            dereference: self.dereference,
+            dereference_args: self.dereference_args,
+            special_files: self.special_files.name().to_string()
+        }
+    }
+
+    #[cfg(unix)]
+    fn path_status(
+        &self,
+        path: &Path,
+        meta: &fs::Metadata,
+        parent_dev: u64,
+        ancestors: &[(u64, u64)],
+    ) -> BackupPathStatus {
+        if self.same_device && meta.dev() != parent_dev {
+            return BackupPathStatus::OtherDevice;
+        }
+        if meta.is_dir() && ancestors.contains(&(meta.dev(), meta.ino())) {
+            return BackupPathStatus::DirectoryLoop;
+        }
+        if self.special_files == SpecialFiles::Skip && is_special_file(meta) {
+            return BackupPathStatus::SpecialFile;
+        }
+        if let Some(ref excludes) = self.excludes {
+            if let Some(index) = excludes.matches(&path.to_string_lossy()).iter().next() {
+                return BackupPathStatus::Excluded(index);
+            }
+        }
+        if meta.is_dir() {
+            if self.skip_dirs.contains(&(meta.dev(), meta.ino())) ||
+                (self.skip_repositories && is_repository_dir(path))
+            {
+                return BackupPathStatus::Repository;
+            }
+        }
+        BackupPathStatus::Included
+    }
+}
+
+
+#[cfg(unix)]
+fn list_backup_paths_recurse<F: FnMut(&Path, BackupPathStatus)>(
+    path: &Path,
+    options: &BackupOptions,
+    callback: &mut F,
+    ancestors: &[(u64, u64)],
+) -> Result<(), io::Error> {
+    let dereference = options.dereference || (options.dereference_args && ancestors.is_empty());
+    let meta = try!(if dereference {
+        fs::metadata(path)
+    } else {
+        fs::symlink_metadata(path)
+    });
+    callback(path, BackupPathStatus::Included);
+    if !meta.is_dir() {
+        return Ok(());
+    }
+    let mut ancestors = ancestors.to_vec();
+    ancestors.push((meta.dev(), meta.ino()));
+    let mut children = vec![];
+    for child in try!(fs::read_dir(path)) {
+        let child = try!(child);
+        let child_meta = if options.dereference {
+            fs::metadata(child.path()).or_else(|_| child.metadata())
+        } else {
+            child.metadata()
+        };
+        match child_meta {
+            Ok(child_meta) => children.push((child, child_meta)),
+            Err(err) => warn!("Failed to read {:?}\n\tcaused by: {}", child.path(), err),
+        }
+    }
+    options.order.sort(&mut children);
+    for (child, child_meta) in children {
+        let child_path = child.path();
+        match options.path_status(&child_path, &child_meta, meta.dev(), &ancestors) {
+            BackupPathStatus::Included => {
+                let res = list_backup_paths_recurse(&child_path, options, callback, &ancestors);
+                if let Err(err) = res {
+                    warn!("Failed to read {:?}\n\tcaused by: {}", child_path, err);
+                }
+            }
+            status => callback(&child_path, status),
+        }
+    }
+    Ok(())
+}
+
+/// Walks the source path like a backup would and reports for every path whether it would be
+/// included in the backup
+///
+/// Excluded directories are reported but not descended into.
+#[cfg(unix)]
+pub fn list_backup_paths<P: AsRef<Path>, F: FnMut(&Path, BackupPathStatus)>(
+    path: P,
+    options: &BackupOptions,
+    mut callback: F,
+) -> Result<(), io::Error> {
+    list_backup_paths_recurse(path.as_ref(), options, &mut callback, &[])
+}
+
+
+pub struct RestoreOptions {
+    pub delta: bool,
+    pub special_files: SpecialFiles,
+    /// Restore into a staging directory that is renamed to the target path on success
+    pub atomic: bool,
+    /// Earlier restore of the tree whose unchanged files are hardlinked instead of written
+    pub link_dest: Option<PathBuf>,
+    /// Set the recorded project (quota) IDs on the restored files and directories
+    pub project_ids: bool
+}
+
+
+/// Hardlinks `link_path` to `full_path` if it is a file with the same size and metadata as `inode`
+///
+/// Like with rsync, the contents are assumed to be equal if the size and the modification time
+/// match. As hardlinks share their metadata, also the mode and the owner have to match.
+#[cfg(unix)]
+fn link_unchanged_file(inode: &Inode, link_path: &Path, full_path: &Path) -> bool {
+    let meta = match fs::symlink_metadata(link_path) {
+        Ok(meta) => meta,
+        Err(_) => return false,
+    };
+    if !meta.is_file() || meta.len() != inode.size || meta.mtime() != inode.timestamp ||
+        meta.mode() & 0o7777 != inode.mode & 0o7777 || meta.uid() != inode.user ||
+        meta.gid() != inode.group
+    {
+        return false;
+    }
+    match fs::hard_link(link_path, full_path) {
+        Ok(()) => true,
+        Err(err) => {
+            // e.g. when the directories are on different filesystems
+            debug!("Failed to link {:?}, writing data instead: {}", link_path, err);
+            false
+        }
+    }
+}
+
+/// The mode and the owner of files can not be compared on other platforms, so nothing is linked
+#[cfg(not(unix))]
+fn link_unchanged_file(_inode: &Inode, _link_path: &Path, _full_path: &Path) -> bool {
+    false
+}
+
+
+/// Maps the user and group names recorded in a backup to the ids on this system
+#[cfg(unix)]
+struct OwnerMap(users::UsersCache);
+
+#[cfg(unix)]
+impl OwnerMap {
+    fn new() -> Self {
+        OwnerMap(users::UsersCache::new())
+    }
+
+    fn map(&self, backup: &Backup, inode: &mut Inode) {
+        if let Some(name) = backup.user_names.get(&inode.user) {
+            if let Some(user) = self.0.get_user_by_name(name) {
+                inode.user = user.uid();
+            }
+        }
+        if let Some(name) = backup.group_names.get(&inode.group) {
+            if let Some(group) = self.0.get_group_by_name(name) {
+                inode.group = group.gid();
+            }
+        }
+    }
+}
+
+/// Owners are not restored on other platforms, so they are left unchanged
+#[cfg(not(unix))]
+struct OwnerMap;
+
+#[cfg(not(unix))]
+impl OwnerMap {
+    fn new() -> Self {
+        OwnerMap
+    }
+
+    fn map(&self, _backup: &Backup, _inode: &mut Inode) {}
 }
 
 
 pub enum DiffType {
     Add,
     Mod,
-    Del
+    Del,
+    /// Moved from the given path, the contents are the same
+    Mov(PathBuf)
+}
+
+
+/// Contents of an entry that are compared to find moved entries
+#[derive(PartialEq, Eq, Hash)]
+enum MoveKey {
+    File(ChunkList),
+    Directory(BTreeMap<String, ChunkList>)
 }
 
 
@@ -72,25 +444,41 @@ impl Repository {
     pub fn save_backup(&mut self, backup: &Backup, name: &str) -> Result<(), RepositoryError> {
         try!(self.write_mode());
         let path = self.layout.backup_path(name);
+        let action = if path.exists() {
+            "backup-modify"
+        } else {
+            "backup-create"
+        };
         try!(fs::create_dir_all(path.parent().unwrap()));
-        Ok(try!(backup.save_to(
+        try!(backup.save_to(
             &self.crypto.lock().unwrap(),
             self.config.encryption.clone(),
             path
-        )))
+        ));
+        self.audit(action, name.to_string());
+        self.count_saved_backup(name, backup);
+        Ok(())
     }
 
-    pub fn delete_backup(&mut self, name: &str) -> Result<(), RepositoryError> {
+    /// Adds and removes tags of an existing backup and returns the new tags
+    pub fn tag_backup(
+        &mut self,
+        name: &str,
+        add: &[String],
+        remove: &[String],
+    ) -> Result<Vec<String>, RepositoryError> {
         try!(self.write_mode());
-        let mut path = self.layout.backup_path(name);
-        try!(fs::remove_file(&path));
-        loop {
-            path = path.parent().unwrap().to_owned();
-            if path == self.layout.backups_path() || fs::remove_dir(&path).is_err() {
-                break;
+        let _lock = try!(self.lock(false));
+        let mut backup = try!(self.get_backup(name));
+        backup.tags.retain(|tag| !remove.contains(tag));
+        for tag in add {
+            if !backup.tags.contains(tag) {
+                backup.tags.push(tag.clone());
             }
         }
-        Ok(())
+        backup.tags.sort();
+        try!(self.save_backup(&backup, name));
+        Ok(backup.tags)
     }
 
 
@@ -101,6 +489,7 @@ impl Repository {
         weekly: usize,
         monthly: usize,
         yearly: usize,
+        ignore_min_retention: bool,
         force: bool,
     ) -> Result<(), RepositoryError> {
         try!(self.write_mode());
@@ -165,14 +554,43 @@ impl Repository {
             );
         }
         let mut remove = Vec::new();
+        let mut kept = Vec::new();
+        let mut protected = Vec::new();
         println!("Removing the following backups");
         for (i, backup) in backups.into_iter().enumerate() {
-            if !keep.get(i) {
+            if keep.get(i) {
+                kept.push(backup.0);
+            } else if !ignore_min_retention &&
+                self.check_min_retention(&backup.0, &backup.2).is_err()
+            {
+                // Protected backups are skipped so that the others can still be removed
+                protected.push(backup.0);
+            } else {
                 println!("  - {}", backup.0);
                 remove.push(backup.0);
             }
         }
+        if !protected.is_empty() {
+            println!("Keeping the following backups protected by the minimal retention");
+            for name in &protected {
+                println!("  - {}", name);
+            }
+            kept.extend(protected);
+        }
         if force {
+            self.audit(
+                "prune",
+                format!(
+                    "prefix={}, daily={}, weekly={}, monthly={}, yearly={}, keep=[{}], remove=[{}]",
+                    prefix,
+                    daily,
+                    weekly,
+                    monthly,
+                    yearly,
+                    kept.join(", "),
+                    remove.join(", ")
+                )
+            );
             for name in remove {
                 try!(self.delete_backup(&name));
             }
@@ -185,27 +603,82 @@ impl Repository {
         backup: &Backup,
         inode: Inode,
         path: P,
+        options: &RestoreOptions,
+    ) -> Result<(), RepositoryError> {
+        let path = path.as_ref();
+        if !options.atomic {
+            return self.restore_inode_tree_at(backup, inode, path, options);
+        }
+        let name = match path.file_name() {
+            Some(name) if !path.exists() => name.to_string_lossy().to_string(),
+            _ => return Err(BackupError::TargetExists(path.to_path_buf()).into()),
+        };
+        let staging_name = format!("{}.partial-{:08x}", name, rand::random::<u32>());
+        let staging = path.with_file_name(staging_name);
+        try!(fs::create_dir(&staging));
+        if let Err(err) = self.restore_inode_tree_at(backup, inode, &staging, options) {
+            warn!("Leaving the partially restored tree in {:?}", staging);
+            return Err(err);
+        }
+        try!(fs::rename(&staging, path));
+        Ok(())
+    }
+
+    fn restore_inode_tree_at(
+        &mut self,
+        backup: &Backup,
+        inode: Inode,
+        path: &Path,
+        options: &RestoreOptions,
     ) -> Result<(), RepositoryError> {
         let _lock = try!(self.lock(false));
         let mut queue = VecDeque::new();
-        queue.push_back((path.as_ref().to_owned(), inode));
-        let cache = users::UsersCache::new();
+        queue.push_back((path.to_owned(), options.link_dest.clone(), inode));
+        let owners = OwnerMap::new();
+        let mut plan = RestorePlan::new();
         let mut is_root = true;
-        while let Some((path, mut inode)) = queue.pop_front() {
-            if inode.file_type != FileType::Directory || !is_root {
-                if let Some(name) = backup.user_names.get(&inode.user) {
-                    if let Some(user) = cache.get_user_by_name(name) {
-                        inode.user = user.uid();
+        let mut linked = 0;
+        while let Some((path, link_dir, mut inode)) = queue.pop_front() {
+            if inode.file_type.is_special() {
+                match options.special_files {
+                    SpecialFiles::Keep => (),
+                    SpecialFiles::Skip => {
+                        info!("Skipping {} {:?}", inode.file_type, path.join(&inode.name));
+                        continue;
+                    }
+                    SpecialFiles::Fail => {
+                        return Err(BackupError::SpecialFile(path.join(&inode.name)).into());
                     }
                 }
-                if let Some(name) = backup.group_names.get(&inode.group) {
-                    if let Some(group) = cache.get_group_by_name(name) {
-                        inode.group = group.gid();
+            }
+            if !options.project_ids {
+                inode.project_id = None;
+            }
+            if inode.file_type != FileType::Directory || !is_root {
+                owners.map(backup, &mut inode);
+                if inode.file_type == FileType::File {
+                    if let Some(ref link_dir) = link_dir {
+                        let link_path = link_dir.join(&inode.name);
+                        if link_unchanged_file(&inode, &link_path, &path.join(&inode.name)) {
+                            linked += 1;
+                            continue;
+                        }
                     }
                 }
-                try!(self.save_inode_at(&inode, &path));
+                let updated = options.delta &&
+                    try!(self.update_inode_at(&inode, &path.join(&inode.name)));
+                if !updated {
+                    try!(self.plan_inode_at(&inode, &path, &mut plan));
+                } else if inode.file_type == FileType::Directory {
+                    plan.add_directory(path.join(&inode.name), &inode);
+                }
             }
             if inode.file_type == FileType::Directory {
+                let link_dir = if is_root {
+                    link_dir
+                } else {
+                    link_dir.map(|dir| dir.join(&inode.name))
+                };
                 let path = if is_root {
                     path.to_path_buf()
                 } else {
@@ -213,14 +686,18 @@ impl Repository {
                 };
                 for chunks in inode.children.unwrap().values() {
                     let inode = try!(self.get_inode(chunks));
-                    queue.push_back((path.clone(), inode));
+                    queue.push_back((path.clone(), link_dir.clone(), inode));
                 }
             }
             is_root = false;
         }
-        Ok(())
+        if let Some(ref link_dest) = options.link_dest {
+            info!("Linked {} unchanged files from {:?}", linked, link_dest);
+        }
+        self.execute_restore_plan(plan)
     }
 
+    #[cfg(unix)]
     pub fn create_backup_recurse<P: AsRef<Path>>(
         &mut self,
         path: P,
@@ -228,9 +705,29 @@ impl Repository {
         options: &BackupOptions,
         backup: &mut Backup,
         failed_paths: &mut Vec<PathBuf>,
+        ancestors: &[(u64, u64)],
+        scanner: &mut DirScanner,
     ) -> Result<Inode, RepositoryError> {
         let path = path.as_ref();
-        let mut inode = try!(self.create_inode(path, reference));
+        if is_terminated() {
+            return Err(BackupError::Interrupted.into());
+        }
+        let dereference = options.dereference || (options.dereference_args && ancestors.is_empty());
+        let data_written = self.data_written;
+        let mut inode = try!(self.create_inode(path, reference, dereference));
+        if inode.file_type == FileType::File {
+            let changed = reference.map_or(true, |ref_inode| !ref_inode.is_same_meta_quick(&inode));
+            let stats = backup.extensions.entry(file_extension(&inode.name)).or_insert_with(
+                ExtensionStats::default
+            );
+            stats.file_count += 1;
+            stats.total_data_size += inode.size;
+            if changed {
+                stats.changed_data_size += inode.size;
+            }
+            stats.deduplicated_data_size += self.data_written.0 - data_written.0;
+            stats.encoded_data_size += self.data_written.1 - data_written.1;
+        }
         if !backup.user_names.contains_key(&inode.user) {
             if let Some(user) = users::get_user_by_uid(inode.user) {
                 backup.user_names.insert(
@@ -254,24 +751,44 @@ impl Repository {
         let mut meta_size = 0;
         inode.cum_size = inode.size;
         if inode.file_type == FileType::Directory {
+            notify_progress(&format!("Backing up {}", path.display()));
             inode.cum_dirs = 1;
             let mut children = BTreeMap::new();
-            let parent_dev = try!(path.metadata()).st_dev();
-            for ch in try!(fs::read_dir(path)) {
-                let child = try!(ch);
+            let meta = try!(path.metadata());
+            let parent_dev = meta.dev();
+            // Directories on the current path, following symlinks to them would cause a loop
+            let mut ancestors = ancestors.to_vec();
+            ancestors.push((meta.dev(), meta.ino()));
+            let mut entries = try!(scanner.take(path));
+            options.order.sort(&mut entries);
+            let mut included = Vec::with_capacity(entries.len());
+            for (child, child_meta) in entries {
                 let child_path = child.path();
-                if options.same_device {
-                    let child_dev = try!(child.metadata()).st_dev();
-                    if child_dev != parent_dev {
+                match options.path_status(&child_path, &child_meta, parent_dev, &ancestors) {
+                    BackupPathStatus::Included => (),
+                    BackupPathStatus::DirectoryLoop => {
+                        warn!("Skipping {:?} as it would create a directory loop", child_path);
                         continue;
                     }
-                }
-                if let Some(ref excludes) = options.excludes {
-                    let child_path_str = child_path.to_string_lossy();
-                    if excludes.is_match(&child_path_str) {
+                    BackupPathStatus::SpecialFile => {
+                        info!("Skipping special file {:?}", child_path);
                         continue;
                     }
+                    BackupPathStatus::Repository => {
+                        warn!("Skipping {:?} as it belongs to a zvault repository", child_path);
+                        continue;
+                    }
+                    _ => continue,
+                }
+                if options.special_files == SpecialFiles::Fail && is_special_file(&child_meta) {
+                    return Err(BackupError::SpecialFile(child_path).into());
+                }
+                if child_meta.is_dir() {
+                    scanner.request(child_path.clone());
                 }
+                included.push((child, child_path));
+            }
+            for (child, child_path) in included {
                 let name = child.file_name().to_string_lossy().to_string();
                 let ref_child = reference
                     .as_ref()
@@ -283,13 +800,16 @@ impl Repository {
                     ref_child.as_ref(),
                     options,
                     backup,
-                    failed_paths
+                    failed_paths,
+                    &ancestors,
+                    scanner
                 ) {
                     Ok(inode) => inode,
                     Err(RepositoryError::Inode(_)) |
                     Err(RepositoryError::Chunker(_)) |
                     Err(RepositoryError::Io(_)) => {
                         info!("Failed to backup {:?}", child_path);
+                        scanner.forget(&child_path);
                         failed_paths.push(child_path);
                         continue;
                     }
@@ -324,6 +844,7 @@ impl Repository {
         Ok(inode)
     }
 
+    #[cfg(unix)]
     pub fn create_backup_recursively<P: AsRef<Path>>(
         &mut self,
         path: P,
@@ -338,21 +859,49 @@ impl Repository {
         try!(self.set_dirty());
         let reference_inode = reference.and_then(|b| self.get_inode(&b.root).ok());
         let mut backup = Backup::default();
-        backup.config = self.config.clone();
+        backup.config = self.config.for_backup();
         backup.host = get_hostname().unwrap_or_else(|_| "".to_string());
         backup.path = path.as_ref().to_string_lossy().to_string();
+        backup.source = SourceInfo::detect(&path, options.snapshot.clone());
+        backup.rules = Some(options.rules());
         let info_before = self.info();
         let start = Local::now();
+        self.phase_times = PhaseTimes::default();
+        self.zero_chunks = 0;
+        let upload_before = self.bundles.upload_wait();
+        let run_start = Instant::now();
         let mut failed_paths = vec![];
-        let root_inode = try!(self.create_backup_recurse(
+        let scan_threads = if options.scan_threads > 0 {
+            options.scan_threads
+        } else {
+            worker_threads(SCAN_THREADS)
+        };
+        let mut scanner = DirScanner::new(scan_threads, options.dereference);
+        let root_inode = match self.create_backup_recurse(
             path,
             reference_inode.as_ref(),
             options,
             &mut backup,
-            &mut failed_paths
-        ));
+            &mut failed_paths,
+            &[],
+            &mut scanner
+        ) {
+            Ok(inode) => inode,
+            Err(RepositoryError::Backup(BackupError::Interrupted)) => {
+                // After flushing, the index is consistent with the bundles again
+                self.dirty = false;
+                try!(self.flush());
+                return Err(BackupError::Interrupted.into());
+            }
+            Err(err) => return Err(err),
+        };
         backup.root = try!(self.put_inode(&root_inode));
+        let run = run_start.elapsed();
+        let upload = self.bundles.upload_wait() - upload_before;
+        let phase_times = mem::replace(&mut self.phase_times, PhaseTimes::default());
+        let flush_start = Instant::now();
         try!(self.flush());
+        backup.timings = phase_times.to_backup_timings(run, upload, flush_start.elapsed());
         let elapsed = Local::now().signed_duration_since(start);
         backup.timestamp = start.timestamp();
         backup.total_data_size = root_inode.cum_size;
@@ -366,7 +915,8 @@ impl Repository {
         backup.deduplicated_data_size = info_after.raw_data_size - info_before.raw_data_size;
         backup.encoded_data_size = info_after.encoded_data_size - info_before.encoded_data_size;
         backup.bundle_count = info_after.bundle_count - info_before.bundle_count;
-        backup.chunk_count = info_after.chunk_count - info_before.chunk_count;
+        backup.chunk_count = (info_after.chunk_count - info_before.chunk_count) as u64;
+        backup.zero_chunk_count = self.zero_chunks;
         backup.avg_chunk_size = backup.deduplicated_data_size as f32 / backup.chunk_count as f32;
         self.dirty = false;
         if failed_paths.is_empty() {
@@ -474,26 +1024,56 @@ impl Repository {
         Ok(versions)
     }
 
+    /// Returns the contents of the inode if they are worth detecting moves for
+    ///
+    /// Empty and inline files are too common to tell where an entry has been moved from.
+    fn move_key(&mut self, chunks: &ChunkList) -> Result<Option<MoveKey>, RepositoryError> {
+        let inode = try!(self.get_inode(chunks));
+        Ok(match (inode.data, inode.children) {
+            (Some(FileData::ChunkedDirect(chunks)), _) |
+            (Some(FileData::ChunkedIndirect(chunks)), _) => Some(MoveKey::File(chunks)),
+            (_, Some(children)) if !children.is_empty() => Some(MoveKey::Directory(children)),
+            _ => None,
+        })
+    }
+
+    fn push_move_candidate(
+        &mut self,
+        diff: DiffType,
+        path: PathBuf,
+        chunks: &ChunkList,
+        diffs: &mut Vec<(DiffType, PathBuf)>,
+        keys: &mut HashMap<usize, MoveKey>,
+    ) -> Result<(), RepositoryError> {
+        if let Some(key) = try!(self.move_key(chunks)) {
+            keys.insert(diffs.len(), key);
+        }
+        diffs.push((diff, path));
+        Ok(())
+    }
+
     fn find_differences_recurse(
         &mut self,
         inode1: &Inode,
         inode2: &Inode,
         path: PathBuf,
         diffs: &mut Vec<(DiffType, PathBuf)>,
+        keys: &mut HashMap<usize, MoveKey>,
     ) -> Result<(), RepositoryError> {
         if !inode1.is_same_meta(inode2) || inode1.data != inode2.data {
             diffs.push((DiffType::Mod, path.clone()));
         }
         if let Some(ref children1) = inode1.children {
-            if let Some(ref children2) = inode2.children {
-                for name in children1.keys() {
-                    if !children2.contains_key(name) {
-                        diffs.push((DiffType::Del, path.join(name)));
-                    }
-                }
-            } else {
-                for name in children1.keys() {
-                    diffs.push((DiffType::Del, path.join(name)));
+            let children2 = inode2.children.as_ref();
+            for (name, chunks1) in children1 {
+                if !children2.map_or(false, |c| c.contains_key(name)) {
+                    try!(self.push_move_candidate(
+                        DiffType::Del,
+                        path.join(name),
+                        chunks1,
+                        diffs,
+                        keys
+                    ));
                 }
             }
         }
@@ -508,16 +1088,29 @@ impl Repository {
                                 &inode1,
                                 &inode2,
                                 path.join(name),
-                                diffs
+                                diffs,
+                                keys
                             ));
                         }
                     } else {
-                        diffs.push((DiffType::Add, path.join(name)));
+                        try!(self.push_move_candidate(
+                            DiffType::Add,
+                            path.join(name),
+                            chunks2,
+                            diffs,
+                            keys
+                        ));
                     }
                 }
             } else {
-                for name in children2.keys() {
-                    diffs.push((DiffType::Add, path.join(name)));
+                for (name, chunks2) in children2 {
+                    try!(self.push_move_candidate(
+                        DiffType::Add,
+                        path.join(name),
+                        chunks2,
+                        diffs,
+                        keys
+                    ));
                 }
             }
         }
@@ -531,13 +1124,282 @@ impl Repository {
         inode2: &Inode,
     ) -> Result<Vec<(DiffType, PathBuf)>, RepositoryError> {
         let mut diffs = vec![];
+        let mut keys = HashMap::new();
         let path = PathBuf::from("/");
         try!(self.find_differences_recurse(
             inode1,
             inode2,
             path,
-            &mut diffs
+            &mut diffs,
+            &mut keys
         ));
-        Ok(diffs)
+        // A deleted and an added entry with the same contents are reported as a move
+        let mut deleted: HashMap<&MoveKey, Vec<usize>> = HashMap::new();
+        for (i, diff) in diffs.iter().enumerate() {
+            if let DiffType::Del = diff.0 {
+                if let Some(key) = keys.get(&i) {
+                    deleted.entry(key).or_insert_with(Vec::new).push(i);
+                }
+            }
+        }
+        let mut moved_from = HashMap::new();
+        for (i, diff) in diffs.iter().enumerate() {
+            if let DiffType::Add = diff.0 {
+                if let Some(key) = keys.get(&i) {
+                    if let Some(source) = deleted.get_mut(key).and_then(|d| d.pop()) {
+                        moved_from.insert(i, source);
+                    }
+                }
+            }
+        }
+        let sources: HashSet<usize> = moved_from.values().cloned().collect();
+        let old_paths: HashMap<usize, PathBuf> = moved_from
+            .iter()
+            .map(|(&i, &source)| (i, diffs[source].1.clone()))
+            .collect();
+        Ok(
+            diffs
+                .into_iter()
+                .enumerate()
+                .filter(|&(i, _)| !sources.contains(&i))
+                .map(|(i, (diff, path))| match old_paths.get(&i) {
+                    Some(old_path) => (DiffType::Mov(old_path.clone()), path),
+                    None => (diff, path),
+                })
+                .collect()
+        )
+    }
+}
+
+
+#[cfg(any(test, feature = "memory"))]
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_restore_after_hash_change() {
+        let mut repo = Repository::create_temporary(Config::default()).unwrap();
+        let data: Vec<u8> = (0..100_000).map(|_| rand::random()).collect();
+        let chunks = repo.put_data(BundleMode::Data, &data).unwrap();
+        let file = Inode {
+            name: "file".to_string(),
+            file_type: FileType::File,
+            size: data.len() as u64,
+            mode: 0o644,
+            data: Some(FileData::ChunkedDirect(chunks.clone())),
+            ..Default::default()
+        };
+        let mut backup = Backup::default();
+        backup.root = repo.put_inode(&file).unwrap();
+        repo.flush().unwrap();
+        repo.config.hash = HashMethod::Murmur3;
+        assert_eq!(repo.get_data(&chunks).unwrap(), data);
+        let inode = repo.get_inode(&backup.root).unwrap();
+        let path = repo.layout.base_path().join("restored");
+        fs::create_dir(&path).unwrap();
+        let options = RestoreOptions {
+            delta: false,
+            special_files: SpecialFiles::Keep,
+            atomic: false,
+            link_dest: None,
+            project_ids: false
+        };
+        repo.restore_inode_tree(&backup, inode, &path, &options).unwrap();
+        let mut restored = vec![];
+        fs::File::open(path.join("file")).unwrap().read_to_end(&mut restored).unwrap();
+        assert_eq!(restored, data);
+    }
+
+    #[test]
+    fn test_prune_skips_protected_backups() {
+        let mut config = Config::default();
+        config.min_retention = 7;
+        let mut repo = Repository::create_temporary(config).unwrap();
+        let now = Local::now().timestamp();
+        for &(name, days) in &[("new", 0), ("recent", 2), ("old", 30)] {
+            let mut backup = Backup::default();
+            backup.timestamp = now - days * 24 * 60 * 60;
+            repo.save_backup(&backup, name).unwrap();
+        }
+        repo.prune_backups("", 1, 0, 0, 0, false, true).unwrap();
+        let backups = repo.get_all_backups().unwrap();
+        assert!(backups.contains_key("new"));
+        assert!(backups.contains_key("recent"));
+        assert!(!backups.contains_key("old"));
+    }
+
+    #[test]
+    fn test_restore_corrupt_chunk() {
+        let mut config = Config::default();
+        config.compression = None;
+        let mut repo = Repository::create_temporary(config).unwrap();
+        let data: Vec<u8> = (0..100_000).map(|_| rand::random()).collect();
+        let chunks = repo.put_data(BundleMode::Data, &data).unwrap();
+        let file = Inode {
+            name: "file".to_string(),
+            file_type: FileType::File,
+            size: data.len() as u64,
+            mode: 0o644,
+            data: Some(FileData::ChunkedDirect(chunks.clone())),
+            ..Default::default()
+        };
+        let mut backup = Backup::default();
+        backup.root = repo.put_inode(&file).unwrap();
+        repo.flush().unwrap();
+        // The contents are stored at the end of the uncompressed bundle, so this changes a chunk
+        let (bundle_id, _) = repo.find_chunk(&chunks[0].0).unwrap().unwrap();
+        let bundle_path = repo.layout.base_path().join(&repo.get_bundle(&bundle_id).unwrap().path);
+        let mut bundle_data = vec![];
+        fs::File::open(&bundle_path).unwrap().read_to_end(&mut bundle_data).unwrap();
+        *bundle_data.last_mut().unwrap() ^= 0xff;
+        fs::File::create(&bundle_path).unwrap().write_all(&bundle_data).unwrap();
+        let inode = repo.get_inode(&backup.root).unwrap();
+        let path = repo.layout.base_path().join("restored");
+        fs::create_dir(&path).unwrap();
+        let options = RestoreOptions {
+            delta: false,
+            special_files: SpecialFiles::Keep,
+            atomic: false,
+            link_dest: None,
+            project_ids: false
+        };
+        assert!(repo.restore_inode_tree(&backup, inode, &path, &options).is_err());
+    }
+
+    #[test]
+    fn test_restore_wrong_chunk_length() {
+        let mut repo = Repository::create_temporary(Config::default()).unwrap();
+        repo.set_verify_reads(false);
+        let data: Vec<u8> = (0..100_000).map(|_| rand::random()).collect();
+        let chunks = repo.put_data(BundleMode::Data, &data).unwrap();
+        // A chunk that is shorter than listed would shift the rest of the file
+        let mut wrong: Vec<Chunk> = chunks.iter().cloned().collect();
+        wrong[0].1 += 1;
+        let file = Inode {
+            name: "file".to_string(),
+            file_type: FileType::File,
+            size: data.len() as u64 + 1,
+            mode: 0o644,
+            data: Some(FileData::ChunkedDirect(ChunkList::from(wrong))),
+            ..Default::default()
+        };
+        let mut backup = Backup::default();
+        backup.root = repo.put_inode(&file).unwrap();
+        repo.flush().unwrap();
+        let inode = repo.get_inode(&backup.root).unwrap();
+        let path = repo.layout.base_path().join("restored");
+        fs::create_dir(&path).unwrap();
+        let options = RestoreOptions {
+            delta: false,
+            special_files: SpecialFiles::Keep,
+            atomic: false,
+            link_dest: None,
+            project_ids: false
+        };
+        assert!(repo.restore_inode_tree(&backup, inode, &path, &options).is_err());
+    }
+
+    #[test]
+    fn test_restore_unverified() {
+        let mut config = Config::default();
+        config.compression = None;
+        let mut repo = Repository::create_temporary(config).unwrap();
+        repo.set_verify_reads(false);
+        let data: Vec<u8> = (0..100_000).map(|_| rand::random()).collect();
+        let chunks = repo.put_data(BundleMode::Data, &data).unwrap();
+        let file = Inode {
+            name: "file".to_string(),
+            file_type: FileType::File,
+            size: data.len() as u64,
+            mode: 0o644,
+            data: Some(FileData::ChunkedDirect(chunks)),
+            ..Default::default()
+        };
+        let mut backup = Backup::default();
+        backup.root = repo.put_inode(&file).unwrap();
+        repo.flush().unwrap();
+        // The chunks are stored verbatim, so they can be copied from the bundle directly
+        let inode = repo.get_inode(&backup.root).unwrap();
+        let path = repo.layout.base_path().join("restored");
+        fs::create_dir(&path).unwrap();
+        let options = RestoreOptions {
+            delta: false,
+            special_files: SpecialFiles::Keep,
+            atomic: false,
+            link_dest: None,
+            project_ids: false
+        };
+        repo.restore_inode_tree(&backup, inode, &path, &options).unwrap();
+        let mut restored = vec![];
+        fs::File::open(path.join("file")).unwrap().read_to_end(&mut restored).unwrap();
+        assert_eq!(restored, data);
+    }
+
+    #[test]
+    fn test_restore_with_memory_limit() {
+        for compression in vec![None, Some(Compression::from_string("deflate/3").unwrap())] {
+            let mut config = Config::default();
+            config.compression = compression;
+            let mut repo = Repository::create_temporary(config).unwrap();
+            // Too little memory to cache a bundle, so chunks are streamed from the bundles
+            repo.set_memory_limit(4 * 1024 * 1024);
+            let data: Vec<u8> = (0..3_000_000)
+                .map(|i| (i / 7 % 13) as u8 ^ rand::random::<u8>() % 4)
+                .collect();
+            let chunks = repo.put_data(BundleMode::Data, &data).unwrap();
+            let file = Inode {
+                name: "file".to_string(),
+                file_type: FileType::File,
+                size: data.len() as u64,
+                mode: 0o644,
+                data: Some(FileData::ChunkedDirect(chunks.clone())),
+                ..Default::default()
+            };
+            let mut backup = Backup::default();
+            backup.root = repo.put_inode(&file).unwrap();
+            repo.flush().unwrap();
+            // New bundles are limited to an eighth of the memory
+            assert!(repo.list_bundles().len() > 2);
+            assert_eq!(repo.get_data(&chunks).unwrap(), data);
+            let inode = repo.get_inode(&backup.root).unwrap();
+            let path = repo.layout.base_path().join("restored");
+            fs::create_dir(&path).unwrap();
+            let options = RestoreOptions {
+                delta: false,
+                special_files: SpecialFiles::Keep,
+                atomic: false,
+                link_dest: None,
+                project_ids: false
+            };
+            repo.restore_inode_tree(&backup, inode, &path, &options).unwrap();
+            let mut restored = vec![];
+            fs::File::open(path.join("file")).unwrap().read_to_end(&mut restored).unwrap();
+            assert_eq!(restored, data);
+        }
+    }
+
+    #[test]
+    fn test_keyed_hash_bundles() {
+        let mut config = Config::default();
+        config.hash_key = Some(Hash::random_key().unwrap());
+        let mut repo = Repository::create_temporary(config).unwrap();
+        assert!(repo.config.for_backup().hash_key.is_none());
+        let data: Vec<u8> = (0..100_000).map(|_| rand::random()).collect();
+        let chunks = repo.put_data(BundleMode::Data, &data).unwrap();
+        repo.flush().unwrap();
+        assert!(repo.list_bundles().iter().all(|info| info.keyed_hash));
+        for &(hash, len) in chunks.iter() {
+            repo.get_verified_chunk(hash, len).unwrap();
+        }
+        // Without the key, e.g. after importing the repository, the chunks are still readable
+        repo.config.hash_key = None;
+        for &(hash, len) in chunks.iter() {
+            repo.get_verified_chunk(hash, len).unwrap();
+        }
     }
 }
@@ -1,12 +1,17 @@
 use prelude::*;
 
+use chrono::prelude::*;
+use serde_yaml;
+
 use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 use std::collections::HashMap;
 
+use super::config::ConfigYaml;
+
 
-static HEADER_STRING: [u8; 7] = *b"zvault\x03";
+pub static HEADER_STRING: [u8; 7] = *b"zvault\x03";
 static HEADER_VERSION: u8 = 1;
 
 
@@ -71,6 +76,144 @@ serde_impl!(BackupHeader(u8) {
 });
 
 
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct ExtensionStats {
+    pub file_count: u64,
+    pub total_data_size: u64, // Sum of all raw sizes of the files
+    pub changed_data_size: u64, // Sum of all raw sizes of the files actively stored
+    pub deduplicated_data_size: u64, // Sum of all raw sizes of new chunks
+    pub encoded_data_size: u64 // Approximate sum of all encoded sizes of new chunks
+}
+serde_impl!(ExtensionStats(u8?) {
+    file_count: u64 => 0,
+    total_data_size: u64 => 1,
+    changed_data_size: u64 => 2,
+    deduplicated_data_size: u64 => 3,
+    encoded_data_size: u64 => 4
+});
+
+
+/// Time spent in the phases of a backup run in seconds
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct BackupTimings {
+    /// Walking the directories, reading metadata and storing inodes
+    pub traversal: f32,
+    /// Reading file contents and finding the chunk boundaries
+    pub chunking: f32,
+    pub hashing: f32,
+    /// Delta encoding, compressing and encrypting chunks and writing the bundles
+    pub compression: f32,
+    /// Waiting for bundles to be uploaded to the remote storage
+    pub upload: f32,
+    /// Writing the last bundles, waiting for all uploads and saving the index at the end
+    pub flush: f32
+}
+serde_impl!(BackupTimings(u8?) {
+    traversal: f32 => 0,
+    chunking: f32 => 1,
+    hashing: f32 => 2,
+    compression: f32 => 3,
+    upload: f32 => 4,
+    flush: f32 => 5
+});
+
+
+/// The rules that decided which paths have been included in a backup
+///
+/// They are recorded so that it can be explained later why a path is missing from a backup
+/// and so that the backup can be repeated with the same filtering.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct BackupRules {
+    /// All exclude patterns from the default excludes, the excludes file and the command line
+    pub excludes: Vec<String>,
+    pub same_device: bool,
+    pub dereference: bool,
+    pub dereference_args: bool,
+    pub special_files: String
+}
+serde_impl!(BackupRules(u8?) {
+    excludes: Vec<String> => 0,
+    same_device: bool => 1,
+    dereference: bool => 2,
+    dereference_args: bool => 3,
+    special_files: String => 4
+});
+
+impl BackupRules {
+    /// The options of the backup subcommand that reproduce these rules except for the excludes
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if !self.same_device {
+            args.push("--xdev".to_string());
+        }
+        if self.dereference {
+            args.push("--dereference".to_string());
+        }
+        if self.dereference_args {
+            args.push("--dereference-args".to_string());
+        }
+        if self.special_files != "keep" {
+            args.push(format!("--special-files={}", self.special_files));
+        }
+        args
+    }
+}
+
+
+/// The filesystem that a backup has been read from
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct SourceInfo {
+    /// Name of the snapshot that has been backed up, if any
+    pub snapshot: Option<String>,
+    pub fs_type: String,
+    pub device: String,
+    pub uuid: Option<String>,
+    pub mount_point: String,
+    /// Path of the mounted directory inside of the filesystem, e.g. the btrfs subvolume
+    pub fs_root: String,
+    pub mount_options: Vec<String>
+}
+serde_impl!(SourceInfo(u8?) {
+    snapshot: Option<String> => 0,
+    fs_type: String => 1,
+    device: String => 2,
+    uuid: Option<String> => 3,
+    mount_point: String => 4,
+    fs_root: String => 5,
+    mount_options: Vec<String> => 6
+});
+
+impl SourceInfo {
+    /// Collects the information on the filesystem that contains the path
+    pub fn detect<P: AsRef<Path>>(path: P, snapshot: Option<String>) -> Self {
+        let path = path.as_ref();
+        let mut info = SourceInfo::default();
+        info.snapshot = snapshot.or_else(|| get_zfs_snapshot_name(path));
+        if let Some(mount) = get_mount_of(path) {
+            info.uuid = get_filesystem_uuid(&mount.device);
+            info.fs_type = mount.fs_type;
+            info.device = mount.device;
+            info.mount_point = mount.mount_point.to_string_lossy().to_string();
+            info.fs_root = mount.root;
+            info.mount_options = mount.options;
+        }
+        info
+    }
+
+    #[inline]
+    pub fn is_known(&self) -> bool {
+        self.snapshot.is_some() || !self.fs_type.is_empty()
+    }
+
+    /// Whether the data could not change while it was backed up
+    ///
+    /// This is the case for snapshots and for filesystems that are mounted read-only.
+    pub fn is_consistent(&self) -> bool {
+        self.snapshot.is_some() || self.mount_options.iter().any(|o| o == "ro")
+    }
+}
+
+
 #[derive(Default, Debug, Clone)]
 pub struct Backup {
     pub root: ChunkList,
@@ -79,18 +222,27 @@ pub struct Backup {
     pub deduplicated_data_size: u64, // Sum of all raw sizes of all new bundles
     pub encoded_data_size: u64, // Sum al all encoded sizes of all new bundles
     pub bundle_count: usize,
-    pub chunk_count: usize,
+    pub chunk_count: u64,
     pub avg_chunk_size: f32,
     pub timestamp: i64,
     pub duration: f32,
-    pub file_count: usize,
-    pub dir_count: usize,
+    pub file_count: u64,
+    pub dir_count: u64,
     pub host: String,
     pub path: String,
     pub config: Config,
     pub modified: bool,
     pub user_names: HashMap<u32, String>,
-    pub group_names: HashMap<u32, String>
+    pub group_names: HashMap<u32, String>,
+    pub extensions: HashMap<String, ExtensionStats>,
+    pub tags: Vec<String>,
+    pub remotes: Vec<String>, // Additional named remotes that contain a complete copy
+    pub timings: BackupTimings,
+    pub source: SourceInfo,
+    /// Not recorded for tar imports and backups of older versions
+    pub rules: Option<BackupRules>,
+    /// Number of chunks that only contained zeros and have not been hashed
+    pub zero_chunk_count: u64
 }
 serde_impl!(Backup(u8?) {
     root: ChunkList => 0,
@@ -99,21 +251,102 @@ serde_impl!(Backup(u8?) {
     deduplicated_data_size: u64 => 3,
     encoded_data_size: u64 => 4,
     bundle_count: usize => 5,
-    chunk_count: usize => 6,
+    chunk_count: u64 => 6,
     avg_chunk_size: f32 => 7,
     timestamp: i64 => 8,
     duration: f32 => 9,
-    file_count: usize => 10,
-    dir_count: usize => 11,
+    file_count: u64 => 10,
+    dir_count: u64 => 11,
     host: String => 12,
     path: String => 13,
     config: Config => 14,
     modified: bool => 15,
     user_names: HashMap<u32, String> => 16,
-    group_names: HashMap<u32, String> => 17
+    group_names: HashMap<u32, String> => 17,
+    extensions: HashMap<String, ExtensionStats> => 18,
+    tags: Vec<String> => 19,
+    remotes: Vec<String> => 20,
+    timings: BackupTimings => 21,
+    source: SourceInfo => 22,
+    rules: Option<BackupRules> => 23,
+    zero_chunk_count: u64 => 24
+});
+
+/// Readable representation of a backup file, e.g. for raw mounts
+#[derive(Default)]
+struct BackupYaml {
+    date: String,
+    timestamp: i64,
+    host: String,
+    path: String,
+    duration: f32,
+    file_count: u64,
+    dir_count: u64,
+    total_data_size: u64,
+    changed_data_size: u64,
+    deduplicated_data_size: u64,
+    encoded_data_size: u64,
+    bundle_count: usize,
+    chunk_count: u64,
+    zero_chunk_count: u64,
+    avg_chunk_size: f32,
+    modified: bool,
+    tags: Vec<String>,
+    remotes: Vec<String>,
+    root: Vec<String>,
+    config: ConfigYaml
+}
+serde_impl!(BackupYaml(String) {
+    date: String => "date",
+    timestamp: i64 => "timestamp",
+    host: String => "host",
+    path: String => "path",
+    duration: f32 => "duration",
+    file_count: u64 => "file_count",
+    dir_count: u64 => "dir_count",
+    total_data_size: u64 => "total_data_size",
+    changed_data_size: u64 => "changed_data_size",
+    deduplicated_data_size: u64 => "deduplicated_data_size",
+    encoded_data_size: u64 => "encoded_data_size",
+    bundle_count: usize => "bundle_count",
+    chunk_count: u64 => "chunk_count",
+    zero_chunk_count: u64 => "zero_chunk_count",
+    avg_chunk_size: f32 => "avg_chunk_size",
+    modified: bool => "modified",
+    tags: Vec<String> => "tags",
+    remotes: Vec<String> => "remotes",
+    root: Vec<String> => "root",
+    config: ConfigYaml => "config"
 });
 
 impl Backup {
+    /// Returns the backup as YAML, the chunks of the root inode are listed as `hash:length`
+    pub fn to_yaml(&self) -> String {
+        let yaml = BackupYaml {
+            date: Local.timestamp(self.timestamp, 0).to_rfc2822(),
+            timestamp: self.timestamp,
+            host: self.host.clone(),
+            path: self.path.clone(),
+            duration: self.duration,
+            file_count: self.file_count,
+            dir_count: self.dir_count,
+            total_data_size: self.total_data_size,
+            changed_data_size: self.changed_data_size,
+            deduplicated_data_size: self.deduplicated_data_size,
+            encoded_data_size: self.encoded_data_size,
+            bundle_count: self.bundle_count,
+            chunk_count: self.chunk_count,
+            zero_chunk_count: self.zero_chunk_count,
+            avg_chunk_size: self.avg_chunk_size,
+            modified: self.modified,
+            tags: self.tags.clone(),
+            remotes: self.remotes.clone(),
+            root: self.root.iter().map(|&(hash, len)| format!("{}:{}", hash, len)).collect(),
+            config: self.config.to_yaml()
+        };
+        serde_yaml::to_string(&yaml).unwrap()
+    }
+
     pub fn read_from<P: AsRef<Path>>(crypto: &Crypto, path: P) -> Result<Self, BackupFileError> {
         let path = path.as_ref();
         let mut file = BufReader::new(try!(File::open(path).map_err(|err| {
@@ -133,7 +366,9 @@ impl Backup {
                 version
             ));
         }
-        let header: BackupHeader = try!(msgpack::decode_from_stream(&mut file).context(path));
+        let header: BackupHeader = try!(
+            msgpack::decode_from_stream_limited(&mut file, msgpack::MAX_HEADER_SIZE).context(path)
+        );
         let mut data = Vec::new();
         try!(file.read_to_end(&mut data).map_err(|err| {
             BackupFileError::Read(err, path.to_path_buf())
@@ -2,14 +2,60 @@ use prelude::*;
 
 use std::mem;
 use std::cmp::min;
-use std::collections::VecDeque;
 use std::io::{self, Read, Write, Cursor};
+use std::time::{Duration, Instant};
+
+
+/// Returns whether the data only consists of zeros
+fn is_zero(data: &[u8]) -> bool {
+    // Most chunks that are not zero are detected by the first bytes
+    data.iter().take(64).all(|&b| b == 0) && data.iter().all(|&b| b == 0)
+}
+
+
+fn to_secs(duration: Duration) -> f32 {
+    duration.as_secs() as f32 + duration.subsec_nanos() as f32 / 1_000_000_000.0
+}
+
+
+/// Time spent chunking, hashing and compressing since the last reset
+#[derive(Default)]
+pub struct PhaseTimes {
+    pub chunking: Duration,
+    pub hashing: Duration,
+    // This includes waiting for the upload queue when finishing bundles
+    pub compression: Duration
+}
+
+impl PhaseTimes {
+    /// Splits the duration of a backup run into phases, the remaining time counts as traversal
+    pub fn to_backup_timings(
+        &self,
+        run: Duration,
+        upload: Duration,
+        flush: Duration,
+    ) -> BackupTimings {
+        let zero = Duration::new(0, 0);
+        let measured = self.chunking + self.hashing + self.compression;
+        BackupTimings {
+            traversal: to_secs(run.checked_sub(measured).unwrap_or(zero)),
+            chunking: to_secs(self.chunking),
+            hashing: to_secs(self.hashing),
+            compression: to_secs(self.compression.checked_sub(upload).unwrap_or(zero)),
+            upload: to_secs(upload),
+            flush: to_secs(flush)
+        }
+    }
+}
 
 
 pub struct ChunkReader<'a> {
-    chunks: VecDeque<Chunk>,
+    chunks: ChunkList,
+    next: usize,
     data: Vec<u8>,
     pos: usize,
+    prefetch: bool,
+    prefetched: Option<u64>,
     repo: &'a mut Repository
 }
 
@@ -17,11 +63,24 @@ impl<'a> ChunkReader<'a> {
     pub fn new(repo: &'a mut Repository, chunks: ChunkList) -> Self {
         ChunkReader {
             repo: repo,
-            chunks: chunks.into_inner().into(),
+            chunks: chunks,
+            next: 0,
             data: vec![],
-            pos: 0
+            pos: 0,
+            prefetch: false,
+            prefetched: None
         }
     }
+
+    /// Creates a reader that loads the next bundle in the background while reading the current one
+    ///
+    /// This only pays off if the chunks are read sequentially until the end, e.g. when a file is
+    /// streamed, as the prefetched bundle is loaded completely.
+    pub fn with_prefetch(repo: &'a mut Repository, chunks: ChunkList) -> Self {
+        let mut reader = ChunkReader::new(repo, chunks);
+        reader.prefetch = true;
+        reader
+    }
 }
 
 impl<'a> Read for ChunkReader<'a> {
@@ -32,17 +91,21 @@ impl<'a> Read for ChunkReader<'a> {
                 break;
             }
             if self.data.len() == self.pos {
-                if let Some(chunk) = self.chunks.pop_front() {
-                    self.data = match self.repo.get_chunk(chunk.0) {
-                        Ok(Some(data)) => data,
-                        Ok(None) => {
-                            return Err(io::Error::new(
-                                io::ErrorKind::Other,
-                                IntegrityError::MissingChunk(chunk.0)
-                            ))
+                if let Some(&chunk) = self.chunks.get(self.next) {
+                    if self.prefetch {
+                        let res = self.repo.prefetch_next_bundle(
+                            &self.chunks[self.next..],
+                            &mut self.prefetched
+                        );
+                        if let Err(err) = res {
+                            return Err(io::Error::new(io::ErrorKind::Other, err));
                         }
+                    }
+                    self.data = match self.repo.read_chunk(chunk.0, chunk.1) {
+                        Ok(data) => data,
                         Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
                     };
+                    self.next += 1;
                     self.pos = 0;
                 } else {
                     break;
@@ -58,9 +121,22 @@ impl<'a> Read for ChunkReader<'a> {
 }
 
 
+/// Detects sequential reads of a chunk list to prefetch the next bundle in time
+///
+/// Readers that only get byte ranges, like the fuse filesystem, keep one of these per file and
+/// pass it to `Repository::read_ahead` with every read. Random reads do not prefetch anything.
+#[derive(Default)]
+pub struct ReadAhead {
+    // Offset at which the next read starts if the file is read sequentially
+    next_offset: u64,
+    // Bundle whose successor has already been prefetched
+    prefetched: Option<u64>
+}
+
+
 impl Repository {
     #[inline]
-    pub fn get_bundle_id(&self, id: u32) -> Result<BundleId, RepositoryError> {
+    pub fn get_bundle_id(&self, id: u64) -> Result<BundleId, RepositoryError> {
         self.bundle_map.get(id).ok_or_else(|| {
             IntegrityError::MissingBundleId(id).into()
         })
@@ -81,6 +157,60 @@ impl Repository {
         )))
     }
 
+    /// Returns the data of a chunk that is part of a file or of metadata
+    ///
+    /// The length of the data is always checked, the hash only if `verify_reads` is set.
+    #[inline]
+    pub fn read_chunk(&mut self, hash: Hash, len: u32) -> Result<Vec<u8>, RepositoryError> {
+        let verify = self.verify_reads;
+        self.load_chunk(hash, len, verify)
+    }
+
+    /// Returns the data of a chunk after checking its length and its hash
+    #[inline]
+    pub fn get_verified_chunk(&mut self, hash: Hash, len: u32) -> Result<Vec<u8>, RepositoryError> {
+        self.load_chunk(hash, len, true)
+    }
+
+    /// Loads a chunk and checks its length and, if requested, its hash
+    ///
+    /// The hash is calculated the way the bundle records, as the hash method of the repository
+    /// might have been changed since the chunk was written. Chunks of keyed bundles can not be
+    /// verified without the key, e.g. in a repository imported from the remote storage.
+    fn load_chunk(&mut self, hash: Hash, len: u32, verify: bool) -> Result<Vec<u8>, RepositoryError> {
+        let found = try!(self.index.get(&hash).ok_or_else(|| IntegrityError::MissingChunk(hash)));
+        let bundle_id = try!(self.get_bundle_id(found.bundle));
+        let data = try!(self.bundles.get_chunk(&bundle_id, found.chunk as usize));
+        if data.len() as u32 != len {
+            return Err(IntegrityError::WrongChunkData(hash).into());
+        }
+        if verify {
+            let info = &try!(self.bundles.get_bundle_info(&bundle_id).ok_or_else(|| {
+                IntegrityError::MissingBundle(bundle_id.clone())
+            })).info;
+            match info.hash_chunk(&data, self.config.hash_key.as_ref()) {
+                Some(actual) if actual != hash => {
+                    return Err(IntegrityError::WrongChunkData(hash).into())
+                }
+                _ => (),
+            }
+        }
+        Ok(data)
+    }
+
+    /// Returns the bundle that contains the chunk and the number of the chunk in that bundle
+    pub fn find_chunk(&self, hash: &Hash) -> Result<Option<(BundleId, usize)>, RepositoryError> {
+        match self.index.get(hash) {
+            Some(found) => Ok(Some((try!(self.get_bundle_id(found.bundle)), found.chunk as usize))),
+            None => Ok(None),
+        }
+    }
+
+    #[inline]
+    pub fn has_chunk(&self, hash: &Hash) -> bool {
+        self.index.contains(hash)
+    }
+
     #[inline]
     pub fn put_chunk(
         &mut self,
@@ -111,13 +241,30 @@ impl Repository {
                 mode,
                 self.config.hash,
                 self.config.compression.clone(),
-                self.config.encryption.clone()
+                self.config.encryption.clone(),
+                self.config.delta_compression && mode == BundleMode::Data,
+                self.config.dictionary
             )));
+            if let Some(ref mut writer) = *writer {
+                writer.set_origin(get_hostname().ok(), self.bundle_origin.clone());
+                writer.set_keyed_hash(self.config.hash_key.is_some());
+                if mode == BundleMode::Data {
+                    writer.set_storage_class(self.bundle_storage_class);
+                }
+            }
         }
         debug_assert!(writer.is_some());
         // Add chunk to bundle writer and determine the size of the bundle
         let writer_obj = writer.as_mut().unwrap();
+        let encoded_size = writer_obj.encoded_size();
+        let start = Instant::now();
         let chunk_id = try!(writer_obj.add(data, hash));
+        self.phase_times.compression += start.elapsed();
+        if mode == BundleMode::Data {
+            // Compressors buffer internally, so this is only an approximation
+            self.data_written.0 += data.len() as u64;
+            self.data_written.1 += (writer_obj.encoded_size() - encoded_size) as u64;
+        }
         let bundle_id = match mode {
             BundleMode::Data => self.next_data_bundle,
             BundleMode::Meta => self.next_meta_bundle,
@@ -125,7 +272,7 @@ impl Repository {
         // Add location to the index
         try!(self.index.set(
             &hash,
-            &Location::new(bundle_id, chunk_id as u32)
+            &Location::new(bundle_id, chunk_id as u64)
         ));
         Ok(())
     }
@@ -146,7 +293,9 @@ impl Repository {
         };
         let mut finished = None;
         mem::swap(writer, &mut finished);
+        let start = Instant::now();
         let bundle = try!(self.bundles.add_bundle(finished.unwrap()));
+        self.phase_times.compression += start.elapsed();
         self.bundle_map.set(bundle_id, bundle.id.clone());
         if self.next_meta_bundle == bundle_id {
             self.next_meta_bundle = next_free_bundle_id
@@ -169,7 +318,11 @@ impl Repository {
                 return Ok(());
             }
         };
-        if size >= self.config.bundle_size || raw_size >= 4 * self.config.bundle_size {
+        let bundle_size = min(
+            self.config.bundle_size,
+            self.max_bundle_size.unwrap_or(self.config.bundle_size)
+        );
+        if size >= bundle_size || raw_size >= 4 * bundle_size {
             if mode == BundleMode::Meta {
                 //First store the current data bundle as meta referrs to those chunks
                 try!(self.finish_bundle(BundleMode::Data))
@@ -211,9 +364,22 @@ impl Repository {
         loop {
             chunk.clear();
             let mut output = Cursor::new(chunk);
+            let start = Instant::now();
             let res = try!(self.chunker.chunk(data, &mut output));
+            self.phase_times.chunking += start.elapsed();
             chunk = output.into_inner();
-            let hash = self.config.hash.hash(&chunk);
+            let start = Instant::now();
+            let hash = if self.config.zero_chunks && is_zero(&chunk) {
+                // Zero chunks mostly have the same length, so their hash is only computed once
+                self.zero_chunks += 1;
+                let config = &self.config;
+                *self.zero_hashes
+                    .entry(chunk.len() as u32)
+                    .or_insert_with(|| config.hash_chunk(&chunk))
+            } else {
+                self.config.hash_chunk(&chunk)
+            };
+            self.phase_times.hashing += start.elapsed();
             try!(self.put_chunk(mode, hash, &chunk));
             chunks.push((hash, chunk.len() as u32));
             if res == ChunkerStatus::Finished {
@@ -224,15 +390,66 @@ impl Repository {
     }
 
     pub fn get_data(&mut self, chunks: &[Chunk]) -> Result<Vec<u8>, RepositoryError> {
-        let mut data =
-            Vec::with_capacity(chunks.iter().map(|&(_, size)| size).sum::<u32>() as usize);
+        let mut data = Vec::with_capacity(chunks_size(chunks) as usize);
         try!(self.get_stream(chunks, &mut data));
         Ok(data)
     }
 
+    /// Returns a reader that prefetches the next bundle, see `ChunkReader::with_prefetch`
     #[inline]
-    pub fn get_reader(&mut self, chunks: ChunkList) -> ChunkReader {
-        ChunkReader::new(self, chunks)
+    pub fn get_prefetching_reader(&mut self, chunks: ChunkList) -> ChunkReader {
+        ChunkReader::with_prefetch(self, chunks)
+    }
+
+    /// Starts loading the next bundle that `chunks` need after the bundle of the first chunk
+    ///
+    /// The chunks are only scanned when the first chunk is in another bundle than the last time,
+    /// which is tracked in `prefetched`, so that reading a whole chunk list stays linear.
+    fn prefetch_next_bundle(
+        &mut self,
+        chunks: &[Chunk],
+        prefetched: &mut Option<u64>,
+    ) -> Result<(), RepositoryError> {
+        let bundle = match chunks.first().and_then(|&(hash, _)| self.index.get(&hash)) {
+            Some(found) => found.bundle,
+            None => return Ok(()),
+        };
+        if *prefetched == Some(bundle) {
+            return Ok(());
+        }
+        *prefetched = Some(bundle);
+        for &(hash, _) in &chunks[1..] {
+            if let Some(found) = self.index.get(&hash) {
+                if found.bundle != bundle {
+                    let bundle_id = try!(self.get_bundle_id(found.bundle));
+                    try!(self.bundles.prefetch_bundle(&bundle_id));
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Prefetches the next bundle of a file that is read sequentially in byte ranges
+    ///
+    /// This has to be called for every read of the chunks with its `offset` and `len`.
+    pub fn read_ahead(
+        &mut self,
+        state: &mut ReadAhead,
+        chunks: &[Chunk],
+        offset: u64,
+        len: u64,
+    ) -> Result<(), RepositoryError> {
+        let sequential = offset == state.next_offset;
+        state.next_offset = offset.saturating_add(len);
+        if !sequential {
+            return Ok(());
+        }
+        // The chunk that contains the end of this read is needed by the next read
+        if let Some(&(index, _, _)) = chunk_range(chunks, state.next_offset, 1).first() {
+            try!(self.prefetch_next_bundle(&chunks[index..], &mut state.prefetched));
+        }
+        Ok(())
     }
 
     pub fn get_stream<W: Write>(
@@ -240,13 +457,32 @@ impl Repository {
         chunks: &[Chunk],
         w: &mut W,
     ) -> Result<(), RepositoryError> {
-        for &(ref hash, len) in chunks {
-            let data = try!(try!(self.get_chunk(*hash)).ok_or_else(|| {
-                IntegrityError::MissingChunk(*hash)
-            }));
-            debug_assert_eq!(data.len() as u32, len);
+        for &(hash, len) in chunks {
+            let data = try!(self.read_chunk(hash, len));
             try!(w.write_all(&data));
         }
         Ok(())
     }
+
+    /// Writes the bytes `offset..offset+len` of the data of the chunks and returns their number
+    ///
+    /// Only the chunks that overlap the range are read, so parts of large files can be served,
+    /// e.g. for resumed downloads, without reading the whole file. The range is cut off at the
+    /// end of the data.
+    pub fn get_stream_range<W: Write>(
+        &mut self,
+        chunks: &[Chunk],
+        offset: u64,
+        len: u64,
+        w: &mut W,
+    ) -> Result<u64, RepositoryError> {
+        let mut written = 0;
+        for (i, start, end) in chunk_range(chunks, offset, len) {
+            let (hash, size) = chunks[i];
+            let data = try!(self.read_chunk(hash, size));
+            try!(w.write_all(&data[start as usize..end as usize]));
+            written += (end - start) as u64;
+        }
+        Ok(written)
+    }
 }
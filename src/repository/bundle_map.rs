@@ -6,7 +6,7 @@ use std::io::{self, BufReader, Read, Write, BufWriter};
 use std::fs::File;
 
 
-static HEADER_STRING: [u8; 7] = *b"zbunmap";
+pub static HEADER_STRING: [u8; 7] = *b"zbunmap";
 static HEADER_VERSION: u8 = 1;
 
 
@@ -39,7 +39,7 @@ quick_error!{
 }
 
 
-pub struct BundleMap(HashMap<u32, BundleId>);
+pub struct BundleMap(HashMap<u64, BundleId>);
 
 impl BundleMap {
     pub fn create() -> Self {
@@ -68,16 +68,16 @@ impl BundleMap {
     }
 
     #[inline]
-    pub fn get(&self, id: u32) -> Option<BundleId> {
+    pub fn get(&self, id: u64) -> Option<BundleId> {
         self.0.get(&id).cloned()
     }
 
     #[inline]
-    pub fn remove(&mut self, id: u32) -> Option<BundleId> {
+    pub fn remove(&mut self, id: u64) -> Option<BundleId> {
         self.0.remove(&id)
     }
 
-    pub fn find(&self, bundle: &BundleId) -> Option<u32> {
+    pub fn find(&self, bundle: &BundleId) -> Option<u64> {
         for (id, bundle_id) in &self.0 {
             if bundle == bundle_id {
                 return Some(*id);
@@ -87,11 +87,11 @@ impl BundleMap {
     }
 
     #[inline]
-    pub fn set(&mut self, id: u32, bundle: BundleId) {
+    pub fn set(&mut self, id: u64, bundle: BundleId) {
         self.0.insert(id, bundle);
     }
 
-    pub fn bundles(&self) -> Vec<(u32, BundleId)> {
+    pub fn bundles(&self) -> Vec<(u64, BundleId)> {
         self.0
             .iter()
             .map(|(id, bundle)| (*id, bundle.clone()))
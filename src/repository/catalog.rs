@@ -0,0 +1,44 @@
+use prelude::*;
+
+use std::collections::HashMap;
+use std::path::Path;
+
+
+/// Read-only access to the backup files of a repository
+///
+/// Opening a catalog only loads the keys. Unlike `Repository::open`, it does not load the index
+/// and the bundle map and does not compare the bundles with the remote storage, so operations
+/// that only need the backup files like listing the backups are fast even for large repositories
+/// on slow remote storage.
+pub struct BackupCatalog {
+    pub layout: RepositoryLayout,
+    crypto: Crypto
+}
+
+impl BackupCatalog {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, RepositoryError> {
+        let layout = RepositoryLayout::new(path.as_ref().to_path_buf());
+        if !layout.remote_exists() {
+            return Err(RepositoryError::NoRemote);
+        }
+        let crypto = try!(Crypto::open(layout.keys_path()));
+        Ok(BackupCatalog {
+            layout: layout,
+            crypto: crypto
+        })
+    }
+
+    pub fn get_all_backups(&self) -> Result<HashMap<String, Backup>, RepositoryError> {
+        Ok(try!(Backup::get_all_from(&self.crypto, self.layout.backups_path())))
+    }
+
+    pub fn get_backups<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<HashMap<String, Backup>, RepositoryError> {
+        Ok(try!(Backup::get_all_from(
+            &self.crypto,
+            self.layout.backups_path().join(path)
+        )))
+    }
+}
@@ -2,6 +2,7 @@ use prelude::*;
 
 use serde_yaml;
 
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::path::Path;
 use std::io;
@@ -89,6 +90,17 @@ impl Compression {
 }
 
 
+impl UploadVerification {
+    fn from_yaml(yaml: String) -> Result<Self, ConfigError> {
+        UploadVerification::from_string(&yaml).map_err(ConfigError::Parse)
+    }
+
+    fn to_yaml(&self) -> String {
+        self.name().to_string()
+    }
+}
+
+
 impl EncryptionMethod {
     #[inline]
     fn from_yaml(yaml: String) -> Result<Self, ConfigError> {
@@ -121,12 +133,51 @@ serde_impl!(EncryptionYaml(String) {
 
 
 
-struct ConfigYaml {
+/// Thresholds of the health check that runs when a repository is opened, 0 disables a check
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HealthConfig {
+    /// Maximal number of days since the last successful check
+    pub max_check_age: u64,
+    /// Maximal number of hours since a remote lock has been updated
+    pub max_lock_age: u64,
+    /// Maximal number of hours since the latest backup matching each pattern
+    pub freshness: BTreeMap<String, u64>
+}
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            max_check_age: 30,
+            max_lock_age: 24,
+            freshness: BTreeMap::new()
+        }
+    }
+}
+serde_impl!(HealthConfig(String?) {
+    max_check_age: u64 => "max_check_age",
+    max_lock_age: u64 => "max_lock_age",
+    freshness: BTreeMap<String, u64> => "freshness"
+});
+
+
+
+pub struct ConfigYaml {
     compression: Option<String>,
     encryption: Option<EncryptionYaml>,
     bundle_size: usize,
     chunker: ChunkerYaml,
-    hash: String
+    hash: String,
+    delta_compression: bool,
+    dictionary: Option<String>,
+    health: HealthConfig,
+    trash_retention: u64,
+    min_retention: u64,
+    upload_verification: String,
+    zero_chunks: bool,
+    codec_helpers: usize,
+    short_bundle_names: bool,
+    hash_key: Option<String>,
+    threads: usize,
+    cpus: String
 }
 impl Default for ConfigYaml {
     fn default() -> Self {
@@ -135,7 +186,19 @@ impl Default for ConfigYaml {
             encryption: None,
             bundle_size: 25 * 1024 * 1024,
             chunker: ChunkerYaml::default(),
-            hash: "blake2".to_string()
+            hash: "blake2".to_string(),
+            delta_compression: false,
+            dictionary: None,
+            health: HealthConfig::default(),
+            trash_retention: 7,
+            min_retention: 0,
+            upload_verification: "off".to_string(),
+            zero_chunks: true,
+            codec_helpers: 0,
+            short_bundle_names: false,
+            hash_key: None,
+            threads: 0,
+            cpus: "".to_string()
         }
     }
 }
@@ -144,7 +207,19 @@ serde_impl!(ConfigYaml(String) {
     encryption: Option<EncryptionYaml> => "encryption",
     bundle_size: usize => "bundle_size",
     chunker: ChunkerYaml => "chunker",
-    hash: String => "hash"
+    hash: String => "hash",
+    delta_compression: bool => "delta_compression",
+    dictionary: Option<String> => "dictionary",
+    health: HealthConfig => "health",
+    trash_retention: u64 => "trash_retention",
+    min_retention: u64 => "min_retention",
+    upload_verification: String => "upload_verification",
+    zero_chunks: bool => "zero_chunks",
+    codec_helpers: usize => "codec_helpers",
+    short_bundle_names: bool => "short_bundle_names",
+    hash_key: Option<String> => "hash_key",
+    threads: usize => "threads",
+    cpus: String => "cpus"
 });
 
 
@@ -155,7 +230,28 @@ pub struct Config {
     pub encryption: Option<Encryption>,
     pub bundle_size: usize,
     pub chunker: ChunkerType,
-    pub hash: HashMethod
+    pub hash: HashMethod,
+    pub delta_compression: bool,
+    pub dictionary: Option<Hash>,
+    pub health: HealthConfig,
+    /// Number of days that removed backups are kept in the trash, 0 deletes them immediately
+    pub trash_retention: u64,
+    /// Number of days that new backups are protected from prune and remove, 0 disables this
+    pub min_retention: u64,
+    /// How new bundles are read back from the remote storage to verify them
+    pub upload_verification: UploadVerification,
+    /// Whether chunks that only contain zeros are detected and stored without hashing them
+    pub zero_chunks: bool,
+    /// Number of helper processes that compress and encrypt new bundles, 0 does this in-process
+    pub codec_helpers: usize,
+    /// Whether new bundles get 8.3 names for remotes on FAT or case-insensitive filesystems
+    pub short_bundle_names: bool,
+    /// Secret key of the chunk hashes, it can only be set when creating the repository
+    pub hash_key: Option<Hash>,
+    /// Number of threads of each worker pool, 0 uses the defaults of the pools
+    pub threads: usize,
+    /// CPUs that the worker threads are pinned to, empty for no restriction
+    pub cpus: Vec<usize>
 }
 impl Default for Config {
     fn default() -> Self {
@@ -164,16 +260,40 @@ impl Default for Config {
             encryption: None,
             bundle_size: 25 * 1024 * 1024,
             chunker: ChunkerType::from_string("fastcdc/16").unwrap(),
-            hash: HashMethod::Blake2
+            hash: HashMethod::Blake2,
+            delta_compression: false,
+            dictionary: None,
+            health: HealthConfig::default(),
+            trash_retention: 7,
+            min_retention: 0,
+            upload_verification: UploadVerification::Off,
+            zero_chunks: true,
+            codec_helpers: 0,
+            short_bundle_names: false,
+            hash_key: None,
+            threads: 0,
+            cpus: vec![]
         }
     }
 }
-serde_impl!(Config(u64) {
+serde_impl!(Config(u64?) {
     compression: Option<Compression> => 0,
     encryption: Option<Encryption> => 1,
     bundle_size: usize => 2,
     chunker: ChunkerType => 3,
-    hash: HashMethod => 4
+    hash: HashMethod => 4,
+    delta_compression: bool => 5,
+    dictionary: Option<Hash> => 6,
+    health: HealthConfig => 7,
+    trash_retention: u64 => 8,
+    threads: usize => 9,
+    cpus: Vec<usize> => 10,
+    min_retention: u64 => 11,
+    upload_verification: UploadVerification => 12,
+    zero_chunks: bool => 13,
+    codec_helpers: usize => 14,
+    short_bundle_names: bool => 15,
+    hash_key: Option<Hash> => 16
 });
 
 impl Config {
@@ -192,16 +312,50 @@ impl Config {
         } else {
             None
         };
+        let dictionary = if let Some(d) = yaml.dictionary {
+            if d.len() != 32 {
+                return Err(ConfigError::Parse("Invalid dictionary id"));
+            }
+            Some(try!(Hash::from_string(&d).map_err(|_| {
+                ConfigError::Parse("Invalid dictionary id")
+            })))
+        } else {
+            None
+        };
+        let hash_key = if let Some(k) = yaml.hash_key {
+            Some(try!(Hash::from_string(&k).map_err(|_| {
+                ConfigError::Parse("Invalid hash key")
+            })))
+        } else {
+            None
+        };
+        let cpus = if yaml.cpus.is_empty() {
+            vec![]
+        } else {
+            try!(parse_cpu_list(&yaml.cpus).map_err(ConfigError::Parse))
+        };
         Ok(Config {
             compression: compression,
             encryption: encryption,
             bundle_size: yaml.bundle_size,
             chunker: try!(ChunkerType::from_yaml(yaml.chunker)),
-            hash: try!(HashMethod::from_yaml(yaml.hash))
+            hash: try!(HashMethod::from_yaml(yaml.hash)),
+            delta_compression: yaml.delta_compression,
+            dictionary: dictionary,
+            health: yaml.health,
+            trash_retention: yaml.trash_retention,
+            min_retention: yaml.min_retention,
+            upload_verification: try!(UploadVerification::from_yaml(yaml.upload_verification)),
+            zero_chunks: yaml.zero_chunks,
+            codec_helpers: yaml.codec_helpers,
+            short_bundle_names: yaml.short_bundle_names,
+            hash_key: hash_key,
+            threads: yaml.threads,
+            cpus: cpus
         })
     }
 
-    fn to_yaml(&self) -> ConfigYaml {
+    pub fn to_yaml(&self) -> ConfigYaml {
         ConfigYaml {
             compression: self.compression.as_ref().map(|c| c.to_yaml()),
             encryption: self.encryption.as_ref().map(|e| {
@@ -212,10 +366,35 @@ impl Config {
             }),
             bundle_size: self.bundle_size,
             chunker: self.chunker.to_yaml(),
-            hash: self.hash.to_yaml()
+            hash: self.hash.to_yaml(),
+            delta_compression: self.delta_compression,
+            dictionary: self.dictionary.map(|d| d.to_string()),
+            health: self.health.clone(),
+            trash_retention: self.trash_retention,
+            min_retention: self.min_retention,
+            upload_verification: self.upload_verification.to_yaml(),
+            zero_chunks: self.zero_chunks,
+            codec_helpers: self.codec_helpers,
+            short_bundle_names: self.short_bundle_names,
+            hash_key: self.hash_key.map(|k| k.to_string()),
+            threads: self.threads,
+            cpus: format_cpu_list(&self.cpus)
         }
     }
 
+    /// Hashes the data of a chunk, with the key of the repository if it has one
+    #[inline]
+    pub fn hash_chunk(&self, data: &[u8]) -> Hash {
+        self.hash.hash_keyed(data, self.hash_key.as_ref())
+    }
+
+    /// Returns the configuration to be stored in backup files, without the secret hash key
+    pub fn for_backup(&self) -> Self {
+        let mut config = self.clone();
+        config.hash_key = None;
+        config
+    }
+
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
         let f = try!(File::open(path));
         let config = try!(serde_yaml::from_reader(f));
@@ -0,0 +1,65 @@
+use prelude::*;
+
+use std::fs;
+use std::path::Path;
+
+
+impl Repository {
+    /// Writes the bundles and backups created after the backup `since` into the folder `dst`
+    ///
+    /// The folder gets the same structure as the remote storage so that it can be imported with
+    /// `import_delta` into a copy of the repository that already contains the backup `since`.
+    /// All dictionaries are included as they are small. Returns the numbers of exported bundles
+    /// and backups.
+    pub fn export_delta(
+        &mut self,
+        since: &str,
+        dst: &Path,
+    ) -> Result<(usize, usize), RepositoryError> {
+        try!(self.flush());
+        let _lock = try!(self.lock(false));
+        let since = try!(self.get_backup(since)).timestamp;
+        let bundles = try!(self.bundles.export_bundles(since, dst));
+        let mut backups = 0;
+        for (name, backup) in try!(self.get_all_backups()) {
+            if backup.timestamp <= since {
+                continue;
+            }
+            let path = dst.join("backups").join(format!("{}.backup", name));
+            try!(fs::create_dir_all(path.parent().unwrap()));
+            try!(fs::copy(self.layout.backup_path(&name), path));
+            backups += 1;
+        }
+        Ok((bundles, backups))
+    }
+
+    /// Copies the bundles and backups exported by `export_delta` into the repository
+    ///
+    /// Existing backups are not replaced. The bundles are added to the index when the repository
+    /// is opened the next time. Returns the numbers of imported bundles and backups.
+    pub fn import_delta(&mut self, src: &Path) -> Result<(usize, usize), RepositoryError> {
+        try!(self.write_mode());
+        let _lock = try!(self.lock(false));
+        let bundles = try!(self.bundles.import_bundles(src));
+        let mut backups = 0;
+        let names: Vec<_> = try!(Backup::get_all_from(
+            &self.crypto.lock().unwrap(),
+            src.join("backups")
+        )).into_iter().map(|(name, _)| name).collect();
+        for name in names {
+            let path = self.layout.backup_path(&name);
+            if path.exists() {
+                warn!("Backup {} already exists, not importing it", name);
+                continue;
+            }
+            try!(fs::create_dir_all(path.parent().unwrap()));
+            try!(fs::copy(src.join("backups").join(format!("{}.backup", name)), path));
+            backups += 1;
+        }
+        self.audit(
+            "delta-import",
+            format!("{} bundles, {} backups", bundles, backups)
+        );
+        Ok((bundles, backups))
+    }
+}
@@ -2,12 +2,15 @@ use prelude::*;
 
 use std::io;
 use std::path::PathBuf;
+use std::process::ExitStatus;
 
 use super::backup_file::BackupFileError;
 use super::backup::BackupError;
 use super::bundle_map::BundleMapError;
 use super::config::ConfigError;
 use super::metadata::InodeError;
+use super::usage::UsageCacheError;
+use super::audit::AuditLogError;
 
 
 quick_error!{
@@ -72,6 +75,18 @@ quick_error!{
             description("Bundle map error")
             display("Repository error: bundle map error\n\tcaused by: {}", err)
         }
+        UsageCache(err: UsageCacheError) {
+            from()
+            cause(err)
+            description("Usage cache error")
+            display("Repository error: usage cache error\n\tcaused by: {}", err)
+        }
+        AuditLog(err: AuditLogError) {
+            from()
+            cause(err)
+            description("Audit log error")
+            display("Repository error: audit log error\n\tcaused by: {}", err)
+        }
         Integrity(err: IntegrityError) {
             from()
             cause(err)
@@ -88,6 +103,12 @@ quick_error!{
             description("Failed to create a backup")
             display("Repository error: failed to create backup\n\tcaused by: {}", err)
         }
+        Compression(err: CompressionError) {
+            from()
+            cause(err)
+            description("Compression error")
+            display("Repository error: compression error\n\tcaused by: {}", err)
+        }
         Lock(err: LockError) {
             from()
             cause(err)
@@ -101,9 +122,41 @@ quick_error!{
             description("IO error")
             display("IO error: {}", err)
         }
+        NoSuchRemote(name: String) {
+            description("No such remote")
+            display("Repository error: there is no remote named {}", name)
+        }
+        ReadOnly {
+            description("Repository is read-only")
+            display("Repository error: the repository is a read-only clone")
+        }
+        NotInTrash(name: String) {
+            description("Backup is not in the trash")
+            display("Repository error: the trash does not contain a backup named {}", name)
+        }
+        BackupExists(name: String) {
+            description("Backup already exists")
+            display("Repository error: a backup named {} already exists", name)
+        }
+        Protected(name: String, days: u64) {
+            description("Backup is protected")
+            display("Repository error: the backup {} is younger than the minimal retention of {} days", name, days)
+        }
         NoSuchFileInBackup(backup: Backup, path: PathBuf) {
             description("No such file in backup")
             display("The backup does not contain the file {:?}", path)
         }
+        NotADirectory(path: String) {
+            description("Not a directory")
+            display("Repository error: {} is not a directory", path)
+        }
+        NotSeeded(path: PathBuf) {
+            description("Remote storage has not been seeded")
+            display("Repository error: the remote storage {:?} contains none of the bundles, it has not been seeded", path)
+        }
+        RemoteCommand(command: String, status: ExitStatus) {
+            description("Remote command failed")
+            display("Repository error: the remote command {:?} failed with {}", command, status)
+        }
     }
 }
@@ -0,0 +1,130 @@
+use prelude::*;
+
+use super::bundle_map::{self, BundleMap};
+use super::usage::{self, UsageCache};
+use super::backup_file;
+use bundledb::{self, Dictionary, CACHE_FILE_STRING, DICTIONARY_FILE_STRING};
+
+use chrono::prelude::*;
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+
+/// The kinds of repository files that can be checked on their own
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    Bundle,
+    Dictionary,
+    BundleCache,
+    Backup,
+    BundleMap,
+    UsageCache
+}
+
+impl FileKind {
+    /// Detects the kind of the file from the magic string at its start
+    pub fn detect<P: AsRef<Path>>(path: P) -> Result<Option<Self>, io::Error> {
+        let mut file = try!(File::open(path));
+        let mut magic = [0u8; 7];
+        try!(file.read_exact(&mut magic));
+        Ok(if magic == bundledb::HEADER_STRING {
+            Some(FileKind::Bundle)
+        } else if magic == DICTIONARY_FILE_STRING {
+            Some(FileKind::Dictionary)
+        } else if magic == CACHE_FILE_STRING {
+            Some(FileKind::BundleCache)
+        } else if magic == backup_file::HEADER_STRING {
+            Some(FileKind::Backup)
+        } else if magic == bundle_map::HEADER_STRING {
+            Some(FileKind::BundleMap)
+        } else if magic == usage::HEADER_STRING {
+            Some(FileKind::UsageCache)
+        } else {
+            None
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            FileKind::Bundle => "bundle",
+            FileKind::Dictionary => "dictionary",
+            FileKind::BundleCache => "bundle cache",
+            FileKind::Backup => "backup",
+            FileKind::BundleMap => "bundle map",
+            FileKind::UsageCache => "usage cache",
+        }
+    }
+}
+
+
+/// Fully decodes a single repository file and returns a short description of its contents
+///
+/// This does not need the repository, so it also works on files that have been copied out of a
+/// damaged repository. Encrypted contents can only be checked if `crypto` has the secret key.
+/// The contents of bundles that are compressed with a dictionary are not decoded as the
+/// dictionary is stored in a separate file.
+pub fn check_file(
+    path: &Path,
+    kind: FileKind,
+    crypto: Arc<Mutex<Crypto>>,
+) -> Result<String, RepositoryError> {
+    Ok(match kind {
+        FileKind::Bundle => {
+            let mut reader = try!(
+                BundleReader::load(path.to_path_buf(), crypto).map_err(BundleDbError::Reader)
+            );
+            let full = reader.info.dictionary.is_none();
+            try!(reader.check(full).map_err(BundleDbError::Reader));
+            if !full {
+                warn!("The bundle uses a dictionary, its contents have not been checked");
+            }
+            format!(
+                "bundle {}: {} chunks, {} raw, {} encoded",
+                reader.info.id,
+                reader.info.chunk_count,
+                to_file_size(reader.info.raw_size as u64),
+                to_file_size(reader.info.encoded_size as u64)
+            )
+        }
+        FileKind::Dictionary => {
+            let dictionary = try!(
+                Dictionary::load(path, &crypto).map_err(BundleDbError::Dictionary)
+            );
+            format!(
+                "dictionary {}: {}",
+                dictionary.id,
+                to_file_size(dictionary.data.len() as u64)
+            )
+        }
+        FileKind::BundleCache => {
+            let bundles = try!(StoredBundle::read_list_from(path).map_err(BundleDbError::Cache));
+            format!("bundle cache with {} bundles", bundles.len())
+        }
+        FileKind::Backup => {
+            let backup = try!(Backup::read_from(&crypto.lock().unwrap(), path));
+            format!(
+                "backup of {}:{} from {}: {} files, {} directories",
+                backup.host,
+                backup.path,
+                Local.timestamp(backup.timestamp, 0).to_rfc2822(),
+                backup.file_count,
+                backup.dir_count
+            )
+        }
+        FileKind::BundleMap => {
+            let map = try!(BundleMap::load(path));
+            format!("bundle map with {} bundles", map.len())
+        }
+        FileKind::UsageCache => {
+            let cache = try!(UsageCache::load(path));
+            format!(
+                "usage cache of {} backups and {} chunks",
+                cache.backups.len(),
+                cache.chunks.len()
+            )
+        }
+    })
+}
@@ -0,0 +1,159 @@
+use prelude::*;
+
+use chrono::prelude::*;
+use regex::{self, Regex};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+
+
+/// The latest backup matching a freshness pattern of the health config
+#[derive(Debug, Clone)]
+pub struct FreshnessStatus {
+    pub pattern: String,
+    /// Maximal age of the latest backup in hours
+    pub max_age: u64,
+    /// Name and date of the latest matching backup
+    pub latest: Option<(String, i64)>
+}
+
+impl FreshnessStatus {
+    /// Returns whether the latest matching backup is too old or there is none at all
+    pub fn is_overdue(&self, now: i64) -> bool {
+        match self.latest {
+            Some((_, date)) => now - date > self.max_age as i64 * 3600,
+            None => true,
+        }
+    }
+}
+
+
+/// Creates a regex matching backup names that start with the pattern
+///
+/// In the pattern, `*` matches any characters except `/` and `**` matches any characters.
+fn freshness_regex(pattern: &str) -> Regex {
+    let pattern = regex::escape(pattern)
+        .replace(r"\*\*", ".*")
+        .replace(r"\*", "[^/]*");
+    Regex::new(&format!("^{}", pattern)).unwrap()
+}
+
+
+impl HealthConfig {
+    /// Finds the latest backup for each freshness pattern
+    pub fn check_freshness(&self, backups: &HashMap<String, Backup>) -> Vec<FreshnessStatus> {
+        let mut result = Vec::with_capacity(self.freshness.len());
+        for (pattern, max_age) in &self.freshness {
+            let regex = freshness_regex(pattern);
+            let latest = backups
+                .iter()
+                .filter(|&(name, _)| regex.is_match(name))
+                .max_by_key(|&(_, backup)| backup.timestamp)
+                .map(|(name, backup)| (name.clone(), backup.timestamp));
+            result.push(FreshnessStatus {
+                pattern: pattern.clone(),
+                max_age: *max_age,
+                latest: latest
+            });
+        }
+        result
+    }
+}
+
+
+impl Repository {
+    /// Records the current time as the time of the last successful check of the repository
+    pub fn set_checked(&self) -> Result<(), RepositoryError> {
+        let mut file = try!(File::create(self.layout.last_check_path()));
+        try!(write!(file, "{}", Utc::now().timestamp()));
+        Ok(())
+    }
+
+    /// Returns the time of the last successful check of the repository if it has been recorded
+    pub fn get_last_check(&self) -> Option<i64> {
+        let mut data = String::new();
+        match File::open(self.layout.last_check_path()) {
+            Ok(mut file) => {
+                if file.read_to_string(&mut data).is_err() {
+                    return None;
+                }
+            }
+            Err(_) => return None,
+        }
+        data.trim().parse().ok()
+    }
+
+    /// Runs some quick checks on the repository and returns warnings on the problems found
+    ///
+    /// The checks only use information that is already loaded or very cheap to obtain so they can
+    /// run every time the repository is opened.
+    pub fn check_health(&self) -> Vec<String> {
+        let mut warnings = vec![];
+        let health = &self.config.health;
+        let now = Utc::now().timestamp();
+        if self.dirty {
+            warnings.push(
+                "The repository is marked as dirty as an operation has been interrupted, \
+                 run `zvault check` on it"
+                    .to_string()
+            );
+        }
+        if self.bundle_map.len() != self.bundles.len() {
+            warnings.push(format!(
+                "The bundle map contains {} bundles but the remote storage contains {}, \
+                 run `zvault check --repair` on the repository",
+                self.bundle_map.len(),
+                self.bundles.len()
+            ));
+        }
+        let chunk_count: usize = self.bundles.list_bundles().iter().map(|b| b.chunk_count).sum();
+        if self.index.len() > chunk_count {
+            warnings.push(format!(
+                "The index contains {} entries but the bundles only contain {} chunks, \
+                 run `zvault check --index --repair` on the repository",
+                self.index.len(),
+                chunk_count
+            ));
+        }
+        if health.max_lock_age > 0 {
+            match self.remote_locks.get_locks() {
+                Ok(locks) => {
+                    for lock in locks {
+                        if now - lock.date > health.max_lock_age as i64 * 3600 {
+                            warnings.push(format!(
+                                "The lock of process {} on {} has not been updated since {}, \
+                                 remove it from the remote locks folder if that process is gone",
+                                lock.processid,
+                                lock.hostname,
+                                Local.timestamp(lock.date, 0).to_rfc2822()
+                            ));
+                        }
+                    }
+                }
+                Err(err) => warnings.push(format!("Failed to read the remote locks: {}", err)),
+            }
+        }
+        if health.max_check_age > 0 {
+            match self.get_last_check() {
+                Some(date) => {
+                    if now - date > health.max_check_age as i64 * 86_400 {
+                        warnings.push(format!(
+                            "The repository has not been checked since {}, run `zvault check` \
+                             on it",
+                            Local.timestamp(date, 0).to_rfc2822()
+                        ));
+                    }
+                }
+                None => {
+                    warnings.push(
+                        "No successful check of the repository has been recorded, run \
+                         `zvault check` on it"
+                            .to_string()
+                    )
+                }
+            }
+        }
+        warnings
+    }
+}
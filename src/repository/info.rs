@@ -1,6 +1,9 @@
 use prelude::*;
 
-use std::collections::{HashMap, VecDeque};
+use std::cmp::min;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use super::backup::file_extension;
 
 
 pub struct BundleAnalysis {
@@ -26,6 +29,106 @@ impl BundleAnalysis {
     }
 }
 
+/// Space that became reclaimable between two usage analyses
+pub struct ReclaimReport {
+    /// Size of the data in the changed bundles that is no longer used
+    pub reclaimable_size: u64,
+    /// Size of the data that vacuum has to rewrite to reclaim that space
+    pub rewrite_size: u64,
+    /// Bundles that lost data, with their new usage ratio
+    pub bundles: Vec<(BundleId, f32)>
+}
+
+impl ReclaimReport {
+    pub fn new(
+        before: &HashMap<u64, BundleAnalysis>,
+        after: &HashMap<u64, BundleAnalysis>,
+    ) -> Self {
+        let mut report = ReclaimReport {
+            reclaimable_size: 0,
+            rewrite_size: 0,
+            bundles: vec![]
+        };
+        for (id, bundle) in after {
+            let changed = match before.get(id) {
+                Some(old) => old.used_raw_size > bundle.used_raw_size,
+                None => false
+            };
+            if changed {
+                report.reclaimable_size += bundle.get_unused_size() as u64;
+                report.rewrite_size += bundle.get_used_size() as u64;
+                report.bundles.push((bundle.info.id.clone(), bundle.get_usage_ratio()));
+            }
+        }
+        report.bundles.sort_by(|a, b| a.0.cmp(&b.0));
+        report
+    }
+
+    /// The smallest vacuum ratio in percent that rewrites all changed bundles
+    pub fn vacuum_ratio(&self) -> usize {
+        let max_ratio = self.bundles.iter().fold(0.0f32, |max, &(_, ratio)| max.max(ratio));
+        (max_ratio * 100.0).ceil() as usize
+    }
+}
+
+/// Hosts beyond this number are combined into one group
+const MAX_HOSTS: usize = 64;
+
+/// Data that is referenced by exactly one set of hosts
+pub struct HostShare {
+    pub hosts: Vec<String>,
+    pub chunk_count: usize,
+    pub raw_size: u64,
+    pub encoded_size: u64 // Estimated from the compression ratio of the bundles
+}
+
+pub struct HostAnalysis {
+    /// The hosts with the raw and encoded size of all distinct chunks they reference
+    pub hosts: Vec<(String, u64, u64)>,
+    /// The data grouped by the hosts that reference it, largest first
+    pub shares: Vec<HostShare>
+}
+
+impl HostAnalysis {
+    /// The encoded size of all data referenced by the backups
+    pub fn stored_size(&self) -> u64 {
+        self.shares.iter().map(|share| share.encoded_size).sum()
+    }
+
+    /// The encoded size that would be needed if every host had its own repository
+    pub fn separate_size(&self) -> u64 {
+        self.hosts.iter().map(|&(_, _, encoded)| encoded).sum()
+    }
+}
+
+/// Bundles created by one host, bundles of older versions have no host
+pub struct OriginUsage {
+    pub host: Option<String>,
+    pub bundle_count: usize,
+    pub encoded_size: u64,
+    /// Estimated encoded size of the data in the bundles that is still used
+    pub used_size: u64
+}
+
+/// Where the chunks of a file are stored, relative to the backup that contains it
+#[derive(Default)]
+pub struct FileChunkAnalysis {
+    /// All chunks of the file as (count, raw size)
+    pub total: (usize, u64),
+    /// Chunks stored in bundles created for the backup, i.e. new data at backup time
+    pub new: (usize, u64),
+    /// Chunks that were already stored in bundles of other backups
+    pub existing: (usize, u64),
+    /// Chunks that occur more than once in the file, only the first occurrence is counted above
+    pub repeated: (usize, u64),
+    /// Chunks in bundles without a recorded backup, e.g. of older versions or rewritten by vacuum
+    pub unknown: (usize, u64),
+    /// Bundles that contain the chunks with the backup they were created for, the number of
+    /// chunks and their raw size, most chunks first
+    pub bundles: Vec<(BundleId, Option<String>, usize, u64)>
+}
+
+
 pub struct RepositoryInfo {
     pub bundle_count: usize,
     pub encoded_data_size: u64,
@@ -40,35 +143,29 @@ pub struct RepositoryInfo {
 
 
 impl Repository {
-    fn mark_used(
-        &self,
-        bundles: &mut HashMap<u32, BundleAnalysis>,
-        chunks: &[Chunk],
-    ) -> Result<bool, RepositoryError> {
-        let mut new = false;
-        for &(hash, len) in chunks {
-            if let Some(pos) = self.index.get(&hash) {
-                if let Some(bundle) = bundles.get_mut(&pos.bundle) {
-                    if !bundle.chunk_usage.get(pos.chunk as usize) {
-                        new = true;
-                        bundle.chunk_usage.set(pos.chunk as usize);
-                        bundle.used_raw_size += len as usize;
-                    }
-                } else {
-                    return Err(IntegrityError::MissingBundleId(pos.bundle).into());
-                }
-            } else {
-                return Err(IntegrityError::MissingChunk(hash).into());
-            }
-        }
-        Ok(new)
+    pub fn analyze_usage(&mut self) -> Result<HashMap<u64, BundleAnalysis>, RepositoryError> {
+        self.analyze_usage_of(true)
+    }
+
+    /// Analyzes the usage as if the backups in the trash had already expired
+    ///
+    /// Comparing the results before and after removing backups shows the space that becomes
+    /// reclaimable once the removed backups have left the trash.
+    pub fn analyze_usage_ignoring_trash(
+        &mut self,
+    ) -> Result<HashMap<u64, BundleAnalysis>, RepositoryError> {
+        self.analyze_usage_of(false)
     }
 
-    pub fn analyze_usage(&mut self) -> Result<HashMap<u32, BundleAnalysis>, RepositoryError> {
+    fn analyze_usage_of(
+        &mut self,
+        include_trash: bool,
+    ) -> Result<HashMap<u64, BundleAnalysis>, RepositoryError> {
         if self.dirty {
             return Err(RepositoryError::Dirty);
         }
         try!(self.set_dirty());
+        let cache = try!(self.get_chunk_usage(include_trash));
         let mut usage = HashMap::new();
         for (id, bundle) in self.bundle_map.bundles() {
             let bundle = try!(self.bundles.get_bundle_info(&bundle).ok_or_else(|| {
@@ -83,40 +180,244 @@ impl Repository {
                 }
             );
         }
+        for (hash, &(_refs, len, _owners)) in &cache.chunks {
+            let pos = try!(self.index.get(hash).ok_or_else(|| IntegrityError::MissingChunk(*hash)));
+            let bundle = try!(usage.get_mut(&pos.bundle).ok_or_else(|| {
+                IntegrityError::MissingBundleId(pos.bundle)
+            }));
+            bundle.chunk_usage.set(pos.chunk as usize);
+            bundle.used_raw_size += len as usize;
+        }
+        self.dirty = false;
+        Ok(usage)
+    }
+
+    fn count_new_chunks(
+        &self,
+        seen: &mut HashSet<Hash>,
+        ratios: &HashMap<u64, f32>,
+        chunks: &[Chunk],
+    ) -> Result<(usize, u64, u64), RepositoryError> {
+        let mut count = 0;
+        let mut raw_size = 0;
+        let mut encoded_size = 0.0;
+        for &(hash, len) in chunks {
+            if !seen.insert(hash) {
+                continue;
+            }
+            let pos = try!(self.index.get(&hash).ok_or_else(|| IntegrityError::MissingChunk(hash)));
+            let ratio = try!(ratios.get(&pos.bundle).ok_or_else(|| {
+                IntegrityError::MissingBundleId(pos.bundle)
+            }));
+            count += 1;
+            raw_size += len as u64;
+            encoded_size += len as f32 * *ratio;
+        }
+        Ok((count, raw_size, encoded_size as u64))
+    }
+
+    /// Breaks down the data of all backups by file extension
+    ///
+    /// Every chunk is attributed to the first file that references it, its encoded size is
+    /// estimated from the compression ratio of its bundle. All distinct file versions count as
+    /// modified data.
+    pub fn analyze_extensions(
+        &mut self,
+    ) -> Result<HashMap<String, ExtensionStats>, RepositoryError> {
         let backups = try!(self.get_all_backups());
-        let mut todo = VecDeque::new();
-        for (_name, backup) in backups {
-            todo.push_back(backup.root);
+        let mut ratios = HashMap::new();
+        for (id, bundle) in self.bundle_map.bundles() {
+            let bundle = try!(self.bundles.get_bundle_info(&bundle).ok_or_else(|| {
+                IntegrityError::MissingBundle(bundle)
+            }));
+            ratios.insert(
+                id,
+                bundle.info.encoded_size as f32 / bundle.info.raw_size as f32
+            );
         }
+        let mut seen = HashSet::new();
+        let mut stats = HashMap::new();
+        let mut todo: VecDeque<_> = backups.into_iter().map(|(_, backup)| backup.root).collect();
         while let Some(chunks) = todo.pop_back() {
-            if !try!(self.mark_used(&mut usage, &chunks)) {
+            // Unchanged inodes have already been counted
+            if try!(self.count_new_chunks(&mut seen, &ratios, &chunks)).0 == 0 {
                 continue;
             }
             let inode = try!(self.get_inode(&chunks));
-            // Mark the content chunks as used
-            match inode.data {
-                None |
-                Some(FileData::Inline(_)) => (),
-                Some(FileData::ChunkedDirect(chunks)) => {
-                    try!(self.mark_used(&mut usage, &chunks));
-                }
-                Some(FileData::ChunkedIndirect(chunks)) => {
-                    if try!(self.mark_used(&mut usage, &chunks)) {
-                        let chunk_data = try!(self.get_data(&chunks));
+            if inode.file_type == FileType::File {
+                let (_, raw_size, encoded_size) = match inode.data {
+                    None |
+                    Some(FileData::Inline(_)) => (0, 0, 0),
+                    Some(FileData::ChunkedDirect(ref chunks)) => {
+                        try!(self.count_new_chunks(&mut seen, &ratios, chunks))
+                    }
+                    Some(FileData::ChunkedIndirect(ref chunks)) => {
+                        let chunk_data = try!(self.get_data(chunks));
                         let chunks = ChunkList::read_from(&chunk_data);
-                        try!(self.mark_used(&mut usage, &chunks));
+                        try!(self.count_new_chunks(&mut seen, &ratios, &chunks))
                     }
-                }
+                };
+                let entry = stats.entry(file_extension(&inode.name)).or_insert_with(
+                    ExtensionStats::default
+                );
+                entry.file_count += 1;
+                entry.total_data_size += inode.size;
+                entry.changed_data_size += inode.size;
+                entry.deduplicated_data_size += raw_size;
+                entry.encoded_data_size += encoded_size;
             }
-            // Put children in todo
             if let Some(children) = inode.children {
                 for (_name, chunks) in children {
                     todo.push_back(chunks);
                 }
             }
         }
-        self.dirty = false;
-        Ok(usage)
+        Ok(stats)
+    }
+
+    fn mark_host(
+        owners: &mut HashMap<Hash, (u64, u32)>,
+        chunks: &[Chunk],
+        host: u64,
+    ) -> bool {
+        let mut new = false;
+        for &(hash, len) in chunks {
+            let entry = owners.entry(hash).or_insert((0, len));
+            if entry.0 & host == 0 {
+                entry.0 |= host;
+                new = true;
+            }
+        }
+        new
+    }
+
+    /// Attributes all chunks to the hosts whose backups reference them
+    ///
+    /// This shows how much data is unique to a host and how much is shared between hosts, i.e.
+    /// how much the deduplication across hosts saves compared to separate repositories. Encoded
+    /// sizes are estimated from the compression ratios of the bundles.
+    pub fn analyze_hosts(&mut self) -> Result<HostAnalysis, RepositoryError> {
+        let backups = try!(self.get_all_backups());
+        let mut host_names: Vec<_> = backups.values().map(|b| b.host.clone()).collect();
+        host_names.sort();
+        host_names.dedup();
+        let host_bits: HashMap<_, _> = host_names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.clone(), 1u64 << min(i, MAX_HOSTS - 1)))
+            .collect();
+        if host_names.len() > MAX_HOSTS {
+            warn!(
+                "The repository contains more than {} hosts, combining the rest",
+                MAX_HOSTS - 1
+            );
+            host_names.truncate(MAX_HOSTS - 1);
+            host_names.push("(other hosts)".to_string());
+        }
+        let mut owners = HashMap::new();
+        for backup in ProgressIter::new("scanning backups", backups.len(), backups.values()) {
+            let host = host_bits[&backup.host];
+            let mut todo = vec![backup.root.clone()];
+            while let Some(chunks) = todo.pop() {
+                // Inodes that have already been seen for this host are skipped with their children
+                if !Self::mark_host(&mut owners, &chunks, host) {
+                    continue;
+                }
+                let inode = try!(self.get_inode(&chunks));
+                match inode.data {
+                    None |
+                    Some(FileData::Inline(_)) => (),
+                    Some(FileData::ChunkedDirect(ref chunks)) => {
+                        Self::mark_host(&mut owners, chunks, host);
+                    }
+                    Some(FileData::ChunkedIndirect(ref chunks)) => {
+                        if Self::mark_host(&mut owners, chunks, host) {
+                            let chunk_data = try!(self.get_data(chunks));
+                            let chunks = ChunkList::read_from(&chunk_data);
+                            Self::mark_host(&mut owners, &chunks, host);
+                        }
+                    }
+                }
+                if let Some(children) = inode.children {
+                    todo.extend(children.into_iter().map(|(_, chunks)| chunks));
+                }
+            }
+        }
+        let mut ratios = HashMap::new();
+        for (id, bundle) in self.bundle_map.bundles() {
+            let bundle = try!(self.bundles.get_bundle_info(&bundle).ok_or_else(|| {
+                IntegrityError::MissingBundle(bundle)
+            }));
+            ratios.insert(
+                id,
+                bundle.info.encoded_size as f32 / bundle.info.raw_size as f32
+            );
+        }
+        let mut hosts: Vec<_> = host_names.iter().map(|name| (name.clone(), 0, 0.0)).collect();
+        let mut shares = HashMap::<u64, (usize, u64, f32)>::new();
+        for (hash, (mask, len)) in owners {
+            let pos = try!(self.index.get(&hash).ok_or_else(|| IntegrityError::MissingChunk(hash)));
+            let ratio = try!(ratios.get(&pos.bundle).ok_or_else(|| {
+                IntegrityError::MissingBundleId(pos.bundle)
+            }));
+            let encoded = len as f32 * *ratio;
+            let share = shares.entry(mask).or_insert((0, 0, 0.0));
+            share.0 += 1;
+            share.1 += len as u64;
+            share.2 += encoded;
+            for (i, host) in hosts.iter_mut().enumerate() {
+                if mask & (1u64 << i) != 0 {
+                    host.1 += len as u64;
+                    host.2 += encoded;
+                }
+            }
+        }
+        let mut shares: Vec<_> = shares
+            .into_iter()
+            .map(|(mask, (count, raw, encoded))| {
+                HostShare {
+                    hosts: host_names
+                        .iter()
+                        .enumerate()
+                        .filter(|&(i, _)| mask & (1u64 << i) != 0)
+                        .map(|(_, name)| name.clone())
+                        .collect(),
+                    chunk_count: count,
+                    raw_size: raw,
+                    encoded_size: encoded as u64
+                }
+            })
+            .collect();
+        shares.sort_by(|a, b| b.raw_size.cmp(&a.raw_size));
+        Ok(HostAnalysis {
+            hosts: hosts
+                .into_iter()
+                .map(|(name, raw, encoded)| (name, raw, encoded as u64))
+                .collect(),
+            shares: shares
+        })
+    }
+
+    /// Groups the stored bundles by the hosts that created them, largest first
+    pub fn analyze_origins(&mut self) -> Result<Vec<OriginUsage>, RepositoryError> {
+        let usage = try!(self.analyze_usage());
+        let mut origins = HashMap::<Option<String>, OriginUsage>::new();
+        for bundle in usage.values() {
+            let origin = origins.entry(bundle.info.host.clone()).or_insert_with(|| {
+                OriginUsage {
+                    host: bundle.info.host.clone(),
+                    bundle_count: 0,
+                    encoded_size: 0,
+                    used_size: 0
+                }
+            });
+            origin.bundle_count += 1;
+            origin.encoded_size += bundle.info.encoded_size as u64;
+            origin.used_size += bundle.get_used_size() as u64;
+        }
+        let mut origins: Vec<_> = origins.into_iter().map(|(_, origin)| origin).collect();
+        origins.sort_by(|a, b| b.encoded_size.cmp(&a.encoded_size));
+        Ok(origins)
     }
 
     #[inline]
@@ -129,6 +430,220 @@ impl Repository {
         self.bundles.get_bundle_info(bundle)
     }
 
+    #[inline]
+    pub fn get_bundle_chunk_list(&self, bundle: &BundleId) -> Result<ChunkList, RepositoryError> {
+        Ok(try!(self.bundles.get_chunk_list(bundle)))
+    }
+
+    /// Reads a chunk of the bundle by its position, no matter whether the index points to it
+    #[inline]
+    pub fn get_bundle_chunk(
+        &mut self,
+        bundle: &BundleId,
+        chunk: usize,
+    ) -> Result<Vec<u8>, RepositoryError> {
+        Ok(try!(self.bundles.get_chunk(bundle, chunk)))
+    }
+
+    #[inline]
+    pub fn load_bundle_reader(&self, bundle: &BundleId) -> Result<BundleReader, RepositoryError> {
+        Ok(try!(self.bundles.load_bundle_reader(bundle)))
+    }
+
+    fn find_affected_recurse(
+        &mut self,
+        chunks: &ChunkList,
+        hashes: &HashSet<Hash>,
+        cache: &mut HashMap<ChunkList, Vec<String>>,
+    ) -> Result<Vec<String>, RepositoryError> {
+        // Subtrees are shared between backups, so the paths relative to the subtree are cached
+        if let Some(paths) = cache.get(chunks) {
+            return Ok(paths.clone());
+        }
+        let uses_bundle = |chunks: &[Chunk]| chunks.iter().any(|c| hashes.contains(&c.0));
+        let mut affected = uses_bundle(chunks);
+        let inode = match self.get_inode(chunks) {
+            Ok(inode) => Some(inode),
+            // The contents of the subtree can not be listed anymore
+            Err(_) if affected => None,
+            Err(err) => return Err(err),
+        };
+        let mut paths = vec![];
+        if let Some(inode) = inode {
+            match inode.data {
+                None |
+                Some(FileData::Inline(_)) => (),
+                Some(FileData::ChunkedDirect(ref chunks)) => affected |= uses_bundle(chunks),
+                Some(FileData::ChunkedIndirect(ref chunks)) => {
+                    affected |= uses_bundle(chunks);
+                    match self.get_data(chunks) {
+                        Ok(data) => affected |= uses_bundle(&ChunkList::read_from(&data)),
+                        Err(_) if affected => (),
+                        Err(err) => return Err(err),
+                    }
+                }
+            }
+            if let Some(children) = inode.children {
+                for (name, chunks) in children {
+                    for path in try!(self.find_affected_recurse(&chunks, hashes, cache)) {
+                        paths.push(format!("/{}{}", name, path));
+                    }
+                }
+            }
+        }
+        if affected {
+            paths.insert(0, String::new());
+        }
+        cache.insert(chunks.clone(), paths.clone());
+        Ok(paths)
+    }
+
+    /// Returns all paths in all backups that use chunks of the given bundle as (backup, path)
+    ///
+    /// A path is affected if its metadata or its data is stored in the bundle. If the contents of
+    /// a directory can not be listed anymore, only the directory itself is returned.
+    pub fn find_affected_paths(
+        &mut self,
+        bundle: &BundleId,
+    ) -> Result<Vec<(String, String)>, RepositoryError> {
+        let num = match self.bundle_map.bundles().into_iter().find(|&(_, ref id)| id == bundle) {
+            Some((num, _)) => num,
+            None => return Err(IntegrityError::MissingBundle(bundle.clone()).into()),
+        };
+        let hashes: HashSet<Hash> = self.index
+            .iter()
+            .filter(|&(_, location)| location.bundle == num)
+            .map(|(hash, _)| *hash)
+            .collect();
+        let backups = match self.get_all_backups() {
+            Ok(backup_map) => backup_map,
+            Err(RepositoryError::BackupFile(BackupFileError::PartialBackupsList(backup_map,
+                                                                                _failed))) => {
+                warn!("Some backups could not be read, ignoring them");
+                backup_map
+            }
+            Err(err) => return Err(err),
+        };
+        let mut names: Vec<_> = backups.keys().cloned().collect();
+        names.sort();
+        let mut cache = HashMap::new();
+        let mut affected = vec![];
+        for name in ProgressIter::new("scanning backups", names.len(), names.into_iter()) {
+            let root = backups[&name].root.clone();
+            for path in try!(self.find_affected_recurse(&root, &hashes, &mut cache)) {
+                let path = if path.is_empty() { "/".to_string() } else { path };
+                affected.push((name.clone(), path));
+            }
+        }
+        Ok(affected)
+    }
+
+    /// Analyzes whether the chunks of the file were new when the backup `backup_name` was created
+    ///
+    /// The bundles record the backup they were created for, so a chunk was new if it is stored in
+    /// a bundle of this backup and deduplicated otherwise.
+    pub fn analyze_file_chunks(
+        &mut self,
+        backup_name: &str,
+        inode: &Inode,
+    ) -> Result<FileChunkAnalysis, RepositoryError> {
+        let chunks = match inode.data {
+            Some(FileData::ChunkedDirect(ref chunks)) => chunks.clone(),
+            Some(FileData::ChunkedIndirect(ref chunks)) => {
+                let chunk_data = try!(self.get_data(chunks));
+                ChunkList::read_from(&chunk_data)
+            }
+            _ => ChunkList::new(),
+        };
+        let mut analysis = FileChunkAnalysis::default();
+        let mut seen = HashSet::new();
+        let mut bundles = HashMap::<u64, (BundleId, Option<String>, usize, u64)>::new();
+        for &(hash, len) in chunks.iter() {
+            let len = len as u64;
+            analysis.total.0 += 1;
+            analysis.total.1 += len;
+            if !seen.insert(hash) {
+                analysis.repeated.0 += 1;
+                analysis.repeated.1 += len;
+                continue;
+            }
+            let pos = try!(self.index.get(&hash).ok_or_else(|| IntegrityError::MissingChunk(hash)));
+            if !bundles.contains_key(&pos.bundle) {
+                let bundle_id = try!(self.get_bundle_id(pos.bundle));
+                let bundle = try!(self.bundles.get_bundle_info(&bundle_id).ok_or_else(|| {
+                    IntegrityError::MissingBundle(bundle_id.clone())
+                }));
+                bundles.insert(pos.bundle, (bundle_id, bundle.info.backup.clone(), 0, 0));
+            }
+            let bundle = bundles.get_mut(&pos.bundle).unwrap();
+            bundle.2 += 1;
+            bundle.3 += len;
+            let class = match bundle.1 {
+                Some(ref backup) if backup == backup_name => &mut analysis.new,
+                Some(_) => &mut analysis.existing,
+                None => &mut analysis.unknown,
+            };
+            class.0 += 1;
+            class.1 += len;
+        }
+        analysis.bundles = bundles.into_iter().map(|(_, bundle)| bundle).collect();
+        analysis.bundles.sort_by(|a, b| b.2.cmp(&a.2));
+        Ok(analysis)
+    }
+
+    /// Returns the number and the encoded size of the bundles per storage class that restoring the
+    /// inode tree reads data from
+    ///
+    /// Only data bundles are kept in colder tiers, so this only reads metadata. Nothing is read at
+    /// all if all bundles are kept in the standard tier.
+    pub fn analyze_storage_classes(
+        &mut self,
+        inode: &Inode,
+    ) -> Result<HashMap<StorageClass, (usize, u64)>, RepositoryError> {
+        let mut classes = HashMap::new();
+        if self.list_bundles().iter().all(|b| b.storage_class == StorageClass::Standard) {
+            return Ok(classes);
+        }
+        let mut bundles = HashSet::new();
+        try!(self.find_data_bundles_recurse(inode, &mut bundles));
+        for num in bundles {
+            let bundle_id = try!(self.get_bundle_id(num));
+            let bundle = try!(self.bundles.get_bundle_info(&bundle_id).ok_or_else(|| {
+                IntegrityError::MissingBundle(bundle_id.clone())
+            }));
+            let class = classes.entry(bundle.info.storage_class).or_insert((0, 0));
+            class.0 += 1;
+            class.1 += bundle.info.encoded_size as u64;
+        }
+        Ok(classes)
+    }
+
+    fn find_data_bundles_recurse(
+        &mut self,
+        inode: &Inode,
+        bundles: &mut HashSet<u64>,
+    ) -> Result<(), RepositoryError> {
+        let chunks = match inode.data {
+            Some(FileData::ChunkedDirect(ref chunks)) => chunks.clone(),
+            Some(FileData::ChunkedIndirect(ref chunks)) => {
+                let chunk_data = try!(self.get_data(chunks));
+                ChunkList::read_from(&chunk_data)
+            }
+            _ => ChunkList::new(),
+        };
+        for &(hash, _) in chunks.iter() {
+            let pos = try!(self.index.get(&hash).ok_or_else(|| IntegrityError::MissingChunk(hash)));
+            bundles.insert(pos.bundle);
+        }
+        if let Some(ref children) = inode.children {
+            for chunks in children.values() {
+                let child = try!(self.get_inode(chunks));
+                try!(self.find_data_bundles_recurse(&child, bundles));
+            }
+        }
+        Ok(())
+    }
+
     pub fn info(&self) -> RepositoryInfo {
         let bundles = self.list_bundles();
         let encoded_data_size = bundles.iter().map(|b| b.encoded_size as u64).sum();
@@ -2,9 +2,15 @@ use prelude::*;
 
 use super::*;
 
+use bundledb::ChunkFetcher;
+
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::time::Duration;
 
+use crossbeam;
 use pbr::ProgressBar;
 
 
@@ -15,7 +21,7 @@ quick_error!{
             description("Missing chunk")
             display("Missing chunk: {}", hash)
         }
-        MissingBundleId(id: u32) {
+        MissingBundleId(id: u64) {
             description("Missing bundle")
             display("Missing bundle: {}", id)
         }
@@ -23,7 +29,7 @@ quick_error!{
             description("Missing bundle")
             display("Missing bundle: {}", id)
         }
-        NoSuchChunk(bundle: BundleId, chunk: u32) {
+        NoSuchChunk(bundle: BundleId, chunk: u64) {
             description("No such chunk")
             display("Bundle {} does not contain the chunk {}", bundle, chunk)
         }
@@ -43,9 +49,151 @@ quick_error!{
             description("Missing inode data")
             display("Missing inode data in: {:?}\n\tcaused by: {}", path, err)
         }
+        WrongChunkData(hash: Hash) {
+            description("Wrong chunk data")
+            display("The data of chunk {} does not match its hash", hash)
+        }
+        WrongFileSize(expected: u64, actual: u64) {
+            description("Wrong file size")
+            display("The restored data has {} bytes instead of {}", actual, expected)
+        }
+    }
+}
+
+/// The classes of problems found by `check_backup_files`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BackupFileProblem {
+    /// Backups whose root chunks are not in the index
+    MissingRoot,
+    /// Backups that are identical copies of another backup
+    Duplicate,
+    /// Meta bundles without any chunk used by a backup
+    OrphanedMeta
+}
+
+impl BackupFileProblem {
+    pub fn from_string(name: &str) -> Option<Self> {
+        match name {
+            "missing-roots" => Some(BackupFileProblem::MissingRoot),
+            "duplicates" => Some(BackupFileProblem::Duplicate),
+            "orphaned-meta" => Some(BackupFileProblem::OrphanedMeta),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            BackupFileProblem::MissingRoot => "missing-roots",
+            BackupFileProblem::Duplicate => "duplicates",
+            BackupFileProblem::OrphanedMeta => "orphaned-meta",
+        }
+    }
+}
+
+
+pub struct BackupFileReport {
+    /// Backups whose root chunks are missing
+    pub missing_roots: Vec<String>,
+    /// Duplicated backups as (duplicate, original)
+    pub duplicates: Vec<(String, String)>,
+    /// Meta bundles that are not used by any backup
+    pub orphaned_meta: Vec<BundleId>
+}
+
+
+fn check_chunks(
+    index: &Index<Hash, Location>,
+    checked: &mut Bitmap,
+    chunks: &[Chunk],
+    mark: bool,
+) -> Result<bool, RepositoryError> {
+    let mut new = false;
+    for &(hash, _len) in chunks {
+        if let Some(pos) = index.pos(&hash) {
+            new |= !checked.get(pos);
+            if mark {
+                checked.set(pos);
+            }
+        } else {
+            return Err(IntegrityError::MissingChunk(hash).into());
+        }
+    }
+    Ok(new)
+}
+
+
+/// Checks backup trees without modifying them so that several checkers can run in parallel
+///
+/// All checkers share the bitmap of checked chunks, so subtrees shared between backups are only
+/// checked once.
+struct TreeChecker<'a> {
+    index: &'a Index<Hash, Location>,
+    bundle_map: &'a BundleMap,
+    fetcher: ChunkFetcher<'a>,
+    checked: &'a Mutex<Bitmap>
+}
+
+impl<'a> TreeChecker<'a> {
+    fn get_data(&mut self, chunks: &[Chunk]) -> Result<Vec<u8>, RepositoryError> {
+        let mut data = Vec::with_capacity(chunks_size(chunks) as usize);
+        for &(hash, _len) in chunks {
+            let found = try!(self.index.get(&hash).ok_or_else(|| {
+                IntegrityError::MissingChunk(hash)
+            }));
+            let bundle_id = try!(self.bundle_map.get(found.bundle).ok_or_else(|| {
+                IntegrityError::MissingBundleId(found.bundle)
+            }));
+            data.extend_from_slice(&try!(
+                self.fetcher.get_chunk(&bundle_id, found.chunk as usize)
+            ));
+        }
+        Ok(data)
+    }
+
+    #[inline]
+    fn check_chunks(&self, chunks: &[Chunk], mark: bool) -> Result<bool, RepositoryError> {
+        check_chunks(self.index, &mut self.checked.lock().unwrap(), chunks, mark)
+    }
+
+    fn check_inode_contents(&mut self, inode: &Inode) -> Result<(), RepositoryError> {
+        match inode.data {
+            None |
+            Some(FileData::Inline(_)) => (),
+            Some(FileData::ChunkedDirect(ref chunks)) => {
+                try!(self.check_chunks(chunks, true));
+            }
+            Some(FileData::ChunkedIndirect(ref chunks)) => {
+                if try!(self.check_chunks(chunks, true)) {
+                    let chunk_data = try!(self.get_data(chunks));
+                    let chunks = ChunkList::read_from(&chunk_data);
+                    try!(self.check_chunks(&chunks, true));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_subtree(&mut self, path: PathBuf, chunks: &[Chunk]) -> Result<(), RepositoryError> {
+        match self.check_chunks(chunks, false) {
+            Ok(false) => return Ok(()),
+            Ok(true) => (),
+            Err(err) => return Err(IntegrityError::BrokenInode(path, Box::new(err)).into()),
+        }
+        let inode = try!(Inode::decode(&try!(self.get_data(chunks))));
+        if let Err(err) = self.check_inode_contents(&inode) {
+            return Err(IntegrityError::MissingInodeData(path, Box::new(err)).into());
+        }
+        if let Some(ref children) = inode.children {
+            for (name, chunks) in children {
+                try!(self.check_subtree(path.join(name), chunks));
+            }
+        }
+        try!(self.check_chunks(chunks, true));
+        Ok(())
     }
 }
 
+
 impl Repository {
     fn check_index_chunks(&self) -> Result<(), RepositoryError> {
         let mut progress = ProgressBar::new(self.index.len() as u64);
@@ -76,24 +224,14 @@ impl Repository {
         Ok(())
     }
 
+    #[inline]
     fn check_chunks(
         &self,
         checked: &mut Bitmap,
         chunks: &[Chunk],
         mark: bool,
     ) -> Result<bool, RepositoryError> {
-        let mut new = false;
-        for &(hash, _len) in chunks {
-            if let Some(pos) = self.index.pos(&hash) {
-                new |= !checked.get(pos);
-                if mark {
-                    checked.set(pos);
-                }
-            } else {
-                return Err(IntegrityError::MissingChunk(hash).into());
-            }
-        }
-        Ok(new)
+        check_chunks(&self.index, checked, chunks, mark)
     }
 
     fn check_inode_contents(
@@ -124,6 +262,7 @@ impl Repository {
         chunks: &[Chunk],
         checked: &mut Bitmap,
         repair: bool,
+        damaged: &mut Vec<String>,
     ) -> Result<Option<ChunkList>, RepositoryError> {
         let mut modified = false;
         match self.check_chunks(checked, chunks, false) {
@@ -141,6 +280,7 @@ impl Repository {
                     err
                 );
                 info!("Removing inode data");
+                damaged.push(format!("removed data of {:?}", path));
                 inode.data = Some(FileData::Inline(vec![].into()));
                 inode.size = 0;
                 modified = true;
@@ -152,7 +292,7 @@ impl Repository {
         if let Some(ref mut children) = inode.children {
             let mut removed = vec![];
             for (name, chunks) in children.iter_mut() {
-                match self.check_subtree(path.join(name), chunks, checked, repair) {
+                match self.check_subtree(path.join(name), chunks, checked, repair, damaged) {
                     Ok(None) => (),
                     Ok(Some(c)) => {
                         *chunks = c;
@@ -166,6 +306,7 @@ impl Repository {
                                 err
                             );
                             info!("Removing broken inode from backup");
+                            damaged.push(format!("removed {:?}", path.join(name)));
                             removed.push(name.to_string());
                             modified = true;
                         } else {
@@ -186,23 +327,20 @@ impl Repository {
         }
     }
 
-    fn evacuate_broken_backup(&self, name: &str) -> Result<(), RepositoryError> {
+    fn evacuate_broken_backup(
+        &self,
+        name: &str,
+        reason: &str,
+        damaged: &[String],
+    ) -> Result<(), RepositoryError> {
         warn!(
             "The backup {} was corrupted and needed to be modified.",
             name
         );
         let src = self.layout.backup_path(name);
-        let mut dst = src.with_extension("backup.broken");
-        let mut num = 1;
-        while dst.exists() {
-            dst = src.with_extension(&format!("backup.{}.broken", num));
-            num += 1;
-        }
-        if fs::rename(&src, &dst).is_err() {
-            try!(fs::copy(&src, &dst));
-            try!(fs::remove_file(&src));
-        }
-        info!("The original backup was renamed to {:?}", dst);
+        let dst = PathBuf::from("backups").join(format!("{}.backup", name));
+        let dst = try!(quarantine_file(&self.layout, &src, &dst, reason, damaged));
+        info!("The original backup was moved to quarantine at {:?}", dst);
         Ok(())
     }
 
@@ -221,18 +359,20 @@ impl Repository {
         };
         info!("Checking backup...");
         let mut checked = Bitmap::new(self.index.capacity());
+        let mut damaged = vec![];
         match self.check_subtree(
             Path::new("").to_path_buf(),
             &backup.root,
             &mut checked,
-            repair
+            repair,
+            &mut damaged
         ) {
             Ok(None) => (),
             Ok(Some(chunks)) => {
                 try!(self.flush());
                 backup.root = chunks;
                 backup.modified = true;
-                try!(self.evacuate_broken_backup(name));
+                try!(self.evacuate_broken_backup(name, "some inodes were corrupt", &damaged));
                 try!(self.save_backup(backup, name));
             }
             Err(err) => {
@@ -242,7 +382,7 @@ impl Repository {
                         name,
                         err
                     );
-                    try!(self.evacuate_broken_backup(name));
+                    try!(self.evacuate_broken_backup(name, "the root inode was corrupt", &[]));
                 } else {
                     return Err(err);
                 }
@@ -266,6 +406,7 @@ impl Repository {
         };
         info!("Checking inode...");
         let mut checked = Bitmap::new(self.index.capacity());
+        let mut damaged = vec![];
         let mut inodes = try!(self.get_backup_path(backup, path));
         let mut inode = inodes.pop().unwrap();
         let mut modified = false;
@@ -277,6 +418,7 @@ impl Repository {
                     err
                 );
                 info!("Removing inode data");
+                damaged.push(format!("removed data of {:?}", path));
                 inode.data = Some(FileData::Inline(vec![].into()));
                 inode.size = 0;
                 modified = true;
@@ -289,7 +431,8 @@ impl Repository {
         if let Some(ref mut children) = inode.children {
             let mut removed = vec![];
             for (name, chunks) in children.iter_mut() {
-                match self.check_subtree(path.join(name), chunks, &mut checked, repair) {
+                let child = path.join(name);
+                match self.check_subtree(child, chunks, &mut checked, repair, &mut damaged) {
                     Ok(None) => (),
                     Ok(Some(c)) => {
                         *chunks = c;
@@ -303,6 +446,7 @@ impl Repository {
                                 err
                             );
                             info!("Removing broken inode from backup");
+                            damaged.push(format!("removed {:?}", path.join(name)));
                             removed.push(name.to_string());
                             modified = true;
                         } else {
@@ -325,13 +469,64 @@ impl Repository {
             try!(self.flush());
             backup.root = chunks;
             backup.modified = true;
-            try!(self.evacuate_broken_backup(name));
+            try!(self.evacuate_broken_backup(name, "some inodes were corrupt", &damaged));
             try!(self.save_backup(backup, name));
         }
         Ok(())
     }
 
-    pub fn check_backups(&mut self, repair: bool) -> Result<(), RepositoryError> {
+    /// Checks the backups using the given number of threads
+    ///
+    /// Without `repair` all broken backups are reported and the first error is returned.
+    fn check_backups_parallel(
+        &self,
+        backup_map: &HashMap<String, Backup>,
+        threads: usize,
+    ) -> Result<(), RepositoryError> {
+        let checked = Mutex::new(Bitmap::new(self.index.capacity()));
+        let failed = Mutex::new(vec![]);
+        {
+            let backups = Mutex::new(
+                ProgressIter::new("checking backups", backup_map.len(), backup_map.iter()).fuse()
+            );
+            crossbeam::scope(|scope| {
+                for num in 0..max(threads, 1) {
+                    let mut checker = TreeChecker {
+                        index: &self.index,
+                        bundle_map: &self.bundle_map,
+                        fetcher: self.bundles.fetcher(),
+                        checked: &checked
+                    };
+                    let (backups, failed) = (&backups, &failed);
+                    scope.spawn(move || {
+                        pin_worker(num);
+                        loop {
+                            let next = backups.lock().unwrap().next();
+                            let (name, backup) = match next {
+                                Some(next) => next,
+                                None => break,
+                            };
+                            let path = PathBuf::from(format!("{}::", name));
+                            if let Err(err) = checker.check_subtree(path, &backup.root) {
+                                failed.lock().unwrap().push((name.clone(), err));
+                            }
+                        }
+                    });
+                }
+            });
+        }
+        let mut failed = failed.into_inner().unwrap();
+        failed.sort_by(|a, b| a.0.cmp(&b.0));
+        if failed.is_empty() {
+            return Ok(());
+        }
+        for &(ref name, ref err) in &failed[1..] {
+            error!("The backup {} is broken\n\tcaused by: {}", name, err);
+        }
+        Err(failed.swap_remove(0).1)
+    }
+
+    pub fn check_backups(&mut self, repair: bool, threads: usize) -> Result<(), RepositoryError> {
         let _lock = if repair {
             try!(self.write_mode());
             Some(self.lock(false))
@@ -339,7 +534,6 @@ impl Repository {
             None
         };
         info!("Checking backups...");
-        let mut checked = Bitmap::new(self.index.capacity());
         let backup_map = match self.get_all_backups() {
             Ok(backup_map) => backup_map,
             Err(RepositoryError::BackupFile(BackupFileError::PartialBackupsList(backup_map,
@@ -349,22 +543,30 @@ impl Repository {
             }
             Err(err) => return Err(err),
         };
+        // Repairing modifies the backups, so only the plain check runs in parallel
+        if !repair {
+            return self.check_backups_parallel(&backup_map, threads);
+        }
+        let mut checked = Bitmap::new(self.index.capacity());
         for (name, mut backup) in
             ProgressIter::new("checking backups", backup_map.len(), backup_map.into_iter())
         {
             let path = format!("{}::", name);
+            let mut damaged = vec![];
             match self.check_subtree(
                 Path::new(&path).to_path_buf(),
                 &backup.root,
                 &mut checked,
-                repair
+                repair,
+                &mut damaged
             ) {
                 Ok(None) => (),
                 Ok(Some(chunks)) => {
                     try!(self.flush());
                     backup.root = chunks;
                     backup.modified = true;
-                    try!(self.evacuate_broken_backup(&name));
+                    let reason = "some inodes were corrupt";
+                    try!(self.evacuate_broken_backup(&name, reason, &damaged));
                     try!(self.save_backup(&backup, &name));
                 }
                 Err(err) => {
@@ -374,7 +576,8 @@ impl Repository {
                             name,
                             err
                         );
-                        try!(self.evacuate_broken_backup(&name));
+                        let reason = "the root inode was corrupt";
+                        try!(self.evacuate_broken_backup(&name, reason, &[]));
                     } else {
                         return Err(err);
                     }
@@ -453,19 +656,29 @@ impl Repository {
                 try!(self.index.set(
                     &hash,
                     &Location {
-                        bundle: num as u32,
-                        chunk: i as u32
+                        bundle: num,
+                        chunk: i as u64
                     }
                 ));
             }
         }
+        try!(self.index.shrink_to_fit());
         Ok(())
     }
 
+    /// Shrinks the index to fit its entries and returns its sizes before and after that
+    pub fn shrink_index(&mut self) -> Result<(usize, usize), RepositoryError> {
+        try!(self.write_mode());
+        let _lock = try!(self.lock(true));
+        let before = self.index.size();
+        try!(self.index.shrink_to_fit());
+        Ok((before, self.index.size()))
+    }
+
     #[inline]
     pub fn check_index(&mut self, repair: bool) -> Result<(), RepositoryError> {
         if repair {
-            try!(self.write_mode());
+            try!(self.local_write_mode());
         }
         info!("Checking index integrity...");
         if let Err(err) = self.index.check() {
@@ -495,17 +708,129 @@ impl Repository {
     }
 
     #[inline]
-    pub fn check_bundles(&mut self, full: bool, repair: bool) -> Result<(), RepositoryError> {
+    pub fn check_bundles(
+        &mut self,
+        full: bool,
+        repair: bool,
+        threads: usize,
+    ) -> Result<(), RepositoryError> {
         if repair {
             try!(self.write_mode());
         }
         info!("Checking bundle integrity...");
-        if try!(self.bundles.check(full, repair)) {
+        if try!(self.bundles.check(full, repair, threads)) {
             // Some bundles got repaired
-            warn!("Some bundles have been rewritten, please remove the broken bundles manually.");
+            warn!(
+                "Some bundles have been rewritten, the broken bundles were moved to {:?}",
+                self.layout.quarantine_path()
+            );
             try!(self.rebuild_bundle_map());
             try!(self.rebuild_index());
         }
         Ok(())
     }
+
+    /// Checks the backup files for problems that the check of the backup trees does not find
+    ///
+    /// Each class of problems is reported separately and only fixed if it is contained in `fix`.
+    /// Backups with missing roots are moved to quarantine, duplicated backups are removed like
+    /// with `delete_backup` and orphaned meta bundles are deleted.
+    pub fn check_backup_files(
+        &mut self,
+        fix: &HashSet<BackupFileProblem>,
+    ) -> Result<BackupFileReport, RepositoryError> {
+        if !fix.is_empty() {
+            try!(self.write_mode());
+        }
+        try!(self.flush());
+        // Deleting bundles needs the same exclusive lock as vacuum
+        let _lock = try!(self.lock(fix.contains(&BackupFileProblem::OrphanedMeta)));
+        info!("Checking backup files...");
+        let backup_map = match self.get_all_backups() {
+            Ok(backup_map) => backup_map,
+            Err(RepositoryError::BackupFile(BackupFileError::PartialBackupsList(backup_map,
+                                                                                _failed))) => {
+                warn!("Some backups could not be read, ignoring them");
+                backup_map
+            }
+            Err(err) => return Err(err),
+        };
+        let mut names: Vec<_> = backup_map.keys().cloned().collect();
+        names.sort();
+        let mut report = BackupFileReport {
+            missing_roots: vec![],
+            duplicates: vec![],
+            orphaned_meta: vec![]
+        };
+        let mut originals: HashMap<_, String> = HashMap::new();
+        for name in names {
+            let backup = &backup_map[&name];
+            if backup.root.is_empty() || backup.root.iter().any(|c| !self.index.contains(&c.0)) {
+                warn!("Problem detected: the root of backup {} is missing", name);
+                if fix.contains(&BackupFileProblem::MissingRoot) {
+                    let src = self.layout.backup_path(&name);
+                    let dst = PathBuf::from("backups").join(format!("{}.backup", name));
+                    let reason = "the root chunks were missing";
+                    let dst = try!(quarantine_file(&self.layout, &src, &dst, reason, &[]));
+                    info!("The backup was moved to quarantine at {:?}", dst);
+                }
+                report.missing_roots.push(name);
+                continue;
+            }
+            let key = (
+                backup.root.clone(),
+                backup.host.clone(),
+                backup.path.clone(),
+                backup.timestamp
+            );
+            if let Some(original) = originals.get(&key) {
+                warn!("Problem detected: backup {} is a duplicate of {}", name, original);
+                if fix.contains(&BackupFileProblem::Duplicate) {
+                    try!(self.delete_backup(&name));
+                }
+                report.duplicates.push((name, original.clone()));
+                continue;
+            }
+            originals.insert(key, name);
+        }
+        if !report.missing_roots.is_empty() && !fix.contains(&BackupFileProblem::MissingRoot) {
+            warn!("Not checking for orphaned meta bundles as some backups are broken");
+            return Ok(report);
+        }
+        let usage = try!(self.analyze_usage());
+        let mut orphaned: Vec<_> = usage
+            .iter()
+            .filter(|&(_, bundle)| {
+                bundle.info.mode == BundleMode::Meta && bundle.used_raw_size == 0
+            })
+            .map(|(id, bundle)| (*id, bundle.info.id.clone()))
+            .collect();
+        orphaned.sort_by(|a, b| a.1.cmp(&b.1));
+        for &(_, ref bundle_id) in &orphaned {
+            warn!("Problem detected: meta bundle {} is not used by any backup", bundle_id);
+        }
+        if fix.contains(&BackupFileProblem::OrphanedMeta) && !orphaned.is_empty() {
+            for &(id, ref bundle_id) in &orphaned {
+                let chunks = try!(self.bundles.get_chunk_list(bundle_id));
+                for &(hash, _len) in chunks.iter() {
+                    try!(self.index.delete(&hash));
+                }
+                if let Some(bundle) = self.bundle_map.remove(id) {
+                    try!(self.bundles.delete_bundle(&bundle));
+                }
+            }
+            try!(self.save_bundle_map());
+            info!("Deleted {} orphaned meta bundles", orphaned.len());
+        }
+        report.orphaned_meta = orphaned.into_iter().map(|(_, bundle_id)| bundle_id).collect();
+        if fix.iter().any(|problem| match *problem {
+            BackupFileProblem::MissingRoot => !report.missing_roots.is_empty(),
+            BackupFileProblem::Duplicate => !report.duplicates.is_empty(),
+            BackupFileProblem::OrphanedMeta => !report.orphaned_meta.is_empty(),
+        }) {
+            let fixed: Vec<_> = fix.iter().map(|problem| problem.name()).collect();
+            self.audit("check-backup-files", format!("fixed {}", fixed.join(", ")));
+        }
+        Ok(report)
+    }
 }
@@ -25,6 +25,11 @@ impl RepositoryLayout {
         self.0.join("excludes")
     }
 
+    #[inline]
+    pub fn remotes_path(&self) -> PathBuf {
+        self.0.join("remotes.yaml")
+    }
+
     #[inline]
     pub fn index_path(&self) -> PathBuf {
         self.0.join("index")
@@ -45,6 +50,11 @@ impl RepositoryLayout {
         self.0.join("locks")
     }
 
+    #[inline]
+    pub fn backup_locks_path(&self) -> PathBuf {
+        self.0.join("backup_locks")
+    }
+
     #[inline]
     pub fn backups_path(&self) -> PathBuf {
         self.0.join("remote/backups")
@@ -66,6 +76,31 @@ impl RepositoryLayout {
             self.remote_locks_path().exists()
     }
 
+    #[inline]
+    pub fn audit_log_path(&self) -> PathBuf {
+        self.0.join("remote/audit.log")
+    }
+
+    #[inline]
+    pub fn trash_path(&self) -> PathBuf {
+        self.0.join("remote/trash")
+    }
+
+    #[inline]
+    pub fn trash_backup_path(&self, name: &str) -> PathBuf {
+        self.trash_path().join(format!("{}.backup", name))
+    }
+
+    #[inline]
+    pub fn quarantine_path(&self) -> PathBuf {
+        self.0.join("remote/quarantine")
+    }
+
+    #[inline]
+    pub fn quarantine_report_path(&self) -> PathBuf {
+        self.0.join("remote/quarantine/report.txt")
+    }
+
     #[inline]
     pub fn remote_readme_path(&self) -> PathBuf {
         self.0.join("remote/README.md")
@@ -81,6 +116,16 @@ impl RepositoryLayout {
         self.0.join("remote/bundles")
     }
 
+    #[inline]
+    pub fn dictionaries_path(&self) -> PathBuf {
+        self.0.join("remote/dictionaries")
+    }
+
+    #[inline]
+    pub fn dictionary_path(&self, id: &Hash) -> PathBuf {
+        self.dictionaries_path().join(format!("{}.dict", id))
+    }
+
     #[inline]
     pub fn local_bundles_path(&self) -> PathBuf {
         self.0.join("bundles/cached")
@@ -112,6 +157,20 @@ impl RepositoryLayout {
         self.bundle_path(&BundleId::random(), self.remote_bundles_path(), count)
     }
 
+    /// Returns a random path that only consists of lowercase 8.3 names
+    ///
+    /// The bundle is nested in three levels of folders named after the first bytes of a random
+    /// id and the file is named after the next four bytes, e.g. `ab/cd/ef/0123abcd.bnd`. Those
+    /// names also work on FAT filesystems without long names and on case-insensitive remotes.
+    pub fn short_remote_bundle_path(&self) -> (PathBuf, PathBuf) {
+        let id = BundleId::random().to_string();
+        let folder = self.remote_bundles_path()
+            .join(&id[0..2])
+            .join(&id[2..4])
+            .join(&id[4..6]);
+        (folder, format!("{}.bnd", &id[6..14]).into())
+    }
+
     #[inline]
     pub fn local_bundle_path(&self, bundle: &BundleId, count: usize) -> (PathBuf, PathBuf) {
         self.bundle_path(bundle, self.local_bundles_path(), count)
@@ -130,6 +189,11 @@ impl RepositoryLayout {
         )
     }
 
+    #[inline]
+    pub fn proxy_cache_path(&self) -> PathBuf {
+        self.0.join("bundles/proxy")
+    }
+
     #[inline]
     pub fn local_bundle_cache_path(&self) -> PathBuf {
         self.0.join("bundles/local.cache")
@@ -140,8 +204,28 @@ impl RepositoryLayout {
         self.0.join("bundles/remote.cache")
     }
 
+    #[inline]
+    pub fn usage_cache_path(&self) -> PathBuf {
+        self.0.join("bundles/usage.cache")
+    }
+
     #[inline]
     pub fn dirtyfile_path(&self) -> PathBuf {
         self.0.join("dirty")
     }
+
+    #[inline]
+    pub fn last_check_path(&self) -> PathBuf {
+        self.0.join("last_check")
+    }
+
+    #[inline]
+    pub fn read_only_path(&self) -> PathBuf {
+        self.0.join("read_only")
+    }
+
+    #[inline]
+    pub fn mount_hints_path(&self) -> PathBuf {
+        self.0.join("mount_hints")
+    }
 }
@@ -0,0 +1,186 @@
+use prelude::*;
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::fs::File;
+use std::path::Path;
+
+
+/// Formats a line in the format of `sha256sum`, escaping backslashes and newlines in the path
+fn format_manifest_line(hash: &[u8], path: &str) -> String {
+    if path.contains('\\') || path.contains('\n') {
+        format!(
+            "\\{}  {}",
+            to_hex(hash),
+            path.replace('\\', "\\\\").replace('\n', "\\n")
+        )
+    } else {
+        format!("{}  {}", to_hex(hash), path)
+    }
+}
+
+fn parse_manifest_line(line: &str) -> Option<(Vec<u8>, String)> {
+    let (escaped, line) = if line.starts_with('\\') {
+        (true, &line[1..])
+    } else {
+        (false, line)
+    };
+    if line.len() < 66 || !line.is_char_boundary(64) || !line.is_char_boundary(66) {
+        return None;
+    }
+    let hash = match parse_hex(&line[..64]) {
+        Ok(hash) => hash,
+        Err(()) => return None,
+    };
+    // The second separator character is '*' for files hashed in binary mode
+    if &line[64..65] != " " || (&line[65..66] != " " && &line[65..66] != "*") {
+        return None;
+    }
+    let path = &line[66..];
+    if !escaped {
+        return Some((hash, path.to_string()));
+    }
+    let mut unescaped = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('n') => unescaped.push('\n'),
+            _ => return None,
+        }
+    }
+    Some((hash, unescaped))
+}
+
+
+pub struct ManifestReport {
+    pub checked: usize,
+    pub missing: Vec<String>,
+    pub mismatched: Vec<String>,
+    pub invalid_lines: usize
+}
+
+impl ManifestReport {
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.mismatched.is_empty() && self.invalid_lines == 0
+    }
+}
+
+
+/// Checks the files below `root` against a manifest in the format of `sha256sum`
+pub fn verify_manifest<P: AsRef<Path>, R: AsRef<Path>>(
+    manifest: P,
+    root: R,
+) -> Result<ManifestReport, io::Error> {
+    let root = root.as_ref();
+    let file = BufReader::new(try!(File::open(manifest)));
+    let mut report = ManifestReport {
+        checked: 0,
+        missing: vec![],
+        mismatched: vec![],
+        invalid_lines: 0
+    };
+    for line in file.lines() {
+        let line = try!(line);
+        if line.is_empty() {
+            continue;
+        }
+        let (hash, path) = match parse_manifest_line(&line) {
+            Some(entry) => entry,
+            None => {
+                warn!("Invalid manifest line: {}", line);
+                report.invalid_lines += 1;
+                continue;
+            }
+        };
+        report.checked += 1;
+        let mut file = match File::open(root.join(&path)) {
+            Ok(file) => file,
+            Err(err) => {
+                println!("{}: MISSING ({})", path, err);
+                report.missing.push(path);
+                continue;
+            }
+        };
+        let mut hasher = Sha256::new();
+        try!(io::copy(&mut file, &mut hasher));
+        if hasher.finish()[..] == hash[..] {
+            info!("{}: OK", path);
+        } else {
+            println!("{}: FAILED", path);
+            report.mismatched.push(path);
+        }
+    }
+    Ok(report)
+}
+
+
+impl Repository {
+    fn hash_inode_data(&mut self, inode: &Inode) -> Result<[u8; 32], RepositoryError> {
+        let mut hasher = Sha256::new();
+        match inode.data {
+            None => (),
+            Some(FileData::Inline(ref data)) => hasher.update(data),
+            Some(FileData::ChunkedDirect(ref chunks)) => {
+                try!(self.get_stream(chunks, &mut hasher));
+            }
+            Some(FileData::ChunkedIndirect(ref chunks)) => {
+                let chunk_data = try!(self.get_data(chunks));
+                let chunks = ChunkList::read_from(&chunk_data);
+                try!(self.get_stream(&chunks, &mut hasher));
+            }
+        }
+        Ok(hasher.finish())
+    }
+
+    fn write_manifest_recurse<W: Write>(
+        &mut self,
+        path: &str,
+        inode: Inode,
+        out: &mut W,
+    ) -> Result<(), RepositoryError> {
+        match inode.file_type {
+            FileType::File => {
+                let hash = try!(self.hash_inode_data(&inode));
+                try!(writeln!(out, "{}", format_manifest_line(&hash, path)));
+            }
+            FileType::Directory => {
+                if let Some(children) = inode.children {
+                    for (name, chunks) in children {
+                        let child = try!(self.get_inode(&chunks));
+                        let child_path = if path.is_empty() {
+                            name
+                        } else {
+                            format!("{}/{}", path, name)
+                        };
+                        try!(self.write_manifest_recurse(&child_path, child, out));
+                    }
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Writes the SHA-256 checksums of all files below `inode` in the format of `sha256sum`
+    ///
+    /// The paths are relative to the directory the inode would be restored into. They are built
+    /// from the stored inode names, so no lossy conversion of file names is needed.
+    pub fn write_manifest<W: Write>(
+        &mut self,
+        inode: Inode,
+        out: &mut W,
+    ) -> Result<(), RepositoryError> {
+        let path = if inode.file_type == FileType::Directory {
+            String::new()
+        } else {
+            inode.name.clone()
+        };
+        self.write_manifest_recurse(&path, inode, out)
+    }
+}
+
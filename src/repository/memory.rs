@@ -0,0 +1,47 @@
+use prelude::*;
+
+use rand;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+
+/// Creates a new folder on the memory filesystem or in the temporary folder if there is none
+fn create_memory_folder() -> Result<PathBuf, io::Error> {
+    let shm = Path::new("/dev/shm");
+    let base = if shm.is_dir() {
+        shm.to_path_buf()
+    } else {
+        env::temp_dir()
+    };
+    let path = base.join(format!("zvault-{:016x}", rand::random::<u64>()));
+    try!(fs::create_dir(&path));
+    Ok(path)
+}
+
+
+impl Repository {
+    /// Creates a temporary repository that is deleted when it is dropped
+    ///
+    /// This is a normal file based repository, its folder and remote storage are placed on the
+    /// memory filesystem `/dev/shm` if it exists and in the temporary folder otherwise.
+    pub fn create_temporary(config: Config) -> Result<Self, RepositoryError> {
+        let base = try!(create_memory_folder());
+        let remote = base.join("remote");
+        let repo = fs::create_dir(&remote).map_err(RepositoryError::from).and_then(|()| {
+            Self::create(base.join("repository"), config, &remote)
+        });
+        match repo {
+            Ok(mut repo) => {
+                repo.temp_folder = Some(base);
+                Ok(repo)
+            }
+            Err(err) => {
+                let _ = fs::remove_dir_all(&base);
+                Err(err)
+            }
+        }
+    }
+}
@@ -1,18 +1,16 @@
 use prelude::*;
 
-use filetime::{self, FileTime};
+#[cfg(unix)]
 use xattr;
-use libc;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::fs::{self, File, Permissions};
-use std::os::linux::fs::MetadataExt;
-use std::os::unix::fs::{FileTypeExt, PermissionsExt, MetadataExt as UnixMetadataExt, symlink};
-use std::io::{self, Read, Write};
-use std::os::unix::ffi::OsStrExt;
+use std::fs::{self, File, OpenOptions};
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt, MetadataExt};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 use std::fmt;
-use std::ffi;
+use std::time::UNIX_EPOCH;
 
 
 quick_error!{
@@ -69,7 +67,8 @@ pub enum FileType {
     Symlink,
     BlockDevice,
     CharDevice,
-    NamedPipe
+    NamedPipe,
+    Socket
 }
 serde_impl!(FileType(u8) {
     File => 0,
@@ -77,8 +76,19 @@ serde_impl!(FileType(u8) {
     Symlink => 2,
     BlockDevice => 3,
     CharDevice => 4,
-    NamedPipe => 5
+    NamedPipe => 5,
+    Socket => 6
 });
+impl FileType {
+    /// Whether this is a device, named pipe or socket which only consists of metadata
+    #[inline]
+    pub fn is_special(&self) -> bool {
+        match *self {
+            FileType::File | FileType::Directory | FileType::Symlink => false,
+            _ => true,
+        }
+    }
+}
 impl fmt::Display for FileType {
     fn fmt(&self, format: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match *self {
@@ -88,6 +98,7 @@ impl fmt::Display for FileType {
             FileType::BlockDevice => write!(format, "block device"),
             FileType::CharDevice => write!(format, "char device"),
             FileType::NamedPipe => write!(format, "named pipe"),
+            FileType::Socket => write!(format, "socket"),
         }
     }
 }
@@ -106,7 +117,7 @@ serde_impl!(FileData(u8) {
 });
 
 
-#[derive(Debug, Hash, Eq, PartialEq)]
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
 pub struct Inode {
     pub name: String,
     pub size: u64,
@@ -119,10 +130,13 @@ pub struct Inode {
     pub data: Option<FileData>,
     pub children: Option<BTreeMap<String, ChunkList>>,
     pub cum_size: u64,
-    pub cum_dirs: usize,
-    pub cum_files: usize,
+    pub cum_dirs: u64,
+    pub cum_files: u64,
     pub xattrs: BTreeMap<String, msgpack::Bytes>,
-    pub device: Option<(u32, u32)>
+    pub device: Option<(u32, u32)>,
+    pub flags: Option<u32>,
+    pub project_id: Option<u32>,
+    pub birth_time: Option<i64>
 }
 impl Default for Inode {
     fn default() -> Self {
@@ -141,7 +155,10 @@ impl Default for Inode {
             cum_dirs: 0,
             cum_files: 0,
             xattrs: BTreeMap::new(),
-            device: None
+            device: None,
+            flags: None,
+            project_id: None,
+            birth_time: None
         }
     }
 }
@@ -157,19 +174,33 @@ serde_impl!(Inode(u8?) {
     data: Option<FileData> => 10,
     children: Option<BTreeMap<String, ChunkList>> => 11,
     cum_size: u64 => 12,
-    cum_dirs: usize => 13,
-    cum_files: usize => 14,
+    cum_dirs: u64 => 13,
+    cum_files: u64 => 14,
     xattrs: BTreeMap<String, msgpack::Bytes> => 15,
-    device: Option<(u32, u32)> => 16
+    device: Option<(u32, u32)> => 16,
+    flags: Option<u32> => 17,
+    project_id: Option<u32> => 18,
+    birth_time: Option<i64> => 19
 });
 
 
 impl Inode {
-    pub fn get_from<P: AsRef<Path>>(path: P) -> Result<Self, InodeError> {
+    #[cfg(unix)]
+    pub fn get_from<P: AsRef<Path>>(path: P, dereference: bool) -> Result<Self, InodeError> {
         let path = path.as_ref();
         let name = path.file_name()
             .map(|s| s.to_string_lossy().to_string())
             .unwrap_or_else(|| "_".to_string());
+        // When following symlinks, all metadata is taken from the link target
+        let real_path;
+        let path = if dereference {
+            real_path = try!(fs::canonicalize(path).map_err(|e| {
+                InodeError::ReadMetadata(e, path.to_owned())
+            }));
+            &real_path as &Path
+        } else {
+            path
+        };
         let meta = try!(fs::symlink_metadata(path).map_err(|e| {
             InodeError::ReadMetadata(e, path.to_owned())
         }));
@@ -190,6 +221,8 @@ impl Inode {
             FileType::CharDevice
         } else if meta.file_type().is_fifo() {
             FileType::NamedPipe
+        } else if meta.file_type().is_socket() {
+            FileType::Socket
         } else {
             return Err(InodeError::UnsupportedFiletype(path.to_owned()));
         };
@@ -202,15 +235,29 @@ impl Inode {
             );
         }
         if meta.file_type().is_block_device() || meta.file_type().is_char_device() {
-            let rdev = meta.rdev();
-            let major = (rdev >> 8) as u32;
-            let minor = (rdev & 0xff) as u32;
-            inode.device = Some((major, minor));
+            inode.device = Some(split_device(meta.rdev() as u64));
         }
         inode.mode = meta.permissions().mode();
-        inode.user = meta.st_uid();
-        inode.group = meta.st_gid();
-        inode.timestamp = meta.st_mtime();
+        inode.user = meta.uid();
+        inode.group = meta.gid();
+        inode.timestamp = meta.mtime();
+        inode.flags = get_file_flags(&meta).and_then(|flags| if flags == 0 {
+            None
+        } else {
+            Some(flags)
+        });
+        if meta.is_file() || meta.is_dir() {
+            inode.project_id = get_project_id(path).and_then(|id| if id == 0 {
+                None
+            } else {
+                Some(id)
+            });
+        }
+        // Only available on some platforms and filesystems, it can not be restored
+        inode.birth_time = meta.created()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|dur| dur.as_secs() as i64);
         if xattr::SUPPORTED_PLATFORM {
             if let Ok(attrs) = xattr::list(path) {
                 for name in attrs {
@@ -245,7 +292,7 @@ impl Inode {
             }
             FileType::Symlink => {
                 if let Some(ref src) = self.symlink_target {
-                    try!(symlink(src, &full_path).map_err(|e| {
+                    try!(create_symlink(src, &full_path).map_err(|e| {
                         InodeError::Create(e, full_path.clone())
                     }));
                 } else {
@@ -253,64 +300,53 @@ impl Inode {
                 }
             }
             FileType::NamedPipe => {
-                let name = try!(
-                    ffi::CString::new(full_path.as_os_str().as_bytes())
-                        .map_err(|_| InodeError::Integrity("Name contains nulls"))
-                );
-                let mode = self.mode | libc::S_IFIFO;
-                if unsafe { libc::mkfifo(name.as_ptr(), mode) } != 0 {
-                    return Err(InodeError::Create(
-                        io::Error::last_os_error(),
-                        full_path.clone()
-                    ));
-                }
+                try!(create_fifo(&full_path, self.mode).map_err(|e| {
+                    InodeError::Create(e, full_path.clone())
+                }));
+            }
+            FileType::Socket => {
+                try!(create_socket(&full_path, self.mode).map_err(|e| {
+                    InodeError::Create(e, full_path.clone())
+                }));
             }
             FileType::BlockDevice | FileType::CharDevice => {
-                let name = try!(
-                    ffi::CString::new(full_path.as_os_str().as_bytes())
-                        .map_err(|_| InodeError::Integrity("Name contains nulls"))
-                );
-                let mode = self.mode |
-                    match self.file_type {
-                        FileType::BlockDevice => libc::S_IFBLK,
-                        FileType::CharDevice => libc::S_IFCHR,
-                        _ => unreachable!(),
-                    };
-                let device = if let Some((major, minor)) = self.device {
-                    unsafe { libc::makedev(major, minor) }
+                let (major, minor) = if let Some(device) = self.device {
+                    device
                 } else {
                     return Err(InodeError::Integrity("Device without id"));
                 };
-                if unsafe { libc::mknod(name.as_ptr(), mode, device) } != 0 {
-                    return Err(InodeError::Create(
-                        io::Error::last_os_error(),
-                        full_path.clone()
-                    ));
-                }
+                let block = self.file_type == FileType::BlockDevice;
+                try!(create_device(&full_path, self.mode, block, major, minor).map_err(|e| {
+                    InodeError::Create(e, full_path.clone())
+                }));
             }
         }
-        let time = FileTime::from_seconds_since_1970(self.timestamp as u64, 0);
-        if let Err(err) = filetime::set_file_times(&full_path, time, time) {
+        self.set_metadata_at(&full_path);
+        Ok(file)
+    }
+
+    /// Sets times, xattrs, permissions and ownership on an existing entry, failures are only logged
+    ///
+    /// Symlinks are never followed, their own times and ownership are set instead of the ones of
+    /// their targets. Symlinks have no permissions of their own, so their mode is not set.
+    pub fn set_metadata_at(&self, full_path: &Path) {
+        if let Err(err) = set_times_nofollow(&full_path, self.timestamp) {
             warn!("Failed to set file time on {:?}: {}", full_path, err);
         }
-        if !self.xattrs.is_empty() {
-            if xattr::SUPPORTED_PLATFORM {
-                for (name, data) in &self.xattrs {
-                    if let Err(err) = xattr::set(&full_path, name, data) {
-                        warn!("Failed to set xattr {} on {:?}: {}", name, full_path, err);
-                    }
-                }
-            } else {
-                warn!("Not setting xattr on {:?}", full_path);
+        for (name, data) in &self.xattrs {
+            if let Err(err) = set_xattr(&full_path, name, data) {
+                warn!("Failed to set xattr {} on {:?}: {}", name, full_path, err);
             }
         }
-        if let Err(err) = fs::set_permissions(&full_path, Permissions::from_mode(self.mode)) {
-            warn!(
-                "Failed to set permissions {:o} on {:?}: {}",
-                self.mode,
-                full_path,
-                err
-            );
+        if self.file_type != FileType::Symlink {
+            if let Err(err) = set_mode(&full_path, self.mode) {
+                warn!(
+                    "Failed to set permissions {:o} on {:?}: {}",
+                    self.mode,
+                    full_path,
+                    err
+                );
+            }
         }
         if let Err(err) = chown(&full_path, self.user, self.group) {
             warn!(
@@ -321,7 +357,11 @@ impl Inode {
                 err
             );
         }
-        Ok(file)
+        if let Some(id) = self.project_id {
+            if let Err(err) = set_project_id(&full_path, id) {
+                warn!("Failed to set project id {} on {:?}: {}", id, full_path, err);
+            }
+        }
     }
 
     #[inline]
@@ -351,12 +391,14 @@ impl Inode {
 
 
 impl Repository {
+    #[cfg(unix)]
     pub fn create_inode<P: AsRef<Path>>(
         &mut self,
         path: P,
         reference: Option<&Inode>,
+        dereference: bool,
     ) -> Result<Inode, RepositoryError> {
-        let mut inode = try!(Inode::get_from(path.as_ref()));
+        let mut inode = try!(Inode::get_from(path.as_ref(), dereference));
         if inode.file_type == FileType::File && inode.size > 0 {
             if let Some(reference) = reference {
                 if reference.is_same_meta_quick(&inode) {
@@ -394,28 +436,115 @@ impl Repository {
         Ok(try!(Inode::decode(&try!(self.get_data(chunks)))))
     }
 
-    pub fn save_inode_at<P: AsRef<Path>>(
+    /// Overwrites the contents of an existing file, only writing the chunks that differ
+    ///
+    /// The existing file is chunked with the chunker of the repository, chunks with the same hash
+    /// at the same position are kept. Returns the number of bytes that have been written.
+    fn update_file_data(
+        &mut self,
+        chunks: &[Chunk],
+        file: &mut File,
+    ) -> Result<u64, RepositoryError> {
+        let mut existing = HashSet::new();
+        let mut offset = 0;
+        let mut chunk = Vec::with_capacity(self.config.chunker.avg_size() * 2);
+        loop {
+            chunk.clear();
+            let mut output = Cursor::new(chunk);
+            let res = try!(self.chunker.chunk(file, &mut output));
+            chunk = output.into_inner();
+            existing.insert((offset, self.config.hash_chunk(&chunk)));
+            offset += chunk.len() as u64;
+            if res == ChunkerStatus::Finished {
+                break;
+            }
+        }
+        let mut written = 0;
+        offset = 0;
+        for &(hash, len) in chunks {
+            if !existing.contains(&(offset, hash)) {
+                let data = try!(self.read_chunk(hash, len));
+                try!(file.seek(SeekFrom::Start(offset)));
+                try!(file.write_all(&data));
+                written += len as u64;
+            }
+            offset += len as u64;
+        }
+        try!(file.set_len(offset));
+        Ok(written)
+    }
+
+    /// Updates an existing entry at `full_path` in place instead of creating it
+    ///
+    /// Existing directories are kept and existing files are only partially rewritten. Returns
+    /// `false` if the inode still needs to be created, other existing entries are removed for that.
+    /// The metadata of kept directories is not set as updating their contents would change it.
+    pub fn update_inode_at(
         &mut self,
         inode: &Inode,
-        path: P,
-    ) -> Result<(), RepositoryError> {
-        if let Some(mut file) = try!(inode.create_at(path.as_ref())) {
-            if let Some(ref contents) = inode.data {
-                match *contents {
-                    FileData::Inline(ref data) => {
-                        try!(file.write_all(data));
-                    }
-                    FileData::ChunkedDirect(ref chunks) => {
-                        try!(self.get_stream(chunks, &mut file));
-                    }
-                    FileData::ChunkedIndirect(ref chunks) => {
-                        let chunk_data = try!(self.get_data(chunks));
-                        let chunks = ChunkList::read_from(&chunk_data);
-                        try!(self.get_stream(&chunks, &mut file));
-                    }
+        full_path: &Path,
+    ) -> Result<bool, RepositoryError> {
+        let meta = match fs::symlink_metadata(full_path) {
+            Ok(meta) => meta,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+        match (inode.file_type, &inode.data) {
+            (FileType::Directory, _) if meta.is_dir() => return Ok(true),
+            (FileType::File, &Some(FileData::ChunkedDirect(ref chunks))) if meta.is_file() => {
+                let mut file = try!(OpenOptions::new().read(true).write(true).open(full_path));
+                let written = try!(self.update_file_data(chunks, &mut file));
+                debug!(
+                    "Updated {:?}, wrote {} of {}",
+                    full_path,
+                    to_file_size(written),
+                    to_file_size(inode.size)
+                );
+            }
+            (FileType::File, &Some(FileData::ChunkedIndirect(ref chunks))) if meta.is_file() => {
+                let chunk_data = try!(self.get_data(chunks));
+                let chunks = ChunkList::read_from(&chunk_data);
+                let mut file = try!(OpenOptions::new().read(true).write(true).open(full_path));
+                let written = try!(self.update_file_data(&chunks, &mut file));
+                debug!(
+                    "Updated {:?}, wrote {} of {}",
+                    full_path,
+                    to_file_size(written),
+                    to_file_size(inode.size)
+                );
+            }
+            _ => {
+                if !meta.is_dir() {
+                    try!(fs::remove_file(full_path));
                 }
+                return Ok(false);
+            }
+        }
+        inode.set_metadata_at(full_path);
+        if let Some(flags) = inode.flags {
+            if let Err(err) = set_file_flags(full_path, flags) {
+                warn!("Failed to set flags {:x} on {:?}: {}", flags, full_path, err);
             }
         }
-        Ok(())
+        Ok(true)
+    }
+}
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_large_counts_roundtrip() {
+        let mut inode = Inode::default();
+        inode.size = u64::max_value();
+        inode.cum_size = u64::max_value();
+        inode.cum_files = u32::max_value() as u64 + 1;
+        inode.cum_dirs = u64::max_value();
+        let decoded: Inode = msgpack::decode(&msgpack::encode(&inode).unwrap()).unwrap();
+        assert_eq!(decoded, inode);
     }
 }
@@ -10,43 +10,106 @@ mod vacuum;
 mod backup_file;
 mod tarfile;
 mod layout;
+mod usage;
+mod audit;
+mod manifest;
+mod purge;
+mod health;
+mod restore_plan;
+mod remotes;
+mod quarantine;
+mod trash;
+mod delta;
+mod file_check;
+mod mount_hints;
+mod sample;
+mod rebuild;
+mod publish;
+mod catalog;
+mod scanner;
+mod seed;
+mod send_stream;
+#[cfg(any(test, feature = "memory"))]
+mod memory;
 
 use prelude::*;
 
 use std::mem;
-use std::cmp::max;
-use std::path::Path;
+use std::cmp::{min, max};
+use std::path::{Path, PathBuf};
 use std::fs::{self, File};
 use std::sync::{Arc, Mutex};
+#[cfg(unix)]
 use std::os::unix::fs::symlink;
+#[cfg(windows)]
+use std::os::windows::fs::symlink_dir as symlink;
 use std::io::Write;
+use std::collections::HashMap;
+
+use chrono::prelude::*;
 
 pub use self::error::RepositoryError;
-pub use self::config::Config;
+pub use self::config::{Config, HealthConfig};
 pub use self::metadata::{Inode, FileType, FileData, InodeError};
-pub use self::backup::{BackupError, BackupOptions, DiffType};
-pub use self::backup_file::{Backup, BackupFileError};
-pub use self::integrity::IntegrityError;
-pub use self::info::{RepositoryInfo, BundleAnalysis};
+pub use self::backup::{BackupError, BackupOptions, RestoreOptions, DiffType, BackupPathStatus,
+                       SpecialFiles, BackupOrder};
+#[cfg(unix)]
+pub use self::backup::list_backup_paths;
+pub use self::backup_file::{Backup, BackupFileError, BackupTimings, ExtensionStats, SourceInfo,
+                            BackupRules};
+pub use self::integrity::{IntegrityError, BackupFileProblem, BackupFileReport};
+pub use self::info::{RepositoryInfo, BundleAnalysis, ReclaimReport, HostAnalysis,
+                     OriginUsage, FileChunkAnalysis};
 pub use self::layout::RepositoryLayout;
+pub use self::audit::AuditEntry;
+use self::audit::AuditLog;
+pub use self::manifest::verify_manifest;
+pub use self::remotes::{remote_reachable, check_remote, load_remotes, RemoteCheck,
+                        MAX_CLOCK_SKEW};
+pub use self::restore_plan::RestorePlan;
+pub use self::quarantine::quarantine_file;
+pub use self::file_check::{FileKind, check_file};
+pub use self::tarfile::TarSource;
+pub use self::catalog::BackupCatalog;
+pub use self::seed::copy_seed_files;
+pub use self::basic_io::ReadAhead;
 use self::bundle_map::BundleMap;
+use self::basic_io::PhaseTimes;
 
 
 const REPOSITORY_README: &'static [u8] = include_bytes!("../../docs/repository_readme.md");
 const DEFAULT_EXCLUDES: &'static [u8] = include_bytes!("../../docs/excludes.default");
 
+
+/// Contents of the files that are placed into a new repository
+pub struct RepositoryTemplates {
+    /// The default excludes used for all backups
+    pub excludes: Vec<u8>,
+    /// The README that is placed into the remote storage
+    pub readme: Vec<u8>
+}
+
+impl Default for RepositoryTemplates {
+    fn default() -> Self {
+        RepositoryTemplates {
+            excludes: DEFAULT_EXCLUDES.to_vec(),
+            readme: REPOSITORY_README.to_vec()
+        }
+    }
+}
+
 const INDEX_MAGIC: [u8; 7] = *b"zvault\x02";
-const INDEX_VERSION: u8 = 1;
+const INDEX_VERSION: u8 = 2;
 
 
 #[repr(packed)]
 #[derive(Clone, Copy, PartialEq, Debug, Default)]
 pub struct Location {
-    pub bundle: u32,
-    pub chunk: u32
+    pub bundle: u64,
+    pub chunk: u64
 }
 impl Location {
-    pub fn new(bundle: u32, chunk: u32) -> Self {
+    pub fn new(bundle: u64, chunk: u64) -> Self {
         Location {
             bundle: bundle,
             chunk: chunk
@@ -56,6 +119,31 @@ impl Location {
 
 impl ::index::Value for Location {}
 
+
+/// Location in index version 1 that only supported 32 bit bundle ids and chunk numbers
+#[repr(packed)]
+#[derive(Clone, Copy, Default)]
+struct LocationV1 {
+    bundle: u32,
+    chunk: u32
+}
+
+impl ::index::Value for LocationV1 {}
+
+/// Converts an index of version 1 into the current format
+fn migrate_index(path: &Path) -> Result<Index<Hash, Location>, IndexError> {
+    let old = try!(unsafe { Index::<Hash, LocationV1>::open(path, &INDEX_MAGIC, 1) });
+    let temp_path = path.with_extension("tmp");
+    let mut index = try!(Index::create(&temp_path, &INDEX_MAGIC, INDEX_VERSION));
+    for (hash, location) in old.iter() {
+        let (bundle, chunk) = (location.bundle, location.chunk);
+        try!(index.set(hash, &Location::new(bundle as u64, chunk as u64)));
+    }
+    drop(old);
+    try!(fs::rename(&temp_path, path));
+    Ok(index)
+}
+
 impl ::index::Key for Hash {
     fn hash(&self) -> u64 {
         self.low
@@ -77,8 +165,8 @@ pub struct Repository {
     index: Index<Hash, Location>,
     crypto: Arc<Mutex<Crypto>>,
     bundle_map: BundleMap,
-    next_data_bundle: u32,
-    next_meta_bundle: u32,
+    next_data_bundle: u64,
+    next_meta_bundle: u64,
     bundles: BundleDb,
     data_bundle: Option<BundleWriter>,
     meta_bundle: Option<BundleWriter>,
@@ -86,7 +174,24 @@ pub struct Repository {
     remote_locks: LockFolder,
     local_locks: LockFolder,
     lock: LockHandle,
-    dirty: bool
+    dirty: bool,
+    read_only: bool,
+    data_written: (u64, u64), // Raw and encoded size of the data chunks written so far
+    phase_times: PhaseTimes,
+    // Hashes of chunks that only contain zeros by their length
+    zero_hashes: HashMap<u32, Hash>,
+    // Number of chunks of only zeros stored since the last reset
+    zero_chunks: u64,
+    // Whether the data of chunks is compared with their hashes when reading files
+    verify_reads: bool,
+    // Name of the backup that new bundles are recorded for
+    bundle_origin: Option<String>,
+    // Storage tier hint recorded in the info of new data bundles
+    bundle_storage_class: StorageClass,
+    // Size at which new bundles are finished if it is lower than the configured bundle size
+    max_bundle_size: Option<usize>,
+    // Folder that is deleted together with the repository, only set for temporary repositories
+    temp_folder: Option<PathBuf>
 }
 
 
@@ -95,21 +200,43 @@ impl Repository {
         path: P,
         config: Config,
         remote: R,
+    ) -> Result<Self, RepositoryError> {
+        Self::create_with_templates(path, config, remote, &RepositoryTemplates::default())
+    }
+
+    /// Creates a repository whose excludes and README are taken from `templates`
+    pub fn create_with_templates<P: AsRef<Path>, R: AsRef<Path>>(
+        path: P,
+        mut config: Config,
+        remote: R,
+        templates: &RepositoryTemplates,
     ) -> Result<Self, RepositoryError> {
         let layout = RepositoryLayout::new(path.as_ref().to_path_buf());
         try!(fs::create_dir(layout.base_path()));
         try!(File::create(layout.excludes_path()).and_then(|mut f| {
-            f.write_all(DEFAULT_EXCLUDES)
+            f.write_all(&templates.excludes)
         }));
         try!(fs::create_dir(layout.keys_path()));
         try!(fs::create_dir(layout.local_locks_path()));
         try!(symlink(remote, layout.remote_path()));
         try!(File::create(layout.remote_readme_path()).and_then(
             |mut f| {
-                f.write_all(REPOSITORY_README)
+                f.write_all(&templates.readme)
             }
         ));
         try!(fs::create_dir_all(layout.remote_locks_path()));
+        // Remotes on FAT, exFAT or SMB shares do not support all names that zvault uses by default
+        let caps = try!(probe_fs_capabilities(layout.remote_path()));
+        if !caps.long_names || !caps.case_sensitive {
+            info!("The remote storage has limited file names, using short bundle names");
+            config.short_bundle_names = true;
+        }
+        if !caps.case_sensitive {
+            warn!("The remote storage ignores case, backup names must not only differ in case");
+        }
+        if !caps.special_chars {
+            warn!("The remote storage does not support characters like ':' in backup names");
+        }
         try!(config.save(layout.config_path()));
         try!(BundleDb::create(layout.clone()));
         try!(Index::<Hash, Location>::create(
@@ -119,6 +246,9 @@ impl Repository {
         ));
         try!(BundleMap::create().save(layout.bundle_map_path()));
         try!(fs::create_dir_all(layout.backups_path()));
+        try!(File::create(layout.last_check_path()).and_then(|mut f| {
+            write!(f, "{}", Utc::now().timestamp())
+        }));
         Self::open(path)
     }
 
@@ -129,27 +259,46 @@ impl Repository {
             return Err(RepositoryError::NoRemote);
         }
         let config = try!(Config::load(layout.config_path()));
+        // Settings made by the caller take precedence over the configuration
+        if worker_threads(0) == 0 {
+            set_worker_threads(config.threads);
+        }
+        if worker_cpus().is_empty() && !config.cpus.is_empty() {
+            if let Err(err) = set_worker_cpus(config.cpus.clone()) {
+                warn!("Failed to restrict the worker threads to the configured CPUs: {}", err);
+            }
+        }
         let remote_locks = LockFolder::new(layout.remote_locks_path());
         try!(fs::create_dir_all(layout.local_locks_path())); // Added after v0.1.0
         let local_locks = LockFolder::new(layout.local_locks_path());
         let lock = try!(local_locks.lock(false));
         let crypto = Arc::new(Mutex::new(try!(Crypto::open(layout.keys_path()))));
-        let (bundles, new, gone) = try!(BundleDb::open(layout.clone(), crypto.clone()));
-        let (index, mut rebuild_index) =
-            match unsafe { Index::open(layout.index_path(), &INDEX_MAGIC, INDEX_VERSION) } {
-                Ok(index) => (index, false),
-                Err(err) => {
-                    error!("Failed to load local index:\n\tcaused by: {}", err);
-                    (
-                        try!(Index::create(
-                            layout.index_path(),
-                            &INDEX_MAGIC,
-                            INDEX_VERSION
-                        )),
-                        true
-                    )
-                }
-            };
+        let (mut bundles, new, gone) = try!(BundleDb::open(layout.clone(), crypto.clone()));
+        bundles.set_upload_verification(config.upload_verification);
+        bundles.set_codec_helpers(config.codec_helpers);
+        bundles.set_short_names(config.short_bundle_names);
+        bundles.set_hash_key(config.hash_key);
+        let index = match unsafe { Index::open(layout.index_path(), &INDEX_MAGIC, INDEX_VERSION) } {
+            Err(IndexError::UnsupportedVersion(1)) => {
+                info!("Converting the local index to the new format");
+                migrate_index(&layout.index_path())
+            }
+            res => res,
+        };
+        let (index, mut rebuild_index) = match index {
+            Ok(index) => (index, false),
+            Err(err) => {
+                error!("Failed to load local index:\n\tcaused by: {}", err);
+                (
+                    try!(Index::create(
+                        layout.index_path(),
+                        &INDEX_MAGIC,
+                        INDEX_VERSION
+                    )),
+                    true
+                )
+            }
+        };
         let (bundle_map, rebuild_bundle_map) = match BundleMap::load(layout.bundle_map_path()) {
             Ok(bundle_map) => (bundle_map, false),
             Err(err) => {
@@ -158,9 +307,11 @@ impl Repository {
             }
         };
         let dirty = layout.dirtyfile_path().exists();
+        let read_only = layout.read_only_path().exists();
         let mut repo = Repository {
             layout: layout,
             dirty: true,
+            read_only: read_only,
             chunker: config.chunker.create(),
             config: config,
             index: index,
@@ -173,13 +324,22 @@ impl Repository {
             meta_bundle: None,
             lock: lock,
             remote_locks: remote_locks,
-            local_locks: local_locks
+            local_locks: local_locks,
+            data_written: (0, 0),
+            phase_times: PhaseTimes::default(),
+            zero_hashes: HashMap::new(),
+            zero_chunks: 0,
+            verify_reads: true,
+            bundle_origin: None,
+            bundle_storage_class: StorageClass::Standard,
+            max_bundle_size: None,
+            temp_folder: None
         };
         if !rebuild_bundle_map {
             let mut save_bundle_map = false;
             if !gone.is_empty() {
                 info!("Removig {} old bundles from index", gone.len());
-                try!(repo.write_mode());
+                try!(repo.local_write_mode());
                 for bundle in gone {
                     try!(repo.remove_gone_remote_bundle(bundle))
                 }
@@ -187,7 +347,7 @@ impl Repository {
             }
             if !new.is_empty() {
                 info!("Adding {} new bundles to index", new.len());
-                try!(repo.write_mode());
+                try!(repo.local_write_mode());
                 for bundle in ProgressIter::new(
                     "adding bundles to index",
                     new.len(),
@@ -199,22 +359,25 @@ impl Repository {
                 save_bundle_map = true;
             }
             if save_bundle_map {
-                try!(repo.write_mode());
+                try!(repo.local_write_mode());
                 try!(repo.save_bundle_map());
             }
         }
         repo.next_meta_bundle = repo.next_free_bundle_id();
         repo.next_data_bundle = repo.next_free_bundle_id();
         if rebuild_bundle_map {
-            try!(repo.write_mode());
+            try!(repo.local_write_mode());
             try!(repo.rebuild_bundle_map());
             rebuild_index = true;
         }
         if rebuild_index {
-            try!(repo.write_mode());
+            try!(repo.local_write_mode());
             try!(repo.rebuild_index());
         }
         repo.dirty = dirty;
+        for warning in repo.check_health() {
+            warn!("{}", warning);
+        }
         Ok(repo)
     }
 
@@ -234,7 +397,13 @@ impl Repository {
         if let Some((name, backup)) = backups.pop() {
             info!("Taking configuration from the last backup '{}'", name);
             repo.config = backup.config;
-            try!(repo.save_config())
+            try!(repo.save_config());
+            // The hash key is not stored in the backups, so it can not be recovered
+            if repo.bundles.list_bundles().iter().any(|b| b.keyed_hash) {
+                warn!(
+                    "The chunk hashes of this repository are keyed, please copy the hash key from the original configuration."
+                );
+            }
         } else {
             warn!(
                 "No backup found in the repository to take configuration from, please set the configuration manually."
@@ -243,6 +412,50 @@ impl Repository {
         Ok(repo)
     }
 
+    /// Creates a read-only clone of the repository at `src` in the folder `path`
+    ///
+    /// The clone uses the same remote storage, which can be given explicitly if it is mounted at
+    /// a different path. The configuration, keys and excludes are copied, the index and the
+    /// bundle map are rebuilt from the remote storage. The original repository is not opened.
+    pub fn clone_from<P: AsRef<Path>, Q: AsRef<Path>>(
+        src: P,
+        path: Q,
+        remote: Option<&Path>,
+    ) -> Result<Self, RepositoryError> {
+        let src = RepositoryLayout::new(src.as_ref().to_path_buf());
+        let layout = RepositoryLayout::new(path.as_ref().to_path_buf());
+        let remote = match remote {
+            Some(remote) => remote.to_path_buf(),
+            None => try!(fs::canonicalize(src.remote_path())),
+        };
+        let config = try!(Config::load(src.config_path()));
+        try!(fs::create_dir(layout.base_path()));
+        try!(fs::copy(src.excludes_path(), layout.excludes_path()));
+        try!(fs::create_dir(layout.keys_path()));
+        for entry in try!(fs::read_dir(src.keys_path())) {
+            let entry = try!(entry);
+            try!(fs::copy(entry.path(), layout.keys_path().join(entry.file_name())));
+        }
+        try!(fs::create_dir(layout.local_locks_path()));
+        try!(symlink(remote, layout.remote_path()));
+        if !layout.remote_exists() {
+            return Err(RepositoryError::NoRemote);
+        }
+        try!(config.save(layout.config_path()));
+        try!(BundleDb::create(layout.clone()));
+        try!(Index::<Hash, Location>::create(
+            layout.index_path(),
+            &INDEX_MAGIC,
+            INDEX_VERSION
+        ));
+        try!(BundleMap::create().save(layout.bundle_map_path()));
+        if src.last_check_path().exists() {
+            try!(fs::copy(src.last_check_path(), layout.last_check_path()));
+        }
+        try!(File::create(layout.read_only_path()));
+        Self::open(path)
+    }
+
     #[inline]
     pub fn register_key(
         &mut self,
@@ -250,19 +463,55 @@ impl Repository {
         secret: SecretKey,
     ) -> Result<(), RepositoryError> {
         try!(self.write_mode());
-        Ok(try!(self.crypto.lock().unwrap().register_secret_key(
+        let key = to_hex(&public[..]);
+        try!(self.crypto.lock().unwrap().register_secret_key(
             public,
             secret
-        )))
+        ));
+        self.audit("key-add", key);
+        Ok(())
     }
 
-    #[inline]
     pub fn save_config(&mut self) -> Result<(), RepositoryError> {
         try!(self.write_mode());
         try!(self.config.save(self.layout.config_path()));
+        let details = format!(
+            "bundle_size={}, chunker={}, compression={}, encryption={}, hash={}, delta={}, \
+             dictionary={}",
+            self.config.bundle_size,
+            self.config.chunker.to_string(),
+            self.config.compression.as_ref().map_or_else(
+                || "none".to_string(),
+                |c| c.to_string()
+            ),
+            self.config.encryption.as_ref().map_or_else(
+                || "none".to_string(),
+                |e| to_hex(&e.1[..])
+            ),
+            self.config.hash.name(),
+            self.config.delta_compression,
+            self.config.dictionary.map_or_else(|| "none".to_string(), |d| d.to_string())
+        );
+        self.audit("config-change", details);
         Ok(())
     }
 
+    /// Records a mutating operation in the audit log of the repository
+    ///
+    /// This is called after the operation has been completed, so a failure to write the audit
+    /// log is only reported as a warning instead of failing the operation.
+    pub fn audit(&self, action: &str, details: String) {
+        let entry = AuditEntry::new(action, details);
+        if let Err(err) = AuditLog::append(self.layout.audit_log_path(), &entry) {
+            warn!("Failed to record {} in the audit log: {}", entry.action, err);
+        }
+    }
+
+    #[inline]
+    pub fn get_audit_log(&self) -> Result<Vec<AuditEntry>, RepositoryError> {
+        Ok(try!(AuditLog::read(self.layout.audit_log_path())))
+    }
+
     #[inline]
     pub fn set_encryption(&mut self, public: Option<&PublicKey>) {
         if let Some(key) = public {
@@ -277,6 +526,66 @@ impl Repository {
         }
     }
 
+    /// Trains a compression dictionary of at most `size` bytes from small chunks
+    ///
+    /// Only chunks up to `max_chunk_size` are used as samples since larger chunks already
+    /// compress well on their own. The dictionary is stored but not configured for use.
+    pub fn train_dictionary(
+        &mut self,
+        size: usize,
+        max_chunk_size: usize,
+    ) -> Result<Hash, RepositoryError> {
+        try!(self.write_mode());
+        let bundles: Vec<_> = self.bundles
+            .list_bundles()
+            .iter()
+            .map(|b| b.id.clone())
+            .collect();
+        // Zstd recommends about 100 times the dictionary size as samples
+        let max_sample_size = 100 * size;
+        let mut samples = vec![];
+        let mut sample_size = 0;
+        for bundle_id in bundles {
+            let chunks = try!(self.bundles.get_chunk_list(&bundle_id));
+            for (id, &(_, len)) in chunks.iter().enumerate() {
+                if len as usize <= max_chunk_size {
+                    samples.push(try!(self.bundles.get_chunk(&bundle_id, id)));
+                    sample_size += len as usize;
+                }
+            }
+            if sample_size >= max_sample_size {
+                break;
+            }
+        }
+        info!(
+            "Training dictionary on {} chunks with {}",
+            samples.len(),
+            to_file_size(sample_size as u64)
+        );
+        let dictionary = try!(train_dictionary(&samples, size));
+        let id = try!(self.bundles.add_dictionary(
+            dictionary,
+            self.config.hash,
+            self.config.encryption.clone()
+        ));
+        self.audit("dictionary-add", id.to_string());
+        Ok(id)
+    }
+
+    /// Sets the dictionary used to compress new bundles, the dictionary must be stored already
+    pub fn set_dictionary(&mut self, dictionary: Option<Hash>) -> Result<(), RepositoryError> {
+        if let Some(ref id) = dictionary {
+            if !self.bundles.has_dictionary(id) {
+                return Err(BundleDbError::NoSuchDictionary(*id).into());
+            }
+            if !self.config.compression.as_ref().map_or(false, |c| c.supports_dictionary()) {
+                warn!("Dictionaries are only used with zstd compression");
+            }
+        }
+        self.config.dictionary = dictionary;
+        Ok(())
+    }
+
     #[inline]
     fn save_bundle_map(&self) -> Result<(), RepositoryError> {
         try!(self.bundle_map.save(self.layout.bundle_map_path()));
@@ -284,7 +593,7 @@ impl Repository {
     }
 
     #[inline]
-    fn next_free_bundle_id(&self) -> u32 {
+    fn next_free_bundle_id(&self) -> u64 {
         let mut id = max(self.next_data_bundle, self.next_meta_bundle) + 1;
         while self.bundle_map.get(id).is_some() {
             id += 1;
@@ -359,8 +668,8 @@ impl Repository {
             if let Some(old) = try!(self.index.set(
                 &hash,
                 &Location {
-                    bundle: bundle_id as u32,
-                    chunk: i as u32
+                    bundle: bundle_id,
+                    chunk: i as u64
                 }
             ))
             {
@@ -386,6 +695,15 @@ impl Repository {
 
     #[inline]
     fn write_mode(&mut self) -> Result<(), RepositoryError> {
+        if self.read_only {
+            return Err(RepositoryError::ReadOnly);
+        }
+        self.local_write_mode()
+    }
+
+    /// Allows changes to the local state only, this is also possible in read-only clones
+    #[inline]
+    fn local_write_mode(&mut self) -> Result<(), RepositoryError> {
         Ok(try!(self.local_locks.upgrade(&mut self.lock)))
     }
 
@@ -394,10 +712,103 @@ impl Repository {
         Ok(try!(self.remote_locks.lock(exclusive)))
     }
 
+    /// Prevents concurrent backups of the same source into the same backup folder
+    ///
+    /// The lock is keyed by the host, the source path and the folder (`prefix`) of the backup
+    /// name and held until the returned lock is dropped. With `wait`, this waits for a running
+    /// backup to finish instead of failing with `LockError::Locked`. The lock files are never
+    /// removed, as removing them could let two processes lock different files.
+    pub fn lock_backup_source(
+        &self,
+        host: &str,
+        path: &str,
+        prefix: &str,
+        wait: bool,
+    ) -> Result<FileLock, RepositoryError> {
+        let locks_path = self.layout.backup_locks_path();
+        try!(fs::create_dir_all(&locks_path));
+        let key = format!("{}\0{}\0{}", host, path, prefix);
+        let name = format!("{}.lock", self.config.hash_chunk(key.as_bytes()));
+        Ok(try!(FileLock::acquire(locks_path.join(name), wait)))
+    }
+
     #[inline]
     pub fn set_clean(&mut self) {
         self.dirty = false;
     }
+
+    /// Sets whether every chunk is hashed again when reading data, this is on by default
+    ///
+    /// Without verification, corrupted chunks that still decode properly end up in restored
+    /// files unnoticed.
+    #[inline]
+    pub fn set_verify_reads(&mut self, verify: bool) {
+        self.verify_reads = verify;
+    }
+
+    /// Whether only the public key of the repository is available
+    ///
+    /// Such hosts can create encrypted backups and check the structure of the repository but
+    /// can not read any data, backups or bundle contents.
+    pub fn is_write_only(&self) -> bool {
+        self.config.encryption.as_ref().map_or(false, |enc| {
+            !self.crypto.lock().unwrap().can_decrypt(enc)
+        })
+    }
+
+    /// Limits the memory used by the repository, roughly
+    ///
+    /// A quarter of the limit is used for the resident pages of the memory-mapped index and
+    /// another quarter for the buffers of the bundles that are being written, so new bundles are
+    /// finished earlier if the configured bundle size does not fit. The rest is used to cache
+    /// decoded bundles, at most 10 of them. If not even one bundle fits, chunks are read one by
+    /// one from the bundles instead of decoding them as a whole, except for encrypted bundles.
+    pub fn set_memory_limit(&mut self, limit: usize) {
+        self.index.set_max_resident(limit / 4);
+        // One bundle each for data and metadata
+        let max_bundle_size = max(limit / 8, 1024 * 1024);
+        if max_bundle_size < self.config.bundle_size {
+            info!(
+                "Limiting the size of new bundles to {}",
+                to_file_size(max_bundle_size as u64)
+            );
+            self.max_bundle_size = Some(max_bundle_size);
+        } else {
+            self.max_bundle_size = None;
+        }
+        let cached = min(10, limit / 2 / max(self.config.bundle_size, 1));
+        if cached == 0 {
+            info!("Reading chunks from bundles without caching them");
+        } else {
+            debug!("Caching at most {} bundles", cached);
+        }
+        self.bundles.set_cache_size(cached);
+    }
+
+    /// Records the backup name in the info of all bundles created from now on
+    #[inline]
+    pub fn set_bundle_origin(&mut self, backup: Option<String>) {
+        self.bundle_origin = backup;
+    }
+
+    /// Records the storage tier hint in the info of all data bundles created from now on
+    ///
+    /// Metadata bundles are always kept in the standard tier as they are needed to list backups.
+    #[inline]
+    pub fn set_bundle_storage_class(&mut self, class: StorageClass) {
+        self.bundle_storage_class = class;
+    }
+
+    /// Reads the contents of remote bundles via a bundle proxy instead of the remote storage
+    #[inline]
+    pub fn set_proxy(&mut self, proxy: Option<String>) {
+        self.bundles.set_proxy(proxy)
+    }
+
+    /// Serves the bundles of this repository to other clients, see `set_proxy`
+    pub fn serve_proxy(&self, addr: &str) -> Result<(), RepositoryError> {
+        Ok(try!(self.bundles.serve_proxy(addr)))
+    }
 }
 
 
@@ -406,5 +817,60 @@ impl Drop for Repository {
         if let Err(err) = self.flush() {
             error!("Failed to flush repository: {}", err);
         }
+        if let Some(ref path) = self.temp_folder {
+            if let Err(err) = fs::remove_dir_all(path) {
+                warn!("Failed to remove temporary repository {:?}: {}", path, err);
+            }
+        }
+    }
+}
+
+
+#[cfg(any(test, feature = "memory"))]
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use rand;
+
+    #[test]
+    fn test_migrate_index_v1() {
+        let mut repo = Repository::create_temporary(Config::default()).unwrap();
+        let data: Vec<u8> = (0..1 << 20).map(|_| rand::random()).collect();
+        let chunks = repo.put_data(BundleMode::Data, &data).unwrap();
+        repo.flush().unwrap();
+        let mut locations: Vec<(Hash, Location)> = repo.index
+            .iter()
+            .map(|(hash, location)| (*hash, *location))
+            .collect();
+        assert!(!locations.is_empty());
+        // A rebuild from the bundles would not contain this entry, only a migration keeps it
+        let stale = (Hash { high: 1, low: 1 }, locations[0].1);
+        locations.push(stale);
+        let path = repo.layout.base_path().to_path_buf();
+        let index_path = repo.layout.index_path();
+        let temp_folder = repo.temp_folder.take();
+        drop(repo);
+        fs::remove_file(&index_path).unwrap();
+        {
+            let mut index = Index::<Hash, LocationV1>::create(&index_path, &INDEX_MAGIC, 1)
+                .unwrap();
+            for &(ref hash, ref location) in &locations {
+                let location = LocationV1 {
+                    bundle: location.bundle as u32,
+                    chunk: location.chunk as u32
+                };
+                index.set(hash, &location).unwrap();
+            }
+        }
+        let mut repo = Repository::open(&path).unwrap();
+        repo.temp_folder = temp_folder;
+        assert_eq!(repo.index.len(), locations.len());
+        for &(ref hash, ref location) in &locations {
+            assert_eq!(repo.index.get(hash), Some(*location));
+        }
+        assert_eq!(repo.get_data(&chunks).unwrap(), data);
     }
 }
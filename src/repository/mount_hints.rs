@@ -0,0 +1,49 @@
+use prelude::*;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+
+
+impl Repository {
+    /// Starts recording the bundles that chunks are read from for `save_mount_hints`
+    #[inline]
+    pub fn record_bundle_access(&mut self) {
+        self.bundles.record_access()
+    }
+
+    /// Writes the recorded bundles to the mount hints file, one bundle id per line
+    ///
+    /// The hints file is only replaced if any bundles have been accessed. Returns the number of
+    /// bundles written.
+    pub fn save_mount_hints(&self) -> Result<usize, RepositoryError> {
+        let bundles = self.bundles.accessed_bundles();
+        if bundles.is_empty() {
+            return Ok(0);
+        }
+        let path = self.layout.mount_hints_path();
+        let mut file = io::BufWriter::new(try!(File::create(&path)));
+        for bundle in &bundles {
+            try!(writeln!(file, "{}", bundle));
+        }
+        Ok(bundles.len())
+    }
+
+    /// Reads the bundles of the mount hints file in the background in the order of their access
+    ///
+    /// Returns the number of bundles that will be read.
+    pub fn prewarm_from_mount_hints(&self) -> Result<usize, RepositoryError> {
+        let path = self.layout.mount_hints_path();
+        if !path.exists() {
+            return Ok(0);
+        }
+        let mut bundles = vec![];
+        for line in BufReader::new(try!(File::open(&path))).lines() {
+            let line = try!(line);
+            match Hash::from_string(line.trim()) {
+                Ok(hash) => bundles.push(BundleId(hash)),
+                Err(_) => warn!("Invalid bundle id in mount hints: {}", line),
+            }
+        }
+        Ok(self.bundles.prewarm_bundles(&bundles))
+    }
+}
@@ -0,0 +1,218 @@
+use prelude::*;
+
+use chrono::prelude::*;
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+
+/// Escapes the characters that have a special meaning in HTML
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+
+/// Counts of a published tree
+#[derive(Default, Debug)]
+pub struct PublishStats {
+    pub dirs: usize,
+    pub files: usize,
+    /// Files with the same contents are only stored once
+    pub stored_files: usize,
+    pub stored_size: u64
+}
+
+
+impl Repository {
+    /// Returns the name of the page of the directory at the path, relative to `pages`
+    fn publish_page_name(&self, path: &str) -> String {
+        format!("{}.html", self.config.hash_chunk(path.as_bytes()))
+    }
+
+    /// Stores the contents of the file under their hash and returns the path relative to `out`
+    fn publish_file(
+        &mut self,
+        inode: &Inode,
+        out: &Path,
+        stats: &mut PublishStats,
+    ) -> Result<String, RepositoryError> {
+        // Files with the same contents have the same chunks, so they do not need to be read
+        let hash = match inode.data {
+            None => self.config.hash_chunk(&[]),
+            Some(FileData::Inline(ref data)) => self.config.hash_chunk(data),
+            Some(FileData::ChunkedDirect(ref chunks)) |
+            Some(FileData::ChunkedIndirect(ref chunks)) => {
+                let mut data = Vec::with_capacity(chunks.encoded_size());
+                chunks.write_to(&mut data).unwrap();
+                self.config.hash_chunk(&data)
+            }
+        };
+        let key = hash.to_string();
+        let rel_path = format!("files/{}/{}", &key[..2], key);
+        let path = out.join(&rel_path);
+        if path.exists() {
+            return Ok(rel_path);
+        }
+        try!(fs::create_dir_all(path.parent().unwrap()));
+        // Written under a temporary name first, so an interrupted run never leaves partial files
+        let tmp_path = path.with_extension("partial");
+        {
+            let mut file = BufWriter::new(try!(File::create(&tmp_path)));
+            match inode.data {
+                None => (),
+                Some(FileData::Inline(ref data)) => try!(file.write_all(data)),
+                Some(FileData::ChunkedDirect(ref chunks)) => {
+                    try!(self.get_stream(chunks, &mut file));
+                }
+                Some(FileData::ChunkedIndirect(ref chunks)) => {
+                    let chunk_data = try!(self.get_data(chunks));
+                    let chunks = ChunkList::read_from(&chunk_data);
+                    try!(self.get_stream(&chunks, &mut file));
+                }
+            }
+            try!(file.flush());
+        }
+        try!(fs::rename(&tmp_path, &path));
+        stats.stored_files += 1;
+        stats.stored_size += inode.size;
+        Ok(rel_path)
+    }
+
+    fn get_child_inodes(&mut self, inode: &Inode) -> Result<Vec<Inode>, RepositoryError> {
+        let mut children = vec![];
+        if let Some(ref chunks) = inode.children {
+            for chunks in chunks.values() {
+                children.push(try!(self.get_inode(chunks)));
+            }
+        }
+        Ok(children)
+    }
+
+    fn publish_dir(
+        &mut self,
+        title: &str,
+        children: Vec<Inode>,
+        path: &str,
+        out: &Path,
+        stats: &mut PublishStats,
+    ) -> Result<(), RepositoryError> {
+        let mut rows = String::new();
+        for child in children {
+            let name = escape_html(&child.name);
+            let date = Local.timestamp(child.timestamp, 0).format("%Y-%m-%d %H:%M");
+            let (link, size) = match child.file_type {
+                FileType::Directory => {
+                    let child_path = if path.is_empty() {
+                        child.name.clone()
+                    } else {
+                        format!("{}/{}", path, child.name)
+                    };
+                    let grandchildren = try!(self.get_child_inodes(&child));
+                    try!(self.publish_dir(title, grandchildren, &child_path, out, stats));
+                    let page = self.publish_page_name(&child_path);
+                    (format!("<a href=\"{}\">{}/</a>", page, name), "".to_string())
+                }
+                FileType::File => {
+                    let file = try!(self.publish_file(&child, out, stats));
+                    stats.files += 1;
+                    (
+                        format!("<a href=\"../{}\" download=\"{}\">{}</a>", file, name, name),
+                        to_file_size(child.size)
+                    )
+                }
+                FileType::Symlink => {
+                    let target = child.symlink_target.as_ref().map_or("", |t| t.as_str());
+                    (format!("{} &rarr; {}", name, escape_html(target)), "".to_string())
+                }
+                file_type => (format!("{} ({})", name, file_type), "".to_string()),
+            };
+            rows.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                link,
+                size,
+                date
+            ));
+        }
+        // Every ancestor gets a link, so the tree can be navigated upwards
+        let mut crumbs = format!(
+            "<a href=\"{}\">{}</a>",
+            self.publish_page_name(""),
+            escape_html(title)
+        );
+        let mut ancestor = String::new();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            if !ancestor.is_empty() {
+                ancestor.push('/');
+            }
+            ancestor.push_str(component);
+            crumbs.push_str(&format!(
+                " / <a href=\"{}\">{}</a>",
+                self.publish_page_name(&ancestor),
+                escape_html(component)
+            ));
+        }
+        let page = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{} /{}</title>\n\
+             </head>\n<body>\n<h1>{}</h1>\n<table>\n\
+             <tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n{}</table>\n</body>\n</html>\n",
+            escape_html(title),
+            escape_html(path),
+            crumbs,
+            rows
+        );
+        let mut file = try!(File::create(out.join("pages").join(self.publish_page_name(path))));
+        try!(file.write_all(page.as_bytes()));
+        stats.dirs += 1;
+        Ok(())
+    }
+
+    /// Writes the tree as a static website into the folder `out`
+    ///
+    /// Every directory gets an HTML page in `pages` and the contents of the files are stored in
+    /// `files`, named after the hash of their contents, so files with the same contents are only
+    /// stored once and the pages do not need any escaping in their links. `index.html` leads to
+    /// the page of the root. The pages do not need JavaScript, so the folder can be served by
+    /// any web server. Files that already exist in `out` are kept, so updating an earlier export
+    /// only stores new files.
+    pub fn publish_backup<P: AsRef<Path>>(
+        &mut self,
+        title: &str,
+        inode: Inode,
+        out: P,
+    ) -> Result<PublishStats, RepositoryError> {
+        let out = out.as_ref();
+        let mut stats = PublishStats::default();
+        try!(fs::create_dir_all(out.join("pages")));
+        // A single file is published as a directory containing only that file
+        let children = if inode.file_type == FileType::Directory {
+            try!(self.get_child_inodes(&inode))
+        } else {
+            vec![inode]
+        };
+        try!(self.publish_dir(title, children, "", out, &mut stats));
+        let index = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <meta http-equiv=\"refresh\" content=\"0; url=pages/{}\">\n<title>{}</title>\n\
+             </head>\n<body>\n<a href=\"pages/{}\">{}</a>\n</body>\n</html>\n",
+            self.publish_page_name(""),
+            escape_html(title),
+            self.publish_page_name(""),
+            escape_html(title)
+        );
+        let mut file = try!(File::create(out.join("index.html")));
+        try!(file.write_all(index.as_bytes()));
+        Ok(stats)
+    }
+}
@@ -0,0 +1,171 @@
+use prelude::*;
+
+use regex::RegexSet;
+
+use std::collections::{HashMap, HashSet};
+
+
+pub struct PurgeReport {
+    /// Purged paths as (backup name, path in backup)
+    pub paths: Vec<(String, String)>,
+    /// Bundles that still contain purged data, with their usage ratio
+    pub bundles: Vec<(BundleId, f32)>
+}
+
+
+struct PurgeState<'a> {
+    patterns: &'a RegexSet,
+    force: bool,
+    // Subtrees are shared between backups, so results are remembered by path and chunks
+    cache: HashMap<(String, ChunkList), (Option<ChunkList>, Vec<String>)>,
+    purged_chunks: HashSet<Hash>
+}
+
+
+impl Repository {
+    fn collect_tree_chunks(
+        &mut self,
+        chunks: ChunkList,
+        hashes: &mut HashSet<Hash>,
+    ) -> Result<(), RepositoryError> {
+        let mut todo = vec![chunks];
+        while let Some(chunks) = todo.pop() {
+            hashes.extend(chunks.iter().map(|c| c.0));
+            let inode = try!(self.get_inode(&chunks));
+            match inode.data {
+                None |
+                Some(FileData::Inline(_)) => (),
+                Some(FileData::ChunkedDirect(chunks)) => {
+                    hashes.extend(chunks.iter().map(|c| c.0));
+                }
+                Some(FileData::ChunkedIndirect(chunks)) => {
+                    hashes.extend(chunks.iter().map(|c| c.0));
+                    let chunk_data = try!(self.get_data(&chunks));
+                    hashes.extend(ChunkList::read_from(&chunk_data).iter().map(|c| c.0));
+                }
+            }
+            if let Some(children) = inode.children {
+                todo.extend(children.into_iter().map(|(_, chunks)| chunks));
+            }
+        }
+        Ok(())
+    }
+
+    fn purge_recurse(
+        &mut self,
+        chunks: &ChunkList,
+        path: &str,
+        state: &mut PurgeState,
+        purged: &mut Vec<String>,
+    ) -> Result<Option<ChunkList>, RepositoryError> {
+        let key = (path.to_string(), chunks.clone());
+        if let Some(&(ref result, ref paths)) = state.cache.get(&key) {
+            purged.extend_from_slice(paths);
+            return Ok(result.clone());
+        }
+        let mut inode = try!(self.get_inode(chunks));
+        let mut paths = vec![];
+        let mut changed = false;
+        if let Some(children) = inode.children.take() {
+            let mut new_children = children.clone();
+            for (name, child) in children {
+                let child_path = format!("{}/{}", path, name);
+                if state.patterns.is_match(&child_path) {
+                    new_children.remove(&name);
+                    if state.force {
+                        try!(self.collect_tree_chunks(child, &mut state.purged_chunks));
+                    }
+                    paths.push(child_path);
+                    changed = true;
+                } else if let Some(new_child) =
+                    try!(self.purge_recurse(&child, &child_path, state, &mut paths))
+                {
+                    new_children.insert(name, new_child);
+                    changed = true;
+                }
+            }
+            inode.children = Some(new_children);
+        }
+        let result = if !changed {
+            None
+        } else if state.force {
+            Some(try!(self.put_inode(&inode)))
+        } else {
+            Some(chunks.clone())
+        };
+        purged.extend_from_slice(&paths);
+        state.cache.insert(key, (result.clone(), paths));
+        Ok(result)
+    }
+
+    /// Removes all paths matching one of the patterns from all backups
+    ///
+    /// The patterns are matched against the paths within the backups starting with `/`. Without
+    /// `force` the matching paths are only reported. The purged data is only removed from the
+    /// storage when the bundles in the report are rewritten by a vacuum run.
+    pub fn purge(
+        &mut self,
+        patterns: &RegexSet,
+        force: bool,
+    ) -> Result<PurgeReport, RepositoryError> {
+        if force {
+            try!(self.write_mode());
+        }
+        let _lock = try!(self.lock(false));
+        let backups = try!(self.get_all_backups());
+        let mut names: Vec<_> = backups.keys().cloned().collect();
+        names.sort();
+        let mut state = PurgeState {
+            patterns: patterns,
+            force: force,
+            cache: HashMap::new(),
+            purged_chunks: HashSet::new()
+        };
+        let mut report = PurgeReport {
+            paths: vec![],
+            bundles: vec![]
+        };
+        for name in names {
+            let mut backup = backups[&name].clone();
+            let mut purged = vec![];
+            let root = backup.root.clone();
+            if let Some(new_root) = try!(self.purge_recurse(&root, "", &mut state, &mut purged)) {
+                if force {
+                    backup.root = new_root;
+                    backup.modified = true;
+                    try!(self.save_backup(&backup, &name));
+                }
+            }
+            report.paths.extend(purged.into_iter().map(|path| (name.clone(), path)));
+        }
+        if !force || report.paths.is_empty() {
+            return Ok(report);
+        }
+        try!(self.flush());
+        let details = report
+            .paths
+            .iter()
+            .map(|&(ref backup, ref path)| format!("{}::{}", backup, path))
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.audit("purge", details);
+        // Chunks that are still used by other files do not need to be removed
+        let usage = try!(self.analyze_usage());
+        let mut affected = HashSet::new();
+        for hash in &state.purged_chunks {
+            if let Some(pos) = self.index.get(hash) {
+                if let Some(bundle) = usage.get(&pos.bundle) {
+                    if !bundle.chunk_usage.get(pos.chunk as usize) {
+                        affected.insert(pos.bundle);
+                    }
+                }
+            }
+        }
+        for id in affected {
+            let bundle = &usage[&id];
+            report.bundles.push((bundle.info.id.clone(), bundle.get_usage_ratio()));
+        }
+        report.bundles.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(report)
+    }
+}
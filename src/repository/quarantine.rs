@@ -0,0 +1,53 @@
+use prelude::*;
+
+use chrono::prelude::*;
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+
+/// Moves a damaged file into the quarantine folder and describes it in the quarantine report
+///
+/// The file is kept unmodified so that its data can still be recovered manually. The path `dst`
+/// is relative to the quarantine folder, a number is appended if it already exists. The
+/// `details` are added to the report line by line. Returns the new path of the file.
+pub fn quarantine_file(
+    layout: &RepositoryLayout,
+    src: &Path,
+    dst: &Path,
+    reason: &str,
+    details: &[String],
+) -> Result<PathBuf, io::Error> {
+    let base = layout.quarantine_path();
+    let mut path = base.join(dst);
+    let mut num = 1;
+    while path.exists() {
+        path = base.join(format!("{}.{}", dst.to_string_lossy(), num));
+        num += 1;
+    }
+    try!(fs::create_dir_all(path.parent().unwrap()));
+    if fs::rename(src, &path).is_err() {
+        try!(fs::copy(src, &path));
+        try!(fs::remove_file(src));
+    }
+    let mut entry = format!(
+        "{}: moved {:?} to {:?}\n  reason: {}\n",
+        Local::now().format("%Y-%m-%d %H:%M:%S"),
+        src.strip_prefix(layout.base_path()).unwrap_or(src),
+        path.strip_prefix(&base).unwrap_or(&path),
+        reason
+    );
+    for line in details {
+        entry.push_str(&format!("  {}\n", line));
+    }
+    let mut file = try!(
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(layout.quarantine_report_path())
+    );
+    // Writing the entry at once so that concurrent writers do not interleave
+    try!(file.write_all(entry.as_bytes()));
+    Ok(path)
+}
@@ -0,0 +1,137 @@
+use prelude::*;
+
+use chrono::prelude::*;
+
+use std::collections::HashSet;
+
+
+/// Maximal number of consecutive chunks that are combined when looking for an inode
+///
+/// The metadata of large directories is split into several chunks that are usually stored
+/// next to each other in the same bundle.
+const MAX_INODE_CHUNKS: usize = 16;
+
+
+/// Tries to decode an inode from the data of the first chunks
+///
+/// Returns the number of chunks that contain the inode together with the inode.
+fn find_inode(chunks: &[Vec<u8>]) -> Option<(usize, Inode)> {
+    let mut data = vec![];
+    for (i, chunk) in chunks.iter().take(MAX_INODE_CHUNKS).enumerate() {
+        data.extend_from_slice(chunk);
+        if let Ok(inode) = Inode::decode(&data) {
+            return Some((i + 1, inode));
+        }
+    }
+    None
+}
+
+
+impl Repository {
+    /// Scans the meta bundle for inodes, the chunks of directories are added to `dirs`
+    ///
+    /// The chunks of the children of all found directories are added to `referenced`.
+    fn scan_meta_bundle(
+        &mut self,
+        bundle: &BundleInfo,
+        dirs: &mut Vec<(ChunkList, Inode, BundleInfo)>,
+        referenced: &mut HashSet<ChunkList>,
+    ) -> Result<(), RepositoryError> {
+        let chunks = try!(self.bundles.get_chunk_list(&bundle.id));
+        let mut data = Vec::with_capacity(chunks.len());
+        for i in 0..chunks.len() {
+            data.push(try!(self.bundles.get_chunk(&bundle.id, i)));
+        }
+        let mut pos = 0;
+        while pos < chunks.len() {
+            let (count, inode) = match find_inode(&data[pos..]) {
+                Some(found) => found,
+                None => {
+                    pos += 1;
+                    continue;
+                }
+            };
+            if let Some(ref children) = inode.children {
+                referenced.extend(children.values().cloned());
+            }
+            if inode.file_type == FileType::Directory {
+                let inode_chunks = chunks[pos..pos + count].to_vec().into();
+                dirs.push((inode_chunks, inode, bundle.clone()));
+            }
+            pos += count;
+        }
+        Ok(())
+    }
+
+    /// Recreates lost backup files from the root directories found in the meta bundles
+    ///
+    /// All meta bundles are scanned for directory inodes that are neither the child of another
+    /// directory nor the root of an existing backup. For each of them, a backup named
+    /// `rebuilt/NAME` is created, using the name of the backup recorded in the bundle if
+    /// known. Host and date are taken from the bundle, the source path is unknown. Also
+    /// backups that have been removed since the last vacuum are found again.
+    pub fn rebuild_backups(&mut self) -> Result<Vec<String>, RepositoryError> {
+        try!(self.write_mode());
+        try!(self.flush());
+        let _lock = try!(self.lock(false));
+        let backups = match self.get_all_backups() {
+            Ok(backups) => backups,
+            Err(RepositoryError::BackupFile(BackupFileError::PartialBackupsList(backups, _))) => {
+                warn!("Some backups could not be read, ignoring them");
+                backups
+            }
+            Err(err) => return Err(err),
+        };
+        let mut known_roots: HashSet<_> = backups.into_iter().map(|(_, b)| b.root).collect();
+        for (_name, backup, _removed) in try!(self.get_trashed_backups()) {
+            known_roots.insert(backup.root);
+        }
+        let mut bundles: Vec<_> = self.bundles
+            .list_bundles()
+            .into_iter()
+            .filter(|bundle| bundle.mode == BundleMode::Meta)
+            .cloned()
+            .collect();
+        bundles.sort_by_key(|bundle| bundle.timestamp);
+        let mut dirs = vec![];
+        let mut referenced = HashSet::new();
+        for bundle in ProgressIter::new("scanning meta bundles", bundles.len(), bundles.iter()) {
+            if let Err(err) = self.scan_meta_bundle(bundle, &mut dirs, &mut referenced) {
+                warn!("Failed to scan meta bundle {}: {}", bundle.id, err);
+            }
+        }
+        let mut rebuilt = vec![];
+        for (root, inode, bundle) in dirs {
+            if referenced.contains(&root) || known_roots.contains(&root) {
+                continue;
+            }
+            let base_name = format!(
+                "rebuilt/{}",
+                bundle.backup.clone().unwrap_or_else(|| {
+                    Local.timestamp(bundle.timestamp, 0).format("%Y-%m-%d_%H%M%S").to_string()
+                })
+            );
+            let mut name = base_name.clone();
+            let mut num = 1;
+            while self.has_backup(&name) {
+                num += 1;
+                name = format!("{}-{}", base_name, num);
+            }
+            let mut backup = Backup::default();
+            backup.config = self.config.for_backup();
+            backup.root = root.clone();
+            backup.timestamp = bundle.timestamp;
+            backup.host = bundle.host.clone().unwrap_or_default();
+            backup.total_data_size = inode.cum_size;
+            backup.file_count = inode.cum_files;
+            backup.dir_count = inode.cum_dirs;
+            backup.modified = true;
+            backup.tags = vec!["rebuilt".to_string()];
+            try!(self.save_backup(&backup, &name));
+            info!("Rebuilt backup {} from meta bundle {}", name, bundle.id);
+            known_roots.insert(root);
+            rebuilt.push(name);
+        }
+        Ok(rebuilt)
+    }
+}
@@ -0,0 +1,257 @@
+use prelude::*;
+
+use serde_yaml;
+use rand::{self, Rng};
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::config::ConfigError;
+
+
+/// Differences between the clock of the remote storage and the local one that are tolerated
+pub const MAX_CLOCK_SKEW: i64 = 60;
+
+
+/// Returns whether the additional remote storage in the folder is currently available
+pub fn remote_reachable(path: &Path) -> bool {
+    path.join("bundles").is_dir() && path.join("backups").is_dir()
+}
+
+
+/// Results of `check_remote`, times are in seconds
+pub struct RemoteCheck {
+    /// Number of entries in the bundle folder and the time it took to list them
+    pub list_entries: usize,
+    pub list_time: f32,
+    /// Folders that no files can be created in with the error
+    pub unwritable: Vec<(String, String)>,
+    pub test_size: usize,
+    pub upload_time: Option<f32>,
+    pub download_time: Option<f32>,
+    /// Whether the test bundle has been read back unchanged
+    pub data_ok: bool,
+    /// Difference of the modification time of the test bundle to the local clock
+    pub clock_skew: Option<i64>
+}
+
+impl RemoteCheck {
+    pub fn is_ok(&self) -> bool {
+        self.unwritable.is_empty() && self.data_ok &&
+            self.clock_skew.map_or(true, |skew| skew.abs() <= MAX_CLOCK_SKEW)
+    }
+}
+
+fn to_secs(duration: Duration) -> f32 {
+    duration.as_secs() as f32 + duration.subsec_nanos() as f32 / 1_000_000_000.0
+}
+
+fn unix_time(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(dur) => dur.as_secs() as i64,
+        Err(err) => -(err.duration().as_secs() as i64),
+    }
+}
+
+fn check_file_name() -> String {
+    // Not ending in .bundle, so scans of the remote storage ignore leftovers
+    format!("zvault-check-{:016x}.tmp", rand::random::<u64>())
+}
+
+fn check_writable(folder: &Path) -> Result<(), io::Error> {
+    let path = folder.join(check_file_name());
+    try!(File::create(&path));
+    fs::remove_file(&path)
+}
+
+/// Checks that the remote storage in the folder works as zvault needs it
+///
+/// The bundle folder is listed, test files are created in all folders that zvault writes to and
+/// a test bundle of `test_size` random bytes is written, read back and removed again. The
+/// modification time that the storage gives the test bundle shows whether its clock differs
+/// from the local one. Only a remote storage that can not be listed at all is an error.
+pub fn check_remote(path: &Path, test_size: usize) -> Result<RemoteCheck, io::Error> {
+    let start = Instant::now();
+    let list_entries = try!(fs::read_dir(path.join("bundles"))).count();
+    let mut check = RemoteCheck {
+        list_entries: list_entries,
+        list_time: to_secs(start.elapsed()),
+        unwritable: vec![],
+        test_size: test_size,
+        upload_time: None,
+        download_time: None,
+        data_ok: false,
+        clock_skew: None
+    };
+    for folder in &["backups", "locks"] {
+        if let Err(err) = check_writable(&path.join(folder)) {
+            check.unwritable.push((folder.to_string(), err.to_string()));
+        }
+    }
+    let mut data = vec![0; test_size];
+    rand::thread_rng().fill_bytes(&mut data);
+    let test_path = path.join("bundles").join(check_file_name());
+    let before = unix_time(SystemTime::now());
+    let start = Instant::now();
+    let written = File::create(&test_path).and_then(|mut file| {
+        try!(file.write_all(&data));
+        file.sync_all()
+    });
+    if let Err(err) = written {
+        let _ = fs::remove_file(&test_path);
+        check.unwritable.push(("bundles".to_string(), err.to_string()));
+        return Ok(check);
+    }
+    check.upload_time = Some(to_secs(start.elapsed()));
+    let after = unix_time(SystemTime::now());
+    if let Ok(mtime) = fs::metadata(&test_path).and_then(|meta| meta.modified()) {
+        let mtime = unix_time(mtime);
+        check.clock_skew = Some(if mtime < before {
+            mtime - before
+        } else if mtime > after {
+            mtime - after
+        } else {
+            0
+        });
+    }
+    let start = Instant::now();
+    let mut read = Vec::with_capacity(test_size);
+    if File::open(&test_path).and_then(|mut file| file.read_to_end(&mut read)).is_ok() {
+        check.download_time = Some(to_secs(start.elapsed()));
+        check.data_ok = read == data;
+    }
+    try!(fs::remove_file(&test_path));
+    Ok(check)
+}
+
+
+/// Returns the additional named remotes of the repository with their paths
+///
+/// Unlike `Repository::get_remotes`, this does not need an opened repository.
+pub fn load_remotes(
+    layout: &RepositoryLayout,
+) -> Result<BTreeMap<String, PathBuf>, RepositoryError> {
+    let path = layout.remotes_path();
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let file = try!(File::open(path));
+    let remotes: BTreeMap<String, PathBuf> =
+        try!(serde_yaml::from_reader(file).map_err(ConfigError::from));
+    Ok(remotes)
+}
+
+
+impl Repository {
+    /// Returns the additional named remotes of the repository with their paths
+    #[inline]
+    pub fn get_remotes(&self) -> Result<BTreeMap<String, PathBuf>, RepositoryError> {
+        load_remotes(&self.layout)
+    }
+
+    fn save_remotes(&self, remotes: &BTreeMap<String, PathBuf>) -> Result<(), RepositoryError> {
+        let mut file = try!(File::create(self.layout.remotes_path()));
+        try!(serde_yaml::to_writer(&mut file, remotes).map_err(ConfigError::from));
+        Ok(())
+    }
+
+    /// Adds an additional remote storage in the given folder
+    ///
+    /// The folder is prepared to hold copies of the bundles and backups. An existing remote with
+    /// the same name is replaced.
+    pub fn add_remote(&mut self, name: &str, path: &Path) -> Result<(), RepositoryError> {
+        let mut remotes = try!(self.get_remotes());
+        try!(fs::create_dir_all(path.join("bundles")));
+        try!(fs::create_dir_all(path.join("backups")));
+        try!(fs::create_dir_all(path.join("dictionaries")));
+        try!(fs::create_dir_all(path.join("locks")));
+        remotes.insert(name.to_string(), path.to_path_buf());
+        self.save_remotes(&remotes)
+    }
+
+    /// Removes the named remote from the repository, its contents are not deleted
+    pub fn remove_remote(&mut self, name: &str) -> Result<(), RepositoryError> {
+        let mut remotes = try!(self.get_remotes());
+        if remotes.remove(name).is_none() {
+            return Err(RepositoryError::NoSuchRemote(name.to_string()));
+        }
+        self.save_remotes(&remotes)
+    }
+
+    /// Copies all bundles written from now on to the named remotes as well
+    ///
+    /// Remotes that are not reachable are skipped with a warning. Returns the names of the remotes
+    /// that will receive the bundles.
+    pub fn set_mirrors(&mut self, names: &[String]) -> Result<Vec<String>, RepositoryError> {
+        let remotes = try!(self.get_remotes());
+        let mut mirrors = vec![];
+        for name in names {
+            let path = match remotes.get(name) {
+                Some(path) => path,
+                None => return Err(RepositoryError::NoSuchRemote(name.to_string())),
+            };
+            if remote_reachable(path) {
+                mirrors.push((name.to_string(), path.to_path_buf()));
+            } else {
+                warn!("Remote {} is not reachable at {:?}, skipping it", name, path);
+            }
+        }
+        let names = mirrors.iter().map(|&(ref name, _)| name.clone()).collect();
+        self.bundles.set_mirrors(mirrors);
+        Ok(names)
+    }
+
+    /// Stores a copy of the backup on all remotes set via `set_mirrors`
+    ///
+    /// Bundles that did not reach a remote during the backup, e.g. those of earlier backups, are
+    /// copied as well. The backup is only written to remotes that contain all bundles and the
+    /// names of those remotes are recorded in `backup.remotes`.
+    pub fn mirror_backup(
+        &mut self,
+        backup: &mut Backup,
+        name: &str,
+    ) -> Result<(), RepositoryError> {
+        try!(self.flush());
+        let mut complete = vec![];
+        for (remote, path) in self.bundles.mirrors().to_vec() {
+            if self.bundles.failed_mirrors().contains(&remote) {
+                warn!("Remote {} is missing some bundles, not storing the backup there", remote);
+                continue;
+            }
+            match self.bundles.sync_to_mirror(&path) {
+                Ok(0) => (),
+                Ok(count) => info!("Copied {} missing bundles to remote {}", count, remote),
+                Err(err) => {
+                    warn!("Failed to copy bundles to remote {}\n\tcaused by: {}", remote, err);
+                    continue;
+                }
+            }
+            complete.push((remote, path));
+        }
+        backup.remotes = complete.iter().map(|&(ref remote, _)| remote.clone()).collect();
+        for (remote, path) in complete {
+            let backup_path = path.join("backups").join(format!("{}.backup", name));
+            let res = fs::create_dir_all(backup_path.parent().unwrap())
+                .map_err(RepositoryError::from)
+                .and_then(|()| {
+                    Ok(try!(backup.save_to(
+                        &self.crypto.lock().unwrap(),
+                        self.config.encryption.clone(),
+                        &backup_path
+                    )))
+                });
+            match res {
+                Ok(()) => info!("Stored a copy of the backup on remote {}", remote),
+                Err(err) => {
+                    warn!("Failed to store backup on remote {}\n\tcaused by: {}", remote, err);
+                    backup.remotes.retain(|r| r != &remote);
+                }
+            }
+        }
+        self.bundles.set_mirrors(vec![]);
+        Ok(())
+    }
+}
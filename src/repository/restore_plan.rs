@@ -0,0 +1,225 @@
+use prelude::*;
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+
+struct PlannedChunk {
+    bundle: u64,
+    chunk: u64,
+    hash: Hash,
+    len: u32,
+    file: usize,
+    offset: u64
+}
+
+
+/// Chunks of one bundle that are stored consecutively in one file
+struct PlannedRun {
+    bundle: u64,
+    file: usize,
+    start: u64,
+    end: u64,
+    ids: Vec<usize>,
+    chunks: Vec<Chunk>
+}
+
+
+/// Collects the file data of a restore so that it can be written bundle by bundle
+///
+/// Chunks of different files are often stored interleaved in the same bundles. Writing the files
+/// one after another would fetch those bundles many times once they drop out of the cache.
+#[derive(Default)]
+pub struct RestorePlan {
+    files: Vec<(PathBuf, Inode)>,
+    // Directories in the order they were created, parents before their children
+    directories: Vec<(PathBuf, Inode)>,
+    chunks: Vec<PlannedChunk>,
+    // Files with the same contents as an earlier file as (file, source)
+    clones: Vec<(usize, usize)>,
+    sources: HashMap<ChunkList, usize>
+}
+
+impl RestorePlan {
+    #[inline]
+    pub fn new() -> Self {
+        RestorePlan::default()
+    }
+
+    /// Adds a directory whose metadata is set after all of its contents have been written
+    pub fn add_directory(&mut self, path: PathBuf, inode: &Inode) {
+        let mut inode = inode.clone();
+        inode.children = None;
+        self.directories.push((path, inode));
+    }
+}
+
+
+impl Repository {
+    /// Creates the inode in the directory `path` and adds its data to the plan
+    ///
+    /// Files are created empty, their data and metadata are written by `execute_restore_plan`.
+    /// Only inline data is written immediately, as it is part of the inode. The metadata of
+    /// directories is also set there as creating their contents changes it. Other inodes are
+    /// created immediately.
+    pub fn plan_inode_at(
+        &mut self,
+        inode: &Inode,
+        path: &Path,
+        plan: &mut RestorePlan,
+    ) -> Result<(), RepositoryError> {
+        if inode.file_type == FileType::Directory {
+            try!(inode.create_at(path));
+            let full_path = path.join(&inode.name);
+            // The directory needs to stay writable until its contents have been created
+            if inode.mode & 0o700 != 0o700 {
+                try!(set_mode(&full_path, inode.mode | 0o700));
+            }
+            plan.add_directory(full_path, inode);
+            return Ok(());
+        }
+        let (key, chunks) = match inode.data {
+            Some(FileData::ChunkedDirect(ref chunks)) => (chunks.clone(), chunks.clone()),
+            Some(FileData::ChunkedIndirect(ref chunks)) => {
+                let chunk_data = try!(self.get_data(chunks));
+                (chunks.clone(), ChunkList::read_from(&chunk_data))
+            }
+            Some(FileData::Inline(ref data)) => {
+                if let Some(mut file) = try!(inode.create_at(path)) {
+                    try!(file.write_all(data));
+                }
+                // Writing the data has changed the modification time
+                let mut inode = inode.clone();
+                inode.data = None;
+                plan.files.push((path.join(&inode.name), inode));
+                return Ok(());
+            }
+            None => {
+                try!(inode.create_at(path));
+                // Flags are set last as they might prevent further modifications
+                if let Some(flags) = inode.flags {
+                    let full_path = path.join(&inode.name);
+                    if let Err(err) = set_file_flags(&full_path, flags) {
+                        warn!("Failed to set flags {:x} on {:?}: {}", flags, full_path, err);
+                    }
+                }
+                return Ok(());
+            }
+        };
+        try!(inode.create_at(path));
+        let full_path = path.join(&inode.name);
+        // The file needs to stay writable until its data has been written
+        if inode.mode & 0o200 == 0 {
+            try!(set_mode(&full_path, inode.mode | 0o200));
+        }
+        let file = plan.files.len();
+        let source = plan.sources.get(&key).cloned();
+        if let Some(source) = source {
+            plan.clones.push((file, source));
+        } else {
+            plan.sources.insert(key, file);
+            let mut offset = 0;
+            for &(hash, len) in chunks.iter() {
+                let location = try!(self.index.get(&hash).ok_or_else(|| {
+                    IntegrityError::MissingChunk(hash)
+                }));
+                plan.chunks.push(PlannedChunk {
+                    bundle: location.bundle,
+                    chunk: location.chunk,
+                    hash: hash,
+                    len: len,
+                    file: file,
+                    offset: offset
+                });
+                offset += len as u64;
+            }
+        }
+        let mut inode = inode.clone();
+        inode.data = None;
+        plan.files.push((full_path, inode));
+        Ok(())
+    }
+
+    /// Writes the data of all files in the plan reading each bundle only once
+    ///
+    /// Consecutive chunks of a file that are stored in the same bundle are written together by
+    /// `BundleDb::write_chunks`, so their lengths and, with `verify_reads`, their hashes are
+    /// checked before anything is written. The next bundle is loaded in the background meanwhile.
+    pub fn execute_restore_plan(&mut self, mut plan: RestorePlan) -> Result<(), RepositoryError> {
+        plan.chunks.sort_by_key(|c| (c.bundle, c.chunk));
+        let mut runs: Vec<PlannedRun> = vec![];
+        for chunk in &plan.chunks {
+            if let Some(run) = runs.last_mut() {
+                if run.bundle == chunk.bundle && run.file == chunk.file && run.end == chunk.offset {
+                    run.end += chunk.len as u64;
+                    run.ids.push(chunk.chunk as usize);
+                    run.chunks.push((chunk.hash, chunk.len));
+                    continue;
+                }
+            }
+            runs.push(PlannedRun {
+                bundle: chunk.bundle,
+                file: chunk.file,
+                start: chunk.offset,
+                end: chunk.offset + chunk.len as u64,
+                ids: vec![chunk.chunk as usize],
+                chunks: vec![(chunk.hash, chunk.len)]
+            });
+        }
+        {
+            let verify = self.verify_reads;
+            let mut current: Option<(usize, File)> = None;
+            let iter = ProgressIter::new("restoring data", runs.len(), runs.iter().enumerate());
+            for (i, run) in iter {
+                if let Some(next) = runs.get(i + 1) {
+                    if next.bundle != run.bundle {
+                        let next_id = try!(self.get_bundle_id(next.bundle));
+                        try!(self.bundles.prefetch_bundle(&next_id));
+                    }
+                }
+                let path = &plan.files[run.file].0;
+                if current.as_ref().map_or(true, |&(file, _)| file != run.file) {
+                    current = Some((run.file, try!(OpenOptions::new().write(true).open(path))));
+                }
+                let file = &mut current.as_mut().unwrap().1;
+                try!(file.seek(SeekFrom::Start(run.start)));
+                let bundle_id = try!(self.get_bundle_id(run.bundle));
+                try!(self.bundles.write_chunks(
+                    &bundle_id,
+                    &run.ids,
+                    &run.chunks,
+                    file,
+                    path,
+                    verify
+                ));
+            }
+        }
+        for &(file, source) in &plan.clones {
+            let path = &plan.files[file].0;
+            let source = &plan.files[source].0;
+            let cloned = File::open(source).and_then(|src| {
+                OpenOptions::new().write(true).open(path).and_then(
+                    |dst| clone_file(&src, &dst)
+                )
+            });
+            if let Err(err) = cloned {
+                debug!("Failed to clone {:?}, copying data instead: {}", source, err);
+                try!(fs::copy(source, path));
+            }
+        }
+        // Writing the data has changed the modification times, children have to be handled
+        // before their parent directories
+        let directories = plan.directories.into_iter().rev();
+        for (path, inode) in plan.files.into_iter().chain(directories) {
+            inode.set_metadata_at(&path);
+            if let Some(flags) = inode.flags {
+                if let Err(err) = set_file_flags(&path, flags) {
+                    warn!("Failed to set flags {:x} on {:?}: {}", flags, path, err);
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+use prelude::*;
+
+use rand::{self, Rng};
+
+use std::path::PathBuf;
+
+
+/// The result of restoring a random sample of files, see `Repository::check_sample`
+#[derive(Default)]
+pub struct SampleReport {
+    /// Number of sampled files that have been restored
+    pub files: usize,
+    /// Amount of restored data
+    pub size: u64,
+    /// Files that could not be restored as (backup, path, error)
+    pub failed: Vec<(String, PathBuf, String)>
+}
+
+
+/// Picks a random index with a probability proportional to its weight
+fn pick_weighted<R: Rng>(rng: &mut R, weights: &[u64]) -> Option<usize> {
+    let total: u64 = weights.iter().sum();
+    if total == 0 {
+        return None;
+    }
+    let mut pos = rng.gen_range(0, total);
+    for (i, &weight) in weights.iter().enumerate() {
+        if pos < weight {
+            return Some(i);
+        }
+        pos -= weight;
+    }
+    None
+}
+
+
+impl Repository {
+    /// Restores the data of the file without storing it and returns its size
+    ///
+    /// Every chunk is compared with its hash and the total size with the size of the inode.
+    fn restore_sample_file(&mut self, inode: &Inode) -> Result<u64, RepositoryError> {
+        let mut size = 0;
+        let chunks = match inode.data {
+            None => ChunkList::new(),
+            Some(FileData::Inline(ref data)) => {
+                size = data.len() as u64;
+                ChunkList::new()
+            }
+            Some(FileData::ChunkedDirect(ref chunks)) => chunks.clone(),
+            Some(FileData::ChunkedIndirect(ref chunks)) => {
+                let mut chunk_data = vec![];
+                for &(hash, len) in chunks.iter() {
+                    let data = try!(self.get_verified_chunk(hash, len));
+                    chunk_data.extend_from_slice(&data);
+                }
+                ChunkList::read_from(&chunk_data)
+            }
+        };
+        for &(hash, len) in chunks.iter() {
+            size += try!(self.get_verified_chunk(hash, len)).len() as u64;
+        }
+        if size != inode.size {
+            return Err(IntegrityError::WrongFileSize(inode.size, size).into());
+        }
+        Ok(size)
+    }
+
+    /// Picks a random file of the backup by descending the tree weighted by the file counts
+    ///
+    /// The path of the picked file is stored in `path`, also when the tree is broken.
+    fn pick_sample_file<R: Rng>(
+        &mut self,
+        rng: &mut R,
+        backup: &Backup,
+        path: &mut PathBuf,
+    ) -> Result<Inode, RepositoryError> {
+        let mut inode = try!(self.get_inode(&backup.root));
+        while let Some(children) = inode.children.take() {
+            let mut candidates = Vec::with_capacity(children.len());
+            for (name, chunks) in children {
+                let child = try!(self.get_inode(&chunks));
+                candidates.push((name, child));
+            }
+            let weights: Vec<_> = candidates.iter().map(|c| c.1.cum_files).collect();
+            match pick_weighted(rng, &weights) {
+                Some(i) => {
+                    let (name, child) = candidates.swap_remove(i);
+                    path.push(name);
+                    inode = child;
+                }
+                None => break,
+            }
+        }
+        Ok(inode)
+    }
+
+    /// Restores a random sample of the files in the backups as an end-to-end test
+    ///
+    /// About `ratio` of all files in the backups are picked, so backups with more files are
+    /// sampled more often. The data is not written anywhere but all chunks are read from the
+    /// bundles and compared with their hashes.
+    pub fn check_sample(
+        &mut self,
+        backups: &[(String, Backup)],
+        ratio: f32,
+    ) -> Result<SampleReport, RepositoryError> {
+        let mut rng = rand::thread_rng();
+        let weights: Vec<_> = backups.iter().map(|b| b.1.file_count).collect();
+        let total: u64 = weights.iter().sum();
+        let count = ((total as f64 * ratio as f64).ceil() as u64).min(total) as usize;
+        info!("Restoring {} sampled files from {} backups...", count, backups.len());
+        let mut report = SampleReport::default();
+        for _ in ProgressIter::new("restoring samples", count, 0..count) {
+            let (ref name, ref backup) = backups[pick_weighted(&mut rng, &weights).unwrap()];
+            let mut path = PathBuf::new();
+            let result = match self.pick_sample_file(&mut rng, backup, &mut path) {
+                Ok(ref inode) if inode.file_type == FileType::File => {
+                    debug!("Restoring {}::{:?}", name, path);
+                    self.restore_sample_file(inode)
+                }
+                // Only regular files have data that can be restored
+                Ok(_) => continue,
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(size) => {
+                    report.files += 1;
+                    report.size += size;
+                }
+                Err(err) => report.failed.push((name.clone(), path, err.to_string())),
+            }
+        }
+        Ok(report)
+    }
+}
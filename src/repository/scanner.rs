@@ -0,0 +1,125 @@
+use prelude::*;
+
+use std::cmp::max;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use crossbeam::sync::MsQueue;
+
+
+/// Default number of threads that read directories ahead of the backup
+pub const SCAN_THREADS: usize = 4;
+
+
+/// Entries of a directory together with their metadata
+pub type DirEntries = Vec<(fs::DirEntry, fs::Metadata)>;
+
+/// Lists the directory and reads the metadata of all its entries
+pub fn scan_dir(path: &Path, dereference: bool) -> Result<DirEntries, io::Error> {
+    let mut entries = vec![];
+    for ch in try!(fs::read_dir(path)) {
+        let child = try!(ch);
+        let child_meta = try!(if dereference {
+            fs::metadata(child.path()).or_else(|_| child.metadata())
+        } else {
+            child.metadata()
+        });
+        entries.push((child, child_meta));
+    }
+    Ok(entries)
+}
+
+
+struct ScanJob {
+    path: PathBuf,
+    result: Sender<Result<DirEntries, io::Error>>
+}
+
+
+/// Reads directories ahead of the backup on a pool of threads
+///
+/// On network filesystems, walking the source is dominated by the latency of listing the
+/// directories and reading the metadata of their entries. The backup requests the directories
+/// that it is going to visit and takes their entries when it gets there, so the order in which
+/// the entries are processed and stored does not depend on the threads. At most `limit`
+/// directories are read ahead, further requests are ignored and those directories are read
+/// when they are taken.
+pub struct DirScanner {
+    threads: usize,
+    limit: usize,
+    dereference: bool,
+    queue: Arc<MsQueue<Option<ScanJob>>>,
+    pending: HashMap<PathBuf, Receiver<Result<DirEntries, io::Error>>>
+}
+
+impl DirScanner {
+    pub fn new(threads: usize, dereference: bool) -> Self {
+        let threads = max(threads, 1);
+        let queue = Arc::new(MsQueue::new());
+        for num in 0..threads {
+            let queue = queue.clone();
+            thread::Builder::new()
+                .name(format!("scan-{}", num))
+                .spawn(move || {
+                    pin_worker(num);
+                    Self::worker_thread(&queue, dereference)
+                })
+                .unwrap();
+        }
+        DirScanner {
+            threads: threads,
+            limit: threads * 16,
+            dereference: dereference,
+            queue: queue,
+            pending: HashMap::new()
+        }
+    }
+
+    fn worker_thread(queue: &MsQueue<Option<ScanJob>>, dereference: bool) {
+        while let Some(job) = queue.pop() {
+            // The backup might not need the directory anymore
+            let _ = job.result.send(scan_dir(&job.path, dereference));
+        }
+    }
+
+    /// Starts reading the directory in the background unless too many are pending already
+    pub fn request(&mut self, path: PathBuf) {
+        if self.pending.len() >= self.limit || self.pending.contains_key(&path) {
+            return;
+        }
+        let (sender, receiver) = mpsc::channel();
+        self.queue.push(Some(ScanJob {
+            path: path.clone(),
+            result: sender
+        }));
+        self.pending.insert(path, receiver);
+    }
+
+    /// Returns the entries of the directory, waiting for a pending request if there is one
+    pub fn take(&mut self, path: &Path) -> Result<DirEntries, io::Error> {
+        if let Some(receiver) = self.pending.remove(path) {
+            if let Ok(result) = receiver.recv() {
+                return result;
+            }
+        }
+        scan_dir(path, self.dereference)
+    }
+
+    /// Drops a pending request for a directory that is not going to be taken
+    pub fn forget(&mut self, path: &Path) {
+        self.pending.remove(path);
+    }
+}
+
+impl Drop for DirScanner {
+    fn drop(&mut self) {
+        for _ in 0..self.threads {
+            self.queue.push(None);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use prelude::*;
+
+use bundledb::is_bundle_file;
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::os::unix::fs::symlink;
+#[cfg(windows)]
+use std::os::windows::fs::symlink_dir as symlink;
+
+
+/// Folders of the remote storage that are transferred when seeding
+const SEED_FOLDERS: [&'static str; 3] = ["dictionaries", "bundles", "backups"];
+
+
+#[derive(Default, Debug)]
+pub struct SeedReport {
+    /// Number of files that have been copied
+    pub copied: usize,
+    /// Number of files that were already present with the same size
+    pub skipped: usize,
+    /// Number of bytes that have been copied
+    pub size: u64
+}
+
+
+/// Temporary files of interrupted copies are not included
+fn is_seed_file(path: &Path) -> bool {
+    is_bundle_file(path) ||
+        path.extension().map_or(false, |ext| ext == "dict" || ext == "backup")
+}
+
+/// Lists the bundles, dictionaries and backups in the remote storage relative to `remote`
+///
+/// Backups are listed last, so that no backup is ever transferred before the data it uses.
+fn list_seed_files(remote: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let mut files = vec![];
+    for folder in &SEED_FOLDERS {
+        let mut folder_files = vec![];
+        let mut paths = vec![remote.join(folder)];
+        while let Some(path) = paths.pop() {
+            if !path.exists() {
+                continue;
+            }
+            for entry in try!(fs::read_dir(&path)) {
+                let path = try!(entry).path();
+                if path.is_dir() {
+                    paths.push(path);
+                } else if is_seed_file(&path) {
+                    folder_files.push(path.strip_prefix(remote).unwrap().to_path_buf());
+                }
+            }
+        }
+        folder_files.sort();
+        files.extend(folder_files);
+    }
+    Ok(files)
+}
+
+/// Copies the file via a temporary file so that `dst` only exists once it is complete
+fn copy_file_throttled(src: &Path, dst: &Path, throttle: &mut Throttle) -> Result<u64, io::Error> {
+    try!(fs::create_dir_all(dst.parent().unwrap()));
+    let temp_path = dst.with_extension("tmp");
+    let mut src_file = try!(File::open(src));
+    let mut dst_file = try!(File::create(&temp_path));
+    let mut buffer = vec![0; 1024 * 1024];
+    let mut size = 0;
+    loop {
+        let len = try!(src_file.read(&mut buffer));
+        if len == 0 {
+            break;
+        }
+        try!(dst_file.write_all(&buffer[..len]));
+        throttle.consume(len as u64);
+        size += len as u64;
+    }
+    try!(dst_file.sync_all());
+    try!(fs::rename(&temp_path, dst));
+    Ok(size)
+}
+
+/// Copies all bundles, dictionaries and backups from `src` to `dst` that are missing in `dst`
+///
+/// Files that exist in `dst` with the same size are skipped, so an interrupted transfer can simply
+/// be started again. Files only exist under their final name once they have been copied
+/// completely. The transfer is limited to `bwlimit` bytes per second if given.
+pub fn copy_seed_files(
+    src: &Path,
+    dst: &Path,
+    bwlimit: Option<u64>,
+) -> Result<SeedReport, RepositoryError> {
+    let files = try!(list_seed_files(src));
+    let mut report = SeedReport::default();
+    let mut throttle = Throttle::new(bwlimit);
+    for file in ProgressIter::new("copying files", files.len(), files.into_iter()) {
+        let src_path = src.join(&file);
+        let dst_path = dst.join(&file);
+        if let (Ok(src_meta), Ok(dst_meta)) = (src_path.metadata(), dst_path.metadata()) {
+            if src_meta.len() == dst_meta.len() {
+                report.skipped += 1;
+                continue;
+            }
+        }
+        report.size += try!(copy_file_throttled(&src_path, &dst_path, &mut throttle));
+        report.copied += 1;
+    }
+    for folder in &["bundles", "backups", "locks"] {
+        try!(fs::create_dir_all(dst.join(folder)));
+    }
+    let readme = src.join("README.md");
+    if readme.exists() && !dst.join("README.md").exists() {
+        try!(fs::copy(readme, dst.join("README.md")));
+    }
+    Ok(report)
+}
+
+
+impl Repository {
+    fn is_seeded(&self, remote: &Path) -> bool {
+        let bundles = self.bundles.list_bundles();
+        bundles.is_empty() ||
+            bundles.iter().any(|info| {
+                self.bundles.get_bundle_info(&info.id).map_or(false, |bundle| {
+                    let src_path = self.layout.base_path().join(&bundle.path);
+                    let rel_path = src_path.strip_prefix(self.layout.remote_path()).unwrap();
+                    remote.join(rel_path).exists()
+                })
+            })
+    }
+
+    /// Writes all bundles, dictionaries and backups to `dst` to seed a remote storage offline
+    ///
+    /// The folder gets the structure of the remote storage and can be imported on the server with
+    /// `copy_seed_files`. Files that have already been exported are skipped.
+    pub fn seed_export(
+        &mut self,
+        dst: &Path,
+        bwlimit: Option<u64>,
+    ) -> Result<SeedReport, RepositoryError> {
+        try!(self.flush());
+        let _lock = try!(self.lock(false));
+        copy_seed_files(&self.layout.remote_path(), dst, bwlimit)
+    }
+
+    /// Switches the repository over to the seeded remote storage `remote`
+    ///
+    /// Everything that has not been seeded, e.g. backups created after the export, is uploaded
+    /// first. Afterwards, the remote storage of the repository points to `remote`.
+    pub fn seed_finish(
+        &mut self,
+        remote: &Path,
+        bwlimit: Option<u64>,
+    ) -> Result<SeedReport, RepositoryError> {
+        try!(self.write_mode());
+        let remote = try!(remote.canonicalize());
+        let remote = &remote as &Path;
+        let report = {
+            let _lock = try!(self.lock(true));
+            // Uploading everything again is most likely not what the user wants
+            if !self.is_seeded(remote) {
+                return Err(RepositoryError::NotSeeded(remote.to_path_buf()));
+            }
+            try!(copy_seed_files(&self.layout.remote_path(), remote, bwlimit))
+        };
+        // The lock lives in the old remote storage and has to be released before switching
+        try!(fs::remove_file(self.layout.remote_path()));
+        try!(symlink(remote, self.layout.remote_path()));
+        self.audit(
+            "seed-finish",
+            format!("{} files uploaded, {} already seeded", report.copied, report.skipped)
+        );
+        Ok(report)
+    }
+}
@@ -0,0 +1,473 @@
+use prelude::*;
+
+use std::fs::File;
+use std::io::{self, Read, Write, Cursor};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ByteOrder, WriteBytesExt};
+
+
+static STREAM_MAGIC: &'static [u8] = b"btrfs-stream\0";
+const STREAM_VERSION: u32 = 1;
+/// Size of the data in a single write command, the same as `btrfs send` uses
+const WRITE_SIZE: usize = 48 * 1024;
+/// Inode number of the first file, 256 is the root directory of the subvolume
+const FIRST_INO: u64 = 257;
+
+const CMD_SUBVOL: u16 = 1;
+const CMD_SNAPSHOT: u16 = 2;
+const CMD_MKFILE: u16 = 3;
+const CMD_MKDIR: u16 = 4;
+const CMD_MKNOD: u16 = 5;
+const CMD_MKFIFO: u16 = 6;
+const CMD_MKSOCK: u16 = 7;
+const CMD_SYMLINK: u16 = 8;
+const CMD_UNLINK: u16 = 11;
+const CMD_RMDIR: u16 = 12;
+const CMD_SET_XATTR: u16 = 13;
+const CMD_REMOVE_XATTR: u16 = 14;
+const CMD_WRITE: u16 = 15;
+const CMD_TRUNCATE: u16 = 17;
+const CMD_CHMOD: u16 = 18;
+const CMD_CHOWN: u16 = 19;
+const CMD_UTIMES: u16 = 20;
+const CMD_END: u16 = 21;
+
+const ATTR_UUID: u16 = 1;
+const ATTR_CTRANSID: u16 = 2;
+const ATTR_INO: u16 = 3;
+const ATTR_SIZE: u16 = 4;
+const ATTR_MODE: u16 = 5;
+const ATTR_UID: u16 = 6;
+const ATTR_GID: u16 = 7;
+const ATTR_RDEV: u16 = 8;
+const ATTR_CTIME: u16 = 9;
+const ATTR_MTIME: u16 = 10;
+const ATTR_ATIME: u16 = 11;
+const ATTR_XATTR_NAME: u16 = 13;
+const ATTR_XATTR_DATA: u16 = 14;
+const ATTR_PATH: u16 = 15;
+const ATTR_PATH_LINK: u16 = 17;
+const ATTR_FILE_OFFSET: u16 = 18;
+const ATTR_DATA: u16 = 19;
+const ATTR_CLONE_UUID: u16 = 20;
+const ATTR_CLONE_CTRANSID: u16 = 21;
+
+const S_IFCHR: u64 = 0o020_000;
+const S_IFBLK: u64 = 0o060_000;
+
+
+/// A single command of a btrfs send stream with its attributes
+struct SendCommand {
+    command: u16,
+    attrs: Vec<u8>
+}
+
+impl SendCommand {
+    fn new(command: u16) -> Self {
+        SendCommand {
+            command: command,
+            attrs: vec![]
+        }
+    }
+
+    fn attr(mut self, attr: u16, value: &[u8]) -> Self {
+        debug_assert!(value.len() <= u16::max_value() as usize);
+        self.attrs.write_u16::<LittleEndian>(attr).unwrap();
+        self.attrs.write_u16::<LittleEndian>(value.len() as u16).unwrap();
+        self.attrs.extend_from_slice(value);
+        self
+    }
+
+    fn attr_u64(self, attr: u16, value: u64) -> Self {
+        let mut buf = [0; 8];
+        LittleEndian::write_u64(&mut buf, value);
+        self.attr(attr, &buf)
+    }
+
+    fn attr_path(self, attr: u16, path: &Path) -> Self {
+        self.attr(attr, &path_to_bytes(path))
+    }
+
+    /// Timestamps are encoded as seconds and nanoseconds, zVault only stores the seconds
+    fn attr_time(self, attr: u16, time: i64) -> Self {
+        let mut buf = [0; 12];
+        LittleEndian::write_i64(&mut buf[..8], time);
+        self.attr(attr, &buf)
+    }
+
+    /// The checksum covers the header with a zero checksum field and the attributes
+    fn write_to<W: Write>(self, out: &mut W) -> Result<(), io::Error> {
+        let mut buf = Vec::with_capacity(10 + self.attrs.len());
+        try!(buf.write_u32::<LittleEndian>(self.attrs.len() as u32));
+        try!(buf.write_u16::<LittleEndian>(self.command));
+        try!(buf.write_u32::<LittleEndian>(0));
+        buf.extend_from_slice(&self.attrs);
+        let crc = crc32c(0, &buf);
+        LittleEndian::write_u32(&mut buf[6..10], crc);
+        out.write_all(&buf)
+    }
+}
+
+
+struct SendStream<W: Write> {
+    out: W,
+    next_ino: u64
+}
+
+impl<W: Write> SendStream<W> {
+    fn new(mut out: W) -> Result<Self, io::Error> {
+        try!(out.write_all(STREAM_MAGIC));
+        try!(out.write_u32::<LittleEndian>(STREAM_VERSION));
+        Ok(SendStream {
+            out: out,
+            next_ino: FIRST_INO
+        })
+    }
+
+    #[inline]
+    fn send(&mut self, command: SendCommand) -> Result<(), io::Error> {
+        command.write_to(&mut self.out)
+    }
+
+    /// The receiving side ignores the inode numbers, they only have to be unique
+    fn next_ino(&mut self) -> u64 {
+        self.next_ino += 1;
+        self.next_ino - 1
+    }
+
+    fn send_data<R: Read>(&mut self, path: &Path, mut data: R) -> Result<(), io::Error> {
+        let mut buffer = vec![0; WRITE_SIZE];
+        let mut offset = 0;
+        loop {
+            let len = try!(data.read(&mut buffer));
+            if len == 0 {
+                return Ok(());
+            }
+            try!(self.send(
+                SendCommand::new(CMD_WRITE)
+                    .attr_path(ATTR_PATH, path)
+                    .attr_u64(ATTR_FILE_OFFSET, offset)
+                    .attr(ATTR_DATA, &buffer[..len])
+            ));
+            offset += len as u64;
+        }
+    }
+
+    fn finish(mut self) -> Result<(), io::Error> {
+        try!(self.send(SendCommand::new(CMD_END)));
+        self.out.flush()
+    }
+}
+
+
+/// Encodes the device number like Linux does for 32-bit device numbers
+fn encode_device(major: u32, minor: u32) -> u64 {
+    let (major, minor) = (u64::from(major), u64::from(minor));
+    (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+}
+
+/// Inodes that can not be updated in place but have to be removed and created again
+fn needs_replace(old: &Inode, new: &Inode) -> bool {
+    old.file_type != new.file_type || old.symlink_target != new.symlink_target ||
+        old.device != new.device
+}
+
+/// Derives a stable UUID for the snapshot of a backup or subtree
+///
+/// The receiving side records this UUID and finds the parent of an incremental stream by it, so
+/// the same backup always has to get the same UUID.
+fn snapshot_uuid(backup: &Backup, subtree: Option<&str>) -> [u8; 16] {
+    let mut hasher = Sha256::new();
+    hasher.update(backup.timestamp.to_string().as_bytes());
+    hasher.update(b"\0");
+    hasher.update(backup.host.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(backup.path.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(subtree.unwrap_or("").as_bytes());
+    let mut uuid = [0; 16];
+    uuid.copy_from_slice(&hasher.finish()[..16]);
+    uuid
+}
+
+
+impl Repository {
+    fn load_send_root(
+        &mut self,
+        backup: &Backup,
+        subtree: Option<&str>,
+    ) -> Result<Inode, RepositoryError> {
+        let inode = if let Some(subtree) = subtree {
+            try!(self.get_backup_inode(backup, subtree))
+        } else {
+            try!(self.get_inode(&backup.root))
+        };
+        if inode.file_type != FileType::Directory {
+            return Err(RepositoryError::NotADirectory(subtree.unwrap_or("/").to_string()));
+        }
+        Ok(inode)
+    }
+
+    fn send_inode_data<W: Write>(
+        &mut self,
+        stream: &mut SendStream<W>,
+        path: &Path,
+        inode: &Inode,
+    ) -> Result<(), RepositoryError> {
+        match inode.data {
+            None => (),
+            Some(FileData::Inline(ref data)) => try!(stream.send_data(path, Cursor::new(data))),
+            Some(FileData::ChunkedDirect(ref chunks)) => {
+                try!(stream.send_data(path, self.get_prefetching_reader(chunks.clone())))
+            }
+            Some(FileData::ChunkedIndirect(ref chunks)) => {
+                let chunks = ChunkList::read_from(&try!(self.get_data(chunks)));
+                try!(stream.send_data(path, self.get_prefetching_reader(chunks)))
+            }
+        }
+        Ok(())
+    }
+
+    fn send_inode_create<W: Write>(
+        &mut self,
+        stream: &mut SendStream<W>,
+        path: &Path,
+        inode: &Inode,
+    ) -> Result<(), RepositoryError> {
+        let ino = stream.next_ino();
+        let command = match inode.file_type {
+            FileType::File => CMD_MKFILE,
+            FileType::Directory => CMD_MKDIR,
+            FileType::Symlink => CMD_SYMLINK,
+            FileType::BlockDevice | FileType::CharDevice => CMD_MKNOD,
+            FileType::NamedPipe => CMD_MKFIFO,
+            FileType::Socket => CMD_MKSOCK,
+        };
+        let mut command = SendCommand::new(command)
+            .attr_path(ATTR_PATH, path)
+            .attr_u64(ATTR_INO, ino);
+        if let Some(ref target) = inode.symlink_target {
+            command = command.attr(ATTR_PATH_LINK, target.as_bytes());
+        }
+        if let Some((major, minor)) = inode.device {
+            let file_type = if inode.file_type == FileType::BlockDevice {
+                S_IFBLK
+            } else {
+                S_IFCHR
+            };
+            command = command
+                .attr_u64(ATTR_MODE, file_type | u64::from(inode.mode & 0o7777))
+                .attr_u64(ATTR_RDEV, encode_device(major, minor));
+        }
+        try!(stream.send(command));
+        self.send_inode_data(stream, path, inode)
+    }
+
+    /// Sends the metadata of the inode, only changed extended attributes are sent if `old` is given
+    fn send_inode_meta<W: Write>(
+        &mut self,
+        stream: &mut SendStream<W>,
+        path: &Path,
+        inode: &Inode,
+        old: Option<&Inode>,
+    ) -> Result<(), RepositoryError> {
+        if let Some(old) = old {
+            for name in old.xattrs.keys() {
+                if !inode.xattrs.contains_key(name) {
+                    try!(stream.send(
+                        SendCommand::new(CMD_REMOVE_XATTR)
+                            .attr_path(ATTR_PATH, path)
+                            .attr(ATTR_XATTR_NAME, name.as_bytes())
+                    ));
+                }
+            }
+        }
+        for (name, value) in &inode.xattrs {
+            if old.map_or(false, |old| old.xattrs.get(name) == Some(value)) {
+                continue;
+            }
+            try!(stream.send(
+                SendCommand::new(CMD_SET_XATTR)
+                    .attr_path(ATTR_PATH, path)
+                    .attr(ATTR_XATTR_NAME, name.as_bytes())
+                    .attr(ATTR_XATTR_DATA, value)
+            ));
+        }
+        // Changing the owner clears the setuid bits, so the mode has to be set afterwards
+        try!(stream.send(
+            SendCommand::new(CMD_CHOWN)
+                .attr_path(ATTR_PATH, path)
+                .attr_u64(ATTR_UID, u64::from(inode.user))
+                .attr_u64(ATTR_GID, u64::from(inode.group))
+        ));
+        // The mode of a symlink can not be changed, chmod would change its target instead
+        if inode.file_type != FileType::Symlink {
+            try!(stream.send(
+                SendCommand::new(CMD_CHMOD)
+                    .attr_path(ATTR_PATH, path)
+                    .attr_u64(ATTR_MODE, u64::from(inode.mode & 0o7777))
+            ));
+        }
+        try!(stream.send(
+            SendCommand::new(CMD_UTIMES)
+                .attr_path(ATTR_PATH, path)
+                .attr_time(ATTR_ATIME, inode.timestamp)
+                .attr_time(ATTR_MTIME, inode.timestamp)
+                .attr_time(ATTR_CTIME, inode.timestamp)
+        ));
+        Ok(())
+    }
+
+    /// Sends the inode and all of its children, the root of the snapshot already exists
+    fn send_inode_tree<W: Write>(
+        &mut self,
+        stream: &mut SendStream<W>,
+        path: &Path,
+        inode: &Inode,
+        is_root: bool,
+    ) -> Result<(), RepositoryError> {
+        if !is_root {
+            try!(self.send_inode_create(stream, path, inode));
+        }
+        if let Some(ref children) = inode.children {
+            for (name, chunks) in children {
+                let child = try!(self.get_inode(chunks));
+                try!(self.send_inode_tree(stream, &path.join(name), &child, false));
+            }
+        }
+        // Creating the children changes the times of the directory, so they are set last
+        self.send_inode_meta(stream, path, inode, None)
+    }
+
+    fn send_inode_remove<W: Write>(
+        &mut self,
+        stream: &mut SendStream<W>,
+        path: &Path,
+        inode: &Inode,
+    ) -> Result<(), RepositoryError> {
+        if let Some(ref children) = inode.children {
+            for (name, chunks) in children {
+                let child = try!(self.get_inode(chunks));
+                try!(self.send_inode_remove(stream, &path.join(name), &child));
+            }
+            try!(stream.send(SendCommand::new(CMD_RMDIR).attr_path(ATTR_PATH, path)));
+        } else {
+            try!(stream.send(SendCommand::new(CMD_UNLINK).attr_path(ATTR_PATH, path)));
+        }
+        Ok(())
+    }
+
+    /// Sends the changes from `old` to `inode` which must not need to be replaced
+    fn send_inode_changes<W: Write>(
+        &mut self,
+        stream: &mut SendStream<W>,
+        path: &Path,
+        old: &Inode,
+        inode: &Inode,
+    ) -> Result<(), RepositoryError> {
+        let mut changed = !old.is_same_meta(inode) || old.xattrs != inode.xattrs;
+        if inode.file_type == FileType::File && old.data != inode.data {
+            // The whole file is written again as the data of both versions is not compared
+            try!(self.send_inode_data(stream, path, inode));
+            try!(stream.send(
+                SendCommand::new(CMD_TRUNCATE)
+                    .attr_path(ATTR_PATH, path)
+                    .attr_u64(ATTR_SIZE, inode.size)
+            ));
+            changed = true;
+        }
+        let old_children = old.children.as_ref();
+        if let (Some(old_children), Some(children)) = (old_children, inode.children.as_ref()) {
+            for (name, chunks) in old_children {
+                if !children.contains_key(name) {
+                    let child = try!(self.get_inode(chunks));
+                    try!(self.send_inode_remove(stream, &path.join(name), &child));
+                    changed = true;
+                }
+            }
+            for (name, chunks) in children {
+                let child_path = path.join(name);
+                match old_children.get(name) {
+                    Some(old_chunks) if old_chunks == chunks => continue,
+                    Some(old_chunks) => {
+                        let old_child = try!(self.get_inode(old_chunks));
+                        let child = try!(self.get_inode(chunks));
+                        if needs_replace(&old_child, &child) {
+                            try!(self.send_inode_remove(stream, &child_path, &old_child));
+                            try!(self.send_inode_tree(stream, &child_path, &child, false));
+                            changed = true;
+                        } else {
+                            try!(self.send_inode_changes(stream, &child_path, &old_child, &child));
+                        }
+                    }
+                    None => {
+                        let child = try!(self.get_inode(chunks));
+                        try!(self.send_inode_tree(stream, &child_path, &child, false));
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if changed {
+            try!(self.send_inode_meta(stream, path, inode, Some(old)));
+        }
+        Ok(())
+    }
+
+    fn write_send_stream<W: Write>(
+        &mut self,
+        name: &str,
+        backup: &Backup,
+        parent: Option<&Backup>,
+        subtree: Option<&str>,
+        out: W,
+    ) -> Result<(), RepositoryError> {
+        let inode = try!(self.load_send_root(backup, subtree));
+        let mut stream = try!(SendStream::new(out));
+        let uuid = snapshot_uuid(backup, subtree);
+        let command = if let Some(parent) = parent {
+            SendCommand::new(CMD_SNAPSHOT)
+                .attr(ATTR_PATH, name.as_bytes())
+                .attr(ATTR_UUID, &uuid)
+                .attr_u64(ATTR_CTRANSID, backup.timestamp as u64)
+                .attr(ATTR_CLONE_UUID, &snapshot_uuid(parent, subtree))
+                .attr_u64(ATTR_CLONE_CTRANSID, parent.timestamp as u64)
+        } else {
+            SendCommand::new(CMD_SUBVOL)
+                .attr(ATTR_PATH, name.as_bytes())
+                .attr(ATTR_UUID, &uuid)
+                .attr_u64(ATTR_CTRANSID, backup.timestamp as u64)
+        };
+        try!(stream.send(command));
+        if let Some(parent) = parent {
+            let old = try!(self.load_send_root(parent, subtree));
+            try!(self.send_inode_changes(&mut stream, Path::new(""), &old, &inode));
+        } else {
+            try!(self.send_inode_tree(&mut stream, Path::new(""), &inode, true));
+        }
+        try!(stream.finish());
+        Ok(())
+    }
+
+    /// Writes the backup or subtree as a btrfs send stream to `dst`, `-` means stdout
+    ///
+    /// The stream creates a snapshot called `name` when it is applied with `btrfs receive`. If
+    /// `parent` is given, the stream is incremental and only contains the changes since that
+    /// backup. It can only be received if the stream of the parent has been received before.
+    pub fn export_send_stream<P: AsRef<Path>>(
+        &mut self,
+        name: &str,
+        backup: &Backup,
+        parent: Option<&Backup>,
+        subtree: Option<&str>,
+        dst: P,
+    ) -> Result<(), RepositoryError> {
+        let dst = dst.as_ref();
+        if dst == Path::new("-") {
+            self.write_send_stream(name, backup, parent, subtree, io::stdout())
+        } else {
+            self.write_send_stream(name, backup, parent, subtree, try!(File::create(dst)))
+        }
+    }
+}
@@ -4,8 +4,12 @@ use std::collections::{HashMap, HashSet, BTreeMap};
 use std::path::{Path, PathBuf};
 use std::io::{self, Read, Write, Cursor};
 use std::fs::File;
+use std::mem;
+use std::process::{Command, Stdio};
 use std::str;
-use std::os::unix::ffi::OsStrExt;
+use std::time::Instant;
+
+use super::basic_io::PhaseTimes;
 
 use chrono::prelude::*;
 
@@ -13,6 +17,62 @@ use tar;
 
 static MAX_NAME_LEN: usize = 99;
 static MAX_LINK_LEN: usize = 99;
+static MAX_USER_NAME_LEN: usize = 31;
+
+
+/// The place a tar file is imported from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TarSource {
+    Stdin,
+    File(PathBuf),
+    /// A command that writes the tar stream to stdout on a remote host reached via `ssh`
+    Ssh { host: String, command: String }
+}
+
+impl TarSource {
+    /// Parses `-`, a file path or `ssh://[user@]host:command`
+    pub fn parse(src: &str) -> Result<Self, String> {
+        if src == "-" {
+            return Ok(TarSource::Stdin);
+        }
+        if !src.starts_with("ssh://") {
+            return Ok(TarSource::File(Path::new(src).to_path_buf()));
+        }
+        let rest = &src["ssh://".len()..];
+        let pos = match rest.find(':') {
+            Some(pos) => pos,
+            None => return Err("Remote source must have the form ssh://host:command".to_string()),
+        };
+        let (host, command) = (&rest[..pos], rest[pos + 1..].trim());
+        if host.is_empty() || host.starts_with('-') {
+            return Err(format!("Invalid remote host: {:?}", host));
+        }
+        if command.is_empty() {
+            return Err("Remote source needs a command that writes a tar file".to_string());
+        }
+        Ok(TarSource::Ssh {
+            host: host.to_string(),
+            command: command.to_string()
+        })
+    }
+
+    /// The host that is stored in the backup, `None` for the local host
+    fn host(&self) -> Option<&str> {
+        match *self {
+            TarSource::Ssh { ref host, .. } => Some(host.rsplit('@').next().unwrap_or(host)),
+            _ => None,
+        }
+    }
+
+    /// The path that is stored in the backup
+    fn path(&self) -> String {
+        match *self {
+            TarSource::Stdin => "-".to_string(),
+            TarSource::File(ref path) => path.to_string_lossy().to_string(),
+            TarSource::Ssh { ref command, .. } => command.clone(),
+        }
+    }
+}
 
 
 struct PaxBuilder(Vec<u8>);
@@ -22,7 +82,9 @@ impl PaxBuilder {
         PaxBuilder(Vec::new())
     }
 
-    pub fn add(&mut self, key: &str, value: &str) {
+    /// Adds a record, the value can contain any bytes including newlines as records have a
+    /// length prefix
+    pub fn add(&mut self, key: &str, value: &[u8]) {
         let mut len_len = 1;
         let mut max_len = 10;
         let rest_len = 3 + key.len() + value.len();
@@ -31,7 +93,14 @@ impl PaxBuilder {
             max_len *= 10;
         }
         let len = rest_len + len_len;
-        write!(&mut self.0, "{} {}={}\n", len, key, value).unwrap();
+        write!(&mut self.0, "{} {}=", len, key).unwrap();
+        self.0.extend_from_slice(value);
+        self.0.push(b'\n');
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
     }
 
     fn as_bytes(&self) -> &[u8] {
@@ -40,10 +109,121 @@ impl PaxBuilder {
 }
 
 
+/// Returns whether a name has to be stored in a PAX record as the header field can not hold it
+///
+/// Names with control characters or non-ASCII characters are put into PAX records as well,
+/// since those are defined to be UTF-8 while readers interpret header fields in their locale.
+fn needs_pax_record(name: &[u8], max_len: usize) -> bool {
+    name.len() > max_len || name.iter().any(|&b| b < 0x20 || b >= 0x7f)
+}
+
+/// Writes the start of the name into the header for readers that do not understand PAX records
+fn set_fallback_name(header: &mut tar::Header, name: &[u8]) {
+    let field = &mut header.as_old_mut().name;
+    let len = ::std::cmp::min(name.len(), MAX_NAME_LEN);
+    field[..len].copy_from_slice(&name[..len]);
+}
+
+
+/// Parses the PAX records of an extended header and appends them to `records`
+///
+/// The records are located via their length prefix, so values can contain newlines.
+fn parse_pax_records(
+    mut data: &[u8],
+    records: &mut Vec<(String, Vec<u8>)>,
+) -> Result<(), io::Error> {
+    let malformed = || io::Error::new(io::ErrorKind::Other, "malformed pax extension");
+    while !data.is_empty() {
+        let space = try!(data.iter().position(|&b| b == b' ').ok_or_else(&malformed));
+        let len = try!(
+            str::from_utf8(&data[..space])
+                .ok()
+                .and_then(|len| len.parse::<usize>().ok())
+                .ok_or_else(&malformed)
+        );
+        if len <= space + 1 || len > data.len() || data[len - 1] != b'\n' {
+            return Err(malformed());
+        }
+        let record = &data[space + 1..len - 1];
+        let equals = try!(record.iter().position(|&b| b == b'=').ok_or_else(&malformed));
+        records.push((
+            String::from_utf8_lossy(&record[..equals]).to_string(),
+            record[equals + 1..].to_vec()
+        ));
+        data = &data[len..];
+    }
+    Ok(())
+}
+
+/// Iterates over the entries of a tar file together with the PAX records that precede them
+///
+/// The tar crate splits PAX records at newlines, so the headers are read raw and parsed here.
+/// GNU long names and link targets are returned as `path` and `linkpath` records.
+struct RecordEntries<'a, R: 'a + Read> {
+    entries: tar::Entries<'a, R>
+}
+
+impl<'a, R: Read> RecordEntries<'a, R> {
+    fn new(archive: &'a mut tar::Archive<R>) -> Result<Self, io::Error> {
+        Ok(RecordEntries { entries: try!(archive.entries()).raw(true) })
+    }
+}
+
+impl<'a, R: Read> Iterator for RecordEntries<'a, R> {
+    type Item = Result<(tar::Entry<'a, R>, Vec<(String, Vec<u8>)>), io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut records = vec![];
+        for entry in &mut self.entries {
+            let mut entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => return Some(Err(err)),
+            };
+            let key = match entry.header().entry_type() {
+                tar::EntryType::XHeader => None,
+                tar::EntryType::GNULongName => Some("path"),
+                tar::EntryType::GNULongLink => Some("linkpath"),
+                _ => return Some(Ok((entry, records))),
+            };
+            let mut data = vec![];
+            if let Err(err) = entry.read_to_end(&mut data) {
+                return Some(Err(err));
+            }
+            if let Some(key) = key {
+                if data.last() == Some(&0) {
+                    data.pop();
+                }
+                records.push((key.to_string(), data));
+            } else if let Err(err) = parse_pax_records(&data, &mut records) {
+                return Some(Err(err));
+            }
+        }
+        if records.is_empty() {
+            None
+        } else {
+            Some(Err(io::Error::new(io::ErrorKind::Other, "headers without an entry at the end")))
+        }
+    }
+}
+
+fn pax_value<'a>(records: &'a [(String, Vec<u8>)], key: &str) -> Option<&'a [u8]> {
+    records.iter().rev().find(|r| r.0 == key).map(|r| &r.1 as &[u8])
+}
+
+/// Returns the path of the entry, taking a PAX `path` record into account
+fn entry_path<R: Read>(
+    entry: &tar::Entry<R>,
+    records: &[(String, Vec<u8>)],
+) -> Result<PathBuf, io::Error> {
+    Ok(match pax_value(records, "path") {
+        Some(path) => path_from_bytes(path),
+        None => try!(entry.path()).to_path_buf(),
+    })
+}
+
+
 trait BuilderExt {
     fn append_pax_extensions(&mut self, headers: &PaxBuilder) -> Result<(), io::Error>;
-    fn append_long_name(&mut self, path: &Path) -> Result<(), io::Error>;
-    fn append_long_link(&mut self, path: &Path) -> Result<(), io::Error>;
 }
 
 impl<T: Write> BuilderExt for tar::Builder<T> {
@@ -54,32 +234,24 @@ impl<T: Write> BuilderExt for tar::Builder<T> {
         header.set_cksum();
         self.append(&header, headers.as_bytes())
     }
-
-    fn append_long_name(&mut self, path: &Path) -> Result<(), io::Error> {
-        let mut header = tar::Header::new_gnu();
-        let bytes = path.as_os_str().as_bytes();
-        header.set_size(bytes.len() as u64);
-        header.set_entry_type(tar::EntryType::GNULongName);
-        header.set_cksum();
-        self.append(&header, bytes)
-    }
-
-    fn append_long_link(&mut self, path: &Path) -> Result<(), io::Error> {
-        let mut header = tar::Header::new_gnu();
-        let bytes = path.as_os_str().as_bytes();
-        header.set_size(bytes.len() as u64);
-        header.set_entry_type(tar::EntryType::GNULongLink);
-        header.set_cksum();
-        self.append(&header, bytes)
-    }
 }
 
 
 static PAX_XATTR_PREFIX: &'static str = "SCHILY.xattr.";
 
-fn inode_from_entry<R: Read>(entry: &mut tar::Entry<R>) -> Result<Inode, RepositoryError> {
+/// Parses a PAX time value like `1234567890.5`, the fractional part is ignored
+fn parse_pax_time(value: &[u8]) -> Option<i64> {
+    str::from_utf8(value).ok().and_then(|value| value.split('.').next()).and_then(
+        |secs| secs.parse().ok()
+    )
+}
+
+fn inode_from_entry<R: Read>(
+    entry: &mut tar::Entry<R>,
+    path: &Path,
+    records: &[(String, Vec<u8>)],
+) -> Result<Inode, RepositoryError> {
     let mut inode = {
-        let path = try!(entry.path());
         let header = entry.header();
         let file_type = match header.entry_type() {
             tar::EntryType::Regular |
@@ -97,12 +269,18 @@ fn inode_from_entry<R: Read>(entry: &mut tar::Entry<R>) -> Result<Inode, Reposit
             name: path.file_name()
                 .map(|s| s.to_string_lossy().to_string())
                 .unwrap_or_else(|| "/".to_string()),
-            symlink_target: try!(entry.link_name()).map(|s| s.to_string_lossy().to_string()),
+            symlink_target: match pax_value(records, "linkpath") {
+                Some(target) => Some(String::from_utf8_lossy(target).to_string()),
+                None => try!(entry.link_name()).map(|s| s.to_string_lossy().to_string()),
+            },
             size: try!(header.size()),
             mode: try!(header.mode()),
             user: try!(header.uid()),
             group: try!(header.gid()),
-            timestamp: try!(header.mtime()) as i64,
+            timestamp: match pax_value(records, "mtime").and_then(parse_pax_time) {
+                Some(time) => time,
+                None => try!(header.mtime()) as i64,
+            },
             device: match file_type {
                 FileType::BlockDevice | FileType::CharDevice => Some((
                     try!(header.device_major())
@@ -115,16 +293,15 @@ fn inode_from_entry<R: Read>(entry: &mut tar::Entry<R>) -> Result<Inode, Reposit
             ..Default::default()
         }
     };
-    if let Some(exts) = try!(entry.pax_extensions()) {
-        for ext in exts {
-            let ext = try!(ext);
-            let key = ext.key().unwrap_or("");
-            if key.starts_with(PAX_XATTR_PREFIX) {
-                inode.xattrs.insert(
-                    key[PAX_XATTR_PREFIX.len()..].to_string(),
-                    ext.value_bytes().to_vec().into()
-                );
-            }
+    if path.file_name().map_or(false, |name| name.to_str().is_none()) {
+        warn!("The name of {:?} is not valid UTF-8, invalid characters are replaced", path);
+    }
+    for &(ref key, ref value) in records {
+        if key.starts_with(PAX_XATTR_PREFIX) {
+            inode.xattrs.insert(
+                key[PAX_XATTR_PREFIX.len()..].to_string(),
+                value.clone().into()
+            );
         }
     }
     if inode.file_type == FileType::Directory {
@@ -137,8 +314,10 @@ impl Repository {
     fn import_tar_entry<R: Read>(
         &mut self,
         entry: &mut tar::Entry<R>,
+        path: &Path,
+        records: &[(String, Vec<u8>)],
     ) -> Result<Inode, RepositoryError> {
-        let mut inode = try!(inode_from_entry(entry));
+        let mut inode = try!(inode_from_entry(entry, path, records));
         if inode.size < 100 {
             let mut data = Vec::with_capacity(inode.size as usize);
             try!(entry.read_to_end(&mut data));
@@ -166,10 +345,10 @@ impl Repository {
         let mut tarfile = tar::Archive::new(input);
         // Step 1: create inodes for all entries
         let mut inodes = HashMap::<PathBuf, (Inode, HashSet<String>)>::new();
-        for entry in try!(tarfile.entries()) {
-            let mut entry = try!(entry);
-            let path = try!(entry.path()).to_path_buf();
-            match self.import_tar_entry(&mut entry) {
+        for entry in try!(RecordEntries::new(&mut tarfile)) {
+            let (mut entry, records) = try!(entry);
+            let path = try!(entry_path(&entry, &records));
+            match self.import_tar_entry(&mut entry, &path, &records) {
                 Ok(mut inode) => {
                     inode.cum_size = inode.size;
                     if inode.file_type == FileType::Directory {
@@ -187,10 +366,20 @@ impl Repository {
                             children.insert(inode.name.clone());
                         }
                     }
-                    if let Ok(Some(name)) = entry.header().username() {
+                    if let Some(name) = pax_value(&records, "uname") {
+                        backup.user_names.insert(
+                            inode.user,
+                            String::from_utf8_lossy(name).to_string()
+                        );
+                    } else if let Ok(Some(name)) = entry.header().username() {
                         backup.user_names.insert(inode.user, name.to_string());
                     }
-                    if let Ok(Some(name)) = entry.header().groupname() {
+                    if let Some(name) = pax_value(&records, "gname") {
+                        backup.group_names.insert(
+                            inode.group,
+                            String::from_utf8_lossy(name).to_string()
+                        );
+                    } else if let Ok(Some(name)) = entry.header().groupname() {
                         backup.group_names.insert(inode.group, name.to_string());
                     }
                     inodes.insert(path, (inode, HashSet::new()));
@@ -269,10 +458,11 @@ impl Repository {
         }
     }
 
-    pub fn import_tarfile<P: AsRef<Path>>(
-        &mut self,
-        tarfile: P,
-    ) -> Result<Backup, RepositoryError> {
+    /// Imports a tar file from stdin, a file or a remote command
+    ///
+    /// The stream is chunked while it is read and the progress is shown based on the position
+    /// in the stream. Remote commands are run with `ssh`, so the remote host only needs `tar`.
+    pub fn import_tar_source(&mut self, source: &TarSource) -> Result<Backup, RepositoryError> {
         try!(self.write_mode());
         let _lock = try!(self.lock(false));
         if self.dirty {
@@ -280,28 +470,71 @@ impl Repository {
         }
         try!(self.set_dirty());
         let mut backup = Backup::default();
-        backup.config = self.config.clone();
-        backup.host = get_hostname().unwrap_or_else(|_| "".to_string());
-        backup.path = tarfile.as_ref().to_string_lossy().to_string();
+        backup.config = self.config.for_backup();
+        backup.host = match source.host() {
+            Some(host) => host.to_string(),
+            None => get_hostname().unwrap_or_else(|_| "".to_string()),
+        };
+        backup.path = source.path();
         let info_before = self.info();
         let start = Local::now();
+        self.phase_times = PhaseTimes::default();
+        self.zero_chunks = 0;
+        let upload_before = self.bundles.upload_wait();
+        let run_start = Instant::now();
         let mut failed_paths = vec![];
-        let tarfile = tarfile.as_ref();
-        let (root_inode, chunks) = if tarfile == Path::new("-") {
-            try!(self.import_tarfile_as_inode(
-                &mut backup,
-                io::stdin(),
-                &mut failed_paths
-            ))
-        } else {
-            try!(self.import_tarfile_as_inode(
-                &mut backup,
-                try!(File::open(tarfile)),
-                &mut failed_paths
-            ))
+        let (root_inode, chunks) = match *source {
+            TarSource::Stdin => {
+                try!(self.import_tarfile_as_inode(
+                    &mut backup,
+                    ProgressReader::new("reading tar file", None, io::stdin()),
+                    &mut failed_paths
+                ))
+            }
+            TarSource::File(ref path) => {
+                let file = try!(File::open(path));
+                let size = try!(file.metadata()).len();
+                try!(self.import_tarfile_as_inode(
+                    &mut backup,
+                    ProgressReader::new("reading tar file", Some(size), file),
+                    &mut failed_paths
+                ))
+            }
+            TarSource::Ssh { ref host, ref command } => {
+                let mut child = try!(
+                    Command::new("ssh")
+                        .arg("--")
+                        .arg(host)
+                        .arg(command)
+                        .stdin(Stdio::null())
+                        .stdout(Stdio::piped())
+                        .spawn()
+                );
+                let result = self.import_tarfile_as_inode(
+                    &mut backup,
+                    ProgressReader::new("reading tar stream", None, child.stdout.take().unwrap()),
+                    &mut failed_paths
+                );
+                if result.is_err() {
+                    child.kill().ok();
+                }
+                let status = try!(child.wait());
+                let result = try!(result);
+                if !status.success() {
+                    try!(self.flush());
+                    self.dirty = false;
+                    return Err(RepositoryError::RemoteCommand(command.clone(), status));
+                }
+                result
+            }
         };
         backup.root = chunks;
+        let run = run_start.elapsed();
+        let upload = self.bundles.upload_wait() - upload_before;
+        let phase_times = mem::replace(&mut self.phase_times, PhaseTimes::default());
+        let flush_start = Instant::now();
         try!(self.flush());
+        backup.timings = phase_times.to_backup_timings(run, upload, flush_start.elapsed());
         let elapsed = Local::now().signed_duration_since(start);
         backup.timestamp = start.timestamp();
         backup.total_data_size = root_inode.cum_size;
@@ -312,7 +545,8 @@ impl Repository {
         backup.deduplicated_data_size = info_after.raw_data_size - info_before.raw_data_size;
         backup.encoded_data_size = info_after.encoded_data_size - info_before.encoded_data_size;
         backup.bundle_count = info_after.bundle_count - info_before.bundle_count;
-        backup.chunk_count = info_after.chunk_count - info_before.chunk_count;
+        backup.chunk_count = (info_after.chunk_count - info_before.chunk_count) as u64;
+        backup.zero_chunk_count = self.zero_chunks;
         backup.avg_chunk_size = backup.deduplicated_data_size as f32 / backup.chunk_count as f32;
         self.dirty = false;
         if failed_paths.is_empty() {
@@ -322,21 +556,6 @@ impl Repository {
         }
     }
 
-    fn export_xattrs<W: Write>(
-        &mut self,
-        inode: &Inode,
-        tarfile: &mut tar::Builder<W>,
-    ) -> Result<(), RepositoryError> {
-        let mut pax = PaxBuilder::new();
-        for (key, value) in &inode.xattrs {
-            pax.add(
-                &format!("{}{}", PAX_XATTR_PREFIX, key),
-                str::from_utf8(value).unwrap()
-            );
-        }
-        Ok(try!(tarfile.append_pax_extensions(&pax)))
-    }
-
     fn export_tarfile_recurse<W: Write>(
         &mut self,
         backup: &Backup,
@@ -350,20 +569,29 @@ impl Repository {
         } else {
             path.join(&inode.name)
         };
+        if inode.file_type == FileType::Socket {
+            warn!("Skipping socket {:?} as it can not be stored in a tar file", path);
+            return Ok(());
+        }
         if inode.file_type != FileType::Directory || !skip_root {
-            if !inode.xattrs.is_empty() {
-                try!(self.export_xattrs(&inode, tarfile));
+            // Everything that does not fit into the header is stored in PAX records so that
+            // nothing gets truncated
+            let mut pax = PaxBuilder::new();
+            for (key, value) in &inode.xattrs {
+                pax.add(&format!("{}{}", PAX_XATTR_PREFIX, key), value);
             }
-            let mut header = tar::Header::new_gnu();
+            let mut header = tar::Header::new_ustar();
             header.set_size(inode.size);
-            if path.as_os_str().as_bytes().len() >= MAX_NAME_LEN {
-                try!(tarfile.append_long_name(&path));
+            let path_bytes: &[u8] = &path_to_bytes(&path);
+            if needs_pax_record(path_bytes, MAX_NAME_LEN) {
+                pax.add("path", path_bytes);
+                set_fallback_name(&mut header, path_bytes);
             } else {
                 try!(header.set_path(&path));
             }
             if let Some(target) = inode.symlink_target {
-                if target.len() >= MAX_LINK_LEN {
-                    try!(tarfile.append_long_link(Path::new(&target)));
+                if needs_pax_record(target.as_bytes(), MAX_LINK_LEN) {
+                    pax.add("linkpath", target.as_bytes());
                 } else {
                     try!(header.set_link_name(target));
                 }
@@ -375,13 +603,29 @@ impl Repository {
             header.set_mode(inode.mode);
             header.set_uid(inode.user);
             if let Some(name) = backup.user_names.get(&inode.user) {
-                header.set_username(name).ok();
+                if needs_pax_record(name.as_bytes(), MAX_USER_NAME_LEN) {
+                    pax.add("uname", name.as_bytes());
+                } else {
+                    try!(header.set_username(name));
+                }
             }
             header.set_gid(inode.group);
             if let Some(name) = backup.group_names.get(&inode.group) {
-                header.set_groupname(name).ok();
+                if needs_pax_record(name.as_bytes(), MAX_USER_NAME_LEN) {
+                    pax.add("gname", name.as_bytes());
+                } else {
+                    try!(header.set_groupname(name));
+                }
+            }
+            if inode.timestamp < 0 {
+                pax.add("mtime", inode.timestamp.to_string().as_bytes());
+                header.set_mtime(0);
+            } else {
+                header.set_mtime(inode.timestamp as u64);
+            }
+            if !pax.is_empty() {
+                try!(tarfile.append_pax_extensions(&pax));
             }
-            header.set_mtime(inode.timestamp as u64);
             header.set_entry_type(match inode.file_type {
                 FileType::File => tar::EntryType::Regular,
                 FileType::Symlink => tar::EntryType::Symlink,
@@ -389,17 +633,18 @@ impl Repository {
                 FileType::BlockDevice => tar::EntryType::Block,
                 FileType::CharDevice => tar::EntryType::Char,
                 FileType::NamedPipe => tar::EntryType::Fifo,
+                FileType::Socket => unreachable!(),
             });
             header.set_cksum();
             match inode.data {
                 None => try!(tarfile.append(&header, Cursor::new(&[]))),
                 Some(FileData::Inline(data)) => try!(tarfile.append(&header, Cursor::new(data))),
                 Some(FileData::ChunkedDirect(chunks)) => {
-                    try!(tarfile.append(&header, self.get_reader(chunks)))
+                    try!(tarfile.append(&header, self.get_prefetching_reader(chunks)))
                 }
                 Some(FileData::ChunkedIndirect(chunks)) => {
                     let chunks = ChunkList::read_from(&try!(self.get_data(&chunks)));
-                    try!(tarfile.append(&header, self.get_reader(chunks)))
+                    try!(tarfile.append(&header, self.get_prefetching_reader(chunks)))
                 }
             }
         }
@@ -449,3 +694,154 @@ impl Repository {
         Ok(())
     }
 }
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+
+    #[test]
+    fn test_pax_record_length() {
+        // The length includes its own digits
+        let mut pax = PaxBuilder::new();
+        pax.add("path", &[b'a'; 93]);
+        assert_eq!(&pax.as_bytes()[..4], b"103 ");
+        assert_eq!(pax.as_bytes().len(), 103);
+        let mut pax = PaxBuilder::new();
+        pax.add("path", b"a\nb");
+        assert_eq!(pax.as_bytes(), b"12 path=a\nb\n");
+    }
+
+}
+
+
+// Uses temporary repositories, which are only available in tests or with the memory feature
+#[cfg(any(test, feature = "memory"))]
+mod roundtrip_tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use std::io::Cursor;
+
+    #[allow(dead_code)]
+    struct Entry {
+        path: Vec<u8>,
+        entry_type: tar::EntryType,
+        link: Option<Vec<u8>>,
+        data: Vec<u8>,
+        xattrs: Vec<(String, Vec<u8>)>
+    }
+
+    #[allow(dead_code)]
+    fn entry(path: &[u8], entry_type: tar::EntryType, data: &[u8]) -> Entry {
+        Entry {
+            path: path.to_vec(),
+            entry_type: entry_type,
+            link: None,
+            data: data.to_vec(),
+            xattrs: vec![]
+        }
+    }
+
+    #[allow(dead_code)]
+    fn build_tar(entries: &[Entry]) -> Vec<u8> {
+        let mut tarfile = tar::Builder::new(Vec::new());
+        for entry in entries {
+            let mut pax = PaxBuilder::new();
+            for &(ref key, ref value) in &entry.xattrs {
+                pax.add(&format!("{}{}", PAX_XATTR_PREFIX, key), value);
+            }
+            pax.add("path", &entry.path);
+            if let Some(ref link) = entry.link {
+                pax.add("linkpath", link);
+            }
+            tarfile.append_pax_extensions(&pax).unwrap();
+            let mut header = tar::Header::new_ustar();
+            set_fallback_name(&mut header, &entry.path);
+            header.set_size(entry.data.len() as u64);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(1_500_000_000);
+            header.set_entry_type(entry.entry_type);
+            header.set_cksum();
+            tarfile.append(&header, Cursor::new(&entry.data)).unwrap();
+        }
+        tarfile.into_inner().unwrap()
+    }
+
+    #[allow(dead_code)]
+    fn read_tar(data: &[u8]) -> Vec<Entry> {
+        let mut archive = tar::Archive::new(Cursor::new(data));
+        let mut entries = vec![];
+        for entry in RecordEntries::new(&mut archive).unwrap() {
+            let (mut entry, records) = entry.unwrap();
+            let path = entry_path(&entry, &records).unwrap();
+            let link = match pax_value(&records, "linkpath") {
+                Some(link) => Some(link.to_vec()),
+                None => {
+                    entry.link_name().unwrap().map(|l| path_to_bytes(&l).to_vec())
+                }
+            };
+            let mut data = vec![];
+            entry.read_to_end(&mut data).unwrap();
+            entries.push(Entry {
+                path: path_to_bytes(&path).to_vec(),
+                entry_type: entry.header().entry_type(),
+                link: link,
+                data: data,
+                xattrs: records.into_iter().filter(|r| r.0.starts_with(PAX_XATTR_PREFIX)).collect()
+            });
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        entries
+    }
+
+    #[allow(dead_code)]
+    fn roundtrip(data: &[u8]) -> Vec<u8> {
+        let mut repo = Repository::create_temporary(Config::default()).unwrap();
+        let mut backup = Backup::default();
+        let mut failed = vec![];
+        let (root, _) = repo.import_tarfile_as_inode(&mut backup, Cursor::new(data), &mut failed)
+            .unwrap();
+        assert!(failed.is_empty());
+        repo.flush().unwrap();
+        let mut tarfile = tar::Builder::new(Vec::new());
+        repo.export_tarfile_recurse(&backup, Path::new(""), root, &mut tarfile, true).unwrap();
+        tarfile.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_roundtrip_special_names() {
+        let long_dir = vec![b'd'; 150];
+        let mut long_file = long_dir.clone();
+        long_file.extend_from_slice(b"/");
+        long_file.extend_from_slice(&[b'f'; 120]);
+        let mut long_link = entry(b"link", tar::EntryType::Symlink, b"");
+        long_link.link = Some(long_file.clone());
+        let mut xattr_file = entry("n\u{e4}me".as_bytes(), tar::EntryType::Regular, b"xattr");
+        xattr_file.xattrs.push(("user.binary".to_string(), vec![0, 1, 0xff, b'\n']));
+        let original = vec![
+            entry(&long_dir, tar::EntryType::Directory, b""),
+            entry(&long_file, tar::EntryType::Regular, &[42; 1000]),
+            entry(b"new\nline", tar::EntryType::Regular, b"data"),
+            long_link,
+            xattr_file,
+        ];
+        let exported = read_tar(&roundtrip(&build_tar(&original)));
+        let original = read_tar(&build_tar(&original));
+        assert_eq!(exported.len(), original.len());
+        for (a, b) in exported.iter().zip(original.iter()) {
+            assert_eq!(a.path, b.path);
+            assert_eq!(a.entry_type, b.entry_type);
+            assert_eq!(a.link, b.link);
+            assert_eq!(a.data, b.data);
+            assert_eq!(a.xattrs, b.xattrs);
+        }
+    }
+
+}
@@ -0,0 +1,189 @@
+use prelude::*;
+
+use chrono::prelude::*;
+use filetime::{self, FileTime};
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+
+/// Format of the removal time that is appended to the names of backups in the trash
+const TRASH_TIME_FORMAT: &'static str = "%Y-%m-%d-%H%M%S";
+
+
+fn move_file(src: &Path, dst: &Path) -> Result<(), io::Error> {
+    try!(fs::create_dir_all(dst.parent().unwrap()));
+    if fs::rename(src, dst).is_err() {
+        try!(fs::copy(src, dst));
+        try!(fs::remove_file(src));
+    }
+    Ok(())
+}
+
+/// Returns the name of the backup a trash entry has been created from
+///
+/// Entries are named `name@time`, entries of older versions have no suffix.
+fn untrashed_name(trash_name: &str) -> &str {
+    if let Some(pos) = trash_name.rfind('@') {
+        let suffix = &trash_name[pos + 1..];
+        if !suffix.is_empty() && suffix.chars().all(|c| c.is_digit(10) || c == '-') {
+            return &trash_name[..pos];
+        }
+    }
+    trash_name
+}
+
+/// Removes the empty parent folders of `path` up to the folder `base`
+fn remove_empty_parents(path: &Path, base: &Path) {
+    let mut path = path.to_path_buf();
+    loop {
+        path = path.parent().unwrap().to_owned();
+        if path == base || fs::remove_dir(&path).is_err() {
+            break;
+        }
+    }
+}
+
+
+impl Repository {
+    /// Returns a name for the trash entry of the backup that is not used yet
+    fn new_trash_name(&self, name: &str) -> String {
+        let base = format!("{}@{}", name, Utc::now().format(TRASH_TIME_FORMAT));
+        let mut trash_name = base.clone();
+        let mut count = 1;
+        while self.layout.trash_backup_path(&trash_name).exists() {
+            count += 1;
+            trash_name = format!("{}-{}", base, count);
+        }
+        trash_name
+    }
+
+    /// Removes the backup file, it is moved to the trash if a trash retention is configured
+    ///
+    /// The backup is stored in the trash as `name@time` so that backups with the same name
+    /// that are removed later do not replace it. The modification time of a trashed backup file
+    /// is set to the time of its removal.
+    pub fn delete_backup(&mut self, name: &str) -> Result<(), RepositoryError> {
+        try!(self.write_mode());
+        let path = self.layout.backup_path(name);
+        let trash_name = if self.config.trash_retention > 0 {
+            let trash_name = self.new_trash_name(name);
+            let trash_path = self.layout.trash_backup_path(&trash_name);
+            try!(move_file(&path, &trash_path));
+            let time = FileTime::from_seconds_since_1970(Utc::now().timestamp() as u64, 0);
+            try!(filetime::set_file_times(&trash_path, time, time));
+            Some(trash_name)
+        } else {
+            try!(fs::remove_file(&path));
+            None
+        };
+        remove_empty_parents(&path, &self.layout.backups_path());
+        self.audit("backup-remove", name.to_string());
+        self.count_removed_backup(name, trash_name.as_ref().map(|n| n as &str));
+        Ok(())
+    }
+
+    /// Fails if the backup is younger than the configured minimal retention
+    pub fn check_min_retention(&self, name: &str, backup: &Backup) -> Result<(), RepositoryError> {
+        let window = self.config.min_retention as i64 * 24 * 60 * 60;
+        if window > 0 && backup.timestamp > Utc::now().timestamp() - window {
+            return Err(RepositoryError::Protected(name.to_string(), self.config.min_retention));
+        }
+        Ok(())
+    }
+
+    /// Returns the backups in the trash with the times of their removal, sorted by name
+    ///
+    /// The names are the ones of the trash entries, i.e. `name@time`.
+    pub fn get_trashed_backups(&self) -> Result<Vec<(String, Backup, i64)>, RepositoryError> {
+        let backups = try!(Backup::get_all_from(
+            &self.crypto.lock().unwrap(),
+            self.layout.trash_path()
+        ));
+        let mut trashed = Vec::with_capacity(backups.len());
+        for (name, backup) in backups {
+            let meta = try!(fs::metadata(self.layout.trash_backup_path(&name)));
+            let removed = FileTime::from_last_modification_time(&meta).seconds_relative_to_1970();
+            trashed.push((name, backup, removed as i64));
+        }
+        trashed.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(trashed)
+    }
+
+    /// Returns the time when a backup removed at the given time will be deleted from the trash
+    #[inline]
+    pub fn trash_expiry(&self, removed: i64) -> i64 {
+        removed + self.config.trash_retention as i64 * 24 * 60 * 60
+    }
+
+    /// Returns the name of the trash entry for a trash entry or backup name
+    ///
+    /// If the trash contains several removed backups with that name, the one removed last is
+    /// selected.
+    fn find_trashed_backup(&self, name: &str) -> Result<String, RepositoryError> {
+        if self.layout.trash_backup_path(name).exists() {
+            return Ok(name.to_string());
+        }
+        let mut found: Option<(String, i64)> = None;
+        for (trash_name, _backup, removed) in try!(self.get_trashed_backups()) {
+            if untrashed_name(&trash_name) != name {
+                continue;
+            }
+            if found.as_ref().map_or(true, |&(_, time)| removed >= time) {
+                found = Some((trash_name, removed));
+            }
+        }
+        found.map(|(trash_name, _)| trash_name).ok_or_else(|| {
+            RepositoryError::NotInTrash(name.to_string())
+        })
+    }
+
+    /// Moves a backup from the trash back to the backups
+    ///
+    /// The name can either be the name of the trash entry or the one of the removed backup.
+    /// Returns the name the backup has been restored as.
+    pub fn undelete_backup(&mut self, name: &str) -> Result<String, RepositoryError> {
+        try!(self.write_mode());
+        let trash_name = try!(self.find_trashed_backup(name));
+        let trash_path = self.layout.trash_backup_path(&trash_name);
+        let name = untrashed_name(&trash_name).to_string();
+        let path = self.layout.backup_path(&name);
+        if path.exists() {
+            return Err(RepositoryError::BackupExists(name));
+        }
+        try!(move_file(&trash_path, &path));
+        remove_empty_parents(&trash_path, &self.layout.trash_path());
+        self.audit("backup-undelete", name.clone());
+        self.count_undeleted_backup(&trash_name, &name);
+        Ok(name)
+    }
+
+    /// Deletes all backups whose retention time in the trash has passed
+    ///
+    /// Without `force` the expired backups are only reported. Returns the names of the expired
+    /// backups.
+    pub fn expire_trash(&mut self, force: bool) -> Result<Vec<String>, RepositoryError> {
+        if force {
+            try!(self.write_mode());
+        }
+        let now = Utc::now().timestamp();
+        let mut expired = vec![];
+        for (name, _backup, removed) in try!(self.get_trashed_backups()) {
+            if self.trash_expiry(removed) <= now {
+                expired.push(name);
+            }
+        }
+        if !force || expired.is_empty() {
+            return Ok(expired);
+        }
+        for name in &expired {
+            let path = self.layout.trash_backup_path(name);
+            try!(fs::remove_file(&path));
+            remove_empty_parents(&path, &self.layout.trash_path());
+        }
+        self.audit("trash-expire", expired.join(", "));
+        self.count_expired_backups(&expired);
+        Ok(expired)
+    }
+}
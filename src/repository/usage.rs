@@ -0,0 +1,531 @@
+use prelude::*;
+
+use chrono::prelude::*;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::io::{self, BufReader, Read, Write, BufWriter};
+use std::fs::{self, File};
+
+
+pub static HEADER_STRING: [u8; 7] = *b"zvusage";
+static HEADER_VERSION: u8 = 2;
+
+/// Prefix of the names of trashed backups in the usage cache, backup names can not contain "::"
+static TRASH_PREFIX: &'static str = "::trash::";
+
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum UsageCacheError {
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            description("Failed to read/write usage cache")
+        }
+        Decode(err: msgpack::DecodeError) {
+            from()
+            cause(err)
+            description("Failed to decode usage cache")
+        }
+        Encode(err: msgpack::EncodeError) {
+            from()
+            cause(err)
+            description("Failed to encode usage cache")
+        }
+        WrongHeader {
+            description("Wrong header")
+        }
+        WrongVersion(version: u8) {
+            description("Wrong version")
+            display("Wrong version: {}", version)
+        }
+        Inconsistent(hash: Hash) {
+            description("Inconsistent reference count")
+            display("Inconsistent reference count of chunk {}", hash)
+        }
+    }
+}
+
+
+/// Reference counts of all chunks that are used by backups
+///
+/// Every backup that uses a chunk counts as one reference, no matter how often the chunk
+/// appears in the backup. The cache also stores the roots of all counted backups, so only
+/// backups that have been created, modified or removed since need to be walked to update the
+/// counts. The counts are stored per chunk, so they stay valid when vacuum moves chunks to
+/// other bundles.
+///
+/// Every counted backup has a numeric id and every chunk stores the sum of the ids of the
+/// backups using it. For chunks with a single reference, this is the id of the only backup
+/// that uses it, so the data unique to a backup can be found without walking it.
+#[derive(Default, Clone)]
+pub struct UsageCache {
+    /// Root and id of every counted backup
+    pub backups: HashMap<String, (ChunkList, u64)>,
+    /// Number of references, length and sum of the ids of the referencing backups of every
+    /// used chunk
+    pub chunks: HashMap<Hash, (u32, u32, u64)>,
+    pub next_id: u64
+}
+serde_impl!(UsageCache(u8) {
+    backups: HashMap<String, (ChunkList, u64)> => 0,
+    chunks: HashMap<Hash, (u32, u32, u64)> => 1,
+    next_id: u64 => 2
+});
+
+impl UsageCache {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, UsageCacheError> {
+        let mut file = BufReader::new(try!(File::open(path.as_ref())));
+        let mut header = [0u8; 8];
+        try!(file.read_exact(&mut header));
+        if header[..HEADER_STRING.len()] != HEADER_STRING {
+            return Err(UsageCacheError::WrongHeader);
+        }
+        let version = header[HEADER_STRING.len()];
+        if version != HEADER_VERSION {
+            return Err(UsageCacheError::WrongVersion(version));
+        }
+        Ok(try!(msgpack::decode_from_stream(&mut file)))
+    }
+
+    /// Saves the cache, it is written to a temporary file first so readers never see a
+    /// partially written cache
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), UsageCacheError> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = BufWriter::new(try!(File::create(&tmp_path)));
+            try!(file.write_all(&HEADER_STRING));
+            try!(file.write_all(&[HEADER_VERSION]));
+            try!(msgpack::encode_to_stream(self, &mut file));
+            try!(file.flush());
+        }
+        try!(fs::rename(&tmp_path, path));
+        Ok(())
+    }
+
+    /// Counts the chunks as used by the backup and returns the id of the backup
+    fn add_backup(&mut self, name: String, root: ChunkList, chunks: &HashMap<Hash, u32>) -> u64 {
+        self.next_id += 1;
+        let id = self.next_id;
+        for (&hash, &len) in chunks {
+            let entry = self.chunks.entry(hash).or_insert((0, len, 0));
+            entry.0 += 1;
+            entry.2 = entry.2.wrapping_add(id);
+        }
+        self.backups.insert(name, (root, id));
+        id
+    }
+
+    fn remove_references(
+        &mut self,
+        id: u64,
+        chunks: &HashMap<Hash, u32>,
+    ) -> Result<(), UsageCacheError> {
+        for hash in chunks.keys() {
+            let unused = match self.chunks.get_mut(hash) {
+                Some(entry) if entry.0 > 0 => {
+                    entry.0 -= 1;
+                    entry.2 = entry.2.wrapping_sub(id);
+                    entry.0 == 0
+                }
+                _ => return Err(UsageCacheError::Inconsistent(*hash)),
+            };
+            if unused {
+                self.chunks.remove(hash);
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// Adds the chunks to the set and returns whether any of them was new
+fn add_chunks(set: &mut HashMap<Hash, u32>, chunks: &[Chunk]) -> bool {
+    let mut new = false;
+    for &(hash, len) in chunks {
+        if set.insert(hash, len).is_none() {
+            new = true;
+        }
+    }
+    new
+}
+
+
+impl Repository {
+    /// Returns the distinct chunks of the backup with their lengths
+    fn get_backup_chunks(
+        &mut self,
+        root: &ChunkList,
+    ) -> Result<HashMap<Hash, u32>, RepositoryError> {
+        let mut chunks = HashMap::new();
+        let mut todo = vec![root.clone()];
+        while let Some(inode_chunks) = todo.pop() {
+            // Subtrees that have already been seen in this backup are skipped with their children
+            if !add_chunks(&mut chunks, &inode_chunks) {
+                continue;
+            }
+            let inode = try!(self.get_inode(&inode_chunks));
+            match inode.data {
+                None |
+                Some(FileData::Inline(_)) => (),
+                Some(FileData::ChunkedDirect(ref data_chunks)) => {
+                    add_chunks(&mut chunks, data_chunks);
+                }
+                Some(FileData::ChunkedIndirect(ref data_chunks)) => {
+                    if add_chunks(&mut chunks, data_chunks) {
+                        let chunk_data = try!(self.get_data(data_chunks));
+                        add_chunks(&mut chunks, &ChunkList::read_from(&chunk_data));
+                    }
+                }
+            }
+            if let Some(children) = inode.children {
+                todo.extend(children.into_iter().map(|(_, chunks)| chunks));
+            }
+        }
+        Ok(chunks)
+    }
+
+    /// Returns all backups whose chunks are counted as used
+    ///
+    /// Backups in the trash can still be restored, so their data stays in use until they
+    /// expire.
+    fn get_counted_backups(&self) -> Result<HashMap<String, Backup>, RepositoryError> {
+        let mut backups = try!(self.get_all_backups());
+        let now = Utc::now().timestamp();
+        for (name, backup, removed) in try!(self.get_trashed_backups()) {
+            if self.trash_expiry(removed) > now {
+                backups.insert(format!("{}{}", TRASH_PREFIX, name), backup);
+            }
+        }
+        Ok(backups)
+    }
+
+    /// Updates the reference counts so they match the given backups
+    ///
+    /// Only backups that have been added, modified or removed since the counts were last
+    /// updated are walked. Backups that have only been renamed, e.g. moved to the trash, keep
+    /// their references.
+    fn count_references(
+        &mut self,
+        cache: &mut UsageCache,
+        backups: &HashMap<String, Backup>,
+    ) -> Result<(), RepositoryError> {
+        let mut removed = vec![];
+        for (name, &(ref root, id)) in &cache.backups {
+            if backups.get(name).map_or(true, |backup| backup.root != *root) {
+                removed.push((name.clone(), root.clone(), id));
+            }
+        }
+        for &(ref name, _, _) in &removed {
+            cache.backups.remove(name);
+        }
+        let mut added = vec![];
+        for (name, backup) in backups {
+            if !cache.backups.contains_key(name) {
+                match removed.iter().position(|r| r.1 == backup.root) {
+                    Some(pos) => {
+                        let (_, root, id) = removed.swap_remove(pos);
+                        cache.backups.insert(name.clone(), (root, id));
+                    }
+                    None => added.push((name.clone(), backup.root.clone())),
+                }
+            }
+        }
+        if removed.len() + added.len() > 1 {
+            info!(
+                "Updating chunk usage of {} added and {} removed backups",
+                added.len(),
+                removed.len()
+            );
+        }
+        for (_name, root, id) in removed {
+            let chunks = try!(self.get_backup_chunks(&root));
+            try!(cache.remove_references(id, &chunks));
+        }
+        for (name, root) in added {
+            let chunks = try!(self.get_backup_chunks(&root));
+            cache.add_backup(name, root, &chunks);
+        }
+        Ok(())
+    }
+
+    /// Removes the references of the counted backup with this name if there is one
+    fn remove_counted_backup(
+        &mut self,
+        cache: &mut UsageCache,
+        name: &str,
+    ) -> Result<(), RepositoryError> {
+        if let Some((root, id)) = cache.backups.remove(name) {
+            let chunks = try!(self.get_backup_chunks(&root));
+            try!(cache.remove_references(id, &chunks));
+        }
+        Ok(())
+    }
+
+    /// Loads the reference counts and updates them to match the given backups
+    ///
+    /// If the stored counts can not be updated, e.g. because an old version of a backup can
+    /// not be read anymore, all backups are counted again.
+    fn load_usage_cache(
+        &mut self,
+        backups: &HashMap<String, Backup>,
+    ) -> Result<UsageCache, RepositoryError> {
+        let mut cache = match UsageCache::load(self.layout.usage_cache_path()) {
+            Ok(cache) => cache,
+            Err(UsageCacheError::Io(ref err)) if err.kind() == io::ErrorKind::NotFound => {
+                UsageCache::default()
+            }
+            Err(UsageCacheError::WrongVersion(_)) => {
+                info!("Chunk usage has an old format, counting all backups");
+                UsageCache::default()
+            }
+            Err(err) => {
+                warn!("Failed to load chunk usage, counting all backups: {}", err);
+                UsageCache::default()
+            }
+        };
+        if let Err(err) = self.count_references(&mut cache, backups) {
+            warn!("Failed to update chunk usage, counting all backups: {}", err);
+            cache = UsageCache::default();
+            try!(self.count_references(&mut cache, backups));
+        }
+        Ok(cache)
+    }
+
+    /// Loads the reference counts of all backups and stores them after updating
+    ///
+    /// With `include_trash` set to false, the returned counts ignore the backups in the trash
+    /// as if they had already expired.
+    pub fn get_chunk_usage(&mut self, include_trash: bool) -> Result<UsageCache, RepositoryError> {
+        let backups = try!(self.get_counted_backups());
+        let mut cache = try!(self.load_usage_cache(&backups));
+        try!(cache.save(self.layout.usage_cache_path()));
+        if !include_trash {
+            let backups = backups
+                .into_iter()
+                .filter(|&(ref name, _)| !name.starts_with(TRASH_PREFIX))
+                .collect();
+            try!(self.count_references(&mut cache, &backups));
+        }
+        Ok(cache)
+    }
+
+    /// Applies a change of some backups to the stored reference counts
+    ///
+    /// Only the changed backups are walked by `update`. Without stored counts nothing happens as
+    /// all backups are counted on the next analysis anyway. Failures are only logged, the counts
+    /// are then removed and recounted on the next analysis.
+    fn update_chunk_usage<F>(&mut self, update: F)
+    where
+        F: FnOnce(&mut Self, &mut UsageCache) -> Result<(), RepositoryError>,
+    {
+        let path = self.layout.usage_cache_path();
+        let mut cache = match UsageCache::load(&path) {
+            Ok(cache) => cache,
+            Err(_) => return,
+        };
+        let res = update(self, &mut cache).and_then(|()| Ok(try!(cache.save(&path))));
+        if let Err(err) = res {
+            warn!("Failed to update chunk usage: {}", err);
+            fs::remove_file(&path).ok();
+        }
+    }
+
+    /// Counts the references of a backup that has been created or modified
+    pub fn count_saved_backup(&mut self, name: &str, backup: &Backup) {
+        self.update_chunk_usage(|repo, cache| {
+            if cache.backups.get(name).map_or(false, |&(ref root, _)| *root == backup.root) {
+                return Ok(());
+            }
+            try!(repo.remove_counted_backup(cache, name));
+            let chunks = try!(repo.get_backup_chunks(&backup.root));
+            cache.add_backup(name.to_string(), backup.root.clone(), &chunks);
+            Ok(())
+        })
+    }
+
+    /// Removes the references of a backup that has been removed
+    ///
+    /// Backups that have been moved to the trash as `trash_name` keep their references.
+    pub fn count_removed_backup(&mut self, name: &str, trash_name: Option<&str>) {
+        self.update_chunk_usage(|repo, cache| {
+            let trash_name = match trash_name {
+                Some(trash_name) => format!("{}{}", TRASH_PREFIX, trash_name),
+                None => return repo.remove_counted_backup(cache, name),
+            };
+            if let Some(backup) = cache.backups.remove(name) {
+                cache.backups.insert(trash_name, backup);
+            }
+            Ok(())
+        })
+    }
+
+    /// Moves the references of a backup that has been restored from the trash
+    pub fn count_undeleted_backup(&mut self, trash_name: &str, name: &str) {
+        self.update_chunk_usage(|_repo, cache| {
+            let trash_name = format!("{}{}", TRASH_PREFIX, trash_name);
+            if let Some(backup) = cache.backups.remove(&trash_name) {
+                cache.backups.insert(name.to_string(), backup);
+            }
+            Ok(())
+        })
+    }
+
+    /// Removes the references of backups that have been deleted from the trash
+    pub fn count_expired_backups(&mut self, names: &[String]) {
+        self.update_chunk_usage(|repo, cache| {
+            for name in names {
+                try!(repo.remove_counted_backup(cache, &format!("{}{}", TRASH_PREFIX, name)));
+            }
+            Ok(())
+        })
+    }
+
+    /// Returns the raw size and the estimated encoded size of the data only used by the backup
+    ///
+    /// This is the space that is freed when the backup is removed and its bundles are
+    /// vacuumed. It is taken from the stored chunk usage without reading the backup. `None` is
+    /// returned if the backup has not been counted in the stored chunk usage yet, e.g. when it
+    /// was created by an older version.
+    pub fn get_unique_size(
+        &self,
+        name: &str,
+        backup: &Backup,
+    ) -> Result<Option<(u64, u64)>, RepositoryError> {
+        let cache = match UsageCache::load(self.layout.usage_cache_path()) {
+            Ok(cache) => cache,
+            Err(_) => return Ok(None),
+        };
+        let id = match cache.backups.get(name) {
+            Some(&(ref root, id)) if *root == backup.root => id,
+            _ => return Ok(None),
+        };
+        let mut raw_size = 0;
+        let mut encoded_size = 0.0;
+        for (hash, &(refs, len, owners)) in &cache.chunks {
+            if refs != 1 || owners != id {
+                continue;
+            }
+            raw_size += len as u64;
+            let pos = try!(self.index.get(hash).ok_or_else(|| IntegrityError::MissingChunk(*hash)));
+            let bundle_id = try!(self.bundle_map.get(pos.bundle).ok_or_else(|| {
+                IntegrityError::MissingBundleId(pos.bundle)
+            }));
+            let bundle = try!(self.bundles.get_bundle_info(&bundle_id).ok_or_else(|| {
+                IntegrityError::MissingBundle(bundle_id.clone())
+            }));
+            encoded_size +=
+                len as f32 * bundle.info.encoded_size as f32 / bundle.info.raw_size as f32;
+        }
+        Ok(Some((raw_size, encoded_size as u64)))
+    }
+}
+
+
+#[cfg(any(test, feature = "memory"))]
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use rand;
+
+    #[allow(dead_code)]
+    fn random_chunks(repo: &mut Repository, len: usize) -> ChunkList {
+        let data: Vec<u8> = (0..len).map(|_| rand::random()).collect();
+        repo.put_data(BundleMode::Data, &data).unwrap()
+    }
+
+    #[allow(dead_code)]
+    fn file_backup(repo: &mut Repository, parts: &[&ChunkList]) -> Backup {
+        let mut chunks = vec![];
+        for part in parts {
+            chunks.extend_from_slice(part);
+        }
+        let chunks = ChunkList::from(chunks);
+        let file = Inode {
+            name: "file".to_string(),
+            file_type: FileType::File,
+            size: chunks.iter().map(|&(_, len)| len as u64).sum(),
+            data: Some(FileData::ChunkedDirect(chunks)),
+            ..Default::default()
+        };
+        let mut backup = Backup::default();
+        backup.root = repo.put_inode(&file).unwrap();
+        backup
+    }
+
+    /// Returns the number of counted backups that use the chunk
+    #[allow(dead_code)]
+    fn references(cache: &UsageCache, hash: &Hash) -> u32 {
+        cache.chunks.get(hash).map_or(0, |&(refs, _, _)| refs)
+    }
+
+    #[allow(dead_code)]
+    fn raw_size(chunks: &[&ChunkList]) -> u64 {
+        chunks.iter().flat_map(|c| c.iter()).map(|&(_, len)| len as u64).sum()
+    }
+
+    #[test]
+    fn test_incremental_usage() {
+        let mut config = Config::default();
+        config.trash_retention = 0;
+        let mut repo = Repository::create_temporary(config).unwrap();
+        repo.get_chunk_usage(true).unwrap();
+        let shared = random_chunks(&mut repo, 100_000);
+        let first = random_chunks(&mut repo, 100_000);
+        let second = random_chunks(&mut repo, 100_000);
+        let backup1 = file_backup(&mut repo, &[&shared, &first]);
+        let backup2 = file_backup(&mut repo, &[&shared, &second]);
+        repo.flush().unwrap();
+        repo.save_backup(&backup1, "first").unwrap();
+        repo.save_backup(&backup2, "second").unwrap();
+        assert_eq!(
+            repo.get_unique_size("first", &backup1).unwrap().map(|s| s.0),
+            Some(raw_size(&[&first, &backup1.root]))
+        );
+        repo.delete_backup("second").unwrap();
+        assert_eq!(
+            repo.get_unique_size("first", &backup1).unwrap().map(|s| s.0),
+            Some(raw_size(&[&shared, &first, &backup1.root]))
+        );
+        // The incremental counts have to match the ones of a full count
+        let cache = UsageCache::load(repo.layout.usage_cache_path()).unwrap();
+        fs::remove_file(repo.layout.usage_cache_path()).unwrap();
+        let counted = repo.get_chunk_usage(true).unwrap();
+        assert_eq!(cache.chunks.len(), counted.chunks.len());
+        for hash in counted.chunks.keys() {
+            assert_eq!(references(&cache, hash), references(&counted, hash));
+        }
+    }
+
+    #[test]
+    fn test_trash_keeps_backups_with_same_name() {
+        let mut repo = Repository::create_temporary(Config::default()).unwrap();
+        let old = random_chunks(&mut repo, 100_000);
+        let new = random_chunks(&mut repo, 100_000);
+        let old_backup = file_backup(&mut repo, &[&old]);
+        let new_backup = file_backup(&mut repo, &[&new]);
+        repo.flush().unwrap();
+        repo.save_backup(&old_backup, "backup").unwrap();
+        repo.delete_backup("backup").unwrap();
+        repo.save_backup(&new_backup, "backup").unwrap();
+        repo.delete_backup("backup").unwrap();
+        let trashed = repo.get_trashed_backups().unwrap();
+        assert_eq!(trashed.len(), 2);
+        // Both removed backups still count as used
+        let counted = repo.get_chunk_usage(true).unwrap();
+        assert_eq!(references(&counted, &old[0].0), 1);
+        assert_eq!(references(&counted, &new[0].0), 1);
+        // The backup removed last is restored by its name
+        assert_eq!(repo.undelete_backup("backup").unwrap(), "backup");
+        assert_eq!(repo.get_backup("backup").unwrap().root, new_backup.root);
+        let trashed = repo.get_trashed_backups().unwrap();
+        assert_eq!(trashed.len(), 1);
+        assert_eq!(trashed[0].1.root, old_backup.root);
+        assert!(repo.undelete_backup(&trashed[0].0).is_err());
+    }
+}
@@ -1,10 +1,16 @@
 use prelude::*;
 
-use std::collections::HashSet;
+use chrono::prelude::*;
+
+use std::collections::{HashMap, HashSet};
+
+
+/// Bundles younger than this (in seconds) are not combined as their usage is likely to change
+const COMBINE_MIN_AGE: i64 = 24 * 3600;
 
 
 impl Repository {
-    fn delete_bundle(&mut self, id: u32) -> Result<(), RepositoryError> {
+    fn delete_bundle(&mut self, id: u64) -> Result<(), RepositoryError> {
         if let Some(bundle) = self.bundle_map.remove(id) {
             try!(self.bundles.delete_bundle(&bundle));
             Ok(())
@@ -13,16 +19,62 @@ impl Repository {
         }
     }
 
+    /// Selects groups of small bundles that are combined into larger ones
+    ///
+    /// Bundles smaller than `threshold` are grouped by mode and sorted by age so that each group
+    /// contains bundles of similar age that together fill at most one new bundle. Groups with
+    /// only one bundle are left alone as rewriting them would not combine anything.
+    fn select_combine_bundles(
+        &self,
+        usage: &HashMap<u64, BundleAnalysis>,
+        threshold: usize,
+    ) -> Vec<u64> {
+        let min_age = Utc::now().timestamp() - COMBINE_MIN_AGE;
+        let mut selected = vec![];
+        for mode in &[BundleMode::Meta, BundleMode::Data] {
+            let mut small: Vec<_> = usage
+                .iter()
+                .filter(|&(_, bundle)| {
+                    bundle.info.mode == *mode && bundle.info.encoded_size < threshold &&
+                        bundle.info.timestamp <= min_age
+                })
+                .map(|(id, bundle)| (bundle.info.timestamp, *id, bundle.get_used_size()))
+                .collect();
+            small.sort();
+            let mut group = vec![];
+            let mut group_size = 0;
+            for (_, id, size) in small {
+                if group_size + size > self.config.bundle_size {
+                    if group.len() >= 2 {
+                        selected.extend_from_slice(&group);
+                    }
+                    group.clear();
+                    group_size = 0;
+                }
+                group.push(id);
+                group_size += size;
+            }
+            if group.len() >= 2 {
+                selected.extend_from_slice(&group);
+            }
+        }
+        selected
+    }
+
     pub fn vacuum(
         &mut self,
         ratio: f32,
-        combine: bool,
+        combine: Option<usize>,
         force: bool,
     ) -> Result<(), RepositoryError> {
         try!(self.flush());
         info!("Locking repository");
         try!(self.write_mode());
         let _lock = try!(self.lock(true));
+        let expired = try!(self.expire_trash(force));
+        if !expired.is_empty() {
+            info!("Deleting {} expired backups from the trash", expired.len());
+        }
         // analyze_usage will set the dirty flag
         info!("Analyzing chunk usage");
         let usage = try!(self.analyze_usage());
@@ -46,27 +98,10 @@ impl Repository {
                 reclaim_space += bundle.get_unused_size();
             }
         }
-        if combine {
-            let mut small_meta = vec![];
-            let mut small_data = vec![];
-            for (id, bundle) in &usage {
-                if bundle.info.encoded_size * 4 < self.config.bundle_size {
-                    match bundle.info.mode {
-                        BundleMode::Meta => small_meta.push(*id),
-                        BundleMode::Data => small_data.push(*id),
-                    }
-                }
-            }
-            if small_meta.len() >= 2 {
-                for bundle in small_meta {
-                    rewrite_bundles.insert(bundle);
-                }
-            }
-            if small_data.len() >= 2 {
-                for bundle in small_data {
-                    rewrite_bundles.insert(bundle);
-                }
-            }
+        if let Some(threshold) = combine {
+            let combined = self.select_combine_bundles(&usage, threshold);
+            info!("Combining {} small bundles", combined.len());
+            rewrite_bundles.extend(combined);
         }
         info!(
             "Reclaiming {} by rewriting {} bundles",
@@ -77,10 +112,13 @@ impl Repository {
             self.dirty = false;
             return Ok(());
         }
+        // Rewriting the bundles by age keeps chunks of similar age together in the new bundles
+        let mut rewrite_order: Vec<_> = rewrite_bundles.iter().collect();
+        rewrite_order.sort_by_key(|id| (usage[*id].info.timestamp, **id));
         for id in ProgressIter::new(
             "rewriting bundles",
-            rewrite_bundles.len(),
-            rewrite_bundles.iter()
+            rewrite_order.len(),
+            rewrite_order.into_iter()
         )
         {
             let bundle = &usage[id];
@@ -109,10 +147,32 @@ impl Repository {
             }
         }
         info!("Deleting {} bundles", rewrite_bundles.len());
+        let mut deleted = Vec::with_capacity(rewrite_bundles.len());
         for id in rewrite_bundles {
+            if let Some(bundle) = self.bundle_map.get(id) {
+                deleted.push(bundle.to_string());
+            }
             try!(self.delete_bundle(id));
         }
         try!(self.save_bundle_map());
+        let index_size = self.index.size();
+        if try!(self.index.shrink_to_fit()) {
+            info!(
+                "Shrunk the index from {} to {}",
+                to_file_size(index_size as u64),
+                to_file_size(self.index.size() as u64)
+            );
+        }
+        self.audit(
+            "vacuum",
+            format!(
+                "ratio={}, combine={}, reclaimed={}, bundles=[{}]",
+                ratio,
+                combine.is_some(),
+                reclaim_space,
+                deleted.join(", ")
+            )
+        );
         self.dirty = false;
         Ok(())
     }
@@ -0,0 +1,256 @@
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+
+
+/// Environment variable that can hold the path of the agent socket
+pub static AGENT_SOCKET_ENV: &'static str = "ZVAULT_AGENT_SOCKET";
+
+
+/// Returns the path of the socket that the passphrase agent listens on
+///
+/// The path is taken from the environment variable `ZVAULT_AGENT_SOCKET` if it is set and is
+/// `~/.zvault/agent.socket` otherwise.
+pub fn agent_socket_path() -> PathBuf {
+    if let Some(path) = env::var_os(AGENT_SOCKET_ENV) {
+        return PathBuf::from(path);
+    }
+    env::home_dir().unwrap().join(".zvault").join("agent.socket")
+}
+
+/// Returns the id of the user running the process on the other end of the socket
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &UnixStream) -> Result<libc::uid_t, io::Error> {
+    #[repr(C)]
+    struct PeerCredentials {
+        pid: libc::pid_t,
+        uid: libc::uid_t,
+        gid: libc::gid_t
+    }
+    let mut cred = PeerCredentials {
+        pid: 0,
+        uid: 0,
+        gid: 0
+    };
+    let mut len = ::std::mem::size_of::<PeerCredentials>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut PeerCredentials as *mut libc::c_void,
+            &mut len
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+/// Returns the id of the user running the process on the other end of the socket
+#[cfg(all(unix, not(target_os = "linux")))]
+fn peer_uid(stream: &UnixStream) -> Result<libc::uid_t, io::Error> {
+    extern "C" {
+        fn getpeereid(
+            socket: libc::c_int,
+            euid: *mut libc::uid_t,
+            egid: *mut libc::gid_t,
+        ) -> libc::c_int;
+    }
+    let (mut uid, mut gid) = (0, 0);
+    if unsafe { getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+/// Fails unless the other end of the socket is run by the same user as this process
+///
+/// Clients check this before they send the passphrase so that it is not given to a socket that
+/// another user placed at the path of the agent.
+#[cfg(unix)]
+fn check_peer(stream: &UnixStream) -> Result<(), io::Error> {
+    let uid = try!(peer_uid(stream));
+    if uid != unsafe { libc::getuid() } {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("Agent socket is owned by user {}", uid)
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn agent_request(request: &str) -> Result<String, io::Error> {
+    let mut stream = try!(UnixStream::connect(agent_socket_path()));
+    try!(check_peer(&stream));
+    try!(stream.set_read_timeout(Some(Duration::from_secs(5))));
+    try!(stream.write_all(request.as_bytes()));
+    try!(stream.write_all(b"\n"));
+    let mut line = String::new();
+    try!(BufReader::new(stream).read_line(&mut line));
+    while line.ends_with('\n') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+#[cfg(not(unix))]
+fn agent_request(_request: &str) -> Result<String, io::Error> {
+    Err(io::Error::new(io::ErrorKind::Other, "The agent is not supported on this platform"))
+}
+
+/// Asks a running agent for the cached passphrase
+pub fn agent_get_passphrase() -> Option<String> {
+    match agent_request("GET") {
+        Ok(ref line) if line.starts_with("OK ") => Some(line[3..].to_string()),
+        _ => None,
+    }
+}
+
+/// Stores the passphrase in a running agent, nothing happens if no agent is running
+pub fn agent_set_passphrase(passphrase: &str) {
+    if let Err(err) = agent_request(&format!("SET {}", passphrase)) {
+        debug!("Passphrase not cached, no agent running: {}", err);
+    }
+}
+
+/// Asks a running agent to forget the passphrase and to exit
+pub fn agent_stop() -> Result<(), io::Error> {
+    agent_request("STOP").map(|_| ())
+}
+
+
+/// Caches the passphrase of the secret keys in memory for other processes of the same user
+///
+/// The agent listens on a unix socket that only the user can access and ignores connections of
+/// other users. Every request is answered in its own thread so that slow clients do not block
+/// the others. It exits when the passphrase has not been used for `timeout`.
+#[cfg(unix)]
+pub struct PassphraseAgent {
+    listener: UnixListener,
+    path: PathBuf,
+    timeout: Duration,
+    passphrase: Arc<Mutex<Option<String>>>,
+    stopped: Arc<AtomicBool>
+}
+
+#[cfg(unix)]
+impl PassphraseAgent {
+    pub fn new(timeout: Duration) -> Result<Self, io::Error> {
+        let path = agent_socket_path();
+        if UnixStream::connect(&path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "An agent is already running"));
+        }
+        // The socket of an agent that has been killed is still there
+        if path.exists() {
+            try!(fs::remove_file(&path));
+        }
+        if let Some(parent) = path.parent() {
+            try!(fs::create_dir_all(parent));
+        }
+        // Create the socket without access for others instead of restricting it afterwards
+        let umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(&path);
+        unsafe { libc::umask(umask) };
+        let listener = try!(listener);
+        try!(listener.set_nonblocking(true));
+        Ok(PassphraseAgent {
+            listener: listener,
+            path: path,
+            timeout: timeout,
+            passphrase: Arc::new(Mutex::new(None)),
+            stopped: Arc::new(AtomicBool::new(false))
+        })
+    }
+
+    fn handle(stream: UnixStream, passphrase: &Mutex<Option<String>>) -> Result<bool, io::Error> {
+        try!(stream.set_nonblocking(false));
+        try!(stream.set_read_timeout(Some(Duration::from_secs(5))));
+        let mut line = String::new();
+        try!(BufReader::new(&stream).read_line(&mut line));
+        while line.ends_with('\n') {
+            line.pop();
+        }
+        let mut stream = stream;
+        if line == "GET" {
+            let passphrase = passphrase.lock().unwrap().clone();
+            match passphrase {
+                Some(passphrase) => try!(writeln!(stream, "OK {}", passphrase)),
+                None => try!(writeln!(stream, "NONE")),
+            }
+        } else if line.starts_with("SET ") {
+            *passphrase.lock().unwrap() = Some(line[4..].to_string());
+            try!(writeln!(stream, "OK"));
+        } else if line == "STOP" {
+            try!(writeln!(stream, "OK"));
+            return Ok(false);
+        } else {
+            try!(writeln!(stream, "ERROR"));
+        }
+        Ok(true)
+    }
+
+    /// Answers requests until the timeout expired or the agent is stopped
+    pub fn run(&mut self) -> Result<(), io::Error> {
+        let mut last_use = Instant::now();
+        while last_use.elapsed() < self.timeout && !self.stopped.load(Ordering::SeqCst) {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(err) = check_peer(&stream) {
+                        warn!("Rejected agent request: {}", err);
+                        continue;
+                    }
+                    last_use = Instant::now();
+                    let passphrase = self.passphrase.clone();
+                    let stopped = self.stopped.clone();
+                    thread::spawn(move || match Self::handle(stream, &passphrase) {
+                        Ok(true) => (),
+                        Ok(false) => stopped.store(true, Ordering::SeqCst),
+                        Err(err) => warn!("Failed to answer agent request: {}", err),
+                    });
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(100))
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Drop for PassphraseAgent {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+
+/// The agent needs unix sockets, so it can not be started on other platforms
+#[cfg(not(unix))]
+pub struct PassphraseAgent;
+
+#[cfg(not(unix))]
+impl PassphraseAgent {
+    pub fn new(_timeout: Duration) -> Result<Self, io::Error> {
+        agent_request("START").map(|_| PassphraseAgent)
+    }
+
+    pub fn run(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
@@ -77,6 +77,40 @@ impl ChunkList {
     }
 }
 
+
+/// Returns the total size of the data in the chunks
+///
+/// The sizes of the single chunks are `u32`, their sum easily exceeds that for large files.
+#[inline]
+pub fn chunks_size(chunks: &[Chunk]) -> u64 {
+    chunks.iter().map(|&(_, len)| len as u64).sum()
+}
+
+
+/// Maps the byte range `offset..offset+len` of the data onto the chunks
+///
+/// Returns the chunks that overlap the range as `(index, start, end)` with the part of the
+/// range inside each chunk. The range is cut off at the end of the data.
+pub fn chunk_range(chunks: &[Chunk], mut offset: u64, len: u64) -> Vec<(usize, u32, u32)> {
+    let mut parts = vec![];
+    let mut remaining = len;
+    for (i, &(_, size)) in chunks.iter().enumerate() {
+        if remaining == 0 {
+            break;
+        }
+        if size as u64 <= offset {
+            offset -= size as u64;
+            continue;
+        }
+        let start = offset as u32;
+        let end = offset.saturating_add(remaining).min(size as u64) as u32;
+        parts.push((i, start, end));
+        remaining -= (end - start) as u64;
+        offset = 0;
+    }
+    parts
+}
+
 impl Default for ChunkList {
     #[inline]
     fn default() -> Self {
@@ -142,7 +176,7 @@ impl<'a> Deserialize<'a> for ChunkList {
 mod tests {
 
     #[allow(unused_imports)]
-    use super::ChunkList;
+    use super::{ChunkList, chunk_range, chunks_size};
 
     #[allow(unused_imports)]
     use super::super::Hash;
@@ -174,6 +208,40 @@ mod tests {
         assert_eq!(list.len(), 2);
     }
 
+    #[test]
+    fn test_chunks_size() {
+        assert_eq!(chunks_size(&[]), 0);
+        let chunks = vec![(Hash::default(), u32::max_value()); 3];
+        assert_eq!(chunks_size(&chunks), 3 * u32::max_value() as u64);
+    }
+
+    #[test]
+    fn test_chunk_range_beyond_u32() {
+        let chunks = vec![(Hash::default(), u32::max_value()); 3];
+        let offset = u32::max_value() as u64 + 10;
+        assert_eq!(chunk_range(&chunks, offset, 20), vec![(1, 10, 30)]);
+        // Ranges spanning chunk boundaries far beyond 4 GiB
+        let offset = 2 * u32::max_value() as u64 - 5;
+        assert_eq!(
+            chunk_range(&chunks, offset, 10),
+            vec![(1, u32::max_value() - 5, u32::max_value()), (2, 0, 5)]
+        );
+        // The range is cut off at the end of the data
+        let end = chunks_size(&chunks);
+        let last = vec![(2, u32::max_value() - 1, u32::max_value())];
+        assert_eq!(chunk_range(&chunks, end - 1, u64::max_value()), last);
+        assert!(chunk_range(&chunks, end, 1).is_empty());
+    }
+
+    #[test]
+    fn test_max_chunk_size_roundtrip() {
+        let mut list = ChunkList::new();
+        list.push((Hash::default(), u32::max_value()));
+        let mut buf = Vec::new();
+        assert!(list.write_to(&mut buf).is_ok());
+        assert_eq!(ChunkList::read_from(&buf), list);
+    }
+
     #[test]
     fn test_into_inner() {
         let mut list = ChunkList::new();
@@ -278,4 +346,16 @@ mod tests {
         assert_eq!(msgpack::decode::<ChunkList>(&buf).unwrap(), list);
     }
 
+    #[test]
+    fn test_chunk_range() {
+        let chunks = vec![(Hash::default(), 10), (Hash::default(), 20), (Hash::default(), 5)];
+        assert_eq!(chunk_range(&chunks, 0, 35), vec![(0, 0, 10), (1, 0, 20), (2, 0, 5)]);
+        assert_eq!(chunk_range(&chunks, 5, 10), vec![(0, 5, 10), (1, 0, 5)]);
+        assert_eq!(chunk_range(&chunks, 10, 20), vec![(1, 0, 20)]);
+        assert_eq!(chunk_range(&chunks, 12, 3), vec![(1, 2, 5)]);
+        assert_eq!(chunk_range(&chunks, 32, 100), vec![(2, 2, 5)]);
+        assert!(chunk_range(&chunks, 35, 10).is_empty());
+        assert!(chunk_range(&chunks, 3, 0).is_empty());
+    }
+
 }
@@ -1,30 +1,51 @@
 use pbr;
-use std::io::Stdout;
+use chrono::prelude::*;
+use chrono::format::{Item, StrftimeItems};
+use std::env;
+use std::io::{self, Stdout, Write, Read, BufRead};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
 use std::time::Duration;
 
-pub fn to_file_size(size: u64) -> String {
-    let mut size = size as f32;
-    if size >= 512.0 {
-        size /= 1024.0;
-    } else {
+#[cfg(unix)]
+use libc;
+
+static SI_UNITS: AtomicBool = ATOMIC_BOOL_INIT;
+static PAGER: AtomicBool = ATOMIC_BOOL_INIT;
+
+pub const DEFAULT_NAME_TIME_FORMAT: &'static str = "%Y-%m-%d_%H%M";
+
+/// Selects whether sizes are displayed in units based on 1000 (kB, MB, ...) or on 1024 (KiB, ...)
+pub fn set_si_units(si: bool) {
+    SI_UNITS.store(si, Ordering::SeqCst)
+}
+
+/// Selects whether long outputs on a terminal are shown in a pager
+pub fn set_pager(pager: bool) {
+    PAGER.store(pager, Ordering::SeqCst)
+}
+
+fn format_file_size(size: u64, base: f64, units: &[&str]) -> String {
+    let mut size = size as f64;
+    if size < base / 2.0 {
         return format!("{:.0} Byte", size);
     }
-    if size >= 512.0 {
-        size /= 1024.0;
-    } else {
-        return format!("{:.1} KiB", size);
-    }
-    if size >= 512.0 {
-        size /= 1024.0;
-    } else {
-        return format!("{:.1} MiB", size);
+    let (last, units) = units.split_last().unwrap();
+    for unit in units {
+        size /= base;
+        if size < base / 2.0 {
+            return format!("{:.1} {}", size, unit);
+        }
     }
-    if size >= 512.0 {
-        size /= 1024.0;
+    format!("{:.1} {}", size / base, last)
+}
+
+pub fn to_file_size(size: u64) -> String {
+    if SI_UNITS.load(Ordering::SeqCst) {
+        format_file_size(size, 1000.0, &["kB", "MB", "GB", "TB"])
     } else {
-        return format!("{:.1} GiB", size);
+        format_file_size(size, 1024.0, &["KiB", "MiB", "GiB", "TiB"])
     }
-    format!("{:.1} TiB", size)
 }
 
 pub fn to_speed(size: u64, dur: f32) -> String {
@@ -41,6 +62,244 @@ pub fn to_duration(dur: f32) -> String {
     format!("{}:{:02}:{:04.1}", hours, mins, secs)
 }
 
+/// Splits a value like `2h30m` into the number and unit parts, e.g. `[("2", "h"), ("30", "m")]`
+fn split_units(val: &str) -> Result<Vec<(&str, &str)>, String> {
+    let val = val.trim();
+    if val.is_empty() {
+        return Err("Must not be empty".to_string());
+    }
+    let mut parts = vec![];
+    let mut rest = val;
+    while !rest.is_empty() {
+        let num_len = rest.find(|c: char| !c.is_digit(10) && c != '.').unwrap_or(rest.len());
+        if num_len == 0 {
+            return Err(format!("Expected a number in {:?}", val));
+        }
+        let (num, tail) = rest.split_at(num_len);
+        let tail = tail.trim_left();
+        let unit_len = tail.find(|c: char| c.is_digit(10) || c.is_whitespace())
+            .unwrap_or_else(|| tail.len());
+        let (unit, tail) = tail.split_at(unit_len);
+        parts.push((num, unit));
+        rest = tail.trim_left();
+    }
+    Ok(parts)
+}
+
+/// Parses a size like `32MiB`, `1.5G` or `500MB` into bytes
+///
+/// The units `K`, `M`, `G` and `T` as well as `KiB`, `MiB`, ... are based on 1024, `kB`, `MB`, ...
+/// are based on 1000. A number without a unit is taken in `default_unit` bytes so that plain
+/// numbers keep the meaning given by each option.
+pub fn parse_file_size(val: &str, default_unit: u64) -> Result<u64, String> {
+    let parts = try!(split_units(val));
+    if parts.len() != 1 {
+        return Err(format!("Invalid size {:?}, must be a number with an optional unit", val));
+    }
+    let (num, unit) = parts[0];
+    let factor = match unit {
+        "" => default_unit,
+        "b" | "B" | "Byte" | "Bytes" => 1,
+        "k" | "K" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        "T" | "TiB" => 1 << 40,
+        "kB" | "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        _ => return Err(format!("Unknown size unit {:?}", unit)),
+    };
+    let num: f64 = try!(num.parse().map_err(|_| format!("Invalid number {:?}", num)));
+    Ok((num * factor as f64).round() as u64)
+}
+
+/// Parses a duration like `2h30m`, `14d` or `90s` into seconds
+///
+/// The units `s`, `m`, `h`, `d` and `w` can be combined. A number without a unit is taken in
+/// `default_unit` seconds so that plain numbers keep the meaning given by each option.
+pub fn parse_duration(val: &str, default_unit: u64) -> Result<u64, String> {
+    let parts = try!(split_units(val));
+    if parts.len() > 1 && parts.iter().any(|&(_, unit)| unit.is_empty()) {
+        return Err(format!("Invalid duration {:?}, all parts need a unit", val));
+    }
+    let mut secs = 0.0;
+    for (num, unit) in parts {
+        let factor = match unit {
+            "" => default_unit,
+            "s" | "sec" => 1,
+            "m" | "min" => 60,
+            "h" => 3600,
+            "d" => 86_400,
+            "w" => 7 * 86_400,
+            _ => return Err(format!("Unknown duration unit {:?}", unit)),
+        };
+        let num: f64 = try!(num.parse().map_err(|_| format!("Invalid number {:?}", num)));
+        secs += num * factor as f64;
+    }
+    Ok(secs.round() as u64)
+}
+
+
+fn format_time(time: &DateTime<Local>, format: &str) -> Result<String, String> {
+    if StrftimeItems::new(format).any(|item| match item {
+        Item::Error => true,
+        _ => false,
+    })
+    {
+        return Err(format!("Invalid time format {:?}", format));
+    }
+    Ok(time.format(format).to_string())
+}
+
+/// Expands the placeholders in a name like `servers/{hostname}/{now:%Y-%m-%d}`
+///
+/// `{hostname}` and `{user}` are replaced with the given names, `{now}` with the given time in
+/// the format `DEFAULT_NAME_TIME_FORMAT` and `{now:FORMAT}` with the time in a strftime format.
+/// Literal braces are written as `{{` and `}}`.
+pub fn expand_name_template(
+    template: &str,
+    hostname: &str,
+    user: &str,
+    now: &DateTime<Local>,
+) -> Result<String, String> {
+    let mut name = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(pos) = rest.find(|c: char| c == '{' || c == '}') {
+        name.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            name.push_str(&rest[..1]);
+            rest = &rest[2..];
+            continue;
+        }
+        let end = match rest.find('}') {
+            Some(end) if rest.starts_with('{') => end,
+            _ => return Err(format!("Unbalanced braces in {:?}", template)),
+        };
+        let placeholder = &rest[1..end];
+        let (key, format) = match placeholder.find(':') {
+            Some(pos) => (&placeholder[..pos], Some(&placeholder[pos + 1..])),
+            None => (placeholder, None),
+        };
+        match (key, format) {
+            ("hostname", None) => name.push_str(hostname),
+            ("user", None) => name.push_str(user),
+            ("now", format) => {
+                name.push_str(&try!(format_time(now, format.unwrap_or(DEFAULT_NAME_TIME_FORMAT))))
+            }
+            _ => return Err(format!("Unknown placeholder {{{}}} in {:?}", placeholder, template)),
+        }
+        rest = &rest[end + 1..];
+    }
+    name.push_str(rest);
+    Ok(name)
+}
+
+
+#[cfg(unix)]
+fn without_echo<T, F: FnOnce() -> T>(f: F) -> T {
+    unsafe {
+        let mut termios: libc::termios = ::std::mem::zeroed();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+            // Not a terminal
+            return f();
+        }
+        let mut silent = termios;
+        silent.c_lflag &= !libc::ECHO;
+        silent.c_lflag |= libc::ECHONL;
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &silent);
+        let res = f();
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+        res
+    }
+}
+
+#[cfg(not(unix))]
+fn without_echo<T, F: FnOnce() -> T>(f: F) -> T {
+    f()
+}
+
+#[cfg(unix)]
+fn terminal_height() -> Option<usize> {
+    unsafe {
+        if libc::isatty(libc::STDOUT_FILENO) != 1 {
+            return None;
+        }
+        let mut size: libc::winsize = ::std::mem::zeroed();
+        if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) != 0 || size.ws_row == 0 {
+            return None;
+        }
+        Some(size.ws_row as usize)
+    }
+}
+
+#[cfg(not(unix))]
+fn terminal_height() -> Option<usize> {
+    None
+}
+
+/// Returns the width of the widest value, for aligning columns
+pub fn column_width<I: IntoIterator<Item = S>, S: AsRef<str>>(values: I) -> usize {
+    values.into_iter().map(|v| v.as_ref().chars().count()).max().unwrap_or(0)
+}
+
+fn run_pager(lines: &[String]) -> Result<(), io::Error> {
+    let pager = env::var("PAGER").unwrap_or_else(|_| "less".to_string());
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(&pager).stdin(Stdio::piped());
+    if env::var_os("LESS").is_none() {
+        // Quit if the output fits on the screen after all and keep colors and the output
+        command.env("LESS", "FRX");
+    }
+    let mut child = try!(command.spawn());
+    {
+        let mut stdin = child.stdin.take().unwrap();
+        for line in lines {
+            // Fails when the user quits the pager early
+            if writeln!(stdin, "{}", line).is_err() {
+                break;
+            }
+        }
+    }
+    try!(child.wait());
+    Ok(())
+}
+
+/// Prints the lines, using a pager if stdout is a terminal and they do not fit on it
+///
+/// The pager is taken from `$PAGER` and defaults to `less`. Output that is redirected or piped
+/// is always printed as it is.
+pub fn print_lines(lines: &[String]) {
+    if PAGER.load(Ordering::SeqCst) {
+        if let Some(height) = terminal_height() {
+            if lines.len() >= height {
+                match run_pager(lines) {
+                    Ok(()) => return,
+                    Err(err) => warn!("Failed to run the pager: {}", err),
+                }
+            }
+        }
+    }
+    for line in lines {
+        println!("{}", line);
+    }
+}
+
+/// Asks for a passphrase on the terminal without echoing it
+pub fn read_passphrase(prompt: &str) -> Result<String, io::Error> {
+    let mut stderr = io::stderr();
+    try!(stderr.write_all(prompt.as_bytes()));
+    try!(stderr.flush());
+    let mut line = String::new();
+    let stdin = io::stdin();
+    try!(without_echo(|| stdin.lock().read_line(&mut line)));
+    while line.ends_with('\n') || line.ends_with('\r') {
+        line.pop();
+    }
+    Ok(line)
+}
+
 
 pub struct ProgressIter<T> {
     inner: T,
@@ -86,6 +345,62 @@ impl<T: Iterator> Iterator for ProgressIter<T> {
 }
 
 
+/// Shows the position in a stream while it is being read
+///
+/// If the total size of the stream is not known, only the number of bytes read so far and the
+/// speed are shown.
+pub struct ProgressReader<R> {
+    inner: R,
+    msg: String,
+    bar: pbr::ProgressBar<Stdout>,
+    size_known: bool,
+    pos: u64,
+    done: bool
+}
+
+impl<R> ProgressReader<R> {
+    pub fn new(msg: &str, size: Option<u64>, inner: R) -> Self {
+        let mut bar = pbr::ProgressBar::new(size.unwrap_or(0));
+        let msg = format!("{}: ", msg);
+        bar.message(&msg);
+        bar.set_units(pbr::Units::Bytes);
+        bar.set_max_refresh_rate(Some(Duration::from_millis(100)));
+        if size.is_none() {
+            bar.show_bar = false;
+            bar.show_counter = false;
+            bar.show_percent = false;
+            bar.show_time_left = false;
+        }
+        ProgressReader {
+            inner: inner,
+            bar: bar,
+            msg: msg,
+            size_known: size.is_some(),
+            pos: 0,
+            done: false
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let len = try!(self.inner.read(buf));
+        if len > 0 {
+            self.pos += len as u64;
+            if !self.size_known {
+                self.bar.message(&format!("{}{} ", self.msg, to_file_size(self.pos)));
+            }
+            self.bar.add(len as u64);
+        } else if !buf.is_empty() && !self.done {
+            self.done = true;
+            let msg = self.msg.clone() + "done.";
+            self.bar.finish_print(&msg);
+        }
+        Ok(len)
+    }
+}
+
+
 mod tests {
 
     #[allow(unused_imports)]
@@ -108,6 +423,33 @@ mod tests {
         assert_eq!("3.9 TiB", to_file_size(4312534553170));
     }
 
+    #[test]
+    fn test_format_file_size_si() {
+        let units = ["kB", "MB", "GB", "TB"];
+        assert_eq!("0 Byte", format_file_size(0, 1000.0, &units));
+        assert_eq!("456 Byte", format_file_size(456, 1000.0, &units));
+        assert_eq!("0.7 kB", format_file_size(670, 1000.0, &units));
+        assert_eq!("242.7 kB", format_file_size(242670, 1000.0, &units));
+        assert_eq!("12.8 MB", format_file_size(12753170, 1000.0, &units));
+        assert_eq!("5.4 GB", format_file_size(5435353170, 1000.0, &units));
+        assert_eq!("4.3 TB", format_file_size(4312534553170, 1000.0, &units));
+    }
+
+    #[test]
+    fn test_file_size_roundtrip() {
+        for &size in &[15, 753170, 5435353170] {
+            let parsed = parse_file_size(&to_file_size(size), 1).unwrap() as f64;
+            assert!((parsed - size as f64).abs() / (size as f64) < 0.05);
+        }
+    }
+
+    #[test]
+    fn test_column_width() {
+        assert_eq!(0, column_width(Vec::<String>::new()));
+        assert_eq!(5, column_width(vec!["abc", "de", "fghij"]));
+        assert_eq!(3, column_width(vec!["äöü".to_string()]));
+    }
+
     #[test]
     fn test_to_speed() {
         assert_eq!("0 Byte/s", to_speed(0, 1.0));
@@ -131,5 +473,57 @@ mod tests {
         assert_eq!("2:02:02.2", to_duration(7322.2));
     }
 
+    #[test]
+    fn test_parse_file_size() {
+        assert_eq!(Ok(25 * 1024 * 1024), parse_file_size("25", 1024 * 1024));
+        assert_eq!(Ok(100), parse_file_size("100B", 1024));
+        assert_eq!(Ok(456), parse_file_size("456 Byte", 1024));
+        assert_eq!(Ok(32 * 1024 * 1024), parse_file_size("32MiB", 1));
+        assert_eq!(Ok(32 * 1024 * 1024), parse_file_size("32M", 1));
+        assert_eq!(Ok(32 * 1024 * 1024), parse_file_size("32 MiB", 1));
+        assert_eq!(Ok(32_000_000), parse_file_size("32MB", 1));
+        assert_eq!(Ok(1536 * 1024 * 1024), parse_file_size("1.5G", 1));
+        assert_eq!(Ok(2 << 40), parse_file_size("2TiB", 1));
+        assert!(parse_file_size("", 1).is_err());
+        assert!(parse_file_size("MiB", 1).is_err());
+        assert!(parse_file_size("10XB", 1).is_err());
+        assert!(parse_file_size("1M2K", 1).is_err());
+        assert!(parse_file_size("1.2.3M", 1).is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(Ok(24 * 3600), parse_duration("24", 3600));
+        assert_eq!(Ok(90), parse_duration("90s", 3600));
+        assert_eq!(Ok(2 * 3600 + 30 * 60), parse_duration("2h30m", 1));
+        assert_eq!(Ok(2 * 3600 + 30 * 60), parse_duration("2h 30m", 1));
+        assert_eq!(Ok(14 * 86_400), parse_duration("14d", 1));
+        assert_eq!(Ok(2 * 7 * 86_400), parse_duration("2w", 1));
+        assert_eq!(Ok(90 * 60), parse_duration("1.5h", 1));
+        assert!(parse_duration("", 1).is_err());
+        assert!(parse_duration("h", 1).is_err());
+        assert!(parse_duration("5y", 1).is_err());
+        assert!(parse_duration("2h30", 1).is_err());
+    }
+
+    #[test]
+    fn test_expand_name_template() {
+        let now = Local.ymd(2017, 3, 4).and_hms(5, 6, 7);
+        let expand = |template| expand_name_template(template, "server1", "root", &now);
+        assert_eq!(Ok("daily".to_string()), expand("daily"));
+        assert_eq!(
+            Ok("servers/server1/2017-03-04_0506".to_string()),
+            expand("servers/{hostname}/{now}")
+        );
+        assert_eq!(Ok("root@server1-2017-03".to_string()), expand("{user}@{hostname}-{now:%Y-%m}"));
+        assert_eq!(Ok("05:06:07".to_string()), expand("{now:%H:%M:%S}"));
+        assert_eq!(Ok("{hostname}".to_string()), expand("{{hostname}}"));
+        assert!(expand("{hostname").is_err());
+        assert!(expand("hostname}").is_err());
+        assert!(expand("{today}").is_err());
+        assert!(expand("{hostname:%Y}").is_err());
+        assert!(expand("{now:%Q}").is_err());
+    }
+
 
 }
@@ -1,10 +1,11 @@
 use std::ptr;
 use std::ffi::{CStr, CString};
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
 use std::str::FromStr;
 
 use libc;
 use squash::*;
+use zstd;
 
 
 quick_error!{
@@ -40,13 +41,15 @@ pub enum CompressionMethod {
     Deflate, // Standardized
     Brotli, // Good speed and ratio
     Lzma, // Very good ratio, slow
-    Lz4 // Very fast, low ratio
+    Lz4, // Very fast, low ratio
+    Zstd // Good speed and ratio, supports dictionaries
 }
 serde_impl!(CompressionMethod(u8) {
     Deflate => 0,
     Brotli => 1,
     Lzma => 2,
-    Lz4 => 3
+    Lz4 => 3,
+    Zstd => 4
 });
 
 
@@ -90,6 +93,7 @@ impl Compression {
             "brotli" => CompressionMethod::Brotli,
             "lzma" | "lzma2" | "xz" => CompressionMethod::Lzma,
             "lz4" => CompressionMethod::Lz4,
+            "zstd" => CompressionMethod::Zstd,
             _ => return Err(CompressionError::UnsupportedCodec(name.to_string())),
         };
         Ok(Compression {
@@ -104,9 +108,15 @@ impl Compression {
             CompressionMethod::Brotli => "brotli",
             CompressionMethod::Lzma => "lzma",
             CompressionMethod::Lz4 => "lz4",
+            CompressionMethod::Zstd => "zstd",
         }
     }
 
+    #[inline]
+    pub fn supports_dictionary(&self) -> bool {
+        self.method == CompressionMethod::Zstd
+    }
+
     fn codec(&self) -> Result<*mut SquashCodec, CompressionError> {
         let name = CString::new(self.name().as_bytes()).unwrap();
         let codec = unsafe { squash_get_codec(name.as_ptr()) };
@@ -222,6 +232,46 @@ impl Compression {
         }
         Ok(CompressionStream::new(stream))
     }
+
+    pub fn compress_stream_with_dictionary(
+        &self,
+        dictionary: &[u8],
+    ) -> Result<DictionaryCompressionStream, CompressionError> {
+        if !self.supports_dictionary() {
+            return Err(CompressionError::UnsupportedCodec(self.to_string()));
+        }
+        let encoder = try!(
+            zstd::stream::Encoder::with_dictionary(Vec::new(), self.level as i32, dictionary)
+                .map_err(|_| CompressionError::InitializeStream)
+        );
+        Ok(DictionaryCompressionStream { encoder: encoder })
+    }
+
+    pub fn decompress_with_dictionary(
+        &self,
+        data: &[u8],
+        dictionary: &[u8],
+    ) -> Result<Vec<u8>, CompressionError> {
+        if !self.supports_dictionary() {
+            return Err(CompressionError::UnsupportedCodec(self.to_string()));
+        }
+        let mut decoder = try!(
+            zstd::stream::Decoder::with_dictionary(data, dictionary)
+                .map_err(|_| CompressionError::InitializeStream)
+        );
+        let mut buf = Vec::with_capacity(data.len() * 2);
+        try!(decoder.read_to_end(&mut buf).map_err(|_| {
+            CompressionError::Operation("Failed to decompress data")
+        }));
+        Ok(buf)
+    }
+}
+
+
+/// Trains a compression dictionary of at most `size` bytes from the given samples
+pub fn train_dictionary(samples: &[Vec<u8>], size: usize) -> Result<Vec<u8>, CompressionError> {
+    zstd::dict::from_samples(samples, size)
+        .map_err(|_| CompressionError::Operation("Failed to train dictionary"))
 }
 
 
@@ -282,6 +332,36 @@ impl CompressionStream {
     }
 }
 
+
+/// Compression stream using a dictionary, only supported by zstd
+pub struct DictionaryCompressionStream {
+    encoder: zstd::stream::Encoder<Vec<u8>>
+}
+
+impl DictionaryCompressionStream {
+    pub fn process<W: Write>(
+        &mut self,
+        input: &[u8],
+        output: &mut W,
+    ) -> Result<(), CompressionError> {
+        try!(self.encoder.write_all(input).map_err(|_| {
+            CompressionError::Operation("Failed to compress data")
+        }));
+        let buffer = self.encoder.get_mut();
+        try!(output.write_all(buffer));
+        buffer.clear();
+        Ok(())
+    }
+
+    pub fn finish<W: Write>(self, output: &mut W) -> Result<(), CompressionError> {
+        let buffer = try!(self.encoder.finish().map_err(|_| {
+            CompressionError::Operation("Failed to compress data")
+        }));
+        try!(output.write_all(&buffer));
+        Ok(())
+    }
+}
+
 impl Drop for CompressionStream {
     fn drop(&mut self) {
         unsafe {
@@ -314,6 +394,8 @@ mod tests {
         assert_eq!(("lzma", 3), (method.name(), method.level()));
         let method = Compression::from_string("lz4/1").unwrap();
         assert_eq!(("lz4", 1), (method.name(), method.level()));
+        let method = Compression::from_string("zstd/3").unwrap();
+        assert_eq!(("zstd", 3), (method.name(), method.level()));
     }
 
     #[test]
@@ -415,6 +497,29 @@ mod tests {
         test_stream_compression("lz4", 1, 11)
     }
 
+    #[test]
+    fn test_stream_compression_zstd() {
+        test_stream_compression("zstd", 1, 19)
+    }
+
+    #[test]
+    fn test_dictionary_compression() {
+        let samples: Vec<Vec<u8>> = (0..1000)
+            .map(|i| format!("key{}: value {} of a small config file\n", i % 10, i).into_bytes())
+            .collect();
+        let dictionary = train_dictionary(&samples, 4096).unwrap();
+        let method = Compression::from_string("zstd/3").unwrap();
+        let input = test_data(64 * 1024);
+        let mut compressor = method.compress_stream_with_dictionary(&dictionary).unwrap();
+        let mut compressed = Vec::with_capacity(input.len());
+        compressor.process(&input, &mut compressed).unwrap();
+        compressor.finish(&mut compressed).unwrap();
+        let decompressed = method
+            .decompress_with_dictionary(&compressed, &dictionary)
+            .unwrap();
+        assert_eq!(input, decompressed);
+    }
+
 }
 
 
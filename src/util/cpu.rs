@@ -0,0 +1,224 @@
+use libc;
+
+use super::sodium_init;
+
+use std::io;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+
+mod ffi {
+    use libc;
+
+    extern "C" {
+        pub fn sodium_runtime_has_sse2() -> libc::c_int;
+        pub fn sodium_runtime_has_ssse3() -> libc::c_int;
+        pub fn sodium_runtime_has_sse41() -> libc::c_int;
+        pub fn sodium_runtime_has_avx() -> libc::c_int;
+        pub fn sodium_runtime_has_avx2() -> libc::c_int;
+        pub fn sodium_runtime_has_aesni() -> libc::c_int;
+    }
+}
+
+lazy_static! {
+    static ref CPU_FEATURES: CpuFeatures = CpuFeatures::detect();
+}
+
+
+/// CPU features that allow faster implementations of the hash and encryption methods
+///
+/// The features are detected by libsodium at runtime, so the same binary runs on all CPUs and
+/// still uses the SIMD implementations where they are available.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct CpuFeatures {
+    pub sse2: bool,
+    pub ssse3: bool,
+    pub sse41: bool,
+    pub avx: bool,
+    pub avx2: bool,
+    pub aesni: bool
+}
+
+impl CpuFeatures {
+    fn detect() -> Self {
+        sodium_init();
+        let has = |res: libc::c_int| res != 0;
+        unsafe {
+            CpuFeatures {
+                sse2: has(ffi::sodium_runtime_has_sse2()),
+                ssse3: has(ffi::sodium_runtime_has_ssse3()),
+                sse41: has(ffi::sodium_runtime_has_sse41()),
+                avx: has(ffi::sodium_runtime_has_avx()),
+                avx2: has(ffi::sodium_runtime_has_avx2()),
+                aesni: has(ffi::sodium_runtime_has_aesni())
+            }
+        }
+    }
+
+    /// Returns the features of this CPU, they are only detected once
+    #[inline]
+    pub fn get() -> Self {
+        *CPU_FEATURES
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names = vec![];
+        for &(present, name) in &[
+            (self.sse2, "sse2"),
+            (self.ssse3, "ssse3"),
+            (self.sse41, "sse4.1"),
+            (self.avx, "avx"),
+            (self.avx2, "avx2"),
+            (self.aesni, "aes-ni"),
+        ]
+        {
+            if present {
+                names.push(name);
+            }
+        }
+        names
+    }
+}
+
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use libc;
+
+    use std::io;
+    use std::mem;
+
+    /// Restricts the current thread to the given CPUs, threads started by it inherit the setting
+    pub fn set_thread_affinity(cpus: &[usize]) -> Result<(), io::Error> {
+        let max_cpus = mem::size_of::<libc::cpu_set_t>() * 8;
+        if cpus.iter().any(|&cpu| cpu >= max_cpus) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "CPU number is too large"));
+        }
+        unsafe {
+            let mut set: libc::cpu_set_t = mem::zeroed();
+            libc::CPU_ZERO(&mut set);
+            for &cpu in cpus {
+                libc::CPU_SET(cpu, &mut set);
+            }
+            if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use self::linux::*;
+
+
+/// Restricts the current thread to the given CPUs, threads started by it inherit the setting
+#[cfg(not(target_os = "linux"))]
+pub fn set_thread_affinity(_cpus: &[usize]) -> Result<(), io::Error> {
+    Err(io::Error::new(io::ErrorKind::Other, "CPU affinity is not supported on this platform"))
+}
+
+
+static WORKER_THREADS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+lazy_static! {
+    static ref WORKER_CPUS: RwLock<Vec<usize>> = RwLock::new(vec![]);
+}
+
+/// Sets the number of threads of each worker pool, 0 keeps the defaults of the pools
+pub fn set_worker_threads(threads: usize) {
+    WORKER_THREADS.store(threads, Ordering::SeqCst);
+}
+
+/// Returns the configured number of threads for a worker pool or its `default`
+pub fn worker_threads(default: usize) -> usize {
+    match WORKER_THREADS.load(Ordering::SeqCst) {
+        0 => default,
+        threads => threads,
+    }
+}
+
+/// Restricts the process to the given CPUs and pins the threads of worker pools to them
+///
+/// This has to be called before any threads are started as only threads that are started
+/// afterwards inherit the restriction. An empty list removes the pinning of new workers.
+pub fn set_worker_cpus(cpus: Vec<usize>) -> Result<(), io::Error> {
+    if !cpus.is_empty() {
+        try!(set_thread_affinity(&cpus));
+    }
+    *WORKER_CPUS.write().unwrap() = cpus;
+    Ok(())
+}
+
+#[inline]
+pub fn worker_cpus() -> Vec<usize> {
+    WORKER_CPUS.read().unwrap().clone()
+}
+
+/// Pins the current thread, the worker `num` of a pool, to one of the configured CPUs
+///
+/// The workers of a pool are distributed over the CPUs in turn. Nothing happens if no CPUs
+/// have been configured.
+pub fn pin_worker(num: usize) {
+    let cpus = WORKER_CPUS.read().unwrap();
+    if cpus.is_empty() {
+        return;
+    }
+    let cpu = cpus[num % cpus.len()];
+    if let Err(err) = set_thread_affinity(&[cpu]) {
+        warn!("Failed to pin worker thread to CPU {}: {}", cpu, err);
+    }
+}
+
+/// Parses a list of CPUs like `0-3,6` as used by `taskset`
+pub fn parse_cpu_list(val: &str) -> Result<Vec<usize>, &'static str> {
+    let mut cpus = vec![];
+    for part in val.split(',') {
+        let part = part.trim();
+        let (start, end) = if let Some(pos) = part.find('-') {
+            (&part[..pos], &part[pos + 1..])
+        } else {
+            (part, part)
+        };
+        let start = try!(start.parse::<usize>().map_err(|_| "Invalid CPU number"));
+        let end = try!(end.parse::<usize>().map_err(|_| "Invalid CPU number"));
+        if start > end {
+            return Err("Invalid CPU range");
+        }
+        for cpu in start..end + 1 {
+            if !cpus.contains(&cpu) {
+                cpus.push(cpu);
+            }
+        }
+    }
+    Ok(cpus)
+}
+
+/// Formats a list of CPUs so that `parse_cpu_list` can read it
+pub fn format_cpu_list(cpus: &[usize]) -> String {
+    cpus.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(",")
+}
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0"), Ok(vec![0]));
+        assert_eq!(parse_cpu_list("0-3,6"), Ok(vec![0, 1, 2, 3, 6]));
+        assert_eq!(parse_cpu_list("2, 1-2"), Ok(vec![2, 1]));
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+    }
+
+    #[test]
+    fn test_format_cpu_list() {
+        assert_eq!(format_cpu_list(&[0, 1, 6]), "0,1,6");
+        assert_eq!(parse_cpu_list(&format_cpu_list(&[4, 2])), Ok(vec![4, 2]));
+    }
+
+}
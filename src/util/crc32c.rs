@@ -0,0 +1,58 @@
+/// Reversed polynomial of CRC-32C (Castagnoli)
+const POLYNOMIAL: u32 = 0x82F6_3B78;
+
+
+fn table_entry(index: u32) -> u32 {
+    let mut crc = index;
+    for _ in 0..8 {
+        crc = if crc & 1 == 1 {
+            (crc >> 1) ^ POLYNOMIAL
+        } else {
+            crc >> 1
+        };
+    }
+    crc
+}
+
+/// Continues the CRC-32C `crc` with `data`
+///
+/// The value is neither inverted before nor after the calculation, so the standard checksum is
+/// `!crc32c(!0, data)`. The btrfs send stream uses the raw value with a seed of 0.
+pub fn crc32c(mut crc: u32, data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        *entry = table_entry(i as u32);
+    }
+    for &byte in data {
+        crc = table[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+
+    #[test]
+    fn test_standard() {
+        assert_eq!(!crc32c(!0, b"123456789"), 0xE306_9283);
+        assert_eq!(!crc32c(!0, b""), 0);
+    }
+
+    #[test]
+    fn test_raw() {
+        assert_eq!(crc32c(0, b"123456789"), 0x58E3_FA20);
+    }
+
+    #[test]
+    fn test_streaming() {
+        let data = b"The quick brown fox jumps over the lazy dog";
+        let (first, second) = data.split_at(17);
+        assert_eq!(crc32c(crc32c(0, first), second), crc32c(0, data));
+    }
+
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+
+
+/// Number of super features, similar chunks share at least one of them
+pub const SUPER_FEATURE_COUNT: usize = 3;
+const FEATURES_PER_SUPER_FEATURE: usize = 2;
+const FEATURE_COUNT: usize = SUPER_FEATURE_COUNT * FEATURES_PER_SUPER_FEATURE;
+
+const FEATURE_MULTIPLIERS: [u64; FEATURE_COUNT] = [
+    0x9e37_79b9_7f4a_7c15,
+    0xc2b2_ae3d_27d4_eb4f,
+    0x1656_67b1_9e37_79f9,
+    0x27d4_eb2f_1656_67c5,
+    0x94d0_49bb_1331_11eb,
+    0xbf58_476d_1ce4_e5b9
+];
+const FEATURE_OFFSETS: [u64; FEATURE_COUNT] = [
+    0x2545_f491_4f6c_dd1d,
+    0x8538_ecb5_bd45_6ea3,
+    0x6a09_e667_f3bc_c909,
+    0xbb67_ae85_84ca_a73b,
+    0x3c6e_f372_fe94_f82b,
+    0xa54f_f53a_5f1d_36f1
+];
+
+const BLOCK_SIZE: usize = 16;
+const OP_INSERT: u8 = 0;
+const OP_COPY: u8 = 1;
+
+
+#[inline]
+fn gear(byte: u8) -> u64 {
+    (byte as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15).rotate_left(31)
+}
+
+/// Calculates the super features of the data
+///
+/// Each feature is the maximum of a transformed rolling hash over all positions, so small changes
+/// to the data only change a few features. Chunks that share a super feature are very likely to
+/// be similar.
+pub fn similarity_features(data: &[u8]) -> [u64; SUPER_FEATURE_COUNT] {
+    let mut features = [0u64; FEATURE_COUNT];
+    let mut hash = 0u64;
+    for &byte in data {
+        hash = (hash << 1).wrapping_add(gear(byte));
+        for i in 0..FEATURE_COUNT {
+            let value = hash.wrapping_mul(FEATURE_MULTIPLIERS[i]).wrapping_add(FEATURE_OFFSETS[i]);
+            if value > features[i] {
+                features[i] = value;
+            }
+        }
+    }
+    let mut super_features = [0u64; SUPER_FEATURE_COUNT];
+    for (i, super_feature) in super_features.iter_mut().enumerate() {
+        let mut value = i as u64;
+        let start = i * FEATURES_PER_SUPER_FEATURE;
+        for feature in &features[start..start + FEATURES_PER_SUPER_FEATURE] {
+            value = (value ^ feature).wrapping_mul(0x0100_0000_01b3).rotate_left(17);
+        }
+        *super_feature = value;
+    }
+    super_features
+}
+
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<usize, ()> {
+    let mut value = 0usize;
+    let mut shift = 0;
+    loop {
+        if *pos >= data.len() || shift > 63 {
+            return Err(());
+        }
+        let byte = data[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+#[inline]
+fn block_key(block: &[u8]) -> (u64, u64) {
+    let mut high = 0u64;
+    let mut low = 0u64;
+    for i in 0..8 {
+        high = (high << 8) | block[i] as u64;
+        low = (low << 8) | block[i + 8] as u64;
+    }
+    (high, low)
+}
+
+fn write_insert(out: &mut Vec<u8>, data: &[u8]) {
+    out.push(OP_INSERT);
+    write_varint(out, data.len());
+    out.extend_from_slice(data);
+}
+
+/// Encodes `target` as a list of copy and insert operations relative to `base`
+pub fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut index = HashMap::new();
+    let mut pos = 0;
+    while pos + BLOCK_SIZE <= base.len() {
+        index.entry(block_key(&base[pos..pos + BLOCK_SIZE])).or_insert(pos);
+        pos += BLOCK_SIZE;
+    }
+    let mut out = Vec::new();
+    let mut literal_start = 0;
+    pos = 0;
+    while pos + BLOCK_SIZE <= target.len() {
+        let base_pos = match index.get(&block_key(&target[pos..pos + BLOCK_SIZE])) {
+            Some(&base_pos) => base_pos,
+            None => {
+                pos += 1;
+                continue;
+            }
+        };
+        let (mut start, mut base_start) = (pos, base_pos);
+        while start > literal_start && base_start > 0 && target[start - 1] == base[base_start - 1] {
+            start -= 1;
+            base_start -= 1;
+        }
+        let (mut end, mut base_end) = (pos + BLOCK_SIZE, base_pos + BLOCK_SIZE);
+        while end < target.len() && base_end < base.len() && target[end] == base[base_end] {
+            end += 1;
+            base_end += 1;
+        }
+        if start > literal_start {
+            write_insert(&mut out, &target[literal_start..start]);
+        }
+        out.push(OP_COPY);
+        write_varint(&mut out, base_start);
+        write_varint(&mut out, end - start);
+        pos = end;
+        literal_start = end;
+    }
+    if literal_start < target.len() {
+        write_insert(&mut out, &target[literal_start..]);
+    }
+    out
+}
+
+/// Reconstructs the data from `base` and a delta created by `encode_delta`
+pub fn decode_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, ()> {
+    let mut out = Vec::with_capacity(base.len());
+    let mut pos = 0;
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        match op {
+            OP_INSERT => {
+                let len = try!(read_varint(delta, &mut pos));
+                if len > delta.len() - pos {
+                    return Err(());
+                }
+                out.extend_from_slice(&delta[pos..pos + len]);
+                pos += len;
+            }
+            OP_COPY => {
+                let offset = try!(read_varint(delta, &mut pos));
+                let len = try!(read_varint(delta, &mut pos));
+                if offset > base.len() || len > base.len() - offset {
+                    return Err(());
+                }
+                out.extend_from_slice(&base[offset..offset + len]);
+            }
+            _ => return Err(()),
+        }
+    }
+    Ok(out)
+}
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(dead_code)]
+    fn test_data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+                (state >> 33) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_roundtrip_similar() {
+        let base = test_data(16 * 1024, 1);
+        let mut target = base.clone();
+        target[100] ^= 0xff;
+        let tail = target.split_off(5000);
+        target.extend_from_slice(&[1, 2, 3, 4, 5]);
+        target.extend_from_slice(&tail);
+        target.truncate(15000);
+        let delta = encode_delta(&base, &target);
+        assert!(delta.len() < 200);
+        assert_eq!(decode_delta(&base, &delta), Ok(target));
+    }
+
+    #[test]
+    fn test_roundtrip_different() {
+        let base = test_data(4096, 1);
+        let target = test_data(4096, 2);
+        let delta = encode_delta(&base, &target);
+        assert_eq!(decode_delta(&base, &delta), Ok(target));
+    }
+
+    #[test]
+    fn test_roundtrip_empty() {
+        assert_eq!(decode_delta(&[], &encode_delta(&[], &[])), Ok(vec![]));
+        let data = test_data(100, 3);
+        assert_eq!(decode_delta(&[], &encode_delta(&[], &data)), Ok(data.clone()));
+        assert_eq!(decode_delta(&data, &encode_delta(&data, &[])), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let base = test_data(100, 1);
+        assert_eq!(decode_delta(&base, &[OP_COPY, 90, 20]), Err(()));
+        assert_eq!(decode_delta(&base, &[OP_INSERT, 5, 1]), Err(()));
+        assert_eq!(decode_delta(&base, &[7]), Err(()));
+    }
+
+    #[test]
+    fn test_features_similar() {
+        let base = test_data(16 * 1024, 1);
+        let mut similar = base.clone();
+        similar[8000] ^= 0xff;
+        let different = test_data(16 * 1024, 2);
+        let base_features = similarity_features(&base);
+        let similar_features = similarity_features(&similar);
+        let different_features = similarity_features(&different);
+        assert!(base_features.iter().any(|f| similar_features.contains(f)));
+        assert!(!base_features.iter().any(|f| different_features.contains(f)));
+    }
+
+}
@@ -0,0 +1,51 @@
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+
+
+// Whether desktop notifications have been enabled via --notify
+static NOTIFICATIONS: AtomicBool = ATOMIC_BOOL_INIT;
+
+
+pub fn enable_desktop_notifications() {
+    NOTIFICATIONS.store(true, Ordering::SeqCst);
+}
+
+#[inline]
+pub fn desktop_notifications_enabled() -> bool {
+    NOTIFICATIONS.load(Ordering::SeqCst)
+}
+
+
+/// Shows a notification on the desktop of the current user if notifications are enabled
+///
+/// The notification is sent over D-Bus by `notify-send`, so it only reaches the desktop if the
+/// session bus is available, i.e. `DBUS_SESSION_BUS_ADDRESS` is set. Failures are only logged.
+pub fn desktop_notify(summary: &str, body: &str, critical: bool) {
+    if !desktop_notifications_enabled() {
+        return;
+    }
+    let result = Command::new("notify-send")
+        .arg("--app-name=zvault")
+        .arg(if critical {
+            "--urgency=critical"
+        } else {
+            "--urgency=normal"
+        })
+        .arg(if critical {
+            "--icon=dialog-error"
+        } else {
+            "--icon=drive-harddisk"
+        })
+        .arg(summary)
+        .arg(body)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    match result {
+        Ok(status) if status.success() => (),
+        Ok(status) => {
+            debug!("Failed to show desktop notification: notify-send exited with {}", status)
+        }
+        Err(err) => debug!("Failed to show desktop notification: {}", err),
+    }
+}
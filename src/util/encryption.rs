@@ -1,8 +1,10 @@
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::io;
+use std::env;
 use std::fs::{self, File};
-use std::sync::{Once, ONCE_INIT};
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, Once, ONCE_INIT};
 
 use serde_yaml;
 use serde_bytes::ByteBuf;
@@ -12,6 +14,7 @@ use sodiumoxide;
 use sodiumoxide::crypto::sealedbox;
 use sodiumoxide::crypto::box_;
 use sodiumoxide::crypto::pwhash;
+use sodiumoxide::crypto::secretbox;
 pub use sodiumoxide::crypto::box_::{SecretKey, PublicKey};
 
 use util::*;
@@ -19,7 +22,15 @@ use util::*;
 
 static INIT: Once = ONCE_INIT;
 
-fn sodium_init() {
+/// Environment variable that can hold the passphrase protecting the stored secret keys
+pub static PASSPHRASE_ENV: &'static str = "ZVAULT_PASSPHRASE";
+
+lazy_static! {
+    // Command that prints the passphrase, given via --passcommand
+    static ref PASSPHRASE_COMMAND: Mutex<Option<String>> = Mutex::new(None);
+}
+
+pub fn sodium_init() {
     INIT.call_once(|| if !sodiumoxide::init() {
         panic!("Failed to initialize sodiumoxide");
     });
@@ -32,8 +43,8 @@ quick_error!{
             description("Invalid key")
         }
         MissingKey(key: PublicKey) {
-            description("Missing key")
-            display("Missing key: {}", to_hex(&key[..]))
+            description("Secret key required")
+            display("Secret key required: only the public key {} is available", to_hex(&key[..]))
         }
         Operation(reason: &'static str) {
             description("Operation failed")
@@ -51,6 +62,14 @@ quick_error!{
             description("Yaml format error")
             display("Yaml format error: {}", err)
         }
+        WrongPassphrase {
+            description("Wrong passphrase")
+            display("Wrong passphrase for the stored secret keys")
+        }
+        PassphraseCommand(command: String, reason: String) {
+            description("Passphrase command failed")
+            display("Failed to get the passphrase from command {:?}: {}", command, reason)
+        }
     }
 }
 
@@ -77,6 +96,23 @@ impl EncryptionMethod {
             EncryptionMethod::Sodium => "sodium".to_string(),
         }
     }
+
+    /// Describes the implementation that is used on this CPU
+    pub fn implementation(&self) -> &'static str {
+        match *self {
+            // Libsodium selects the fastest salsa20 implementation itself
+            EncryptionMethod::Sodium => {
+                let cpu = CpuFeatures::get();
+                if cpu.avx2 {
+                    "libsodium (avx2)"
+                } else if cpu.sse2 {
+                    "libsodium (sse2)"
+                } else {
+                    "libsodium (portable)"
+                }
+            }
+        }
+    }
 }
 
 
@@ -85,22 +121,34 @@ pub type Encryption = (EncryptionMethod, ByteBuf);
 
 pub struct KeyfileYaml {
     public: String,
-    secret: String
+    secret: String,
+    // Only set if the secret key is encrypted with a passphrase
+    salt: String,
+    nonce: String
 }
 impl Default for KeyfileYaml {
     fn default() -> Self {
         KeyfileYaml {
             public: "".to_string(),
-            secret: "".to_string()
+            secret: "".to_string(),
+            salt: "".to_string(),
+            nonce: "".to_string()
         }
     }
 }
-serde_impl!(KeyfileYaml(String) {
+serde_impl!(KeyfileYaml(String?) {
     public: String => "public",
-    secret: String => "secret"
+    secret: String => "secret",
+    salt: String => "salt",
+    nonce: String => "nonce"
 });
 
 impl KeyfileYaml {
+    #[inline]
+    pub fn is_protected(&self) -> bool {
+        !self.salt.is_empty()
+    }
+
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, EncryptionError> {
         let f = try!(File::open(path));
         Ok(try!(serde_yaml::from_reader(f)))
@@ -113,9 +161,65 @@ impl KeyfileYaml {
 }
 
 
+/// Sets a shell command whose first line of output is used as the passphrase
+pub fn set_passphrase_command(command: Option<String>) {
+    *PASSPHRASE_COMMAND.lock().unwrap() = command;
+}
+
+fn run_passphrase_command(command: &str) -> Result<String, EncryptionError> {
+    let error = |reason: String| EncryptionError::PassphraseCommand(command.to_string(), reason);
+    let output = try!(
+        Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .output()
+            .map_err(|err| error(err.to_string()))
+    );
+    if !output.status.success() {
+        return Err(error(output.status.to_string()));
+    }
+    let output = try!(String::from_utf8(output.stdout).map_err(|err| error(err.to_string())));
+    Ok(output.lines().next().unwrap_or("").to_string())
+}
+
+/// Returns the passphrase protecting the stored secret keys
+///
+/// The passphrase is taken from the environment variable `ZVAULT_PASSPHRASE` if it is set, then
+/// from a running passphrase agent, then from the passphrase command if one is set. Otherwise it
+/// is asked for on the terminal.
+pub fn get_passphrase() -> Result<String, EncryptionError> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    if let Some(passphrase) = agent_get_passphrase() {
+        return Ok(passphrase);
+    }
+    if let Some(ref command) = *PASSPHRASE_COMMAND.lock().unwrap() {
+        return run_passphrase_command(command);
+    }
+    Ok(try!(read_passphrase("Passphrase: ")))
+}
+
+fn passphrase_key(passphrase: &str, salt: &pwhash::Salt) -> secretbox::Key {
+    let mut key = [0u8; secretbox::KEYBYTES];
+    pwhash::derive_key(
+        &mut key,
+        passphrase.as_bytes(),
+        salt,
+        pwhash::OPSLIMIT_INTERACTIVE,
+        pwhash::MEMLIMIT_INTERACTIVE
+    ).unwrap();
+    secretbox::Key(key)
+}
+
+
 pub struct Crypto {
     path: Option<PathBuf>,
-    keys: HashMap<PublicKey, SecretKey>
+    keys: HashMap<PublicKey, SecretKey>,
+    // Passphrase of the stored keys, new keys are protected with it as well
+    passphrase: Option<String>
 }
 
 impl Crypto {
@@ -124,7 +228,8 @@ impl Crypto {
         sodium_init();
         Crypto {
             path: None,
-            keys: HashMap::new()
+            keys: HashMap::new(),
+            passphrase: None
         }
     }
 
@@ -132,29 +237,108 @@ impl Crypto {
         sodium_init();
         let path = path.as_ref().to_owned();
         let mut keys: HashMap<PublicKey, SecretKey> = HashMap::default();
+        let mut passphrase = None;
         for entry in try!(fs::read_dir(&path)) {
             let entry = try!(entry);
             let keyfile = try!(KeyfileYaml::load(entry.path()));
-            let public = try!(parse_hex(&keyfile.public).map_err(
-                |_| EncryptionError::InvalidKey
-            ));
-            let public = try!(PublicKey::from_slice(&public).ok_or(
-                EncryptionError::InvalidKey
-            ));
-            let secret = try!(parse_hex(&keyfile.secret).map_err(
-                |_| EncryptionError::InvalidKey
-            ));
-            let secret = try!(SecretKey::from_slice(&secret).ok_or(
-                EncryptionError::InvalidKey
-            ));
+            let (public, secret) = try!(Self::decode_keyfile(&keyfile, &mut passphrase));
             keys.insert(public, secret);
         }
+        if let Some(ref passphrase) = passphrase {
+            // The passphrase is correct, so other commands can get it from the agent
+            agent_set_passphrase(passphrase);
+        }
         Ok(Crypto {
             path: Some(path),
-            keys: keys
+            keys: keys,
+            passphrase: passphrase
         })
     }
 
+    /// Protects all stored secret keys in the folder with a new passphrase
+    ///
+    /// Without a new passphrase, the keys are stored unprotected. Only the key files are
+    /// rewritten, all data stays encrypted with the same keys. Returns the number of keys.
+    pub fn change_passphrase<P: AsRef<Path>>(
+        path: P,
+        new_passphrase: Option<&str>,
+    ) -> Result<usize, EncryptionError> {
+        sodium_init();
+        let mut passphrase = None;
+        let mut keys = vec![];
+        // All keys are decoded first so that a wrong passphrase does not change anything
+        for entry in try!(fs::read_dir(path)) {
+            let path = try!(entry).path();
+            let keyfile = try!(KeyfileYaml::load(&path));
+            keys.push((path, try!(Self::decode_keyfile(&keyfile, &mut passphrase))));
+        }
+        for &(ref path, (ref public, ref secret)) in &keys {
+            let temp_path = path.with_extension("yaml.tmp");
+            try!(Self::encode_keyfile(public, secret, new_passphrase).save(&temp_path));
+            try!(fs::rename(&temp_path, path));
+        }
+        if let Some(passphrase) = new_passphrase {
+            agent_set_passphrase(passphrase);
+        }
+        Ok(keys.len())
+    }
+
+    /// Decodes a key pair, asking for the passphrase if the key is protected and the passphrase
+    /// is not known yet
+    fn decode_keyfile(
+        keyfile: &KeyfileYaml,
+        passphrase: &mut Option<String>,
+    ) -> Result<(PublicKey, SecretKey), EncryptionError> {
+        let public = try!(parse_hex(&keyfile.public).map_err(
+            |_| EncryptionError::InvalidKey
+        ));
+        let public = try!(PublicKey::from_slice(&public).ok_or(
+            EncryptionError::InvalidKey
+        ));
+        let mut secret = try!(parse_hex(&keyfile.secret).map_err(
+            |_| EncryptionError::InvalidKey
+        ));
+        if keyfile.is_protected() {
+            let salt = try!(parse_hex(&keyfile.salt).ok().and_then(|salt| {
+                pwhash::Salt::from_slice(&salt)
+            }).ok_or(EncryptionError::InvalidKey));
+            let nonce = try!(parse_hex(&keyfile.nonce).ok().and_then(|nonce| {
+                secretbox::Nonce::from_slice(&nonce)
+            }).ok_or(EncryptionError::InvalidKey));
+            if passphrase.is_none() {
+                *passphrase = Some(try!(get_passphrase()));
+            }
+            let key = passphrase_key(passphrase.as_ref().unwrap(), &salt);
+            secret = try!(secretbox::open(&secret, &nonce, &key).map_err(
+                |_| EncryptionError::WrongPassphrase
+            ));
+        }
+        let secret = try!(SecretKey::from_slice(&secret).ok_or(
+            EncryptionError::InvalidKey
+        ));
+        Ok((public, secret))
+    }
+
+    fn encode_keyfile(
+        public: &PublicKey,
+        secret: &SecretKey,
+        passphrase: Option<&str>,
+    ) -> KeyfileYaml {
+        let passphrase = match passphrase {
+            Some(passphrase) => passphrase,
+            None => return Self::save_keypair_to_file_data(public, secret),
+        };
+        let salt = pwhash::gen_salt();
+        let nonce = secretbox::gen_nonce();
+        let key = passphrase_key(passphrase, &salt);
+        KeyfileYaml {
+            public: to_hex(&public[..]),
+            secret: to_hex(&secretbox::seal(&secret[..], &nonce, &key)),
+            salt: to_hex(&salt[..]),
+            nonce: to_hex(&nonce[..])
+        }
+    }
+
     #[inline]
     pub fn add_secret_key(&mut self, public: PublicKey, secret: SecretKey) {
         self.keys.insert(public, secret);
@@ -173,29 +357,19 @@ impl Crypto {
         Self::load_keypair_from_file_data(&try!(KeyfileYaml::load(path)))
     }
 
+    #[inline]
     pub fn load_keypair_from_file_data(
         keyfile: &KeyfileYaml,
     ) -> Result<(PublicKey, SecretKey), EncryptionError> {
-        let public = try!(parse_hex(&keyfile.public).map_err(
-            |_| EncryptionError::InvalidKey
-        ));
-        let public = try!(PublicKey::from_slice(&public).ok_or(
-            EncryptionError::InvalidKey
-        ));
-        let secret = try!(parse_hex(&keyfile.secret).map_err(
-            |_| EncryptionError::InvalidKey
-        ));
-        let secret = try!(SecretKey::from_slice(&secret).ok_or(
-            EncryptionError::InvalidKey
-        ));
-        Ok((public, secret))
+        Self::decode_keyfile(keyfile, &mut None)
     }
 
     #[inline]
     pub fn save_keypair_to_file_data(public: &PublicKey, secret: &SecretKey) -> KeyfileYaml {
         KeyfileYaml {
             public: to_hex(&public[..]),
-            secret: to_hex(&secret[..])
+            secret: to_hex(&secret[..]),
+            ..Default::default()
         }
     }
 
@@ -216,7 +390,8 @@ impl Crypto {
     ) -> Result<(), EncryptionError> {
         if let Some(ref path) = self.path {
             let path = path.join(to_hex(&public[..]) + ".yaml");
-            try!(Self::save_keypair_to_file(&public, &secret, path));
+            let passphrase = self.passphrase.as_ref().map(|p| p as &str);
+            try!(Self::encode_keyfile(&public, &secret, passphrase).save(path));
         }
         self.keys.insert(public, secret);
         Ok(())
@@ -227,6 +402,11 @@ impl Crypto {
         self.keys.contains_key(public)
     }
 
+    /// Whether the secret key that is needed to decrypt data encrypted with `enc` is available
+    pub fn can_decrypt(&self, enc: &Encryption) -> bool {
+        PublicKey::from_slice(&enc.1).map_or(false, |public| self.keys.contains_key(&public))
+    }
+
     fn get_secret_key(&self, public: &PublicKey) -> Result<&SecretKey, EncryptionError> {
         self.keys.get(public).ok_or_else(
             || EncryptionError::MissingKey(*public)
@@ -337,6 +517,20 @@ mod tests {
         assert_eq!(sk, sk2);
     }
 
+    #[test]
+    fn test_protected_keyfile() {
+        let (pk, sk) = Crypto::gen_keypair();
+        let data = Crypto::encode_keyfile(&pk, &sk, Some("foo"));
+        assert!(data.is_protected());
+        assert!(data.secret != to_hex(&sk[..]));
+        let res = Crypto::decode_keyfile(&data, &mut Some("foo".to_string()));
+        assert!(res.is_ok());
+        let (pk2, sk2) = res.unwrap();
+        assert_eq!(pk, pk2);
+        assert_eq!(sk, sk2);
+        assert!(Crypto::decode_keyfile(&data, &mut Some("bar".to_string())).is_err());
+    }
+
     #[test]
     fn test_encrypt_decrpyt() {
         let mut crypto = Crypto::dummy();
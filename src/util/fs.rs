@@ -1,27 +1,591 @@
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::Path;
+
+
+#[cfg(unix)]
+mod unix {
+    use libc;
+    use xattr;
+
+    use std::borrow::Cow;
+    use std::path::{Path, PathBuf};
+    use std::io;
+    use std::fs::{self, File, Permissions};
+    use std::ffi::{CString, OsStr};
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::os::unix::io::AsRawFd;
+
+    // Linux and macOS accept at most 1024 buffers per writev call
+    const IOV_MAX: usize = 1024;
+
+    #[inline]
+    pub fn path_to_cstring<P: AsRef<Path>>(path: P) -> Result<CString, io::Error> {
+        CString::new(path.as_ref().to_path_buf().into_os_string().into_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Name contains nulls"))
+    }
+
+    /// Returns the raw bytes of the path
+    #[inline]
+    pub fn path_to_bytes(path: &Path) -> Cow<[u8]> {
+        Cow::Borrowed(path.as_os_str().as_bytes())
+    }
+
+    /// Creates a path from its raw bytes, see `path_to_bytes`
+    #[inline]
+    pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+        Path::new(OsStr::from_bytes(bytes)).to_path_buf()
+    }
+
+    #[inline]
+    pub fn chown<P: AsRef<Path>>(path: P, uid: u32, gid: u32) -> Result<(), io::Error> {
+        let path = try!(path_to_cstring(path));
+        let result = unsafe { libc::lchown((&path).as_ptr(), uid, gid) };
+        match result {
+            0 => Ok(()),
+            -1 => Err(io::Error::last_os_error()),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Sets an extended attribute, fails on platforms without them
+    #[inline]
+    pub fn set_xattr<P: AsRef<Path>>(path: P, name: &str, data: &[u8]) -> Result<(), io::Error> {
+        if !xattr::SUPPORTED_PLATFORM {
+            return Err(io::Error::new(io::ErrorKind::Other, "Xattrs are not supported"));
+        }
+        xattr::set(path, name, data)
+    }
+
+    #[inline]
+    pub fn set_mode<P: AsRef<Path>>(path: P, mode: u32) -> Result<(), io::Error> {
+        fs::set_permissions(path, Permissions::from_mode(mode))
+    }
+
+    /// Sets the access and modification time of the entry itself, symlinks are not followed
+    pub fn set_times_nofollow<P: AsRef<Path>>(path: P, mtime: i64) -> Result<(), io::Error> {
+        let path = try!(path_to_cstring(path));
+        let time = libc::timespec {
+            tv_sec: mtime as libc::time_t,
+            tv_nsec: 0
+        };
+        let times = [time, time];
+        let result = unsafe {
+            libc::utimensat(
+                libc::AT_FDCWD,
+                path.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW
+            )
+        };
+        match result {
+            0 => Ok(()),
+            -1 => Err(io::Error::last_os_error()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[inline]
+    pub fn create_symlink<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> Result<(), io::Error> {
+        symlink(src, dst)
+    }
+
+    pub fn create_fifo<P: AsRef<Path>>(path: P, mode: u32) -> Result<(), io::Error> {
+        let path = try!(path_to_cstring(path));
+        let mode = mode as libc::mode_t | libc::S_IFIFO;
+        if unsafe { libc::mkfifo(path.as_ptr(), mode) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Creates a socket file that no process is listening on
+    pub fn create_socket<P: AsRef<Path>>(path: P, mode: u32) -> Result<(), io::Error> {
+        let path = try!(path_to_cstring(path));
+        let mode = mode as libc::mode_t | libc::S_IFSOCK;
+        if unsafe { libc::mknod(path.as_ptr(), mode, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn create_device<P: AsRef<Path>>(
+        path: P,
+        mode: u32,
+        block: bool,
+        major: u32,
+        minor: u32,
+    ) -> Result<(), io::Error> {
+        let path = try!(path_to_cstring(path));
+        let mode = mode as libc::mode_t | if block { libc::S_IFBLK } else { libc::S_IFCHR };
+        let device = super::make_device(major, minor);
+        if unsafe { libc::mknod(path.as_ptr(), mode, device) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Writes all buffers to the file with as few `writev` calls as possible
+    pub fn write_all_vectored(file: &File, bufs: &[&[u8]]) -> Result<(), io::Error> {
+        let bufs: Vec<&[u8]> = bufs.iter().filter(|buf| !buf.is_empty()).cloned().collect();
+        let mut bufs = &bufs[..];
+        // Number of bytes of the first buffer that have already been written
+        let mut offset = 0;
+        while !bufs.is_empty() {
+            let iovecs: Vec<libc::iovec> = bufs.iter()
+                .take(IOV_MAX)
+                .enumerate()
+                .map(|(i, buf)| {
+                    let buf = if i == 0 { &buf[offset..] } else { buf };
+                    libc::iovec {
+                        iov_base: buf.as_ptr() as *mut libc::c_void,
+                        iov_len: buf.len()
+                    }
+                })
+                .collect();
+            let res = unsafe {
+                libc::writev(file.as_raw_fd(), iovecs.as_ptr(), iovecs.len() as libc::c_int)
+            };
+            if res < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            if res == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "Failed to write data"));
+            }
+            offset += res as usize;
+            while !bufs.is_empty() && offset >= bufs[0].len() {
+                offset -= bufs[0].len();
+                bufs = &bufs[1..];
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+pub use self::unix::*;
+
+
+#[cfg(target_os = "linux")]
 mod linux {
     use libc;
 
+    use std::ptr;
     use std::path::Path;
+    use std::fs::{File, Metadata, OpenOptions};
     use std::io;
-    use std::ffi::CString;
-    use std::os::unix::ffi::OsStringExt;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+
+    // _IOW(0x94, 9, int)
+    const FICLONE: libc::c_ulong = 0x4004_9409;
+    // _IOR('X', 31, struct fsxattr)
+    const FS_IOC_FSGETXATTR: libc::c_ulong = 0x801C_581F;
+    // _IOW('X', 32, struct fsxattr)
+    const FS_IOC_FSSETXATTR: libc::c_ulong = 0x401C_5820;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct FsXattr {
+        xflags: u32,
+        extsize: u32,
+        nextents: u32,
+        projid: u32,
+        cowextsize: u32,
+        pad: [u8; 8]
+    }
+
+    fn open_nofollow(path: &Path) -> Result<File, io::Error> {
+        OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+            .open(path)
+    }
+
+    fn get_fsxattr(file: &File) -> Result<FsXattr, io::Error> {
+        let mut attr = FsXattr::default();
+        if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FSGETXATTR, &mut attr) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(attr)
+    }
 
     #[inline]
-    pub fn chown<P: AsRef<Path>>(
-        path: P,
-        uid: libc::uid_t,
-        gid: libc::gid_t,
+    pub fn split_device(rdev: u64) -> (u32, u32) {
+        ((rdev >> 8) as u32, (rdev & 0xff) as u32)
+    }
+
+    #[inline]
+    pub fn make_device(major: u32, minor: u32) -> libc::dev_t {
+        unsafe { libc::makedev(major, minor) }
+    }
+
+    #[inline]
+    pub fn get_file_flags(_meta: &Metadata) -> Option<u32> {
+        None
+    }
+
+    #[inline]
+    pub fn set_file_flags<P: AsRef<Path>>(_path: P, _flags: u32) -> Result<(), io::Error> {
+        Err(io::Error::new(io::ErrorKind::Other, "File flags are not supported on this platform"))
+    }
+
+    /// Returns the project (quota) ID of a file or directory, only works on XFS, ext4 and similar
+    pub fn get_project_id<P: AsRef<Path>>(path: P) -> Option<u32> {
+        open_nofollow(path.as_ref())
+            .and_then(|file| get_fsxattr(&file))
+            .ok()
+            .map(|attr| attr.projid)
+    }
+
+    pub fn set_project_id<P: AsRef<Path>>(path: P, id: u32) -> Result<(), io::Error> {
+        let file = try!(open_nofollow(path.as_ref()));
+        let mut attr = try!(get_fsxattr(&file));
+        if attr.projid == id {
+            return Ok(());
+        }
+        attr.projid = id;
+        if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FSSETXATTR, &attr) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Makes `dst` share the data of `src` (reflink), only works on btrfs, XFS and similar
+    pub fn clone_file(src: &File, dst: &File) -> Result<(), io::Error> {
+        if unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE, src.as_raw_fd()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Copies `len` bytes at `offset` in `src` to the current position of `dst` inside the kernel
+    ///
+    /// This fails on kernels before 4.5 and, before 5.3, for files on different filesystems.
+    pub fn copy_file_range(
+        src: &File,
+        offset: u64,
+        dst: &File,
+        len: usize,
     ) -> Result<(), io::Error> {
-        let path = CString::new(path.as_ref().to_path_buf().into_os_string().into_vec()).unwrap();
-        let result = unsafe { libc::lchown((&path).as_ptr(), uid, gid) };
+        let mut offset = offset as libc::loff_t;
+        let mut remaining = len;
+        while remaining > 0 {
+            let res = unsafe {
+                libc::syscall(
+                    libc::SYS_copy_file_range,
+                    src.as_raw_fd(),
+                    &mut offset as *mut libc::loff_t,
+                    dst.as_raw_fd(),
+                    ptr::null_mut::<libc::loff_t>(),
+                    remaining,
+                    0
+                )
+            };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if res == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Source file too short"));
+            }
+            remaining -= res as usize;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use self::linux::*;
+
+
+#[cfg(target_os = "macos")]
+mod macos {
+    use libc;
+
+    use std::path::Path;
+    use std::fs::{File, Metadata};
+    use std::io;
+    use std::os::macos::fs::MetadataExt;
+
+    mod ffi {
+        use libc;
+
+        extern "C" {
+            pub fn lchflags(path: *const libc::c_char, flags: libc::c_uint) -> libc::c_int;
+        }
+    }
+
+    #[inline]
+    pub fn split_device(rdev: u64) -> (u32, u32) {
+        (((rdev >> 24) & 0xff) as u32, (rdev & 0xff_ffff) as u32)
+    }
+
+    #[inline]
+    pub fn make_device(major: u32, minor: u32) -> libc::dev_t {
+        ((major << 24) | (minor & 0xff_ffff)) as libc::dev_t
+    }
+
+    #[inline]
+    pub fn get_file_flags(meta: &Metadata) -> Option<u32> {
+        Some(meta.st_flags())
+    }
+
+    pub fn set_file_flags<P: AsRef<Path>>(path: P, flags: u32) -> Result<(), io::Error> {
+        let path = try!(super::path_to_cstring(path));
+        let result = unsafe { ffi::lchflags((&path).as_ptr(), flags as libc::c_uint) };
         match result {
             0 => Ok(()),
             -1 => Err(io::Error::last_os_error()),
             _ => unreachable!(),
         }
     }
+
+    #[inline]
+    pub fn get_project_id<P: AsRef<Path>>(_path: P) -> Option<u32> {
+        None
+    }
+
+    #[inline]
+    pub fn set_project_id<P: AsRef<Path>>(_path: P, _id: u32) -> Result<(), io::Error> {
+        Err(io::Error::new(io::ErrorKind::Other, "Project IDs are not supported on this platform"))
+    }
+
+    #[inline]
+    pub fn clone_file(_src: &File, _dst: &File) -> Result<(), io::Error> {
+        Err(io::Error::new(io::ErrorKind::Other, "Cloning files is not supported on this platform"))
+    }
+
+    #[inline]
+    pub fn copy_file_range(
+        _src: &File,
+        _offset: u64,
+        _dst: &File,
+        _len: usize,
+    ) -> Result<(), io::Error> {
+        Err(io::Error::new(io::ErrorKind::Other, "Copying file ranges is not supported"))
+    }
 }
 
-pub use self::linux::*;
+#[cfg(target_os = "macos")]
+pub use self::macos::*;
+
+
+/// Other unix platforms use the traditional device numbers and have no file flags
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "macos"))))]
+mod other_unix {
+    use libc;
+
+    use std::path::Path;
+    use std::fs::{File, Metadata};
+    use std::io;
+
+    #[inline]
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "Not supported on this platform")
+    }
+
+    #[inline]
+    pub fn split_device(rdev: u64) -> (u32, u32) {
+        (((rdev >> 8) & 0xff) as u32, (rdev & 0xff) as u32)
+    }
+
+    #[inline]
+    pub fn make_device(major: u32, minor: u32) -> libc::dev_t {
+        (((major & 0xff) << 8) | (minor & 0xff)) as libc::dev_t
+    }
+
+    #[inline]
+    pub fn get_file_flags(_meta: &Metadata) -> Option<u32> {
+        None
+    }
+
+    #[inline]
+    pub fn set_file_flags<P: AsRef<Path>>(_path: P, _flags: u32) -> Result<(), io::Error> {
+        Err(unsupported())
+    }
+
+    #[inline]
+    pub fn get_project_id<P: AsRef<Path>>(_path: P) -> Option<u32> {
+        None
+    }
+
+    #[inline]
+    pub fn set_project_id<P: AsRef<Path>>(_path: P, _id: u32) -> Result<(), io::Error> {
+        Err(unsupported())
+    }
+
+    #[inline]
+    pub fn clone_file(_src: &File, _dst: &File) -> Result<(), io::Error> {
+        Err(unsupported())
+    }
+
+    #[inline]
+    pub fn copy_file_range(
+        _src: &File,
+        _offset: u64,
+        _dst: &File,
+        _len: usize,
+    ) -> Result<(), io::Error> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "macos"))))]
+pub use self::other_unix::*;
+
+
+/// Windows only supports restoring, all unix specific metadata is ignored
+#[cfg(windows)]
+mod windows {
+    use filetime::{self, FileTime};
+
+    use std::borrow::Cow;
+    use std::path::{Path, PathBuf};
+    use std::fs::File;
+    use std::io::{self, Write};
+    use std::os::windows::fs::symlink_file;
+
+    #[inline]
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "Not supported on this platform")
+    }
+
+    /// Returns the path as UTF-8, paths are stored that way to be portable between platforms
+    #[inline]
+    pub fn path_to_bytes(path: &Path) -> Cow<[u8]> {
+        match path.to_string_lossy() {
+            Cow::Borrowed(path) => Cow::Borrowed(path.as_bytes()),
+            Cow::Owned(path) => Cow::Owned(path.into_bytes()),
+        }
+    }
+
+    /// Creates a path from its UTF-8 bytes, invalid sequences are replaced
+    #[inline]
+    pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+        PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    #[inline]
+    pub fn chown<P: AsRef<Path>>(_path: P, _uid: u32, _gid: u32) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    #[inline]
+    pub fn set_xattr<P: AsRef<Path>>(_path: P, _name: &str, _data: &[u8]) -> Result<(), io::Error> {
+        Err(unsupported())
+    }
+
+    #[inline]
+    pub fn set_mode<P: AsRef<Path>>(_path: P, _mode: u32) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    #[inline]
+    pub fn set_times_nofollow<P: AsRef<Path>>(path: P, mtime: i64) -> Result<(), io::Error> {
+        let time = FileTime::from_seconds_since_1970(mtime as u64, 0);
+        filetime::set_file_times(path, time, time)
+    }
+
+    #[inline]
+    pub fn create_symlink<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> Result<(), io::Error> {
+        symlink_file(src, dst)
+    }
+
+    #[inline]
+    pub fn create_fifo<P: AsRef<Path>>(_path: P, _mode: u32) -> Result<(), io::Error> {
+        Err(unsupported())
+    }
+
+    #[inline]
+    pub fn create_socket<P: AsRef<Path>>(_path: P, _mode: u32) -> Result<(), io::Error> {
+        Err(unsupported())
+    }
+
+    #[inline]
+    pub fn create_device<P: AsRef<Path>>(
+        _path: P,
+        _mode: u32,
+        _block: bool,
+        _major: u32,
+        _minor: u32,
+    ) -> Result<(), io::Error> {
+        Err(unsupported())
+    }
+
+    #[inline]
+    pub fn set_file_flags<P: AsRef<Path>>(_path: P, _flags: u32) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    #[inline]
+    pub fn set_project_id<P: AsRef<Path>>(_path: P, _id: u32) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    #[inline]
+    pub fn clone_file(_src: &File, _dst: &File) -> Result<(), io::Error> {
+        Err(unsupported())
+    }
+
+    #[inline]
+    pub fn copy_file_range(
+        _src: &File,
+        _offset: u64,
+        _dst: &File,
+        _len: usize,
+    ) -> Result<(), io::Error> {
+        Err(unsupported())
+    }
+
+    pub fn write_all_vectored(mut file: &File, bufs: &[&[u8]]) -> Result<(), io::Error> {
+        for buf in bufs {
+            try!(file.write_all(buf));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+pub use self::windows::*;
+
+
+/// Features of a filesystem that the names of repository files depend on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsCapabilities {
+    /// Names that only differ in case refer to different files
+    pub case_sensitive: bool,
+    /// Names do not need to follow the 8.3 format
+    pub long_names: bool,
+    /// Names can contain characters like `:` and `?` that FAT and SMB reject
+    pub special_chars: bool
+}
+
+fn probe_name<P: AsRef<Path>>(path: P) -> bool {
+    match OpenOptions::new().write(true).create_new(true).open(path.as_ref()) {
+        Ok(_) => fs::remove_file(path).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Determines the capabilities of the filesystem of the folder by creating some test files
+///
+/// The folder must be writable, the test files are removed afterwards.
+pub fn probe_fs_capabilities<P: AsRef<Path>>(dir: P) -> Result<FsCapabilities, io::Error> {
+    let dir = dir.as_ref();
+    let probe = dir.join("ZVPROBE.TMP");
+    try!(OpenOptions::new().write(true).create_new(true).open(&probe));
+    let case_sensitive = !dir.join("zvprobe.tmp").exists();
+    try!(fs::remove_file(&probe));
+    Ok(FsCapabilities {
+        case_sensitive: case_sensitive,
+        long_names: probe_name(dir.join("zvault-probe-of-long-file-names.bundle")),
+        special_chars: probe_name(dir.join("zvprobe:?.tmp"))
+    })
+}
 
 // Not testing since this requires root
@@ -4,9 +4,13 @@ use serde_bytes::{ByteBuf, Bytes};
 
 use murmurhash3::murmurhash3_x64_128;
 use blake2::blake2b::blake2b;
-use byteorder::{LittleEndian, ByteOrder, WriteBytesExt, ReadBytesExt};
+use byteorder::{LittleEndian, BigEndian, ByteOrder, WriteBytesExt, ReadBytesExt};
+use libc;
+use rand::{OsRng, Rng};
 
-use std::mem;
+use super::{CpuFeatures, sodium_init};
+
+use std::ptr;
 use std::fmt;
 use std::u64;
 use std::io::{self, Read, Write};
@@ -51,8 +55,20 @@ impl Hash {
         })
     }
 
+    /// Generates a random value from the secure random source of the OS, e.g. for keys
+    pub fn random_key() -> Result<Self, io::Error> {
+        let mut rng = try!(OsRng::new());
+        Ok(Hash {
+            high: rng.next_u64(),
+            low: rng.next_u64()
+        })
+    }
+
     #[inline]
     pub fn from_string(val: &str) -> Result<Self, ()> {
+        if val.len() != 32 || !val.is_ascii() {
+            return Err(());
+        }
         let high = try!(u64::from_str_radix(&val[..16], 16).map_err(|_| ()));
         let low = try!(u64::from_str_radix(&val[16..], 16).map_err(|_| ()));
         Ok(Self {
@@ -106,6 +122,50 @@ impl<'a> Deserialize<'a> for Hash {
 }
 
 
+mod ffi {
+    use libc;
+
+    extern "C" {
+        pub fn crypto_generichash(
+            out: *mut u8,
+            outlen: libc::size_t,
+            input: *const u8,
+            inlen: libc::c_ulonglong,
+            key: *const u8,
+            keylen: libc::size_t,
+        ) -> libc::c_int;
+    }
+}
+
+/// Returns whether libsodium has a SIMD implementation of blake2b for this CPU
+#[inline]
+fn has_fast_blake2() -> bool {
+    let cpu = CpuFeatures::get();
+    cpu.avx2 || cpu.sse41 || cpu.ssse3
+}
+
+/// Blake2b using libsodium which picks the fastest implementation for the CPU at runtime
+fn blake2b_sodium(data: &[u8], key: &[u8], out: &mut [u8; 16]) {
+    sodium_init();
+    let res = unsafe {
+        ffi::crypto_generichash(
+            out.as_mut_ptr(),
+            out.len() as libc::size_t,
+            data.as_ptr(),
+            data.len() as libc::c_ulonglong,
+            if key.is_empty() { ptr::null() } else { key.as_ptr() },
+            key.len() as libc::size_t
+        )
+    };
+    assert_eq!(res, 0);
+}
+
+/// Portable blake2b that is used when there is no SIMD implementation for the CPU
+fn blake2b_portable(data: &[u8], key: &[u8], out: &mut [u8; 16]) {
+    out.copy_from_slice(blake2b(16, key, data).as_bytes());
+}
+
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum HashMethod {
     Blake2,
@@ -120,18 +180,38 @@ serde_impl!(HashMethod(u64) {
 impl HashMethod {
     #[inline]
     pub fn hash(&self, data: &[u8]) -> Hash {
+        self.hash_keyed(data, None)
+    }
+
+    /// Hashes the data with a secret key so that the hashes can not be predicted without it
+    ///
+    /// Blake2 is used as a MAC with the key. Murmur3 can only use 64 bits of the key as its seed
+    /// and is not cryptographically secure, so it does not really protect the hashes.
+    pub fn hash_keyed(&self, data: &[u8], key: Option<&Hash>) -> Hash {
         match *self {
             HashMethod::Blake2 => {
-                let hash = blake2b(16, &[], data);
-                let hash =
-                    unsafe { &*mem::transmute::<_, *const (u64, u64)>(hash.as_bytes().as_ptr()) };
+                let mut key_data = [0u8; 16];
+                let key_data = match key {
+                    Some(key) => {
+                        BigEndian::write_u64(&mut key_data[..8], key.high);
+                        BigEndian::write_u64(&mut key_data[8..], key.low);
+                        &key_data as &[u8]
+                    }
+                    None => &[] as &[u8],
+                };
+                let mut hash = [0u8; 16];
+                if has_fast_blake2() {
+                    blake2b_sodium(data, key_data, &mut hash);
+                } else {
+                    blake2b_portable(data, key_data, &mut hash);
+                }
                 Hash {
-                    high: u64::from_be(hash.0),
-                    low: u64::from_be(hash.1)
+                    high: BigEndian::read_u64(&hash[..8]),
+                    low: BigEndian::read_u64(&hash[8..])
                 }
             }
             HashMethod::Murmur3 => {
-                let (a, b) = murmurhash3_x64_128(data, 0);
+                let (a, b) = murmurhash3_x64_128(data, key.map_or(0, |key| key.low));
                 Hash { high: a, low: b }
             }
         }
@@ -153,6 +233,25 @@ impl HashMethod {
             HashMethod::Murmur3 => "murmur3",
         }
     }
+
+    /// Describes the implementation that is used on this CPU
+    pub fn implementation(&self) -> &'static str {
+        match *self {
+            HashMethod::Blake2 => {
+                let cpu = CpuFeatures::get();
+                if cpu.avx2 {
+                    "libsodium (avx2)"
+                } else if cpu.sse41 {
+                    "libsodium (sse4.1)"
+                } else if cpu.ssse3 {
+                    "libsodium (ssse3)"
+                } else {
+                    "blake2-rfc (portable)"
+                }
+            }
+            HashMethod::Murmur3 => "murmurhash3 (portable)",
+        }
+    }
 }
 
 
@@ -187,6 +286,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_blake2_implementations() {
+        for data in &[&b""[..], &b"abc"[..], &[0x5a; 1000][..]] {
+            for key in &[&b""[..], &[0xa5; 16][..]] {
+                let (mut fast, mut portable) = ([0u8; 16], [0u8; 16]);
+                blake2b_sodium(data, key, &mut fast);
+                blake2b_portable(data, key, &mut portable);
+                assert_eq!(fast, portable);
+            }
+        }
+    }
+
+    #[test]
+    fn test_keyed() {
+        let key = Hash {
+            high: 0x0123456789abcdef,
+            low: 0xfedcba9876543210
+        };
+        let other = Hash { high: 1, low: 2 };
+        for method in &[HashMethod::Blake2, HashMethod::Murmur3] {
+            let keyed = method.hash_keyed(b"abc", Some(&key));
+            assert_eq!(method.hash_keyed(b"abc", None), method.hash(b"abc"));
+            assert_eq!(method.hash_keyed(b"abc", Some(&key)), keyed);
+            assert!(keyed != method.hash(b"abc"));
+            assert!(keyed != method.hash_keyed(b"abc", Some(&other)));
+        }
+    }
+
     #[test]
     fn test_murmur3() {
         assert_eq!(
@@ -198,6 +325,15 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_from_string() {
+        let hash = HashMethod::Blake2.hash(b"abc");
+        assert_eq!(Hash::from_string(&hash.to_string()), Ok(hash));
+        assert!(Hash::from_string("").is_err());
+        assert!(Hash::from_string("cf4ab791c62b8d2b").is_err());
+        assert!(Hash::from_string("xf4ab791c62b8d2b2109c90275287816").is_err());
+    }
+
 }
 
 
@@ -1,4 +1,8 @@
 use libc;
+#[cfg(unix)]
+use users;
+#[cfg(not(unix))]
+use std::env;
 use std::ffi;
 
 extern "C" {
@@ -23,6 +27,18 @@ pub fn get_hostname() -> Result<String, ()> {
     }
 }
 
+/// Returns the name of the user running the process, empty if it is unknown
+#[cfg(unix)]
+pub fn get_username() -> String {
+    users::get_current_username().unwrap_or_default()
+}
+
+/// Returns the name of the user running the process, empty if it is unknown
+#[cfg(not(unix))]
+pub fn get_username() -> String {
+    env::var("USERNAME").unwrap_or_default()
+}
+
 
 
 mod tests {
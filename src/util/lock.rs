@@ -6,7 +6,12 @@ use libc;
 
 use std::path::{Path, PathBuf};
 use std::io;
-use std::fs::{self, File};
+use std::fs::{self, File, OpenOptions};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(windows)]
+use std::os::windows::fs::OpenOptionsExt;
+use std::sync::Mutex;
 
 
 quick_error!{
@@ -36,18 +41,32 @@ quick_error!{
 }
 
 
+lazy_static! {
+    // Operation that is recorded in new lock files
+    static ref LOCK_OPERATION: Mutex<String> = Mutex::new(String::new());
+}
+
+/// Sets the operation that is recorded in all lock files created from now on
+pub fn set_lock_operation(operation: &str) {
+    *LOCK_OPERATION.lock().unwrap() = operation.to_string();
+}
+
+
 #[derive(Debug, Clone, Default, Eq, PartialEq)]
 pub struct LockFile {
     pub hostname: String,
     pub processid: usize,
     pub date: i64,
-    pub exclusive: bool
+    pub exclusive: bool,
+    /// Command that holds the lock, empty for locks of older versions
+    pub operation: String
 }
-serde_impl!(LockFile(String) {
+serde_impl!(LockFile(String?) {
     hostname: String => "hostname",
     processid: usize => "processid",
     date: i64 => "date",
-    exclusive: bool => "exclusive"
+    exclusive: bool => "exclusive",
+    operation: String => "operation"
 });
 
 impl LockFile {
@@ -98,6 +117,74 @@ impl Drop for LockHandle {
 
 
 
+/// Advisory lock on a file, held until it is dropped
+///
+/// Unlike the lock files of a `LockFolder`, the lock is released by the system when the process
+/// ends, so it can not go stale when a process crashes.
+pub struct FileLock {
+    _file: File
+}
+
+impl FileLock {
+    /// Locks the file, creating it if needed
+    ///
+    /// If the file is locked by another process, this waits for it to be released when `wait` is
+    /// set and fails with `LockError::Locked` otherwise.
+    #[cfg(unix)]
+    pub fn acquire<P: AsRef<Path>>(path: P, wait: bool) -> Result<Self, LockError> {
+        let file = try!(OpenOptions::new().write(true).create(true).open(path));
+        let mut operation = libc::LOCK_EX;
+        if !wait {
+            operation |= libc::LOCK_NB;
+        }
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                return Err(LockError::Locked);
+            }
+            return Err(LockError::Io(err));
+        }
+        Ok(FileLock { _file: file })
+    }
+
+    /// Locks the file, creating it if needed
+    ///
+    /// Windows has no advisory locks, so the file is opened without sharing it instead. Other
+    /// processes can not open it until it is closed.
+    #[cfg(windows)]
+    pub fn acquire<P: AsRef<Path>>(path: P, wait: bool) -> Result<Self, LockError> {
+        // Returned when the file is opened by another process
+        const ERROR_SHARING_VIOLATION: i32 = 32;
+        loop {
+            match OpenOptions::new().write(true).create(true).share_mode(0).open(path.as_ref()) {
+                Ok(file) => return Ok(FileLock { _file: file }),
+                Err(ref err) if err.raw_os_error() == Some(ERROR_SHARING_VIOLATION) => {
+                    if !wait {
+                        return Err(LockError::Locked);
+                    }
+                    ::std::thread::sleep(::std::time::Duration::from_millis(100));
+                }
+                Err(err) => return Err(LockError::Io(err)),
+            }
+        }
+    }
+}
+
+
+
+/// Whether a process with the given id is running on this host
+#[cfg(unix)]
+pub fn process_exists(pid: usize) -> bool {
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+/// Processes can not be checked on other platforms, so they are assumed to be running
+#[cfg(not(unix))]
+pub fn process_exists(_pid: usize) -> bool {
+    true
+}
+
+
 pub struct LockFolder {
     path: PathBuf
 }
@@ -107,11 +194,17 @@ impl LockFolder {
         LockFolder { path: path.as_ref().to_path_buf() }
     }
 
-    fn get_locks(&self) -> Result<Vec<LockFile>, LockError> {
+    pub fn get_locks(&self) -> Result<Vec<LockFile>, LockError> {
+        Ok(try!(self.get_lock_files()).into_iter().map(|(_, lock)| lock).collect())
+    }
+
+    /// Returns all locks together with the paths of their files
+    pub fn get_lock_files(&self) -> Result<Vec<(PathBuf, LockFile)>, LockError> {
         let mut locks = vec![];
         for entry in try!(fs::read_dir(&self.path)) {
-            let entry = try!(entry);
-            locks.push(try!(LockFile::load(entry.path())));
+            let path = try!(entry).path();
+            let lock = try!(LockFile::load(&path));
+            locks.push((path, lock));
         }
         Ok(locks)
     }
@@ -145,7 +238,8 @@ impl LockFolder {
             hostname: get_hostname().unwrap(),
             processid: unsafe { libc::getpid() } as usize,
             date: Utc::now().timestamp(),
-            exclusive: exclusive
+            exclusive: exclusive,
+            operation: LOCK_OPERATION.lock().unwrap().clone()
         };
         let path = self.path.join(format!(
             "{}-{}.lock",
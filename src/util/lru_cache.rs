@@ -21,6 +21,15 @@ impl<K: Eq + Hash, V> LruCache<K, V> {
         }
     }
 
+    #[inline]
+    pub fn resize(&mut self, min_size: usize, max_size: usize) {
+        self.min_size = min_size;
+        self.max_size = max_size;
+        if self.items.len() > self.max_size {
+            self.shrink()
+        }
+    }
+
     #[inline]
     pub fn put(&mut self, key: K, value: V) {
         self.items.insert(key, (value, self.next));
@@ -52,6 +61,11 @@ impl<K: Eq + Hash, V> LruCache<K, V> {
         }
     }
 
+    #[inline]
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.items.remove(key).map(|(item, _)| item)
+    }
+
     fn shrink(&mut self) {
         let mut tags: Vec<u64> = self.items.values().map(|&(_, n)| n).collect();
         tags.sort();
@@ -9,6 +9,16 @@ mod cli;
 mod hostname;
 mod fs;
 mod lock;
+mod sha256;
+mod priority;
+mod delta;
+mod cpu;
+mod agent;
+mod mounts;
+mod systemd;
+mod desktop;
+mod throttle;
+mod crc32c;
 pub mod msgpack;
 
 pub use self::fs::*;
@@ -22,3 +32,13 @@ pub use self::hex::*;
 pub use self::cli::*;
 pub use self::hostname::*;
 pub use self::lock::*;
+pub use self::sha256::*;
+pub use self::priority::*;
+pub use self::delta::*;
+pub use self::cpu::*;
+pub use self::agent::*;
+pub use self::mounts::*;
+pub use self::systemd::*;
+pub use self::desktop::*;
+pub use self::throttle::*;
+pub use self::crc32c::*;
@@ -0,0 +1,187 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+
+/// A mounted filesystem as listed in `/proc/self/mountinfo`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MountInfo {
+    pub mount_point: PathBuf,
+    /// Path of the mounted directory inside of the filesystem, e.g. the btrfs subvolume
+    pub root: String,
+    pub fs_type: String,
+    pub device: String,
+    /// Mount options followed by the options of the filesystem
+    pub options: Vec<String>
+}
+
+impl MountInfo {
+    #[inline]
+    pub fn is_read_only(&self) -> bool {
+        self.options.iter().any(|o| o == "ro")
+    }
+}
+
+
+/// Replaces the octal escapes like `\040` for spaces that mountinfo uses in paths
+fn unescape(val: &str) -> String {
+    let bytes = val.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() &&
+            bytes[i + 1..i + 4].iter().all(|&b| b >= b'0' && b <= b'7')
+        {
+            result.push(
+                (bytes[i + 1] - b'0') * 64 + (bytes[i + 2] - b'0') * 8 + (bytes[i + 3] - b'0')
+            );
+            i += 4;
+        } else {
+            result.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&result).to_string()
+}
+
+/// Parses the contents of `/proc/self/mountinfo`, malformed lines are skipped
+pub fn parse_mountinfo(data: &str) -> Vec<MountInfo> {
+    let mut mounts = vec![];
+    for line in data.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // The optional fields are terminated by a single dash
+        let sep = match fields.iter().position(|&f| f == "-") {
+            Some(sep) if sep >= 6 && fields.len() >= sep + 3 => sep,
+            _ => continue,
+        };
+        let mut options: Vec<String> = fields[5].split(',').map(|o| o.to_string()).collect();
+        if let Some(super_options) = fields.get(sep + 3) {
+            for option in super_options.split(',') {
+                if !options.iter().any(|o| o == option) {
+                    options.push(option.to_string());
+                }
+            }
+        }
+        mounts.push(MountInfo {
+            mount_point: PathBuf::from(unescape(fields[4])),
+            root: unescape(fields[3]),
+            fs_type: fields[sep + 1].to_string(),
+            device: unescape(fields[sep + 2]),
+            options: options
+        });
+    }
+    mounts
+}
+
+/// Finds the mount that contains the path, the path must be absolute and canonical
+pub fn find_mount<'a>(mounts: &'a [MountInfo], path: &Path) -> Option<&'a MountInfo> {
+    // Later mounts hide earlier ones on the same mount point, max_by_key returns the last one
+    mounts
+        .iter()
+        .filter(|mount| path.starts_with(&mount.mount_point))
+        .max_by_key(|mount| mount.mount_point.components().count())
+}
+
+/// Returns the mount that contains the path, only supported on Linux
+pub fn get_mount_of<P: AsRef<Path>>(path: P) -> Option<MountInfo> {
+    let path = match fs::canonicalize(path) {
+        Ok(path) => path,
+        Err(_) => return None,
+    };
+    let mut data = String::new();
+    if File::open("/proc/self/mountinfo")
+        .and_then(|mut f| f.read_to_string(&mut data))
+        .is_err()
+    {
+        return None;
+    }
+    let mounts = parse_mountinfo(&data);
+    find_mount(&mounts, &path).cloned()
+}
+
+/// Looks up the UUID of the filesystem on the device in `/dev/disk/by-uuid`
+pub fn get_filesystem_uuid(device: &str) -> Option<String> {
+    let device = match fs::canonicalize(device) {
+        Ok(device) => device,
+        Err(_) => return None,
+    };
+    let entries = match fs::read_dir("/dev/disk/by-uuid") {
+        Ok(entries) => entries,
+        Err(_) => return None,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        if fs::canonicalize(entry.path()).ok().as_ref() == Some(&device) {
+            return Some(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    None
+}
+
+/// Returns the name of the ZFS snapshot if the path is inside of `.zfs/snapshot/NAME`
+pub fn get_zfs_snapshot_name(path: &Path) -> Option<String> {
+    let components: Vec<_> = path.components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect();
+    components
+        .windows(3)
+        .find(|w| w[0] == ".zfs" && w[1] == "snapshot")
+        .map(|w| w[2].clone())
+}
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(dead_code)]
+    const MOUNTINFO: &'static str = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw,errors=remount-ro
+25 22 0:21 / /proc rw,nosuid,nodev,noexec,relatime shared:12 - proc proc rw
+40 22 0:35 /snapshots/daily /mnt/snap ro,relatime shared:20 - btrfs /dev/sdb1 ro,space_cache
+41 22 8:3 / /mnt/my\\040disk rw,noatime - ext4 /dev/sdc1 rw
+broken line
+";
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!("/mnt/my disk", unescape("/mnt/my\\040disk"));
+        assert_eq!("a\\b", unescape("a\\b"));
+        assert_eq!("tab\t", unescape("tab\\011"));
+    }
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+        assert_eq!(4, mounts.len());
+        assert_eq!(Path::new("/"), mounts[0].mount_point);
+        assert_eq!("ext4", mounts[0].fs_type);
+        assert_eq!("/dev/sda1", mounts[0].device);
+        assert_eq!(vec!["rw", "relatime", "errors=remount-ro"], mounts[0].options);
+        assert!(!mounts[0].is_read_only());
+        assert_eq!("/snapshots/daily", mounts[2].root);
+        assert!(mounts[2].is_read_only());
+        assert_eq!(Path::new("/mnt/my disk"), mounts[3].mount_point);
+    }
+
+    #[test]
+    fn test_find_mount() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+        let find = |path| find_mount(&mounts, Path::new(path)).map(|m| m.device.clone());
+        assert_eq!(Some("/dev/sda1".to_string()), find("/home/user"));
+        assert_eq!(Some("/dev/sdb1".to_string()), find("/mnt/snap/data"));
+        assert_eq!(Some("/dev/sda1".to_string()), find("/mnt/snapshot"));
+        assert_eq!(Some("/dev/sdc1".to_string()), find("/mnt/my disk"));
+    }
+
+    #[test]
+    fn test_get_zfs_snapshot_name() {
+        assert_eq!(
+            Some("daily-1".to_string()),
+            get_zfs_snapshot_name(Path::new("/tank/.zfs/snapshot/daily-1/home"))
+        );
+        assert_eq!(None, get_zfs_snapshot_name(Path::new("/tank/home")));
+        assert_eq!(None, get_zfs_snapshot_name(Path::new("/tank/.zfs/snapshot")));
+    }
+
+}
@@ -1,13 +1,127 @@
 use rmp_serde;
 use serde::{Serialize, Deserialize};
+use serde::de::Error;
 
-use std::io::{Write, Read, Cursor};
+use std::io::{self, Write, Read, Cursor};
 
 pub use serde_bytes::ByteBuf as Bytes;
 pub use rmp_serde::decode::Error as DecodeError;
 pub use rmp_serde::encode::Error as EncodeError;
 
 
+/// Maximal size of a value decoded from a stream
+pub const MAX_STREAM_SIZE: usize = 1 << 30;
+
+/// Maximal size of a file header decoded from a stream
+pub const MAX_HEADER_SIZE: usize = 4096;
+
+/// Maximal nesting depth of arrays and maps in a decoded value
+pub const MAX_DEPTH: usize = 256;
+
+
+/// Walks over the structure of one encoded value without decoding it
+///
+/// Every length announced in the data is checked against the remaining size before anything is
+/// read, so a malformed value fails with an error instead of causing a huge allocation. The
+/// scanned bytes are copied to `writer`.
+struct Scanner<'a> {
+    reader: &'a mut Read,
+    writer: &'a mut Write,
+    remaining: usize
+}
+
+impl<'a> Scanner<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), DecodeError> {
+        if buf.len() > self.remaining {
+            return Err(DecodeError::custom("Value exceeds the size limit"));
+        }
+        try!(self.reader.read_exact(buf).map_err(|err| {
+            DecodeError::custom(format!("Failed to read value: {}", err))
+        }));
+        try!(self.writer.write_all(buf).map_err(|err| {
+            DecodeError::custom(format!("Failed to copy value: {}", err))
+        }));
+        self.remaining -= buf.len();
+        Ok(())
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), DecodeError> {
+        if len > self.remaining {
+            return Err(DecodeError::custom("Value exceeds the size limit"));
+        }
+        let mut reader = (&mut *self.reader).take(len as u64);
+        let copied = try!(io::copy(&mut reader, &mut *self.writer).map_err(|err| {
+            DecodeError::custom(format!("Failed to read value: {}", err))
+        }));
+        if copied != len as u64 {
+            return Err(DecodeError::custom("Value is truncated"));
+        }
+        self.remaining -= len;
+        Ok(())
+    }
+
+    fn uint(&mut self, bytes: usize) -> Result<usize, DecodeError> {
+        let mut buf = [0u8; 4];
+        try!(self.read(&mut buf[..bytes]));
+        Ok(buf[..bytes].iter().fold(0, |val, &b| (val << 8) | b as usize))
+    }
+
+    fn scan(&mut self) -> Result<(), DecodeError> {
+        // Number of values that are still missing on each nesting level
+        let mut pending = vec![1usize];
+        while let Some(count) = pending.pop() {
+            if count == 0 {
+                continue;
+            }
+            pending.push(count - 1);
+            let mut marker = [0u8];
+            try!(self.read(&mut marker));
+            let marker = marker[0];
+            let (len, children) = match marker {
+                0x00...0x7f | 0xe0...0xff | 0xc0 | 0xc2 | 0xc3 => (0, 0),
+                0x80...0x8f => (0, 2 * (marker & 0x0f) as usize),
+                0x90...0x9f => (0, (marker & 0x0f) as usize),
+                0xa0...0xbf => ((marker & 0x1f) as usize, 0),
+                0xc4 | 0xd9 => (try!(self.uint(1)), 0),
+                0xc5 | 0xda => (try!(self.uint(2)), 0),
+                0xc6 | 0xdb => (try!(self.uint(4)), 0),
+                0xc7 => (try!(self.uint(1)).saturating_add(1), 0),
+                0xc8 => (try!(self.uint(2)).saturating_add(1), 0),
+                0xc9 => (try!(self.uint(4)).saturating_add(1), 0),
+                0xca => (4, 0),
+                0xcb => (8, 0),
+                0xcc | 0xd0 => (1, 0),
+                0xcd | 0xd1 => (2, 0),
+                0xce | 0xd2 => (4, 0),
+                0xcf | 0xd3 => (8, 0),
+                0xd4 => (2, 0),
+                0xd5 => (3, 0),
+                0xd6 => (5, 0),
+                0xd7 => (9, 0),
+                0xd8 => (17, 0),
+                0xdc => (0, try!(self.uint(2))),
+                0xdd => (0, try!(self.uint(4))),
+                0xde => (0, try!(self.uint(2)).saturating_mul(2)),
+                0xdf => (0, try!(self.uint(4)).saturating_mul(2)),
+                _ => return Err(DecodeError::custom(format!("Invalid marker: {:#x}", marker))),
+            };
+            try!(self.skip(len));
+            if children > 0 {
+                // Every value takes at least one byte
+                if children > self.remaining {
+                    return Err(DecodeError::custom("Value exceeds the size limit"));
+                }
+                if pending.len() >= MAX_DEPTH {
+                    return Err(DecodeError::custom("Value is nested too deeply"));
+                }
+                pending.push(children);
+            }
+        }
+        Ok(())
+    }
+}
+
+
 #[inline]
 pub fn encode<T: Serialize>(t: &T) -> Result<Vec<u8>, EncodeError> {
     let mut data = Vec::new();
@@ -24,8 +138,20 @@ pub fn encode_to_stream<T: Serialize>(t: &T, w: &mut Write) -> Result<(), Encode
     t.serialize(&mut writer)
 }
 
+/// Checks that `data` starts with a well-formed value whose lengths fit into the data
+pub fn validate(mut data: &[u8]) -> Result<(), DecodeError> {
+    let mut sink = io::sink();
+    let mut scanner = Scanner {
+        remaining: data.len(),
+        reader: &mut data,
+        writer: &mut sink
+    };
+    scanner.scan()
+}
+
 #[inline]
 pub fn decode<'a, T: Deserialize<'a>>(data: &[u8]) -> Result<T, DecodeError> {
+    try!(validate(data));
     let data = Cursor::new(data);
     let mut reader = rmp_serde::Deserializer::new(data);
     T::deserialize(&mut reader)
@@ -33,6 +159,93 @@ pub fn decode<'a, T: Deserialize<'a>>(data: &[u8]) -> Result<T, DecodeError> {
 
 #[inline]
 pub fn decode_from_stream<'a, T: Deserialize<'a>>(r: &mut Read) -> Result<T, DecodeError> {
-    let mut reader = rmp_serde::Deserializer::new(r);
+    decode_from_stream_limited(r, MAX_STREAM_SIZE)
+}
+
+/// Decodes one value from the stream, failing if it takes more than `limit` bytes
+///
+/// Exactly the bytes of the value are consumed from the stream so that following data can
+/// still be read.
+pub fn decode_from_stream_limited<'a, T: Deserialize<'a>>(
+    r: &mut Read,
+    limit: usize,
+) -> Result<T, DecodeError> {
+    let mut data = Vec::new();
+    {
+        let mut scanner = Scanner {
+            reader: r,
+            writer: &mut data,
+            remaining: limit
+        };
+        try!(scanner.scan());
+    }
+    let data = Cursor::new(data);
+    let mut reader = rmp_serde::Deserializer::new(data);
     T::deserialize(&mut reader)
 }
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use std::io::{Cursor, Read};
+
+    #[allow(unused_imports)]
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut map = HashMap::new();
+        map.insert("test".to_string(), vec![1u64, 2, 3]);
+        map.insert("empty".to_string(), vec![]);
+        let data = encode(&map).unwrap();
+        assert!(validate(&data).is_ok());
+        assert_eq!(decode::<HashMap<String, Vec<u64>>>(&data).unwrap(), map);
+    }
+
+    #[test]
+    fn test_stream_keeps_following_data() {
+        let mut data = encode(&(1u8, "test".to_string())).unwrap();
+        data.extend_from_slice(b"rest");
+        let mut stream = Cursor::new(data);
+        let value: (u8, String) = decode_from_stream(&mut stream).unwrap();
+        assert_eq!(value, (1, "test".to_string()));
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"rest");
+    }
+
+    #[test]
+    fn test_truncated() {
+        let data = encode(&vec!["test".to_string(); 10]).unwrap();
+        for len in 0..data.len() {
+            assert!(validate(&data[..len]).is_err());
+            assert!(decode::<Vec<String>>(&data[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_huge_lengths() {
+        // Binary data, string and array announcing 4 GiB of content
+        for marker in &[0xc6, 0xdb, 0xdd, 0xdf] {
+            let data = [*marker, 0xff, 0xff, 0xff, 0xff, 0x00];
+            assert!(validate(&data).is_err());
+            let mut stream = Cursor::new(data);
+            assert!(decode_from_stream::<Bytes>(&mut stream).is_err());
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let data = encode(&vec![0u8; 100]).unwrap();
+        assert!(decode_from_stream_limited::<Vec<u8>>(&mut Cursor::new(&data), 50).is_err());
+        assert!(decode_from_stream_limited::<Vec<u8>>(&mut Cursor::new(&data), 200).is_ok());
+        let mut data = vec![0x91; MAX_DEPTH + 1];
+        data.push(0xc0);
+        assert!(validate(&data).is_err());
+    }
+
+}
@@ -0,0 +1,45 @@
+use std::io;
+
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use libc;
+
+    use std::io;
+
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    pub fn set_idle_io_priority() -> Result<(), io::Error> {
+        let prio = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use self::linux::*;
+
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_idle_io_priority() -> Result<(), io::Error> {
+    Err(io::Error::new(io::ErrorKind::Other, "IO priorities are not supported on this platform"))
+}
+
+
+#[cfg(unix)]
+pub fn set_lowest_cpu_priority() -> Result<(), io::Error> {
+    use libc;
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn set_lowest_cpu_priority() -> Result<(), io::Error> {
+    Err(io::Error::new(io::ErrorKind::Other, "CPU priorities are not supported on this platform"))
+}
@@ -0,0 +1,110 @@
+use std::io::{self, Write};
+use std::mem;
+
+use libsodium_sys;
+
+
+/// Streaming SHA-256 hasher
+///
+/// This is only used to create checksums that are compatible with external tools, chunks are
+/// hashed with the configured `HashMethod`.
+pub struct Sha256 {
+    state: libsodium_sys::crypto_hash_sha256_state
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Sha256::new()
+    }
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        let mut state = unsafe { mem::zeroed() };
+        unsafe { libsodium_sys::crypto_hash_sha256_init(&mut state) };
+        Sha256 { state: state }
+    }
+
+    #[inline]
+    pub fn update(&mut self, data: &[u8]) {
+        unsafe {
+            libsodium_sys::crypto_hash_sha256_update(
+                &mut self.state,
+                data.as_ptr(),
+                data.len() as u64
+            )
+        };
+    }
+
+    pub fn finish(mut self) -> [u8; 32] {
+        let mut result = [0u8; 32];
+        unsafe { libsodium_sys::crypto_hash_sha256_final(&mut self.state, &mut result) };
+        result
+    }
+}
+
+impl Write for Sha256 {
+    #[inline]
+    fn write(&mut self, data: &[u8]) -> Result<usize, io::Error> {
+        self.update(data);
+        Ok(data.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use super::super::to_hex;
+
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(
+            to_hex(&Sha256::new().finish()),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn test_abc() {
+        let mut hasher = Sha256::new();
+        hasher.update(b"abc");
+        assert_eq!(
+            to_hex(&hasher.finish()),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_two_blocks() {
+        let mut hasher = Sha256::new();
+        hasher.update(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq");
+        assert_eq!(
+            to_hex(&hasher.finish()),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn test_streaming() {
+        let data = vec![0x61u8; 1_000_000];
+        let mut hasher = Sha256::new();
+        for part in data.chunks(997) {
+            hasher.update(part);
+        }
+        assert_eq!(
+            to_hex(&hasher.finish()),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+}
@@ -0,0 +1,229 @@
+use libc;
+
+use std::env;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use std::time::{Duration, Instant};
+
+
+/// Environment variable that holds the socket for notifications to the service manager
+pub static NOTIFY_SOCKET_ENV: &'static str = "NOTIFY_SOCKET";
+
+// Whether the systemd integration has been enabled via --systemd
+static SYSTEMD: AtomicBool = ATOMIC_BOOL_INIT;
+// Set when SIGTERM has been received, long running operations stop at the next file
+static TERMINATED: AtomicBool = ATOMIC_BOOL_INIT;
+
+lazy_static! {
+    static ref LAST_PROGRESS: Mutex<Option<Instant>> = Mutex::new(None);
+}
+
+
+extern "C" fn handle_sigterm(_signal: libc::c_int) {
+    TERMINATED.store(true, Ordering::SeqCst);
+    // A second SIGTERM kills the process immediately
+    unsafe { libc::signal(libc::SIGTERM, libc::SIG_DFL) };
+}
+
+/// Enables the notifications to the service manager and the clean handling of `SIGTERM`
+pub fn enable_systemd() {
+    SYSTEMD.store(true, Ordering::SeqCst);
+    let handler = handle_sigterm as extern "C" fn(libc::c_int);
+    unsafe { libc::signal(libc::SIGTERM, handler as libc::sighandler_t) };
+}
+
+#[inline]
+pub fn systemd_enabled() -> bool {
+    SYSTEMD.load(Ordering::SeqCst)
+}
+
+/// Whether a `SIGTERM` has been received since `enable_systemd` was called
+#[inline]
+pub fn is_terminated() -> bool {
+    TERMINATED.load(Ordering::SeqCst)
+}
+
+
+#[cfg(unix)]
+fn sd_notify(state: &str) -> Result<(), io::Error> {
+    let path = match env::var(NOTIFY_SOCKET_ENV) {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+    if path.starts_with('@') {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Abstract notification sockets are not supported"
+        ));
+    }
+    let socket = try!(UnixDatagram::unbound());
+    try!(socket.send_to(state.as_bytes(), path));
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sd_notify(_state: &str) -> Result<(), io::Error> {
+    Ok(())
+}
+
+fn notify(state: &str) {
+    if !systemd_enabled() {
+        return;
+    }
+    if let Err(err) = sd_notify(state) {
+        debug!("Failed to notify the service manager: {}", err);
+    }
+}
+
+/// Tells the service manager that the startup has finished
+pub fn notify_ready() {
+    notify("READY=1")
+}
+
+/// Sets the status line that is shown by `systemctl status`
+pub fn notify_status(status: &str) {
+    notify(&format!("STATUS={}", status.replace('\n', " ")))
+}
+
+/// Like `notify_status` but sends at most one update per second
+pub fn notify_progress(status: &str) {
+    if !systemd_enabled() {
+        return;
+    }
+    let mut last = LAST_PROGRESS.lock().unwrap();
+    if last.map_or(false, |t| t.elapsed() < Duration::from_secs(1)) {
+        return;
+    }
+    *last = Some(Instant::now());
+    notify_status(status)
+}
+
+/// Tells the service manager that the process is shutting down
+pub fn notify_stopping() {
+    notify("STOPPING=1")
+}
+
+
+/// Prevents the system from sleeping while this object exists
+///
+/// The lock is held by a `systemd-inhibit` process that waits for its input to be closed, so
+/// it is also released if zvault gets killed.
+pub struct SleepInhibitor {
+    child: Child
+}
+
+impl SleepInhibitor {
+    pub fn new(why: &str) -> Result<Self, io::Error> {
+        let child = try!(
+            Command::new("systemd-inhibit")
+                .arg("--what=sleep:idle")
+                .arg("--who=zvault")
+                .arg(format!("--why={}", why))
+                .arg("--mode=block")
+                .arg("cat")
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+        );
+        Ok(SleepInhibitor { child: child })
+    }
+}
+
+impl Drop for SleepInhibitor {
+    fn drop(&mut self) {
+        // Closing the input ends cat and with it the lock
+        self.child.stdin.take();
+        self.child.wait().ok();
+    }
+}
+
+
+/// Quotes an argument for the `ExecStart` line of a unit file
+pub fn escape_unit_arg(arg: &str) -> String {
+    let arg = arg.replace('%', "%%").replace('$', "$$");
+    if !arg.is_empty() && !arg.chars().any(|c| c.is_whitespace() || "\"'\\;".contains(c)) {
+        return arg;
+    }
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Creates a service unit that runs the command once
+pub fn systemd_service_unit(description: &str, command: &[String]) -> String {
+    let command: Vec<_> = command.iter().map(|arg| escape_unit_arg(arg)).collect();
+    format!(
+        "[Unit]
+Description={}
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify
+ExecStart={}
+Nice=19
+IOSchedulingClass=idle
+TimeoutStopSec=5min
+",
+        description,
+        command.join(" ")
+    )
+}
+
+/// Creates a timer unit that starts the service of the same name on the schedule
+///
+/// The schedule uses the calendar event format of systemd, e.g. `daily` or `Mon *-*-* 03:00`.
+pub fn systemd_timer_unit(description: &str, schedule: &str) -> String {
+    format!(
+        "[Unit]
+Description={}
+
+[Timer]
+OnCalendar={}
+Persistent=true
+RandomizedDelaySec=10min
+
+[Install]
+WantedBy=timers.target
+",
+        description,
+        schedule
+    )
+}
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_escape_unit_arg() {
+        assert_eq!("/usr/bin/zvault", escape_unit_arg("/usr/bin/zvault"));
+        assert_eq!("::home-{now:%%Y}", escape_unit_arg("::home-{now:%Y}"));
+        assert_eq!("\"/my files\"", escape_unit_arg("/my files"));
+        assert_eq!("\"a\\\"b\"", escape_unit_arg("a\"b"));
+        assert_eq!("$$HOME", escape_unit_arg("$HOME"));
+        assert_eq!("\"\"", escape_unit_arg(""));
+    }
+
+    #[test]
+    fn test_systemd_service_unit() {
+        let command = vec!["zvault".to_string(), "backup".to_string(), "/my home".to_string()];
+        let unit = systemd_service_unit("Backup", &command);
+        assert!(unit.starts_with("[Unit]\nDescription=Backup\n"));
+        assert!(unit.contains("\nType=notify\n"));
+        assert!(unit.contains("\nExecStart=zvault backup \"/my home\"\n"));
+    }
+
+    #[test]
+    fn test_systemd_timer_unit() {
+        let unit = systemd_timer_unit("Regular backup", "daily");
+        assert!(unit.contains("\nOnCalendar=daily\n"));
+        assert!(unit.ends_with("WantedBy=timers.target\n"));
+    }
+
+}
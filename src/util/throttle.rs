@@ -0,0 +1,64 @@
+use std::cmp::max;
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+/// Limits the average rate of a transfer by sleeping when it gets ahead
+pub struct Throttle {
+    limit: Option<u64>,
+    start: Instant,
+    bytes: u64
+}
+
+impl Throttle {
+    /// Creates a throttle allowing `limit` bytes per second, `None` means no limit
+    pub fn new(limit: Option<u64>) -> Self {
+        Throttle {
+            limit: limit,
+            start: Instant::now(),
+            bytes: 0
+        }
+    }
+
+    /// Accounts for `bytes` that have been transferred and waits until they are within the limit
+    pub fn consume(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        if let Some(limit) = self.limit {
+            let due = self.bytes as f64 / max(limit, 1) as f64;
+            let elapsed = self.start.elapsed();
+            let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+            if due > elapsed {
+                let wait = due - elapsed;
+                thread::sleep(Duration::new(wait as u64, (wait.fract() * 1e9) as u32));
+            }
+        }
+    }
+}
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+
+    #[test]
+    fn test_unlimited() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(None);
+        throttle.consume(1 << 40);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_limited() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(Some(1_000_000));
+        for _ in 0..10 {
+            throttle.consume(10_000);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+}